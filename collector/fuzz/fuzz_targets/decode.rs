@@ -0,0 +1,10 @@
+#![no_main]
+
+use collector::importers::decode::{decode_json, decode_protobuf};
+use libfuzzer_sys::fuzz_target;
+
+// payloads come straight from the network, any input has to be either decoded or rejected
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_json(data);
+    let _ = decode_protobuf(data);
+});
@@ -0,0 +1,145 @@
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::{Error, HttpResponse};
+
+/// Env variable with the token admin api requests have to carry, the api is open when neither
+/// it nor the token file is set
+pub const ADMIN_TOKEN_ENV: &str = "KREWETKA_ADMIN_TOKEN";
+/// Env variable with the path of a file holding the token, e.g. a mounted secret
+pub const ADMIN_TOKEN_FILE_ENV: &str = "KREWETKA_ADMIN_TOKEN_FILE";
+
+/// Bearer token gate of the admin api
+#[derive(Debug, Default)]
+pub struct AdminAuth {
+    /// expected value of the authorization header
+    expected: Option<Vec<u8>>,
+}
+
+impl AdminAuth {
+    pub fn with_token(token: &str) -> io::Result<Self> {
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "admin token is empty",
+            ));
+        }
+        Ok(Self {
+            expected: Some(format!("Bearer {}", token).into_bytes()),
+        })
+    }
+
+    /// Token is read from the env variable first, then from the file it points to
+    pub fn from_env() -> io::Result<Self> {
+        if let Ok(token) = std::env::var(ADMIN_TOKEN_ENV) {
+            return Self::with_token(&token);
+        }
+        match std::env::var(ADMIN_TOKEN_FILE_ENV) {
+            Ok(path) => Self::with_token(&std::fs::read_to_string(Path::new(&path))?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.expected.is_some()
+    }
+
+    pub fn allows(&self, authorization: Option<&HeaderValue>) -> bool {
+        match (&self.expected, authorization) {
+            (None, _) => true,
+            (Some(expected), Some(given)) => constant_time_eq(expected, given.as_bytes()),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Compares every byte, so the time taken does not tell how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+type AuthFuture<B> = Pin<Box<dyn Future<Output = Result<ServiceResponse<EitherBody<B>>, Error>>>>;
+
+/// Middleware answering requests without the token with `401`, passed to `App::wrap_fn`
+pub fn require_token<S, B>(
+    auth: Arc<AdminAuth>,
+) -> impl Fn(ServiceRequest, &S) -> AuthFuture<B> + Clone
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    move |req: ServiceRequest, srv: &S| {
+        if auth.allows(req.headers().get(AUTHORIZATION)) {
+            let res = srv.call(req);
+            return Box::pin(async move { res.await.map(ServiceResponse::map_into_left_body) });
+        }
+
+        let res = req.into_response(
+            HttpResponse::Unauthorized()
+                .insert_header((WWW_AUTHENTICATE, "Bearer"))
+                .finish(),
+        );
+        Box::pin(async move { Ok(res.map_into_right_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::{handler, IngestionControl};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_token_is_compared_whole() {
+        let auth = AdminAuth::with_token("s3cret\n").unwrap();
+
+        assert!(auth.allows(Some(&HeaderValue::from_static("Bearer s3cret"))));
+        assert!(!auth.allows(Some(&HeaderValue::from_static("Bearer s3cre"))));
+        assert!(!auth.allows(Some(&HeaderValue::from_static("Bearer s3cret2"))));
+        assert!(!auth.allows(Some(&HeaderValue::from_static("s3cret"))));
+        assert!(!auth.allows(None));
+
+        assert!(AdminAuth::default().allows(None));
+        assert!(AdminAuth::with_token(" ").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_requests_without_token_are_rejected() {
+        let control = Arc::new(IngestionControl::default());
+        let auth = Arc::new(AdminAuth::with_token("s3cret").unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(control.clone()))
+                .wrap_fn(require_token(auth))
+                .service(handler::pause)
+                .service(handler::status),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/pause").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(!control.is_paused());
+
+        let req = test::TestRequest::post()
+            .uri("/pause")
+            .insert_header((AUTHORIZATION, "Bearer s3cret"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(control.is_paused());
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+pub struct IngestionControl {
+    paused: AtomicBool,
+    resumed: Notify,
+    stopped: AtomicBool,
+}
+
+impl IngestionControl {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Stops ingestion for good, paused importers are woken up so they can finish
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.resumed.notify_waiters();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Returns immediately when ingestion is running or stopped, otherwise waits for resume
+    pub async fn wait_while_paused(&self) {
+        loop {
+            // register before checking the flag, so a resume in between is not missed
+            let resumed = self.resumed.notified();
+            if !self.is_paused() || self.is_stopped() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+
+    /// Waits until ingestion is stopped
+    pub async fn wait_until_stopped(&self) {
+        loop {
+            let notified = self.resumed.notified();
+            if self.is_stopped() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_wait_while_paused() {
+        let control = Arc::new(IngestionControl::default());
+
+        // not paused, returns right away
+        timeout(Duration::from_millis(100), control.wait_while_paused())
+            .await
+            .expect("running ingestion should not wait");
+
+        control.pause();
+        assert!(control.is_paused());
+        assert!(
+            timeout(Duration::from_millis(100), control.wait_while_paused())
+                .await
+                .is_err(),
+            "paused ingestion should wait"
+        );
+
+        let waiter = tokio::spawn({
+            let control = control.clone();
+            async move { control.wait_while_paused().await }
+        });
+        control.resume();
+        timeout(Duration::from_millis(100), waiter)
+            .await
+            .expect("resume should wake up waiting importer")
+            .unwrap();
+        assert!(!control.is_paused());
+    }
+
+    #[tokio::test]
+    async fn test_stop_wakes_up_paused_importer() {
+        let control = Arc::new(IngestionControl::default());
+        control.pause();
+
+        let waiter = tokio::spawn({
+            let control = control.clone();
+            async move {
+                control.wait_while_paused().await;
+                control.wait_until_stopped().await;
+            }
+        });
+        control.stop();
+        timeout(Duration::from_millis(100), waiter)
+            .await
+            .expect("stop should wake up paused importer")
+            .unwrap();
+        assert!(control.is_stopped());
+    }
+}
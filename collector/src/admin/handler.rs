@@ -0,0 +1,123 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use super::IngestionControl;
+use crate::config_log::ConfigLog;
+use crate::metrics;
+
+#[derive(Debug, Serialize)]
+struct Status {
+    paused: bool,
+}
+
+impl From<&IngestionControl> for Status {
+    fn from(control: &IngestionControl) -> Self {
+        Status {
+            paused: control.is_paused(),
+        }
+    }
+}
+
+#[post("/pause")]
+async fn pause(control: web::Data<IngestionControl>) -> impl Responder {
+    control.pause();
+    HttpResponse::Ok().json(Status::from(control.as_ref()))
+}
+
+#[post("/resume")]
+async fn resume(control: web::Data<IngestionControl>) -> impl Responder {
+    control.resume();
+    HttpResponse::Ok().json(Status::from(control.as_ref()))
+}
+
+#[get("/status")]
+async fn status(control: web::Data<IngestionControl>) -> impl Responder {
+    HttpResponse::Ok().json(Status::from(control.as_ref()))
+}
+
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::gather())
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangesQuery {
+    since: Option<u64>,
+}
+
+/// Redacted configuration changes, oldest first. `since` leaves out the ones already seen
+#[get("/config/changes")]
+async fn config_changes(
+    log: web::Data<ConfigLog>,
+    query: web::Query<ChangesQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(log.changes(query.since))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_log::FlatConfig;
+    use actix_web::{test, App};
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_pause_resume_status() {
+        let control = Arc::new(IngestionControl::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(control.clone()))
+                .service(pause)
+                .service(resume)
+                .service(status),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/status").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, r#"{"paused":false}"#);
+
+        let req = test::TestRequest::post().uri("/pause").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, r#"{"paused":true}"#);
+        assert!(control.is_paused());
+
+        let req = test::TestRequest::post().uri("/resume").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, r#"{"paused":false}"#);
+        assert!(!control.is_paused());
+    }
+
+    #[actix_web::test]
+    async fn test_config_changes() {
+        let log = Arc::new(ConfigLog::default());
+        let previous = FlatConfig::from([("exporter.destination".to_owned(), "file".to_owned())]);
+        let current = FlatConfig::from([("exporter.destination".to_owned(), "kafka".to_owned())]);
+        log.record("./krewetka.yaml", &Default::default(), &previous);
+        log.record("./krewetka.yaml", &previous, &current);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(log))
+                .service(config_changes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/config/changes?since=0")
+            .to_request();
+        let changes: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0]["seq"], 1);
+        assert_eq!(
+            changes[0]["diff"],
+            serde_json::json!(["~ exporter.destination: file -> kafka"])
+        );
+
+        let req = test::TestRequest::get().uri("/config/changes").to_request();
+        let changes: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(changes.len(), 2);
+    }
+}
@@ -0,0 +1,38 @@
+pub mod auth;
+mod control;
+pub mod handler;
+
+pub use auth::AdminAuth;
+pub use control::IngestionControl;
+
+use actix_web::dev::Server;
+use actix_web::{web, App, HttpServer};
+use std::sync::Arc;
+
+use crate::config_log::ConfigLog;
+
+/// Every endpoint requires the token when `auth` is enabled
+pub fn server(
+    control: Arc<IngestionControl>,
+    auth: Arc<AdminAuth>,
+    config_log: Arc<ConfigLog>,
+    port: u16,
+) -> std::io::Result<Server> {
+    let control = web::Data::from(control);
+    let config_log = web::Data::from(config_log);
+
+    Ok(HttpServer::new(move || {
+        App::new()
+            .app_data(control.clone())
+            .app_data(config_log.clone())
+            .wrap_fn(auth::require_token(auth.clone()))
+            .service(handler::pause)
+            .service(handler::resume)
+            .service(handler::status)
+            .service(handler::metrics)
+            .service(handler::config_changes)
+    })
+    .workers(1)
+    .bind(format!("0.0.0.0:{}", port))?
+    .run())
+}
@@ -1,15 +1,29 @@
-use log::error;
+use log::{error, info, warn};
 use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use tokio::sync::mpsc;
-use tokio::task::{self};
+use tokio::task::{self, JoinHandle};
 
+use common::log_sampling;
+use common::redact::redact_credentials;
+
+use crate::admin::{self, AdminAuth, IngestionControl};
 use crate::config::{ConfigCache, ConfigErr};
-use crate::exporters;
-use crate::importers;
-use crate::settings::Configuration;
+use crate::config_log::ConfigLog;
+use crate::config_source::{ConfigSource, CONFIG_CACHE_ENV, CONFIG_SOURCE_ENV};
+use crate::exporters::{self, Export, ExporterError, Shadowed};
+use crate::importers::{
+    self, Completeness, PolicySender, RateLimiter, Ring, Spill, SpillDrainMode,
+};
+use crate::settings::{Configuration, ConstructorErr};
+use crate::shutdown::{
+    self, DRAIN_EXPORTER_TIMEOUT, FLUSH_EXPORTER_TIMEOUT, STOP_IMPORTER_TIMEOUT,
+};
 
 const CONFIG_PATH: &str = "./krewetka.yaml";
+const ADMIN_HTTP_PORT: u16 = 8080;
 
 pub struct ApplicationState {
     pub config: ConfigCache,
@@ -18,7 +32,34 @@ pub struct ApplicationState {
 #[derive(Debug)]
 pub enum AppInitErr {
     Config(ConfigErr),
-    ImporterInit(ConfigErr),
+    ImporterInit(ConstructorErr),
+    ExporterInit(ConstructorErr),
+    ExporterPrepare(ExporterError),
+    AdminServer(std::io::Error),
+}
+
+impl AppInitErr {
+    /// Exit status of the collector, tells an orchestrator why it did not start
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 78,
+            Self::ImporterInit(_) | Self::ExporterInit(_) | Self::ExporterPrepare(_) => 69,
+            Self::AdminServer(_) => 71,
+        }
+    }
+}
+
+/// Setting which does not parse, the collector exits as misconfigured
+fn invalid<E: fmt::Debug>(setting: &'static str) -> impl FnOnce(E) -> AppInitErr {
+    move |e| AppInitErr::Config(ConfigErr::Invalid(format!("{}: {:?}", setting, e)))
+}
+
+/// Spill or ring which can not be opened, the importer can not start without it
+fn unopened(err: ConstructorErr) -> impl FnOnce(std::io::Error) -> AppInitErr {
+    move |e| {
+        error!("unable to open {:?}: {}", err, e);
+        AppInitErr::ImporterInit(err)
+    }
 }
 
 #[derive(Debug)]
@@ -75,43 +116,521 @@ impl ApplicationState {
         self.config.get_config::<Configuration>()
     }
 
-    pub async fn init_components(config: Configuration) -> Result<(), AppInitErr> {
+    pub async fn init_components(
+        config: Configuration,
+        config_log: Arc<ConfigLog>,
+    ) -> Result<(), AppInitErr> {
         let identifier = HostIdentifier::default();
+        info!("{}", redact_credentials(&config.to_string()));
 
         // initialize exporter and importer
         let exporter = config
             .exporter
             .destination
-            .construct_exporter(config.exporter.settings)
-            .expect("unable to initialize exporter");
+            .construct_exporter(config.exporter.settings, &identifier)
+            .map_err(AppInitErr::ExporterInit)?;
+        exporter
+            .prepare()
+            .await
+            .map_err(AppInitErr::ExporterPrepare)?;
+
+        // a sample of exported messages is tee'd to the candidate, best effort
+        let (exporter, shadow): (Box<dyn Export<Item = Vec<u8>>>, _) = match config.shadow_exporter
+        {
+            Some(shadow) => {
+                let sample_percent = shadow
+                    .sample_percent()
+                    .map_err(invalid("shadow exporter sample percent"))?;
+                let candidate = shadow
+                    .destination
+                    .construct_exporter(shadow.settings, &identifier)
+                    .map_err(AppInitErr::ExporterInit)?;
+                let (shadowed, shadow_rx) = Shadowed::new(exporter, sample_percent);
+                info!("Shadow exporter gets {}% of messages", sample_percent);
+                (Box::new(shadowed), Some((candidate, shadow_rx)))
+            }
+            None => (exporter, None),
+        };
+
+        let drop_policy = config
+            .importer
+            .settings
+            .drop_policy()
+            .map_err(invalid("importer drop policy"))?;
+        let importer_source = config.importer.source.to_string();
+        let transforms = config
+            .importer
+            .settings
+            .transforms()
+            .map_err(invalid("importer transforms"))?;
+        log_sampling::set_debug_log_sample_rate(
+            config
+                .importer
+                .settings
+                .debug_log_sample_rate()
+                .map_err(invalid("debug log sample rate"))?,
+        );
+        let spill = config
+            .importer
+            .settings
+            .spill_settings()
+            .map_err(invalid("importer spill settings"))?
+            .map(|s| Spill::open(s).map_err(unopened(ConstructorErr::SpillErr)))
+            .transpose()?;
+        let heartbeat = config
+            .importer
+            .settings
+            .heartbeat_settings()
+            .map_err(invalid("importer heartbeat settings"))?;
+        let ring = config
+            .importer
+            .settings
+            .ring_settings()
+            .map_err(invalid("importer ring settings"))?
+            .map(|s| Ring::open(s).map_err(unopened(ConstructorErr::RingErr)))
+            .transpose()?;
+
+        let field_defaults = config
+            .importer
+            .settings
+            .field_defaults()
+            .map_err(invalid("importer field defaults"))?;
+        let host_normalization = config
+            .importer
+            .settings
+            .host_normalization()
+            .map_err(invalid("host normalization"))?;
+        let rate_limit = config
+            .importer
+            .settings
+            .rate_limit()
+            .map_err(invalid("importer rate limit"))?
+            .map(|s| RateLimiter::new(&s, &importer_source));
 
         let importer = config
             .importer
             .source
             .construct_importer(config.importer.settings)
-            .expect("unable to initialize importer");
+            .await
+            .map_err(AppInitErr::ImporterInit)?;
+        // `@source` is known only once the importer is connected
+        let field_defaults = field_defaults
+            .resolve(importer.source_addr())
+            .map_err(invalid("importer field defaults"))?;
+        // flows are exported as sent by the host the `host` default names, normalized the way
+        // processor normalizes identifiers, so kafka keys of a host do not depend on its spelling
+        let exported_host = host_normalization.apply(
+            &field_defaults
+                .host()
+                .map_or_else(|| String::from(&identifier), str::to_owned),
+        );
+        if !host_normalization.is_noop() {
+            info!("Flows are exported as sent by {}", exported_host);
+        }
+        if let Some((candidate, shadow_rx)) = shadow {
+            task::spawn(exporters::shadow::run(
+                candidate,
+                shadow_rx,
+                exported_host.clone(),
+            ));
+        }
+
+        // admin api allows to pause and resume importing
+        let control = Arc::new(IngestionControl::default());
+        let auth = Arc::new(AdminAuth::from_env().map_err(AppInitErr::AdminServer)?);
+        let admin_server =
+            admin::server(control.clone(), auth.clone(), config_log, ADMIN_HTTP_PORT)
+                .map_err(AppInitErr::AdminServer)?;
+        task::spawn(admin_server);
+        if auth.is_enabled() {
+            info!(
+                "Admin api listening on port {}, token required",
+                ADMIN_HTTP_PORT
+            );
+        } else {
+            warn!(
+                "Admin api listening on port {} without authentication",
+                ADMIN_HTTP_PORT
+            );
+        }
 
         // make a shared channel for common data
         let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1024);
 
-        let tx1 = tx.clone();
+        let mut tx1 = PolicySender::new(tx.clone(), drop_policy, &importer_source);
+        // spill whose leftovers have to be exported before the importer starts
+        let mut hold_for = None;
+
+        // messages pass through the ring spool, leftovers of the previous run first
+        if let Some(ring) = ring {
+            if spill.is_some() {
+                warn!("spill_dir is ignored, messages go through the ring spool");
+            }
+            task::spawn(importers::ring::drain(ring.clone(), tx.clone()));
+            tx1 = tx1.with_ring(ring);
+        } else if let Some(spill) = spill {
+            // overflow spilled to disk goes back to the channel, leftovers of the previous run first
+            task::spawn(importers::spill::drain(spill.clone(), tx.clone()));
+            if spill.drain_mode() == SpillDrainMode::BeforeLive && spill.has_leftovers() {
+                hold_for = Some(spill.clone());
+            }
+            tx1 = tx1.with_spill(spill);
+        }
 
-        // spawning task responsbile for importing data
-        let importer_task = task::spawn(async move { importers::run(importer, tx1).await });
+        // spawning task responsbile for importing data, an importer which gave up stops ingestion
+        let importer_task = task::spawn({
+            let control = control.clone();
+            async move {
+                if let Some(spill) = hold_for {
+                    info!("importer is held until the spill left by the previous run is exported");
+                    tokio::select! {
+                        _ = spill.wait_for_leftovers() => {
+                            info!("spill leftovers exported, starting importer")
+                        }
+                        _ = control.wait_until_stopped() => {}
+                    }
+                }
+                importers::run(
+                    importer,
+                    tx1,
+                    control.clone(),
+                    field_defaults,
+                    transforms,
+                    Completeness::new(&importer_source),
+                    rate_limit,
+                )
+                .await;
+                control.stop();
+            }
+        });
+        // heartbeats go straight to the exporter channel, next to imported flows
+        if let Some(heartbeat) = heartbeat {
+            task::spawn(importers::heartbeat::run(
+                heartbeat,
+                tx.clone(),
+                control.clone(),
+            ));
+        }
+        // the channel is closed once the importer, the heartbeat and the spill or ring drainer
+        // are done with it
+        drop(tx);
 
         // export data
-        exporters::run(exporter, &mut rx, &identifier).await;
+        let exporter_task =
+            task::spawn(async move { exporters::run(exporter, &mut rx, &exported_host).await });
 
-        importer_task.await;
+        tokio::select! {
+            _ = shutdown::signal() => info!("Shutdown requested"),
+            _ = control.wait_until_stopped() => info!("Ingestion stopped"),
+        }
+        shutdown_components(&control, importer_task, exporter_task).await;
         Ok(())
     }
 }
 
-pub fn init_config() -> Result<(ConfigCache, Configuration), AppInitErr> {
-    let config_cache = ConfigCache::new(CONFIG_PATH).map_err(AppInitErr::Config)?;
+/// Stops the pipeline front to back, so every message accepted by the importer is exported:
+/// stop importer, drain exporter channel, flush exporter
+async fn shutdown_components<E: Export>(
+    control: &IngestionControl,
+    mut importer_task: JoinHandle<()>,
+    mut exporter_task: JoinHandle<E>,
+) {
+    control.stop();
+    if shutdown::stage("stop importer", STOP_IMPORTER_TIMEOUT, &mut importer_task)
+        .await
+        .is_none()
+    {
+        // otherwise it keeps the exporter channel open
+        importer_task.abort();
+    }
+
+    let exporter = match shutdown::stage(
+        "drain exporter channel",
+        DRAIN_EXPORTER_TIMEOUT,
+        &mut exporter_task,
+    )
+    .await
+    {
+        Some(Ok(exporter)) => exporter,
+        Some(Err(e)) => {
+            error!(
+                "exporter task failed, messages left in the channel are lost: {}",
+                e
+            );
+            return;
+        }
+        None => {
+            exporter_task.abort();
+            return;
+        }
+    };
+
+    if let Some(Err(e)) =
+        shutdown::stage("flush exporter", FLUSH_EXPORTER_TIMEOUT, exporter.flush()).await
+    {
+        error!("unable to flush exporter: {:?}", e);
+    }
+}
+
+/// Reads configuration from `KREWETKA_CONFIG_SOURCE`, `./krewetka.yaml` by default
+pub async fn init_config() -> Result<(ConfigCache, Configuration), AppInitErr> {
+    let source = ConfigSource::parse(
+        &std::env::var(CONFIG_SOURCE_ENV).unwrap_or_else(|_| CONFIG_PATH.to_owned()),
+    );
+    let cache_path = std::env::var(CONFIG_CACHE_ENV).ok().map(PathBuf::from);
+    let config_cache = ConfigCache::from_source(source, cache_path)
+        .await
+        .map_err(AppInitErr::Config)?;
     let configuration = config_cache
         .get_config::<Configuration>()
         .map_err(AppInitErr::Config)?;
 
     Ok((config_cache, configuration))
 }
+
+/// Fetches the configuration again on every `SIGHUP`. A reloaded configuration is recorded in
+/// the config change log and its debug log sample rate applies right away, the importer and
+/// exporter keep running with the settings they were started with until a restart
+#[cfg(unix)]
+pub async fn reload_on_hangup(mut config_cache: ConfigCache) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("unable to listen for SIGHUP, config is not reloaded: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+        let config = match config_cache.reload::<Configuration>().await {
+            Ok(config) => config,
+            // logged by the reload
+            Err(_) => continue,
+        };
+        match config.importer.settings.debug_log_sample_rate() {
+            Ok(rate) => log_sampling::set_debug_log_sample_rate(rate),
+            Err(e) => warn!("keeping debug log sample rate: {}", e),
+        }
+        info!("config reloaded, other changes apply once the collector is restarted");
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_hangup(_config_cache: ConfigCache) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::importers::decode::Framing;
+    use crate::importers::errors::ImporterError;
+    use crate::importers::zmq::DEFAULT_ZMQ_CONNECT_TIMEOUT;
+    use crate::importers::{
+        DropPolicy, FieldDefaults, Import, Received, SourceAllowlist, Subscriber, Transforms,
+        ZMQSettings, ZMQ,
+    };
+    use crate::pb::FlowMessage;
+    use crate::settings::{
+        Exporter, ExporterSettings, ExporterVariants, Importer, ImporterSettings, ImporterVariants,
+    };
+    use async_trait::async_trait;
+    use pretty_assertions::assert_eq;
+    use prost::Message;
+    use std::sync::Mutex;
+    use tokio::sync::Notify;
+    use tokio::time::{sleep, Duration};
+
+    /// Hands over a single batch, then blocks like an idle socket
+    struct SingleBatch {
+        batch: Mutex<Option<Vec<FlowMessage>>>,
+        handed_over: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl Import for SingleBatch {
+        async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
+            let batch = self.batch.lock().unwrap().take();
+            match batch {
+                Some(batch) => Ok(batch),
+                None => {
+                    self.handed_over.notify_one();
+                    std::future::pending().await
+                }
+            }
+        }
+    }
+
+    /// Hands over a single frame, then blocks its thread a poll interval at a time like a zmq
+    /// socket nothing is published to
+    struct IdleSocket {
+        frame: Mutex<Option<Vec<u8>>>,
+        handed_over: Arc<Notify>,
+    }
+
+    impl Subscriber for IdleSocket {
+        fn recv(&self) -> Result<Option<Received>, ImporterError> {
+            let frame = self.frame.lock().unwrap().take();
+            match frame {
+                Some(frame) => Ok(Some(Received { frame, peer: None })),
+                None => {
+                    self.handed_over.notify_one();
+                    std::thread::sleep(Duration::from_millis(200));
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorded {
+        exported: Mutex<Vec<Vec<u8>>>,
+        exported_before_flush: Mutex<Option<usize>>,
+    }
+
+    /// Slow exporter, so the channel still holds messages when the shutdown starts
+    struct Recorder(Arc<Recorded>);
+
+    #[async_trait]
+    impl Export for Recorder {
+        type Item = Vec<u8>;
+
+        async fn export(&self, message: &Vec<u8>, _identifier: &str) -> Result<(), ExporterError> {
+            sleep(Duration::from_millis(5)).await;
+            self.0.exported.lock().unwrap().push(message.clone());
+            Ok(())
+        }
+
+        async fn flush(&self) -> Result<(), ExporterError> {
+            let exported = self.0.exported.lock().unwrap().len();
+            *self.0.exported_before_flush.lock().unwrap() = Some(exported);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_accepted_before_shutdown_are_exported() {
+        let flows = (0..20)
+            .map(|i| FlowMessage {
+                in_bytes: i,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let handed_over = Arc::new(Notify::new());
+        let importer = SingleBatch {
+            batch: Mutex::new(Some(flows)),
+            handed_over: handed_over.clone(),
+        };
+        let recorded = Arc::new(Recorded::default());
+
+        let control = Arc::new(IngestionControl::default());
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
+        let importer_task = task::spawn(importers::run(
+            importer,
+            PolicySender::new(tx, DropPolicy::Block, "test"),
+            control.clone(),
+            FieldDefaults::default(),
+            Transforms::default(),
+            Completeness::new("test"),
+            None,
+        ));
+        let exporter_task = task::spawn({
+            let exporter = Recorder(recorded.clone());
+            async move { exporters::run(exporter, &mut rx, "host").await }
+        });
+
+        // the whole batch is in the channel, the importer waits for more
+        handed_over.notified().await;
+        shutdown_components(&control, importer_task, exporter_task).await;
+
+        assert_eq!(recorded.exported.lock().unwrap().len(), 20);
+        assert_eq!(*recorded.exported_before_flush.lock().unwrap(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_idle_zmq_importer_is_stopped_and_exported_messages_flushed() {
+        let mut frame = Vec::new();
+        for i in 0..20 {
+            FlowMessage {
+                in_bytes: i,
+                ..Default::default()
+            }
+            .encode_length_delimited(&mut frame)
+            .unwrap();
+        }
+        let handed_over = Arc::new(Notify::new());
+        let socket: Box<dyn Subscriber + Send> = Box::new(IdleSocket {
+            frame: Mutex::new(Some(frame)),
+            handed_over: handed_over.clone(),
+        });
+        let importer = ZMQ {
+            subscriber: Arc::new(Mutex::new(socket)),
+            settings: ZMQSettings {
+                address: "localhost:5561".to_string(),
+                queue_name: "flow".to_string(),
+                tcp_keepalive_idle_secs: None,
+                idle_timeout_ms: None,
+                connect_timeout: DEFAULT_ZMQ_CONNECT_TIMEOUT,
+                framing: Framing::LengthDelimitedBatch,
+                allowlist: SourceAllowlist::default(),
+            },
+        };
+        let recorded = Arc::new(Recorded::default());
+
+        let control = Arc::new(IngestionControl::default());
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
+        let importer_task = task::spawn(importers::run(
+            importer,
+            PolicySender::new(tx, DropPolicy::Block, "zmq"),
+            control.clone(),
+            FieldDefaults::default(),
+            Transforms::default(),
+            Completeness::new("zmq"),
+            None,
+        ));
+        let exporter_task = task::spawn({
+            let exporter = Recorder(recorded.clone());
+            async move { exporters::run(exporter, &mut rx, "host").await }
+        });
+
+        // the importer blocks a thread waiting for the next frame
+        handed_over.notified().await;
+        tokio::time::timeout(
+            STOP_IMPORTER_TIMEOUT,
+            shutdown_components(&control, importer_task, exporter_task),
+        )
+        .await
+        .expect("stopping an idle importer should not wait for its timeouts");
+
+        assert_eq!(recorded.exported.lock().unwrap().len(), 20);
+        assert_eq!(*recorded.exported_before_flush.lock().unwrap(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_setting_exits_as_misconfigured() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Configuration {
+            importer: Importer {
+                source: ImporterVariants::ZMQ,
+                settings: ImporterSettings {
+                    drop_policy: Some("drop".to_owned()),
+                    ..Default::default()
+                },
+            },
+            exporter: Exporter {
+                destination: ExporterVariants::File,
+                settings: ExporterSettings {
+                    file_dir: Some(dir.path().display().to_string()),
+                    ..Default::default()
+                },
+            },
+            shadow_exporter: None,
+        };
+
+        let err = ApplicationState::init_components(config, Arc::new(ConfigLog::default()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppInitErr::Config(ConfigErr::Invalid(_))));
+        assert_eq!(err.exit_code(), 78);
+    }
+}
@@ -1,36 +1,133 @@
 use config::builder::DefaultState;
 use config::{Config, ConfigBuilder, Environment, File};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use crate::config_log::{self, ConfigLog, FlatConfig};
+use crate::config_source::{read_file, ConfigSource, FetchError, LastGood};
+
 const DEFAULT_ENV_VAR_PREFIX: &str = "KREWETKA";
 
 #[derive(Debug)]
 pub enum ConfigErr {
     Read(config::ConfigError),
+    Fetch(String),
+    /// setting which was read but does not parse, named along with the error
+    Invalid(String),
+}
+
+impl From<FetchError> for ConfigErr {
+    fn from(e: FetchError) -> Self {
+        Self::Fetch(e.0)
+    }
 }
 
 pub struct ConfigCache {
     config: Config,
-    config_path: String,
+    source: ConfigSource,
+    last_good: LastGood,
     ts: SystemTime,
+    log: Arc<ConfigLog>,
 }
 
 impl ConfigCache {
     pub fn new(global_config_path: &str) -> Result<Self, ConfigErr> {
+        let source = ConfigSource::File(PathBuf::from(global_config_path));
+        let document = read_file(&PathBuf::from(global_config_path))?;
         let config_cache = Self {
-            config: Self::load_config(global_config_path)?,
-            config_path: global_config_path.to_owned(),
+            config: Self::load_config(&source, document.as_deref())?,
+            source,
+            last_good: LastGood::new(None),
             ts: SystemTime::now(),
+            log: Arc::new(ConfigLog::default()),
         };
+        config_cache.record(&FlatConfig::new());
 
         Ok(config_cache)
     }
 
-    fn load_config(global_config_path: &str) -> Result<Config, ConfigErr> {
-        let base_config_builder = ConfigBuilder::<DefaultState>::default();
+    /// Fetches the document from the source, falling back to the last good copy kept
+    /// at `cache_path` when a remote source is unavailable
+    pub async fn from_source(
+        source: ConfigSource,
+        cache_path: Option<PathBuf>,
+    ) -> Result<Self, ConfigErr> {
+        let mut config_cache = Self {
+            config: Config::default(),
+            source,
+            last_good: LastGood::new(cache_path),
+            ts: SystemTime::now(),
+            log: Arc::new(ConfigLog::default()),
+        };
+        config_cache.config = config_cache.fetch_config().await?;
+        config_cache.record(&FlatConfig::new());
+
+        Ok(config_cache)
+    }
+
+    /// Fetches the document again. It replaces the current configuration only once it
+    /// deserializes into `T`, otherwise the current one is kept
+    pub async fn reload<T: DeserializeOwned>(&mut self) -> Result<T, ConfigErr> {
+        let reloaded = self
+            .fetch_config()
+            .await
+            .and_then(|config| {
+                let parsed = config.clone().try_deserialize().map_err(ConfigErr::Read)?;
+                Ok((config, parsed))
+            })
+            .map_err(|e| {
+                error!("keeping current config, reload failed: {:?}", e);
+                e
+            })?;
+        let previous = config_log::flatten(&self.config);
+        self.config = reloaded.0;
+        self.ts = SystemTime::now();
+        self.record(&previous);
+        Ok(reloaded.1)
+    }
+
+    /// Changes of the configuration since it was first loaded
+    pub fn log(&self) -> Arc<ConfigLog> {
+        self.log.clone()
+    }
+
+    fn record(&self, previous: &FlatConfig) {
+        let current = config_log::flatten(&self.config);
+        self.log
+            .record(&self.source.to_string(), previous, &current);
+    }
+
+    async fn fetch_config(&mut self) -> Result<Config, ConfigErr> {
+        match self.source.fetch().await {
+            Ok(document) => {
+                info!("config fetched from {}", self.source);
+                let config = Self::load_config(&self.source, document.as_deref())?;
+                // only documents which were loaded are kept, so the fallback stays usable
+                if let (true, Some(document)) = (self.source.is_remote(), &document) {
+                    self.last_good.store(document);
+                }
+                Ok(config)
+            }
+            Err(FetchError(e)) => {
+                warn!("unable to fetch config: {}", e);
+                let document = self.last_good.load().ok_or(ConfigErr::Fetch(e))?;
+                Self::load_config(&self.source, Some(&document))
+            }
+        }
+    }
+
+    /// Builds configuration from the document, environment variables override it
+    fn load_config(source: &ConfigSource, document: Option<&str>) -> Result<Config, ConfigErr> {
+        let mut base_config_builder = ConfigBuilder::<DefaultState>::default();
+        if let Some(document) = document {
+            base_config_builder =
+                base_config_builder.add_source(File::from_str(document, source.format()));
+        }
         base_config_builder
-            .add_source(File::with_name(global_config_path).required(false))
             .add_source(Environment::with_prefix(DEFAULT_ENV_VAR_PREFIX).separator("__"))
             .build()
             .map_err(ConfigErr::Read)
@@ -43,3 +140,74 @@ impl ConfigCache {
             .map_err(ConfigErr::Read)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Stands in for the collector configuration, which requires the exporter
+    #[derive(Debug, Deserialize)]
+    struct Exported {
+        exporter: serde_json::Value,
+    }
+
+    #[tokio::test]
+    async fn test_reload_replaces_config_only_when_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("krewetka.yaml");
+        std::fs::write(&path, "exporter:\n  settings:\n    file_dir: /var/a\n").unwrap();
+        let mut config_cache = ConfigCache::from_source(ConfigSource::File(path.clone()), None)
+            .await
+            .unwrap();
+
+        std::fs::write(&path, "exporter:\n  settings:\n    file_dir: /var/b\n").unwrap();
+        let reloaded = config_cache.reload::<Exported>().await.unwrap();
+        assert_eq!(reloaded.exporter["settings"]["file_dir"], "/var/b");
+        let file_dir = |c: &ConfigCache| c.config.get_string("exporter.settings.file_dir");
+        assert_eq!(file_dir(&config_cache).unwrap(), "/var/b");
+        let changes = config_cache.log().changes(None);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[1].seq, 1);
+        assert_eq!(
+            changes[1].diff,
+            vec!["~ exporter.settings.file_dir: /var/a -> /var/b"]
+        );
+
+        // without the exporter the document is rejected and nothing changes
+        std::fs::write(&path, "importer:\n  source: zmq\n").unwrap();
+        assert!(config_cache.reload::<Exported>().await.is_err());
+        assert_eq!(file_dir(&config_cache).unwrap(), "/var/b");
+        assert_eq!(config_cache.log().changes(None).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_source_falls_back_to_last_good_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("krewetka-config-cache.yaml");
+        std::fs::write(
+            &cache_path,
+            "exporter:\n  settings:\n    file_dir: /var/krewetka\n",
+        )
+        .unwrap();
+
+        // nothing listens on the discard port
+        let source = ConfigSource::Http("http://127.0.0.1:9/collector.yaml".to_owned());
+        let config_cache = ConfigCache::from_source(source.clone(), Some(cache_path.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            config_cache
+                .config
+                .get_string("exporter.settings.file_dir")
+                .unwrap(),
+            "/var/krewetka"
+        );
+
+        std::fs::remove_file(&cache_path).unwrap();
+        assert!(matches!(
+            ConfigCache::from_source(source, Some(cache_path)).await,
+            Err(ConfigErr::Fetch(_))
+        ));
+    }
+}
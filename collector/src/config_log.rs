@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+use config::Config;
+use serde::Serialize;
+use serde_json::Value;
+
+use common::redact::{redact_credentials, REDACTED};
+
+/// Changes kept by the log if not configured, the oldest ones are forgotten first
+pub const DEFAULT_CONFIG_LOG_CAPACITY: usize = 64;
+
+/// Values of keys with any of these in their last segment are never shown
+const SECRET_KEY_PARTS: [&str; 4] = ["password", "secret", "token", "credential"];
+
+/// Configuration flattened to dotted keys, e.g. `exporter.settings.kafka_topic`
+pub type FlatConfig = BTreeMap<String, String>;
+
+/// A successful load of the configuration and how it differed from the previous one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigChange {
+    /// increases by one with every change, so a client can ask only for newer ones
+    pub seq: u64,
+    pub at: String,
+    pub source: String,
+    /// one line per changed key, `+` added, `-` removed, `~` modified
+    pub diff: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    changes: VecDeque<ConfigChange>,
+    next_seq: u64,
+}
+
+/// Append-only log of configuration changes held in memory, so an incident review can tell
+/// when and to what the configuration changed while the collector was running. It is not
+/// persisted, a restarted collector starts a new one. Values are redacted before they are recorded
+#[derive(Debug)]
+pub struct ConfigLog {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for ConfigLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONFIG_LOG_CAPACITY)
+    }
+}
+
+impl ConfigLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Records the difference between configurations, nothing when they are the same
+    pub fn record(&self, source: &str, previous: &FlatConfig, current: &FlatConfig) {
+        let diff = diff(previous, current);
+        if diff.is_empty() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let seq = entries.next_seq;
+        entries.next_seq += 1;
+        if entries.changes.len() == self.capacity {
+            entries.changes.pop_front();
+        }
+        entries.changes.push_back(ConfigChange {
+            seq,
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            source: redact_credentials(source),
+            diff,
+        });
+    }
+
+    /// Changes still kept, oldest first, only the ones after `since` when given
+    pub fn changes(&self, since: Option<u64>) -> Vec<ConfigChange> {
+        self.entries
+            .lock()
+            .unwrap()
+            .changes
+            .iter()
+            .filter(|c| since.map_or(true, |since| c.seq > since))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Flattens the configuration, environment overrides included. Arrays are kept as json
+pub fn flatten(config: &Config) -> FlatConfig {
+    let mut flat = FlatConfig::new();
+    match config.clone().try_deserialize::<Value>() {
+        Ok(value) => flatten_value("", &value, &mut flat),
+        Err(e) => log::warn!("unable to flatten config for the change log: {}", e),
+    }
+    flat
+}
+
+fn flatten_value(prefix: &str, value: &Value, flat: &mut FlatConfig) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = match prefix {
+                    "" => key.clone(),
+                    prefix => format!("{}.{}", prefix, key),
+                };
+                flatten_value(&key, value, flat);
+            }
+        }
+        Value::String(s) => {
+            flat.insert(prefix.to_owned(), s.clone());
+        }
+        Value::Null => {}
+        value => {
+            flat.insert(prefix.to_owned(), value.to_string());
+        }
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    let name = key.rsplit('.').next().unwrap_or(key).to_lowercase();
+    SECRET_KEY_PARTS.iter().any(|part| name.contains(part))
+}
+
+fn redact(key: &str, value: &str) -> String {
+    if is_secret(key) {
+        REDACTED.to_owned()
+    } else {
+        redact_credentials(value)
+    }
+}
+
+/// Human readable difference, sorted by key. A changed secret shows up without its values
+fn diff(previous: &FlatConfig, current: &FlatConfig) -> Vec<String> {
+    let mut keys = previous
+        .keys()
+        .chain(current.keys())
+        .collect::<Vec<&String>>();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| match (previous.get(key), current.get(key)) {
+            (None, Some(new)) => Some(format!("+ {}: {}", key, redact(key, new))),
+            (Some(old), None) => Some(format!("- {}: {}", key, redact(key, old))),
+            (Some(old), Some(new)) if old != new => Some(format!(
+                "~ {}: {} -> {}",
+                key,
+                redact(key, old),
+                redact(key, new)
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+    use pretty_assertions::assert_eq;
+
+    fn flat(pairs: &[(&str, &str)]) -> FlatConfig {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_is_flattened() {
+        let config = Config::builder()
+            .add_source(config::File::from_str(
+                "exporter:\n  destination: kafka\n  settings:\n    kafka_brokers: b1:9092\n    batch: 100\n",
+                FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            flatten(&config),
+            flat(&[
+                ("exporter.destination", "kafka"),
+                ("exporter.settings.batch", "100"),
+                ("exporter.settings.kafka_brokers", "b1:9092"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_diff_is_redacted() {
+        let previous = flat(&[
+            ("exporter.settings.kafka_brokers", "b1:9092"),
+            ("exporter.settings.schema_registry_password", "old"),
+            ("importer.settings.zmq_address", "tcp://u:p@h:5561"),
+        ]);
+        let current = flat(&[
+            ("exporter.settings.kafka_brokers", "b1:9092,b2:9092"),
+            ("exporter.settings.schema_registry_password", "new"),
+            ("importer.settings.transforms", "drop:tcp_flags"),
+        ]);
+
+        assert_eq!(
+            diff(&previous, &current),
+            vec![
+                "~ exporter.settings.kafka_brokers: b1:9092 -> b1:9092,b2:9092",
+                "~ exporter.settings.schema_registry_password: *** -> ***",
+                "+ importer.settings.transforms: drop:tcp_flags",
+                "- importer.settings.zmq_address: tcp://u:***@h:5561",
+            ]
+        );
+        assert!(diff(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let log = ConfigLog::new(2);
+        let mut previous = FlatConfig::new();
+        for brokers in ["b1", "b2", "b3"] {
+            let current = flat(&[("exporter.settings.kafka_brokers", brokers)]);
+            log.record("./krewetka.yaml", &previous, &current);
+            log.record("./krewetka.yaml", &current, &current);
+            previous = current;
+        }
+
+        let changes = log.changes(None);
+        assert_eq!(
+            changes.iter().map(|c| c.seq).collect::<Vec<u64>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            changes[1].diff,
+            vec!["~ exporter.settings.kafka_brokers: b2 -> b3"]
+        );
+        assert_eq!(log.changes(Some(1)).len(), 1);
+    }
+}
@@ -0,0 +1,231 @@
+use config::FileFormat;
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Env variable with the location of the configuration document, `./krewetka.yaml` when not set
+pub const CONFIG_SOURCE_ENV: &str = "KREWETKA_CONFIG_SOURCE";
+/// Env variable with the path where the last fetched remote document is kept
+pub const CONFIG_CACHE_ENV: &str = "KREWETKA_CONFIG_CACHE";
+/// Token sent to consul, the same variable consul cli reads
+const CONSUL_TOKEN_ENV: &str = "CONSUL_HTTP_TOKEN";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchError(pub String);
+
+/// Where the configuration document comes from, environment variables override it in any case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// local file, a missing file means an empty document
+    File(PathBuf),
+    /// document served at the url as it is
+    Http(String),
+    /// raw value of a key in consul KV store
+    Consul { address: String, key: String },
+}
+
+impl ConfigSource {
+    /// `http://` urls are fetched as they are, `consul://<agent>/<key>` reads a consul key,
+    /// anything else is a path
+    pub fn parse(location: &str) -> Self {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Self::Http(location.to_owned());
+        }
+        match location.strip_prefix("consul://") {
+            Some(rest) => {
+                let (agent, key) = rest.split_once('/').unwrap_or((rest, ""));
+                Self::Consul {
+                    address: format!("http://{}", agent),
+                    key: key.trim_matches('/').to_owned(),
+                }
+            }
+            None => Self::File(PathBuf::from(location)),
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        !matches!(self, Self::File(_))
+    }
+
+    /// Format of the document, remote documents are yaml, which covers json too
+    pub fn format(&self) -> FileFormat {
+        match self {
+            Self::File(path) => match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => FileFormat::Json,
+                Some("toml") => FileFormat::Toml,
+                _ => FileFormat::Yaml,
+            },
+            _ => FileFormat::Yaml,
+        }
+    }
+
+    /// Raw configuration document, `None` when there is no local file
+    pub async fn fetch(&self) -> Result<Option<String>, FetchError> {
+        match self {
+            Self::File(path) => read_file(path),
+            Self::Http(url) => get(url, None).await.map(Some),
+            Self::Consul { address, key } => {
+                let url = format!("{}/v1/kv/{}?raw", address, key);
+                let token = std::env::var(CONSUL_TOKEN_ENV).ok();
+                get(&url, token.as_deref()).await.map(Some)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Http(url) => write!(f, "{}", url),
+            Self::Consul { address, key } => write!(f, "consul {} key {}", address, key),
+        }
+    }
+}
+
+pub fn read_file(path: &Path) -> Result<Option<String>, FetchError> {
+    match fs::read_to_string(path) {
+        Ok(document) => Ok(Some(document)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(FetchError(format!("{}: {}", path.display(), e))),
+    }
+}
+
+async fn get(url: &str, token: Option<&str>) -> Result<String, FetchError> {
+    let mut request = reqwest::Client::new().get(url).timeout(FETCH_TIMEOUT);
+    if let Some(token) = token {
+        request = request.header("X-Consul-Token", token);
+    }
+    request
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| FetchError(format!("{}: {}", url, e)))?
+        .text()
+        .await
+        .map_err(|e| FetchError(format!("{}: {}", url, e)))
+}
+
+/// Copy of the last remote document fetched successfully, so a restart while the source is down
+/// still starts with the previous configuration
+#[derive(Debug)]
+pub struct LastGood {
+    path: Option<PathBuf>,
+    document: Option<String>,
+}
+
+impl LastGood {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            document: None,
+        }
+    }
+
+    pub fn store(&mut self, document: &str) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::write(path, document) {
+                warn!("unable to keep config at {}: {}", path.display(), e);
+            }
+        }
+        self.document = Some(document.to_owned());
+    }
+
+    /// In memory copy, or the one kept on disk by a previous run
+    pub fn load(&self) -> Option<String> {
+        if self.document.is_some() {
+            return self.document.clone();
+        }
+        let path = self.path.as_ref()?;
+        match read_file(path) {
+            Ok(document) => {
+                info!("using config kept at {}", path.display());
+                document
+            }
+            Err(FetchError(e)) => {
+                warn!("unable to read kept config {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test_case(
+        "./krewetka.yaml",
+        ConfigSource::File(PathBuf::from("./krewetka.yaml"))
+    )]
+    #[test_case("http://config:8080/collector.yaml", ConfigSource::Http("http://config:8080/collector.yaml".to_owned()))]
+    #[test_case("consul://localhost:8500/krewetka/collector", ConfigSource::Consul { address: "http://localhost:8500".to_owned(), key: "krewetka/collector".to_owned() })]
+    fn test_config_source_parse(location: &str, expected: ConfigSource) {
+        assert_eq!(ConfigSource::parse(location), expected);
+    }
+
+    /// Serves a single http response, enough for a single fetch
+    async fn serve_once(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn test_remote_documents_are_fetched() {
+        let url = serve_once("200 OK", "exporter:\n  destination: kafka\n").await;
+        assert_eq!(
+            ConfigSource::Http(format!("{}/collector.yaml", url))
+                .fetch()
+                .await
+                .unwrap(),
+            Some("exporter:\n  destination: kafka\n".to_owned())
+        );
+
+        let url = serve_once("404 Not Found", "").await;
+        let consul = ConfigSource::parse(&format!("consul://{}/krewetka", &url[7..]));
+        assert!(consul.fetch().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_file_is_empty_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = ConfigSource::File(dir.path().join("krewetka-missing.yaml"));
+        assert_eq!(source.fetch().await, Ok(None));
+    }
+
+    #[test]
+    fn test_last_good_copy_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("krewetka-last-good.yaml");
+
+        let mut last_good = LastGood::new(Some(path.clone()));
+        assert_eq!(last_good.load(), None);
+        last_good.store("importer:\n  source: zmq\n");
+
+        let restarted = LastGood::new(Some(path.clone()));
+        assert_eq!(
+            restarted.load(),
+            Some("importer:\n  source: zmq\n".to_owned())
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::{info, warn};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
+
+use common::parse::UnknownVariant;
+
+use super::errors::ExporterError;
+
+use crate::pb::FlowMessage;
+
+/// Avro schema of exported flows, consumers decode payloads with the same schema
+pub const FLOW_MESSAGE_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "FlowMessage",
+  "namespace": "krewetka.flow",
+  "fields": [
+    {"name": "out_bytes", "type": "long"},
+    {"name": "out_pkts", "type": "long"},
+    {"name": "in_bytes", "type": "long"},
+    {"name": "in_pkts", "type": "long"},
+    {"name": "ipv4_src_addr", "type": "string"},
+    {"name": "ipv4_dst_addr", "type": "string"},
+    {"name": "l7_proto", "type": "float"},
+    {"name": "l4_dst_port", "type": "long"},
+    {"name": "l4_src_port", "type": "long"},
+    {"name": "flow_duration_milliseconds", "type": "long"},
+    {"name": "protocol", "type": "long"},
+    {"name": "tcp_flags", "type": "long"}
+  ]
+}"#;
+
+/// Full name of the record, used by record based subject names
+const FLOW_MESSAGE_RECORD: &str = "krewetka.flow.FlowMessage";
+
+/// First byte of payloads framed for the schema registry
+const MAGIC_BYTE: u8 = 0;
+
+/// Longest wait between attempts to register the schema, waits double up to it
+const REGISTRY_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Encoding of exported payloads, consumers detect it by the `codec-x` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Protobuf,
+    /// avro framed with the id of the schema registered in the schema registry
+    Avro,
+}
+
+impl FromStr for Codec {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "protobuf" => Ok(Self::Protobuf),
+            "avro" => Ok(Self::Avro),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl Codec {
+    /// Value of the `codec-x` header, not set for protobuf payloads
+    pub fn header_value(&self) -> Option<&'static str> {
+        match self {
+            Self::Protobuf => None,
+            Self::Avro => Some("avro"),
+        }
+    }
+}
+
+/// How the subject the schema is registered under is named
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubjectNaming {
+    /// `<topic>-value`
+    #[default]
+    TopicName,
+    /// `krewetka.flow.FlowMessage`
+    RecordName,
+    /// `<topic>-krewetka.flow.FlowMessage`
+    TopicRecordName,
+}
+
+impl FromStr for SubjectNaming {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "topic_name" => Ok(Self::TopicName),
+            "record_name" => Ok(Self::RecordName),
+            "topic_record_name" => Ok(Self::TopicRecordName),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl SubjectNaming {
+    pub fn subject(&self, topic: &str) -> String {
+        match self {
+            Self::TopicName => format!("{}-value", topic),
+            Self::RecordName => FLOW_MESSAGE_RECORD.to_owned(),
+            Self::TopicRecordName => format!("{}-{}", topic, FLOW_MESSAGE_RECORD),
+        }
+    }
+}
+
+fn encode_long(value: i64, buf: &mut Vec<u8>) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        buf.push((zigzag as u8 & 0x7f) | 0x80);
+        zigzag >>= 7;
+    }
+    buf.push(zigzag as u8);
+}
+
+fn encode_string(value: &str, buf: &mut Vec<u8>) {
+    encode_long(value.len() as i64, buf);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Encodes a flow according to `FLOW_MESSAGE_SCHEMA`, unsigned counters are stored as longs
+pub fn encode_flow(f: &FlowMessage) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    encode_long(f.out_bytes as i64, &mut buf);
+    encode_long(f.out_pkts as i64, &mut buf);
+    encode_long(f.in_bytes as i64, &mut buf);
+    encode_long(f.in_pkts as i64, &mut buf);
+    encode_string(&f.ipv4_src_addr, &mut buf);
+    encode_string(&f.ipv4_dst_addr, &mut buf);
+    buf.extend_from_slice(&f.l7_proto.to_le_bytes());
+    encode_long(f.l4_dst_port as i64, &mut buf);
+    encode_long(f.l4_src_port as i64, &mut buf);
+    encode_long(f.flow_duration_milliseconds as i64, &mut buf);
+    encode_long(f.protocol as i64, &mut buf);
+    encode_long(f.tcp_flags as i64, &mut buf);
+    buf
+}
+
+/// Prefixes the payload with the magic byte and the big endian schema id
+pub fn frame(schema_id: u32, body: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(body.len() + 5);
+    framed.push(MAGIC_BYTE);
+    framed.extend_from_slice(&schema_id.to_be_bytes());
+    framed.extend_from_slice(body);
+    framed
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaRegistrySettings {
+    pub url: String,
+    pub subject: String,
+}
+
+#[derive(Deserialize)]
+struct RegisteredSchema {
+    id: u32,
+}
+
+/// Registry errors worth retrying, any other error status rejects the schema
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Registers the flow schema once and caches its id
+pub struct SchemaRegistry {
+    settings: SchemaRegistrySettings,
+    http: reqwest::Client,
+    schema_id: OnceCell<u32>,
+}
+
+impl fmt::Debug for SchemaRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.settings)
+    }
+}
+
+impl SchemaRegistry {
+    pub fn new(settings: SchemaRegistrySettings) -> Self {
+        Self {
+            settings,
+            http: reqwest::Client::new(),
+            schema_id: OnceCell::new(),
+        }
+    }
+
+    /// Id of the flow schema, registering an existing schema returns its id
+    pub async fn schema_id(&self) -> Result<u32, ExporterError> {
+        self.schema_id
+            .get_or_try_init(|| self.register())
+            .await
+            .copied()
+    }
+
+    /// Waits for an unavailable registry rather than losing flows, the exporter channel fills
+    /// up meanwhile and backpressures importers. A rejected schema is returned right away
+    pub async fn wait_for_schema_id(&self) -> Result<u32, ExporterError> {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match self.schema_id().await {
+                Err(ExporterError::RegistryUnavailable(e)) => {
+                    warn!(
+                        "schema registry is unavailable, retrying in {:?}: {}",
+                        delay, e
+                    );
+                    sleep(delay).await;
+                    delay = (delay * 2).min(REGISTRY_RETRY_MAX_DELAY);
+                }
+                registered => return registered,
+            }
+        }
+    }
+
+    async fn register(&self) -> Result<u32, ExporterError> {
+        let url = format!(
+            "{}/subjects/{}/versions",
+            self.settings.url.trim_end_matches('/'),
+            self.settings.subject
+        );
+        let body = serde_json::json!({ "schema": FLOW_MESSAGE_SCHEMA }).to_string();
+
+        let response = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/vnd.schemaregistry.v1+json")
+            .timeout(Duration::from_secs(10))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ExporterError::RegistryUnavailable(e.to_string()))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| ExporterError::RegistryUnavailable(e.to_string()))?;
+        if is_retryable(status) {
+            return Err(ExporterError::RegistryUnavailable(format!(
+                "{}: {}",
+                status, text
+            )));
+        }
+        if !status.is_success() {
+            return Err(ExporterError::RegistryErr(format!(
+                "schema rejected with {}: {}",
+                status, text
+            )));
+        }
+        let registered: RegisteredSchema = serde_json::from_str(&text).map_err(|e| {
+            ExporterError::RegistryErr(format!("unexpected response {}: {}", text, e))
+        })?;
+
+        info!(
+            "flow schema registered under subject {} with id {}",
+            self.settings.subject, registered.id
+        );
+        Ok(registered.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case(0, &[0x00])]
+    #[test_case(-1, &[0x01])]
+    #[test_case(1, &[0x02])]
+    #[test_case(-64, &[0x7f])]
+    #[test_case(64, &[0x80, 0x01])]
+    #[test_case(i64::MIN, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01])]
+    fn test_encode_long(value: i64, expected: &[u8]) {
+        let mut buf = Vec::new();
+        encode_long(value, &mut buf);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_encode_framed_flow() {
+        let flow = FlowMessage {
+            out_bytes: 77,
+            in_pkts: 1,
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            l7_proto: 5.0,
+            l4_dst_port: 53,
+            ..Default::default()
+        };
+        let framed = frame(7, &encode_flow(&flow));
+
+        let mut expected = vec![0, 0, 0, 0, 7, 154, 1, 0, 0, 2, 16];
+        expected.extend_from_slice(b"10.0.0.2");
+        expected.push(0);
+        expected.extend_from_slice(&5.0f32.to_le_bytes());
+        expected.extend_from_slice(&[106, 0, 0, 0, 0]);
+        assert_eq!(framed, expected);
+    }
+
+    #[test]
+    fn test_schema_is_valid_json() {
+        let schema: serde_json::Value = serde_json::from_str(FLOW_MESSAGE_SCHEMA).unwrap();
+        assert_eq!(schema["fields"].as_array().unwrap().len(), 12);
+    }
+
+    #[test_case(StatusCode::SERVICE_UNAVAILABLE, true)]
+    #[test_case(StatusCode::TOO_MANY_REQUESTS, true)]
+    #[test_case(StatusCode::CONFLICT, false)]
+    #[test_case(StatusCode::UNPROCESSABLE_ENTITY, false)]
+    fn test_only_unavailable_registry_is_retried(status: StatusCode, retryable: bool) {
+        assert_eq!(is_retryable(status), retryable);
+    }
+
+    #[test_case(SubjectNaming::TopicName, "flows-value")]
+    #[test_case(SubjectNaming::RecordName, "krewetka.flow.FlowMessage")]
+    #[test_case(SubjectNaming::TopicRecordName, "flows-krewetka.flow.FlowMessage")]
+    fn test_subject_naming(naming: SubjectNaming, expected: &str) {
+        assert_eq!(naming.subject("flows"), expected);
+    }
+}
@@ -0,0 +1,66 @@
+use std::str::FromStr;
+
+use common::hash::crc32;
+use common::parse::UnknownVariant;
+
+/// Checksum of exported payloads, sent in the `checksum-x` header so consumers can detect corruption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    None,
+    #[default]
+    Crc32,
+}
+
+impl FromStr for Checksum {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "crc32" => Ok(Self::Crc32),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl Checksum {
+    /// Value of the `checksum-x` header, the algorithm and the hex digest, e.g. `crc32:cbf43926`.
+    /// Computed over the payload as sent, after compression
+    pub fn header_value(&self, payload: &[u8]) -> Option<String> {
+        match self {
+            Self::None => None,
+            Self::Crc32 => Some(format!("crc32:{:08x}", crc32(payload))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case("none", Ok(Checksum::None))]
+    #[test_case("crc32", Ok(Checksum::Crc32))]
+    #[test_case("xxhash", Err(UnknownVariant("xxhash".to_owned())))]
+    fn test_checksum_from_str(input: &str, expected: Result<Checksum, UnknownVariant>) {
+        assert_eq!(input.parse::<Checksum>(), expected);
+    }
+
+    #[test]
+    fn test_flipped_byte_changes_checksum() {
+        assert_eq!(
+            Checksum::Crc32.header_value(b"123456789"),
+            Some("crc32:cbf43926".to_owned())
+        );
+        assert_eq!(Checksum::None.header_value(b"123456789"), None);
+
+        let payload = b"flow message payload".to_vec();
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 0x01;
+        assert_ne!(
+            Checksum::Crc32.header_value(&payload),
+            Checksum::Crc32.header_value(&corrupted)
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::write::GzEncoder;
+
+use common::parse::UnknownVariant;
+
+/// Compression applied to exported payloads or files, readers detect it by the header or extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "pb",
+            Self::Gzip => "pb.gz",
+            Self::Zstd => "pb.zst",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        [Self::Gzip, Self::Zstd, Self::None]
+            .into_iter()
+            .find(|c| name.ends_with(&format!(".{}", c.extension())))
+    }
+
+    /// Value of the `compression-x` header, not set for uncompressed payloads
+    pub fn header_value(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip"),
+            Self::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Compresses a single payload as a complete gzip or zstd frame
+    pub fn compress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(payload.to_vec()),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::encode_all(payload, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+    use test_case::test_case;
+
+    #[test_case("none", Ok(Compression::None))]
+    #[test_case("gzip", Ok(Compression::Gzip))]
+    #[test_case("zstd", Ok(Compression::Zstd))]
+    #[test_case("lz4", Err(UnknownVariant("lz4".to_owned())))]
+    fn test_compression_from_str(input: &str, expected: Result<Compression, UnknownVariant>) {
+        assert_eq!(input.parse::<Compression>(), expected);
+    }
+
+    #[test_case("flows-1-000001.pb", Some(Compression::None))]
+    #[test_case("flows-1-000001.pb.gz", Some(Compression::Gzip))]
+    #[test_case("flows-1-000001.pb.zst", Some(Compression::Zstd))]
+    #[test_case("flows-1-000001.json", None)]
+    fn test_compression_from_path(name: &str, expected: Option<Compression>) {
+        assert_eq!(Compression::from_path(Path::new(name)), expected);
+    }
+
+    #[test]
+    fn test_compressed_payloads_decompress() {
+        let payload = b"flow message payload".repeat(10);
+
+        let mut gzip = Vec::new();
+        flate2::read::GzDecoder::new(Compression::Gzip.compress(&payload).unwrap().as_slice())
+            .read_to_end(&mut gzip)
+            .unwrap();
+        assert_eq!(gzip, payload);
+
+        let zstd = zstd::decode_all(Compression::Zstd.compress(&payload).unwrap().as_slice());
+        assert_eq!(zstd.unwrap(), payload);
+    }
+}
@@ -3,6 +3,14 @@ use rdkafka::error::KafkaError;
 #[derive(Debug)]
 pub enum ExporterError {
     KafkaErr(KafkaError),
+    IoErr(std::io::Error),
+    DecodeErr(prost::DecodeError),
+    /// schema registry rejected the schema
+    RegistryErr(String),
+    /// schema registry is unreachable or failed, registering may succeed once it is back
+    RegistryUnavailable(String),
+    /// topic is missing and could not be created
+    TopicErr(String),
 }
 
 impl From<KafkaError> for ExporterError {
@@ -10,3 +18,15 @@ impl From<KafkaError> for ExporterError {
         ExporterError::KafkaErr(error)
     }
 }
+
+impl From<prost::DecodeError> for ExporterError {
+    fn from(error: prost::DecodeError) -> ExporterError {
+        ExporterError::DecodeErr(error)
+    }
+}
+
+impl From<std::io::Error> for ExporterError {
+    fn from(error: std::io::Error) -> ExporterError {
+        ExporterError::IoErr(error)
+    }
+}
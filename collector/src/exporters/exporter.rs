@@ -1,5 +1,4 @@
 use super::errors::ExporterError;
-use crate::application_state::HostIdentifier;
 use async_trait::async_trait;
 use log::{debug, info};
 use std::sync::{Arc, Mutex};
@@ -9,12 +8,43 @@ use tokio::time::Duration;
 
 #[async_trait]
 pub trait Export: Sync + Send {
-    async fn export(&self, message: &[u8], identifier: &str) -> Result<(), ExporterError>;
+    /// Message received from the importer channel
+    type Item: Send + Sync;
+
+    async fn export(&self, message: &Self::Item, identifier: &str) -> Result<(), ExporterError>;
+
+    /// Prepares the destination, called once before the first message is exported
+    async fn prepare(&self) -> Result<(), ExporterError> {
+        Ok(())
+    }
+
+    /// Waits until exported messages are delivered, called once on shutdown
+    async fn flush(&self) -> Result<(), ExporterError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<I: Send + Sync> Export for Box<dyn Export<Item = I>> {
+    type Item = I;
+
+    async fn export(&self, message: &I, identifier: &str) -> Result<(), ExporterError> {
+        self.as_ref().export(message, identifier).await
+    }
+
+    async fn prepare(&self) -> Result<(), ExporterError> {
+        self.as_ref().prepare().await
+    }
+
+    async fn flush(&self) -> Result<(), ExporterError> {
+        self.as_ref().flush().await
+    }
 }
 
-pub async fn run(exporter: impl Export, rx: &mut Receiver<Vec<u8>>, identifier: &HostIdentifier) {
+/// Exports until the channel is closed and drained, the exporter is handed back to be flushed.
+/// Every message is exported as sent by the host of the identifier
+pub async fn run<E: Export>(exporter: E, rx: &mut Receiver<E::Item>, identifier: &str) -> E {
     info!("Spawned exporter...");
-    let identifier = &String::from(identifier);
 
     let current = Arc::new(Mutex::new(0));
     let current_clone = current.clone();
@@ -41,24 +71,43 @@ pub async fn run(exporter: impl Export, rx: &mut Receiver<Vec<u8>>, identifier:
         }
     }
 
-    timer.await;
+    timer.abort();
     info!("Closing exporter...");
+    exporter
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use mockall::mock;
+    use tokio::sync::mpsc::channel;
 
     mock! {
         pub Exporter {}
 
         #[async_trait]
         impl Export for Exporter {
-            async fn export(&self, message: &[u8], identifier: &str) -> Result<(), ExporterError>;
+            type Item = Vec<u8>;
+
+            async fn export(&self, message: &Vec<u8>, identifier: &str) -> Result<(), ExporterError>;
+            async fn flush(&self) -> Result<(), ExporterError>;
         }
     }
 
+    #[tokio::test]
+    async fn test_run_exports_until_channel_is_closed() {
+        let mut exporter = MockExporter::new();
+        exporter.expect_export().times(3).returning(|_, _| Ok(()));
+
+        let (tx, mut rx) = channel::<Vec<u8>>(10);
+        for i in 0..3u8 {
+            tx.send(vec![i]).await.unwrap();
+        }
+        drop(tx);
+
+        run(exporter, &mut rx, "host").await;
+    }
+
     // #[test]
     // fn test_export_runner() {
     //     let mut exporter = MockExporter::new();
@@ -0,0 +1,199 @@
+use core::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use log::{error, info};
+
+use super::compression::Compression;
+use super::errors::ExporterError;
+use super::exporter::Export;
+
+const FILE_PREFIX: &str = "flows";
+
+#[derive(Debug, Clone)]
+pub struct FileSettings {
+    pub dir: PathBuf,
+    /// size after which the file is rotated, counted in bytes written to disk
+    pub max_file_bytes: u64,
+    pub compression: Compression,
+    pub write_buffer_bytes: usize,
+}
+
+/// Counts bytes passed to the inner writer
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+type FileWriter = CountingWriter<BufWriter<File>>;
+
+enum Sink {
+    Plain(FileWriter),
+    Gzip(GzEncoder<FileWriter>),
+    Zstd(zstd::Encoder<'static, FileWriter>),
+}
+
+impl Sink {
+    fn open(path: &Path, settings: &FileSettings) -> io::Result<Self> {
+        let file = File::create(path)?;
+        let writer = CountingWriter {
+            inner: BufWriter::with_capacity(settings.write_buffer_bytes, file),
+            written: 0,
+        };
+
+        Ok(match settings.compression {
+            Compression::None => Sink::Plain(writer),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Sink::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Sink::Plain(w) => w,
+            Sink::Gzip(w) => w,
+            Sink::Zstd(w) => w,
+        }
+    }
+
+    /// Bytes which left the compressor, it may still hold some data internally
+    fn written(&self) -> u64 {
+        match self {
+            Sink::Plain(w) => w.written,
+            Sink::Gzip(w) => w.get_ref().written,
+            Sink::Zstd(w) => w.get_ref().written,
+        }
+    }
+
+    /// Writes the compression footer and flushes everything to disk
+    fn finish(self) -> io::Result<()> {
+        let mut writer = match self {
+            Sink::Plain(w) => w,
+            Sink::Gzip(w) => w.finish()?,
+            Sink::Zstd(w) => w.finish()?,
+        };
+        writer.flush()?;
+        writer.inner.get_ref().sync_all()
+    }
+}
+
+struct OpenFile {
+    path: PathBuf,
+    sink: Sink,
+}
+
+/// Writes flows as length delimited protobuf messages to files rotated by size
+pub struct FileExporter {
+    settings: FileSettings,
+    current: Mutex<Option<OpenFile>>,
+    sequence: Mutex<u64>,
+}
+
+impl fmt::Debug for FileExporter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.settings)
+    }
+}
+
+impl FileExporter {
+    pub fn new(settings: FileSettings) -> Result<FileExporter, ExporterError> {
+        fs::create_dir_all(&settings.dir)?;
+
+        Ok(FileExporter {
+            settings,
+            current: Mutex::new(None),
+            sequence: Mutex::new(0),
+        })
+    }
+
+    fn next_path(&self) -> PathBuf {
+        let mut sequence = self.sequence.lock().unwrap();
+        *sequence += 1;
+        self.settings.dir.join(format!(
+            "{}-{}-{:06}.{}",
+            FILE_PREFIX,
+            Utc::now().timestamp_millis(),
+            *sequence,
+            self.settings.compression.extension()
+        ))
+    }
+
+    fn write(&self, msg: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+
+        let rotate = current
+            .as_ref()
+            .map_or(false, |f| f.sink.written() >= self.settings.max_file_bytes);
+        if rotate {
+            let finished = current.take().unwrap();
+            finished.sink.finish()?;
+            info!("rotated flow file {}", finished.path.display());
+        }
+
+        if current.is_none() {
+            let path = self.next_path();
+            let sink = Sink::open(&path, &self.settings)?;
+            *current = Some(OpenFile { path, sink });
+        }
+
+        let mut delimiter = Vec::with_capacity(prost::length_delimiter_len(msg.len()));
+        prost::encode_length_delimiter(msg.len(), &mut delimiter)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let writer = current.as_mut().unwrap().sink.writer();
+        writer.write_all(&delimiter)?;
+        writer.write_all(msg)
+    }
+}
+
+impl Drop for FileExporter {
+    fn drop(&mut self) {
+        if let Some(file) = self.current.lock().unwrap().take() {
+            if let Err(e) = file.sink.finish() {
+                error!(
+                    "unable to finalize flow file {}: {}",
+                    file.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Export for FileExporter {
+    type Item = Vec<u8>;
+
+    async fn export(&self, msg: &Vec<u8>, _identifier: &str) -> Result<(), ExporterError> {
+        self.write(msg).map_err(|e| {
+            error!("unable to write message to file: {}", e);
+            ExporterError::from(e)
+        })
+    }
+
+    /// Finalizes the open file, so it is complete even if the process is killed afterwards
+    async fn flush(&self) -> Result<(), ExporterError> {
+        if let Some(file) = self.current.lock().unwrap().take() {
+            file.sink.finish()?;
+            info!("finalized flow file {}", file.path.display());
+        }
+        Ok(())
+    }
+}
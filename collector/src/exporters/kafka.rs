@@ -1,33 +1,164 @@
+use prost::Message as _;
 use rdkafka::Message;
 use std::fmt;
 
 use async_trait::async_trait;
 use chrono::Utc;
 
-use log::error;
-use rdkafka::config::ClientConfig;
+use log::{error, info, log, Level};
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
 use rdkafka::message::OwnedHeaders;
-use rdkafka::producer::{BaseRecord, DefaultProducerContext, ThreadedProducer};
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::ClientContext;
 use uuid::Uuid;
 
+use super::avro::{encode_flow, frame, Codec, SchemaRegistry, SchemaRegistrySettings};
+use super::checksum::Checksum;
+use super::compression::Compression;
 use super::errors::ExporterError;
 use super::exporter::Export;
+use super::key::{bucketed_key, message_key, KeyField, KeyHash, Partitioner};
+
+use crate::metrics::{KAFKA_DELIVERIES, KAFKA_INFLIGHT_PRODUCES, PAYLOAD_BYTES};
+use crate::pb::FlowMessage;
+use crate::shutdown::FLUSH_EXPORTER_TIMEOUT;
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+const TOPIC_CREATION_TIMEOUT: Duration = Duration::from_secs(10);
+/// librdkafka default of `queue.buffering.max.messages`
+const PRODUCER_QUEUE_MESSAGES: usize = 100_000;
+
+/// Topic created at startup when it does not exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicCreation {
+    pub partitions: i32,
+    pub replication_factor: i32,
+}
 
 #[derive(Debug, Clone)]
 pub struct KafkaSettings {
     pub brokers: Vec<String>,
     pub topic: String,
+    pub key_field: KeyField,
+    /// key is prefixed with the start of the time bucket of this length the message falls in
+    pub time_bucket_ms: Option<u64>,
+    pub key_hash: KeyHash,
+    pub client_id: String,
+    /// compression of payloads, applied on top of kafka compression of batches
+    pub compression: Compression,
+    /// checksum of payloads verified by processor
+    pub checksum: Checksum,
+    /// librdkafka default is used when not set
+    pub partitioner: Option<Partitioner>,
+    pub codec: Codec,
+    /// required by the avro codec
+    pub schema_registry: Option<SchemaRegistrySettings>,
+    /// topic has to exist when not set
+    pub topic_creation: Option<TopicCreation>,
+    /// produces waiting for their delivery report, exporting waits once it is reached.
+    /// Unbounded when not set, messages which do not fit the producer queue are lost
+    pub max_inflight_produces: Option<usize>,
 }
 
 impl KafkaSettings {
     pub fn get_brokers_kafka_format(&self) -> String {
         self.brokers.join(",")
     }
+
+    fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", self.get_brokers_kafka_format())
+            .set("client.id", &self.client_id);
+        config
+    }
+}
+
+/// Header telling consumers how the payload was compressed
+const COMPRESSION_HEADER: &str = "compression-x";
+/// Header telling consumers how the payload was encoded, protobuf when missing
+const CODEC_HEADER: &str = "codec-x";
+/// Header carrying the checksum of the payload as sent
+const CHECKSUM_HEADER: &str = "checksum-x";
+
+/// Client id telling producers apart in broker metrics, kafka allows only `[a-zA-Z0-9._-]`
+pub fn default_client_id(identifier: &str, topic: &str) -> String {
+    format!("krewetka-{}-{}", identifier, topic)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Forwards librdkafka logs and errors to our logger tagged with the client id,
+/// reports messages which failed to be delivered
+pub struct ProducerLogContext {
+    client_id: String,
+    /// permits of in-flight produces, given back once a message is reported
+    inflight: Option<Arc<Semaphore>>,
+}
+
+impl ClientContext for ProducerLogContext {
+    fn log(&self, level: RDKafkaLogLevel, fac: &str, log_message: &str) {
+        let level = match level {
+            RDKafkaLogLevel::Emerg
+            | RDKafkaLogLevel::Alert
+            | RDKafkaLogLevel::Critical
+            | RDKafkaLogLevel::Error => Level::Error,
+            RDKafkaLogLevel::Warning => Level::Warn,
+            RDKafkaLogLevel::Notice | RDKafkaLogLevel::Info => Level::Info,
+            RDKafkaLogLevel::Debug => Level::Debug,
+        };
+        log!(target: "librdkafka", level, "[{}] {} {}", self.client_id, fac, log_message);
+    }
+
+    fn error(&self, error: KafkaError, reason: &str) {
+        error!(target: "librdkafka", "[{}] {}: {}", self.client_id, error, reason);
+    }
+}
+
+impl ProducerLogContext {
+    fn release(&self) {
+        KAFKA_INFLIGHT_PRODUCES.dec();
+        if let Some(inflight) = &self.inflight {
+            inflight.add_permits(1);
+        }
+    }
+}
+
+impl ProducerContext for ProducerLogContext {
+    type DeliveryOpaque = ();
+
+    /// Called from the polling thread of the producer for every produced message
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _delivery_opaque: ()) {
+        match delivery_result {
+            Ok(_) => KAFKA_DELIVERIES.with_label_values(&["delivered"]).inc(),
+            Err((e, msg)) => {
+                error!(
+                    "[{}] message to [{}] was not delivered: {}",
+                    self.client_id,
+                    msg.topic(),
+                    e
+                );
+                KAFKA_DELIVERIES.with_label_values(&["failed"]).inc();
+            }
+        }
+        self.release();
+    }
 }
 
 pub struct KafkaExporter {
     settings: KafkaSettings,
-    producer: ThreadedProducer<DefaultProducerContext>,
+    producer: ThreadedProducer<ProducerLogContext>,
+    schema_registry: Option<SchemaRegistry>,
 }
 
 impl fmt::Debug for KafkaExporter {
@@ -37,37 +168,233 @@ impl fmt::Debug for KafkaExporter {
 }
 impl KafkaExporter {
     pub fn new(settings: KafkaSettings) -> Result<KafkaExporter, ExporterError> {
-        let producer: ThreadedProducer<DefaultProducerContext> = ClientConfig::new()
-            .set("bootstrap.servers", settings.get_brokers_kafka_format())
-            .set("message.timeout.ms", "5000")
-            // .set("queue.buffering.max.ms", "10")
-            // .set("queue.buffering.max.messages", "1000")
-            .create()
+        let context = ProducerLogContext {
+            client_id: settings.client_id.clone(),
+            inflight: settings
+                .max_inflight_produces
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+        };
+        let mut config = settings.client_config();
+        config.set("message.timeout.ms", "5000");
+        if let Some(limit) = settings.max_inflight_produces {
+            // in-flight produces have to fit the queue, so sending never fails with a full queue
+            config.set(
+                "queue.buffering.max.messages",
+                limit.max(PRODUCER_QUEUE_MESSAGES).to_string(),
+            );
+            info!("produces are streamed, at most {} wait for delivery", limit);
+        }
+        // .set("queue.buffering.max.ms", "10")
+        // .set("queue.buffering.max.messages", "1000")
+        if let Some(partitioner) = settings.partitioner {
+            config.set("partitioner", partitioner.as_str());
+        }
+
+        let producer: ThreadedProducer<ProducerLogContext> = config
+            .create_with_context(context)
             .expect("Producer creation error");
 
-        Ok(KafkaExporter { settings, producer })
+        let schema_registry = settings.schema_registry.clone().map(SchemaRegistry::new);
+
+        Ok(KafkaExporter {
+            settings,
+            producer,
+            schema_registry,
+        })
+    }
+
+    fn topic_exists(&self) -> Result<bool, ExporterError> {
+        let metadata = tokio::task::block_in_place(|| {
+            self.producer
+                .client()
+                .fetch_metadata(Some(&self.settings.topic), TOPIC_CREATION_TIMEOUT)
+        })?;
+        // brokers with auto creation disabled report a missing topic with an error
+        Ok(metadata
+            .topics()
+            .iter()
+            .any(|t| t.name() == self.settings.topic && t.error().is_none()))
+    }
+
+    async fn create_topic(&self, creation: &TopicCreation) -> Result<(), ExporterError> {
+        let topic = &self.settings.topic;
+        let admin: AdminClient<DefaultClientContext> = self.settings.client_config().create()?;
+        let new_topic = NewTopic::new(
+            topic,
+            creation.partitions,
+            TopicReplication::Fixed(creation.replication_factor),
+        );
+        let options = AdminOptions::new().operation_timeout(Some(TOPIC_CREATION_TIMEOUT));
+
+        for result in admin.create_topics(&[new_topic], &options).await? {
+            match result {
+                Ok(_) => info!(
+                    "created topic {} with {} partitions and replication factor {}",
+                    topic, creation.partitions, creation.replication_factor
+                ),
+                // another collector created it in the meantime
+                Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((name, code)) => {
+                    return Err(ExporterError::TopicErr(format!(
+                        "unable to create topic {}: {}",
+                        name, code
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Payloads arrive protobuf encoded from importers
+    async fn encode(&self, msg: &[u8]) -> Result<Vec<u8>, ExporterError> {
+        match self.settings.codec {
+            Codec::Protobuf => Ok(msg.to_vec()),
+            Codec::Avro => {
+                let registry = self.schema_registry.as_ref().ok_or_else(|| {
+                    ExporterError::RegistryErr("avro codec requires a schema registry".to_owned())
+                })?;
+                let flow = FlowMessage::decode(msg)?;
+                Ok(frame(
+                    registry.wait_for_schema_id().await?,
+                    &encode_flow(&flow),
+                ))
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Export for KafkaExporter {
-    async fn export(&self, msg: &[u8], identifier: &str) -> Result<(), ExporterError> {
+    type Item = Vec<u8>;
+
+    async fn export(&self, msg: &Vec<u8>, identifier: &str) -> Result<(), ExporterError> {
         // send event to kafka
-        let key = format!("KREWETKA-{}", &Uuid::new_v4().to_string());
+        let timestamp = Utc::now().timestamp_millis();
+        let key = match self.settings.time_bucket_ms {
+            Some(bucket_ms) => bucketed_key(
+                self.settings.key_field,
+                msg,
+                identifier,
+                timestamp,
+                bucket_ms,
+            ),
+            None => message_key(self.settings.key_field, msg, identifier),
+        };
+        let key = self.settings.key_hash.apply(key);
+        let encoded = self.encode(msg).await.map_err(|e| {
+            error!("unable to encode message: {:?}", e);
+            e
+        })?;
+        let payload = self.settings.compression.compress(&encoded)?;
+
+        let mut headers = OwnedHeaders::new()
+            .add::<str>("host-identifier-x", identifier)
+            .add::<str>("message-id-x", &Uuid::new_v4().to_string())
+            .add::<str>("timestamp-x", &timestamp.to_string())
+            .add::<str>("retry-x", &0.to_string()); // .add::<bool>("proto-encoding-x", true)
+        if let Some(compression) = self.settings.compression.header_value() {
+            headers = headers.add::<str>(COMPRESSION_HEADER, compression);
+        }
+        if let Some(codec) = self.settings.codec.header_value() {
+            headers = headers.add::<str>(CODEC_HEADER, codec);
+        }
+        if let Some(checksum) = self.settings.checksum.header_value(&payload) {
+            headers = headers.add::<str>(CHECKSUM_HEADER, &checksum);
+        }
+
+        PAYLOAD_BYTES
+            .with_label_values(&["exported"])
+            .observe(payload.len() as f64);
+        // sending never waits for delivery, it waits only for a free slot once the limit is
+        // reached, which backpressures the importer through the exporter channel
+        if let Some(inflight) = &self.producer.context().inflight {
+            inflight
+                .acquire()
+                .await
+                .expect("in-flight produces are never closed")
+                .forget();
+        }
+        KAFKA_INFLIGHT_PRODUCES.inc();
+
         let record = BaseRecord::to(&self.settings.topic)
-            .payload(msg)
+            .payload(&payload)
             .key(&key)
-            .headers(
-                OwnedHeaders::new()
-                    .add::<str>("host-identifier-x", identifier)
-                    .add::<str>("message-id-x", &Uuid::new_v4().to_string())
-                    .add::<str>("timestamp-x", &Utc::now().timestamp_millis().to_string())
-                    .add::<str>("retry-x", &0.to_string()), // .add::<bool>("proto-encoding-x", true)
-            );
+            .headers(headers);
 
         self.producer.send(record).map_err(|(e, record)| {
+            // a message which was not queued gets no delivery report
+            self.producer.context().release();
             error!("Unable to send message: {}\nPayload: {:?}", e, record);
             ExporterError::from(e)
         })
     }
+
+    /// Creates the topic when it is missing and creation is enabled
+    async fn prepare(&self) -> Result<(), ExporterError> {
+        let creation = match &self.settings.topic_creation {
+            Some(creation) => creation,
+            None => return Ok(()),
+        };
+        if self.topic_exists()? {
+            return Ok(());
+        }
+        self.create_topic(creation).await.map_err(|e| {
+            error!(
+                "topic {} does not exist and could not be created: {:?}",
+                self.settings.topic, e
+            );
+            e
+        })
+    }
+
+    async fn flush(&self) -> Result<(), ExporterError> {
+        // flushing blocks until the queue is delivered or the timeout passes
+        let undelivered = tokio::task::block_in_place(|| {
+            self.producer.flush(FLUSH_EXPORTER_TIMEOUT);
+            self.producer.in_flight_count()
+        });
+        if undelivered > 0 {
+            error!(
+                "{} messages were not delivered before shutdown",
+                undelivered
+            );
+            return Err(ExporterError::KafkaErr(KafkaError::MessageProduction(
+                RDKafkaErrorCode::MessageTimedOut,
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_default_client_id() {
+        assert_eq!(
+            default_client_id("rpi-5.15.0-1034-raspi", "flows"),
+            "krewetka-rpi-5.15.0-1034-raspi-flows"
+        );
+        assert_eq!(
+            default_client_id("my host-5.15+", "flows"),
+            "krewetka-my_host-5.15_-flows"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reported_produces_free_their_slot() {
+        let inflight = Arc::new(Semaphore::new(1));
+        let context = ProducerLogContext {
+            client_id: "test".to_owned(),
+            inflight: Some(inflight.clone()),
+        };
+
+        inflight.acquire().await.unwrap().forget();
+        assert!(inflight.try_acquire().is_err());
+
+        context.release();
+        assert!(inflight.try_acquire().is_ok());
+    }
 }
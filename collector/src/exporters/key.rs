@@ -0,0 +1,216 @@
+use std::str::FromStr;
+
+use prost::Message;
+use uuid::Uuid;
+
+use common::hash::{crc32, murmur2};
+pub use common::keys::KeyField;
+use common::keys::KeyParts;
+use common::parse::UnknownVariant;
+
+use crate::pb::FlowMessage;
+
+/// Hash applied to the key before it is sent.
+/// `murmur2` matches the hash of the default partitioner of the java kafka client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyHash {
+    #[default]
+    None,
+    Murmur2,
+    Crc32,
+}
+
+/// Partitioner of the producer, librdkafka picks `consistent_random` when it is not set.
+/// `murmur2_random` places keyed messages like the java kafka client does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partitioner {
+    Random,
+    Consistent,
+    ConsistentRandom,
+    Murmur2,
+    Murmur2Random,
+    Fnv1a,
+    Fnv1aRandom,
+}
+
+impl FromStr for KeyHash {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "murmur2" => Ok(Self::Murmur2),
+            "crc32" => Ok(Self::Crc32),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for Partitioner {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "consistent" => Ok(Self::Consistent),
+            "consistent_random" => Ok(Self::ConsistentRandom),
+            "murmur2" => Ok(Self::Murmur2),
+            "murmur2_random" => Ok(Self::Murmur2Random),
+            "fnv1a" => Ok(Self::Fnv1a),
+            "fnv1a_random" => Ok(Self::Fnv1aRandom),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl Partitioner {
+    /// Value of librdkafka `partitioner` property
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Random => "random",
+            Self::Consistent => "consistent",
+            Self::ConsistentRandom => "consistent_random",
+            Self::Murmur2 => "murmur2",
+            Self::Murmur2Random => "murmur2_random",
+            Self::Fnv1a => "fnv1a",
+            Self::Fnv1aRandom => "fnv1a_random",
+        }
+    }
+}
+
+/// Kafka message key of the flow. `random` and flows which can't be decoded get a random key,
+/// undecodable flows are still exported, just not colocated
+pub fn message_key(field: KeyField, msg: &[u8], identifier: &str) -> String {
+    let key = match field {
+        KeyField::Random => None,
+        KeyField::Host => field.key(&KeyParts {
+            host: identifier,
+            ..Default::default()
+        }),
+        KeyField::SrcIp | KeyField::FiveTuple => FlowMessage::decode(msg).ok().and_then(|f| {
+            field.key(&KeyParts {
+                host: identifier,
+                ipv4_src_addr: &f.ipv4_src_addr,
+                l4_src_port: f.l4_src_port,
+                ipv4_dst_addr: &f.ipv4_dst_addr,
+                l4_dst_port: f.l4_dst_port,
+                protocol: f.protocol,
+            })
+        }),
+    };
+    key.unwrap_or_else(|| format!("KREWETKA-{}", Uuid::new_v4()))
+}
+
+/// Start of the bucket of `bucket_ms` the timestamp falls in, buckets are aligned to the epoch
+pub fn time_bucket(timestamp_ms: i64, bucket_ms: u64) -> i64 {
+    let bucket_ms = bucket_ms as i64;
+    timestamp_ms.div_euclid(bucket_ms) * bucket_ms
+}
+
+/// Key prefixed with the start of the time bucket of the message, so messages of a bucket
+/// share a partition. A random key is replaced by the bucket alone
+pub fn bucketed_key(
+    field: KeyField,
+    msg: &[u8],
+    identifier: &str,
+    timestamp_ms: i64,
+    bucket_ms: u64,
+) -> String {
+    let bucket = time_bucket(timestamp_ms, bucket_ms);
+    match field {
+        KeyField::Random => bucket.to_string(),
+        _ => format!("{}-{}", bucket, message_key(field, msg, identifier)),
+    }
+}
+
+impl KeyHash {
+    /// Key bytes sent to kafka, hashes are encoded as big endian u32
+    pub fn apply(&self, key: String) -> Vec<u8> {
+        match self {
+            Self::None => key.into_bytes(),
+            Self::Murmur2 => murmur2(key.as_bytes()).to_be_bytes().to_vec(),
+            Self::Crc32 => crc32(key.as_bytes()).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case("murmur2_random", Ok(Partitioner::Murmur2Random))]
+    #[test_case("consistent_random", Ok(Partitioner::ConsistentRandom))]
+    #[test_case("fnv1a", Ok(Partitioner::Fnv1a))]
+    #[test_case("default", Err(UnknownVariant("default".to_owned())))]
+    fn test_partitioner_from_str(input: &str, expected: Result<Partitioner, UnknownVariant>) {
+        let parsed = input.parse::<Partitioner>();
+        assert_eq!(parsed, expected);
+        if let Ok(p) = parsed {
+            assert_eq!(p.as_str(), input);
+        }
+    }
+
+    #[test]
+    fn test_key_hash_apply() {
+        assert_eq!(KeyHash::None.apply("abc".to_owned()), b"abc".to_vec());
+        assert_eq!(
+            KeyHash::Murmur2.apply("abc".to_owned()),
+            479470107u32.to_be_bytes().to_vec()
+        );
+        assert_eq!(
+            KeyHash::Crc32.apply("123456789".to_owned()),
+            vec![0xcb, 0xf4, 0x39, 0x26]
+        );
+    }
+
+    #[test]
+    fn test_message_key() {
+        let flow = FlowMessage {
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            ipv4_dst_addr: "10.0.0.1".to_owned(),
+            l4_src_port: 56341,
+            l4_dst_port: 53,
+            protocol: 17,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        flow.encode(&mut buf).unwrap();
+
+        assert_eq!(
+            message_key(KeyField::FiveTuple, &buf, "host"),
+            "10.0.0.2:56341-10.0.0.1:53-17"
+        );
+        assert_eq!(message_key(KeyField::SrcIp, &buf, "host"), "10.0.0.2");
+        assert_eq!(message_key(KeyField::Host, &buf, "host"), "host");
+        assert!(message_key(KeyField::Random, &buf, "host").starts_with("KREWETKA-"));
+        assert!(message_key(KeyField::SrcIp, b"\xff", "host").starts_with("KREWETKA-"));
+    }
+
+    #[test_case(1_669_990_799_999, 1_669_990_740_000 ; "last millisecond of a minute")]
+    #[test_case(1_669_990_800_000, 1_669_990_800_000 ; "start of a minute")]
+    #[test_case(1_669_990_800_001, 1_669_990_800_000 ; "first millisecond after start")]
+    #[test_case(0, 0 ; "epoch")]
+    #[test_case(-1, -60_000 ; "before epoch")]
+    fn test_time_bucket_of_minute(timestamp_ms: i64, expected: i64) {
+        assert_eq!(time_bucket(timestamp_ms, 60_000), expected);
+    }
+
+    #[test]
+    fn test_bucketed_key() {
+        let ts = 1_669_990_812_345;
+        assert_eq!(
+            bucketed_key(KeyField::Random, b"", "host", ts, 60_000),
+            "1669990800000"
+        );
+        assert_eq!(
+            bucketed_key(KeyField::Host, b"", "host", ts, 60_000),
+            "1669990800000-host"
+        );
+        assert_eq!(
+            bucketed_key(KeyField::Host, b"", "host", ts, 1_000),
+            "1669990812000-host"
+        );
+    }
+}
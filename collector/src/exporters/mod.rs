@@ -1,5 +1,17 @@
+pub mod avro;
+pub mod checksum;
+pub mod compression;
 mod errors;
 mod exporter;
+pub mod file;
 pub mod kafka;
+pub mod key;
+pub mod shadow;
+pub use avro::{Codec, SchemaRegistrySettings, SubjectNaming};
+pub use checksum::Checksum;
+pub use compression::Compression;
+pub use errors::ExporterError;
 pub use exporter::{run, Export};
-pub use kafka::{KafkaExporter, KafkaSettings};
+pub use file::{FileExporter, FileSettings};
+pub use kafka::{default_client_id, KafkaExporter, KafkaSettings, TopicCreation};
+pub use shadow::Shadowed;
@@ -0,0 +1,188 @@
+use super::errors::ExporterError;
+use super::exporter::Export;
+use crate::metrics::SHADOW_EXPORTS;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Sampled messages waiting for the candidate, the ones which do not fit are dropped
+pub const SHADOW_CHANNEL_CAPACITY: usize = 1024;
+
+/// Picks `percent` of every 100 messages, spread evenly rather than in a run
+#[derive(Debug)]
+pub struct Sampler {
+    percent: u64,
+    seen: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(percent: u8) -> Self {
+        Self {
+            percent: u64::from(percent.min(100)),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sampled(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        (n + 1) * self.percent / 100 > n * self.percent / 100
+    }
+}
+
+/// Production exporter tee'ing a sample of messages to a candidate exporter running in its
+/// own task. The candidate can not slow down or fail exports of the production one, its
+/// messages are dropped when it falls behind
+pub struct Shadowed<E: Export> {
+    primary: E,
+    sampler: Sampler,
+    tx: Sender<E::Item>,
+}
+
+impl<E: Export> Shadowed<E>
+where
+    E::Item: Clone + 'static,
+{
+    /// Returns the tee'ing exporter and the receiving end to run the candidate with
+    pub fn new(primary: E, sample_percent: u8) -> (Self, Receiver<E::Item>) {
+        let (tx, rx) = mpsc::channel(SHADOW_CHANNEL_CAPACITY);
+        (
+            Self {
+                primary,
+                sampler: Sampler::new(sample_percent),
+                tx,
+            },
+            rx,
+        )
+    }
+}
+
+#[async_trait]
+impl<E: Export> Export for Shadowed<E>
+where
+    E::Item: Clone + 'static,
+{
+    type Item = E::Item;
+
+    async fn export(&self, message: &E::Item, identifier: &str) -> Result<(), ExporterError> {
+        let result = self.primary.export(message, identifier).await;
+        // full when the candidate falls behind, closed when it could not be prepared
+        if self.sampler.sampled() && self.tx.try_send(message.clone()).is_err() {
+            SHADOW_EXPORTS.with_label_values(&["dropped"]).inc();
+        }
+        result
+    }
+
+    async fn prepare(&self) -> Result<(), ExporterError> {
+        self.primary.prepare().await
+    }
+
+    /// Only the production exporter is waited for, the candidate flushes once its channel closes
+    async fn flush(&self) -> Result<(), ExporterError> {
+        self.primary.flush().await
+    }
+}
+
+/// Exports sampled messages to the candidate until the production exporter is dropped.
+/// A candidate which can not be prepared is given up on, the collector keeps running
+pub async fn run<C: Export>(candidate: C, mut rx: Receiver<C::Item>, identifier: String) {
+    if let Err(e) = candidate.prepare().await {
+        error!("unable to prepare shadow exporter, it is disabled: {:?}", e);
+        return;
+    }
+    info!("Spawned shadow exporter...");
+
+    while let Some(m) = rx.recv().await {
+        let outcome = match candidate.export(&m, &identifier).await {
+            Ok(()) => "exported",
+            Err(e) => {
+                warn!("shadow exporter failed to export a message: {:?}", e);
+                "failed"
+            }
+        };
+        SHADOW_EXPORTS.with_label_values(&[outcome]).inc();
+    }
+
+    if let Err(e) = candidate.flush().await {
+        warn!("unable to flush shadow exporter: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        exported: Arc<Mutex<Vec<u8>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Export for Recorder {
+        type Item = u8;
+
+        async fn export(&self, message: &u8, _identifier: &str) -> Result<(), ExporterError> {
+            if self.fail {
+                return Err(ExporterError::TopicErr("unreachable".to_owned()));
+            }
+            self.exported.lock().unwrap().push(*message);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sampler_spreads_percentage() {
+        let sampler = Sampler::new(25);
+        let sampled = (0..8).map(|_| sampler.sampled()).collect::<Vec<bool>>();
+        assert_eq!(
+            sampled,
+            vec![false, false, false, true, false, false, false, true]
+        );
+
+        let all = Sampler::new(100);
+        assert!((0..10).all(|_| all.sampled()));
+        let none = Sampler::new(0);
+        assert!((0..1000).all(|_| !none.sampled()));
+    }
+
+    #[tokio::test]
+    async fn test_candidate_gets_sample_and_can_not_fail_production() {
+        let production = Recorder::default();
+        let produced = production.exported.clone();
+        let candidate = Recorder {
+            fail: true,
+            ..Default::default()
+        };
+        let (shadowed, rx) = Shadowed::new(production, 50);
+        let failed_before = SHADOW_EXPORTS.with_label_values(&["failed"]).get();
+
+        for i in 0..10u8 {
+            assert!(shadowed.export(&i, "test").await.is_ok());
+        }
+        drop(shadowed);
+        run(candidate, rx, "test".to_owned()).await;
+
+        assert_eq!(produced.lock().unwrap().len(), 10);
+        assert!(SHADOW_EXPORTS.with_label_values(&["failed"]).get() - failed_before >= 5);
+    }
+
+    #[tokio::test]
+    async fn test_candidate_falling_behind_drops_messages() {
+        let (shadowed, mut rx) = Shadowed::new(Recorder::default(), 100);
+        let dropped_before = SHADOW_EXPORTS.with_label_values(&["dropped"]).get();
+
+        for _ in 0..SHADOW_CHANNEL_CAPACITY + 3 {
+            shadowed.export(&1, "test").await.unwrap();
+        }
+
+        assert!(SHADOW_EXPORTS.with_label_values(&["dropped"]).get() - dropped_before >= 3);
+        rx.close();
+        let mut queued = 0;
+        while rx.recv().await.is_some() {
+            queued += 1;
+        }
+        assert_eq!(queued, SHADOW_CHANNEL_CAPACITY);
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowlistError(String);
+
+impl fmt::Display for AllowlistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid allowed source: {}", self.0)
+    }
+}
+
+impl std::error::Error for AllowlistError {}
+
+/// Network a sender has to be in, a single address is a network of its full length
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AllowedNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl AllowedNet {
+    fn parse(entry: &str) -> Result<Self, AllowlistError> {
+        let invalid = || AllowlistError(entry.to_owned());
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix(&addr),
+        };
+        if prefix > max_prefix(&addr) {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        match (addr, &self.addr) {
+            (IpAddr::V4(addr), IpAddr::V4(net)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(*addr) & mask == u32::from(*net) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(net)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(*addr) & mask == u128::from(*net) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Senders flows are accepted from, checked against the address of the peer every frame or
+/// request comes from. Empty list accepts everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceAllowlist {
+    nets: Vec<AllowedNet>,
+}
+
+impl FromStr for SourceAllowlist {
+    type Err = AllowlistError;
+
+    /// Comma separated addresses and networks, e.g. `10.0.0.0/8,192.168.0.7`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nets = s
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(AllowedNet::parse)
+            .collect::<Result<Vec<AllowedNet>, AllowlistError>>()?;
+        Ok(Self { nets })
+    }
+}
+
+impl SourceAllowlist {
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    /// A sender whose address is unknown is allowed only by an empty list
+    pub fn allows(&self, peer: Option<IpAddr>) -> bool {
+        if self.nets.is_empty() {
+            return true;
+        }
+        let peer = match peer {
+            // dual stack listeners see ipv4 senders as mapped ipv6 addresses
+            Some(IpAddr::V6(addr)) => addr.to_ipv4_mapped().map_or(IpAddr::V6(addr), IpAddr::V4),
+            Some(addr) => addr,
+            None => return false,
+        };
+        self.nets.iter().any(|net| net.contains(&peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_everyone_is_allowed_by_default() {
+        let allowlist = "".parse::<SourceAllowlist>().unwrap();
+        assert!(allowlist.is_empty());
+        assert!(allowlist.allows(peer("203.0.113.9")));
+        assert!(allowlist.allows(None));
+    }
+
+    #[test]
+    fn test_addresses_and_networks_are_allowed() {
+        let allowlist = "10.1.0.0/16, 192.168.0.7, fd00::/8"
+            .parse::<SourceAllowlist>()
+            .unwrap();
+
+        assert!(allowlist.allows(peer("10.1.200.3")));
+        assert!(allowlist.allows(peer("192.168.0.7")));
+        assert!(allowlist.allows(peer("::ffff:192.168.0.7")));
+        assert!(allowlist.allows(peer("fd00::1")));
+        assert!(!allowlist.allows(peer("10.2.0.1")));
+        assert!(!allowlist.allows(peer("192.168.0.8")));
+        assert!(!allowlist.allows(None));
+    }
+
+    #[test]
+    fn test_invalid_entries_are_rejected() {
+        for entry in ["10.0.0.0/33", "10.0.0.0/x", "exporter-1", "exporter/24"] {
+            assert!(entry.parse::<SourceAllowlist>().is_err(), "{}", entry);
+        }
+    }
+}
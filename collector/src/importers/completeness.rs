@@ -0,0 +1,108 @@
+use prometheus::IntCounter;
+
+use crate::metrics::{EMPTY_FIELDS, IMPORTED_FLOWS};
+use crate::pb::FlowMessage;
+
+/// Fields of which empty values are counted, the ones every usable flow has
+const TRACKED_FIELDS: [&str; 9] = [
+    "ipv4_src_addr",
+    "ipv4_dst_addr",
+    "l4_src_port",
+    "l4_dst_port",
+    "protocol",
+    "in_bytes",
+    "out_bytes",
+    "in_pkts",
+    "out_pkts",
+];
+
+fn is_empty_address(addr: &str) -> bool {
+    addr.is_empty() || addr == "0.0.0.0"
+}
+
+/// Whether the tracked field at the same index of `TRACKED_FIELDS` is empty or zero
+fn empty_fields(f: &FlowMessage) -> [bool; 9] {
+    [
+        is_empty_address(&f.ipv4_src_addr),
+        is_empty_address(&f.ipv4_dst_addr),
+        f.l4_src_port == 0,
+        f.l4_dst_port == 0,
+        f.protocol == 0,
+        f.in_bytes == 0,
+        f.out_bytes == 0,
+        f.in_pkts == 0,
+        f.out_pkts == 0,
+    ]
+}
+
+/// Counts imported flows and their empty fields, so the fraction of flows missing a field
+/// can be alerted on when a producer starts sending incomplete data
+pub struct Completeness {
+    imported: IntCounter,
+    empty: Vec<IntCounter>,
+}
+
+impl Completeness {
+    pub fn new(source: &str) -> Self {
+        Self {
+            imported: IMPORTED_FLOWS.with_label_values(&[source]),
+            empty: TRACKED_FIELDS
+                .iter()
+                .map(|field| EMPTY_FIELDS.with_label_values(&[source, field]))
+                .collect(),
+        }
+    }
+
+    /// Flows are observed as they were imported, before transforms
+    pub fn observe(&self, f: &FlowMessage) {
+        self.imported.inc();
+        for (counter, empty) in self.empty.iter().zip(empty_fields(f)) {
+            if empty {
+                counter.inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_empty_fields_are_counted() {
+        let completeness = Completeness::new("completeness-test");
+        let complete = FlowMessage {
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            ipv4_dst_addr: "10.0.0.1".to_owned(),
+            l4_src_port: 40000,
+            l4_dst_port: 53,
+            protocol: 17,
+            in_bytes: 61,
+            out_bytes: 120,
+            in_pkts: 1,
+            out_pkts: 1,
+            ..Default::default()
+        };
+        let blank_src = FlowMessage {
+            ipv4_src_addr: "0.0.0.0".to_owned(),
+            l4_dst_port: 0,
+            ..complete.clone()
+        };
+
+        completeness.observe(&complete);
+        completeness.observe(&blank_src);
+        completeness.observe(&blank_src);
+
+        let empty = |field: &str| {
+            EMPTY_FIELDS
+                .with_label_values(&["completeness-test", field])
+                .get()
+        };
+        assert_eq!(completeness.imported.get(), 3);
+        assert_eq!(empty("ipv4_src_addr"), 2);
+        assert_eq!(empty("l4_dst_port"), 2);
+        assert_eq!(empty("ipv4_dst_addr"), 0);
+        assert_eq!(empty("in_bytes"), 0);
+    }
+}
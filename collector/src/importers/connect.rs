@@ -0,0 +1,99 @@
+use std::fmt;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use tokio::time::sleep;
+
+/// Backoff between connection attempts if not configured
+pub const DEFAULT_CONNECT_BACKOFF: Duration = Duration::from_millis(1000);
+/// Backoff is doubled after every failed attempt, up to this
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRetry {
+    /// attempts made after the first one failed, the importer fails right away with 0
+    pub max_retries: u32,
+    /// wait before the first retry
+    pub backoff: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: DEFAULT_CONNECT_BACKOFF,
+        }
+    }
+}
+
+/// Connects an importer of the source, failed attempts are retried with exponential backoff
+/// so a source which is not up yet when the collector starts does not fail it.
+/// The error of the last attempt is returned once the retries run out
+pub async fn connect_with_retry<T, E: fmt::Debug>(
+    retry: &ConnectRetry,
+    source: &str,
+    mut connect: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let attempts = retry.max_retries.saturating_add(1);
+    let mut backoff = retry.backoff;
+    let mut attempt = 1;
+
+    loop {
+        match connect() {
+            Ok(importer) => {
+                if attempt > 1 {
+                    info!("connected {} importer on attempt {}", source, attempt);
+                }
+                return Ok(importer);
+            }
+            Err(e) if attempt >= attempts => {
+                error!(
+                    "giving up connecting {} importer after {} attempts: {:?}",
+                    source, attempt, e
+                );
+                return Err(e);
+            }
+            Err(e) => {
+                warn!(
+                    "attempt {}/{} to connect {} importer failed: {:?}, retrying in {:?}",
+                    attempt, attempts, source, e, backoff
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failing(failures: u32) -> impl FnMut() -> Result<u32, String> {
+        let mut attempts = 0;
+        move || {
+            attempts += 1;
+            if attempts > failures {
+                Ok(attempts)
+            } else {
+                Err("source is not up yet".to_string())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_is_retried_until_retries_run_out() {
+        let retry = ConnectRetry {
+            max_retries: 3,
+            backoff: Duration::from_millis(1),
+        };
+        assert_eq!(connect_with_retry(&retry, "zmq", failing(3)).await, Ok(4));
+        assert!(connect_with_retry(&retry, "zmq", failing(4)).await.is_err());
+
+        // without retries the first failure is final
+        let retry = ConnectRetry::default();
+        assert_eq!(connect_with_retry(&retry, "zmq", failing(0)).await, Ok(1));
+        assert!(connect_with_retry(&retry, "zmq", failing(1)).await.is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use prost::encoding::{decode_key, skip_field, DecodeContext};
+use prost::{DecodeError, EncodeError, Message};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use common::parse::UnknownVariant;
+
+use super::errors::ImporterError;
+
+use crate::metrics::EMPTY_FRAMES;
+use crate::pb::FlowMessage;
+
+/// How flows are packed into a single message of the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// a json document exported by nProbe
+    #[default]
+    Single,
+    /// protobuf encoded flows, each prefixed with its varint length
+    LengthDelimitedBatch,
+}
+
+impl FromStr for Framing {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Self::Single),
+            "length-delimited-batch" => Ok(Self::LengthDelimitedBatch),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+/// Tags of `FlowMessage` fields, has to follow `proto/flow.proto`
+const FLOW_MESSAGE_TAGS: RangeInclusive<u32> = 1..=12;
+
+/// Flow along with encoded fields `FlowMessage` does not know, e.g. added by a producer with
+/// a newer schema. They are kept as received and encoded back after the known ones
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ImportedFlow {
+    pub flow: FlowMessage,
+    pub unknown_fields: Vec<u8>,
+}
+
+impl From<FlowMessage> for ImportedFlow {
+    fn from(flow: FlowMessage) -> Self {
+        Self {
+            flow,
+            unknown_fields: Vec::new(),
+        }
+    }
+}
+
+impl ImportedFlow {
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.flow.encode(buf)?;
+        buf.extend_from_slice(&self.unknown_fields);
+        Ok(())
+    }
+}
+
+/// Encoded fields of the message with tags out of `FLOW_MESSAGE_TAGS`, in the received order
+fn unknown_fields(mut payload: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut unknown = Vec::new();
+    while !payload.is_empty() {
+        let field = payload;
+        let (tag, wire_type) = decode_key(&mut payload)?;
+        skip_field(wire_type, tag, &mut payload, DecodeContext::default())?;
+        if !FLOW_MESSAGE_TAGS.contains(&tag) {
+            unknown.extend_from_slice(&field[..field.len() - payload.len()]);
+        }
+    }
+    Ok(unknown)
+}
+
+/// Zero length frames, e.g. keepalives of a producer, carry no flow. Importers skip them
+/// instead of decoding them into an error or into a flow with every field unset
+pub fn is_empty_frame(payload: &[u8], source: &str) -> bool {
+    if !payload.is_empty() {
+        return false;
+    }
+    EMPTY_FRAMES.with_label_values(&[source]).inc();
+    true
+}
+
+/// Decodes a batch of flows exported by nProbe in json format
+pub fn decode_json(payload: &[u8]) -> Result<Vec<FlowMessage>, ImporterError> {
+    Ok(serde_json::from_slice(payload)?)
+}
+
+/// Decodes a single protobuf encoded flow, keeping its unknown fields
+pub fn decode_protobuf(payload: &[u8]) -> Result<ImportedFlow, ImporterError> {
+    Ok(ImportedFlow {
+        flow: FlowMessage::decode(payload)?,
+        unknown_fields: unknown_fields(payload)?,
+    })
+}
+
+/// Decodes a batch of protobuf encoded flows prefixed with their varint length.
+/// A single malformed record fails the whole batch, so it is never ingested partially
+pub fn decode_length_delimited_batch(
+    mut payload: &[u8],
+) -> Result<Vec<ImportedFlow>, ImporterError> {
+    let mut flows = Vec::new();
+    while !payload.is_empty() {
+        let record = payload;
+        let flow = FlowMessage::decode_length_delimited(&mut payload)?;
+        let mut record = &record[..record.len() - payload.len()];
+        prost::decode_length_delimiter(&mut record)?;
+        flows.push(ImportedFlow {
+            flow,
+            unknown_fields: unknown_fields(record)?,
+        });
+    }
+    Ok(flows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(b""; "empty")]
+    #[test_case(b"\xff\xfe"; "not utf8")]
+    #[test_case(b"[{\"OUT_BYTES\":-1}]"; "negative counter")]
+    #[test_case(b"[[[[[[[["; "unterminated")]
+    fn test_malformed_json_is_an_error(payload: &[u8]) {
+        assert!(matches!(
+            decode_json(payload),
+            Err(ImporterError::DeserializationErr(_))
+        ));
+    }
+
+    #[test_case(b"\x0a"; "truncated field")]
+    #[test_case(b"\x08\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff"; "varint overflow")]
+    #[test_case(b"\x2a\xff\xff\xff\xff\x0f"; "length beyond payload")]
+    fn test_malformed_protobuf_is_an_error(payload: &[u8]) {
+        assert!(matches!(
+            decode_protobuf(payload),
+            Err(ImporterError::DecodeErr(_))
+        ));
+    }
+
+    fn batch(flows: &[FlowMessage]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for flow in flows {
+            flow.encode_length_delimited(&mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn test_length_delimited_batch_is_decoded() {
+        let flows = (0..3)
+            .map(|i| FlowMessage {
+                l4_src_port: 40000 + i,
+                ipv4_src_addr: "10.0.0.2".to_owned(),
+                ..Default::default()
+            })
+            .collect::<Vec<FlowMessage>>();
+
+        assert_eq!(
+            decode_length_delimited_batch(&batch(&flows)).unwrap(),
+            flows
+                .into_iter()
+                .map(ImportedFlow::from)
+                .collect::<Vec<ImportedFlow>>()
+        );
+        assert_eq!(decode_length_delimited_batch(b"").unwrap(), vec![]);
+        // a flow with every field at its default is encoded as a zero length record
+        assert_eq!(
+            decode_length_delimited_batch(&batch(&[FlowMessage::default()])).unwrap(),
+            vec![ImportedFlow::default()]
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_survive_round_trip() {
+        let flow = FlowMessage {
+            l4_src_port: 40000,
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            tcp_flags: 2,
+            ..Default::default()
+        };
+        // a newer schema with `uint64 flow_start_ms = 13` and `string vlan_name = 20`
+        let mut newer = Vec::new();
+        flow.encode(&mut newer).unwrap();
+        let unknown = b"\x68\xe8\x07\xa2\x01\x04lan0".to_vec();
+        newer.extend_from_slice(&unknown);
+
+        let imported = decode_protobuf(&newer).unwrap();
+        assert_eq!(imported.flow, flow);
+        assert_eq!(imported.unknown_fields, unknown);
+
+        let mut encoded = Vec::new();
+        imported.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, newer);
+
+        let mut record = Vec::new();
+        prost::encode_length_delimiter(newer.len(), &mut record).unwrap();
+        record.extend_from_slice(&newer);
+        assert_eq!(
+            decode_length_delimited_batch(&record).unwrap(),
+            vec![imported]
+        );
+
+        // known fields are never duplicated, whatever their values
+        let every_field = FlowMessage {
+            out_bytes: 1,
+            out_pkts: 1,
+            in_bytes: 1,
+            in_pkts: 1,
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            ipv4_dst_addr: "10.0.0.1".to_owned(),
+            l7_proto: 7.0,
+            l4_dst_port: 53,
+            l4_src_port: 40000,
+            flow_duration_milliseconds: 1,
+            protocol: 17,
+            tcp_flags: 2,
+        };
+        assert!(decode_protobuf(&every_field.encode_to_vec())
+            .unwrap()
+            .unknown_fields
+            .is_empty());
+    }
+
+    #[test]
+    fn test_truncated_batch_fails_whole() {
+        let flows = vec![
+            FlowMessage {
+                l4_src_port: 40000,
+                ..Default::default()
+            };
+            2
+        ];
+        let payload = batch(&flows);
+
+        assert!(matches!(
+            decode_length_delimited_batch(&payload[..payload.len() - 1]),
+            Err(ImporterError::DecodeErr(_))
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use super::transform::{FieldValue, FlowField};
+use crate::pb::FlowMessage;
+
+/// Value standing for the address of the peer flows are imported from
+const SOURCE_ADDRESS: &str = "@source";
+/// Not a field of `FlowMessage`, flows are exported along with the identifier of their host
+const HOST: &str = "host";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDefaultsError(String);
+
+impl fmt::Display for FieldDefaultsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid field default: {}", self.0)
+    }
+}
+
+impl std::error::Error for FieldDefaultsError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostDefault {
+    Name(String),
+    /// address of the peer, known once the importer is connected
+    SourceAddress,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FieldDefault {
+    field: FlowField,
+    value: FieldValue,
+}
+
+impl FromStr for FieldDefault {
+    type Err = FieldDefaultsError;
+
+    /// e.g. `protocol=17`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, value) = s
+            .split_once('=')
+            .ok_or_else(|| FieldDefaultsError(format!("{} is not field=value", s)))?;
+        let field = field
+            .trim()
+            .parse::<FlowField>()
+            .map_err(|e| FieldDefaultsError(e.0))?;
+
+        // the peer is the publisher, its address is not an endpoint of the flows it sends
+        let value = match value.trim() {
+            SOURCE_ADDRESS => {
+                return Err(FieldDefaultsError(format!(
+                    "{} is a value of {} only, not of {:?}",
+                    SOURCE_ADDRESS, HOST, field
+                )))
+            }
+            value => field
+                .parse_value(value)
+                .map_err(|e| FieldDefaultsError(e.0))?,
+        };
+        Ok(Self { field, value })
+    }
+}
+
+/// Values of fields a source never sends, set on imported flows right after they are decoded,
+/// so the rest of the pipeline does not have to special case the source. Fields which were sent
+/// are kept as they are
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FieldDefaults {
+    fields: Vec<FieldDefault>,
+    /// host identifier flows are exported with instead of the one of the collector's host
+    host: Option<HostDefault>,
+}
+
+impl FromStr for FieldDefaults {
+    type Err = FieldDefaultsError;
+
+    /// Accepts defaults separated with `;`, e.g. `host=@source;protocol=17`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut defaults = Self::default();
+        for d in s.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+            match d.split_once('=').map(|(f, v)| (f.trim(), v.trim())) {
+                Some((HOST, "")) => {
+                    return Err(FieldDefaultsError(format!("{} needs a value", HOST)))
+                }
+                Some((HOST, SOURCE_ADDRESS)) => defaults.host = Some(HostDefault::SourceAddress),
+                Some((HOST, host)) => defaults.host = Some(HostDefault::Name(host.to_owned())),
+                _ => defaults.fields.push(d.parse::<FieldDefault>()?),
+            }
+        }
+        Ok(defaults)
+    }
+}
+
+impl FieldDefaults {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.host.is_none()
+    }
+
+    /// Replaces `@source` with the address of the importer's peer, fails when the importer
+    /// has no single peer, e.g. it reads a file
+    pub fn resolve(self, source: Option<Ipv4Addr>) -> Result<Self, FieldDefaultsError> {
+        let host = match (self.host, source) {
+            (Some(HostDefault::SourceAddress), Some(addr)) => {
+                Some(HostDefault::Name(addr.to_string()))
+            }
+            (Some(HostDefault::SourceAddress), None) => {
+                return Err(FieldDefaultsError(format!(
+                    "{} of {} needs an importer with a single peer address",
+                    SOURCE_ADDRESS, HOST
+                )))
+            }
+            (host, _) => host,
+        };
+        Ok(Self {
+            fields: self.fields,
+            host,
+        })
+    }
+
+    /// Host identifier every flow is exported with, flows never carry one of their own
+    pub fn host(&self) -> Option<&str> {
+        match &self.host {
+            Some(HostDefault::Name(host)) => Some(host),
+            // unresolved source addresses are refused on startup
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, f: &mut FlowMessage) {
+        for d in self.fields.iter() {
+            if d.field.is_unset(f) {
+                d.field.set(f, &d.value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    fn defaults(s: &str, source: Option<Ipv4Addr>) -> FieldDefaults {
+        s.parse::<FieldDefaults>().unwrap().resolve(source).unwrap()
+    }
+
+    #[test]
+    fn test_only_unset_fields_are_defaulted() {
+        let defaults = defaults("ipv4_src_addr=10.0.0.5; protocol=17; l4_dst_port=53", None);
+        let mut f = FlowMessage {
+            ipv4_src_addr: "0.0.0.0".to_owned(),
+            l4_dst_port: 5353,
+            ..Default::default()
+        };
+
+        defaults.apply(&mut f);
+
+        assert_eq!(
+            f,
+            FlowMessage {
+                ipv4_src_addr: "10.0.0.5".to_owned(),
+                protocol: 17,
+                l4_dst_port: 5353,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_host_is_defaulted_from_the_source_address() {
+        let source = Some(Ipv4Addr::new(10, 0, 0, 5));
+        let from_source = defaults("host=@source", source);
+        assert!(!from_source.is_empty());
+        assert_eq!(from_source.host(), Some("10.0.0.5"));
+
+        let named = defaults("host = exporter-1; protocol=17", None);
+        assert_eq!(named.host(), Some("exporter-1"));
+        assert_eq!(defaults("protocol=17", source).host(), None);
+    }
+
+    #[test]
+    fn test_source_address_needs_a_peer() {
+        let defaults = "host=@source".parse::<FieldDefaults>().unwrap();
+        assert!(defaults.resolve(None).is_err());
+        assert!("protocol=6"
+            .parse::<FieldDefaults>()
+            .unwrap()
+            .resolve(None)
+            .is_ok());
+    }
+
+    #[test_case("ipv4_src_addr=@source"; "source of a flow address")]
+    #[test_case("protocol=@source"; "source of not an address")]
+    #[test_case("host="; "missing host")]
+    #[test_case("protocol=tcp"; "invalid value")]
+    #[test_case("anomaly_score=1"; "unknown field")]
+    #[test_case("protocol"; "missing value")]
+    fn test_invalid_defaults(defaults: &str) {
+        assert!(defaults.parse::<FieldDefaults>().is_err());
+    }
+}
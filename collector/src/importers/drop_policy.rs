@@ -0,0 +1,523 @@
+use core::fmt;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+use prometheus::{IntCounter, IntGauge};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{Sender, WeakSender};
+use tokio::sync::Notify;
+use tokio::time::{interval, MissedTickBehavior};
+
+use common::parse::UnknownVariant;
+
+use super::ring::Ring;
+use super::spill::Spill;
+use crate::metrics::{DROPPED_MESSAGES, QUEUE_DEPTH};
+
+/// Number of messages kept aside by `drop_oldest` while the channel is full
+const DROP_OLDEST_RING_CAPACITY: usize = 128;
+
+/// How often messages kept aside by `drop_oldest` are offered to the channel meanwhile
+const DROP_OLDEST_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the queue depth is reported while nothing is imported
+const QUEUE_DEPTH_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the importer does when the exporter channel is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// waits for free space, backpressures the source
+    #[default]
+    Block,
+    /// drops the message which did not fit
+    DropNewest,
+    /// keeps the latest messages in a small ring buffer, dropping the oldest ones from it
+    DropOldest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop_newest" => Ok(Self::DropNewest),
+            "drop_oldest" => Ok(Self::DropOldest),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for DropPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Block => "block",
+            Self::DropNewest => "drop_newest",
+            Self::DropOldest => "drop_oldest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Messages kept aside by `drop_oldest` while the channel is full. Shared with the task handing
+/// them over to the channel, messages are taken from it and sent under its lock so they keep
+/// their order whoever sends them
+#[derive(Default)]
+struct Pending {
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    added: Notify,
+    closed: AtomicBool,
+}
+
+impl Pending {
+    fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Keeps the message, returns whether the oldest one had to be dropped for it
+    fn push(&self, msg: Vec<u8>) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(msg);
+        queue.len() > DROP_OLDEST_RING_CAPACITY && queue.pop_front().is_some()
+    }
+
+    /// Sends as many messages as fit into the channel without waiting
+    fn flush(&self, tx: &Sender<Vec<u8>>) -> Result<(), SendError<Vec<u8>>> {
+        let mut queue = self.queue.lock().unwrap();
+        while let Some(msg) = queue.pop_front() {
+            match tx.try_send(msg) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(msg)) => {
+                    queue.push_front(msg);
+                    break;
+                }
+                Err(TrySendError::Closed(msg)) => return Err(SendError(msg)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for space in the channel and sends the oldest message, `false` once none is left
+    async fn send_oldest(&self, tx: &Sender<Vec<u8>>) -> Result<bool, SendError<Vec<u8>>> {
+        if self.len() == 0 {
+            return Ok(false);
+        }
+        let permit = match tx.reserve().await {
+            Ok(permit) => permit,
+            Err(_) => {
+                let msg = self.queue.lock().unwrap().pop_front();
+                return Err(SendError(msg.unwrap_or_default()));
+            }
+        };
+        let mut queue = self.queue.lock().unwrap();
+        match queue.pop_front() {
+            Some(msg) => {
+                permit.send(msg);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Hands messages kept aside over to the channel while they wait, rather than on the next
+/// message of the source, which an idle source may not send for long. Checks for space on a
+/// timer instead of reserving it, so the source never finds the channel full because of a slot
+/// held for a message it could have sent itself. Stops once the sender is dropped
+async fn flush_while_pending(tx: WeakSender<Vec<u8>>, pending: Arc<Pending>) {
+    let mut tick = interval(DROP_OLDEST_FLUSH_INTERVAL);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    while !pending.closed.load(Ordering::SeqCst) {
+        pending.added.notified().await;
+        while pending.len() > 0 {
+            tick.tick().await;
+            match tx.upgrade() {
+                Some(tx) if pending.flush(&tx).is_ok() => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Messages waiting in the channel and kept aside by `drop_oldest`
+fn queue_depth(tx: &Sender<Vec<u8>>, pending: &Pending) -> usize {
+    tx.max_capacity() - tx.capacity() + pending.len()
+}
+
+/// Reports the depth on a timer, so the gauge follows the exporter draining the channel also
+/// while the source is idle and nothing is sent. Stops once the sender is dropped
+async fn report_depth(tx: WeakSender<Vec<u8>>, pending: Arc<Pending>, depth: IntGauge) {
+    let mut tick = interval(QUEUE_DEPTH_REPORT_INTERVAL);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    while !pending.closed.load(Ordering::SeqCst) {
+        tick.tick().await;
+        match tx.upgrade() {
+            Some(tx) => depth.set(queue_depth(&tx, &pending) as i64),
+            None => return,
+        }
+    }
+}
+
+/// Sends messages to the exporter channel according to the drop policy
+pub struct PolicySender {
+    tx: Sender<Vec<u8>>,
+    policy: DropPolicy,
+    pending: Arc<Pending>,
+    spill: Option<Arc<Spill>>,
+    ring: Option<Arc<Ring>>,
+    dropped: IntCounter,
+    depth: IntGauge,
+}
+
+impl PolicySender {
+    /// Has to be called within a tokio runtime
+    pub fn new(tx: Sender<Vec<u8>>, policy: DropPolicy, source: &str) -> Self {
+        let dropped = DROPPED_MESSAGES.with_label_values(&[source, &policy.to_string()]);
+        let depth = QUEUE_DEPTH.with_label_values(&[source]);
+
+        let pending = Arc::new(Pending::default());
+        tokio::spawn(report_depth(tx.downgrade(), pending.clone(), depth.clone()));
+        if policy == DropPolicy::DropOldest {
+            tokio::spawn(flush_while_pending(tx.downgrade(), pending.clone()));
+        }
+
+        Self {
+            tx,
+            policy,
+            pending,
+            spill: None,
+            ring: None,
+            dropped,
+            depth,
+        }
+    }
+
+    /// Messages which do not fit into the channel are spilled to disk before the drop policy applies
+    pub fn with_spill(self, spill: Arc<Spill>) -> Self {
+        Self {
+            spill: Some(spill),
+            ..self
+        }
+    }
+
+    /// Messages go through the ring spool instead of the channel, the drop policy and spill
+    /// do not apply since the ring overwrites its oldest messages when full
+    pub fn with_ring(self, ring: Arc<Ring>) -> Self {
+        Self {
+            ring: Some(ring),
+            ..self
+        }
+    }
+
+    /// Fails only when the channel is closed
+    pub async fn send(&mut self, msg: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
+        let result = self.send_with_policy(msg).await;
+        self.depth.set(self.depth() as i64);
+        result
+    }
+
+    async fn send_with_policy(&mut self, msg: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
+        if let Some(ring) = &self.ring {
+            if let Err(e) = ring.push(&msg) {
+                error!("unable to write message to ring: {}", e);
+                self.dropped.inc();
+            }
+            return Ok(());
+        }
+
+        let msg = match self.spill_overflow(msg).await? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+
+        match self.policy {
+            DropPolicy::Block => self.tx.send(msg).await,
+            DropPolicy::DropNewest => match self.tx.try_send(msg) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.inc();
+                    Ok(())
+                }
+                Err(TrySendError::Closed(msg)) => Err(SendError(msg)),
+            },
+            DropPolicy::DropOldest => {
+                // make use of space freed since the last send before anything is dropped
+                self.pending.flush(&self.tx)?;
+                if self.pending.push(msg) {
+                    self.dropped.inc();
+                }
+                self.pending.flush(&self.tx)?;
+                if self.pending.len() > 0 {
+                    self.pending.added.notify_one();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the message back when it was neither sent nor spilled.
+    /// While anything spilled by this run is left new messages are appended after it, so their
+    /// order is kept
+    async fn spill_overflow(
+        &mut self,
+        msg: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, SendError<Vec<u8>>> {
+        let spill = match &self.spill {
+            Some(spill) => spill,
+            None => return Ok(Some(msg)),
+        };
+
+        let msg = if spill.is_empty_since_open() {
+            match self.tx.try_send(msg) {
+                Ok(()) => return Ok(None),
+                Err(TrySendError::Full(msg)) => msg,
+                Err(TrySendError::Closed(msg)) => return Err(SendError(msg)),
+            }
+        } else {
+            msg
+        };
+
+        match spill.append(msg).await {
+            (_, Ok(true)) => Ok(None),
+            (msg, Ok(false)) => Ok(Some(msg)),
+            (msg, Err(e)) => {
+                error!("unable to spill message to disk: {}", e);
+                Ok(Some(msg))
+            }
+        }
+    }
+
+    /// Hands messages kept by `drop_oldest` over to the channel, waiting for space in it
+    pub async fn drain_pending(&mut self) -> Result<(), SendError<Vec<u8>>> {
+        while self.pending.send_oldest(&self.tx).await? {}
+        Ok(())
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
+    /// Messages waiting in the channel and in the ring buffer
+    pub fn depth(&self) -> usize {
+        queue_depth(&self.tx, &self.pending)
+    }
+}
+
+impl Drop for PolicySender {
+    fn drop(&mut self) {
+        self.pending.closed.store(true, Ordering::SeqCst);
+        self.pending.added.notify_one();
+        if let Some(spill) = &self.spill {
+            spill.close();
+        }
+        if let Some(ring) = &self.ring {
+            ring.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+    use tokio::sync::mpsc;
+
+    #[test_case("block", Ok(DropPolicy::Block))]
+    #[test_case("drop_newest", Ok(DropPolicy::DropNewest))]
+    #[test_case("drop_oldest", Ok(DropPolicy::DropOldest))]
+    #[test_case("drop", Err(UnknownVariant("drop".to_owned())))]
+    fn test_drop_policy_from_str(input: &str, expected: Result<DropPolicy, UnknownVariant>) {
+        assert_eq!(input.parse::<DropPolicy>(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut sender = PolicySender::new(tx, DropPolicy::DropNewest, "test_drop_newest");
+
+        for i in 0..5u8 {
+            sender.send(vec![i]).await.unwrap();
+        }
+
+        assert_eq!(sender.dropped(), 3);
+        assert_eq!(sender.depth(), 2);
+        assert_eq!(rx.recv().await, Some(vec![0]));
+        assert_eq!(rx.recv().await, Some(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut sender = PolicySender::new(tx, DropPolicy::DropOldest, "test_drop_oldest");
+
+        let total = DROP_OLDEST_RING_CAPACITY + 3;
+        for i in 0..total {
+            sender.send(i.to_be_bytes().to_vec()).await.unwrap();
+        }
+        // one message is in the channel, the ring is full
+        assert_eq!(sender.dropped(), 2);
+        assert_eq!(sender.depth(), DROP_OLDEST_RING_CAPACITY + 1);
+
+        assert_eq!(rx.recv().await, Some(0usize.to_be_bytes().to_vec()));
+        // freed space is filled on the next send, oldest messages in the ring were dropped
+        sender.send(total.to_be_bytes().to_vec()).await.unwrap();
+        assert_eq!(sender.dropped(), 2);
+        assert_eq!(rx.recv().await, Some(3usize.to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_depth_is_reported_while_source_is_idle() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut sender = PolicySender::new(tx, DropPolicy::DropNewest, "test_depth_idle");
+        let gauge = QUEUE_DEPTH.with_label_values(&["test_depth_idle"]);
+
+        sender.send(vec![0]).await.unwrap();
+        sender.send(vec![1]).await.unwrap();
+        assert_eq!(gauge.get(), 2);
+
+        // the exporter catches up while nothing more is imported
+        rx.recv().await.unwrap();
+        rx.recv().await.unwrap();
+        tokio::time::sleep(QUEUE_DEPTH_REPORT_INTERVAL * 2).await;
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_flushes_once_channel_frees_up() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut sender = PolicySender::new(tx, DropPolicy::DropOldest, "test_drop_oldest_idle");
+
+        for i in 0..3u8 {
+            sender.send(vec![i]).await.unwrap();
+        }
+        assert_eq!(sender.depth(), 3);
+
+        // the source stays idle, messages kept aside still reach the channel
+        for i in 0..3u8 {
+            let received = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+            assert_eq!(received.unwrap(), Some(vec![i]));
+        }
+        assert_eq!(sender.depth(), 0);
+        assert_eq!(sender.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_overflow_is_spilled_in_order() {
+        use super::super::spill::{drain, SpillDrainMode, SpillSettings};
+
+        let dir = tempfile::tempdir().unwrap();
+        let spill = Spill::open(SpillSettings {
+            dir: dir.path().to_path_buf(),
+            max_bytes: 1024,
+            drain_mode: SpillDrainMode::Concurrent,
+        })
+        .unwrap();
+
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut sender = PolicySender::new(tx.clone(), DropPolicy::DropNewest, "test_spill")
+            .with_spill(spill.clone());
+
+        for i in 0..5u8 {
+            sender.send(vec![i]).await.unwrap();
+        }
+        assert_eq!(sender.dropped(), 0);
+        assert!(!spill.is_empty());
+
+        // channel has free space, but spilled messages go first
+        assert_eq!(rx.recv().await, Some(vec![0]));
+        sender.send(vec![5]).await.unwrap();
+        drop(sender);
+
+        tokio::spawn(drain(spill, tx));
+        for i in 1..6u8 {
+            assert_eq!(rx.recv().await, Some(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_live_messages_skip_leftovers_of_concurrent_spill() {
+        use super::super::spill::{drain, SpillDrainMode, SpillSettings};
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = SpillSettings {
+            dir: dir.path().to_path_buf(),
+            max_bytes: 1024,
+            drain_mode: SpillDrainMode::Concurrent,
+        };
+        // left by the previous run
+        {
+            let spill = Spill::open(settings.clone()).unwrap();
+            assert!(spill.append(vec![0]).await.1.unwrap());
+            assert!(spill.append(vec![1]).await.1.unwrap());
+        }
+
+        let spill = Spill::open(settings).unwrap();
+        assert!(spill.has_leftovers());
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut sender = PolicySender::new(tx.clone(), DropPolicy::DropNewest, "test_spill")
+            .with_spill(spill.clone());
+
+        // live messages are not queued behind the leftovers while the channel has room
+        sender.send(vec![10]).await.unwrap();
+        sender.send(vec![11]).await.unwrap();
+        assert!(spill.is_empty_since_open());
+        // overflow of the live traffic is spilled after them
+        sender.send(vec![12]).await.unwrap();
+        assert!(!spill.is_empty_since_open());
+        drop(sender);
+
+        tokio::spawn(drain(spill, tx));
+        for i in [10, 11, 0, 1, 12] {
+            assert_eq!(rx.recv().await, Some(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_go_through_ring() {
+        use super::super::ring::{drain, RingSettings};
+
+        let dir = tempfile::tempdir().unwrap();
+        let ring = Ring::open(RingSettings {
+            path: dir.path().join("ring"),
+            capacity_bytes: 1024,
+        })
+        .unwrap();
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut sender = PolicySender::new(tx.clone(), DropPolicy::DropNewest, "test_ring")
+            .with_ring(ring.clone());
+
+        // nothing is dropped although the channel fits a single message
+        for i in 0..5u8 {
+            sender.send(vec![i]).await.unwrap();
+        }
+        assert_eq!(sender.dropped(), 0);
+        assert_eq!(ring.bytes(), 25);
+        drop(sender);
+
+        tokio::spawn(drain(ring, tx));
+        for i in 0..5u8 {
+            assert_eq!(rx.recv().await, Some(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closed_channel() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+
+        for policy in [
+            DropPolicy::Block,
+            DropPolicy::DropNewest,
+            DropPolicy::DropOldest,
+        ] {
+            let mut sender = PolicySender::new(tx.clone(), policy, "test_closed_channel");
+            assert!(sender.send(vec![0]).await.is_err());
+        }
+    }
+}
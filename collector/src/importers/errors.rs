@@ -2,6 +2,12 @@
 pub enum ImporterError {
     ZMQErr(zmq::Error),
     DeserializationErr(serde_json::Error),
+    IoErr(std::io::Error),
+    DecodeErr(prost::DecodeError),
+    /// source has nothing more to import
+    Exhausted,
+    /// source did not accept the connection in time
+    ConnectTimeout,
 }
 
 impl PartialEq for ImporterError {
@@ -11,6 +17,10 @@ impl PartialEq for ImporterError {
             (Self::DeserializationErr(a), Self::DeserializationErr(b)) => {
                 a.classify() == b.classify()
             }
+            (Self::IoErr(a), Self::IoErr(b)) => a.kind() == b.kind(),
+            (Self::DecodeErr(a), Self::DecodeErr(b)) => a == b,
+            (Self::Exhausted, Self::Exhausted) => true,
+            (Self::ConnectTimeout, Self::ConnectTimeout) => true,
             _ => false,
         }
     }
@@ -21,3 +31,15 @@ impl From<serde_json::Error> for ImporterError {
         Self::DeserializationErr(error)
     }
 }
+
+impl From<std::io::Error> for ImporterError {
+    fn from(error: std::io::Error) -> Self {
+        Self::IoErr(error)
+    }
+}
+
+impl From<prost::DecodeError> for ImporterError {
+    fn from(error: prost::DecodeError) -> Self {
+        Self::DecodeErr(error)
+    }
+}
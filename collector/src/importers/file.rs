@@ -0,0 +1,257 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use log::info;
+
+use super::{
+    decode::{decode_protobuf, is_empty_frame, ImportedFlow},
+    errors::ImporterError,
+    import::Import,
+};
+
+use crate::exporters::Compression;
+use crate::pb::FlowMessage;
+
+/// Number of flows returned by a single import
+const FILE_IMPORT_BATCH_SIZE: usize = 128;
+
+#[derive(Debug)]
+pub struct FileSettings {
+    /// a single file or a directory with files written by the file exporter
+    pub path: PathBuf,
+}
+
+struct OpenFile {
+    path: PathBuf,
+    reader: Box<dyn Read + Send>,
+}
+
+struct FileQueue {
+    pending: Vec<PathBuf>,
+    current: Option<OpenFile>,
+}
+
+/// Reads flows back from files written by the file exporter, oldest file first.
+/// Import fails with `Exhausted` once all files were read
+pub struct FileImporter {
+    queue: Mutex<FileQueue>,
+}
+
+/// Files in the order they were written, their names start with the creation timestamp
+fn flow_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut files = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?
+        .into_iter()
+        .filter(|p| p.is_file() && Compression::from_path(p).is_some())
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+    Ok(files)
+}
+
+fn open(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(match Compression::from_path(path) {
+        Some(Compression::Gzip) => Box::new(GzDecoder::new(file)),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    })
+}
+
+/// Reads a protobuf varint length delimiter, `None` on a clean end of file
+fn read_delimiter(reader: &mut dyn Read) -> io::Result<Option<usize>> {
+    let mut len: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        len |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len as usize));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid length delimiter",
+    ))
+}
+
+/// Reads the next flow, skipping empty frames
+fn read_flow(reader: &mut dyn Read) -> Result<Option<ImportedFlow>, ImporterError> {
+    loop {
+        let len = match read_delimiter(reader)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf)?;
+        if !is_empty_frame(&buf, "file") {
+            return Ok(Some(decode_protobuf(&buf)?));
+        }
+    }
+}
+
+impl FileImporter {
+    pub fn new(settings: FileSettings) -> Result<Self, ImporterError> {
+        let mut pending = flow_files(&settings.path)?;
+        info!(
+            "importing {} flow files from {}",
+            pending.len(),
+            settings.path.display()
+        );
+        // popped from the end
+        pending.reverse();
+
+        Ok(FileImporter {
+            queue: Mutex::new(FileQueue {
+                pending,
+                current: None,
+            }),
+        })
+    }
+
+    fn read_batch(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut flows = Vec::with_capacity(FILE_IMPORT_BATCH_SIZE);
+
+        while flows.len() < FILE_IMPORT_BATCH_SIZE {
+            if queue.current.is_none() {
+                let path = match queue.pending.pop() {
+                    Some(p) => p,
+                    None => break,
+                };
+                info!("importing flows from {}", path.display());
+                let reader = open(&path)?;
+                queue.current = Some(OpenFile { path, reader });
+            }
+
+            let file = queue.current.as_mut().unwrap();
+            match read_flow(&mut file.reader)? {
+                Some(flow) => flows.push(flow),
+                None => {
+                    info!("finished importing flows from {}", file.path.display());
+                    queue.current = None;
+                }
+            }
+        }
+
+        if flows.is_empty() {
+            return Err(ImporterError::Exhausted);
+        }
+        Ok(flows)
+    }
+}
+
+#[async_trait]
+impl Import for FileImporter {
+    async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
+        Ok(self.read_batch()?.into_iter().map(|f| f.flow).collect())
+    }
+
+    async fn import_with_unknown_fields(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        self.read_batch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporters::{Export, FileExporter, FileSettings as ExportSettings};
+    use pretty_assertions::assert_eq;
+    use prost::Message;
+    use test_case::test_case;
+    use tokio_test::block_on;
+
+    fn flows(n: u32) -> Vec<FlowMessage> {
+        (0..n)
+            .map(|i| FlowMessage {
+                out_bytes: i as u64 * 100,
+                out_pkts: 1,
+                in_bytes: 61,
+                in_pkts: 1,
+                ipv4_src_addr: "10.0.0.2".to_owned(),
+                ipv4_dst_addr: "10.0.0.1".to_owned(),
+                l7_proto: 5.0,
+                l4_dst_port: 53,
+                l4_src_port: 40000 + i,
+                flow_duration_milliseconds: 12,
+                protocol: 17,
+                tcp_flags: 0,
+            })
+            .collect()
+    }
+
+    #[test_case(Compression::None; "plain")]
+    #[test_case(Compression::Gzip; "gzip")]
+    #[test_case(Compression::Zstd; "zstd")]
+    fn test_exported_flows_are_imported_back(compression: Compression) {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("flows");
+
+        let written = flows(500);
+        {
+            let exporter = FileExporter::new(ExportSettings {
+                dir: dir.clone(),
+                max_file_bytes: 2048,
+                compression,
+                write_buffer_bytes: 512,
+            })
+            .unwrap();
+
+            for flow in written.iter() {
+                block_on(exporter.export(&flow.encode_to_vec(), "host")).unwrap();
+            }
+            // files are finalized when the exporter is dropped
+        }
+
+        let files = flow_files(&dir).unwrap();
+        assert!(files.len() > 1, "files should be rotated");
+        // every file is readable on its own
+        for file in files.iter() {
+            let importer = FileImporter::new(FileSettings { path: file.clone() }).unwrap();
+            assert!(!block_on(importer.import()).unwrap().is_empty());
+        }
+
+        let importer = FileImporter::new(FileSettings { path: dir.clone() }).unwrap();
+        let mut read = Vec::new();
+        loop {
+            match block_on(importer.import()) {
+                Ok(mut batch) => read.append(&mut batch),
+                Err(e) => {
+                    assert_eq!(e, ImporterError::Exhausted);
+                    break;
+                }
+            }
+        }
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn test_truncated_file_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        let flow = flows(1).remove(0).encode_length_delimited_to_vec();
+        fs::write(dir.join("flows-1-000001.pb"), &flow[..flow.len() - 1]).unwrap();
+
+        let importer = FileImporter::new(FileSettings { path: dir.clone() }).unwrap();
+        assert!(matches!(
+            block_on(importer.import()),
+            Err(ImporterError::IoErr(_))
+        ));
+    }
+}
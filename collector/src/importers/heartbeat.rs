@@ -0,0 +1,89 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use prost::Message;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+
+use crate::admin::IngestionControl;
+use crate::metrics::HEARTBEATS;
+use crate::pb::FlowMessage;
+
+/// Address heartbeats are marked with if not configured, reserved for documentation by
+/// RFC 5737 so it never shows up in real traffic
+pub const DEFAULT_HEARTBEAT_MARKER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeartbeatSettings {
+    pub interval: Duration,
+    /// Source and destination address of every heartbeat
+    pub marker: Ipv4Addr,
+}
+
+/// Synthetic flow marked by its addresses, exported like an imported one so its arrival in
+/// storage proves every stage of the pipeline works
+pub fn heartbeat(marker: Ipv4Addr) -> FlowMessage {
+    FlowMessage {
+        ipv4_src_addr: marker.to_string(),
+        ipv4_dst_addr: marker.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Sends a heartbeat into the exporter channel every interval until ingestion is stopped.
+/// Heartbeats skip the importer, so they are neither transformed, sampled nor rate limited,
+/// and one which does not fit into a full channel is skipped rather than waited for
+pub async fn run(settings: HeartbeatSettings, tx: Sender<Vec<u8>>, control: Arc<IngestionControl>) {
+    info!(
+        "sending heartbeats from {} every {:?}",
+        settings.marker, settings.interval
+    );
+    let payload = heartbeat(settings.marker).encode_to_vec();
+    let mut interval = tokio::time::interval(settings.interval);
+
+    loop {
+        // the exporter channel stays open as long as this sender does
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = control.wait_until_stopped() => break,
+        }
+
+        let outcome = match tx.try_send(payload.clone()) {
+            Ok(()) => "sent",
+            Err(TrySendError::Full(_)) => "skipped",
+            Err(TrySendError::Closed(_)) => break,
+        };
+        HEARTBEATS.with_label_values(&[outcome]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_heartbeats_are_sent_until_stopped() {
+        let control = Arc::new(IngestionControl::default());
+        let (tx, mut rx) = mpsc::channel(1);
+        let settings = HeartbeatSettings {
+            interval: Duration::from_millis(10),
+            marker: DEFAULT_HEARTBEAT_MARKER,
+        };
+        let task = tokio::spawn(run(settings, tx, control.clone()));
+
+        for _ in 0..3 {
+            let msg = rx.recv().await.unwrap();
+            let flow = FlowMessage::decode(msg.as_slice()).unwrap();
+            assert_eq!(flow.ipv4_src_addr, "192.0.2.1");
+            assert_eq!(flow, heartbeat(DEFAULT_HEARTBEAT_MARKER));
+        }
+
+        control.stop();
+        task.await.unwrap();
+        // the sender is dropped, so the exporter channel can close
+        while rx.recv().await.is_some() {}
+    }
+}
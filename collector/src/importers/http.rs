@@ -0,0 +1,307 @@
+use std::net::SocketAddr;
+
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use async_trait::async_trait;
+use log::{debug, info};
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex};
+
+use super::{
+    allowlist::SourceAllowlist, decode::ImportedFlow, errors::ImporterError, import::Import,
+};
+
+use crate::metrics::{HTTP_LINES, REJECTED_SOURCE_MESSAGES};
+use crate::pb::FlowMessage;
+
+/// Path flows are posted to if not configured
+pub const DEFAULT_HTTP_PATH: &str = "/flows";
+/// Requests with a larger body are rejected if not configured
+pub const DEFAULT_HTTP_MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Maximum number of flows returned by a single import
+const HTTP_IMPORT_BATCH_SIZE: usize = 128;
+/// Flows posted and not imported yet, requests wait once it is full
+const HTTP_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    pub bind_addr: String,
+    pub path: String,
+    pub max_body_bytes: usize,
+    /// requests of a peer outside the allowlist are refused
+    pub allowlist: SourceAllowlist,
+}
+
+/// Accepts flows posted by agents which can only speak http, as JSON lines with
+/// a flow in the nProbe json format on each line
+pub struct HttpImporter {
+    addr: SocketAddr,
+    flows: Mutex<mpsc::Receiver<ImportedFlow>>,
+    server: ServerHandle,
+}
+
+#[derive(Debug, Serialize)]
+struct LineError {
+    /// numbered from 1, blank lines included
+    line: usize,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct IngestResponse {
+    accepted: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<LineError>,
+}
+
+/// Decodes every line of the body on its own, so a malformed line does not fail the others.
+/// Blank lines are skipped
+fn decode_lines(body: &[u8]) -> (Vec<FlowMessage>, Vec<LineError>) {
+    let mut flows = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in body.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        match serde_json::from_slice::<FlowMessage>(line) {
+            Ok(flow) => flows.push(flow),
+            Err(e) => errors.push(LineError {
+                line: i + 1,
+                error: e.to_string(),
+            }),
+        }
+    }
+    (flows, errors)
+}
+
+/// Well formed lines are imported even when others are not, the response tells which ones failed
+async fn ingest(
+    req: HttpRequest,
+    body: web::Bytes,
+    tx: web::Data<mpsc::Sender<ImportedFlow>>,
+    allowlist: web::Data<SourceAllowlist>,
+) -> HttpResponse {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if !allowlist.allows(peer) {
+        REJECTED_SOURCE_MESSAGES.with_label_values(&["http"]).inc();
+        debug!("refused flows posted by {:?}, not an allowed source", peer);
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let (flows, errors) = decode_lines(&body);
+    HTTP_LINES
+        .with_label_values(&["accepted"])
+        .inc_by(flows.len() as u64);
+    HTTP_LINES
+        .with_label_values(&["rejected"])
+        .inc_by(errors.len() as u64);
+
+    let accepted = flows.len();
+    for flow in flows {
+        if tx.send(flow.into()).await.is_err() {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+    }
+
+    if errors.is_empty() {
+        HttpResponse::Ok().json(IngestResponse { accepted, errors })
+    } else {
+        debug!("rejected {} posted lines", errors.len());
+        HttpResponse::BadRequest().json(IngestResponse { accepted, errors })
+    }
+}
+
+impl HttpImporter {
+    /// Has to be called within a tokio runtime, requests are accepted right away
+    pub fn new(settings: HttpSettings) -> Result<Self, ImporterError> {
+        let (tx, rx) = mpsc::channel(HTTP_QUEUE_CAPACITY);
+        let tx = web::Data::new(tx);
+        let allowlist = web::Data::new(settings.allowlist.clone());
+        let path = settings.path.clone();
+        let limit = settings.max_body_bytes;
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(tx.clone())
+                .app_data(allowlist.clone())
+                .app_data(web::PayloadConfig::new(limit))
+                .route(&path, web::post().to(ingest))
+        })
+        .workers(1)
+        .bind(&settings.bind_addr)?;
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+        info!(
+            "listening for flows posted to http://{}{}",
+            addr, settings.path
+        );
+
+        Ok(Self {
+            addr,
+            flows: Mutex::new(rx),
+            server: handle,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the first flow, then takes whatever else is already queued
+    async fn recv_batch(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        let mut flows = self.flows.lock().await;
+        let first = flows.recv().await.ok_or(ImporterError::Exhausted)?;
+
+        let mut batch = Vec::with_capacity(HTTP_IMPORT_BATCH_SIZE);
+        batch.push(first);
+        while batch.len() < HTTP_IMPORT_BATCH_SIZE {
+            match flows.try_recv() {
+                Ok(flow) => batch.push(flow),
+                Err(_) => break,
+            }
+        }
+        Ok(batch)
+    }
+}
+
+impl Drop for HttpImporter {
+    fn drop(&mut self) {
+        // the stop command is sent right away, its completion is not waited for
+        drop(self.server.stop(false));
+    }
+}
+
+#[async_trait]
+impl Import for HttpImporter {
+    async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
+        Ok(self
+            .recv_batch()
+            .await?
+            .into_iter()
+            .map(|f| f.flow)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn importer(max_body_bytes: usize) -> HttpImporter {
+        importer_allowing(max_body_bytes, SourceAllowlist::default())
+    }
+
+    fn importer_allowing(max_body_bytes: usize, allowlist: SourceAllowlist) -> HttpImporter {
+        HttpImporter::new(HttpSettings {
+            bind_addr: "127.0.0.1:0".to_owned(),
+            path: DEFAULT_HTTP_PATH.to_owned(),
+            max_body_bytes,
+            allowlist,
+        })
+        .unwrap()
+    }
+
+    fn line(flow: &FlowMessage) -> String {
+        format!(
+            r#"{{"OUT_BYTES":{},"OUT_PKTS":0,"IN_BYTES":0,"IN_PKTS":0,"IPV4_SRC_ADDR":"{}","IPV4_DST_ADDR":"{}","L7_PROTO":0,"L4_DST_PORT":0,"L4_SRC_PORT":0,"FLOW_DURATION_MILLISECONDS":0,"PROTOCOL":{},"TCP_FLAGS":0}}"#,
+            flow.out_bytes, flow.ipv4_src_addr, flow.ipv4_dst_addr, flow.protocol
+        )
+    }
+
+    async fn post(importer: &HttpImporter, body: String) -> (u16, serde_json::Value) {
+        let response = reqwest::Client::new()
+            .post(format!(
+                "http://{}{}",
+                importer.local_addr(),
+                DEFAULT_HTTP_PATH
+            ))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        let body = response.text().await.unwrap();
+        (status, serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_posted_lines_are_imported() {
+        let importer = importer(DEFAULT_HTTP_MAX_BODY_BYTES);
+        let flows = (0..3)
+            .map(|i| FlowMessage {
+                out_bytes: i,
+                ipv4_src_addr: "10.0.0.2".to_owned(),
+                ipv4_dst_addr: "10.0.0.3".to_owned(),
+                protocol: 6,
+                ..Default::default()
+            })
+            .collect::<Vec<FlowMessage>>();
+        let body = flows.iter().map(line).collect::<Vec<_>>().join("\n") + "\n";
+
+        let (status, response) = post(&importer, body).await;
+        assert_eq!(status, 200);
+        assert_eq!(response, serde_json::json!({ "accepted": 3 }));
+
+        let mut imported = Vec::new();
+        while imported.len() < flows.len() {
+            imported.extend(importer.import().await.unwrap());
+        }
+        assert_eq!(imported, flows);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_lines_are_rejected_alone() {
+        let importer = importer(DEFAULT_HTTP_MAX_BODY_BYTES);
+        let flow = FlowMessage {
+            protocol: 17,
+            ..Default::default()
+        };
+        let body = format!("{}\n\n{{\"PROTOCOL\":\n{}\n", line(&flow), line(&flow));
+
+        let (status, response) = post(&importer, body).await;
+        assert_eq!(status, 400);
+        assert_eq!(response["accepted"], 2);
+        assert_eq!(response["errors"].as_array().unwrap().len(), 1);
+        assert_eq!(response["errors"][0]["line"], 3);
+
+        let mut imported = Vec::new();
+        while imported.len() < 2 {
+            imported.extend(importer.import().await.unwrap());
+        }
+        assert_eq!(imported, vec![flow.clone(), flow]);
+    }
+
+    #[tokio::test]
+    async fn test_large_bodies_are_refused() {
+        let importer = importer(64);
+        let body = line(&FlowMessage::default());
+        assert!(body.len() > 64);
+
+        let (status, _) = post(&importer, body).await;
+        assert_eq!(status, 413);
+    }
+
+    #[tokio::test]
+    async fn test_senders_outside_allowlist_are_refused() {
+        let body = line(&FlowMessage::default());
+
+        let refusing =
+            importer_allowing(DEFAULT_HTTP_MAX_BODY_BYTES, "10.0.0.0/8".parse().unwrap());
+        let (status, _) = post(&refusing, body.clone()).await;
+        assert_eq!(status, 403);
+
+        let allowing = importer_allowing(
+            DEFAULT_HTTP_MAX_BODY_BYTES,
+            "10.0.0.0/8,127.0.0.1".parse().unwrap(),
+        );
+        let (status, _) = post(&allowing, body).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            allowing.import().await.unwrap(),
+            vec![FlowMessage::default()]
+        );
+    }
+}
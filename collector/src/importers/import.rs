@@ -1,30 +1,108 @@
 use log::{error, info};
 
-use tokio::sync::mpsc::Sender;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
 
+use super::completeness::Completeness;
+use super::decode::ImportedFlow;
+use super::defaults::FieldDefaults;
+use super::drop_policy::PolicySender;
 use super::errors::ImporterError;
+use super::rate_limit::RateLimiter;
+use super::transform::Transforms;
 use async_trait::async_trait;
 
-use prost::Message;
-
+use crate::admin::IngestionControl;
+use crate::metrics::PAYLOAD_BYTES;
 use crate::pb::FlowMessage;
 
 #[async_trait]
 pub trait Import: Sync + Send {
     async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError>;
+
+    /// Flows along with their fields unknown to `FlowMessage`, so they are exported as well.
+    /// Importers of protobuf keep them, others have none
+    async fn import_with_unknown_fields(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        Ok(self
+            .import()
+            .await?
+            .into_iter()
+            .map(ImportedFlow::from)
+            .collect())
+    }
+
+    /// Address of the single peer flows are received from, `None` when there is no such peer
+    fn source_addr(&self) -> Option<Ipv4Addr> {
+        None
+    }
 }
 
-pub async fn run(importer: impl Import, tx: Sender<Vec<u8>>) {
+#[async_trait]
+impl Import for Box<dyn Import> {
+    async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
+        self.as_ref().import().await
+    }
+
+    async fn import_with_unknown_fields(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        self.as_ref().import_with_unknown_fields().await
+    }
+
+    fn source_addr(&self) -> Option<Ipv4Addr> {
+        self.as_ref().source_addr()
+    }
+}
+
+pub async fn run(
+    importer: impl Import,
+    mut tx: PolicySender,
+    control: Arc<IngestionControl>,
+    defaults: FieldDefaults,
+    transforms: Transforms,
+    completeness: Completeness,
+    mut rate_limit: Option<RateLimiter>,
+) {
     info!("Spawned importer...");
+    let imported_bytes = PAYLOAD_BYTES.with_label_values(&["imported"]);
+
+    while !control.is_stopped() {
+        if control.is_paused() {
+            info!("Importer paused...");
+            control.wait_while_paused().await;
+            info!("Importer resumed...");
+            continue;
+        }
+
+        // importers block until flows arrive, stopping must not wait for them
+        let mut m = tokio::select! {
+            m = importer.import_with_unknown_fields() => match m {
+                Ok(m) => m,
+                Err(_) => break,
+            },
+            _ = control.wait_until_stopped() => break,
+        };
+
+        for msg in m.iter_mut() {
+            completeness.observe(&msg.flow);
+            defaults.apply(&mut msg.flow);
+            transforms.apply(&mut msg.flow);
+        }
 
-    while let Ok(m) = importer.import().await {
         let mut buffer: Vec<u8> = Vec::with_capacity(4092);
 
         for msg in m.iter() {
+            if let Some(limiter) = rate_limit.as_mut() {
+                if !limiter.admit().await {
+                    continue;
+                }
+            }
+
+            // every message is encoded on its own, not appended to the previous one
+            buffer.clear();
             if let Err(e) = msg.encode(&mut buffer) {
                 error!("FlowMessage could not be encoded to bytes: {}", e);
                 continue;
             }
+            imported_bytes.observe(buffer.len() as f64);
 
             if let Err(e) = tx.send(buffer.clone()).await {
                 error!(
@@ -36,9 +114,24 @@ pub async fn run(importer: impl Import, tx: Sender<Vec<u8>>) {
         }
     }
 
+    // nothing accepted before stopping is left behind
+    if let Err(e) = tx.drain_pending().await {
+        error!(
+            "exporter channel closed before pending messages were sent: {:?}",
+            e
+        );
+    }
     info!("Closing importer...");
 }
 
+/// Frame received by a subscriber along with the address of the peer which sent it, if known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub frame: Vec<u8>,
+    pub peer: Option<IpAddr>,
+}
+
 pub trait Subscriber {
-    fn recv(&self) -> Result<Vec<u8>, ImporterError>;
+    /// Blocks until a frame arrives or a poll interval passes, `None` when nothing arrived
+    fn recv(&self) -> Result<Option<Received>, ImporterError>;
 }
@@ -1,6 +1,34 @@
+pub mod allowlist;
+pub mod completeness;
+pub mod connect;
+pub mod decode;
+pub mod defaults;
+pub mod drop_policy;
 pub mod errors;
+pub mod file;
+pub mod heartbeat;
+pub mod http;
 mod import;
+pub mod rate_limit;
+pub mod ring;
+pub mod spill;
+pub mod stream;
+pub mod transform;
+pub mod unix_socket;
 pub mod zmq;
 
-pub use self::import::{run, Import};
+pub use self::allowlist::SourceAllowlist;
+pub use self::completeness::Completeness;
+pub use self::defaults::FieldDefaults;
+pub use self::drop_policy::{DropPolicy, PolicySender};
+pub use self::file::{FileImporter, FileSettings};
+pub use self::heartbeat::HeartbeatSettings;
+pub use self::http::{HttpImporter, HttpSettings};
+pub use self::import::{run, Import, Received, Subscriber};
+pub use self::rate_limit::{RateLimitPolicy, RateLimitSettings, RateLimiter};
+pub use self::ring::{Ring, RingSettings};
+pub use self::spill::{Spill, SpillDrainMode, SpillSettings};
+pub use self::stream::FlowStream;
+pub use self::transform::Transforms;
+pub use self::unix_socket::{UnixSocketImporter, UnixSocketSettings};
 pub use self::zmq::{ZMQSettings, ZMQ};
@@ -0,0 +1,204 @@
+use core::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use prometheus::IntCounter;
+use tokio::time::sleep;
+
+use common::parse::UnknownVariant;
+
+use crate::metrics::RATE_LIMITED_MESSAGES;
+
+/// What the importer does with messages over the rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitPolicy {
+    /// waits until the message fits the limit, backpressures the source
+    #[default]
+    Block,
+    /// drops the message
+    Drop,
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop" => Ok(Self::Drop),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Block => "block",
+            Self::Drop => "drop",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitSettings {
+    pub max_messages_per_sec: u64,
+    /// messages let through at once after the source was quiet
+    pub burst: u64,
+    pub policy: RateLimitPolicy,
+}
+
+/// Tokens refill continuously at `rate` per second up to `burst`, a message takes one
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so a burst is let through right away
+    fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate: rate.max(1) as f64,
+            burst,
+            tokens: burst,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.refilled_at = now;
+    }
+
+    /// Takes a token, otherwise tells how long until one is refilled
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
+
+/// Protective ceiling on the rate messages are imported at, unlike sampling it does not
+/// reduce volume below the limit
+pub struct RateLimiter {
+    bucket: TokenBucket,
+    policy: RateLimitPolicy,
+    limited: IntCounter,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings, source: &str) -> Self {
+        Self {
+            bucket: TokenBucket::new(
+                settings.max_messages_per_sec,
+                settings.burst,
+                Instant::now(),
+            ),
+            policy: settings.policy,
+            limited: RATE_LIMITED_MESSAGES
+                .with_label_values(&[source, &settings.policy.to_string()]),
+        }
+    }
+
+    /// Returns false when the message is over the limit and has to be dropped
+    pub async fn admit(&mut self) -> bool {
+        let mut limited = false;
+        loop {
+            match self.bucket.take(Instant::now()) {
+                Ok(()) => return true,
+                Err(_) if self.policy == RateLimitPolicy::Drop => {
+                    self.limited.inc();
+                    return false;
+                }
+                Err(wait) => {
+                    if !limited {
+                        limited = true;
+                        self.limited.inc();
+                    }
+                    sleep(wait).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio_test::block_on;
+
+    #[test]
+    fn test_bucket_refills_up_to_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, 3, start);
+
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), Ok(()));
+        }
+        let wait = bucket.take(start).unwrap_err();
+        assert!(wait > Duration::from_millis(99) && wait <= Duration::from_millis(100));
+
+        // a token is refilled every 100ms
+        assert_eq!(bucket.take(start + Duration::from_millis(150)), Ok(()));
+        assert!(bucket.take(start + Duration::from_millis(150)).is_err());
+
+        // a long pause refills no more than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(later), Ok(()));
+        }
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn test_messages_over_limit_are_dropped() {
+        let mut limiter = RateLimiter::new(
+            &RateLimitSettings {
+                max_messages_per_sec: 1,
+                burst: 2,
+                policy: RateLimitPolicy::Drop,
+            },
+            "test",
+        );
+
+        let admitted = (0..5)
+            .map(|_| block_on(limiter.admit()))
+            .collect::<Vec<bool>>();
+        assert_eq!(admitted, vec![true, true, false, false, false]);
+        assert_eq!(limiter.limited.get(), 3);
+    }
+
+    #[test]
+    fn test_messages_over_limit_wait() {
+        let mut limiter = RateLimiter::new(
+            &RateLimitSettings {
+                max_messages_per_sec: 50,
+                burst: 1,
+                policy: RateLimitPolicy::Block,
+            },
+            "test-block",
+        );
+
+        let start = Instant::now();
+        assert!(block_on(limiter.admit()));
+        assert!(block_on(limiter.admit()));
+        assert!(start.elapsed() >= Duration::from_millis(15));
+        assert_eq!(limiter.limited.get(), 1);
+    }
+
+    #[test]
+    fn test_policy_names() {
+        assert_eq!("drop".parse::<RateLimitPolicy>(), Ok(RateLimitPolicy::Drop));
+        assert!("drop_newest".parse::<RateLimitPolicy>().is_err());
+    }
+}
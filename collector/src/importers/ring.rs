@@ -0,0 +1,409 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::{error, info, warn};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+
+use crate::metrics::{RING_BYTES, RING_OVERWRITTEN};
+
+const MAGIC: &[u8; 8] = b"KRWRING1";
+/// magic, capacity, head and tail, followed by the data region
+const HEADER_BYTES: usize = 32;
+/// Every record is prefixed with its big endian u32 length
+const RECORD_HEADER_BYTES: u64 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RingSettings {
+    pub path: PathBuf,
+    /// Size of the data region, the oldest messages are overwritten once it is full
+    pub capacity_bytes: u64,
+}
+
+/// Shared writable mapping of the whole ring file
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is only accessed with the ring state locked
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(settings: &RingSettings) -> io::Result<Self> {
+        let len = HEADER_BYTES + settings.capacity_bytes as usize;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&settings.path)?;
+        if file.metadata()?.len() != len as u64 {
+            file.set_len(len as u64)?;
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // the mapping stays valid after the file is closed
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::msync(self.ptr as *mut libc::c_void, self.len, libc::MS_ASYNC);
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+struct RingState {
+    mapping: Mapping,
+    capacity: u64,
+    /// positions grow forever, the data region is addressed modulo `capacity`
+    head: u64,
+    tail: u64,
+    closed: bool,
+}
+
+impl RingState {
+    fn used(&self) -> u64 {
+        self.tail - self.head
+    }
+
+    fn data(&mut self) -> &mut [u8] {
+        &mut self.mapping.bytes()[HEADER_BYTES..]
+    }
+
+    fn write_header(&mut self) {
+        let (capacity, head, tail) = (self.capacity, self.head, self.tail);
+        let header = &mut self.mapping.bytes()[..HEADER_BYTES];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..16].copy_from_slice(&capacity.to_be_bytes());
+        header[16..24].copy_from_slice(&head.to_be_bytes());
+        header[24..32].copy_from_slice(&tail.to_be_bytes());
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) {
+        let start = (pos % self.capacity) as usize;
+        let first = buf.len().min(self.capacity as usize - start);
+        let data = self.data();
+        data[start..start + first].copy_from_slice(&buf[..first]);
+        data[..buf.len() - first].copy_from_slice(&buf[first..]);
+    }
+
+    fn read_at(&mut self, pos: u64, len: usize) -> Vec<u8> {
+        let start = (pos % self.capacity) as usize;
+        let first = len.min(self.capacity as usize - start);
+        let data = self.data();
+        let mut buf = Vec::with_capacity(len);
+        buf.extend_from_slice(&data[start..start + first]);
+        buf.extend_from_slice(&data[..len - first]);
+        buf
+    }
+
+    /// Length of the record at `head`, `None` when it does not fit what is stored
+    fn front_len(&mut self) -> Option<u64> {
+        if self.used() < RECORD_HEADER_BYTES {
+            return None;
+        }
+        let len = self.read_at(self.head, RECORD_HEADER_BYTES as usize);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as u64;
+        (RECORD_HEADER_BYTES + len <= self.used()).then_some(len)
+    }
+
+    /// Forgets everything stored, used when the ring is found inconsistent
+    fn reset(&mut self) {
+        self.head = self.tail;
+        self.write_header();
+    }
+}
+
+/// Fixed size memory-mapped file used as a ring buffer of messages between the importer
+/// and the exporter channel. Messages are kept across restarts, when the ring is full
+/// the oldest ones are overwritten
+pub struct Ring {
+    settings: RingSettings,
+    state: Mutex<RingState>,
+    written: Notify,
+}
+
+impl Ring {
+    /// Opens the ring file, messages left by the previous run are drained first.
+    /// A file of a different capacity is started over
+    pub fn open(settings: RingSettings) -> io::Result<Arc<Self>> {
+        if settings.capacity_bytes <= RECORD_HEADER_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ring capacity is too small",
+            ));
+        }
+
+        let mut mapping = Mapping::new(&settings)?;
+        let header = &mapping.bytes()[..HEADER_BYTES];
+        let (head, tail) =
+            if &header[..8] == MAGIC && read_u64(header, 8) == settings.capacity_bytes {
+                (read_u64(header, 16), read_u64(header, 24))
+            } else {
+                (0, 0)
+            };
+
+        let mut state = RingState {
+            mapping,
+            capacity: settings.capacity_bytes,
+            head,
+            tail,
+            closed: false,
+        };
+        if state.tail < state.head || state.used() > state.capacity {
+            warn!(
+                "ring {} is inconsistent, starting it over",
+                settings.path.display()
+            );
+            state.tail = 0;
+            state.reset();
+        }
+        state.write_header();
+
+        if state.used() > 0 {
+            info!(
+                "found {} bytes in ring {}, draining them first",
+                state.used(),
+                settings.path.display()
+            );
+        }
+        RING_BYTES.set(state.used() as i64);
+
+        Ok(Arc::new(Self {
+            settings,
+            state: Mutex::new(state),
+            written: Notify::new(),
+        }))
+    }
+
+    fn state(&self) -> MutexGuard<RingState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.state().used()
+    }
+
+    /// Never waits, the oldest messages are overwritten to make room.
+    /// Fails only for a message larger than the whole ring
+    pub fn push(&self, msg: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        let record = RECORD_HEADER_BYTES + msg.len() as u64;
+        if record > state.capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message of {} bytes does not fit the ring", msg.len()),
+            ));
+        }
+
+        while state.used() + record > state.capacity {
+            match state.front_len() {
+                Some(len) => state.head += RECORD_HEADER_BYTES + len,
+                None => state.reset(),
+            }
+            RING_OVERWRITTEN.inc();
+        }
+        // head is moved past overwritten records before their bytes are reused
+        state.write_header();
+
+        let mut buf = Vec::with_capacity(record as usize);
+        buf.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        buf.extend_from_slice(msg);
+        let tail = state.tail;
+        state.write_at(tail, &buf);
+        // the record is complete before it becomes visible
+        state.tail += record;
+        state.write_header();
+
+        RING_BYTES.set(state.used() as i64);
+        drop(state);
+
+        self.written.notify_one();
+        Ok(())
+    }
+
+    /// Oldest message with its position, it stays in the ring until consumed
+    fn front(&self) -> Option<(u64, Vec<u8>)> {
+        let mut state = self.state();
+        let len = match state.front_len() {
+            Some(len) => len,
+            None => {
+                if state.used() > 0 {
+                    warn!(
+                        "ring {} has a truncated record, skipping {} bytes",
+                        self.settings.path.display(),
+                        state.used()
+                    );
+                    state.reset();
+                }
+                return None;
+            }
+        };
+        let head = state.head;
+        let msg = state.read_at(head + RECORD_HEADER_BYTES, len as usize);
+        Some((head, msg))
+    }
+
+    /// Removes the message at `position`, unless it was overwritten in the meantime
+    fn consume(&self, position: u64, len: usize) {
+        let mut state = self.state();
+        if state.head == position {
+            state.head += RECORD_HEADER_BYTES + len as u64;
+            state.write_header();
+        }
+        RING_BYTES.set(state.used() as i64);
+    }
+
+    /// Stops draining once everything written so far is sent
+    pub fn close(&self) {
+        self.state().closed = true;
+        self.written.notify_one();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+}
+
+/// Moves messages from the ring into the exporter channel, oldest first.
+/// A message is removed from the ring only once the channel took it
+pub async fn drain(ring: Arc<Ring>, tx: Sender<Vec<u8>>) {
+    loop {
+        // checked before reading, so nothing written before closing is left behind
+        let closed = ring.is_closed();
+        let (position, msg) = match ring.front() {
+            Some(front) => front,
+            None if closed => break,
+            None => {
+                ring.written.notified().await;
+                continue;
+            }
+        };
+
+        let len = msg.len();
+        if tx.send(msg).await.is_err() {
+            error!(
+                "exporter channel is closed, messages are kept in ring {}",
+                ring.settings.path.display()
+            );
+            return;
+        }
+        ring.consume(position, len);
+    }
+
+    info!("ring drained");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio::sync::mpsc;
+
+    fn settings(dir: &tempfile::TempDir, capacity_bytes: u64) -> RingSettings {
+        RingSettings {
+            path: dir.path().join("ring"),
+            capacity_bytes,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_drained_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(&dir, 60);
+        let ring = Ring::open(settings.clone()).unwrap();
+
+        // records wrap around the end of the data region
+        let (tx, mut rx) = mpsc::channel(1);
+        let drainer = tokio::spawn(drain(ring.clone(), tx));
+        for i in 0..10u8 {
+            ring.push(&[i; 12]).unwrap();
+            assert_eq!(rx.recv().await, Some(vec![i; 12]));
+        }
+        ring.close();
+        drainer.await.unwrap();
+        assert_eq!(ring.bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_messages_are_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(&dir, 48);
+        let ring = Ring::open(settings.clone()).unwrap();
+        let overwritten = RING_OVERWRITTEN.get();
+
+        for i in 0..5u8 {
+            ring.push(&[i; 12]).unwrap();
+        }
+        assert_eq!(RING_OVERWRITTEN.get() - overwritten, 2);
+        assert!(ring.push(&[0; 48]).is_err());
+        ring.close();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        drain(ring, tx).await;
+        for i in 2..5u8 {
+            assert_eq!(rx.recv().await, Some(vec![i; 12]));
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ring_left_by_previous_run_is_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(&dir, 1024);
+        {
+            let ring = Ring::open(settings.clone()).unwrap();
+            ring.push(b"first").unwrap();
+            ring.push(b"second").unwrap();
+        }
+
+        let ring = Ring::open(settings.clone()).unwrap();
+        assert_eq!(ring.bytes(), 19);
+        ring.push(b"third").unwrap();
+        ring.close();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        drain(ring, tx).await;
+        assert_eq!(rx.recv().await, Some(b"first".to_vec()));
+        assert_eq!(rx.recv().await, Some(b"second".to_vec()));
+        assert_eq!(rx.recv().await, Some(b"third".to_vec()));
+
+        // a different capacity starts the ring over
+        let ring = Ring::open(RingSettings {
+            capacity_bytes: 512,
+            ..settings.clone()
+        })
+        .unwrap();
+        assert_eq!(ring.bytes(), 0);
+    }
+}
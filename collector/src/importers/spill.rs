@@ -0,0 +1,582 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::{error, info, warn};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tokio::task;
+
+use common::parse::UnknownVariant;
+
+use crate::metrics::SPILL_BYTES;
+
+/// Segments are rotated at this size, the drainer reads a whole segment at once
+const SEGMENT_MAX_BYTES: u64 = 4 * 1024 * 1024;
+const SEGMENT_EXTENSION: &str = "spill";
+/// Every record is prefixed with its big endian u32 length
+const RECORD_HEADER_BYTES: u64 = 4;
+
+/// How the spill left by the previous run is drained relative to live ingestion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpillDrainMode {
+    /// the importer is held paused until the leftovers are exported, so messages keep their
+    /// order across the restart
+    BeforeLive,
+    /// the importer starts right away, live messages are exported alongside the leftovers
+    #[default]
+    Concurrent,
+}
+
+impl FromStr for SpillDrainMode {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "before-live" => Ok(Self::BeforeLive),
+            "concurrent" => Ok(Self::Concurrent),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for SpillDrainMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::BeforeLive => "before-live",
+            Self::Concurrent => "concurrent",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpillSettings {
+    pub dir: PathBuf,
+    /// Messages which would exceed it are handled by the drop policy
+    pub max_bytes: u64,
+    pub drain_mode: SpillDrainMode,
+}
+
+struct Segment {
+    seq: u64,
+    bytes: u64,
+}
+
+/// Segment file records are appended to
+struct SegmentWriter {
+    seq: u64,
+    file: File,
+    /// length of the file, it is truncated back to it when a record is not fully written
+    bytes: u64,
+}
+
+struct SpillState {
+    segments: VecDeque<Segment>,
+    next_seq: u64,
+    bytes: u64,
+    /// a record is being written to the last segment, which is not taken until it is complete
+    appending: bool,
+    /// segment taken by the drainer and not sent yet
+    draining: Option<u64>,
+    /// no more messages are going to be appended
+    closed: bool,
+}
+
+/// Append-only disk queue of messages which did not fit into the exporter channel.
+/// Files are written and read only on blocking threads, `state` is never held while they are
+pub struct Spill {
+    settings: SpillSettings,
+    segment_max_bytes: u64,
+    state: Mutex<SpillState>,
+    /// serializes appends, the segment it writes is sealed once the drainer took it
+    writer: Mutex<Option<SegmentWriter>>,
+    /// segments before it were left by the previous run
+    live_from: u64,
+    appended: Notify,
+    finished: Notify,
+}
+
+impl Spill {
+    /// Opens the spill directory, segments left by the previous run are drained first
+    pub fn open(settings: SpillSettings) -> io::Result<Arc<Self>> {
+        Self::open_with_segment_bytes(settings, SEGMENT_MAX_BYTES)
+    }
+
+    fn open_with_segment_bytes(
+        settings: SpillSettings,
+        segment_max_bytes: u64,
+    ) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(&settings.dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&settings.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(seq) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                segments.push(Segment {
+                    seq,
+                    bytes: fs::metadata(&path)?.len(),
+                });
+            }
+        }
+        segments.sort_by_key(|s| s.seq);
+
+        let bytes = segments.iter().map(|s| s.bytes).sum();
+        if !segments.is_empty() {
+            info!(
+                "found {} spill segments ({} bytes) in {}, draining them {}",
+                segments.len(),
+                bytes,
+                settings.dir.display(),
+                match settings.drain_mode {
+                    SpillDrainMode::BeforeLive => "before live ingestion starts",
+                    SpillDrainMode::Concurrent => "alongside live ingestion",
+                }
+            );
+        }
+        SPILL_BYTES.set(bytes as i64);
+        let next_seq = segments.last().map_or(0, |s| s.seq + 1);
+
+        Ok(Arc::new(Self {
+            settings,
+            segment_max_bytes,
+            state: Mutex::new(SpillState {
+                next_seq,
+                segments: segments.into(),
+                bytes,
+                appending: false,
+                draining: None,
+                closed: false,
+            }),
+            writer: Mutex::new(None),
+            live_from: next_seq,
+            appended: Notify::new(),
+            finished: Notify::new(),
+        }))
+    }
+
+    fn state(&self) -> MutexGuard<SpillState> {
+        self.state.lock().unwrap()
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.settings
+            .dir
+            .join(format!("{:020}.{}", seq, SEGMENT_EXTENSION))
+    }
+
+    pub fn drain_mode(&self) -> SpillDrainMode {
+        self.settings.drain_mode
+    }
+
+    /// Nothing is spilled or being drained
+    pub fn is_empty(&self) -> bool {
+        let state = self.state();
+        state.segments.is_empty() && state.draining.is_none()
+    }
+
+    /// Nothing spilled since the spill was opened is left, new messages may skip the spill.
+    /// Leftovers of the previous run do not hold them back, they are drained first anyway
+    /// with `before-live`
+    pub fn is_empty_since_open(&self) -> bool {
+        let state = self.state();
+        let live = |seq: u64| seq >= self.live_from;
+        !state.segments.iter().any(|s| live(s.seq)) && !state.draining.map_or(false, live)
+    }
+
+    /// Segments left by the previous run are not exported yet
+    pub fn has_leftovers(&self) -> bool {
+        let state = self.state();
+        let leftover = |seq: u64| seq < self.live_from;
+        state.segments.front().map_or(false, |s| leftover(s.seq))
+            || state.draining.map_or(false, leftover)
+    }
+
+    /// Returns once the leftovers of the previous run are exported
+    pub async fn wait_for_leftovers(&self) {
+        loop {
+            // register before checking, so a segment finished in between is not missed
+            let finished = self.finished.notified();
+            if !self.has_leftovers() {
+                return;
+            }
+            finished.await;
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.state().bytes
+    }
+
+    /// Appends the message on a blocking thread, so the importer does not wait for the disk on
+    /// an async worker. The message is handed back along with the outcome, which is false when
+    /// it does not fit within `max_bytes`
+    pub async fn append(self: &Arc<Self>, msg: Vec<u8>) -> (Vec<u8>, io::Result<bool>) {
+        let spill = self.clone();
+        task::spawn_blocking(move || {
+            let appended = spill.append_blocking(&msg);
+            (msg, appended)
+        })
+        .await
+        .expect("spill append panicked")
+    }
+
+    fn append_blocking(&self, msg: &[u8]) -> io::Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        let record = RECORD_HEADER_BYTES + msg.len() as u64;
+
+        // the record is reserved first, so the segment it goes to is not drained half written
+        let rotate = {
+            let mut state = self.state();
+            if state.bytes + record > self.settings.max_bytes {
+                return Ok(false);
+            }
+            let rotate = match (writer.as_ref(), state.segments.back()) {
+                (Some(w), Some(last)) if w.seq == last.seq => {
+                    last.bytes > 0 && last.bytes + record > self.segment_max_bytes
+                }
+                // nothing opened yet or the segment was taken by the drainer
+                _ => true,
+            };
+            if rotate {
+                *writer = None;
+                state.next_seq += 1;
+                Some(state.next_seq - 1)
+            } else {
+                state.appending = true;
+                None
+            }
+        };
+
+        let written = self.write_record(&mut writer, rotate, msg);
+
+        let record = if written.is_ok() { record } else { 0 };
+        let mut state = self.state();
+        state.appending = false;
+        match rotate {
+            // a segment opened for a record which failed is kept for the next one
+            Some(seq) if writer.as_ref().map_or(false, |w| w.seq == seq) => {
+                state.segments.push_back(Segment { seq, bytes: record })
+            }
+            Some(_) => {}
+            None => state.segments.back_mut().unwrap().bytes += record,
+        }
+        state.bytes += record;
+        SPILL_BYTES.set(state.bytes as i64);
+        drop(state);
+
+        self.appended.notify_one();
+        written.map(|()| true)
+    }
+
+    /// Writes the length and the payload of a record to the segment, opening a new one first
+    /// with `rotate`. A record which is not fully written is cut off, so it does not tear the
+    /// records appended after it
+    fn write_record(
+        &self,
+        writer: &mut Option<SegmentWriter>,
+        rotate: Option<u64>,
+        msg: &[u8],
+    ) -> io::Result<()> {
+        if let Some(seq) = rotate {
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.path(seq))?;
+            *writer = Some(SegmentWriter {
+                seq,
+                file,
+                bytes: 0,
+            });
+        }
+        let w = writer.as_mut().unwrap();
+
+        let mut buf = Vec::with_capacity(RECORD_HEADER_BYTES as usize + msg.len());
+        buf.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        buf.extend_from_slice(msg);
+        if let Err(e) = w.file.write_all(&buf) {
+            if let Err(truncate) = w.file.set_len(w.bytes) {
+                // the torn record stays at the end of the segment, where replay skips it
+                error!(
+                    "unable to cut off partial record of spill segment {}: {}",
+                    self.path(w.seq).display(),
+                    truncate
+                );
+                *writer = None;
+            }
+            return Err(e);
+        }
+        w.bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Stops draining once everything spilled so far is sent
+    pub fn close(&self) {
+        self.state().closed = true;
+        self.appended.notify_one();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    /// Oldest segment, the segment being appended to is sealed when taken. It is not taken
+    /// while a record is being written to it
+    fn take_oldest(&self) -> Option<Segment> {
+        let mut state = self.state();
+        if state.appending && state.segments.len() == 1 {
+            return None;
+        }
+        let segment = state.segments.pop_front()?;
+        state.draining = Some(segment.seq);
+        Some(segment)
+    }
+
+    async fn finish(&self, segment: Segment) {
+        let path = self.path(segment.seq);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            error!("unable to remove spill segment {}: {}", path.display(), e);
+        }
+
+        let mut state = self.state();
+        state.bytes -= segment.bytes;
+        state.draining = None;
+        SPILL_BYTES.set(state.bytes as i64);
+        drop(state);
+
+        self.finished.notify_waiters();
+    }
+}
+
+/// Messages of a segment, a record truncated by a crash is skipped
+async fn read_segment(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let content = tokio::fs::read(path).await?;
+    let mut msgs = Vec::new();
+    let mut rest = content.as_slice();
+
+    while !rest.is_empty() {
+        let end = match rest {
+            [a, b, c, d, ..] => {
+                RECORD_HEADER_BYTES as usize + u32::from_be_bytes([*a, *b, *c, *d]) as usize
+            }
+            _ => usize::MAX,
+        };
+        if rest.len() < end {
+            warn!(
+                "spill segment {} has a truncated record, skipping {} bytes",
+                path.display(),
+                rest.len()
+            );
+            break;
+        }
+        msgs.push(rest[RECORD_HEADER_BYTES as usize..end].to_vec());
+        rest = &rest[end..];
+    }
+    Ok(msgs)
+}
+
+/// Moves spilled messages back into the exporter channel, oldest first.
+/// Waits for free space, so it catches up as soon as the exporter does
+pub async fn drain(spill: Arc<Spill>, tx: Sender<Vec<u8>>) {
+    loop {
+        // checked before taking, so nothing appended before closing is left behind
+        let closed = spill.is_closed();
+        let segment = match spill.take_oldest() {
+            Some(segment) => segment,
+            None if closed => break,
+            None => {
+                spill.appended.notified().await;
+                continue;
+            }
+        };
+
+        let path = spill.path(segment.seq);
+        match read_segment(&path).await {
+            Ok(msgs) => {
+                for msg in msgs {
+                    if tx.send(msg).await.is_err() {
+                        error!(
+                            "exporter channel is closed, spilled messages are kept in {}",
+                            spill.settings.dir.display()
+                        );
+                        return;
+                    }
+                }
+            }
+            Err(e) => error!(
+                "unable to read spill segment {}, dropping it: {}",
+                path.display(),
+                e
+            ),
+        }
+        spill.finish(segment).await;
+    }
+
+    info!("spill drained");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn settings(dir: &tempfile::TempDir, max_bytes: u64) -> SpillSettings {
+        SpillSettings {
+            dir: dir.path().join("spill"),
+            max_bytes,
+            drain_mode: SpillDrainMode::Concurrent,
+        }
+    }
+
+    /// Writes a segment like a previous run would have left it
+    fn seed(settings: &SpillSettings, seq: u64, msgs: &[&[u8]]) {
+        fs::create_dir_all(&settings.dir).unwrap();
+        let mut content = Vec::new();
+        for msg in msgs {
+            content.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+            content.extend_from_slice(msg);
+        }
+        fs::write(
+            settings
+                .dir
+                .join(format!("{:020}.{}", seq, SEGMENT_EXTENSION)),
+            content,
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spilled_messages_are_drained_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(&dir, 1024);
+        let spill = Spill::open_with_segment_bytes(settings.clone(), 32).unwrap();
+
+        for i in 0..10u8 {
+            assert!(spill.append(vec![i; 8]).await.1.unwrap());
+        }
+        assert_eq!(spill.bytes(), 120);
+        assert!(fs::read_dir(&settings.dir).unwrap().count() > 1);
+        spill.close();
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let drainer = tokio::spawn(drain(spill.clone(), tx));
+        for i in 0..10u8 {
+            assert_eq!(rx.recv().await, Some(vec![i; 8]));
+        }
+        drainer.await.unwrap();
+
+        assert!(spill.is_empty());
+        assert_eq!(spill.bytes(), 0);
+        assert_eq!(fs::read_dir(&settings.dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_spill_left_by_previous_run_is_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(&dir, 1024);
+        {
+            let spill = Spill::open(settings.clone()).unwrap();
+            assert!(spill.append(b"first".to_vec()).await.1.unwrap());
+            assert!(spill.append(b"second".to_vec()).await.1.unwrap());
+        }
+        // crash in the middle of writing a record
+        let segment = fs::read_dir(&settings.dir).unwrap().next().unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment.unwrap().path())
+            .unwrap();
+        file.write_all(&[0, 0, 0, 9, 1]).unwrap();
+
+        let spill = Spill::open(settings.clone()).unwrap();
+        assert!(!spill.is_empty());
+        assert!(spill.append(b"third".to_vec()).await.1.unwrap());
+        spill.close();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        drain(spill, tx).await;
+        assert_eq!(rx.recv().await, Some(b"first".to_vec()));
+        assert_eq!(rx.recv().await, Some(b"second".to_vec()));
+        assert_eq!(rx.recv().await, Some(b"third".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_live_ingestion_waits_for_leftovers_before_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = SpillSettings {
+            drain_mode: SpillDrainMode::BeforeLive,
+            ..settings(&dir, 1024)
+        };
+        seed(&settings, 3, &[b"first", b"second"]);
+        seed(&settings, 4, &[b"third"]);
+
+        let spill = Spill::open(settings.clone()).unwrap();
+        assert_eq!(spill.drain_mode(), SpillDrainMode::BeforeLive);
+        assert!(spill.has_leftovers());
+        assert!(spill.is_empty_since_open());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), spill.wait_for_leftovers())
+                .await
+                .is_err()
+        );
+
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(drain(spill.clone(), tx));
+        let importer = tokio::spawn({
+            let spill = spill.clone();
+            async move {
+                spill.wait_for_leftovers().await;
+                // started only once every leftover is in the exporter channel
+                assert!(spill.append(b"live".to_vec()).await.1.unwrap());
+                spill.close();
+            }
+        });
+
+        for msg in [b"first".as_slice(), b"second", b"third", b"live"] {
+            assert_eq!(rx.recv().await, Some(msg.to_vec()));
+        }
+        importer.await.unwrap();
+        assert!(!spill.has_leftovers());
+    }
+
+    #[test]
+    fn test_spill_is_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(&dir, 24);
+        let spill = Spill::open(settings.clone()).unwrap();
+
+        assert!(spill.append_blocking(&[0; 12]).unwrap());
+        assert!(!spill.append_blocking(&[0; 8]).unwrap());
+        // smaller message still fits
+        assert!(spill.append_blocking(&[0; 4]).unwrap());
+        assert_eq!(spill.bytes(), 24);
+    }
+
+    #[test]
+    fn test_segment_is_not_drained_while_a_record_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = Spill::open(settings(&dir, 1024)).unwrap();
+        assert!(spill.append_blocking(b"first").unwrap());
+
+        spill.state().appending = true;
+        assert!(spill.take_oldest().is_none());
+        spill.state().appending = false;
+        assert!(spill.take_oldest().is_some());
+
+        // the taken segment is sealed, the next record starts a new one
+        assert!(spill.append_blocking(b"second").unwrap());
+        assert_eq!(fs::read_dir(&spill.settings.dir).unwrap().count(), 2);
+    }
+}
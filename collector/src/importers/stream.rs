@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use log::{error, info};
+use tokio_stream::Stream;
+
+use super::errors::ImporterError;
+use super::import::Import;
+
+use crate::pb::FlowMessage;
+
+type ImportFuture = Pin<Box<dyn Future<Output = Result<Vec<FlowMessage>, ImporterError>> + Send>>;
+
+/// Flows of an importer as a stream, an alternative to `run` for custom pipelines.
+/// It ends when the importer fails or has nothing more to import
+pub struct FlowStream<I> {
+    importer: Arc<I>,
+    imported: VecDeque<FlowMessage>,
+    pending: Option<ImportFuture>,
+    finished: bool,
+}
+
+impl<I: Import + 'static> FlowStream<I> {
+    pub fn new(importer: I) -> Self {
+        Self {
+            importer: Arc::new(importer),
+            imported: VecDeque::new(),
+            pending: None,
+            finished: false,
+        }
+    }
+}
+
+impl<I: Import + 'static> Stream for FlowStream<I> {
+    type Item = FlowMessage;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(flow) = this.imported.pop_front() {
+                return Poll::Ready(Some(flow));
+            }
+            if this.finished {
+                return Poll::Ready(None);
+            }
+
+            if this.pending.is_none() {
+                let importer = this.importer.clone();
+                this.pending = Some(Box::pin(async move { importer.import().await }));
+            }
+
+            match this.pending.as_mut().unwrap().as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => {
+                    this.pending = None;
+                    match result {
+                        Ok(flows) => this.imported.extend(flows),
+                        Err(ImporterError::Exhausted) => {
+                            info!("importer exhausted, closing flow stream");
+                            this.finished = true;
+                        }
+                        Err(e) => {
+                            error!("unable to import flows, closing flow stream: {:?}", e);
+                            this.finished = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use pretty_assertions::assert_eq;
+    use std::sync::Mutex;
+    use tokio_stream::StreamExt;
+
+    struct Batches(Mutex<VecDeque<Vec<FlowMessage>>>);
+
+    #[async_trait]
+    impl Import for Batches {
+        async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
+            self.0
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(ImporterError::Exhausted)
+        }
+    }
+
+    fn flow(l4_src_port: u32) -> FlowMessage {
+        FlowMessage {
+            l4_src_port,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_flows_of_all_batches() {
+        let importer = Batches(Mutex::new(VecDeque::from(vec![
+            vec![flow(1), flow(2)],
+            vec![],
+            vec![flow(3)],
+        ])));
+
+        let ports = FlowStream::new(importer)
+            .filter(|f| f.l4_src_port != 2)
+            .map(|f| f.l4_src_port)
+            .collect::<Vec<u32>>()
+            .await;
+
+        assert_eq!(ports, vec![1, 3]);
+    }
+}
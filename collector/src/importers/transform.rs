@@ -0,0 +1,355 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use crate::pb::FlowMessage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformParseError(pub(super) String);
+
+impl fmt::Display for TransformParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid transform: {}", self.0)
+    }
+}
+
+impl std::error::Error for TransformParseError {}
+
+/// Field of a flow message a transform operates on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowField {
+    OutBytes,
+    OutPkts,
+    InBytes,
+    InPkts,
+    Ipv4SrcAddr,
+    Ipv4DstAddr,
+    L7Proto,
+    L4DstPort,
+    L4SrcPort,
+    FlowDurationMilliseconds,
+    Protocol,
+    TcpFlags,
+}
+
+impl FromStr for FlowField {
+    type Err = TransformParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "out_bytes" => Ok(Self::OutBytes),
+            "out_pkts" => Ok(Self::OutPkts),
+            "in_bytes" => Ok(Self::InBytes),
+            "in_pkts" => Ok(Self::InPkts),
+            "ipv4_src_addr" => Ok(Self::Ipv4SrcAddr),
+            "ipv4_dst_addr" => Ok(Self::Ipv4DstAddr),
+            "l7_proto" => Ok(Self::L7Proto),
+            "l4_dst_port" => Ok(Self::L4DstPort),
+            "l4_src_port" => Ok(Self::L4SrcPort),
+            "flow_duration_milliseconds" => Ok(Self::FlowDurationMilliseconds),
+            "protocol" => Ok(Self::Protocol),
+            "tcp_flags" => Ok(Self::TcpFlags),
+            _ => Err(TransformParseError(format!("unknown field {}", s))),
+        }
+    }
+}
+
+/// Value set by `set`, parsed according to the type of the field
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    U64(u64),
+    U32(u32),
+    F32(f32),
+    Str(String),
+}
+
+impl FlowField {
+    pub(super) fn parse_value(&self, value: &str) -> Result<FieldValue, TransformParseError> {
+        let invalid = || TransformParseError(format!("invalid value of {:?}: {}", self, value));
+        match self {
+            Self::OutBytes
+            | Self::OutPkts
+            | Self::InBytes
+            | Self::InPkts
+            | Self::FlowDurationMilliseconds => {
+                value.parse().map(FieldValue::U64).map_err(|_| invalid())
+            }
+            Self::L4DstPort | Self::L4SrcPort | Self::Protocol | Self::TcpFlags => {
+                value.parse().map(FieldValue::U32).map_err(|_| invalid())
+            }
+            Self::L7Proto => value.parse().map(FieldValue::F32).map_err(|_| invalid()),
+            Self::Ipv4SrcAddr | Self::Ipv4DstAddr => value
+                .parse::<Ipv4Addr>()
+                .map(|a| FieldValue::Str(a.to_string()))
+                .map_err(|_| invalid()),
+        }
+    }
+
+    fn address_mut<'a>(&self, f: &'a mut FlowMessage) -> Option<&'a mut String> {
+        match self {
+            Self::Ipv4SrcAddr => Some(&mut f.ipv4_src_addr),
+            Self::Ipv4DstAddr => Some(&mut f.ipv4_dst_addr),
+            _ => None,
+        }
+    }
+
+    pub(super) fn set(&self, f: &mut FlowMessage, value: &FieldValue) {
+        match (self, value) {
+            (Self::OutBytes, FieldValue::U64(v)) => f.out_bytes = *v,
+            (Self::OutPkts, FieldValue::U64(v)) => f.out_pkts = *v,
+            (Self::InBytes, FieldValue::U64(v)) => f.in_bytes = *v,
+            (Self::InPkts, FieldValue::U64(v)) => f.in_pkts = *v,
+            (Self::FlowDurationMilliseconds, FieldValue::U64(v)) => {
+                f.flow_duration_milliseconds = *v
+            }
+            (Self::L4DstPort, FieldValue::U32(v)) => f.l4_dst_port = *v,
+            (Self::L4SrcPort, FieldValue::U32(v)) => f.l4_src_port = *v,
+            (Self::Protocol, FieldValue::U32(v)) => f.protocol = *v,
+            (Self::TcpFlags, FieldValue::U32(v)) => f.tcp_flags = *v,
+            (Self::L7Proto, FieldValue::F32(v)) => f.l7_proto = *v,
+            (Self::Ipv4SrcAddr, FieldValue::Str(v)) => f.ipv4_src_addr = v.clone(),
+            (Self::Ipv4DstAddr, FieldValue::Str(v)) => f.ipv4_dst_addr = v.clone(),
+            // values are parsed for their field, so types always match
+            _ => {}
+        }
+    }
+
+    /// Whether the field holds its protobuf default, i.e. it was not sent.
+    /// An unspecified `0.0.0.0` address counts as not sent as well
+    pub(super) fn is_unset(&self, f: &FlowMessage) -> bool {
+        match self {
+            Self::OutBytes => f.out_bytes == 0,
+            Self::OutPkts => f.out_pkts == 0,
+            Self::InBytes => f.in_bytes == 0,
+            Self::InPkts => f.in_pkts == 0,
+            Self::Ipv4SrcAddr => f.ipv4_src_addr.is_empty() || f.ipv4_src_addr == "0.0.0.0",
+            Self::Ipv4DstAddr => f.ipv4_dst_addr.is_empty() || f.ipv4_dst_addr == "0.0.0.0",
+            Self::L7Proto => f.l7_proto == 0.0,
+            Self::L4DstPort => f.l4_dst_port == 0,
+            Self::L4SrcPort => f.l4_src_port == 0,
+            Self::FlowDurationMilliseconds => f.flow_duration_milliseconds == 0,
+            Self::Protocol => f.protocol == 0,
+            Self::TcpFlags => f.tcp_flags == 0,
+        }
+    }
+
+    /// Resets the field to its protobuf default, which is not encoded at all
+    fn reset(&self, f: &mut FlowMessage) {
+        let default = FlowMessage::default();
+        match self {
+            Self::OutBytes => f.out_bytes = default.out_bytes,
+            Self::OutPkts => f.out_pkts = default.out_pkts,
+            Self::InBytes => f.in_bytes = default.in_bytes,
+            Self::InPkts => f.in_pkts = default.in_pkts,
+            Self::Ipv4SrcAddr => f.ipv4_src_addr = default.ipv4_src_addr,
+            Self::Ipv4DstAddr => f.ipv4_dst_addr = default.ipv4_dst_addr,
+            Self::L7Proto => f.l7_proto = default.l7_proto,
+            Self::L4DstPort => f.l4_dst_port = default.l4_dst_port,
+            Self::L4SrcPort => f.l4_src_port = default.l4_src_port,
+            Self::FlowDurationMilliseconds => {
+                f.flow_duration_milliseconds = default.flow_duration_milliseconds
+            }
+            Self::Protocol => f.protocol = default.protocol,
+            Self::TcpFlags => f.tcp_flags = default.tcp_flags,
+        }
+    }
+}
+
+/// Single operation of the transformation stage
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// keeps only the first `prefix_len` bits of an address, e.g. `mask_ip:ipv4_src_addr/24`
+    MaskIp { field: FlowField, prefix_len: u8 },
+    /// e.g. `set:protocol=17`
+    SetField { field: FlowField, value: FieldValue },
+    /// e.g. `drop:tcp_flags`
+    DropField(FlowField),
+}
+
+impl FromStr for Transform {
+    type Err = TransformParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (op, args) = s
+            .split_once(':')
+            .ok_or_else(|| TransformParseError(format!("{} is not operation:arguments", s)))?;
+        let args = args.trim();
+
+        match op.trim() {
+            "mask_ip" => {
+                let (field, prefix_len) = args.split_once('/').ok_or_else(|| {
+                    TransformParseError(format!("{} is not field/prefix_len", args))
+                })?;
+                let field = field.trim().parse::<FlowField>()?;
+                if !matches!(field, FlowField::Ipv4SrcAddr | FlowField::Ipv4DstAddr) {
+                    return Err(TransformParseError(format!(
+                        "{:?} is not an address",
+                        field
+                    )));
+                }
+                let prefix_len = prefix_len
+                    .trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|p| *p <= 32)
+                    .ok_or_else(|| TransformParseError(format!("invalid prefix {}", prefix_len)))?;
+                Ok(Self::MaskIp { field, prefix_len })
+            }
+            "set" => {
+                let (field, value) = args
+                    .split_once('=')
+                    .ok_or_else(|| TransformParseError(format!("{} is not field=value", args)))?;
+                let field = field.trim().parse::<FlowField>()?;
+                let value = field.parse_value(value.trim())?;
+                Ok(Self::SetField { field, value })
+            }
+            "drop" => Ok(Self::DropField(args.parse()?)),
+            other => Err(TransformParseError(format!("unknown operation {}", other))),
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, f: &mut FlowMessage) {
+        match self {
+            Self::MaskIp { field, prefix_len } => {
+                if let Some(addr) = field.address_mut(f) {
+                    // addresses which are not ipv4 are left as they are
+                    if let Ok(ip) = addr.parse::<Ipv4Addr>() {
+                        let mask = u32::MAX.checked_shl(32 - *prefix_len as u32).unwrap_or(0);
+                        *addr = Ipv4Addr::from(u32::from(ip) & mask).to_string();
+                    }
+                }
+            }
+            Self::SetField { field, value } => field.set(f, value),
+            Self::DropField(field) => field.reset(f),
+        }
+    }
+}
+
+/// Ordered list of transforms applied to every imported flow before it is exported
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transforms(Vec<Transform>);
+
+impl FromStr for Transforms {
+    type Err = TransformParseError;
+
+    /// Accepts transforms separated with `;`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(Transform::from_str)
+            .collect::<Result<Vec<Transform>, TransformParseError>>()
+            .map(Transforms)
+    }
+}
+
+impl Transforms {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn apply(&self, f: &mut FlowMessage) {
+        for transform in self.0.iter() {
+            transform.apply(f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    fn flow() -> FlowMessage {
+        FlowMessage {
+            in_bytes: 1200,
+            ipv4_src_addr: "192.168.10.37".to_owned(),
+            ipv4_dst_addr: "10.1.2.3".to_owned(),
+            l4_dst_port: 443,
+            protocol: 6,
+            tcp_flags: 27,
+            ..Default::default()
+        }
+    }
+
+    fn transformed(transforms: &str) -> FlowMessage {
+        let mut f = flow();
+        transforms.parse::<Transforms>().unwrap().apply(&mut f);
+        f
+    }
+
+    #[test_case("mask_ip:ipv4_src_addr/24", "192.168.10.0")]
+    #[test_case("mask_ip:ipv4_src_addr/16", "192.168.0.0")]
+    #[test_case("mask_ip:ipv4_src_addr/32", "192.168.10.37")]
+    #[test_case("mask_ip:ipv4_src_addr/0", "0.0.0.0")]
+    fn test_mask_ip(transform: &str, expected: &str) {
+        let f = transformed(transform);
+        assert_eq!(f.ipv4_src_addr, expected);
+        assert_eq!(f.ipv4_dst_addr, "10.1.2.3");
+    }
+
+    #[test]
+    fn test_mask_ip_keeps_invalid_address() {
+        let mut f = FlowMessage {
+            ipv4_dst_addr: "unknown".to_owned(),
+            ..Default::default()
+        };
+        "mask_ip:ipv4_dst_addr/8"
+            .parse::<Transforms>()
+            .unwrap()
+            .apply(&mut f);
+        assert_eq!(f.ipv4_dst_addr, "unknown");
+    }
+
+    #[test]
+    fn test_set_field() {
+        let f = transformed("set:protocol=17; set:l7_proto=7.5; set:ipv4_dst_addr=10.0.0.1");
+        assert_eq!(f.protocol, 17);
+        assert_eq!(f.l7_proto, 7.5);
+        assert_eq!(f.ipv4_dst_addr, "10.0.0.1");
+        assert_eq!(f.l4_dst_port, 443);
+    }
+
+    #[test]
+    fn test_drop_field() {
+        let f = transformed("drop:tcp_flags;drop:ipv4_src_addr");
+        assert_eq!(f.tcp_flags, 0);
+        assert_eq!(f.ipv4_src_addr, "");
+        assert_eq!(f.in_bytes, 1200);
+    }
+
+    #[test]
+    fn test_transforms_are_applied_in_order() {
+        // the address set first is masked afterwards
+        let f =
+            transformed("set:ipv4_src_addr=172.16.99.1; mask_ip:ipv4_src_addr/12; drop:in_bytes");
+        assert_eq!(f.ipv4_src_addr, "172.16.0.0");
+        assert_eq!(f.in_bytes, 0);
+
+        // a dropped field is set again
+        assert_eq!(transformed("drop:protocol; set:protocol=1").protocol, 1);
+        assert_eq!(transformed("set:protocol=1; drop:protocol").protocol, 0);
+    }
+
+    #[test]
+    fn test_no_transforms_is_noop() {
+        let transforms = "".parse::<Transforms>().unwrap();
+        assert!(transforms.is_empty());
+        assert_eq!(transformed(""), flow());
+    }
+
+    #[test_case("mask_ip:protocol/24"; "not an address")]
+    #[test_case("mask_ip:ipv4_src_addr/33"; "prefix too long")]
+    #[test_case("mask_ip:ipv4_src_addr"; "missing prefix")]
+    #[test_case("set:protocol=tcp"; "invalid value")]
+    #[test_case("set:ipv4_src_addr=10.0.0"; "invalid address")]
+    #[test_case("drop:anomaly_score"; "unknown field")]
+    #[test_case("rename:protocol"; "unknown operation")]
+    #[test_case("drop"; "missing arguments")]
+    fn test_invalid_transform(transform: &str) {
+        assert!(transform.parse::<Transforms>().is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use super::{
+    decode::{decode_protobuf, is_empty_frame, ImportedFlow},
+    errors::ImporterError,
+    import::Import,
+};
+
+use crate::pb::FlowMessage;
+
+/// Maximum number of flows returned by a single import
+const UNIX_SOCKET_IMPORT_BATCH_SIZE: usize = 128;
+/// Flows read from connections and not imported yet, writers wait once it is full
+const UNIX_SOCKET_QUEUE_CAPACITY: usize = 1024;
+/// Frames longer than that are not flows, the connection is dropped
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct UnixSocketSettings {
+    pub path: PathBuf,
+}
+
+/// Listens on a unix domain socket for flows sent by a producer on the same host,
+/// each flow is protobuf encoded and prefixed with its varint length, like in exported files
+pub struct UnixSocketImporter {
+    path: PathBuf,
+    flows: Mutex<mpsc::Receiver<ImportedFlow>>,
+    listener: JoinHandle<()>,
+}
+
+/// Removes a socket file left by a previous run which did not shut down cleanly,
+/// anything else at the path is kept and binding fails
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            warn!("removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a protobuf varint length delimiter, `None` when the peer closed the connection
+async fn read_delimiter<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<usize>> {
+    let mut len: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte).await? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        len |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len as usize));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid length delimiter",
+    ))
+}
+
+/// Reads the next flow, skipping empty frames
+async fn read_flow<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<ImportedFlow>, ImporterError> {
+    loop {
+        let len = match read_delimiter(reader).await? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len > MAX_FRAME_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes is too long", len),
+            )
+            .into());
+        }
+
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf).await?;
+        if !is_empty_frame(&buf, "unix_socket") {
+            return Ok(Some(decode_protobuf(&buf)?));
+        }
+    }
+}
+
+/// Forwards flows of a single connection until the peer closes it.
+/// A malformed frame drops the connection, as the rest of the stream can not be framed anymore
+async fn serve(stream: UnixStream, tx: mpsc::Sender<ImportedFlow>) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        match read_flow(&mut reader).await {
+            Ok(Some(flow)) => {
+                if tx.send(flow).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {
+                debug!("unix socket connection closed");
+                return;
+            }
+            Err(e) => {
+                error!("dropping unix socket connection: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn accept(listener: UnixListener, tx: mpsc::Sender<ImportedFlow>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                debug!("accepted unix socket connection");
+                tokio::spawn(serve(stream, tx.clone()));
+            }
+            Err(e) => error!("unable to accept unix socket connection: {}", e),
+        }
+    }
+}
+
+impl UnixSocketImporter {
+    /// Has to be called within a tokio runtime, connections are accepted right away
+    pub fn new(settings: UnixSocketSettings) -> Result<Self, ImporterError> {
+        remove_stale_socket(&settings.path)?;
+        let listener = UnixListener::bind(&settings.path)?;
+        info!("listening for flows on {}", settings.path.display());
+
+        let (tx, rx) = mpsc::channel(UNIX_SOCKET_QUEUE_CAPACITY);
+        Ok(Self {
+            path: settings.path,
+            flows: Mutex::new(rx),
+            listener: tokio::spawn(accept(listener, tx)),
+        })
+    }
+}
+
+impl Drop for UnixSocketImporter {
+    fn drop(&mut self) {
+        self.listener.abort();
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("unable to remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl UnixSocketImporter {
+    /// Waits for the first flow, then takes whatever else is already queued
+    async fn recv_batch(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        let mut flows = self.flows.lock().await;
+        let first = flows.recv().await.ok_or(ImporterError::Exhausted)?;
+
+        let mut batch = Vec::with_capacity(UNIX_SOCKET_IMPORT_BATCH_SIZE);
+        batch.push(first);
+        while batch.len() < UNIX_SOCKET_IMPORT_BATCH_SIZE {
+            match flows.try_recv() {
+                Ok(flow) => batch.push(flow),
+                Err(_) => break,
+            }
+        }
+        Ok(batch)
+    }
+}
+
+#[async_trait]
+impl Import for UnixSocketImporter {
+    async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
+        Ok(self
+            .recv_batch()
+            .await?
+            .into_iter()
+            .map(|f| f.flow)
+            .collect())
+    }
+
+    async fn import_with_unknown_fields(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        self.recv_batch().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::EMPTY_FRAMES;
+    use pretty_assertions::assert_eq;
+    use prost::Message;
+    use tokio::io::AsyncWriteExt;
+
+    fn socket_path(dir: &tempfile::TempDir) -> PathBuf {
+        dir.path().join("flows.sock")
+    }
+
+    fn frame(flow: &FlowMessage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        flow.encode_length_delimited(&mut buf).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_flows_are_imported_from_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let importer = UnixSocketImporter::new(UnixSocketSettings { path: path.clone() }).unwrap();
+
+        let flows = (0..3)
+            .map(|i| FlowMessage {
+                in_bytes: i,
+                ipv4_src_addr: "10.0.0.2".to_owned(),
+                ..Default::default()
+            })
+            .collect::<Vec<FlowMessage>>();
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        for flow in flows.iter() {
+            stream.write_all(&frame(flow)).await.unwrap();
+        }
+
+        let mut imported = Vec::new();
+        while imported.len() < flows.len() {
+            imported.extend(importer.import().await.unwrap());
+        }
+        assert_eq!(imported, flows);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frame_drops_only_its_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let importer = UnixSocketImporter::new(UnixSocketSettings { path: path.clone() }).unwrap();
+
+        let mut broken = UnixStream::connect(&path).await.unwrap();
+        broken.write_all(b"\xff\xff\xff\xff\x0f").await.unwrap();
+
+        let flow = FlowMessage {
+            protocol: 17,
+            ..Default::default()
+        };
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(&frame(&flow)).await.unwrap();
+
+        assert_eq!(importer.import().await.unwrap(), vec![flow]);
+    }
+
+    #[tokio::test]
+    async fn test_empty_frames_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let importer = UnixSocketImporter::new(UnixSocketSettings { path: path.clone() }).unwrap();
+        let empty_frames = EMPTY_FRAMES.with_label_values(&["unix_socket"]).get();
+
+        let flow = FlowMessage {
+            protocol: 6,
+            ..Default::default()
+        };
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"\x00\x00").await.unwrap();
+        stream.write_all(&frame(&flow)).await.unwrap();
+
+        assert_eq!(importer.import().await.unwrap(), vec![flow]);
+        assert!(EMPTY_FRAMES.with_label_values(&["unix_socket"]).get() - empty_frames >= 2);
+    }
+
+    #[tokio::test]
+    async fn test_socket_file_is_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+
+        // socket left behind by a crashed run
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let importer = UnixSocketImporter::new(UnixSocketSettings { path: path.clone() }).unwrap();
+        assert!(path.exists());
+        drop(importer);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_other_files_are_not_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        fs::write(&path, b"flows").unwrap();
+
+        assert!(matches!(
+            UnixSocketImporter::new(UnixSocketSettings { path: path.clone() }),
+            Err(ImporterError::IoErr(e)) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"flows");
+    }
+}
@@ -1,37 +1,119 @@
 use core::fmt;
+use std::cell::Cell;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use zmq::Socket;
 
 use async_trait::async_trait;
+use tokio::task;
 
 use super::{
+    allowlist::SourceAllowlist,
+    decode::{decode_json, decode_length_delimited_batch, is_empty_frame, Framing, ImportedFlow},
     errors::ImporterError,
-    import::{Import, Subscriber},
+    import::{Import, Received, Subscriber},
 };
 
+use crate::metrics::{MALFORMED_FRAMES, REJECTED_SOURCE_MESSAGES, ZMQ_RECONNECTS};
 use crate::pb::FlowMessage;
-
-#[derive(Debug)]
+use common::sampled_debug;
+
+/// Wait for the publisher to accept the connection if not configured
+pub const DEFAULT_ZMQ_CONNECT_TIMEOUT: Duration = Duration::from_millis(5000);
+/// Receives connection events of the subscriber, every connection attempt has its own context
+const MONITOR_ENDPOINT: &str = "inproc://subscriber-monitor";
+/// Message metadata libzmq sets to the address of the tcp peer which sent the message
+const PEER_ADDRESS_PROPERTY: &str = "Peer-Address";
+/// Longest a receive blocks its thread, an idle importer notices it was stopped within it
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
 pub struct ZMQSettings {
     pub address: String,
     pub queue_name: String,
+    /// enables TCP keepalive, probes are sent after the connection is idle for this long
+    pub tcp_keepalive_idle_secs: Option<i32>,
+    /// socket is reconnected when no message arrives within the timeout
+    pub idle_timeout_ms: Option<i32>,
+    /// connecting fails unless the publisher accepts the connection within the timeout
+    pub connect_timeout: Duration,
+    pub framing: Framing,
+    /// frames sent by a peer outside the allowlist are dropped
+    pub allowlist: SourceAllowlist,
 }
 
-struct MySubscriber(Socket);
+struct MySubscriber {
+    socket: Socket,
+    endpoint: String,
+    idle_timeout: Option<Duration>,
+    /// when the last message arrived or the socket was reconnected
+    last_message: Cell<Instant>,
+}
 
-impl Subscriber for MySubscriber {
-    fn recv(&self) -> Result<Vec<u8>, ImporterError> {
-        let mut messages = self.0.recv_multipart(0).map_err(ImporterError::ZMQErr)?;
-        let msg = messages.remove(1);
+impl MySubscriber {
+    /// Drops the connection which might have been silently closed on the way and opens a new one,
+    /// subscriptions are sent again by the socket
+    fn reconnect(&self) -> Result<(), ImporterError> {
+        ZMQ_RECONNECTS.inc();
+        self.last_message.set(Instant::now());
+        self.socket
+            .disconnect(&self.endpoint)
+            .map_err(ImporterError::ZMQErr)?;
+        self.socket
+            .connect(&self.endpoint)
+            .map_err(ImporterError::ZMQErr)
+    }
 
-        Ok(msg)
+    /// Receives every frame of a message, the payload follows the queue name
+    fn recv_message(&self) -> Result<Received, zmq::Error> {
+        let mut frames = vec![self.socket.recv_msg(0)?];
+        while self.socket.get_rcvmore()? {
+            frames.push(self.socket.recv_msg(0)?);
+        }
+
+        let mut payload = frames.remove(1);
+        let peer = payload
+            .gets(PEER_ADDRESS_PROPERTY)
+            .and_then(|addr| addr.parse::<IpAddr>().ok());
+        Ok(Received {
+            frame: payload.to_vec(),
+            peer,
+        })
+    }
+}
+
+impl Subscriber for MySubscriber {
+    fn recv(&self) -> Result<Option<Received>, ImporterError> {
+        match self.recv_message() {
+            Ok(received) => {
+                self.last_message.set(Instant::now());
+                Ok(Some(received))
+            }
+            // receive timeout is the poll interval
+            Err(zmq::Error::EAGAIN) => {
+                if let Some(idle_timeout) = self.idle_timeout {
+                    if self.last_message.get().elapsed() >= idle_timeout {
+                        warn!(
+                            "no message received from [{}] within idle timeout, reconnecting",
+                            self.endpoint
+                        );
+                        self.reconnect()?;
+                    }
+                }
+                Ok(None)
+            }
+            Err(e) => Err(ImporterError::ZMQErr(e)),
+        }
     }
 }
 
 pub struct ZMQ {
-    pub subscriber: Box<dyn Subscriber>,
+    /// blocking receives run on their own thread, one at a time
+    pub subscriber: Arc<Mutex<Box<dyn Subscriber + Send>>>,
     pub settings: ZMQSettings,
 }
 
@@ -42,15 +124,39 @@ impl fmt::Debug for ZMQ {
 }
 
 impl ZMQ {
-    pub fn new(settings: ZMQSettings) -> Self {
+    pub fn new(settings: ZMQSettings) -> Result<Self, ImporterError> {
         let context = zmq::Context::new();
-        let subscriber = context.socket(zmq::SUB).unwrap();
+        let subscriber = context.socket(zmq::SUB).map_err(ImporterError::ZMQErr)?;
 
         let subscriber_connection = format!("tcp://{}", settings.address);
 
+        if let Some(idle) = settings.tcp_keepalive_idle_secs {
+            subscriber
+                .set_tcp_keepalive(1)
+                .and_then(|()| subscriber.set_tcp_keepalive_idle(idle))
+                .map_err(ImporterError::ZMQErr)?;
+        }
+        // receives return every poll interval, so stopping never waits for the publisher
+        subscriber
+            .set_rcvtimeo(RECV_POLL_INTERVAL.as_millis() as i32)
+            .map_err(ImporterError::ZMQErr)?;
+
+        // libzmq connects in the background, the monitor tells when the publisher accepted
+        subscriber
+            .monitor(
+                MONITOR_ENDPOINT,
+                zmq::SocketEvent::CONNECTED.to_raw() as i32,
+            )
+            .map_err(ImporterError::ZMQErr)?;
+        let monitor = context.socket(zmq::PAIR).map_err(ImporterError::ZMQErr)?;
+        monitor
+            .connect(MONITOR_ENDPOINT)
+            .map_err(ImporterError::ZMQErr)?;
+
         subscriber
             .connect(&subscriber_connection)
-            .expect("Failed connecting subscriber");
+            .map_err(ImporterError::ZMQErr)?;
+        wait_connected(&monitor, settings.connect_timeout)?;
         info!(
             "successfuly connected to socket at: [{}]",
             subscriber_connection
@@ -60,38 +166,154 @@ impl ZMQ {
 
         subscriber
             .set_subscribe(zmq_queue)
-            .expect("Failed setting subscription");
+            .map_err(ImporterError::ZMQErr)?;
         info!(
             "successfuly subscribed to zmq queue: [{}]",
             settings.queue_name
         );
 
-        let boxed_subscriber = Box::new(MySubscriber(subscriber));
-        ZMQ {
+        let boxed_subscriber = Box::new(MySubscriber {
+            socket: subscriber,
+            endpoint: subscriber_connection,
+            idle_timeout: settings
+                .idle_timeout_ms
+                .map(|ms| Duration::from_millis(ms as u64)),
+            last_message: Cell::new(Instant::now()),
+        });
+        Ok(ZMQ {
             settings,
-            subscriber: boxed_subscriber,
+            subscriber: Arc::new(Mutex::new(boxed_subscriber)),
+        })
+    }
+}
+
+/// Whether the first frame of a monitor message is the connected event
+fn is_connected_event(frame: &[u8]) -> bool {
+    frame.len() >= 2
+        && u16::from_ne_bytes([frame[0], frame[1]]) == zmq::SocketEvent::CONNECTED.to_raw()
+}
+
+/// Waits for the monitored socket to connect, a publisher which is not up within the timeout
+/// fails the attempt so it is retried like the other importers
+fn wait_connected(monitor: &Socket, timeout: Duration) -> Result<(), ImporterError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ImporterError::ConnectTimeout);
+        }
+        monitor
+            .set_rcvtimeo(left.as_millis().clamp(1, i32::MAX as u128) as i32)
+            .map_err(ImporterError::ZMQErr)?;
+        match monitor.recv_multipart(0) {
+            Ok(frames) if frames.first().map_or(false, |f| is_connected_event(f)) => return Ok(()),
+            Ok(_) => continue,
+            Err(zmq::Error::EAGAIN) => return Err(ImporterError::ConnectTimeout),
+            Err(e) => return Err(ImporterError::ZMQErr(e)),
         }
     }
 }
 
-unsafe impl Send for ZMQ {}
-unsafe impl Sync for ZMQ {}
+impl ZMQ {
+    /// Waits for the next frame without blocking the runtime. Every poll blocks a thread of its
+    /// own for at most `RECV_POLL_INTERVAL`, so the wait can be dropped once the importer stops
+    async fn recv(&self) -> Result<Received, ImporterError> {
+        loop {
+            let subscriber = self.subscriber.clone();
+            let polled = task::spawn_blocking(move || {
+                subscriber
+                    .lock()
+                    .expect("zmq subscriber lock poisoned")
+                    .recv()
+            })
+            .await
+            .expect("zmq receive panicked")?;
+            if let Some(received) = polled {
+                return Ok(received);
+            }
+        }
+    }
+
+    async fn recv_flows(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        // instead of using nprobe there might be our collector
+        // which will deserialize packets into netflow format flow message
+        loop {
+            let received = self.recv().await?;
+            if !self.settings.allowlist.allows(received.peer) {
+                REJECTED_SOURCE_MESSAGES.with_label_values(&["zmq"]).inc();
+                sampled_debug!(
+                    "dropping frame sent by {:?}, not an allowed source",
+                    received.peer
+                );
+                continue;
+            }
+
+            let received_slice = &received.frame;
+            if is_empty_frame(received_slice, "zmq") {
+                continue;
+            }
+
+            let msg = match self.settings.framing {
+                Framing::Single => {
+                    sampled_debug!(
+                        "String message: {}",
+                        String::from_utf8_lossy(received_slice)
+                    );
+                    decode_json(received_slice)?
+                        .into_iter()
+                        .map(ImportedFlow::from)
+                        .collect()
+                }
+                // a malformed batch is dropped whole and the next frame is awaited
+                Framing::LengthDelimitedBatch => {
+                    match decode_length_delimited_batch(received_slice) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            // not dead lettered, the frame can only be inspected in the debug log
+                            MALFORMED_FRAMES.inc();
+                            warn!(
+                                "dropping malformed batch frame of {} bytes: {:?}",
+                                received_slice.len(),
+                                e
+                            );
+                            debug!("malformed batch frame: {:02x?}", received_slice);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            sampled_debug!("Imported message: {:#?}", msg);
+            return Ok(msg);
+        }
+    }
+}
 
 #[async_trait]
 impl Import for ZMQ {
     async fn import(&self) -> Result<Vec<FlowMessage>, ImporterError> {
-        // instead of using nprobe there might be our collector
-        // which will deserialize packets into netflow format flow message
-        let received_slice = &self.subscriber.recv()?;
+        Ok(self
+            .recv_flows()
+            .await?
+            .into_iter()
+            .map(|f| f.flow)
+            .collect())
+    }
 
-        debug!(
-            "String message: {}",
-            std::str::from_utf8(received_slice).unwrap()
-        ); // TODO remove that
-        let msg: Vec<FlowMessage> = serde_json::from_slice(received_slice).unwrap();
+    async fn import_with_unknown_fields(&self) -> Result<Vec<ImportedFlow>, ImporterError> {
+        self.recv_flows().await
+    }
 
-        debug!("Imported message: {:#?}", msg); // TODO remove that
-        Ok(msg)
+    /// First ipv4 address the publisher's host resolves to
+    fn source_addr(&self) -> Option<Ipv4Addr> {
+        self.settings
+            .address
+            .to_socket_addrs()
+            .ok()?
+            .find_map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
     }
 }
 
@@ -99,15 +321,17 @@ impl Import for ZMQ {
 mod tests {
 
     use super::*;
+    use crate::metrics::EMPTY_FRAMES;
     use crate::pb::FlowMessage;
     use mockall::mock;
     use pretty_assertions::assert_eq;
     use serde_json::error::Category;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     use test_case::case;
     use tokio_test::block_on;
 
-    type RecvResult = Result<Vec<u8>, ImporterError>;
+    type RecvResult = Result<Option<Received>, ImporterError>;
     mock! {
         pub Socket { }
 
@@ -116,6 +340,14 @@ mod tests {
         }
     }
 
+    fn received(frame: Vec<u8>) -> Option<Received> {
+        Some(Received { frame, peer: None })
+    }
+
+    fn subscriber(socket: MockSocket) -> Arc<Mutex<Box<dyn Subscriber + Send>>> {
+        Arc::new(Mutex::new(Box::new(socket)))
+    }
+
     #[case(77, 1, 53, "10.0.0.1".to_string(), "10.0.0.2".to_string(), 17, 56341, 61, 1, 0.2, 0, 12, None; "ensure correct input is deserialized as it should, with no errors")]
     #[case(77, 1, 53, "".to_string(), "".to_string(), 17, 56341, 61, 1, 3.2, 0, 12, Some(Category::Data); "ensure partially missing data results in error")]
     fn test_import_zmq(
@@ -152,15 +384,20 @@ mod tests {
         let mut socket = MockSocket::new();
         socket
             .expect_recv()
-            .returning(move || Ok(prepared_msg.clone().into_bytes()));
+            .returning(move || Ok(received(prepared_msg.clone().into_bytes())));
 
         let settings = ZMQSettings {
             address: "localhost:5561".to_string(),
             queue_name: "flow".to_string(),
+            tcp_keepalive_idle_secs: None,
+            idle_timeout_ms: None,
+            connect_timeout: DEFAULT_ZMQ_CONNECT_TIMEOUT,
+            framing: Framing::Single,
+            allowlist: SourceAllowlist::default(),
         };
 
         let zmq = ZMQ {
-            subscriber: Box::new(socket),
+            subscriber: subscriber(socket),
             settings,
         };
 
@@ -191,7 +428,213 @@ mod tests {
                     assert_eq!(None, de_error_category);
                     panic!("Shouldn't be here: {}", z)
                 }
+                e => panic!("Shouldn't be here: {:?}", e),
+            },
+        }
+    }
+
+    #[test]
+    fn test_batch_frames_are_split_into_flows() {
+        let flows = (0..3)
+            .map(|i| FlowMessage {
+                ipv4_src_addr: "10.0.0.2".to_string(),
+                l4_src_port: 40000 + i,
+                ..Default::default()
+            })
+            .collect::<Vec<FlowMessage>>();
+        let mut frame = Vec::new();
+        for flow in &flows {
+            flow.encode_length_delimited(&mut frame).unwrap();
+        }
+        let malformed = frame[..frame.len() - 1].to_vec();
+
+        let mut socket = MockSocket::new();
+        let mut frames = vec![Ok(received(frame)), Ok(received(malformed))];
+        socket
+            .expect_recv()
+            .times(2)
+            .returning(move || frames.pop().unwrap());
+
+        let zmq = ZMQ {
+            subscriber: subscriber(socket),
+            settings: ZMQSettings {
+                address: "localhost:5561".to_string(),
+                queue_name: "flow".to_string(),
+                tcp_keepalive_idle_secs: None,
+                idle_timeout_ms: None,
+                connect_timeout: DEFAULT_ZMQ_CONNECT_TIMEOUT,
+                framing: Framing::LengthDelimitedBatch,
+                allowlist: SourceAllowlist::default(),
             },
+        };
+        let malformed_frames = MALFORMED_FRAMES.get();
+
+        // the malformed frame comes first and none of its flows is imported
+        assert_eq!(block_on(zmq.import()).unwrap(), flows);
+        assert_eq!(MALFORMED_FRAMES.get() - malformed_frames, 1);
+    }
+
+    #[test]
+    fn test_empty_frames_are_skipped() {
+        let flow = FlowMessage {
+            ipv4_src_addr: "10.0.0.2".to_string(),
+            ..Default::default()
+        };
+        let mut frame = Vec::new();
+        flow.encode_length_delimited(&mut frame).unwrap();
+
+        let mut socket = MockSocket::new();
+        let mut frames = vec![Ok(received(frame)), Ok(received(Vec::new()))];
+        socket
+            .expect_recv()
+            .times(2)
+            .returning(move || frames.pop().unwrap());
+
+        let zmq = ZMQ {
+            subscriber: subscriber(socket),
+            settings: ZMQSettings {
+                address: "localhost:5561".to_string(),
+                queue_name: "flow".to_string(),
+                tcp_keepalive_idle_secs: None,
+                idle_timeout_ms: None,
+                connect_timeout: DEFAULT_ZMQ_CONNECT_TIMEOUT,
+                framing: Framing::LengthDelimitedBatch,
+                allowlist: SourceAllowlist::default(),
+            },
+        };
+        let empty_frames = EMPTY_FRAMES.with_label_values(&["zmq"]).get();
+
+        // the empty frame comes first and is neither an error nor an empty batch
+        assert_eq!(block_on(zmq.import()).unwrap(), vec![flow]);
+        assert!(EMPTY_FRAMES.with_label_values(&["zmq"]).get() - empty_frames >= 1);
+    }
+
+    fn settings(address: String, connect_timeout: Duration) -> ZMQSettings {
+        ZMQSettings {
+            address,
+            queue_name: "flow".to_string(),
+            tcp_keepalive_idle_secs: None,
+            idle_timeout_ms: None,
+            connect_timeout,
+            framing: Framing::Single,
+            allowlist: SourceAllowlist::default(),
         }
     }
+
+    #[case("127.0.0.1:5561", Some(Ipv4Addr::LOCALHOST); "ipv4 address")]
+    #[case("[::1]:5561", None; "ipv6 address")]
+    #[case("127.0.0.1", None; "address without port")]
+    fn test_source_address_is_the_publisher(address: &str, expected: Option<Ipv4Addr>) {
+        let zmq = ZMQ {
+            subscriber: subscriber(MockSocket::new()),
+            settings: settings(address.to_string(), DEFAULT_ZMQ_CONNECT_TIMEOUT),
+        };
+        assert_eq!(zmq.source_addr(), expected);
+    }
+
+    #[test]
+    fn test_connected_event_is_recognized() {
+        let connected = zmq::SocketEvent::CONNECTED.to_raw().to_ne_bytes();
+        let delayed = zmq::SocketEvent::CONNECT_DELAYED.to_raw().to_ne_bytes();
+
+        assert!(is_connected_event(&[&connected[..], &[0; 4]].concat()));
+        assert!(!is_connected_event(&[&delayed[..], &[0; 4]].concat()));
+        assert!(!is_connected_event(&[]));
+    }
+
+    #[test]
+    fn test_connecting_waits_for_the_publisher() {
+        let context = zmq::Context::new();
+        let publisher = context.socket(zmq::PUB).unwrap();
+        publisher.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = publisher.get_last_endpoint().unwrap().unwrap();
+        let address = endpoint.trim_start_matches("tcp://").to_string();
+
+        assert!(ZMQ::new(settings(address.clone(), Duration::from_secs(5))).is_ok());
+
+        // nothing listens on the port once the publisher is gone
+        drop(publisher);
+        assert_eq!(
+            ZMQ::new(settings(address, Duration::from_millis(200))).unwrap_err(),
+            ImporterError::ConnectTimeout
+        );
+    }
+
+    #[test]
+    fn test_frames_of_senders_outside_allowlist_are_dropped() {
+        let flow = FlowMessage {
+            ipv4_src_addr: "10.0.0.2".to_string(),
+            ..Default::default()
+        };
+        let mut frame = Vec::new();
+        flow.encode_length_delimited(&mut frame).unwrap();
+
+        let mut socket = MockSocket::new();
+        let mut frames = vec![
+            Ok(Some(Received {
+                frame: frame.clone(),
+                peer: Some("10.1.0.5".parse().unwrap()),
+            })),
+            Ok(received(frame.clone())),
+            Ok(Some(Received {
+                frame,
+                peer: Some("192.168.0.5".parse().unwrap()),
+            })),
+        ];
+        socket
+            .expect_recv()
+            .times(3)
+            .returning(move || frames.pop().unwrap());
+
+        let zmq = ZMQ {
+            subscriber: subscriber(socket),
+            settings: ZMQSettings {
+                framing: Framing::LengthDelimitedBatch,
+                allowlist: "10.1.0.0/16".parse().unwrap(),
+                ..settings("localhost:5561".to_string(), DEFAULT_ZMQ_CONNECT_TIMEOUT)
+            },
+        };
+        let rejected = REJECTED_SOURCE_MESSAGES.with_label_values(&["zmq"]).get();
+
+        // neither the frame of another sender nor the one of an unknown sender is imported
+        assert_eq!(block_on(zmq.import()).unwrap(), vec![flow]);
+        assert_eq!(
+            REJECTED_SOURCE_MESSAGES.with_label_values(&["zmq"]).get() - rejected,
+            2
+        );
+    }
+
+    #[test]
+    fn test_frames_tell_their_sender() {
+        let context = zmq::Context::new();
+        let publisher = context.socket(zmq::PUB).unwrap();
+        publisher.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = publisher.get_last_endpoint().unwrap().unwrap();
+        let address = endpoint.trim_start_matches("tcp://").to_string();
+
+        let zmq = ZMQ::new(settings(address, Duration::from_secs(5))).unwrap();
+        // the subscription reaches the publisher some time after connecting
+        let stop = Arc::new(AtomicBool::new(false));
+        let publishing = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    publisher
+                        .send_multipart([&b"flow"[..], &b"payload"[..]], 0)
+                        .unwrap();
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+
+        assert_eq!(
+            block_on(zmq.recv()).unwrap(),
+            Received {
+                frame: b"payload".to_vec(),
+                peer: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            }
+        );
+        stop.store(true, Ordering::Relaxed);
+        publishing.join().unwrap();
+    }
 }
@@ -1,9 +1,14 @@
 // pub mod config;
+pub mod admin;
 pub mod application_state;
 pub mod config;
+pub mod config_log;
+pub mod config_source;
 pub mod exporters;
 pub mod importers;
+pub mod metrics;
 pub mod settings;
+pub mod shutdown;
 
 pub mod pb {
     include!("flow.rs");
@@ -1,4 +1,6 @@
-use collector::application_state::{init_config, ApplicationState};
+use log::error;
+
+use collector::application_state::{init_config, reload_on_hangup, ApplicationState};
 
 #[tokio::main]
 async fn main() {
@@ -7,9 +9,22 @@ async fn main() {
     env_logger::init_from_env(env);
 
     // parse configs
-    let (config_cache, config) = init_config().expect("Configuration init failed");
+    let (config_cache, config) = match init_config().await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("configuration init failed: {:?}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
     let app_state = ApplicationState::new(config_cache, config)
         .expect("Unable to initialize application state");
 
-    let _ = ApplicationState::init_components(app_state.config().unwrap()).await;
+    let config = app_state.config().unwrap();
+    let config_log = app_state.config.log();
+    tokio::spawn(reload_on_hangup(app_state.config));
+
+    if let Err(e) = ApplicationState::init_components(config, config_log).await {
+        error!("collector failed to start: {:?}", e);
+        std::process::exit(e.exit_code());
+    }
 }
@@ -0,0 +1,126 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+
+/// Buckets of payload sizes in bytes, dense around the usual 100-2000 bytes of a flow record
+const PAYLOAD_BYTES_BUCKETS: &[f64] = &[
+    64.0, 128.0, 256.0, 384.0, 512.0, 768.0, 1024.0, 1536.0, 2048.0, 4096.0, 16384.0, 65536.0,
+];
+
+lazy_static! {
+    pub static ref DROPPED_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "collector_dropped_messages_total",
+        "Messages dropped by the importer because the exporter channel was full",
+        &["source", "policy"]
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "collector_queue_depth",
+        "Messages imported but not yet taken by the exporter",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref IMPORTED_FLOWS: IntCounterVec = register_int_counter_vec!(
+        "collector_imported_flows_total",
+        "Flows received from the source, before transforms",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref EMPTY_FIELDS: IntCounterVec = register_int_counter_vec!(
+        "collector_empty_fields_total",
+        "Imported flows with an empty or zero value of the field",
+        &["source", "field"]
+    )
+    .unwrap();
+    pub static ref ZMQ_RECONNECTS: IntCounter = register_int_counter!(
+        "collector_zmq_reconnects_total",
+        "Reconnections of the zmq socket after no message arrived within the idle timeout"
+    )
+    .unwrap();
+    pub static ref EMPTY_FRAMES: IntCounterVec = register_int_counter_vec!(
+        "collector_empty_frames_total",
+        "Zero length frames skipped by the importer",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref REJECTED_SOURCE_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "collector_rejected_source_messages_total",
+        "Frames or requests dropped because their sender is not one of allowed_sources",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref MALFORMED_FRAMES: IntCounter = register_int_counter!(
+        "collector_malformed_frames_total",
+        "Batch frames dropped whole because a record in them could not be decoded"
+    )
+    .unwrap();
+    pub static ref PAYLOAD_BYTES: HistogramVec = register_histogram_vec!(
+        "collector_payload_bytes",
+        "Size of a single message payload, labelled by the stage it was measured at",
+        &["stage"],
+        PAYLOAD_BYTES_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref SPILL_BYTES: IntGauge = register_int_gauge!(
+        "collector_spill_bytes",
+        "Size of messages spilled to disk and not yet moved back to the exporter channel"
+    )
+    .unwrap();
+    pub static ref RING_BYTES: IntGauge = register_int_gauge!(
+        "collector_ring_bytes",
+        "Size of messages in the ring spool not yet moved to the exporter channel"
+    )
+    .unwrap();
+    pub static ref RING_OVERWRITTEN: IntCounter = register_int_counter!(
+        "collector_ring_overwritten_total",
+        "Messages overwritten in the ring spool before they reached the exporter channel"
+    )
+    .unwrap();
+    pub static ref KAFKA_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "collector_kafka_deliveries_total",
+        "Delivery reports of messages produced to kafka, labelled by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref KAFKA_INFLIGHT_PRODUCES: IntGauge = register_int_gauge!(
+        "collector_kafka_inflight_produces",
+        "Messages handed to the kafka producer and not yet reported as delivered or failed"
+    )
+    .unwrap();
+    pub static ref SHADOW_EXPORTS: IntCounterVec = register_int_counter_vec!(
+        "collector_shadow_exports_total",
+        "Messages sampled for the shadow exporter, labelled by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref HTTP_LINES: IntCounterVec = register_int_counter_vec!(
+        "collector_http_lines_total",
+        "Lines posted to the http importer, labelled by whether they were accepted or rejected",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref HEARTBEATS: IntCounterVec = register_int_counter_vec!(
+        "collector_heartbeats_total",
+        "Heartbeat flows sent into the exporter channel or skipped because it was full",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "collector_rate_limited_messages_total",
+        "Imported messages over the rate limit, dropped or delayed depending on the policy",
+        &["source", "policy"]
+    )
+    .unwrap();
+}
+
+/// All registered metrics in prometheus text format
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("unable to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -1,31 +1,129 @@
 use core::fmt;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::exporters::{KafkaExporter, KafkaSettings}; // Exporter};
-use crate::importers::{Import, ZMQSettings, ZMQ};
+use crate::application_state::HostIdentifier;
+use crate::exporters::key::{KeyField, KeyHash, Partitioner};
+use crate::exporters::{
+    default_client_id, Checksum, Codec, Compression, Export, FileExporter, FileSettings,
+    KafkaExporter, KafkaSettings, SchemaRegistrySettings, SubjectNaming, TopicCreation,
+};
+use crate::importers::connect::{connect_with_retry, ConnectRetry, DEFAULT_CONNECT_BACKOFF};
+use crate::importers::decode::Framing;
+use crate::importers::defaults::FieldDefaultsError;
+use crate::importers::heartbeat::DEFAULT_HEARTBEAT_MARKER;
+use crate::importers::http::{DEFAULT_HTTP_MAX_BODY_BYTES, DEFAULT_HTTP_PATH};
+use crate::importers::transform::TransformParseError;
+use crate::importers::zmq::DEFAULT_ZMQ_CONNECT_TIMEOUT;
+use crate::importers::{
+    DropPolicy, FieldDefaults, FileImporter, HeartbeatSettings, HttpImporter, HttpSettings, Import,
+    RateLimitPolicy, RateLimitSettings, RingSettings, SourceAllowlist, SpillDrainMode,
+    SpillSettings, Transforms, UnixSocketImporter, UnixSocketSettings, ZMQSettings, ZMQ,
+};
+use common::host_normalization::{HostNormalization, HostNormalizationSettings};
+use common::log_sampling::DEFAULT_DEBUG_LOG_SAMPLE_RATE;
+use common::parse::UnknownVariant;
 use serde::Deserialize;
 
+/// Rotation size of exported files if not configured
+const DEFAULT_FILE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Write buffer of exported files if not configured
+const DEFAULT_WRITE_BUFFER_BYTES: usize = 64 * 1024;
+/// Disk space used by spilled messages if not configured
+const DEFAULT_SPILL_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+/// Size of the ring spool if not configured
+const DEFAULT_RING_BYTES: u64 = 256 * 1024 * 1024;
+/// Partitions of a topic created by the kafka exporter
+const DEFAULT_TOPIC_PARTITIONS: i32 = 1;
+const DEFAULT_TOPIC_REPLICATION_FACTOR: i32 = 1;
+/// Percentage of messages tee'd to the shadow exporter if not configured
+const DEFAULT_SHADOW_SAMPLE_PERCENT: u8 = 1;
+
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub enum ImporterVariants {
     #[serde(rename = "zmq")]
     ZMQ,
+    #[serde(rename = "file")]
+    File,
+    #[serde(rename = "unix_socket")]
+    UnixSocket,
+    #[serde(rename = "http")]
+    Http,
 }
 
 #[derive(Debug)]
 pub enum ConstructorErr {
     ZMQErr,
     KafkaErr,
+    FileErr,
+    SpillErr,
+    RingErr,
+    UnixSocketErr,
+    RateLimitErr,
+    ShadowErr,
+    HeartbeatErr,
+    ConnectErr,
+    HttpErr,
+    AllowlistErr,
+    HostNormalizationErr,
 }
 
 impl ImporterVariants {
-    pub fn construct_importer(
+    /// Importer whose source could not be connected is retried according to the connect settings
+    pub async fn construct_importer(
         &self,
         settings: ImporterSettings,
-    ) -> Result<impl Import, ConstructorErr> {
+    ) -> Result<Box<dyn Import>, ConstructorErr> {
+        let retry = settings.connect_retry()?;
+        let source = self.to_string();
+        // files and unix sockets have no remote sender to check
+        if matches!(self, Self::File | Self::UnixSocket) && !settings.allowed_sources()?.is_empty()
+        {
+            return Err(ConstructorErr::AllowlistErr);
+        }
+
         match *self {
-            Self::ZMQ => Ok(ZMQ::new(ZMQSettings {
-                address: settings.zmq_address.ok_or(ConstructorErr::ZMQErr)?,
-                queue_name: settings.zmq_queue_name.ok_or(ConstructorErr::ZMQErr)?,
-            })),
+            Self::ZMQ => {
+                let zmq = settings.zmq_settings()?;
+                Ok(Box::new(
+                    connect_with_retry(&retry, &source, || ZMQ::new(zmq.clone()))
+                        .await
+                        .map_err(|_| ConstructorErr::ZMQErr)?,
+                ))
+            }
+            Self::File => {
+                let path = PathBuf::from(settings.file_path.ok_or(ConstructorErr::FileErr)?);
+                Ok(Box::new(
+                    connect_with_retry(&retry, &source, || {
+                        FileImporter::new(crate::importers::FileSettings { path: path.clone() })
+                    })
+                    .await
+                    .map_err(|_| ConstructorErr::FileErr)?,
+                ))
+            }
+            Self::UnixSocket => {
+                let path = PathBuf::from(
+                    settings
+                        .unix_socket_path
+                        .ok_or(ConstructorErr::UnixSocketErr)?,
+                );
+                Ok(Box::new(
+                    connect_with_retry(&retry, &source, || {
+                        UnixSocketImporter::new(UnixSocketSettings { path: path.clone() })
+                    })
+                    .await
+                    .map_err(|_| ConstructorErr::UnixSocketErr)?,
+                ))
+            }
+            Self::Http => {
+                let http = settings.http_settings()?;
+                Ok(Box::new(
+                    connect_with_retry(&retry, &source, || HttpImporter::new(http.clone()))
+                        .await
+                        .map_err(|_| ConstructorErr::HttpErr)?,
+                ))
+            }
         }
     }
 }
@@ -34,6 +132,9 @@ impl From<ImporterVariants> for String {
     fn from(variant: ImporterVariants) -> Self {
         match variant {
             ImporterVariants::ZMQ => "zmq".to_string(),
+            ImporterVariants::File => "file".to_string(),
+            ImporterVariants::UnixSocket => "unix_socket".to_string(),
+            ImporterVariants::Http => "http".to_string(),
         }
     }
 }
@@ -42,6 +143,9 @@ impl fmt::Display for ImporterVariants {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let name = match self {
             Self::ZMQ => "zmq",
+            Self::File => "file",
+            Self::UnixSocket => "unix_socket",
+            Self::Http => "http",
         };
         write!(f, "{}", name)
     }
@@ -53,23 +157,450 @@ pub struct Importer {
     pub settings: ImporterSettings,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
 pub struct ImporterSettings {
     pub zmq_address: Option<String>,
 
     pub zmq_queue_name: Option<String>,
+
+    pub drop_policy: Option<String>,
+
+    pub file_path: Option<String>,
+
+    pub zmq_tcp_keepalive_idle_secs: Option<String>,
+
+    pub zmq_idle_timeout_ms: Option<String>,
+
+    pub zmq_connect_timeout_ms: Option<String>,
+
+    pub zmq_framing: Option<String>,
+
+    pub spill_dir: Option<String>,
+
+    pub spill_max_bytes: Option<String>,
+
+    pub spill_drain_mode: Option<String>,
+
+    pub ring_path: Option<String>,
+
+    pub ring_bytes: Option<String>,
+
+    pub transforms: Option<String>,
+
+    pub field_defaults: Option<String>,
+
+    pub unix_socket_path: Option<String>,
+
+    pub http_bind_addr: Option<String>,
+
+    pub http_path: Option<String>,
+
+    pub http_max_body_bytes: Option<String>,
+
+    pub debug_log_sample_rate: Option<String>,
+
+    pub max_messages_per_sec: Option<String>,
+
+    pub rate_limit_burst: Option<String>,
+
+    pub rate_limit_policy: Option<String>,
+
+    pub heartbeat_interval_ms: Option<String>,
+
+    pub heartbeat_marker: Option<String>,
+
+    pub connect_max_retries: Option<String>,
+
+    pub connect_backoff_ms: Option<String>,
+
+    pub allowed_sources: Option<String>,
+
+    pub host_lowercase: Option<String>,
+
+    pub host_canonical_mac: Option<String>,
+
+    pub host_strip_suffixes: Option<String>,
+
+    pub host_aliases: Option<String>,
+}
+
+impl ImporterSettings {
+    pub fn drop_policy(&self) -> Result<DropPolicy, UnknownVariant> {
+        self.drop_policy
+            .as_deref()
+            .map(str::parse::<DropPolicy>)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// No transforms are applied when not set
+    pub fn transforms(&self) -> Result<Transforms, TransformParseError> {
+        self.transforms
+            .as_deref()
+            .map(str::parse::<Transforms>)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// No fields are defaulted when not set, `@source` still has to be resolved
+    pub fn field_defaults(&self) -> Result<FieldDefaults, FieldDefaultsError> {
+        self.field_defaults
+            .as_deref()
+            .map(str::parse::<FieldDefaults>)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Every message is logged at debug level when not set
+    pub fn debug_log_sample_rate(&self) -> Result<u64, std::num::ParseIntError> {
+        self.debug_log_sample_rate
+            .as_deref()
+            .map(str::parse::<u64>)
+            .transpose()
+            .map(|rate| rate.unwrap_or(DEFAULT_DEBUG_LOG_SAMPLE_RATE))
+    }
+
+    /// Ingestion is not rate limited when no maximum rate is set, the burst defaults to the rate
+    pub fn rate_limit(&self) -> Result<Option<RateLimitSettings>, ConstructorErr> {
+        let parse = |value: &Option<String>| match value.as_deref().map(str::parse::<u64>) {
+            Some(Ok(0)) | Some(Err(_)) => Err(ConstructorErr::RateLimitErr),
+            Some(Ok(value)) => Ok(Some(value)),
+            None => Ok(None),
+        };
+        let max_messages_per_sec = match parse(&self.max_messages_per_sec)? {
+            Some(rate) => rate,
+            None => return Ok(None),
+        };
+
+        Ok(Some(RateLimitSettings {
+            max_messages_per_sec,
+            burst: parse(&self.rate_limit_burst)?.unwrap_or(max_messages_per_sec),
+            policy: self
+                .rate_limit_policy
+                .as_deref()
+                .map(str::parse::<RateLimitPolicy>)
+                .transpose()
+                .map_err(|_| ConstructorErr::RateLimitErr)?
+                .unwrap_or_default(),
+        }))
+    }
+
+    /// Spilling is disabled when no directory is set
+    pub fn spill_settings(&self) -> Result<Option<SpillSettings>, ConstructorErr> {
+        let dir = match &self.spill_dir {
+            Some(dir) => dir.into(),
+            None => return Ok(None),
+        };
+
+        Ok(Some(SpillSettings {
+            dir,
+            max_bytes: self
+                .spill_max_bytes
+                .as_deref()
+                .map(str::parse::<u64>)
+                .transpose()
+                .map_err(|_| ConstructorErr::SpillErr)?
+                .unwrap_or(DEFAULT_SPILL_MAX_BYTES),
+            drain_mode: self
+                .spill_drain_mode
+                .as_deref()
+                .map(str::parse::<SpillDrainMode>)
+                .transpose()
+                .map_err(|_| ConstructorErr::SpillErr)?
+                .unwrap_or_default(),
+        }))
+    }
+
+    /// Messages go straight to the exporter channel when no ring file is set
+    pub fn ring_settings(&self) -> Result<Option<RingSettings>, ConstructorErr> {
+        let path = match &self.ring_path {
+            Some(path) => path.into(),
+            None => return Ok(None),
+        };
+
+        Ok(Some(RingSettings {
+            path,
+            capacity_bytes: self
+                .ring_bytes
+                .as_deref()
+                .map(str::parse::<u64>)
+                .transpose()
+                .map_err(|_| ConstructorErr::RingErr)?
+                .unwrap_or(DEFAULT_RING_BYTES),
+        }))
+    }
+
+    /// Heartbeats are not sent when no interval is set
+    pub fn heartbeat_settings(&self) -> Result<Option<HeartbeatSettings>, ConstructorErr> {
+        let interval_ms = match &self.heartbeat_interval_ms {
+            Some(ms) => ms
+                .parse::<u64>()
+                .ok()
+                .filter(|ms| *ms > 0)
+                .ok_or(ConstructorErr::HeartbeatErr)?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(HeartbeatSettings {
+            interval: Duration::from_millis(interval_ms),
+            marker: self
+                .heartbeat_marker
+                .as_deref()
+                .map(str::parse::<Ipv4Addr>)
+                .transpose()
+                .map_err(|_| ConstructorErr::HeartbeatErr)?
+                .unwrap_or(DEFAULT_HEARTBEAT_MARKER),
+        }))
+    }
+
+    /// Flows are accepted from every sender when not set
+    pub fn allowed_sources(&self) -> Result<SourceAllowlist, ConstructorErr> {
+        self.allowed_sources
+            .as_deref()
+            .map(str::parse::<SourceAllowlist>)
+            .transpose()
+            .map_err(|_| ConstructorErr::AllowlistErr)
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Host identifier flows are exported with is kept as it is when no rule is set
+    pub fn host_normalization(&self) -> Result<HostNormalization, ConstructorErr> {
+        let flag = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(str::parse::<bool>)
+                .transpose()
+                .map_err(|_| ConstructorErr::HostNormalizationErr)
+                .map(Option::unwrap_or_default)
+        };
+        HostNormalization::new(&HostNormalizationSettings {
+            lowercase: flag(&self.host_lowercase)?,
+            canonical_mac: flag(&self.host_canonical_mac)?,
+            strip_suffixes: self.host_strip_suffixes.clone().unwrap_or_default(),
+            aliases: self.host_aliases.clone().unwrap_or_default(),
+        })
+        .map_err(|_| ConstructorErr::HostNormalizationErr)
+    }
+
+    /// Importer fails on the first connection error when no retries are set
+    pub fn connect_retry(&self) -> Result<ConnectRetry, ConstructorErr> {
+        Ok(ConnectRetry {
+            max_retries: self
+                .connect_max_retries
+                .as_deref()
+                .map(str::parse::<u32>)
+                .transpose()
+                .map_err(|_| ConstructorErr::ConnectErr)?
+                .unwrap_or_default(),
+            backoff: match self.connect_backoff_ms.as_deref().map(str::parse::<u64>) {
+                Some(Ok(0)) | Some(Err(_)) => return Err(ConstructorErr::ConnectErr),
+                Some(Ok(ms)) => Duration::from_millis(ms),
+                None => DEFAULT_CONNECT_BACKOFF,
+            },
+        })
+    }
+
+    fn http_settings(&self) -> Result<HttpSettings, ConstructorErr> {
+        let path = self.http_path.as_deref().unwrap_or(DEFAULT_HTTP_PATH);
+        if !path.starts_with('/') {
+            return Err(ConstructorErr::HttpErr);
+        }
+
+        Ok(HttpSettings {
+            bind_addr: self.http_bind_addr.clone().ok_or(ConstructorErr::HttpErr)?,
+            path: path.to_owned(),
+            max_body_bytes: match self.http_max_body_bytes.as_deref().map(str::parse::<usize>) {
+                Some(Ok(0)) | Some(Err(_)) => return Err(ConstructorErr::HttpErr),
+                Some(Ok(bytes)) => bytes,
+                None => DEFAULT_HTTP_MAX_BODY_BYTES,
+            },
+            allowlist: self.allowed_sources()?,
+        })
+    }
+
+    fn zmq_settings(&self) -> Result<ZMQSettings, ConstructorErr> {
+        Ok(ZMQSettings {
+            address: self.zmq_address.clone().ok_or(ConstructorErr::ZMQErr)?,
+            queue_name: self.zmq_queue_name.clone().ok_or(ConstructorErr::ZMQErr)?,
+            tcp_keepalive_idle_secs: self
+                .zmq_tcp_keepalive_idle_secs
+                .as_deref()
+                .map(str::parse::<i32>)
+                .transpose()
+                .map_err(|_| ConstructorErr::ZMQErr)?,
+            idle_timeout_ms: self
+                .zmq_idle_timeout_ms
+                .as_deref()
+                .map(str::parse::<i32>)
+                .transpose()
+                .map_err(|_| ConstructorErr::ZMQErr)?,
+            connect_timeout: match self
+                .zmq_connect_timeout_ms
+                .as_deref()
+                .map(str::parse::<u64>)
+            {
+                Some(Ok(0)) | Some(Err(_)) => return Err(ConstructorErr::ZMQErr),
+                Some(Ok(ms)) => Duration::from_millis(ms),
+                None => DEFAULT_ZMQ_CONNECT_TIMEOUT,
+            },
+            framing: self
+                .zmq_framing
+                .as_deref()
+                .map(str::parse::<Framing>)
+                .transpose()
+                .map_err(|_| ConstructorErr::ZMQErr)?
+                .unwrap_or_default(),
+            allowlist: self.allowed_sources()?,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub enum ExporterVariants {
     #[serde(rename = "kafka")]
     Kafka,
+    #[serde(rename = "file")]
+    File,
 }
 
 impl From<ExporterVariants> for String {
     fn from(variant: ExporterVariants) -> Self {
         match variant {
             ExporterVariants::Kafka => "kafka".to_string(),
+            ExporterVariants::File => "file".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ExporterVariants {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Kafka => "kafka",
+            Self::File => "file",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Writes `name: value` pairs of settings which are set
+fn fmt_set_fields(f: &mut fmt::Formatter, fields: &[(&str, &Option<String>)]) -> fmt::Result {
+    let set = fields
+        .iter()
+        .filter_map(|(name, value)| value.as_ref().map(|v| format!("{}: {}", name, v)))
+        .collect::<Vec<String>>();
+    write!(f, "{}", set.join(", "))
+}
+
+impl fmt::Display for ImporterSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_set_fields(
+            f,
+            &[
+                ("zmq_address", &self.zmq_address),
+                ("zmq_queue_name", &self.zmq_queue_name),
+                ("drop_policy", &self.drop_policy),
+                ("file_path", &self.file_path),
+                (
+                    "zmq_tcp_keepalive_idle_secs",
+                    &self.zmq_tcp_keepalive_idle_secs,
+                ),
+                ("zmq_idle_timeout_ms", &self.zmq_idle_timeout_ms),
+                ("zmq_connect_timeout_ms", &self.zmq_connect_timeout_ms),
+                ("zmq_framing", &self.zmq_framing),
+                ("spill_dir", &self.spill_dir),
+                ("spill_max_bytes", &self.spill_max_bytes),
+                ("spill_drain_mode", &self.spill_drain_mode),
+                ("ring_path", &self.ring_path),
+                ("ring_bytes", &self.ring_bytes),
+                ("transforms", &self.transforms),
+                ("field_defaults", &self.field_defaults),
+                ("unix_socket_path", &self.unix_socket_path),
+                ("http_bind_addr", &self.http_bind_addr),
+                ("http_path", &self.http_path),
+                ("http_max_body_bytes", &self.http_max_body_bytes),
+                ("debug_log_sample_rate", &self.debug_log_sample_rate),
+                ("max_messages_per_sec", &self.max_messages_per_sec),
+                ("rate_limit_burst", &self.rate_limit_burst),
+                ("rate_limit_policy", &self.rate_limit_policy),
+                ("heartbeat_interval_ms", &self.heartbeat_interval_ms),
+                ("heartbeat_marker", &self.heartbeat_marker),
+                ("connect_max_retries", &self.connect_max_retries),
+                ("connect_backoff_ms", &self.connect_backoff_ms),
+                ("allowed_sources", &self.allowed_sources),
+                ("host_lowercase", &self.host_lowercase),
+                ("host_canonical_mac", &self.host_canonical_mac),
+                ("host_strip_suffixes", &self.host_strip_suffixes),
+                ("host_aliases", &self.host_aliases),
+            ],
+        )
+    }
+}
+
+impl fmt::Display for ExporterSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_set_fields(
+            f,
+            &[
+                ("kafka_brokers", &self.kafka_brokers),
+                ("kafka_topic", &self.kafka_topic),
+                ("kafka_key_field", &self.kafka_key_field),
+                ("kafka_time_bucket_ms", &self.kafka_time_bucket_ms),
+                ("kafka_key_hash", &self.kafka_key_hash),
+                ("kafka_client_id", &self.kafka_client_id),
+                ("kafka_payload_compression", &self.kafka_payload_compression),
+                ("kafka_partitioner", &self.kafka_partitioner),
+                ("kafka_codec", &self.kafka_codec),
+                ("kafka_checksum", &self.kafka_checksum),
+                (
+                    "kafka_create_topic_if_missing",
+                    &self.kafka_create_topic_if_missing,
+                ),
+                ("kafka_topic_partitions", &self.kafka_topic_partitions),
+                (
+                    "kafka_topic_replication_factor",
+                    &self.kafka_topic_replication_factor,
+                ),
+                (
+                    "kafka_max_inflight_produces",
+                    &self.kafka_max_inflight_produces,
+                ),
+                ("schema_registry_url", &self.schema_registry_url),
+                (
+                    "schema_registry_subject_naming",
+                    &self.schema_registry_subject_naming,
+                ),
+                ("schema_registry_subject", &self.schema_registry_subject),
+                ("file_dir", &self.file_dir),
+                ("file_max_bytes", &self.file_max_bytes),
+                ("file_compression", &self.file_compression),
+                ("write_buffer_bytes", &self.write_buffer_bytes),
+            ],
+        )
+    }
+}
+
+impl fmt::Display for Configuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pipeline: importer {} [{}] -> exporter {} [{}]",
+            self.importer.source,
+            self.importer.settings,
+            self.exporter.destination,
+            self.exporter.settings
+        )?;
+        match &self.shadow_exporter {
+            Some(shadow) => write!(
+                f,
+                ", shadow exporter {} [{}] sampling {}%",
+                shadow.destination,
+                shadow.settings,
+                shadow
+                    .sample_percent
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SHADOW_SAMPLE_PERCENT.to_string())
+            ),
+            None => Ok(()),
         }
     }
 }
@@ -78,18 +609,85 @@ impl ExporterVariants {
     pub fn construct_exporter(
         &self,
         settings: ExporterSettings,
-    ) -> Result<KafkaExporter, ConstructorErr> {
+        identifier: &HostIdentifier,
+    ) -> Result<Box<dyn Export<Item = Vec<u8>>>, ConstructorErr> {
         match *self {
-            Self::Kafka => Ok(KafkaExporter::new(KafkaSettings {
-                brokers: settings
-                    .kafka_brokers
-                    .ok_or(ConstructorErr::KafkaErr)?
-                    .split(',')
-                    .map(|s| s.to_string())
-                    .collect(),
-                topic: settings.kafka_topic.ok_or(ConstructorErr::KafkaErr)?,
-            })
-            .expect("Wrong kafka config")),
+            Self::Kafka => {
+                let topic = settings
+                    .kafka_topic
+                    .clone()
+                    .ok_or(ConstructorErr::KafkaErr)?;
+                let client_id = settings
+                    .kafka_client_id
+                    .clone()
+                    .unwrap_or_else(|| default_client_id(&identifier.to_string(), &topic));
+                let codec = settings
+                    .kafka_codec
+                    .as_deref()
+                    .map(str::parse::<Codec>)
+                    .transpose()
+                    .map_err(|_| ConstructorErr::KafkaErr)?
+                    .unwrap_or_default();
+                let schema_registry = settings.schema_registry_settings(&topic)?;
+                let topic_creation = settings.topic_creation()?;
+                let time_bucket_ms = settings.time_bucket_ms()?;
+                let max_inflight_produces = settings.max_inflight_produces()?;
+                if codec == Codec::Avro && schema_registry.is_none() {
+                    return Err(ConstructorErr::KafkaErr);
+                }
+
+                Ok(Box::new(
+                    KafkaExporter::new(KafkaSettings {
+                        brokers: settings
+                            .kafka_brokers
+                            .ok_or(ConstructorErr::KafkaErr)?
+                            .split(',')
+                            .map(|s| s.to_string())
+                            .collect(),
+                        topic,
+                        key_field: settings
+                            .kafka_key_field
+                            .map(|f| f.parse::<KeyField>())
+                            .transpose()
+                            .map_err(|_| ConstructorErr::KafkaErr)?
+                            .unwrap_or_default(),
+                        time_bucket_ms,
+                        key_hash: settings
+                            .kafka_key_hash
+                            .map(|h| h.parse::<KeyHash>())
+                            .transpose()
+                            .map_err(|_| ConstructorErr::KafkaErr)?
+                            .unwrap_or_default(),
+                        client_id,
+                        compression: settings
+                            .kafka_payload_compression
+                            .map(|c| c.parse::<Compression>())
+                            .transpose()
+                            .map_err(|_| ConstructorErr::KafkaErr)?
+                            .unwrap_or_default(),
+                        partitioner: settings
+                            .kafka_partitioner
+                            .map(|p| p.parse::<Partitioner>())
+                            .transpose()
+                            .map_err(|_| ConstructorErr::KafkaErr)?,
+                        codec,
+                        checksum: settings
+                            .kafka_checksum
+                            .map(|c| c.parse::<Checksum>())
+                            .transpose()
+                            .map_err(|_| ConstructorErr::KafkaErr)?
+                            .unwrap_or_default(),
+                        schema_registry,
+                        topic_creation,
+                        max_inflight_produces,
+                    })
+                    .map_err(|_| ConstructorErr::KafkaErr)?,
+                ))
+            }
+            Self::File => Ok(Box::new(
+                FileExporter::new(settings.file_settings()?)
+                    .map_err(|_| ConstructorErr::FileErr)?,
+            )),
         }
     }
 }
@@ -100,11 +698,179 @@ pub struct Exporter {
     pub settings: ExporterSettings,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
 pub struct ExporterSettings {
     pub kafka_brokers: Option<String>,
 
     pub kafka_topic: Option<String>,
+
+    pub kafka_key_field: Option<String>,
+
+    pub kafka_time_bucket_ms: Option<String>,
+
+    pub kafka_key_hash: Option<String>,
+
+    pub kafka_client_id: Option<String>,
+
+    pub kafka_payload_compression: Option<String>,
+
+    pub kafka_partitioner: Option<String>,
+
+    pub kafka_codec: Option<String>,
+
+    pub kafka_checksum: Option<String>,
+
+    pub kafka_create_topic_if_missing: Option<String>,
+
+    pub kafka_topic_partitions: Option<String>,
+
+    pub kafka_topic_replication_factor: Option<String>,
+
+    pub kafka_max_inflight_produces: Option<String>,
+
+    pub schema_registry_url: Option<String>,
+
+    pub schema_registry_subject_naming: Option<String>,
+
+    pub schema_registry_subject: Option<String>,
+
+    pub file_dir: Option<String>,
+
+    pub file_max_bytes: Option<String>,
+
+    pub file_compression: Option<String>,
+
+    pub write_buffer_bytes: Option<String>,
+}
+
+impl ExporterSettings {
+    /// Subject is named after the topic unless set explicitly
+    fn schema_registry_settings(
+        &self,
+        topic: &str,
+    ) -> Result<Option<SchemaRegistrySettings>, ConstructorErr> {
+        let url = match &self.schema_registry_url {
+            Some(url) => url.to_owned(),
+            None => return Ok(None),
+        };
+        let naming = self
+            .schema_registry_subject_naming
+            .as_deref()
+            .map(str::parse::<SubjectNaming>)
+            .transpose()
+            .map_err(|_| ConstructorErr::KafkaErr)?
+            .unwrap_or_default();
+
+        Ok(Some(SchemaRegistrySettings {
+            url,
+            subject: self
+                .schema_registry_subject
+                .clone()
+                .unwrap_or_else(|| naming.subject(topic)),
+        }))
+    }
+
+    /// Keys are not bucketed when not set
+    fn time_bucket_ms(&self) -> Result<Option<u64>, ConstructorErr> {
+        match self
+            .kafka_time_bucket_ms
+            .as_deref()
+            .map(str::parse::<u64>)
+            .transpose()
+        {
+            Ok(Some(0)) | Err(_) => Err(ConstructorErr::KafkaErr),
+            Ok(bucket_ms) => Ok(bucket_ms),
+        }
+    }
+
+    /// Produces are not bounded when not set
+    fn max_inflight_produces(&self) -> Result<Option<usize>, ConstructorErr> {
+        match self
+            .kafka_max_inflight_produces
+            .as_deref()
+            .map(str::parse::<usize>)
+            .transpose()
+        {
+            Ok(Some(0)) | Err(_) => Err(ConstructorErr::KafkaErr),
+            Ok(limit) => Ok(limit),
+        }
+    }
+
+    /// Topics are required to exist unless creation is enabled
+    fn topic_creation(&self) -> Result<Option<TopicCreation>, ConstructorErr> {
+        let enabled = self
+            .kafka_create_topic_if_missing
+            .as_deref()
+            .map(str::parse::<bool>)
+            .transpose()
+            .map_err(|_| ConstructorErr::KafkaErr)?
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+
+        let positive = |value: &Option<String>, default: i32| match value
+            .as_deref()
+            .map(str::parse::<i32>)
+            .transpose()
+        {
+            Ok(v) if v.unwrap_or(default) > 0 => Ok(v.unwrap_or(default)),
+            _ => Err(ConstructorErr::KafkaErr),
+        };
+        Ok(Some(TopicCreation {
+            partitions: positive(&self.kafka_topic_partitions, DEFAULT_TOPIC_PARTITIONS)?,
+            replication_factor: positive(
+                &self.kafka_topic_replication_factor,
+                DEFAULT_TOPIC_REPLICATION_FACTOR,
+            )?,
+        }))
+    }
+
+    fn file_settings(&self) -> Result<FileSettings, ConstructorErr> {
+        Ok(FileSettings {
+            dir: PathBuf::from(self.file_dir.as_ref().ok_or(ConstructorErr::FileErr)?),
+            max_file_bytes: self
+                .file_max_bytes
+                .as_deref()
+                .map(str::parse::<u64>)
+                .transpose()
+                .map_err(|_| ConstructorErr::FileErr)?
+                .unwrap_or(DEFAULT_FILE_MAX_BYTES),
+            compression: self
+                .file_compression
+                .as_deref()
+                .map(str::parse::<Compression>)
+                .transpose()
+                .map_err(|_| ConstructorErr::FileErr)?
+                .unwrap_or_default(),
+            write_buffer_bytes: self
+                .write_buffer_bytes
+                .as_deref()
+                .map(str::parse::<usize>)
+                .transpose()
+                .map_err(|_| ConstructorErr::FileErr)?
+                .unwrap_or(DEFAULT_WRITE_BUFFER_BYTES),
+        })
+    }
+}
+
+/// Candidate exporter getting a sample of the messages exported by the production one,
+/// e.g. a new kafka cluster being validated before the switch
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct ShadowExporter {
+    pub destination: ExporterVariants,
+    pub settings: ExporterSettings,
+    pub sample_percent: Option<String>,
+}
+
+impl ShadowExporter {
+    pub fn sample_percent(&self) -> Result<u8, ConstructorErr> {
+        match self.sample_percent.as_deref().map(str::parse::<u8>) {
+            None => Ok(DEFAULT_SHADOW_SAMPLE_PERCENT),
+            Some(Ok(percent)) if (1..=100).contains(&percent) => Ok(percent),
+            Some(_) => Err(ConstructorErr::ShadowErr),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -112,6 +878,10 @@ pub struct Configuration {
     pub importer: Importer,
 
     pub exporter: Exporter,
+
+    /// Nothing is tee'd when not set
+    #[serde(default)]
+    pub shadow_exporter: Option<ShadowExporter>,
 }
 
 #[cfg(test)]
@@ -168,15 +938,479 @@ mod tests {
                     settings: ImporterSettings {
                         zmq_address: address,
                         zmq_queue_name: queue_name,
+                        ..Default::default()
                     },
                     source,
                 },
-                exporter
+                exporter,
+                shadow_exporter: None,
             },
             cfg
         );
     }
 
+    #[test]
+    fn test_configuration_display() {
+        let (_, exporter) = mock_exporter();
+        let configuration = Configuration {
+            importer: Importer {
+                source: ImporterVariants::ZMQ,
+                settings: ImporterSettings {
+                    zmq_address: Some("localhost:5561".to_string()),
+                    zmq_queue_name: Some("flow".to_string()),
+                    ..Default::default()
+                },
+            },
+            exporter,
+            shadow_exporter: None,
+        };
+
+        assert_eq!(
+            configuration.to_string(),
+            "pipeline: importer zmq [zmq_address: localhost:5561, zmq_queue_name: flow] \
+            -> exporter kafka [kafka_brokers: localhost:9092,localhost:9091, kafka_topic: test]"
+        );
+    }
+
+    #[test]
+    fn test_file_exporter_settings() {
+        let settings = ExporterSettings {
+            file_dir: Some("/var/lib/krewetka".to_string()),
+            file_compression: Some("zstd".to_string()),
+            write_buffer_bytes: Some("4096".to_string()),
+            ..Default::default()
+        };
+        let file = settings.file_settings().unwrap();
+
+        assert_eq!(file.dir, PathBuf::from("/var/lib/krewetka"));
+        assert_eq!(file.max_file_bytes, DEFAULT_FILE_MAX_BYTES);
+        assert_eq!(file.compression, Compression::Zstd);
+        assert_eq!(file.write_buffer_bytes, 4096);
+
+        let settings = ExporterSettings {
+            file_dir: Some("/var/lib/krewetka".to_string()),
+            file_compression: Some("lz4".to_string()),
+            ..Default::default()
+        };
+        assert!(settings.file_settings().is_err());
+    }
+
+    #[test]
+    fn test_topic_creation_settings() {
+        assert_eq!(ExporterSettings::default().topic_creation().unwrap(), None);
+
+        let settings = ExporterSettings {
+            kafka_create_topic_if_missing: Some("true".to_string()),
+            kafka_topic_partitions: Some("6".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.topic_creation().unwrap(),
+            Some(TopicCreation {
+                partitions: 6,
+                replication_factor: DEFAULT_TOPIC_REPLICATION_FACTOR,
+            })
+        );
+
+        let settings = ExporterSettings {
+            kafka_topic_replication_factor: Some("0".to_string()),
+            ..settings
+        };
+        assert!(settings.topic_creation().is_err());
+    }
+
+    #[test]
+    fn test_time_bucket_settings() {
+        assert_eq!(ExporterSettings::default().time_bucket_ms().unwrap(), None);
+
+        let bucket = |value: &str| {
+            ExporterSettings {
+                kafka_time_bucket_ms: Some(value.to_string()),
+                ..Default::default()
+            }
+            .time_bucket_ms()
+        };
+        assert_eq!(bucket("60000").unwrap(), Some(60_000));
+        assert!(bucket("0").is_err());
+        assert!(bucket("-1000").is_err());
+    }
+
+    #[test]
+    fn test_max_inflight_produces_settings() {
+        assert_eq!(
+            ExporterSettings::default().max_inflight_produces().unwrap(),
+            None
+        );
+
+        let limit = |value: &str| {
+            ExporterSettings {
+                kafka_max_inflight_produces: Some(value.to_string()),
+                ..Default::default()
+            }
+            .max_inflight_produces()
+        };
+        assert_eq!(limit("10000").unwrap(), Some(10_000));
+        assert!(limit("0").is_err());
+        assert!(limit("many").is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_settings() {
+        assert_eq!(ImporterSettings::default().rate_limit().unwrap(), None);
+
+        let rate_limit = |rate: &str, burst: Option<&str>, policy: Option<&str>| {
+            ImporterSettings {
+                max_messages_per_sec: Some(rate.to_string()),
+                rate_limit_burst: burst.map(str::to_string),
+                rate_limit_policy: policy.map(str::to_string),
+                ..Default::default()
+            }
+            .rate_limit()
+        };
+        assert_eq!(
+            rate_limit("1000", None, None).unwrap(),
+            Some(RateLimitSettings {
+                max_messages_per_sec: 1000,
+                burst: 1000,
+                policy: RateLimitPolicy::Block,
+            })
+        );
+        assert_eq!(
+            rate_limit("1000", Some("5000"), Some("drop")).unwrap(),
+            Some(RateLimitSettings {
+                max_messages_per_sec: 1000,
+                burst: 5000,
+                policy: RateLimitPolicy::Drop,
+            })
+        );
+        assert!(rate_limit("0", None, None).is_err());
+        assert!(rate_limit("1000", Some("0"), None).is_err());
+        assert!(rate_limit("1000", None, Some("shed")).is_err());
+    }
+
+    #[test]
+    fn test_shadow_exporter_settings() {
+        let cfg = serde_yaml::from_str::<Configuration>(
+            "
+importer:
+  source: zmq
+  settings: {}
+exporter:
+  destination: kafka
+  settings:
+    kafka_brokers: old:9092
+    kafka_topic: flows
+shadow_exporter:
+  destination: kafka
+  settings:
+    kafka_brokers: new:9092
+    kafka_topic: flows
+  sample_percent: \"5\"
+",
+        )
+        .unwrap();
+        let shadow = cfg.shadow_exporter.clone().unwrap();
+        assert_eq!(shadow.sample_percent().unwrap(), 5);
+        assert!(cfg.to_string().ends_with(
+            ", shadow exporter kafka [kafka_brokers: new:9092, kafka_topic: flows] sampling 5%"
+        ));
+
+        let percent = |p: Option<&str>| {
+            ShadowExporter {
+                sample_percent: p.map(str::to_string),
+                ..shadow.clone()
+            }
+            .sample_percent()
+        };
+        assert_eq!(percent(None).unwrap(), DEFAULT_SHADOW_SAMPLE_PERCENT);
+        assert!(percent(Some("0")).is_err());
+        assert!(percent(Some("101")).is_err());
+        assert!(percent(Some("0.5")).is_err());
+    }
+
+    #[test]
+    fn test_zmq_importer_settings() {
+        let settings = ImporterSettings {
+            zmq_address: Some("localhost:5561".to_string()),
+            zmq_queue_name: Some("flow".to_string()),
+            zmq_idle_timeout_ms: Some("30000".to_string()),
+            ..Default::default()
+        };
+        let zmq = settings.zmq_settings().unwrap();
+
+        assert_eq!(zmq.tcp_keepalive_idle_secs, None);
+        assert_eq!(zmq.idle_timeout_ms, Some(30000));
+        assert_eq!(zmq.connect_timeout, DEFAULT_ZMQ_CONNECT_TIMEOUT);
+        assert_eq!(zmq.framing, Framing::Single);
+        for invalid in ["0", "soon"] {
+            let settings = ImporterSettings {
+                zmq_connect_timeout_ms: Some(invalid.to_string()),
+                ..settings.clone()
+            };
+            assert!(settings.zmq_settings().is_err());
+        }
+
+        let batched = ImporterSettings {
+            zmq_framing: Some("length-delimited-batch".to_string()),
+            ..settings.clone()
+        };
+        assert_eq!(
+            batched.zmq_settings().unwrap().framing,
+            Framing::LengthDelimitedBatch
+        );
+
+        let allowing = ImporterSettings {
+            allowed_sources: Some("10.0.0.0/8, 192.168.0.7".to_string()),
+            ..settings.clone()
+        };
+        assert_eq!(
+            allowing.zmq_settings().unwrap().allowlist,
+            "10.0.0.0/8,192.168.0.7".parse().unwrap()
+        );
+        assert!(settings.zmq_settings().unwrap().allowlist.is_empty());
+        let invalid = ImporterSettings {
+            allowed_sources: Some("exporter-1".to_string()),
+            ..settings.clone()
+        };
+        assert!(invalid.zmq_settings().is_err());
+
+        let settings = ImporterSettings {
+            zmq_tcp_keepalive_idle_secs: Some("1m".to_string()),
+            ..settings
+        };
+        assert!(settings.zmq_settings().is_err());
+    }
+
+    #[test]
+    fn test_spill_settings() {
+        assert_eq!(ImporterSettings::default().spill_settings().unwrap(), None);
+
+        let settings = ImporterSettings {
+            spill_dir: Some("/var/lib/krewetka/spill".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.spill_settings().unwrap(),
+            Some(SpillSettings {
+                dir: PathBuf::from("/var/lib/krewetka/spill"),
+                max_bytes: DEFAULT_SPILL_MAX_BYTES,
+                drain_mode: SpillDrainMode::Concurrent,
+            })
+        );
+
+        let before_live = ImporterSettings {
+            spill_drain_mode: Some("before-live".to_string()),
+            ..settings.clone()
+        };
+        assert_eq!(
+            before_live.spill_settings().unwrap().unwrap().drain_mode,
+            SpillDrainMode::BeforeLive
+        );
+        let unknown_mode = ImporterSettings {
+            spill_drain_mode: Some("later".to_string()),
+            ..settings.clone()
+        };
+        assert!(unknown_mode.spill_settings().is_err());
+
+        let settings = ImporterSettings {
+            spill_max_bytes: Some("1GB".to_string()),
+            ..settings
+        };
+        assert!(settings.spill_settings().is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_settings() {
+        assert_eq!(
+            ImporterSettings::default().heartbeat_settings().unwrap(),
+            None
+        );
+
+        let settings = ImporterSettings {
+            heartbeat_interval_ms: Some("30000".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.heartbeat_settings().unwrap(),
+            Some(HeartbeatSettings {
+                interval: Duration::from_secs(30),
+                marker: DEFAULT_HEARTBEAT_MARKER,
+            })
+        );
+        let marked = ImporterSettings {
+            heartbeat_marker: Some("198.51.100.7".to_string()),
+            ..settings.clone()
+        };
+        assert_eq!(
+            marked.heartbeat_settings().unwrap().unwrap().marker,
+            Ipv4Addr::new(198, 51, 100, 7)
+        );
+
+        for (interval, marker) in [("0", None), ("30000", Some("heartbeat"))] {
+            let invalid = ImporterSettings {
+                heartbeat_interval_ms: Some(interval.to_string()),
+                heartbeat_marker: marker.map(str::to_string),
+                ..Default::default()
+            };
+            assert!(invalid.heartbeat_settings().is_err());
+        }
+    }
+
+    #[test]
+    fn test_http_importer_settings() {
+        let settings = ImporterSettings {
+            http_bind_addr: Some("0.0.0.0:8080".to_string()),
+            ..Default::default()
+        };
+        let http = settings.http_settings().unwrap();
+        assert_eq!(http.bind_addr, "0.0.0.0:8080");
+        assert_eq!(http.path, DEFAULT_HTTP_PATH);
+        assert_eq!(http.max_body_bytes, DEFAULT_HTTP_MAX_BODY_BYTES);
+
+        let settings = ImporterSettings {
+            http_path: Some("/ingest".to_string()),
+            http_max_body_bytes: Some("65536".to_string()),
+            ..settings
+        };
+        let http = settings.http_settings().unwrap();
+        assert_eq!(http.path, "/ingest");
+        assert_eq!(http.max_body_bytes, 65536);
+
+        assert!(ImporterSettings::default().http_settings().is_err());
+        for (path, max_body_bytes) in [("ingest", None), ("/ingest", Some("0"))] {
+            let invalid = ImporterSettings {
+                http_path: Some(path.to_string()),
+                http_max_body_bytes: max_body_bytes.map(str::to_string),
+                ..settings.clone()
+            };
+            assert!(invalid.http_settings().is_err());
+        }
+    }
+
+    #[test]
+    fn test_connect_retry_settings() {
+        assert_eq!(
+            ImporterSettings::default().connect_retry().unwrap(),
+            ConnectRetry::default()
+        );
+
+        let settings = ImporterSettings {
+            connect_max_retries: Some("5".to_string()),
+            connect_backoff_ms: Some("250".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.connect_retry().unwrap(),
+            ConnectRetry {
+                max_retries: 5,
+                backoff: Duration::from_millis(250),
+            }
+        );
+
+        for (retries, backoff) in [("-1", None), ("5", Some("0")), ("5", Some("1s"))] {
+            let invalid = ImporterSettings {
+                connect_max_retries: Some(retries.to_string()),
+                connect_backoff_ms: backoff.map(str::to_string),
+                ..Default::default()
+            };
+            assert!(invalid.connect_retry().is_err());
+        }
+    }
+
+    #[test]
+    fn test_host_normalization_settings() {
+        assert!(ImporterSettings::default()
+            .host_normalization()
+            .unwrap()
+            .is_noop());
+
+        let settings = ImporterSettings {
+            host_lowercase: Some("true".to_string()),
+            host_strip_suffixes: Some(".lan".to_string()),
+            host_aliases: Some("10.0.0.1=edge-1".to_string()),
+            ..Default::default()
+        };
+        let normalization = settings.host_normalization().unwrap();
+        assert_eq!(normalization.apply("Edge-1.LAN"), "edge-1");
+        assert_eq!(normalization.apply("10.0.0.1"), "edge-1");
+
+        for invalid in [
+            ImporterSettings {
+                host_canonical_mac: Some("yes".to_string()),
+                ..Default::default()
+            },
+            ImporterSettings {
+                host_aliases: Some("edge-1".to_string()),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                invalid.host_normalization(),
+                Err(ConstructorErr::HostNormalizationErr)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_ring_settings() {
+        assert_eq!(ImporterSettings::default().ring_settings().unwrap(), None);
+
+        let settings = ImporterSettings {
+            ring_path: Some("/var/lib/krewetka/ring".to_string()),
+            ring_bytes: Some("1048576".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.ring_settings().unwrap(),
+            Some(RingSettings {
+                path: PathBuf::from("/var/lib/krewetka/ring"),
+                capacity_bytes: 1048576,
+            })
+        );
+
+        let settings = ImporterSettings {
+            ring_bytes: Some("1MB".to_string()),
+            ..settings
+        };
+        assert!(settings.ring_settings().is_err());
+    }
+
+    #[test]
+    fn test_schema_registry_settings() {
+        let settings = ExporterSettings {
+            schema_registry_url: Some("http://registry:8081".to_string()),
+            schema_registry_subject_naming: Some("topic_record_name".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.schema_registry_settings("flows").unwrap(),
+            Some(SchemaRegistrySettings {
+                url: "http://registry:8081".to_string(),
+                subject: "flows-krewetka.flow.FlowMessage".to_string(),
+            })
+        );
+
+        let settings = ExporterSettings {
+            schema_registry_subject: Some("flows-v2".to_string()),
+            ..settings
+        };
+        assert_eq!(
+            settings
+                .schema_registry_settings("flows")
+                .unwrap()
+                .unwrap()
+                .subject,
+            "flows-v2"
+        );
+
+        assert_eq!(
+            ExporterSettings::default()
+                .schema_registry_settings("flows")
+                .unwrap(),
+            None
+        );
+    }
+
     fn mock_exporter() -> (String, Exporter) {
         let yaml = "exporter:
           destination: kafka
@@ -190,6 +1424,7 @@ mod tests {
             settings: ExporterSettings {
                 kafka_brokers: Some("localhost:9092,localhost:9091".to_string()),
                 kafka_topic: Some("test".to_string()),
+                ..Default::default()
             },
         };
 
@@ -213,10 +1448,12 @@ mod tests {
         let importer_settings = ImporterSettings {
             zmq_address: zmq_address.clone(),
             zmq_queue_name: zmq_queue_name.clone(),
+            ..Default::default()
         };
         let exporter_settings = ExporterSettings {
             kafka_brokers: kafka_brokers.clone(),
             kafka_topic: kafka_topic.clone(),
+            ..Default::default()
         };
 
         // expected configuration
@@ -229,6 +1466,7 @@ mod tests {
                 destination: destination.clone(),
                 settings: exporter_settings,
             },
+            shadow_exporter: None,
         };
 
         let env_setter = |key, val| {
@@ -0,0 +1,77 @@
+use log::{info, warn};
+use std::future::Future;
+use tokio::time::{timeout, Duration};
+
+/// How long the importer may take to hand over the batch it is importing
+pub const STOP_IMPORTER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long messages left in the exporter channel, and spilled ones, may take to be exported
+pub const DRAIN_EXPORTER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long exported messages may wait for delivery to kafka
+pub const FLUSH_EXPORTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a single step of the shutdown, `None` when it did not finish in time
+pub async fn stage<F: Future>(name: &str, limit: Duration, fut: F) -> Option<F::Output> {
+    info!("shutdown: {}...", name);
+    match timeout(limit, fut).await {
+        Ok(output) => {
+            info!("shutdown: {} done", name);
+            Some(output)
+        }
+        Err(_) => {
+            warn!("shutdown: {} timed out after {:?}", name, limit);
+            None
+        }
+    }
+}
+
+/// Resolves on ctrl-c, or SIGTERM sent by kubernetes
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("unable to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stage_is_bounded() {
+        assert_eq!(
+            stage("quick", Duration::from_millis(100), async { 7 }).await,
+            Some(7)
+        );
+        assert_eq!(
+            stage(
+                "stuck",
+                Duration::from_millis(20),
+                std::future::pending::<()>()
+            )
+            .await,
+            None
+        );
+    }
+}
@@ -0,0 +1,61 @@
+/// Murmur2 as implemented in `org.apache.kafka.common.utils.Utils`
+pub fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate().rev() {
+            h ^= (*b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// CRC-32 (IEEE 802.3), same as `java.util.zip.CRC32` and python `zlib.crc32`
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // vectors from kafka UtilsTest
+    #[test_case("21", -973932308)]
+    #[test_case("foobar", -790332482)]
+    #[test_case("a-little-bit-long-string", -985981536)]
+    #[test_case("a-little-bit-longer-string", -1486304829)]
+    #[test_case("lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8", -58897971)]
+    #[test_case("abc", 479470107)]
+    fn test_murmur2(input: &str, expected: i32) {
+        assert_eq!(murmur2(input.as_bytes()) as i32, expected);
+    }
+
+    #[test_case("", 0x0000_0000)]
+    #[test_case("a", 0xe8b7_be43)]
+    #[test_case("123456789", 0xcbf4_3926)]
+    #[test_case("The quick brown fox jumps over the lazy dog", 0x414f_a339)]
+    fn test_crc32(input: &str, expected: u32) {
+        assert_eq!(crc32(input.as_bytes()), expected);
+    }
+}
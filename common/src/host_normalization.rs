@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostNormalizationError(String);
+
+impl fmt::Display for HostNormalizationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid host alias: {}", self.0)
+    }
+}
+
+impl std::error::Error for HostNormalizationError {}
+
+/// Rules of host normalization, nothing is changed by default
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HostNormalizationSettings {
+    pub lowercase: bool,
+    /// Mac addresses are written as lowercase octets separated with `:`
+    pub canonical_mac: bool,
+    /// Comma separated domain suffixes stripped from identifiers, e.g. `.lan,.corp.example.com`
+    pub strip_suffixes: String,
+    /// Comma separated `<host>=<canonical-host>` lookup table applied after the other rules
+    pub aliases: String,
+}
+
+/// Rewrites `aa-bb-cc-dd-ee-ff`, `AA:BB:CC:DD:EE:FF` and `aabb.ccdd.eeff` to `aa:bb:cc:dd:ee:ff`,
+/// `None` when the identifier is not a mac address
+fn canonical_mac(id: &str) -> Option<String> {
+    let separator = id.chars().find(|c| matches!(c, ':' | '-' | '.'))?;
+    let groups = id.split(separator).collect::<Vec<&str>>();
+    let group_len = match groups.len() {
+        6 => 2,
+        3 => 4,
+        _ => return None,
+    };
+    if !groups
+        .iter()
+        .all(|g| g.len() == group_len && g.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return None;
+    }
+
+    let digits = groups.concat().to_ascii_lowercase();
+    Some(
+        digits
+            .as_bytes()
+            .chunks(2)
+            .map(|octet| String::from_utf8_lossy(octet).into_owned())
+            .collect::<Vec<String>>()
+            .join(":"),
+    )
+}
+
+/// Canonical form of host identifiers, collector exports flows and processor stores them under it,
+/// so flows of a host are keyed, aggregated and stored under the same identifier however the
+/// host is identified. Rules run in order:
+/// lowercase, mac addresses, domain suffixes, aliases. Changes nothing by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostNormalization {
+    lowercase: bool,
+    canonical_mac: bool,
+    strip_suffixes: Vec<String>,
+    /// keys are normalized by the other rules, so they match however the host is sent
+    aliases: HashMap<String, String>,
+}
+
+impl HostNormalization {
+    pub fn new(settings: &HostNormalizationSettings) -> Result<Self, HostNormalizationError> {
+        let mut normalization = Self {
+            lowercase: settings.lowercase,
+            canonical_mac: settings.canonical_mac,
+            strip_suffixes: settings
+                .strip_suffixes
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            aliases: HashMap::new(),
+        };
+
+        for entry in settings
+            .aliases
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (host, alias) = entry
+                .split_once('=')
+                .map(|(h, a)| (h.trim(), a.trim()))
+                .filter(|(h, a)| !h.is_empty() && !a.is_empty())
+                .ok_or_else(|| HostNormalizationError(entry.to_owned()))?;
+            let host = normalization.canonical(host);
+            normalization.aliases.insert(host, alias.to_owned());
+        }
+        Ok(normalization)
+    }
+
+    pub fn is_noop(&self) -> bool {
+        !self.lowercase
+            && !self.canonical_mac
+            && self.strip_suffixes.is_empty()
+            && self.aliases.is_empty()
+    }
+
+    /// Every rule except aliases
+    fn canonical(&self, host: &str) -> String {
+        let mut host = host.trim().to_owned();
+        if self.lowercase {
+            host = host.to_lowercase();
+        }
+        if self.canonical_mac {
+            if let Some(mac) = canonical_mac(&host) {
+                return mac;
+            }
+        }
+        // the first matching suffix is stripped, an identifier made of the suffix alone is kept
+        if let Some(stripped) = self
+            .strip_suffixes
+            .iter()
+            .find_map(|s| host.strip_suffix(s.as_str()).filter(|h| !h.is_empty()))
+        {
+            host = stripped.to_owned();
+        }
+        host
+    }
+
+    pub fn apply(&self, host: &str) -> String {
+        if self.is_noop() {
+            return host.to_owned();
+        }
+        let host = self.canonical(host);
+        match self.aliases.get(&host) {
+            Some(alias) => alias.clone(),
+            None => host,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalization(
+        lowercase: bool,
+        canonical_mac: bool,
+        strip_suffixes: &str,
+        aliases: &str,
+    ) -> HostNormalization {
+        HostNormalization::new(&HostNormalizationSettings {
+            lowercase,
+            canonical_mac,
+            strip_suffixes: strip_suffixes.to_owned(),
+            aliases: aliases.to_owned(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_nothing_is_changed_by_default() {
+        let normalization = HostNormalization::default();
+        assert!(normalization.is_noop());
+        assert_eq!(normalization.apply(" Edge-1.LAN "), " Edge-1.LAN ");
+    }
+
+    #[test]
+    fn test_rules_are_applied_in_order() {
+        let normalization = normalization(true, true, ".lan,.corp.example.com", "");
+
+        assert_eq!(normalization.apply("Edge-1.LAN"), "edge-1");
+        assert_eq!(normalization.apply("db.corp.example.com"), "db");
+        assert_eq!(normalization.apply(".lan"), ".lan");
+        assert_eq!(normalization.apply("10.0.0.1"), "10.0.0.1");
+        assert_eq!(
+            normalization.apply("AA-BB-CC-00-11-22"),
+            "aa:bb:cc:00:11:22"
+        );
+        assert_eq!(normalization.apply("aabb.cc00.1122"), "aa:bb:cc:00:11:22");
+    }
+
+    const VECTORS: [(&str, &str); 8] = [
+        ("Edge-1.LAN", "edge-1"),
+        ("db.corp.example.com", "db"),
+        (".lan", ".lan"),
+        ("10.0.0.1", "edge-1"),
+        ("10.0.0.2", "10.0.0.2"),
+        ("AA-BB-CC-00-11-22", "edge-1"),
+        ("aabb.cc00.3344", "aa:bb:cc:00:33:44"),
+        (" Edge-2 ", "edge-2"),
+    ];
+
+    #[test]
+    fn test_normalization_vectors() {
+        let normalization = normalization(
+            true,
+            true,
+            ".lan,.corp.example.com",
+            "10.0.0.1=edge-1, aa:bb:cc:00:11:22=edge-1",
+        );
+        for (host, expected) in VECTORS {
+            assert_eq!(normalization.apply(host), expected, "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_mac_addresses() {
+        assert_eq!(
+            canonical_mac("aa:BB:cc:00:11:22").as_deref(),
+            Some("aa:bb:cc:00:11:22")
+        );
+        for not_mac in [
+            "aa:bb:cc:00:11",
+            "aa:bb-cc:00:11:22",
+            "gg:bb:cc:00:11:22",
+            "edge-1",
+        ] {
+            assert_eq!(canonical_mac(not_mac), None, "{}", not_mac);
+        }
+    }
+
+    #[test]
+    fn test_aliases_match_normalized_hosts() {
+        let aliased = normalization(
+            true,
+            true,
+            ".lan",
+            "EDGE-1.lan=edge-1, AA-BB-CC-00-11-22=edge-1",
+        );
+
+        assert_eq!(aliased.apply("edge-1.LAN"), "edge-1");
+        assert_eq!(aliased.apply("aa:bb:cc:00:11:22"), "edge-1");
+        assert_eq!(aliased.apply("Edge-2"), "edge-2");
+
+        // without other rules aliases match identifiers as they are sent
+        assert_eq!(
+            normalization(false, false, "", "10.0.0.1=edge-1").apply("10.0.0.1"),
+            "edge-1"
+        );
+    }
+
+    #[test]
+    fn test_invalid_aliases_are_rejected() {
+        for aliases in ["edge-1", "=edge-1", "edge-1="] {
+            let settings = HostNormalizationSettings {
+                aliases: aliases.to_owned(),
+                ..Default::default()
+            };
+            assert!(HostNormalization::new(&settings).is_err(), "{}", aliases);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::hash::murmur2;
+use crate::parse::UnknownVariant;
+
+/// Part of the flow a key is derived from. Collector derives kafka message keys from it and
+/// processor the sharding key of stored rows, so the same setting colocates a flow on both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyField {
+    /// random key, spreads messages evenly across partitions
+    #[default]
+    Random,
+    /// identifier of the host running collector
+    Host,
+    /// source address of the flow
+    SrcIp,
+    /// source and destination socket with protocol of the flow
+    FiveTuple,
+}
+
+impl FromStr for KeyField {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "host" => Ok(Self::Host),
+            "src_ip" => Ok(Self::SrcIp),
+            "five_tuple" => Ok(Self::FiveTuple),
+            _ => Err(UnknownVariant(s.to_owned())),
+        }
+    }
+}
+
+/// Fields of a flow keys are derived from
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyParts<'a> {
+    pub host: &'a str,
+    pub ipv4_src_addr: &'a str,
+    pub l4_src_port: u32,
+    pub ipv4_dst_addr: &'a str,
+    pub l4_dst_port: u32,
+    pub protocol: u32,
+}
+
+impl KeyField {
+    /// Key of the flow, `None` for `Random` which is not derived from the flow
+    pub fn key(&self, parts: &KeyParts) -> Option<String> {
+        match self {
+            Self::Random => None,
+            Self::Host => Some(parts.host.to_owned()),
+            Self::SrcIp => Some(parts.ipv4_src_addr.to_owned()),
+            Self::FiveTuple => Some(format!(
+                "{}:{}-{}:{}-{}",
+                parts.ipv4_src_addr,
+                parts.l4_src_port,
+                parts.ipv4_dst_addr,
+                parts.l4_dst_port,
+                parts.protocol
+            )),
+        }
+    }
+
+    /// Positive murmur2 of the key, `shard_key % n` is the partition the java kafka client
+    /// and librdkafka `murmur2` partitioner pick out of `n` for the same key
+    pub fn shard_key(&self, parts: &KeyParts) -> Option<u32> {
+        self.key(parts)
+            .map(|key| murmur2(key.as_bytes()) & 0x7fff_ffff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn parts<'a>(host: &'a str, ipv4_src_addr: &'a str) -> KeyParts<'a> {
+        KeyParts {
+            host,
+            ipv4_src_addr,
+            l4_src_port: 56341,
+            ipv4_dst_addr: "10.0.0.1",
+            l4_dst_port: 53,
+            protocol: 17,
+        }
+    }
+
+    #[test_case("random", Ok(KeyField::Random))]
+    #[test_case("host", Ok(KeyField::Host))]
+    #[test_case("src_ip", Ok(KeyField::SrcIp))]
+    #[test_case("five_tuple", Ok(KeyField::FiveTuple))]
+    #[test_case("tuple", Err(UnknownVariant("tuple".to_owned())))]
+    fn test_key_field_from_str(input: &str, expected: Result<KeyField, UnknownVariant>) {
+        assert_eq!(input.parse::<KeyField>(), expected);
+    }
+
+    #[test_case("random", KeyField::Random)]
+    #[test_case("host", KeyField::Host)]
+    #[test_case("src_ip", KeyField::SrcIp)]
+    #[test_case("five_tuple", KeyField::FiveTuple)]
+    fn test_key_field_is_deserialized_like_parsed(input: &str, expected: KeyField) {
+        assert_eq!(
+            serde_json::from_str::<KeyField>(&format!(r#""{input}""#)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_key() {
+        let p = parts("host", "10.0.0.2");
+        assert_eq!(KeyField::Random.key(&p), None);
+        assert_eq!(KeyField::Host.key(&p).unwrap(), "host");
+        assert_eq!(KeyField::SrcIp.key(&p).unwrap(), "10.0.0.2");
+        assert_eq!(
+            KeyField::FiveTuple.key(&p).unwrap(),
+            "10.0.0.2:56341-10.0.0.1:53-17"
+        );
+    }
+
+    #[test]
+    fn test_shard_key_is_deterministic_and_positive() {
+        let p = parts("foobar", "10.0.0.1");
+        assert_eq!(KeyField::Random.shard_key(&p), None);
+
+        // kafka `toPositive` of -790332482
+        assert_eq!(KeyField::Host.shard_key(&p), Some(1357151166));
+        assert_eq!(
+            KeyField::Host.shard_key(&p),
+            KeyField::Host.shard_key(&parts("foobar", "10.0.0.2"))
+        );
+        assert_ne!(
+            KeyField::SrcIp.shard_key(&p),
+            KeyField::SrcIp.shard_key(&parts("foobar", "10.0.0.2"))
+        );
+    }
+}
@@ -0,0 +1,10 @@
+pub mod hash;
+pub mod host_normalization;
+pub mod keys;
+pub mod log_sampling;
+pub mod parse;
+pub mod redact;
+
+// used by `sampled_debug!`, callers don't have to depend on the same version of log
+#[doc(hidden)]
+pub use log;
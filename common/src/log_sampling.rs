@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Every message is logged unless configured otherwise
+pub const DEFAULT_DEBUG_LOG_SAMPLE_RATE: u64 = 1;
+
+static DEBUG_LOG_SAMPLE_RATE: AtomicU64 = AtomicU64::new(DEFAULT_DEBUG_LOG_SAMPLE_RATE);
+
+/// Only 1 in `rate` of per message debug logs is written, 0 is taken as 1
+pub fn set_debug_log_sample_rate(rate: u64) {
+    DEBUG_LOG_SAMPLE_RATE.store(rate.max(1), Ordering::Relaxed);
+}
+
+/// Whether the next message counted by `counter` of a single call site should be logged
+pub fn sampled(counter: &AtomicU64) -> bool {
+    let rate = DEBUG_LOG_SAMPLE_RATE.load(Ordering::Relaxed);
+    rate <= 1 || counter.fetch_add(1, Ordering::Relaxed) % rate == 0
+}
+
+/// `debug!` of per message logs, only 1 in the set sample rate of messages of each call site
+/// is logged, so debug level stays usable under load
+#[macro_export]
+macro_rules! sampled_debug {
+    (target: $target:expr, $($arg:tt)+) => {{
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        if $crate::log::log_enabled!(target: $target, $crate::log::Level::Debug)
+            && $crate::log_sampling::sampled(&COUNTER)
+        {
+            $crate::log::debug!(target: $target, $($arg)+);
+        }
+    }};
+    ($($arg:tt)+) => {{
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        if $crate::log::log_enabled!($crate::log::Level::Debug)
+            && $crate::log_sampling::sampled(&COUNTER)
+        {
+            $crate::log::debug!($($arg)+);
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VECTORS: [(u64, usize, usize); 5] =
+        [(0, 10, 10), (1, 10, 10), (4, 10, 3), (3, 9, 3), (100, 5, 1)];
+
+    #[test]
+    fn test_one_in_rate_is_sampled() {
+        // the rate is global, vectors are checked one after another in a single test
+        for (rate, messages, expected) in VECTORS {
+            set_debug_log_sample_rate(rate);
+            let counter = AtomicU64::new(0);
+            let logged = (0..messages).filter(|_| sampled(&counter)).count();
+            assert_eq!(logged, expected, "1 in {} of {}", rate, messages);
+        }
+        set_debug_log_sample_rate(DEFAULT_DEBUG_LOG_SAMPLE_RATE);
+    }
+}
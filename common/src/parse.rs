@@ -0,0 +1,11 @@
+use core::fmt;
+
+/// Error of parsing a setting into one of the variants of an enum
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownVariant(pub String);
+
+impl fmt::Display for UnknownVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown variant: {}", self.0)
+    }
+}
@@ -8,6 +8,8 @@ fn main() {
     tonic_build::configure()
         .build_server(false)
         .out_dir("./src")
+        // json payloads are decoded straight into the flow, so they always have its fields
+        .type_attribute(".flow.FlowMessage", "#[derive(serde::Deserialize)]")
         .compile(
             &[&proto_path_ancestors.next().unwrap()],
             &[&proto_path_ancestors.next().unwrap()],
@@ -0,0 +1,216 @@
+use super::aggregator::{Arrival, Rollup, WindowAggregator};
+use super::messages::InitRollupFlusher;
+
+use crate::actors::broker::Broker;
+use crate::actors::consts::MAILBOX_CAPACITY;
+use crate::actors::messages::PersistFlowMessageWithMetadata;
+use crate::actors::BrokerType;
+use crate::metrics::ROLLUP_LATE_FLOWS;
+use crate::privacy::Redaction;
+use crate::settings::AggregationSettings;
+
+use actix::{Actor, Context, Handler, ResponseFuture};
+use actix_broker::BrokerSubscribe;
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Mutex as TokioMtx;
+use tokio::time::interval;
+
+#[async_trait]
+pub trait RollupStorage: 'static {
+    async fn stash_rollups(&self, table: &str, rollups: Vec<Rollup>) -> Result<(), Box<dyn Error>>;
+}
+
+/// Saves rollups to every configured destination, one failing does not stop the others
+pub struct RollupDestinations(Vec<Arc<dyn RollupStorage + Send + Sync>>);
+
+impl RollupDestinations {
+    pub fn new(destinations: Vec<Arc<dyn RollupStorage + Send + Sync>>) -> Self {
+        Self(destinations)
+    }
+}
+
+#[async_trait]
+impl RollupStorage for RollupDestinations {
+    async fn stash_rollups(&self, table: &str, rollups: Vec<Rollup>) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+        for destination in &self.0 {
+            if let Err(e) = destination.stash_rollups(table, rollups.clone()).await {
+                errors.push(e.to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", ").into())
+        }
+    }
+}
+
+/// Aggregates persisted flows into per window rollups.
+/// Rollups are best effort, a batch which fails to be saved is dropped
+pub struct AggregationActor<S>
+where
+    S: RollupStorage,
+{
+    storage: Arc<S>,
+    aggregator: Arc<Mutex<WindowAggregator>>,
+    settings: AggregationSettings,
+    redaction: Arc<Redaction>,
+    pub broker: Arc<TokioMtx<Broker>>,
+}
+
+impl<S> AggregationActor<S>
+where
+    S: RollupStorage,
+{
+    pub fn new(
+        storage: Arc<S>,
+        broker: Arc<TokioMtx<Broker>>,
+        settings: AggregationSettings,
+        redaction: Arc<Redaction>,
+    ) -> Self {
+        let aggregator = Arc::new(Mutex::new(
+            WindowAggregator::new(settings.window_secs * 1000, settings.key)
+                .with_allowed_lateness(settings.allowed_lateness_ms),
+        ));
+
+        Self {
+            storage,
+            aggregator,
+            settings,
+            redaction,
+            broker,
+        }
+    }
+}
+
+impl<S> Actor for AggregationActor<S>
+where
+    S: RollupStorage + Unpin,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "Started aggregation actor with {} key!",
+            self.settings.key.as_str()
+        );
+        ctx.set_mailbox_capacity(MAILBOX_CAPACITY);
+
+        self.subscribe_async::<BrokerType, PersistFlowMessageWithMetadata>(ctx);
+        self.subscribe_async::<BrokerType, InitRollupFlusher>(ctx);
+
+        tokio::spawn({
+            let broker = self.broker.clone();
+            async move {
+                broker.lock().await.issue_async(InitRollupFlusher);
+            }
+        });
+    }
+}
+
+impl<S> Handler<PersistFlowMessageWithMetadata> for AggregationActor<S>
+where
+    S: RollupStorage + Unpin,
+{
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: PersistFlowMessageWithMetadata,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let f = self.redaction.apply(&msg.0);
+        match self.aggregator.lock().unwrap().add(&f) {
+            Arrival::OnTime => {}
+            Arrival::Late => ROLLUP_LATE_FLOWS.with_label_values(&["corrected"]).inc(),
+            Arrival::Dropped => ROLLUP_LATE_FLOWS.with_label_values(&["dropped"]).inc(),
+        }
+    }
+}
+
+impl<S> Handler<InitRollupFlusher> for AggregationActor<S>
+where
+    S: RollupStorage + Unpin,
+{
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: InitRollupFlusher, _ctx: &mut Self::Context) -> Self::Result {
+        let mut interval = interval(Duration::from_secs(self.settings.window_secs.max(1)));
+        let storage = self.storage.clone();
+        let aggregator = self.aggregator.clone();
+        let table = self.settings.table.clone();
+
+        Box::pin(async move {
+            loop {
+                interval.tick().await;
+                let now = Utc::now().timestamp_millis() as u64;
+                let rollups = aggregator.lock().unwrap().drain_closed(now);
+
+                if rollups.is_empty() {
+                    continue;
+                }
+
+                let rollups_len = rollups.len();
+                match storage.stash_rollups(&table, rollups).await {
+                    Ok(()) => info!("saved {} rollups", rollups_len),
+                    Err(e) => error!("failed to save {} rollups: {}", rollups_len, e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorded {
+        fail: bool,
+        saved: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl RollupStorage for Recorded {
+        async fn stash_rollups(
+            &self,
+            _table: &str,
+            rollups: Vec<Rollup>,
+        ) -> Result<(), Box<dyn Error>> {
+            if self.fail {
+                return Err("unavailable".into());
+            }
+            *self.saved.lock().unwrap() += rollups.len();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rollups_are_saved_to_every_destination() {
+        let failing = Arc::new(Recorded {
+            fail: true,
+            ..Default::default()
+        });
+        let working = Arc::new(Recorded::default());
+        let destinations: Vec<Arc<dyn RollupStorage + Send + Sync>> =
+            vec![failing, working.clone()];
+        let destinations = RollupDestinations::new(destinations);
+
+        let rollups = vec![Rollup {
+            window_start: 0,
+            key_kind: Default::default(),
+            key: Default::default(),
+            counters: Default::default(),
+        }];
+
+        let saved = destinations.stash_rollups("flow_rollups", rollups).await;
+        assert_eq!(saved.unwrap_err().to_string(), "unavailable");
+        assert_eq!(*working.saved.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Fields flows are grouped by in rollups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregationKey {
+    #[default]
+    Host,
+    SrcDst,
+    DstPort,
+    Protocol,
+}
+
+impl AggregationKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregationKey::Host => "host",
+            AggregationKey::SrcDst => "src_dst",
+            AggregationKey::DstPort => "dst_port",
+            AggregationKey::Protocol => "protocol",
+        }
+    }
+
+    /// Fields which are not part of the key are left empty
+    pub fn extract(&self, f: &FlowMessageWithMetadata) -> RollupKey {
+        match self {
+            AggregationKey::Host => RollupKey {
+                host: f.metadata.host.clone(),
+                ..Default::default()
+            },
+            AggregationKey::SrcDst => RollupKey {
+                src_addr: f.flow_message.ipv4_src_addr.clone(),
+                dst_addr: f.flow_message.ipv4_dst_addr.clone(),
+                ..Default::default()
+            },
+            AggregationKey::DstPort => RollupKey {
+                dst_port: f.flow_message.l4_dst_port,
+                ..Default::default()
+            },
+            AggregationKey::Protocol => RollupKey {
+                protocol: f.flow_message.protocol,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RollupKey {
+    pub host: String,
+    pub src_addr: String,
+    pub dst_addr: String,
+    pub dst_port: u32,
+    pub protocol: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RollupCounters {
+    pub flows: u64,
+    pub malicious_flows: u64,
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+    pub in_pkts: u64,
+    pub out_pkts: u64,
+}
+
+impl RollupCounters {
+    fn add(&mut self, f: &FlowMessageWithMetadata) {
+        self.flows += 1;
+        self.malicious_flows += u64::from(f.malicious.unwrap_or(false));
+        self.in_bytes += f.flow_message.in_bytes;
+        self.out_bytes += f.flow_message.out_bytes;
+        self.in_pkts += f.flow_message.in_pkts;
+        self.out_pkts += f.flow_message.out_pkts;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rollup {
+    pub window_start: u64,
+    pub key_kind: AggregationKey,
+    pub key: RollupKey,
+    pub counters: RollupCounters,
+}
+
+/// How a flow arrived relative to its window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// its window was not saved yet
+    OnTime,
+    /// its window was already saved, the flow goes to a correction row of the window
+    Late,
+    /// its window was saved longer than the allowed lateness ago, the flow is not aggregated
+    Dropped,
+}
+
+/// Tumbling window aggregation of flows by their timestamp (in milliseconds).
+///
+/// A window `[start, start + window)` is closed and its rollups are saved by the first drain
+/// at or after its end. Flows of a closed window arriving within `allowed_lateness` of its end,
+/// as seen by the latest drain, are still aggregated and saved by the next drain as correction
+/// rows with the same window start, which the rollups table sums up with the rows saved before.
+/// Later flows are dropped
+pub struct WindowAggregator {
+    window: u64,
+    allowed_lateness: u64,
+    key: AggregationKey,
+    windows: HashMap<(u64, RollupKey), RollupCounters>,
+    /// time of the latest drain, windows which ended before it are closed
+    watermark: u64,
+}
+
+impl WindowAggregator {
+    pub fn new(window_ms: u64, key: AggregationKey) -> Self {
+        Self {
+            window: window_ms.max(1),
+            allowed_lateness: 0,
+            key,
+            windows: HashMap::new(),
+            watermark: 0,
+        }
+    }
+
+    pub fn with_allowed_lateness(mut self, allowed_lateness_ms: u64) -> Self {
+        self.allowed_lateness = allowed_lateness_ms;
+        self
+    }
+
+    fn window_start(&self, ts: u64) -> u64 {
+        ts - ts % self.window
+    }
+
+    pub fn add(&mut self, f: &FlowMessageWithMetadata) -> Arrival {
+        let window_start = self.window_start(f.metadata.timestamp);
+        let window_end = window_start + self.window;
+        let arrival = if window_end > self.watermark {
+            Arrival::OnTime
+        } else if window_end + self.allowed_lateness > self.watermark {
+            Arrival::Late
+        } else {
+            return Arrival::Dropped;
+        };
+
+        self.windows
+            .entry((window_start, self.key.extract(f)))
+            .or_default()
+            .add(f);
+        arrival
+    }
+
+    /// Removes and returns rollups of windows which ended before `now`, including
+    /// corrections of windows closed by previous drains
+    pub fn drain_closed(&mut self, now: u64) -> Vec<Rollup> {
+        let window = self.window;
+        let key_kind = self.key;
+        self.watermark = self.watermark.max(now);
+
+        let closed = self
+            .windows
+            .keys()
+            .filter(|(start, _)| start + window <= now)
+            .cloned()
+            .collect::<Vec<(u64, RollupKey)>>();
+
+        let mut rollups = closed
+            .into_iter()
+            .filter_map(|k| self.windows.remove_entry(&k))
+            .map(|((window_start, key), counters)| Rollup {
+                window_start,
+                key_kind,
+                key,
+                counters,
+            })
+            .collect::<Vec<Rollup>>();
+        rollups.sort_by_key(|r| r.window_start);
+        rollups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_aggregation_by_host() {
+        let mut agg = WindowAggregator::new(1000, AggregationKey::Host);
+        let flow = testutil::flow().with_traffic((100, 10), (2, 1));
+        agg.add(&flow.clone().with_host("a").with_timestamp(1000).build());
+        agg.add(
+            &flow
+                .clone()
+                .with_host("a")
+                .with_timestamp(1999)
+                .with_malicious(Some(true))
+                .build(),
+        );
+        agg.add(&flow.with_host("b").with_timestamp(1500).build());
+
+        let mut rollups = agg.drain_closed(2000);
+        rollups.sort_by(|a, b| a.key.host.cmp(&b.key.host));
+
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].window_start, 1000);
+        assert_eq!(rollups[0].key.host, "a");
+        assert_eq!(
+            rollups[0].counters,
+            RollupCounters {
+                flows: 2,
+                malicious_flows: 1,
+                in_bytes: 200,
+                out_bytes: 20,
+                in_pkts: 4,
+                out_pkts: 2,
+            }
+        );
+        assert_eq!(rollups[1].key.host, "b");
+        assert_eq!(rollups[1].counters.flows, 1);
+    }
+
+    #[test]
+    fn test_key_fields_outside_of_key_are_empty() {
+        let f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_dst_port(53)
+            .with_protocol(6)
+            .build();
+        assert_eq!(
+            AggregationKey::DstPort.extract(&f),
+            RollupKey {
+                dst_port: 53,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            AggregationKey::SrcDst.extract(&f),
+            RollupKey {
+                src_addr: "10.0.0.2".to_owned(),
+                dst_addr: "10.0.0.1".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            AggregationKey::Protocol.extract(&f),
+            RollupKey {
+                protocol: 6,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_open_windows_are_kept() {
+        let mut agg = WindowAggregator::new(1000, AggregationKey::DstPort);
+        agg.add(
+            &testutil::flow()
+                .with_dst_port(53)
+                .with_timestamp(500)
+                .build(),
+        );
+        agg.add(
+            &testutil::flow()
+                .with_dst_port(53)
+                .with_timestamp(1500)
+                .build(),
+        );
+
+        let rollups = agg.drain_closed(1999);
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].window_start, 0);
+        assert_eq!(rollups[0].key_kind, AggregationKey::DstPort);
+
+        let rollups = agg.drain_closed(2000);
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].window_start, 1000);
+        assert!(agg.drain_closed(10_000).is_empty());
+    }
+
+    #[test]
+    fn test_late_flows_within_allowed_lateness_are_corrected() {
+        let mut agg = WindowAggregator::new(1000, AggregationKey::Host).with_allowed_lateness(500);
+        assert_eq!(
+            agg.add(&testutil::flow().with_host("a").with_timestamp(1200).build()),
+            Arrival::OnTime
+        );
+        // window 1000-2000 is closed and saved
+        let rollups = agg.drain_closed(2100);
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].counters.flows, 1);
+
+        // window 2000-3000 is still open
+        assert_eq!(
+            agg.add(&testutil::flow().with_host("a").with_timestamp(2050).build()),
+            Arrival::OnTime
+        );
+        // window 1000-2000 ended 100ms before the latest drain
+        assert_eq!(
+            agg.add(
+                &testutil::flow()
+                    .with_host("a")
+                    .with_timestamp(1900)
+                    .with_malicious(Some(true))
+                    .build()
+            ),
+            Arrival::Late
+        );
+        assert_eq!(
+            agg.add(&testutil::flow().with_host("a").with_timestamp(1300).build()),
+            Arrival::Late
+        );
+
+        let corrections = agg.drain_closed(2400);
+        assert_eq!(corrections.len(), 1);
+        assert_eq!(corrections[0].window_start, 1000);
+        assert_eq!(corrections[0].counters.flows, 2);
+        assert_eq!(corrections[0].counters.malicious_flows, 1);
+
+        // window 1000-2000 ended 500ms before the latest drain
+        let rollups = agg.drain_closed(2500);
+        assert!(rollups.is_empty());
+        assert_eq!(
+            agg.add(&testutil::flow().with_host("a").with_timestamp(1999).build()),
+            Arrival::Dropped
+        );
+        assert!(agg.drain_closed(2600).is_empty());
+    }
+
+    #[test]
+    fn test_late_flows_are_dropped_without_allowed_lateness() {
+        let mut agg = WindowAggregator::new(1000, AggregationKey::Host);
+        agg.drain_closed(2000);
+
+        assert_eq!(
+            agg.add(&testutil::flow().with_host("a").with_timestamp(1999).build()),
+            Arrival::Dropped
+        );
+        assert_eq!(
+            agg.add(&testutil::flow().with_host("a").with_timestamp(2000).build()),
+            Arrival::OnTime
+        );
+        assert_eq!(agg.drain_closed(3000).len(), 1);
+    }
+}
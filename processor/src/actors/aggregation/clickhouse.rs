@@ -0,0 +1,43 @@
+use super::aggregation_actor::RollupStorage;
+use super::aggregator::Rollup;
+
+use crate::actors::storage::clickhouse::ClickhouseState;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clickhouse_rs::{row, types::Block};
+use std::error::Error;
+
+#[async_trait]
+impl RollupStorage for ClickhouseState {
+    async fn stash_rollups(&self, table: &str, rollups: Vec<Rollup>) -> Result<(), Box<dyn Error>> {
+        let mut block = Block::with_capacity(rollups.len());
+
+        for r in rollups.iter() {
+            let window_start = DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp((r.window_start / 1000) as i64, 0),
+                Utc,
+            );
+
+            block.push(row! {
+                window_start: window_start,
+                key_kind: r.key_kind.as_str(),
+                host: r.key.host.as_str(),
+                src_addr: r.key.src_addr.as_str(),
+                dst_addr: r.key.dst_addr.as_str(),
+                dst_port: r.key.dst_port,
+                protocol: r.key.protocol,
+                flows: r.counters.flows,
+                malicious_flows: r.counters.malicious_flows,
+                in_bytes: r.counters.in_bytes,
+                out_bytes: r.counters.out_bytes,
+                in_pkts: r.counters.in_pkts,
+                out_pkts: r.counters.out_pkts
+            })?;
+        }
+
+        let mut client = self.pool.get_handle().await?;
+        client.insert(table, block).await?;
+        Ok(())
+    }
+}
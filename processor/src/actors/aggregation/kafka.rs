@@ -0,0 +1,172 @@
+use super::aggregation_actor::RollupStorage;
+use super::aggregator::Rollup;
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::error;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+
+/// Rollup as published to kafka, with the columns of the rollups table.
+/// Encoded as a json object with fields in declaration order, so equal rollups encode to
+/// equal bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollupRecord {
+    /// start of the window in milliseconds since the epoch
+    pub window_start: u64,
+    pub key_kind: String,
+    pub host: String,
+    pub src_addr: String,
+    pub dst_addr: String,
+    pub dst_port: u32,
+    pub protocol: u32,
+    pub flows: u64,
+    pub malicious_flows: u64,
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+    pub in_pkts: u64,
+    pub out_pkts: u64,
+}
+
+impl From<&Rollup> for RollupRecord {
+    fn from(r: &Rollup) -> Self {
+        Self {
+            window_start: r.window_start,
+            key_kind: r.key_kind.as_str().to_owned(),
+            host: r.key.host.clone(),
+            src_addr: r.key.src_addr.clone(),
+            dst_addr: r.key.dst_addr.clone(),
+            dst_port: r.key.dst_port,
+            protocol: r.key.protocol,
+            flows: r.counters.flows,
+            malicious_flows: r.counters.malicious_flows,
+            in_bytes: r.counters.in_bytes,
+            out_bytes: r.counters.out_bytes,
+            in_pkts: r.counters.in_pkts,
+            out_pkts: r.counters.out_pkts,
+        }
+    }
+}
+
+impl RollupRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("rollup record is always serializable")
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(payload)
+    }
+
+    /// Rollups of the same key, correction rows included, land in the same partition
+    pub fn key(&self) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}",
+            self.key_kind, self.host, self.src_addr, self.dst_addr, self.dst_port, self.protocol
+        )
+    }
+}
+
+/// Publishes rollups to a kafka topic instead of a table
+pub struct KafkaRollupSink {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaRollupSink {
+    pub fn new(producer: FutureProducer, topic: String) -> Self {
+        Self { producer, topic }
+    }
+}
+
+#[async_trait]
+impl RollupStorage for KafkaRollupSink {
+    async fn stash_rollups(
+        &self,
+        _table: &str,
+        rollups: Vec<Rollup>,
+    ) -> Result<(), Box<dyn Error>> {
+        let records = rollups
+            .iter()
+            .map(|r| {
+                let record = RollupRecord::from(r);
+                (record.key(), record.encode())
+            })
+            .collect::<Vec<(String, Vec<u8>)>>();
+
+        let deliveries = records.iter().map(|(key, payload)| {
+            self.producer.send(
+                FutureRecord::to(&self.topic).key(key).payload(payload),
+                Duration::from_secs(0),
+            )
+        });
+        let failed = join_all(deliveries)
+            .await
+            .into_iter()
+            .filter(|d| match d {
+                Ok(_) => false,
+                Err((e, _)) => {
+                    error!("unable to publish rollup to {}: {}", self.topic, e);
+                    true
+                }
+            })
+            .count();
+
+        match failed {
+            0 => Ok(()),
+            failed => Err(format!(
+                "{} of {} rollups were not published to {}",
+                failed,
+                records.len(),
+                self.topic
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::aggregation::{AggregationKey, RollupCounters, RollupKey};
+
+    fn rollup() -> Rollup {
+        Rollup {
+            window_start: 1_669_024_800_000,
+            key_kind: AggregationKey::SrcDst,
+            key: RollupKey {
+                src_addr: "10.0.0.2".to_owned(),
+                dst_addr: "10.0.0.1".to_owned(),
+                ..Default::default()
+            },
+            counters: RollupCounters {
+                flows: 2,
+                malicious_flows: 1,
+                in_bytes: 300,
+                out_bytes: 40,
+                in_pkts: 3,
+                out_pkts: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn test_record_encodes_deterministically() {
+        let record = RollupRecord::from(&rollup());
+        assert_eq!(
+            String::from_utf8(record.encode()).unwrap(),
+            r#"{"window_start":1669024800000,"key_kind":"src_dst","host":"","src_addr":"10.0.0.2","dst_addr":"10.0.0.1","dst_port":0,"protocol":0,"flows":2,"malicious_flows":1,"in_bytes":300,"out_bytes":40,"in_pkts":3,"out_pkts":1}"#
+        );
+        assert_eq!(record.encode(), RollupRecord::from(&rollup()).encode());
+        assert_eq!(RollupRecord::decode(&record.encode()).unwrap(), record);
+    }
+
+    #[test]
+    fn test_record_key_is_the_rollup_key() {
+        assert_eq!(
+            RollupRecord::from(&rollup()).key(),
+            "src_dst||10.0.0.2|10.0.0.1|0|0"
+        );
+    }
+}
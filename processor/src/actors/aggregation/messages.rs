@@ -0,0 +1,5 @@
+use actix::Message;
+
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct InitRollupFlusher;
@@ -0,0 +1,11 @@
+pub mod aggregation_actor;
+pub mod aggregator;
+pub mod clickhouse;
+pub mod kafka;
+pub mod messages;
+
+pub use aggregation_actor::{AggregationActor, RollupDestinations, RollupStorage};
+pub use aggregator::{
+    AggregationKey, Arrival, Rollup, RollupCounters, RollupKey, WindowAggregator,
+};
+pub use kafka::{KafkaRollupSink, RollupRecord};
@@ -1,27 +1,122 @@
-use crate::actors::messages::{ClassifyFlowMessageWithMetadata, PersistFlowMessageWithMetadata};
+use crate::actors::messages::{
+    AckMessage, ClassifyFlowMessageWithMetadata, PersistFlowMessageWithMetadata,
+};
 use crate::actors::BrokerType;
+use crate::enrich::Enrichment;
+use crate::scan_detection::ScanDetection;
 use crate::{
     actors::messages::FlowMessageWithMetadata,
     pb::{flow_message_classifier_client::FlowMessageClassifierClient, FlowMessage},
 };
 use actix::ResponseFuture;
+use common::sampled_debug;
 
 use actix::Actor;
 use actix::Context;
 use actix::Handler;
 use actix_broker::{Broker, BrokerIssue, BrokerSubscribe};
+use async_trait::async_trait;
 use log::error;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 use tokio_stream::{Stream, StreamExt};
 use tonic::transport::Channel;
 
 use super::super::consts::MAILBOX_CAPACITY;
-use log::{debug, info};
+use log::info;
+
+/// Verdict of the classification server on a single flow
+#[async_trait]
+pub trait Classify: Clone + Send + 'static {
+    async fn verdict(&mut self, flow: FlowMessage) -> Result<bool, tonic::Status>;
+}
 
-pub struct ClassificationActor {
-    pub client: FlowMessageClassifierClient<Channel>,
+#[async_trait]
+impl Classify for FlowMessageClassifierClient<Channel> {
+    async fn verdict(&mut self, flow: FlowMessage) -> Result<bool, tonic::Status> {
+        self.classify(flow).await.map(|r| r.get_ref().malicious)
+    }
 }
 
-impl Actor for ClassificationActor {
+/// Topic and partition flows are consumed from
+type PartitionKey = (Option<String>, Option<i32>);
+
+pub struct ClassificationActor<C = FlowMessageClassifierClient<Channel>> {
+    client: C,
+    /// run on classified flows, so stages can use the classification
+    enrichment: Arc<Enrichment>,
+    /// run on enriched flows, so addresses are oriented by the direction stage
+    scan_detection: Arc<ScanDetection>,
+    /// with `preserve_order`, flows of every partition are queued to a task of their own which
+    /// classifies them one at a time, otherwise each flow is classified as soon as it arrives
+    partitions: Option<HashMap<PartitionKey, mpsc::UnboundedSender<FlowMessageWithMetadata>>>,
+}
+
+impl<C: Classify> ClassificationActor<C> {
+    pub fn new(client: C, enrichment: Arc<Enrichment>, scan_detection: Arc<ScanDetection>) -> Self {
+        Self {
+            client,
+            enrichment,
+            scan_detection,
+            partitions: None,
+        }
+    }
+
+    /// Flows of a partition reach storage in the order they were consumed, at the cost of
+    /// waiting for the classification of every flow before the next one of the partition
+    pub fn with_preserved_order(mut self, preserve_order: bool) -> Self {
+        self.partitions = preserve_order.then(HashMap::new);
+        self
+    }
+}
+
+/// Classifies queued flows one at a time, in the order they were queued
+fn partition_queue<C: Classify>(
+    client: C,
+    enrichment: Arc<Enrichment>,
+    scan_detection: Arc<ScanDetection>,
+) -> mpsc::UnboundedSender<FlowMessageWithMetadata> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    actix::spawn(async move {
+        while let Some(flow) = rx.recv().await {
+            classify(
+                client.clone(),
+                enrichment.clone(),
+                scan_detection.clone(),
+                flow,
+            )
+            .await;
+        }
+    });
+    tx
+}
+
+/// Classifies, enriches and scores the flow, then passes it on to storage
+async fn classify<C: Classify>(
+    mut client: C,
+    enrichment: Arc<Enrichment>,
+    scan_detection: Arc<ScanDetection>,
+    mut flow: FlowMessageWithMetadata,
+) {
+    match client.verdict(flow.flow_message.clone()).await {
+        Ok(malicious) => {
+            flow.malicious = Some(malicious);
+
+            let mut msg = PersistFlowMessageWithMetadata::from(flow);
+            enrichment.apply(&mut msg).await;
+            scan_detection.apply(&mut msg.0);
+            Broker::<BrokerType>::issue_async(msg);
+        }
+        Err(e) => {
+            // sent to retry, so the message is acknowledged and classified again later
+            error!("Classify response: {:?}", e);
+            Broker::<BrokerType>::issue_async(AckMessage::NackRetry(flow));
+        }
+    }
+}
+
+impl<C: Classify + Unpin> Actor for ClassificationActor<C> {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
@@ -31,7 +126,7 @@ impl Actor for ClassificationActor {
     }
 }
 
-impl Handler<ClassifyFlowMessageWithMetadata> for ClassificationActor {
+impl<C: Classify + Unpin> Handler<ClassifyFlowMessageWithMetadata> for ClassificationActor<C> {
     type Result = ResponseFuture<()>;
 
     fn handle(
@@ -39,22 +134,33 @@ impl Handler<ClassifyFlowMessageWithMetadata> for ClassificationActor {
         msg: ClassifyFlowMessageWithMetadata,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let mut client = self.client.clone();
-        let mut msg = msg;
+        sampled_debug!(target: "events_to_classify", "Got event: {}", msg.0.metadata.offset.unwrap());
 
-        debug!(target: "events_to_classify", "Got event: {}", msg.0.metadata.offset.unwrap());
-        Box::pin(async move {
-            match client.classify(msg.0.flow_message.clone()).await {
-                Ok(b) => {
-                    msg.0.malicious = Some(b.get_ref().malicious);
-
-                    Broker::<BrokerType>::issue_async::<PersistFlowMessageWithMetadata>(msg.into());
-                }
-                Err(e) => {
-                    error!("Classify response: {:?}", e);
+        let flow = match &mut self.partitions {
+            None => msg.0,
+            Some(partitions) => {
+                let key = (msg.0.metadata.topic.clone(), msg.0.metadata.partition);
+                let queue = partitions.entry(key).or_insert_with(|| {
+                    partition_queue(
+                        self.client.clone(),
+                        self.enrichment.clone(),
+                        self.scan_detection.clone(),
+                    )
+                });
+                // queued behind earlier flows of the partition
+                match queue.send(msg.0) {
+                    Ok(()) => return Box::pin(async {}),
+                    Err(mpsc::error::SendError(flow)) => flow,
                 }
             }
-        })
+        };
+
+        Box::pin(classify(
+            self.client.clone(),
+            self.enrichment.clone(),
+            self.scan_detection.clone(),
+            flow,
+        ))
     }
 }
 
@@ -107,3 +213,112 @@ pub async fn streaming_classifier(
         tx_result.send(message).await.unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::storage::storage_actor::{
+        AStorage, InsertResult, StorageActor, StorageError,
+    };
+    use crate::settings::StorageSettings;
+    use crate::testutil;
+    use std::sync::Mutex;
+    use tokio::sync::Mutex as TokioMtx;
+    use tokio::time::{sleep, timeout, Duration};
+
+    const FLOWS: i64 = 20;
+
+    /// Answers later flows sooner, so flows classified at once reach storage in reverse
+    #[derive(Clone)]
+    struct SlowerForEarlierFlows;
+
+    #[async_trait]
+    impl Classify for SlowerForEarlierFlows {
+        async fn verdict(&mut self, flow: FlowMessage) -> Result<bool, tonic::Status> {
+            sleep(Duration::from_millis(2 * (FLOWS as u64 - flow.in_pkts))).await;
+            Ok(false)
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingStorage {
+        saved: Mutex<Vec<FlowMessageWithMetadata>>,
+    }
+
+    #[async_trait]
+    impl AStorage for RecordingStorage {
+        async fn stash(
+            &self,
+            msgs: Vec<FlowMessageWithMetadata>,
+        ) -> Result<InsertResult, StorageError> {
+            let inserted = msgs.len();
+            self.saved.lock().unwrap().extend(msgs);
+            Ok(InsertResult::new(vec![], inserted))
+        }
+    }
+
+    fn consumed(offset: i64) -> ClassifyFlowMessageWithMetadata {
+        ClassifyFlowMessageWithMetadata(
+            testutil::flow()
+                .with_partition((offset % 2) as i32)
+                .with_offset(offset)
+                .with_message(FlowMessage {
+                    in_pkts: offset as u64,
+                    ..Default::default()
+                })
+                .build(),
+        )
+    }
+
+    #[actix::test]
+    async fn test_preserved_order_stores_flows_of_a_partition_as_consumed() {
+        let storage = Arc::new(RecordingStorage::default());
+        let settings = StorageSettings {
+            preserve_order: true,
+            ..Default::default()
+        };
+        let storage_actor = StorageActor::new(
+            storage.clone(),
+            Arc::new(TokioMtx::new(crate::actors::broker::Broker)),
+            settings,
+        )
+        .start();
+        let classification = ClassificationActor::new(
+            SlowerForEarlierFlows,
+            Arc::new(Enrichment::default()),
+            Arc::new(ScanDetection::default()),
+        )
+        .with_preserved_order(true)
+        .start();
+        // subscribed once it handled its first message, drained it stores flows as they arrive
+        storage_actor
+            .send(crate::actors::storage::messages::DrainStorage)
+            .await
+            .unwrap();
+
+        for offset in 0..FLOWS {
+            classification.do_send(consumed(offset));
+        }
+        timeout(Duration::from_secs(5), async {
+            while storage.saved.lock().unwrap().len() < FLOWS as usize {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("every flow should be stored");
+
+        let saved = storage.saved.lock().unwrap();
+        for partition in [0, 1] {
+            let offsets = saved
+                .iter()
+                .filter(|f| f.metadata.partition == Some(partition))
+                .map(|f| f.metadata.offset.unwrap())
+                .collect::<Vec<i64>>();
+            let consumed = (0..FLOWS)
+                .filter(|offset| offset % 2 == partition as i64)
+                .collect::<Vec<i64>>();
+            assert_eq!(offsets, consumed);
+        }
+        assert!(saved.iter().all(|f| f.malicious == Some(false)));
+    }
+}
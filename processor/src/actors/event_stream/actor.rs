@@ -19,6 +19,8 @@ use crate::actors::broker::Broker;
 use crate::actors::messages::AckMessage;
 use crate::actors::messages::ClassifyFlowMessageWithMetadata;
 use crate::actors::messages::FlowMessageWithMetadata;
+use crate::dead_letter::{DeadLetter, DeadLetterReason};
+use common::sampled_debug;
 use log::*;
 
 pub struct EventStreamActor<T, R> {
@@ -79,7 +81,7 @@ where
     type Result = ResponseFuture<()>;
 
     fn handle(&mut self, msg: FlowMessageWithMetadata, _ctx: &mut Self::Context) -> Self::Result {
-        debug!(target: "consumed_events", "Got message {}", msg.metadata.offset.unwrap());
+        sampled_debug!(target: "consumed_events", "Got message {}", msg.metadata.offset.unwrap());
         let broker = self.broker.clone();
         Box::pin(async move {
             broker
@@ -176,23 +178,57 @@ where
 
         Box::pin(async move {
             match msg {
-                AckMessage::Ack(offset, partition) => {
-                    processor.ack(offset, partition);
+                AckMessage::Ack(topic, offset, partition) => {
+                    processor.ack(&topic, offset, partition);
                 }
                 AckMessage::NackRetry(mut msg) => {
-                    debug!(
+                    sampled_debug!(
                         "Failed to process message with id: {} after {} try",
-                        msg.metadata.id, msg.metadata.retry
+                        msg.metadata.id,
+                        msg.metadata.retry
                     );
                     msg.metadata.retry += 1;
 
-                    if let Some(t) = &retrier.get_topic_based_on_retry(msg.metadata.retry) {
-                        processor
-                            .produce(t, retrier.get_brokers_retry(), &msg)
-                            .await;
-                        let offst = msg.metadata.offset.unwrap();
-                        processor.ack(offst, msg.metadata.partition.unwrap());
-                    }
+                    // the topic after the last retry tier is the dead letter one, messages
+                    // retried more often than there are tiers end up there as well
+                    let t = retrier
+                        .get_topic_based_on_retry(msg.metadata.retry)
+                        .unwrap_or_else(|| retrier.get_dead_letter_topic());
+                    let dead_letter = (t == retrier.get_dead_letter_topic()).then(|| {
+                        DeadLetter::new(
+                            DeadLetterReason::StorageFailed,
+                            format!("not stored after {} attempts", msg.metadata.retry),
+                        )
+                    });
+                    processor
+                        .produce(&t, retrier.get_brokers_retry(), &msg, dead_letter.as_ref())
+                        .await;
+                    let offst = msg.metadata.offset.unwrap();
+                    processor.ack(
+                        msg.metadata.topic.as_ref().unwrap(),
+                        offst,
+                        msg.metadata.partition.unwrap(),
+                    );
+                }
+                AckMessage::NackDeadLetter(msg, dead_letter) => {
+                    sampled_debug!(
+                        "Message with id: {} can not be stored, moving it to dead letter topic: {}",
+                        msg.metadata.id,
+                        dead_letter
+                    );
+                    processor
+                        .produce(
+                            &retrier.get_dead_letter_topic(),
+                            retrier.get_brokers_retry(),
+                            &msg,
+                            Some(&dead_letter),
+                        )
+                        .await;
+                    processor.ack(
+                        msg.metadata.topic.as_ref().unwrap(),
+                        msg.metadata.offset.unwrap(),
+                        msg.metadata.partition.unwrap(),
+                    );
                 }
             }
         })
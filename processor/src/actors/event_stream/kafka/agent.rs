@@ -1,68 +1,479 @@
 use super::super::Transport;
+use super::avro::{AvroDecoder, AvroError, PayloadCodec};
+use super::checksum::{ChecksumError, PayloadChecksum};
+use super::commits::OffsetCommits;
+use super::compression::PayloadCompression;
+use super::consts::{
+    CONSUMER_GROUP_ID, DEAD_LETTER_RETRY_MAX_DELAY_IN_SECS,
+    SCHEMA_REGISTRY_RETRY_MAX_DELAY_IN_SECS, WATCHDOG_BEAT_INTERVAL_IN_SECS,
+};
 use super::context::CustomContext;
-use super::get_consumer;
+use super::decode_format::{decode_json, DecodeFormat, DecodeFormatError, PayloadFormat};
+use super::get_consumer_with_context;
 use super::get_producer;
+use super::lag::monitor_lag;
 
+use super::messages::{
+    checksum_header, codec_header, compression_header, content_type_header, schema_version_header,
+    with_dead_letter, with_source_topic,
+};
 use super::offset_guard::ConsumerOffsetGuard;
+use super::payload_dump::PayloadDumper;
+use super::schema_version::{self, SchemaDecoders};
 
 use crate::actors::broker::Broker;
 
 use crate::actors::messages::{FlowMessageMetadata, FlowMessageWithMetadata};
+use crate::allowlist::SourceAllowlist;
+use crate::clock_skew::SkewOutcome;
+use crate::dead_letter::{DeadLetter, DeadLetterReason};
+use crate::dead_letter_cap::DeadLetterCap;
+use crate::health::Watchdog;
+use crate::keys::{self, KeyField};
+use crate::metrics::{
+    CORRUPTED_MESSAGES, DEAD_LETTER_REASONS, FUTURE_TIMESTAMPS, REJECTED_SOURCE_MESSAGES,
+};
 use crate::pb::FlowMessage;
+use crate::sampler::FlowSampler;
+use crate::settings::{ClockSkewSettings, ConsumerSettings};
+use crate::shutdown::{Consumption, Signal};
+use common::host_normalization::HostNormalization;
+use common::sampled_debug;
 
 use tokio::sync::mpsc;
 
 use async_trait::async_trait;
-use log::{debug, error, info, warn};
+use chrono::Utc;
+use log::{error, info, warn};
 use prost::Message as PBMessage;
-use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::error::KafkaResult;
 use rdkafka::message::{Message, OwnedHeaders, OwnedMessage};
 use rdkafka::producer::FutureProducer;
 use rdkafka::producer::FutureRecord;
+use rdkafka::{Offset, TopicPartitionList};
 use tokio::sync::Mutex as TokioMtx;
 
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, timeout, Duration};
+
+/// Avro payloads the registry has no flow schema for are unsupported, broken ones undecodable
+fn avro_reason(e: &AvroError) -> DeadLetterReason {
+    match e {
+        AvroError::Frame(_) | AvroError::Decode(_) => DeadLetterReason::DecodeError,
+        AvroError::UnknownCodec(_)
+        | AvroError::Registry(_)
+        | AvroError::RegistryNotConfigured
+        | AvroError::UnexpectedSchema(_) => DeadLetterReason::SchemaUnsupported,
+    }
+}
+
+/// Payloads of a format the processor does not know are unsupported, ambiguous ones undecodable
+fn decode_format_reason(e: &DecodeFormatError) -> DeadLetterReason {
+    match e {
+        DecodeFormatError::Codec(e) => avro_reason(e),
+        DecodeFormatError::UnknownContentType(_) => DeadLetterReason::SchemaUnsupported,
+        DecodeFormatError::Ambiguous => DeadLetterReason::DecodeError,
+    }
+}
+
+/// What happened to a consumed message
+#[derive(Debug, PartialEq, Eq)]
+enum Handover {
+    /// sent to classification, it is acknowledged once saved or moved to a retry topic
+    Sent,
+    /// could not be decoded and was skipped or moved to the dead letter topic
+    Skipped,
+    /// consumption stopped before it could be handed over, it is consumed again after restart
+    Stopped,
+}
 
 pub struct KafkaProcessingAgent {
     producer: FutureProducer,
     consumer: Arc<StreamConsumer<CustomContext>>,
     consumer_guard: ConsumerOffsetGuard,
+    payload_dumper: PayloadDumper,
+    /// offsets committed in batches, `None` with auto commit. Shared with the consumer context,
+    /// which marks them committed and forgets revoked partitions
+    commits: Option<Arc<Mutex<OffsetCommits>>>,
+    commit_interval: Duration,
+    lag_poll_interval: Duration,
+    dead_letter_topic: String,
+    sampler: Arc<FlowSampler>,
+    avro_decoder: Option<AvroDecoder>,
+    decode_format: DecodeFormat,
+    /// protobuf decoders by the schema version of the payload
+    schema_decoders: SchemaDecoders,
+    watchdog: Arc<Watchdog>,
+    message_key: Option<KeyField>,
+    allowlist: SourceAllowlist,
+    host_normalization: HostNormalization,
+    clock_skew: ClockSkewSettings,
+    dead_letter_cap: Arc<DeadLetterCap>,
+    /// consumed messages which were not acknowledged yet
+    in_flight: AtomicUsize,
+    stop: Signal,
+    stopped: Signal,
 }
 
 impl KafkaProcessingAgent {
-    pub fn new(consumer_topic: &str, brokers: &str) -> Self {
+    pub fn new(
+        consumer_topics: &[String],
+        brokers: &str,
+        consumer_settings: &ConsumerSettings,
+        payload_dumper: PayloadDumper,
+        dead_letter_topic: &str,
+        sampler: Arc<FlowSampler>,
+        watchdog: Arc<Watchdog>,
+    ) -> Self {
         let producer = get_producer(brokers);
-        let consumer = get_consumer(brokers);
+        let commits = (!consumer_settings.enable_auto_commit).then(|| {
+            Arc::new(Mutex::new(OffsetCommits::new(
+                consumer_settings.commit_batch_size,
+            )))
+        });
+        let context = commits
+            .clone()
+            .map_or_else(CustomContext::default, CustomContext::with_commits);
+        let consumer =
+            get_consumer_with_context(brokers, CONSUMER_GROUP_ID, consumer_settings, context);
 
         consumer
-            .subscribe(&[consumer_topic])
-            .unwrap_or_else(|_| panic!("Unable to subscribe to topic {}", consumer_topic));
+            .subscribe(
+                &consumer_topics
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<&str>>(),
+            )
+            .unwrap_or_else(|_| panic!("Unable to subscribe to topics {:?}", consumer_topics));
 
         let assignment = consumer.assignment().unwrap();
         warn!("assignment = {assignment:?}");
         let consumer = Arc::new(consumer);
-        let consumer_guard = ConsumerOffsetGuard::new(consumer.clone(), consumer_topic);
+        let consumer_guard = ConsumerOffsetGuard::new(consumer.clone(), &consumer_topics.join(","));
         info!("created kafka processing agent");
 
         Self {
             producer,
             consumer,
             consumer_guard,
+            payload_dumper,
+            commits,
+            commit_interval: Duration::from_millis(consumer_settings.commit_interval_ms),
+            lag_poll_interval: Duration::from_secs(consumer_settings.lag_poll_interval_secs),
+            dead_letter_topic: dead_letter_topic.to_owned(),
+            sampler,
+            avro_decoder: consumer_settings
+                .schema_registry_url
+                .as_deref()
+                .map(AvroDecoder::new),
+            decode_format: consumer_settings.decode_format,
+            schema_decoders: SchemaDecoders::default(),
+            watchdog,
+            message_key: None,
+            allowlist: SourceAllowlist::default(),
+            host_normalization: HostNormalization::default(),
+            clock_skew: ClockSkewSettings::default(),
+            dead_letter_cap: Arc::new(DeadLetterCap::default()),
+            in_flight: AtomicUsize::new(0),
+            stop: Signal::default(),
+            stopped: Signal::default(),
         }
     }
 
-    async fn send_to_actor(msg: OwnedMessage, broker: &Arc<TokioMtx<Broker>>) {
+    /// Messages sent to retry topics are keyed like their rows are sharded, so they land deterministically
+    pub fn with_message_key(mut self, message_key: Option<KeyField>) -> Self {
+        self.message_key = message_key;
+        self
+    }
+
+    /// Host identifiers are normalized right after consumption, before any other check
+    pub fn with_host_normalization(mut self, host_normalization: HostNormalization) -> Self {
+        self.host_normalization = host_normalization;
+        self
+    }
+
+    /// Messages from hosts which are not allowed are dropped before they are decoded
+    pub fn with_allowlist(mut self, allowlist: SourceAllowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Flows timestamped too far in the future are clamped or dead lettered before they are decoded
+    pub fn with_clock_skew(mut self, clock_skew: ClockSkewSettings) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// Messages moved to the dead letter topic are counted against the cap, a fail-closed cap
+    /// stops consumption once it is exceeded
+    pub fn with_dead_letter_cap(mut self, dead_letter_cap: Arc<DeadLetterCap>) -> Self {
+        self.dead_letter_cap = dead_letter_cap;
+        self
+    }
+
+    /// Stops consuming new messages, waits until the message being processed is handed over
+    pub async fn stop(&self) {
+        self.stop.raise();
+        self.stopped.wait().await;
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn release_in_flight(&self) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+
+    /// Waits until every consumed message is saved or moved to a retry topic
+    pub async fn wait_until_acked(&self) {
+        while self.in_flight() > 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Commits offsets of acknowledged messages, so none of them is consumed again after restart
+    pub fn commit(&self) -> KafkaResult<()> {
+        match self.commits {
+            Some(_) => self.commit_acked(CommitMode::Sync),
+            None => self.consumer.commit_consumer_state(CommitMode::Sync),
+        }
+    }
+
+    /// Commits the highest offsets below which every message is acknowledged. Offsets of an
+    /// async commit are marked committed by the consumer context once the commit succeeds
+    fn commit_acked(&self, mode: CommitMode) -> KafkaResult<()> {
+        let commits = match &self.commits {
+            Some(commits) => commits,
+            None => return Ok(()),
+        };
+        let offsets = commits.lock().unwrap().start_commit();
+        if offsets.is_empty() {
+            return Ok(());
+        }
+
+        let mut tpl = TopicPartitionList::new();
+        for (topic, partition, offset) in offsets.iter() {
+            tpl.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+        }
+        self.consumer.commit(&tpl, mode)?;
+        if let CommitMode::Sync = mode {
+            commits.lock().unwrap().mark_committed(&offsets);
+        }
+        Ok(())
+    }
+
+    /// Offsets acknowledged in between batches are committed at least every interval
+    async fn commit_periodically(&self) {
+        if self.commits.is_none() {
+            return;
+        }
+        loop {
+            sleep(self.commit_interval).await;
+            if let Err(e) = self.commit_acked(CommitMode::Async) {
+                error!("unable to commit offsets: {}", e);
+            }
+        }
+    }
+
+    /// Counts a message moved to the dead letter topic against the cap and by its reason
+    fn dead_lettered(&self, dead_letter: &DeadLetter) {
+        self.dead_letter_cap.dead_lettered();
+        DEAD_LETTER_REASONS
+            .with_label_values(&[dead_letter.reason.as_str()])
+            .inc();
+    }
+
+    /// Moves a message which can not be processed to the dead letter topic as it is. It is
+    /// skipped only once produced, consumption is held back while the producer fails, so the
+    /// message is not acknowledged without a copy. `Stopped` once consumption is stopped
+    async fn dead_letter(&self, msg: &OwnedMessage, dead_letter: DeadLetter) -> Handover {
+        let headers = with_dead_letter(
+            msg.headers().cloned().unwrap_or_else(OwnedHeaders::new),
+            &dead_letter,
+        );
+        let max_delay = Duration::from_secs(DEAD_LETTER_RETRY_MAX_DELAY_IN_SECS);
+        let mut delay = Duration::from_secs(1);
+        loop {
+            let mut record =
+                FutureRecord::<(), [u8]>::to(&self.dead_letter_topic).headers(headers.clone());
+            if let Some(payload) = msg.payload() {
+                record = record.payload(payload);
+            }
+
+            match self.producer.send(record, Duration::from_secs(0)).await {
+                Ok(_) => {
+                    self.dead_lettered(&dead_letter);
+                    return Handover::Skipped;
+                }
+                Err((e, _)) => {
+                    error!(
+                        "unable to move message at offset {} of [{}] to dead letter topic, retrying in {:?}: {}",
+                        msg.offset(),
+                        msg.topic(),
+                        delay,
+                        e
+                    );
+                    self.watchdog.beat();
+                    tokio::select! {
+                        _ = sleep(delay) => {}
+                        _ = self.stop.wait() => return Handover::Stopped,
+                    }
+                    delay = (delay * 2).min(max_delay);
+                }
+            }
+        }
+    }
+
+    /// Waits for an unavailable schema registry rather than dead lettering the payload,
+    /// consumption is held back meanwhile. `None` once consumption is stopped
+    async fn decode_avro(&self, payload: &[u8]) -> Option<Result<FlowMessage, AvroError>> {
+        let decoder = match &self.avro_decoder {
+            Some(decoder) => decoder,
+            None => return Some(Err(AvroError::RegistryNotConfigured)),
+        };
+        let max_delay = Duration::from_secs(SCHEMA_REGISTRY_RETRY_MAX_DELAY_IN_SECS);
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match decoder.decode(payload).await {
+                Err(AvroError::Registry(e)) => {
+                    warn!(
+                        "schema registry is unavailable, retrying in {:?}: {}",
+                        delay, e
+                    );
+                    self.watchdog.beat();
+                    tokio::select! {
+                        _ = sleep(delay) => {}
+                        _ = self.stop.wait() => return None,
+                    }
+                    delay = (delay * 2).min(max_delay);
+                }
+                decoded => return Some(decoded),
+            }
+        }
+    }
+
+    async fn send_to_actor(&self, msg: OwnedMessage, broker: &Arc<TokioMtx<Broker>>) -> Handover {
         let hdrs = msg.headers().unwrap(); // TODO make headers as From<OwnedHeaders> for
                                            // FlowMessageMetadata
         let mut metadata: FlowMessageMetadata = hdrs.try_into().unwrap();
+        metadata.topic = Some(msg.topic().to_owned());
         metadata.offset = Some(msg.offset());
         metadata.partition = Some(msg.partition());
+        metadata.host = self.host_normalization.apply(&metadata.host);
+
+        if !self.allowlist.allows(&metadata.host) {
+            REJECTED_SOURCE_MESSAGES.inc();
+            sampled_debug!(
+                "dropping message {} from source {} which is not allowed",
+                metadata.id,
+                metadata.host
+            );
+            return Handover::Skipped;
+        }
+
+        let now = Utc::now().timestamp_millis() as u64;
+        match self.clock_skew.apply(&mut metadata, now) {
+            SkewOutcome::Accepted => {}
+            SkewOutcome::Clamped => {
+                FUTURE_TIMESTAMPS.with_label_values(&["clamped"]).inc();
+                sampled_debug!("clamped future timestamp of message {}", metadata.id);
+            }
+            SkewOutcome::Rejected => {
+                FUTURE_TIMESTAMPS.with_label_values(&["rejected"]).inc();
+                let reason = format!(
+                    "timestamp {} is more than {}ms ahead of {}",
+                    metadata.timestamp, self.clock_skew.max_clock_skew_ms, now
+                );
+                error!("rejecting message {}: {}", metadata.id, reason);
+                return self
+                    .dead_letter(
+                        &msg,
+                        DeadLetter::new(DeadLetterReason::ValidationFailed, reason),
+                    )
+                    .await;
+            }
+        }
+
+        let compression = PayloadCompression::from_header(compression_header(hdrs));
+        let checksum = PayloadChecksum::from_header(checksum_header(hdrs));
+        let version = schema_version::parse_version(schema_version_header(hdrs))
+            .and_then(|v| self.schema_decoders.decoder(v));
 
         match msg.payload_view::<[u8]>() {
             Some(Ok(f)) => {
-                let deserialized_msg: FlowMessage = PBMessage::decode::<&[u8]>(f).unwrap(); // TODO properly handle error
+                if let Err(e) = checksum.and_then(|c| c.map_or(Ok(()), |c| c.verify(f))) {
+                    error!("unable to verify message {}: {}", metadata.id, e);
+                    let reason = match e {
+                        ChecksumError::Mismatch { .. } => {
+                            CORRUPTED_MESSAGES.inc();
+                            DeadLetterReason::ChecksumMismatch
+                        }
+                        ChecksumError::Unknown(_) => DeadLetterReason::SchemaUnsupported,
+                    };
+                    return self.dead_letter(&msg, DeadLetter::new(reason, e)).await;
+                }
+
+                let f = match compression.and_then(|c| c.decompress(f)) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        error!("unable to decompress message {}: {}", metadata.id, e);
+                        return self
+                            .dead_letter(&msg, DeadLetter::new(DeadLetterReason::DecodeError, e))
+                            .await;
+                    }
+                };
+
+                let format =
+                    self.decode_format
+                        .detect(codec_header(hdrs), content_type_header(hdrs), &f);
+                let deserialized_msg: FlowMessage = match format {
+                    Ok(PayloadFormat::Codec(PayloadCodec::Protobuf)) => match version {
+                        Ok(decode) => match decode(f.as_ref()) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                self.payload_dumper.report(&metadata.id, &f, &e);
+                                let dead_letter = DeadLetter::new(DeadLetterReason::DecodeError, e);
+                                return self.dead_letter(&msg, dead_letter).await;
+                            }
+                        },
+                        Err(e) => {
+                            error!("unable to decode message {}: {}", metadata.id, e);
+                            let dead_letter =
+                                DeadLetter::new(DeadLetterReason::SchemaUnsupported, e);
+                            return self.dead_letter(&msg, dead_letter).await;
+                        }
+                    },
+                    Ok(PayloadFormat::Codec(PayloadCodec::Avro)) => {
+                        match self.decode_avro(&f).await {
+                            None => return Handover::Stopped,
+                            Some(Ok(m)) => m,
+                            Some(Err(e)) => {
+                                error!("unable to decode avro message {}: {}", metadata.id, e);
+                                let dead_letter = DeadLetter::new(avro_reason(&e), e);
+                                return self.dead_letter(&msg, dead_letter).await;
+                            }
+                        }
+                    }
+                    Ok(PayloadFormat::Json) => match decode_json(&f) {
+                        Ok(m) => m,
+                        Err(e) => {
+                            error!("unable to decode json message {}: {}", metadata.id, e);
+                            let dead_letter = DeadLetter::new(DeadLetterReason::DecodeError, e);
+                            return self.dead_letter(&msg, dead_letter).await;
+                        }
+                    },
+                    Err(e) => {
+                        error!("unable to decode message {}: {}", metadata.id, e);
+                        let reason = decode_format_reason(&e);
+                        return self.dead_letter(&msg, DeadLetter::new(reason, e)).await;
+                    }
+                };
 
                 let msg_with_metadata: FlowMessageWithMetadata = FlowMessageWithMetadata {
                     flow_message: deserialized_msg,
@@ -70,11 +481,13 @@ impl KafkaProcessingAgent {
                     metadata,
                 };
 
-                debug!(
+                sampled_debug!(
                     "Deserialized kafka event: {:?}",
                     msg_with_metadata.flow_message
                 );
+                self.sampler.offer(&msg_with_metadata);
                 broker.lock().await.issue_async(msg_with_metadata);
+                Handover::Sent
             }
             Some(Err(e)) => {
                 error!("Unable to decode kafka even into flow message: {:?}", e);
@@ -85,15 +498,59 @@ impl KafkaProcessingAgent {
     }
 }
 
+#[async_trait]
+impl Consumption for KafkaProcessingAgent {
+    async fn stop(&self) {
+        KafkaProcessingAgent::stop(self).await
+    }
+
+    fn in_flight(&self) -> usize {
+        KafkaProcessingAgent::in_flight(self)
+    }
+
+    async fn wait_until_acked(&self) {
+        KafkaProcessingAgent::wait_until_acked(self).await
+    }
+
+    fn commit(&self) -> KafkaResult<()> {
+        KafkaProcessingAgent::commit(self)
+    }
+}
+
 #[async_trait]
 impl Transport for KafkaProcessingAgent {
     async fn guard_acks(&self) {
-        self.consumer_guard.inc_offset(self.consumer.clone()).await
+        tokio::join!(
+            self.consumer_guard.inc_offset(self.consumer.clone()),
+            monitor_lag(self.consumer.clone(), self.lag_poll_interval),
+            self.commit_periodically()
+        );
     }
 
-    fn ack(&self, offset: i64, partition: i32) {
-        self.consumer_guard
-            .stash_processed_offset(&self.consumer, offset, partition)
+    fn ack(&self, topic: &str, offset: i64, partition: i32) {
+        self.release_in_flight();
+        let commits = match &self.commits {
+            Some(commits) => commits,
+            None => {
+                self.consumer_guard.stash_processed_offset(
+                    &self.consumer,
+                    topic,
+                    offset,
+                    partition,
+                );
+                return;
+            }
+        };
+
+        let batch_full = commits.lock().unwrap().acked(topic, partition, offset);
+        if batch_full {
+            if let Err(e) = self.commit_acked(CommitMode::Async) {
+                error!(
+                    "unable to commit offset {} of partition {}: {}",
+                    offset, partition, e
+                );
+            }
+        }
     }
 
     async fn consume(&self, broker: Arc<TokioMtx<Broker>>, mut notify_rx: mpsc::Receiver<usize>) {
@@ -102,28 +559,92 @@ impl Transport for KafkaProcessingAgent {
             self.consumer_guard.topic
         );
 
+        // waits are bounded, so the watchdog is beaten also while there is nothing to do
+        let beat_interval = Duration::from_secs(WATCHDOG_BEAT_INTERVAL_IN_SECS);
         let mut counter: usize = 0;
-        while let Some(capacity) = notify_rx.recv().await {
+        'consuming: while !self.stop.is_raised() {
+            self.watchdog.beat();
+            let capacity = tokio::select! {
+                capacity = timeout(beat_interval, notify_rx.recv()) => match capacity {
+                    Ok(Some(capacity)) => capacity,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                },
+                _ = self.stop.wait() => break,
+            };
+
             info!("Received capacity: {}", capacity);
             while counter < capacity {
-                let event = match self.consumer.recv().await {
-                    Ok(e) => e,
-                    Err(e) => {
-                        error!("Error: {}", e);
-                        sleep(Duration::from_secs(4)).await;
-                        continue;
+                self.watchdog.beat();
+                if self.dead_letter_cap.is_closed() {
+                    tokio::select! {
+                        _ = sleep(beat_interval) => continue,
+                        _ = self.stop.wait() => break 'consuming,
                     }
+                }
+                let event = tokio::select! {
+                    event = timeout(beat_interval, self.consumer.recv()) => match event {
+                        Ok(Ok(e)) => e,
+                        Ok(Err(e)) => {
+                            error!("Error: {}", e);
+                            sleep(Duration::from_secs(4)).await;
+                            continue;
+                        }
+                        Err(_) => continue,
+                    },
+                    _ = self.stop.wait() => break 'consuming,
                 };
 
-                Self::send_to_actor(event.detach(), &broker).await;
-                counter += 1;
+                let (offset, partition) = (event.offset(), event.partition());
+                let topic = event.topic().to_owned();
+                self.in_flight.fetch_add(1, Ordering::SeqCst);
+                self.dead_letter_cap.consumed();
+                if let Some(commits) = &self.commits {
+                    commits.lock().unwrap().consumed(&topic, partition, offset);
+                }
+                match self.send_to_actor(event.detach(), &broker).await {
+                    Handover::Sent => counter += 1,
+                    // nothing is going to acknowledge skipped message
+                    Handover::Skipped => self.ack(&topic, offset, partition),
+                    // left unacknowledged, so its offset is not committed
+                    Handover::Stopped => {
+                        self.release_in_flight();
+                        break 'consuming;
+                    }
+                }
             }
             counter = 0;
         }
+
+        info!(
+            "Stopped consuming messages from kafka [topic: {}]",
+            self.consumer_guard.topic
+        );
+        self.stopped.raise();
     }
 
-    async fn produce(&self, topic: &str, _brokers: &str, msg: &FlowMessageWithMetadata) {
+    async fn produce(
+        &self,
+        topic: &str,
+        _brokers: &str,
+        msg: &FlowMessageWithMetadata,
+        dead_letter: Option<&DeadLetter>,
+    ) {
+        let mut headers = with_source_topic(
+            OwnedHeaders::new()
+                .add("host-identifier-x", &msg.metadata.host)
+                .add("message-id-x", &msg.metadata.id)
+                .add("timestamp-x", &msg.metadata.timestamp.to_string())
+                .add("retry-x", &msg.metadata.retry.to_string())
+                .add("replay-x", &msg.metadata.replay.to_string()),
+            &msg.metadata,
+        );
+        if let Some(dead_letter) = dead_letter {
+            self.dead_lettered(dead_letter);
+            headers = with_dead_letter(headers, dead_letter);
+        }
         let mut buffer: Vec<u8> = Vec::with_capacity(4092);
+        let key = keys::message_key(self.message_key, msg);
 
         if let Err(e) = msg.flow_message.encode(&mut buffer) {
             error!("unable to encode message to proto buf format: {}", e);
@@ -135,14 +656,8 @@ impl Transport for KafkaProcessingAgent {
             .send(
                 FutureRecord::to(topic)
                     .payload(&buffer)
-                    .key("KREWETKA")
-                    .headers(
-                        OwnedHeaders::new()
-                            .add("host-identifier-x", &msg.metadata.host)
-                            .add("message-id-x", &msg.metadata.id)
-                            .add("timestamp-x", &msg.metadata.timestamp.to_string())
-                            .add("retry-x", &msg.metadata.retry.to_string()),
-                    ),
+                    .key(&key)
+                    .headers(headers),
                 Duration::from_secs(0),
             )
             .await
@@ -150,7 +665,52 @@ impl Transport for KafkaProcessingAgent {
             Err((err, msg)) => {
                 error!("unable to produce message: {:?}\nerror: {}", msg, err)
             }
-            _ => debug!("message produced to topic {}", topic),
+            _ => sampled_debug!("message produced to topic {}", topic),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_failures_are_bucketed_by_reason() {
+        for (e, reason) in [
+            (
+                AvroError::Frame("missing magic byte".to_owned()),
+                DeadLetterReason::DecodeError,
+            ),
+            (
+                AvroError::Decode("unexpected end of input".to_owned()),
+                DeadLetterReason::DecodeError,
+            ),
+            (
+                AvroError::UnknownCodec("thrift".to_owned()),
+                DeadLetterReason::SchemaUnsupported,
+            ),
+            (
+                AvroError::UnexpectedSchema(7),
+                DeadLetterReason::SchemaUnsupported,
+            ),
+            (
+                AvroError::RegistryNotConfigured,
+                DeadLetterReason::SchemaUnsupported,
+            ),
+        ] {
+            assert_eq!(avro_reason(&e), reason, "{}", e);
+            assert_eq!(decode_format_reason(&DecodeFormatError::Codec(e)), reason);
+        }
+
+        assert_eq!(
+            decode_format_reason(&DecodeFormatError::UnknownContentType(
+                "text/csv".to_owned()
+            )),
+            DeadLetterReason::SchemaUnsupported
+        );
+        assert_eq!(
+            decode_format_reason(&DecodeFormatError::Ambiguous),
+            DeadLetterReason::DecodeError
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use crate::pb::FlowMessage;
+
+use log::info;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Avro schema of flows exported by the collector, registered schemas have to be equal to it
+const FLOW_MESSAGE_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "FlowMessage",
+  "namespace": "krewetka.flow",
+  "fields": [
+    {"name": "out_bytes", "type": "long"},
+    {"name": "out_pkts", "type": "long"},
+    {"name": "in_bytes", "type": "long"},
+    {"name": "in_pkts", "type": "long"},
+    {"name": "ipv4_src_addr", "type": "string"},
+    {"name": "ipv4_dst_addr", "type": "string"},
+    {"name": "l7_proto", "type": "float"},
+    {"name": "l4_dst_port", "type": "long"},
+    {"name": "l4_src_port", "type": "long"},
+    {"name": "flow_duration_milliseconds", "type": "long"},
+    {"name": "protocol", "type": "long"},
+    {"name": "tcp_flags", "type": "long"}
+  ]
+}"#;
+
+const MAGIC_BYTE: u8 = 0;
+
+/// How long a schema id which is not the flow schema is remembered, a schema registered
+/// under the id later on is picked up once it expires
+const UNEXPECTED_SCHEMA_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvroError {
+    UnknownCodec(String),
+    Frame(String),
+    Decode(String),
+    /// registry is unreachable or failed, the payload may be decoded once it is back
+    Registry(String),
+    RegistryNotConfigured,
+    /// registered schema is not the flow schema, or there is no schema with the id
+    UnexpectedSchema(u32),
+}
+
+impl fmt::Display for AvroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AvroError::UnknownCodec(c) => write!(f, "unknown payload codec {c}"),
+            AvroError::Frame(e) => write!(f, "invalid schema registry framing: {e}"),
+            AvroError::Decode(e) => write!(f, "unable to decode avro flow: {e}"),
+            AvroError::Registry(e) => write!(f, "unable to fetch schema: {e}"),
+            AvroError::RegistryNotConfigured => {
+                write!(f, "schema registry url is not configured")
+            }
+            AvroError::UnexpectedSchema(id) => write!(f, "schema {id} is not the flow schema"),
+        }
+    }
+}
+
+impl std::error::Error for AvroError {}
+
+/// Encoding of a payload according to its `codec-x` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadCodec {
+    Protobuf,
+    Avro,
+}
+
+impl PayloadCodec {
+    pub fn from_header(header: Option<&[u8]>) -> Result<Self, AvroError> {
+        match header {
+            None => Ok(Self::Protobuf),
+            Some(b"avro") => Ok(Self::Avro),
+            Some(other) => Err(AvroError::UnknownCodec(
+                String::from_utf8_lossy(other).into_owned(),
+            )),
+        }
+    }
+}
+
+/// Splits a payload into the schema id and the avro record
+fn parse_frame(payload: &[u8]) -> Result<(u32, &[u8]), AvroError> {
+    match payload {
+        [MAGIC_BYTE, a, b, c, d, body @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), body)),
+        [MAGIC_BYTE, ..] => Err(AvroError::Frame("payload is too short".to_owned())),
+        _ => Err(AvroError::Frame("missing magic byte".to_owned())),
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], AvroError> {
+        if self.buf.len() < n {
+            return Err(AvroError::Decode("unexpected end of record".to_owned()));
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn long(&mut self) -> Result<i64, AvroError> {
+        let mut zigzag: u64 = 0;
+        for i in 0..10 {
+            let byte = self.take(1)?[0];
+            zigzag |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64));
+            }
+        }
+        Err(AvroError::Decode("long is longer than 10 bytes".to_owned()))
+    }
+
+    fn u32(&mut self, field: &str) -> Result<u32, AvroError> {
+        let value = self.long()?;
+        u32::try_from(value).map_err(|_| AvroError::Decode(format!("{field} out of range")))
+    }
+
+    fn float(&mut self) -> Result<f32, AvroError> {
+        let bytes = self.take(4)?;
+        Ok(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, AvroError> {
+        let len = usize::try_from(self.long()?)
+            .map_err(|_| AvroError::Decode("negative string length".to_owned()))?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| AvroError::Decode(e.to_string()))
+    }
+}
+
+/// Decodes a record written with `FLOW_MESSAGE_SCHEMA`
+fn decode_flow(body: &[u8]) -> Result<FlowMessage, AvroError> {
+    let mut r = Reader { buf: body };
+    let flow = FlowMessage {
+        out_bytes: r.long()? as u64,
+        out_pkts: r.long()? as u64,
+        in_bytes: r.long()? as u64,
+        in_pkts: r.long()? as u64,
+        ipv4_src_addr: r.string()?,
+        ipv4_dst_addr: r.string()?,
+        l7_proto: r.float()?,
+        l4_dst_port: r.u32("l4_dst_port")?,
+        l4_src_port: r.u32("l4_src_port")?,
+        flow_duration_milliseconds: r.long()? as u64,
+        protocol: r.u32("protocol")?,
+        tcp_flags: r.u32("tcp_flags")?,
+    };
+
+    if !r.buf.is_empty() {
+        return Err(AvroError::Decode(format!(
+            "{} bytes left after the record",
+            r.buf.len()
+        )));
+    }
+    Ok(flow)
+}
+
+/// Schemas are compared as json, so formatting of the registered schema does not matter
+fn is_flow_schema(schema: &str) -> bool {
+    let expected: serde_json::Value = serde_json::from_str(FLOW_MESSAGE_SCHEMA).unwrap();
+    serde_json::from_str::<serde_json::Value>(schema).map_or(false, |s| s == expected)
+}
+
+/// Registry errors worth retrying, any other error status is an answer about the schema
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+#[derive(Deserialize)]
+struct RegisteredSchema {
+    schema: String,
+}
+
+/// Decodes avro payloads, schema ids are checked against the schema registry once, ids which
+/// are not the flow schema again after `UNEXPECTED_SCHEMA_TTL`
+pub struct AvroDecoder {
+    registry_url: String,
+    http: reqwest::Client,
+    /// whether the id is the flow schema and when it was checked
+    checked: Mutex<HashMap<u32, (bool, Instant)>>,
+}
+
+impl AvroDecoder {
+    pub fn new(registry_url: &str) -> Self {
+        Self {
+            registry_url: registry_url.trim_end_matches('/').to_owned(),
+            http: reqwest::Client::new(),
+            checked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn decode(&self, payload: &[u8]) -> Result<FlowMessage, AvroError> {
+        let (id, body) = parse_frame(payload)?;
+        self.check_schema(id).await?;
+        decode_flow(body)
+    }
+
+    /// Result of an earlier check, none when the id was not checked or it expired
+    fn known(&self, id: u32) -> Option<bool> {
+        match self.checked.lock().unwrap().get(&id) {
+            Some((true, _)) => Some(true),
+            Some((false, at)) if at.elapsed() < UNEXPECTED_SCHEMA_TTL => Some(false),
+            _ => None,
+        }
+    }
+
+    async fn check_schema(&self, id: u32) -> Result<(), AvroError> {
+        let is_flow = match self.known(id) {
+            Some(is_flow) => is_flow,
+            None => {
+                let schema = self.fetch_schema(id).await?;
+                let is_flow = schema.as_deref().map_or(false, is_flow_schema);
+                info!("schema {} fetched, flow schema: {}", id, is_flow);
+                self.checked
+                    .lock()
+                    .unwrap()
+                    .insert(id, (is_flow, Instant::now()));
+                is_flow
+            }
+        };
+
+        if !is_flow {
+            return Err(AvroError::UnexpectedSchema(id));
+        }
+        Ok(())
+    }
+
+    /// Schema registered under the id, none when the registry has no such schema
+    async fn fetch_schema(&self, id: u32) -> Result<Option<String>, AvroError> {
+        let response = self
+            .http
+            .get(format!("{}/schemas/ids/{}", self.registry_url, id))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(|e| AvroError::Registry(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() && !is_retryable(status) {
+            info!("schema {} is not registered: {}", id, status);
+            return Ok(None);
+        }
+        let text = response
+            .error_for_status()
+            .map_err(|e| AvroError::Registry(e.to_string()))?
+            .text()
+            .await
+            .map_err(|e| AvroError::Registry(e.to_string()))?;
+
+        serde_json::from_str::<RegisteredSchema>(&text)
+            .map(|r| Some(r.schema))
+            .map_err(|e| AvroError::Registry(format!("unexpected response {text}: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payload of the collector's avro codec, schema id 7
+    fn collector_payload() -> Vec<u8> {
+        let mut payload = vec![0, 0, 0, 0, 7, 154, 1, 0, 0, 2, 16];
+        payload.extend_from_slice(b"10.0.0.2");
+        payload.push(0);
+        payload.extend_from_slice(&5.0f32.to_le_bytes());
+        payload.extend_from_slice(&[106, 0, 0, 0, 0]);
+        payload
+    }
+
+    #[test]
+    fn test_decode_collector_payload() {
+        let payload = collector_payload();
+        let (id, body) = parse_frame(&payload).unwrap();
+
+        assert_eq!(id, 7);
+        assert_eq!(
+            decode_flow(body),
+            Ok(FlowMessage {
+                out_bytes: 77,
+                in_pkts: 1,
+                ipv4_src_addr: "10.0.0.2".to_owned(),
+                l7_proto: 5.0,
+                l4_dst_port: 53,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_malformed_payloads_are_rejected() {
+        assert!(matches!(
+            parse_frame(&[1, 0, 0, 0, 7]),
+            Err(AvroError::Frame(_))
+        ));
+        assert!(matches!(parse_frame(&[0, 0, 7]), Err(AvroError::Frame(_))));
+
+        let payload = collector_payload();
+        let (_, body) = parse_frame(&payload).unwrap();
+        assert!(matches!(
+            decode_flow(&body[..body.len() - 1]),
+            Err(AvroError::Decode(_))
+        ));
+
+        let mut trailing = body.to_vec();
+        trailing.push(0);
+        assert!(matches!(decode_flow(&trailing), Err(AvroError::Decode(_))));
+
+        // negative port
+        let mut negative = body.to_vec();
+        let port = negative.len() - 5;
+        negative[port] = 1;
+        assert!(matches!(decode_flow(&negative), Err(AvroError::Decode(_))));
+    }
+
+    #[test]
+    fn test_registered_schema_is_compared_as_json() {
+        let compact = serde_json::to_string(
+            &serde_json::from_str::<serde_json::Value>(FLOW_MESSAGE_SCHEMA).unwrap(),
+        )
+        .unwrap();
+        assert!(is_flow_schema(&compact));
+        assert!(!is_flow_schema(r#"{"type": "string"}"#));
+        assert!(!is_flow_schema("not json"));
+    }
+
+    /// Source of the schema in a file, between its raw string delimiters
+    fn schema_source(file: &str) -> &str {
+        let start = file
+            .find("FLOW_MESSAGE_SCHEMA: &str = r#\"")
+            .expect("schema is missing");
+        let end = file[start..].find("}\"#;").expect("schema is not closed");
+        &file[start..start + end]
+    }
+
+    /// Collector and processor are built on their own, each has a copy of the schema
+    #[test]
+    fn test_schema_is_the_same_as_in_collector() {
+        assert_eq!(
+            schema_source(include_str!("avro.rs")),
+            schema_source(include_str!(
+                "../../../../../collector/src/exporters/avro.rs"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_only_unavailable_registry_is_retried() {
+        assert!(is_retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn test_unexpected_schemas_are_checked_again() {
+        let decoder = AvroDecoder::new("http://registry:8081");
+        let long_ago = Instant::now()
+            .checked_sub(UNEXPECTED_SCHEMA_TTL * 2)
+            .unwrap();
+        decoder.checked.lock().unwrap().extend([
+            (1, (true, long_ago)),
+            (2, (false, Instant::now())),
+            (3, (false, long_ago)),
+        ]);
+
+        assert_eq!(decoder.known(1), Some(true));
+        assert_eq!(decoder.known(2), Some(false));
+        assert_eq!(decoder.known(3), None);
+        assert_eq!(decoder.known(4), None);
+    }
+
+    #[test]
+    fn test_codec_from_header() {
+        assert_eq!(PayloadCodec::from_header(None), Ok(PayloadCodec::Protobuf));
+        assert_eq!(
+            PayloadCodec::from_header(Some(b"avro")),
+            Ok(PayloadCodec::Avro)
+        );
+        assert_eq!(
+            PayloadCodec::from_header(Some(b"json")),
+            Err(AvroError::UnknownCodec("json".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+use common::hash::crc32;
+
+/// Checksum of a message payload, told by the `checksum-x` header set by the collector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadChecksum {
+    Crc32(u32),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChecksumError {
+    Unknown(String),
+    Mismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(c) => write!(f, "unknown payload checksum {}", c),
+            Self::Mismatch { expected, actual } => write!(
+                f,
+                "corruption detected: checksum {} does not match {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl fmt::Display for PayloadChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Crc32(crc) => write!(f, "crc32:{:08x}", crc),
+        }
+    }
+}
+
+impl PayloadChecksum {
+    /// Missing header means the producer did not compute a checksum, e.g. older collectors or retries
+    pub fn from_header(value: Option<&[u8]>) -> Result<Option<Self>, ChecksumError> {
+        let value = match value {
+            None | Some(b"none") => return Ok(None),
+            Some(value) => value,
+        };
+        let unknown = || ChecksumError::Unknown(String::from_utf8_lossy(value).into_owned());
+
+        match std::str::from_utf8(value)
+            .map_err(|_| unknown())?
+            .split_once(':')
+        {
+            Some(("crc32", digest)) => u32::from_str_radix(digest, 16)
+                .map(|crc| Some(Self::Crc32(crc)))
+                .map_err(|_| unknown()),
+            _ => Err(unknown()),
+        }
+    }
+
+    /// Checks the payload as received, before it is decompressed
+    pub fn verify(&self, payload: &[u8]) -> Result<(), ChecksumError> {
+        let actual = match self {
+            Self::Crc32(_) => Self::Crc32(crc32(payload)),
+        };
+        if actual == *self {
+            return Ok(());
+        }
+        Err(ChecksumError::Mismatch {
+            expected: self.to_string(),
+            actual: actual.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::FlowMessage;
+    use prost::Message;
+
+    fn encoded_flow() -> Vec<u8> {
+        FlowMessage {
+            in_bytes: 61,
+            in_pkts: 1,
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            ipv4_dst_addr: "10.0.0.1".to_owned(),
+            l4_dst_port: 53,
+            protocol: 17,
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    // formatted the same way as collector's kafka exporter does
+    fn header(payload: &[u8]) -> Vec<u8> {
+        format!("crc32:{:08x}", crc32(payload)).into_bytes()
+    }
+
+    #[test]
+    fn test_intact_payload_is_verified() {
+        let payload = encoded_flow();
+        let checksum = PayloadChecksum::from_header(Some(&header(&payload)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(checksum.verify(&payload), Ok(()));
+
+        assert_eq!(PayloadChecksum::from_header(None), Ok(None));
+    }
+
+    #[test]
+    fn test_flipped_byte_is_detected() {
+        let payload = encoded_flow();
+        let checksum = PayloadChecksum::from_header(Some(&header(&payload)))
+            .unwrap()
+            .unwrap();
+
+        for idx in [0, payload.len() / 2, payload.len() - 1] {
+            let mut corrupted = payload.clone();
+            corrupted[idx] ^= 0x01;
+            assert!(matches!(
+                checksum.verify(&corrupted),
+                Err(ChecksumError::Mismatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unknown_checksum() {
+        for header in [&b"xxhash64:0011"[..], b"crc32:zz", b"crc32"] {
+            assert!(matches!(
+                PayloadChecksum::from_header(Some(header)),
+                Err(ChecksumError::Unknown(_))
+            ));
+        }
+    }
+}
@@ -1,8 +1,30 @@
+use super::consts::CONSUMER_GROUP_ID;
 use super::context::CustomContext;
 use rdkafka::config::ClientConfig;
 use rdkafka::config::RDKafkaLogLevel;
 use rdkafka::consumer::StreamConsumer;
 use rdkafka::producer::FutureProducer;
+use serde::Deserialize;
+
+use crate::settings::ConsumerSettings;
+
+/// Where a consumer group without a committed offset starts reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoOffsetReset {
+    Earliest,
+    #[default]
+    Latest,
+}
+
+impl AutoOffsetReset {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AutoOffsetReset::Earliest => "earliest",
+            AutoOffsetReset::Latest => "latest",
+        }
+    }
+}
 
 pub fn get_producer(brokers: &str) -> FutureProducer {
     let producer: FutureProducer = ClientConfig::new()
@@ -15,15 +37,49 @@ pub fn get_producer(brokers: &str) -> FutureProducer {
 }
 
 pub fn get_consumer(brokers: &str) -> StreamConsumer<CustomContext> {
-    let ctx = CustomContext;
-    let consumer: StreamConsumer<CustomContext> = ClientConfig::new()
+    get_consumer_with_group(brokers, CONSUMER_GROUP_ID)
+}
+
+/// Consumer of internal topics (retries, dead letters), which are always read from the beginning
+pub fn get_consumer_with_group(brokers: &str, group_id: &str) -> StreamConsumer<CustomContext> {
+    let settings = ConsumerSettings {
+        auto_offset_reset: AutoOffsetReset::Earliest,
+        enable_auto_commit: true,
+        ..Default::default()
+    };
+    get_consumer_with_settings(brokers, group_id, &settings)
+}
+
+pub fn get_consumer_with_settings(
+    brokers: &str,
+    group_id: &str,
+    settings: &ConsumerSettings,
+) -> StreamConsumer<CustomContext> {
+    get_consumer_with_context(brokers, group_id, settings, CustomContext::default())
+}
+
+pub fn get_consumer_with_context(
+    brokers: &str,
+    group_id: &str,
+    settings: &ConsumerSettings,
+    ctx: CustomContext,
+) -> StreamConsumer<CustomContext> {
+    let mut config = ClientConfig::new();
+    for (key, value) in settings.fetch_overrides() {
+        config.set(key, value);
+    }
+
+    let consumer: StreamConsumer<CustomContext> = config
         .set("bootstrap.servers", brokers)
         .set("enable.partition.eof", "false")
-        .set("enable.auto.commit", "true")
+        .set(
+            "enable.auto.commit",
+            settings.enable_auto_commit.to_string(),
+        )
         .set("enable.auto.offset.store", "false")
         // .set("auto.commit.interval.ms", "2000")
-        .set("auto.offset.reset", "earliest")
-        .set("group.id", "krewetka-group")
+        .set("auto.offset.reset", settings.auto_offset_reset.as_str())
+        .set("group.id", group_id)
         .set_log_level(RDKafkaLogLevel::Debug)
         .create_with_context(ctx)
         .expect("Kafka consumer creation error");
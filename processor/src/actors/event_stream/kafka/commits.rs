@@ -0,0 +1,185 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Offsets of a single partition
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    /// consumed and not acknowledged yet
+    pending: BTreeSet<i64>,
+    highest_acked: Option<i64>,
+    committed: Option<i64>,
+}
+
+impl PartitionOffsets {
+    /// Offset consumption resumes from, every message before it is acknowledged.
+    /// `None` when it did not move since the last commit
+    fn committable(&self) -> Option<i64> {
+        let highest_acked = self.highest_acked?;
+        let next = match self.pending.iter().next() {
+            Some(&lowest) => lowest,
+            None => highest_acked + 1,
+        };
+        match self.committed {
+            Some(committed) if committed >= next => None,
+            _ => Some(next),
+        }
+    }
+}
+
+/// Offsets of messages acknowledged by storage, committed in batches instead of one by one.
+/// Messages are acknowledged out of order, so only offsets below the lowest one still being
+/// processed are committed, which keeps redelivery after a crash limited to unacknowledged ones
+#[derive(Debug)]
+pub struct OffsetCommits {
+    partitions: HashMap<(String, i32), PartitionOffsets>,
+    batch_size: usize,
+    acked_since_commit: usize,
+}
+
+impl OffsetCommits {
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            partitions: HashMap::new(),
+            batch_size: batch_size.max(1),
+            acked_since_commit: 0,
+        }
+    }
+
+    pub fn consumed(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partitions
+            .entry((topic.to_owned(), partition))
+            .or_default()
+            .pending
+            .insert(offset);
+    }
+
+    /// Returns true once `batch_size` messages were acknowledged since the last commit
+    pub fn acked(&mut self, topic: &str, partition: i32, offset: i64) -> bool {
+        let offsets = match self.partitions.get_mut(&(topic.to_owned(), partition)) {
+            Some(offsets) => offsets,
+            None => return false,
+        };
+        if !offsets.pending.remove(&offset) {
+            return false;
+        }
+        offsets.highest_acked = offsets.highest_acked.max(Some(offset));
+
+        self.acked_since_commit += 1;
+        self.acked_since_commit >= self.batch_size
+    }
+
+    /// Forgets offsets of a partition which is no longer assigned, its acks are ignored
+    pub fn revoke(&mut self, topic: &str, partition: i32) {
+        self.partitions.remove(&(topic.to_owned(), partition));
+    }
+
+    /// Offsets to be committed, the batch starts over. They are marked committed only once
+    /// the commit succeeds, so a failed commit is retried with the next one
+    pub fn start_commit(&mut self) -> Vec<(String, i32, i64)> {
+        self.acked_since_commit = 0;
+        self.committable()
+    }
+
+    /// Topic, partition and offset of every partition which moved since the last commit
+    pub fn committable(&self) -> Vec<(String, i32, i64)> {
+        self.partitions
+            .iter()
+            .filter_map(|((topic, partition), offsets)| {
+                offsets
+                    .committable()
+                    .map(|offset| (topic.clone(), *partition, offset))
+            })
+            .collect()
+    }
+
+    /// Called once a commit of offsets returned by `committable` succeeds
+    pub fn mark_committed(&mut self, committed: &[(String, i32, i64)]) {
+        for (topic, partition, offset) in committed {
+            if let Some(offsets) = self.partitions.get_mut(&(topic.clone(), *partition)) {
+                offsets.committed = offsets.committed.max(Some(*offset));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_contiguous_acks_are_committed() {
+        let mut commits = OffsetCommits::new(100);
+        for offset in 10..15 {
+            commits.consumed("flows", 0, offset);
+        }
+        assert!(commits.committable().is_empty());
+
+        commits.acked("flows", 0, 10);
+        commits.acked("flows", 0, 12);
+        commits.acked("flows", 0, 13);
+        // 11 is still being processed
+        assert_eq!(commits.committable(), vec![("flows".to_owned(), 0, 11)]);
+        commits.mark_committed(&commits.committable());
+        assert!(commits.committable().is_empty());
+
+        commits.acked("flows", 0, 11);
+        commits.acked("flows", 0, 14);
+        assert_eq!(commits.committable(), vec![("flows".to_owned(), 0, 15)]);
+    }
+
+    #[test]
+    fn test_batch_size_triggers_commit() {
+        let mut commits = OffsetCommits::new(2);
+        for offset in 0..4 {
+            commits.consumed("flows", 1, offset);
+        }
+
+        assert!(!commits.acked("flows", 1, 0));
+        assert!(commits.acked("flows", 1, 1));
+        commits.start_commit();
+        assert!(!commits.acked("flows", 1, 2));
+        // offsets which were not consumed through the tracker are ignored
+        assert!(!commits.acked("flows", 2, 0));
+        assert!(commits.acked("flows", 1, 3));
+    }
+
+    #[test]
+    fn test_offsets_are_committed_once_the_commit_succeeds() {
+        let mut commits = OffsetCommits::new(100);
+        for offset in 0..3 {
+            commits.consumed("flows", 0, offset);
+            commits.acked("flows", 0, offset);
+        }
+
+        // the commit failed, the same offset is committed again
+        let offsets = commits.start_commit();
+        assert_eq!(offsets, vec![("flows".to_owned(), 0, 3)]);
+        assert_eq!(commits.start_commit(), offsets);
+
+        commits.mark_committed(&offsets);
+        assert!(commits.start_commit().is_empty());
+    }
+
+    #[test]
+    fn test_revoked_partitions_do_not_hold_back_commits() {
+        let mut commits = OffsetCommits::new(100);
+        commits.consumed("flows", 0, 5);
+        commits.consumed("flows", 1, 7);
+        commits.consumed("flows", 1, 8);
+        commits.acked("flows", 1, 8);
+
+        commits.revoke("flows", 1);
+        assert!(!commits.acked("flows", 1, 7));
+        assert!(commits.committable().is_empty());
+
+        // the partition is assigned again and consumed from the committed offset
+        commits.consumed("flows", 1, 7);
+        commits.acked("flows", 1, 7);
+        commits.acked("flows", 0, 5);
+        let mut committable = commits.committable();
+        committable.sort();
+        assert_eq!(
+            committable,
+            vec![("flows".to_owned(), 0, 6), ("flows".to_owned(), 1, 8)]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use flate2::read::GzDecoder;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Read};
+
+/// Largest payload a message is decompressed to, a larger one is not a flow and is rejected
+/// before it takes up the memory
+pub const MAX_DECOMPRESSED_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Compression of a message payload, told by the `compression-x` header set by the collector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug)]
+pub enum DecompressError {
+    Unknown(String),
+    Corrupt(io::Error),
+    TooLarge,
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(c) => write!(f, "unknown payload compression {}", c),
+            Self::Corrupt(e) => write!(f, "corrupt compressed payload: {}", e),
+            Self::TooLarge => write!(
+                f,
+                "payload decompresses to more than {} bytes",
+                MAX_DECOMPRESSED_BYTES
+            ),
+        }
+    }
+}
+
+impl PayloadCompression {
+    /// Missing header means the payload is not compressed
+    pub fn from_header(value: Option<&[u8]>) -> Result<Self, DecompressError> {
+        match value {
+            None | Some(b"none") => Ok(Self::None),
+            Some(b"gzip") => Ok(Self::Gzip),
+            Some(b"zstd") => Ok(Self::Zstd),
+            Some(other) => Err(DecompressError::Unknown(
+                String::from_utf8_lossy(other).into_owned(),
+            )),
+        }
+    }
+
+    pub fn decompress<'a>(&self, payload: &'a [u8]) -> Result<Cow<'a, [u8]>, DecompressError> {
+        self.decompress_at_most(payload, MAX_DECOMPRESSED_BYTES)
+    }
+
+    /// Reads one byte past `max` to tell a payload of exactly `max` bytes from a larger one
+    fn decompress_at_most<'a>(
+        &self,
+        payload: &'a [u8],
+        max: u64,
+    ) -> Result<Cow<'a, [u8]>, DecompressError> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Self::None => return Ok(Cow::Borrowed(payload)),
+            Self::Gzip => Box::new(GzDecoder::new(payload)),
+            Self::Zstd => {
+                Box::new(zstd::Decoder::with_buffer(payload).map_err(DecompressError::Corrupt)?)
+            }
+        };
+
+        let capacity = (payload.len() as u64).saturating_mul(4).min(max);
+        let mut decompressed = Vec::with_capacity(capacity as usize);
+        decoder
+            .take(max + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(DecompressError::Corrupt)?;
+        if decompressed.len() as u64 > max {
+            return Err(DecompressError::TooLarge);
+        }
+        Ok(Cow::Owned(decompressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::FlowMessage;
+    use flate2::write::GzEncoder;
+    use prost::Message;
+    use std::io::Write;
+
+    fn encoded_flow() -> Vec<u8> {
+        FlowMessage {
+            out_bytes: 120,
+            out_pkts: 1,
+            in_bytes: 61,
+            in_pkts: 1,
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            ipv4_dst_addr: "10.0.0.1".to_owned(),
+            l7_proto: 5.0,
+            l4_dst_port: 53,
+            l4_src_port: 40000,
+            flow_duration_milliseconds: 12,
+            protocol: 17,
+            tcp_flags: 0,
+        }
+        .encode_to_vec()
+    }
+
+    // compressed the same way as collector's kafka exporter does
+    fn compress(payload: &[u8], compression: PayloadCompression) -> Vec<u8> {
+        match compression {
+            PayloadCompression::None => payload.to_vec(),
+            PayloadCompression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload).unwrap();
+                encoder.finish().unwrap()
+            }
+            PayloadCompression::Zstd => zstd::encode_all(payload, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_compressed_flow_round_trip() {
+        let flow = encoded_flow();
+
+        for (header, compression) in [
+            (None, PayloadCompression::None),
+            (Some(&b"gzip"[..]), PayloadCompression::Gzip),
+            (Some(&b"zstd"[..]), PayloadCompression::Zstd),
+        ] {
+            let payload = compress(&flow, compression);
+            let decompressed = PayloadCompression::from_header(header)
+                .unwrap()
+                .decompress(&payload)
+                .unwrap();
+
+            assert_eq!(decompressed.as_ref(), flow.as_slice());
+            assert!(FlowMessage::decode(decompressed.as_ref()).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_unknown_compression() {
+        assert!(matches!(
+            PayloadCompression::from_header(Some(b"lz4")),
+            Err(DecompressError::Unknown(c)) if c == "lz4"
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_payload() {
+        let flow = encoded_flow();
+        for compression in [PayloadCompression::Gzip, PayloadCompression::Zstd] {
+            let payload = compress(&flow, compression);
+            assert!(matches!(
+                compression.decompress(&payload[..payload.len() / 2]),
+                Err(DecompressError::Corrupt(_))
+            ));
+            // a plain payload marked as compressed
+            assert!(matches!(
+                compression.decompress(&flow),
+                Err(DecompressError::Corrupt(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_payload_decompressing_past_the_limit_is_rejected() {
+        let flow = encoded_flow();
+        let max = flow.len() as u64;
+        for compression in [PayloadCompression::Gzip, PayloadCompression::Zstd] {
+            assert!(compression
+                .decompress_at_most(&compress(&flow, compression), max)
+                .is_ok());
+
+            let larger = [flow.as_slice(), &[0]].concat();
+            assert!(matches!(
+                compression.decompress_at_most(&compress(&larger, compression), max),
+                Err(DecompressError::TooLarge)
+            ));
+        }
+
+        // a few kilobytes which would expand into far more than the limit
+        let bomb = compress(
+            &vec![0; MAX_DECOMPRESSED_BYTES as usize + 1],
+            PayloadCompression::Zstd,
+        );
+        assert!(bomb.len() < 64 * 1024);
+        assert!(matches!(
+            PayloadCompression::Zstd.decompress(&bomb),
+            Err(DecompressError::TooLarge)
+        ));
+    }
+}
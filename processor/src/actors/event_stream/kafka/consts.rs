@@ -1,2 +1,21 @@
 pub const OFFSET_COMMIT_INTERVAL: u64 = 5;
-pub const BASE_RETRY_INTERVAL_IN_MILLIS: u64 = 60 * 10_u64.pow(3);
+pub const DEAD_LETTER_REPLAY_GROUP_ID: &str = "krewetka-dead-letter-replayer";
+pub const DEAD_LETTER_REPLAY_IDLE_TIMEOUT_IN_SECS: u64 = 5;
+pub const DECODE_ERROR_SNIPPET_LEN: usize = 64;
+pub const CONSUMER_GROUP_ID: &str = "krewetka-group";
+pub const SOURCE_TOPIC_HEADER: &str = "source-topic-x";
+pub const COMPRESSION_HEADER: &str = "compression-x";
+pub const DEAD_LETTER_REASON_HEADER: &str = "dead-letter-reason-x";
+pub const DEAD_LETTER_DETAIL_HEADER: &str = "dead-letter-detail-x";
+pub const CODEC_HEADER: &str = "codec-x";
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+pub const CHECKSUM_HEADER: &str = "checksum-x";
+pub const SCHEMA_VERSION_HEADER: &str = "schema-version-x";
+/// Longest wait of the consumer loop before it beats the watchdog again
+pub const WATCHDOG_BEAT_INTERVAL_IN_SECS: u64 = 5;
+/// Longest wait between attempts to reach the schema registry, waits double up to it
+pub const SCHEMA_REGISTRY_RETRY_MAX_DELAY_IN_SECS: u64 = 30;
+/// Longest wait between attempts to move a message to the dead letter topic
+pub const DEAD_LETTER_RETRY_MAX_DELAY_IN_SECS: u64 = 30;
+/// Dead letters remembered as replayed, so replaying them again is skipped
+pub const DEAD_LETTER_REPLAYED_CAPACITY: usize = 100_000;
@@ -1,23 +1,64 @@
-use log::info;
+use super::commits::OffsetCommits;
+use log::{debug, error, info};
 use rdkafka::consumer::{ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
 use rdkafka::ClientContext;
-use rdkafka::TopicPartitionList;
+use rdkafka::{Offset, TopicPartitionList};
 
-pub struct CustomContext;
+use std::sync::{Arc, Mutex};
+
+/// Keeps offsets committed in batches in sync with rebalances and results of commits,
+/// consumers which do not track their offsets only log them
+#[derive(Default)]
+pub struct CustomContext {
+    commits: Option<Arc<Mutex<OffsetCommits>>>,
+}
+
+impl CustomContext {
+    pub fn with_commits(commits: Arc<Mutex<OffsetCommits>>) -> Self {
+        Self {
+            commits: Some(commits),
+        }
+    }
+}
 
 impl ClientContext for CustomContext {}
 
 impl ConsumerContext for CustomContext {
     fn pre_rebalance(&self, rebalance: &Rebalance) {
         info!("Pre rebalance {:?}", rebalance);
+        // messages of revoked partitions are redelivered to their new owner, offsets pending
+        // here would hold back commits of partitions which are assigned again later
+        if let (Rebalance::Revoke(revoked), Some(commits)) = (rebalance, &self.commits) {
+            let mut commits = commits.lock().unwrap();
+            for elem in revoked.elements() {
+                commits.revoke(elem.topic(), elem.partition());
+            }
+        }
     }
 
     fn post_rebalance(&self, rebalance: &Rebalance) {
         info!("Post rebalance {:?}", rebalance);
     }
 
-    fn commit_callback(&self, _result: KafkaResult<()>, offsets: &TopicPartitionList) {
-        info!("Committing offsets: {:?}", offsets);
+    fn commit_callback(&self, result: KafkaResult<()>, offsets: &TopicPartitionList) {
+        if let Err(e) = result {
+            error!("unable to commit offsets {:?}: {}", offsets, e);
+            return;
+        }
+        debug!("Committed offsets: {:?}", offsets);
+        if let Some(commits) = &self.commits {
+            let committed = offsets
+                .elements()
+                .iter()
+                .filter_map(|elem| match elem.offset() {
+                    Offset::Offset(offset) => {
+                        Some((elem.topic().to_owned(), elem.partition(), offset))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<(String, i32, i64)>>();
+            commits.lock().unwrap().mark_committed(&committed);
+        }
     }
 }
@@ -0,0 +1,216 @@
+use super::avro::{AvroError, PayloadCodec};
+use crate::pb::FlowMessage;
+
+use serde::Deserialize;
+use std::fmt;
+
+/// How payloads of the consumed topics are decoded
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodeFormat {
+    /// decoded as the `codec-x` header says, protobuf when it is missing
+    #[default]
+    Protobuf,
+    /// every payload is a json flow
+    Json,
+    /// chosen per message from its headers, or from the payload when they say nothing,
+    /// for topics written in both formats during a codec migration
+    Auto,
+}
+
+/// Decoder a single payload is handed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Codec(PayloadCodec),
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeFormatError {
+    Codec(AvroError),
+    UnknownContentType(String),
+    /// nothing tells the formats apart, e.g. an empty payload
+    Ambiguous,
+}
+
+impl fmt::Display for DecodeFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Codec(e) => write!(f, "{e}"),
+            Self::UnknownContentType(c) => write!(f, "unknown payload content type {c}"),
+            Self::Ambiguous => write!(f, "payload format can not be detected"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeFormatError {}
+
+impl From<AvroError> for DecodeFormatError {
+    fn from(e: AvroError) -> Self {
+        Self::Codec(e)
+    }
+}
+
+fn from_content_type(content_type: &[u8]) -> Result<PayloadFormat, DecodeFormatError> {
+    let content_type = String::from_utf8_lossy(content_type);
+    // parameters such as the charset do not change the format
+    match content_type.split(';').next().unwrap_or_default().trim() {
+        "application/json" => Ok(PayloadFormat::Json),
+        "application/x-protobuf" | "application/protobuf" => {
+            Ok(PayloadFormat::Codec(PayloadCodec::Protobuf))
+        }
+        "application/avro" => Ok(PayloadFormat::Codec(PayloadCodec::Avro)),
+        _ => Err(DecodeFormatError::UnknownContentType(
+            content_type.into_owned(),
+        )),
+    }
+}
+
+/// A json flow is an object, while a protobuf flow never starts with `{`, it would be a group
+/// of field 15 which the flow schema does not have
+fn sniff(payload: &[u8]) -> Result<PayloadFormat, DecodeFormatError> {
+    match payload.iter().find(|b| !b.is_ascii_whitespace()) {
+        None => Err(DecodeFormatError::Ambiguous),
+        Some(b'{') => Ok(PayloadFormat::Json),
+        Some(_) => Ok(PayloadFormat::Codec(PayloadCodec::Protobuf)),
+    }
+}
+
+impl DecodeFormat {
+    /// Format of a decompressed payload given its `codec-x` and `content-type` headers
+    pub fn detect(
+        &self,
+        codec: Option<&[u8]>,
+        content_type: Option<&[u8]>,
+        payload: &[u8],
+    ) -> Result<PayloadFormat, DecodeFormatError> {
+        match self {
+            Self::Protobuf => Ok(PayloadFormat::Codec(PayloadCodec::from_header(codec)?)),
+            Self::Json => Ok(PayloadFormat::Json),
+            Self::Auto => match (codec, content_type) {
+                (Some(b"json"), _) => Ok(PayloadFormat::Json),
+                (Some(b"protobuf"), _) => Ok(PayloadFormat::Codec(PayloadCodec::Protobuf)),
+                (Some(codec), _) => Ok(PayloadFormat::Codec(PayloadCodec::from_header(Some(
+                    codec,
+                ))?)),
+                (None, Some(content_type)) => from_content_type(content_type),
+                (None, None) => sniff(payload),
+            },
+        }
+    }
+}
+
+/// Flow encoded as a json object with every field of the flow schema, an object missing any
+/// of them is not a flow
+pub fn decode_json(payload: &[u8]) -> Result<FlowMessage, serde_json::Error> {
+    serde_json::from_slice(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    fn flow() -> FlowMessage {
+        FlowMessage {
+            ipv4_src_addr: "10.0.0.2".to_owned(),
+            l4_dst_port: 443,
+            in_bytes: 300,
+            ..Default::default()
+        }
+    }
+
+    /// Decodes the payload like the consumer does, with the format detected per message
+    fn decode(
+        format: DecodeFormat,
+        codec: Option<&[u8]>,
+        payload: &[u8],
+    ) -> Result<FlowMessage, String> {
+        match format.detect(codec, None, payload) {
+            Ok(PayloadFormat::Json) => decode_json(payload).map_err(|e| e.to_string()),
+            Ok(PayloadFormat::Codec(PayloadCodec::Protobuf)) => {
+                FlowMessage::decode(payload).map_err(|e| e.to_string())
+            }
+            Ok(PayloadFormat::Codec(PayloadCodec::Avro)) => Err("avro".to_owned()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn json_flow() -> serde_json::Value {
+        serde_json::json!({
+            "out_bytes": 0,
+            "out_pkts": 0,
+            "in_bytes": 300,
+            "in_pkts": 0,
+            "ipv4_src_addr": "10.0.0.2",
+            "ipv4_dst_addr": "",
+            "l7_proto": 0.0,
+            "l4_dst_port": 443,
+            "l4_src_port": 0,
+            "flow_duration_milliseconds": 0,
+            "protocol": 0,
+            "tcp_flags": 0,
+        })
+    }
+
+    #[test]
+    fn test_mixed_formats_are_decoded_in_auto_mode() {
+        let protobuf = flow().encode_to_vec();
+        let json = json_flow().to_string();
+        let json = json.as_bytes();
+
+        for (codec, payload) in [
+            (None, protobuf.as_slice()),
+            (None, json),
+            (Some(b"json".as_slice()), json),
+            (Some(b"protobuf".as_slice()), protobuf.as_slice()),
+        ] {
+            assert_eq!(decode(DecodeFormat::Auto, codec, payload), Ok(flow()));
+        }
+
+        // without auto detection a json payload is not a flow
+        assert!(decode(DecodeFormat::Protobuf, None, json).is_err());
+        assert_eq!(decode(DecodeFormat::Json, None, json), Ok(flow()));
+    }
+
+    #[test]
+    fn test_json_which_is_not_a_flow_is_an_error() {
+        assert!(decode_json(b"{}").is_err());
+        assert!(decode_json(br#"{"user": "alice"}"#).is_err());
+
+        let mut partial = json_flow();
+        partial.as_object_mut().unwrap().remove("tcp_flags");
+        assert!(decode_json(partial.to_string().as_bytes()).is_err());
+
+        // fields the flow schema does not have are ignored
+        let mut extended = json_flow();
+        extended["exporter"] = "nprobe".into();
+        assert_eq!(
+            decode_json(extended.to_string().as_bytes()).unwrap(),
+            flow()
+        );
+    }
+
+    #[test]
+    fn test_unknown_and_ambiguous_formats_are_errors() {
+        let auto = DecodeFormat::Auto;
+        assert_eq!(
+            auto.detect(None, None, b" \n"),
+            Err(DecodeFormatError::Ambiguous)
+        );
+        assert_eq!(
+            auto.detect(Some(b"xml"), None, b"<flow/>"),
+            Err(DecodeFormatError::Codec(AvroError::UnknownCodec(
+                "xml".to_owned()
+            )))
+        );
+        assert_eq!(
+            auto.detect(None, Some(b"text/csv"), b"1,2,3"),
+            Err(DecodeFormatError::UnknownContentType("text/csv".to_owned()))
+        );
+        assert_eq!(
+            auto.detect(None, Some(b"application/json; charset=utf-8"), b"{}"),
+            Ok(PayloadFormat::Json)
+        );
+    }
+}
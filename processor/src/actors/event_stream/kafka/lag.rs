@@ -0,0 +1,105 @@
+use super::context::CustomContext;
+use crate::metrics::KAFKA_CONSUMER_LAG;
+
+use log::{debug, warn};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::Offset;
+use tokio::task;
+use tokio::time::{interval, Duration};
+
+use std::sync::Arc;
+
+/// Timeout of a single broker request made while estimating lag
+const LAG_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: i32,
+    pub lag: i64,
+}
+
+/// Messages not committed by the group yet, the whole partition if nothing was committed
+fn partition_lag(committed: Offset, low: i64, high: i64) -> i64 {
+    match committed {
+        Offset::Offset(o) => (high - o).max(0),
+        _ => (high - low).max(0),
+    }
+}
+
+/// Lag of every partition assigned to the consumer, makes blocking broker requests
+fn consumer_lag(consumer: &StreamConsumer<CustomContext>) -> Vec<PartitionLag> {
+    let committed = match consumer.committed(LAG_FETCH_TIMEOUT) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("unable to fetch committed offsets: {}", e);
+            return Vec::new();
+        }
+    };
+
+    committed
+        .elements()
+        .iter()
+        .filter_map(|elem| {
+            match consumer.fetch_watermarks(elem.topic(), elem.partition(), LAG_FETCH_TIMEOUT) {
+                Ok((low, high)) => Some(PartitionLag {
+                    topic: elem.topic().to_owned(),
+                    partition: elem.partition(),
+                    lag: partition_lag(elem.offset(), low, high),
+                }),
+                Err(e) => {
+                    warn!(
+                        "unable to fetch watermarks of [{}] partition {}: {}",
+                        elem.topic(),
+                        elem.partition(),
+                        e
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Periodically publishes consumer lag per partition.
+/// Broker requests run on the blocking pool, so the consume loop is never held up
+pub async fn monitor_lag(consumer: Arc<StreamConsumer<CustomContext>>, poll_interval: Duration) {
+    let mut ticker = interval(poll_interval);
+
+    loop {
+        ticker.tick().await;
+
+        let consumer = consumer.clone();
+        let lags = match task::spawn_blocking(move || consumer_lag(&consumer)).await {
+            Ok(lags) => lags,
+            Err(e) => {
+                warn!("lag estimation failed: {}", e);
+                continue;
+            }
+        };
+
+        // partitions revoked in a rebalance should not report stale lag
+        KAFKA_CONSUMER_LAG.reset();
+        for lag in lags {
+            debug!("consumer lag: {:?}", lag);
+            KAFKA_CONSUMER_LAG
+                .with_label_values(&[&lag.topic, &lag.partition.to_string()])
+                .set(lag.lag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_lag() {
+        assert_eq!(partition_lag(Offset::Offset(90), 0, 100), 10);
+        assert_eq!(partition_lag(Offset::Offset(100), 0, 100), 0);
+        // nothing committed yet, everything retained is behind
+        assert_eq!(partition_lag(Offset::Invalid, 40, 100), 60);
+        // committed offset ahead of a truncated partition
+        assert_eq!(partition_lag(Offset::Offset(120), 0, 100), 0);
+    }
+}
@@ -1,41 +1,125 @@
 use super::super::errors::EventStreamError;
+use super::consts::{
+    CHECKSUM_HEADER, CODEC_HEADER, COMPRESSION_HEADER, CONTENT_TYPE_HEADER,
+    DEAD_LETTER_DETAIL_HEADER, DEAD_LETTER_REASON_HEADER, SCHEMA_VERSION_HEADER,
+    SOURCE_TOPIC_HEADER,
+};
 use crate::actors::messages::FlowMessageMetadata;
+use crate::dead_letter::DeadLetter;
 use rdkafka::message::FromBytes;
 use rdkafka::message::Headers;
 use rdkafka::message::{BorrowedHeaders, OwnedHeaders};
 
+fn find_hdr<'a, H: Headers>(headers: &'a H, hdr: &str) -> Option<(&'a str, &'a [u8])> {
+    (0..headers.count())
+        .filter_map(|idx| headers.get(idx))
+        .find(|(name, _)| *name == hdr)
+}
+
+fn metadata_from_headers<H: Headers>(headers: &H) -> Result<FlowMessageMetadata, EventStreamError> {
+    let host = str::from_bytes(FlowMessageMetadata::map_hdr(
+        find_hdr(headers, "host-identifier-x"),
+        "host-identifier-x",
+    )?)?
+    .to_owned();
+    let id = str::from_bytes(FlowMessageMetadata::map_hdr(
+        find_hdr(headers, "message-id-x"),
+        "message-id-x",
+    )?)?
+    .to_owned();
+    let retry = str::from_bytes(FlowMessageMetadata::map_hdr(
+        find_hdr(headers, "retry-x"),
+        "retry-x",
+    )?)?
+    .to_owned()
+    .parse::<usize>()
+    .unwrap();
+    let timestamp = str::from_bytes(FlowMessageMetadata::map_hdr(
+        find_hdr(headers, "timestamp-x"),
+        "timestamp-x",
+    )?)?
+    .to_owned()
+    .parse::<u64>()
+    .unwrap();
+
+    // replay-x is only present on messages re-published from the dead letter queue
+    let replay = match find_hdr(headers, "replay-x") {
+        Some((_h, v)) => str::from_bytes(v)?.parse::<usize>().unwrap_or(0),
+        None => 0,
+    };
+
+    Ok(FlowMessageMetadata {
+        host,
+        id,
+        timestamp,
+        retry,
+        replay,
+        topic: find_hdr(headers, SOURCE_TOPIC_HEADER)
+            .map(|(_h, v)| str::from_bytes(v))
+            .transpose()?
+            .map(str::to_owned),
+        offset: None,
+        partition: None,
+        enrichment_timed_out: false,
+        scan_suspected: false,
+        src_asset: String::new(),
+        dst_asset: String::new(),
+    })
+}
+
+/// Value of the compression header, missing for uncompressed payloads
+pub fn compression_header<H: Headers>(headers: &H) -> Option<&[u8]> {
+    find_hdr(headers, COMPRESSION_HEADER).map(|(_h, v)| v)
+}
+
+/// Value of the codec header, missing for protobuf payloads
+pub fn codec_header<H: Headers>(headers: &H) -> Option<&[u8]> {
+    find_hdr(headers, CODEC_HEADER).map(|(_h, v)| v)
+}
+
+/// Value of the content type header, only consulted by `auto` decode format
+pub fn content_type_header<H: Headers>(headers: &H) -> Option<&[u8]> {
+    find_hdr(headers, CONTENT_TYPE_HEADER).map(|(_h, v)| v)
+}
+
+/// Value of the checksum header, missing when the producer did not compute one
+pub fn checksum_header<H: Headers>(headers: &H) -> Option<&[u8]> {
+    find_hdr(headers, CHECKSUM_HEADER).map(|(_h, v)| v)
+}
+
+/// Value of the schema version header, missing for payloads of the first version
+pub fn schema_version_header<H: Headers>(headers: &H) -> Option<&[u8]> {
+    find_hdr(headers, SCHEMA_VERSION_HEADER).map(|(_h, v)| v)
+}
+
+/// Adds the topic a flow was originally consumed from, so retries and replays go back to it
+pub fn with_source_topic(headers: OwnedHeaders, metadata: &FlowMessageMetadata) -> OwnedHeaders {
+    match &metadata.topic {
+        Some(topic) => headers.add(SOURCE_TOPIC_HEADER, topic),
+        None => headers,
+    }
+}
+
+/// Adds the reason code and the detail of a dead letter, a reason of an earlier dead letter
+/// carried by replayed messages is replaced
+pub fn with_dead_letter(headers: OwnedHeaders, dead_letter: &DeadLetter) -> OwnedHeaders {
+    (0..headers.count())
+        .filter_map(|idx| headers.get(idx))
+        .filter(|(name, _)| {
+            *name != DEAD_LETTER_REASON_HEADER && *name != DEAD_LETTER_DETAIL_HEADER
+        })
+        .fold(OwnedHeaders::new(), |kept, (name, value)| {
+            kept.add(name, value)
+        })
+        .add(DEAD_LETTER_REASON_HEADER, dead_letter.reason.as_str())
+        .add(DEAD_LETTER_DETAIL_HEADER, &dead_letter.detail)
+}
+
 impl TryFrom<&OwnedHeaders> for FlowMessageMetadata {
     type Error = EventStreamError;
 
     fn try_from(headers: &OwnedHeaders) -> Result<Self, Self::Error> {
-        let host = str::from_bytes(FlowMessageMetadata::map_hdr(
-            headers.get(0),
-            "host-identifier-x",
-        )?)?
-        .to_owned();
-        let id = str::from_bytes(FlowMessageMetadata::map_hdr(
-            headers.get(1),
-            "message-id-x",
-        )?)?
-        .to_owned();
-        let retry = str::from_bytes(FlowMessageMetadata::map_hdr(headers.get(3), "retry-x")?)?
-            .to_owned()
-            .parse::<usize>()
-            .unwrap();
-        let timestamp =
-            str::from_bytes(FlowMessageMetadata::map_hdr(headers.get(2), "timestamp-x")?)?
-                .to_owned()
-                .parse::<u64>()
-                .unwrap();
-
-        Ok(FlowMessageMetadata {
-            host,
-            id,
-            timestamp,
-            retry,
-            offset: None,
-            partition: None,
-        })
+        metadata_from_headers(headers)
     }
 }
 
@@ -43,33 +127,45 @@ impl TryFrom<&BorrowedHeaders> for FlowMessageMetadata {
     type Error = EventStreamError;
 
     fn try_from(headers: &BorrowedHeaders) -> Result<Self, Self::Error> {
-        let host = str::from_bytes(FlowMessageMetadata::map_hdr(
-            headers.get(0),
-            "host-identifier-x",
-        )?)?
-        .to_owned();
-        let id = str::from_bytes(FlowMessageMetadata::map_hdr(
-            headers.get(1),
-            "message-id-x",
-        )?)?
-        .to_owned();
-        let retry = str::from_bytes(FlowMessageMetadata::map_hdr(headers.get(3), "retry-x")?)?
-            .to_owned()
-            .parse::<usize>()
-            .unwrap();
-        let timestamp =
-            str::from_bytes(FlowMessageMetadata::map_hdr(headers.get(2), "timestamp-x")?)?
-                .to_owned()
-                .parse::<u64>()
-                .unwrap();
-
-        Ok(FlowMessageMetadata {
-            host,
-            id,
-            timestamp,
-            retry,
-            offset: None,
-            partition: None,
-        })
+        metadata_from_headers(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dead_letter::DeadLetterReason;
+
+    fn header_values(headers: &OwnedHeaders, hdr: &str) -> Vec<String> {
+        (0..headers.count())
+            .filter_map(|idx| headers.get(idx))
+            .filter(|(name, _)| *name == hdr)
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_dead_letter_headers_replace_earlier_ones() {
+        let headers = OwnedHeaders::new()
+            .add("host-identifier-x", "host")
+            .add(DEAD_LETTER_REASON_HEADER, "storage_failed")
+            .add(DEAD_LETTER_DETAIL_HEADER, "failed 3 attempts")
+            .add("message-id-x", "id");
+        let dead_letter = DeadLetter::new(DeadLetterReason::DecodeError, "truncated payload");
+
+        let headers = with_dead_letter(headers, &dead_letter);
+
+        assert_eq!(
+            header_values(&headers, DEAD_LETTER_REASON_HEADER),
+            vec!["decode_error"]
+        );
+        assert_eq!(
+            header_values(&headers, DEAD_LETTER_DETAIL_HEADER),
+            vec!["truncated payload"]
+        );
+        // other headers are kept as they were
+        assert_eq!(header_values(&headers, "host-identifier-x"), vec!["host"]);
+        assert_eq!(header_values(&headers, "message-id-x"), vec!["id"]);
+        assert_eq!(headers.count(), 4);
     }
 }
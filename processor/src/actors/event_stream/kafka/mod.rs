@@ -1,10 +1,20 @@
 pub mod agent;
+pub mod avro;
+pub mod checksum;
 mod client;
+pub mod commits;
+pub mod compression;
 mod consts;
 pub mod context;
-mod messages;
+pub mod decode_format;
+pub mod lag;
+pub mod messages;
 pub mod offset_guard;
+pub mod payload_dump;
+pub mod replayer;
 pub mod retrier;
+pub mod schema_version;
 
 pub use agent::KafkaProcessingAgent;
 pub use client::*;
+pub use replayer::DeadLetterReplayer;
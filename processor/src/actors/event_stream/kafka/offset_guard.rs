@@ -72,12 +72,13 @@ impl ConsumerOffsetGuard {
     pub fn stash_processed_offset(
         &self,
         consumer: &Arc<StreamConsumer<CustomContext>>,
+        topic: &str,
         offset: i64,
         partition: i32,
     ) {
         // self.processed_offsets.clone().lock().unwrap().push(-offset)
-        if let Err(_e) = consumer.store_offset(&self.topic, partition, offset) {
-            error!("error occured while trying to store Offset({offset}) to [{topic}] at partition {partition}", topic=self.topic);
+        if let Err(_e) = consumer.store_offset(topic, partition, offset) {
+            error!("error occured while trying to store Offset({offset}) to [{topic}] at partition {partition}");
         };
     }
 
@@ -0,0 +1,126 @@
+use super::consts::DECODE_ERROR_SNIPPET_LEN;
+use crate::settings::PayloadDumpSettings;
+
+use log::{debug, error, warn};
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub fn hex_snippet(payload: &[u8], len: usize) -> String {
+    let mut snippet = String::with_capacity(len.min(payload.len()) * 2 + 3);
+    for b in payload.iter().take(len) {
+        let _ = write!(snippet, "{b:02x}");
+    }
+    if payload.len() > len {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+/// Reports payloads which could not be decoded into a flow message.
+/// Full payloads are written to a directory only when it is configured, up to the configured
+/// number of files and total size
+pub struct PayloadDumper {
+    settings: PayloadDumpSettings,
+    dumped_files: AtomicUsize,
+    dumped_bytes: AtomicUsize,
+}
+
+impl PayloadDumper {
+    pub fn new(settings: PayloadDumpSettings) -> Self {
+        if let Some(dir) = &settings.dir {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("unable to create payload dump directory {}: {}", dir, e);
+            }
+        }
+
+        Self {
+            settings,
+            dumped_files: AtomicUsize::new(0),
+            dumped_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn report(&self, id: &str, payload: &[u8], err: &prost::DecodeError) {
+        error!(
+            "unable to decode message {} ({} bytes) into flow message: {}",
+            id,
+            payload.len(),
+            err
+        );
+        debug!(
+            "undecodable payload of message {}: {}",
+            id,
+            hex_snippet(payload, DECODE_ERROR_SNIPPET_LEN)
+        );
+
+        if let Some(path) = self.reserve(id, payload.len()) {
+            match fs::write(&path, payload) {
+                Ok(()) => debug!("payload of message {} dumped to {}", id, path.display()),
+                Err(e) => error!("unable to dump payload to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Returns a path for the dump if limits allow it
+    fn reserve(&self, id: &str, len: usize) -> Option<PathBuf> {
+        let dir = self.settings.dir.as_ref()?;
+
+        let files = self.dumped_files.fetch_add(1, Ordering::SeqCst);
+        let bytes = self.dumped_bytes.fetch_add(len, Ordering::SeqCst);
+        if files >= self.settings.max_files || bytes + len > self.settings.max_bytes {
+            self.dumped_files.fetch_sub(1, Ordering::SeqCst);
+            self.dumped_bytes.fetch_sub(len, Ordering::SeqCst);
+            warn!("payload dump limits reached, message {} is not dumped", id);
+            return None;
+        }
+
+        // message id comes from a header, keep only characters safe for a file name
+        let file_name = id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect::<String>();
+        Some(PathBuf::from(dir).join(format!("{}-{}.bin", files, file_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dumper(dir: Option<String>, max_files: usize, max_bytes: usize) -> PayloadDumper {
+        PayloadDumper::new(PayloadDumpSettings {
+            dir,
+            max_files,
+            max_bytes,
+        })
+    }
+
+    #[test]
+    fn test_hex_snippet() {
+        assert_eq!(hex_snippet(&[0x0a, 0xff, 0x01], 8), "0aff01");
+        assert_eq!(hex_snippet(&[0x0a, 0xff, 0x01], 2), "0aff...");
+        assert_eq!(hex_snippet(&[], 2), "");
+    }
+
+    #[test]
+    fn test_dump_disabled_by_default() {
+        let dumper = PayloadDumper::new(PayloadDumpSettings::default());
+        assert_eq!(dumper.reserve("id", 10), None);
+    }
+
+    #[test]
+    fn test_dump_limits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("dumps");
+        let d = dumper(Some(dir.to_str().unwrap().to_owned()), 2, 15);
+
+        assert_eq!(d.reserve("a/../b", 10), Some(dir.join("0-ab.bin")));
+        // exceeds size
+        assert_eq!(d.reserve("c", 10), None);
+        assert_eq!(d.reserve("c", 5), Some(dir.join("1-c.bin")));
+        // exceeds count
+        assert_eq!(d.reserve("d", 0), None);
+    }
+}
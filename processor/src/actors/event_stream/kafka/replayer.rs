@@ -0,0 +1,326 @@
+use super::consts::{
+    DEAD_LETTER_REPLAYED_CAPACITY, DEAD_LETTER_REPLAY_GROUP_ID,
+    DEAD_LETTER_REPLAY_IDLE_TIMEOUT_IN_SECS,
+};
+use super::get_consumer_with_group;
+use super::get_producer;
+use super::messages::with_source_topic;
+
+use crate::actors::messages::FlowMessageMetadata;
+use crate::keys::DEFAULT_MESSAGE_KEY;
+use crate::settings::ReplaySettings;
+
+use log::{error, info, warn};
+use lru::LruCache;
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::{Message, OwnedHeaders};
+use rdkafka::producer::FutureRecord;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval, timeout, Duration};
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub replayed: usize,
+    pub skipped_duplicates: usize,
+    pub skipped_exhausted: usize,
+    pub failed: usize,
+}
+
+/// What happens to a single dead letter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Replay,
+    /// replayed already, by this replay or an earlier one
+    Duplicate,
+    /// replayed `max_attempts` times
+    Exhausted,
+}
+
+/// Headers of the replayed message, retries start over and the replay counter goes up
+fn replay_headers(metadata: &FlowMessageMetadata) -> OwnedHeaders {
+    with_source_topic(
+        OwnedHeaders::new()
+            .add("host-identifier-x", &metadata.host)
+            .add("message-id-x", &metadata.id)
+            .add("timestamp-x", &metadata.timestamp.to_string())
+            .add("retry-x", &0.to_string())
+            .add("replay-x", &(metadata.replay + 1).to_string()),
+        metadata,
+    )
+}
+
+/// Re-publishes messages from the dead letter topic back to the original topic
+///
+/// Every replayed message gets its retry counter reset and its replay counter incremented,
+/// so messages that keep failing end up in the dead letter topic again and are no longer
+/// replayed once `max_attempts` is reached.
+///
+/// A dead letter is identified by its message id and its replay counter, a message which
+/// failed again after being replayed is a new dead letter. Dead letters replayed by this
+/// process are remembered, so reading them again, e.g. when offsets of an earlier replay were
+/// not committed, does not replay and insert them twice.
+pub struct DeadLetterReplayer {
+    brokers: String,
+    dead_letter_topic: String,
+    destination_topic: String,
+    settings: ReplaySettings,
+    in_progress: AtomicBool,
+    replayed: Mutex<LruCache<(String, usize), ()>>,
+}
+
+impl DeadLetterReplayer {
+    pub fn new(
+        brokers: &str,
+        dead_letter_topic: &str,
+        destination_topic: &str,
+        settings: ReplaySettings,
+    ) -> Self {
+        Self {
+            brokers: brokers.to_owned(),
+            dead_letter_topic: dead_letter_topic.to_owned(),
+            destination_topic: destination_topic.to_owned(),
+            settings,
+            in_progress: AtomicBool::new(false),
+            replayed: Mutex::new(LruCache::new(
+                NonZeroUsize::new(DEAD_LETTER_REPLAYED_CAPACITY).expect("capacity is not zero"),
+            )),
+        }
+    }
+
+    /// Ids replayed by the running replay are skipped whatever their replay counter, so a
+    /// message failing again meanwhile is not replayed in a loop by the same replay
+    fn verdict(&self, metadata: &FlowMessageMetadata, run: &mut HashSet<String>) -> Verdict {
+        if metadata.replay >= self.settings.max_attempts {
+            return Verdict::Exhausted;
+        }
+        let dead_letter = (metadata.id.clone(), metadata.replay);
+        if self.replayed.lock().unwrap().contains(&dead_letter) || !run.insert(metadata.id.clone())
+        {
+            return Verdict::Duplicate;
+        }
+        Verdict::Replay
+    }
+
+    fn mark_replayed(&self, metadata: &FlowMessageMetadata) {
+        self.replayed
+            .lock()
+            .unwrap()
+            .put((metadata.id.clone(), metadata.replay), ());
+    }
+
+    /// Spawns replay in the background, returns false if one is already running
+    pub fn start(self: &Arc<Self>) -> bool {
+        if self
+            .in_progress
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return false;
+        }
+
+        let replayer = self.clone();
+        tokio::spawn(async move {
+            let summary = replayer.replay().await;
+            info!("dead letter replay finished: {:?}", summary);
+            replayer.in_progress.store(false, Ordering::SeqCst);
+        });
+
+        true
+    }
+
+    async fn replay(&self) -> ReplaySummary {
+        let mut summary = ReplaySummary::default();
+
+        // offsets of the replayer group are committed, so the same dead letter is never
+        // replayed twice by subsequent replays
+        let consumer = get_consumer_with_group(&self.brokers, DEAD_LETTER_REPLAY_GROUP_ID);
+        let producer = get_producer(&self.brokers);
+
+        if let Err(e) = consumer.subscribe(&[&self.dead_letter_topic]) {
+            error!(
+                "unable to subscribe to dead letter topic {}: {}",
+                self.dead_letter_topic, e
+            );
+            return summary;
+        }
+
+        info!(
+            "Replaying dead letters [{}] -> [{}] with a rate of {} messages per second",
+            self.dead_letter_topic, self.destination_topic, self.settings.rate_per_sec
+        );
+
+        let mut replayed_ids = HashSet::new();
+        let mut pace = interval(Duration::from_secs_f64(
+            1.0 / self.settings.rate_per_sec.max(1) as f64,
+        ));
+        let idle_timeout = Duration::from_secs(DEAD_LETTER_REPLAY_IDLE_TIMEOUT_IN_SECS);
+
+        loop {
+            let event = match timeout(idle_timeout, consumer.recv()).await {
+                Ok(Ok(e)) => e,
+                Ok(Err(e)) => {
+                    error!("unable to receive dead letter: {}", e);
+                    break;
+                }
+                // dead letter topic has been drained
+                Err(_) => break,
+            };
+
+            let metadata = match event.headers().map(FlowMessageMetadata::try_from) {
+                Some(Ok(m)) => Some(m),
+                _ => {
+                    warn!(
+                        "dead letter at offset {} has malformed headers",
+                        event.offset()
+                    );
+                    summary.failed += 1;
+                    None
+                }
+            };
+
+            if let Some(metadata) = metadata {
+                match self.verdict(&metadata, &mut replayed_ids) {
+                    Verdict::Exhausted => summary.skipped_exhausted += 1,
+                    Verdict::Duplicate => summary.skipped_duplicates += 1,
+                    Verdict::Replay => {
+                        pace.tick().await;
+
+                        let destination_topic =
+                            metadata.topic.as_deref().unwrap_or(&self.destination_topic);
+                        let record = FutureRecord::to(destination_topic)
+                            .payload(event.payload().unwrap_or_default())
+                            .key(event.key().unwrap_or(DEFAULT_MESSAGE_KEY.as_bytes()))
+                            .headers(replay_headers(&metadata));
+
+                        if let Err((e, _)) = producer.send(record, Duration::from_secs(0)).await {
+                            // stop here, otherwise committing later offsets would skip this message
+                            error!("unable to replay dead letter {}: {}", metadata.id, e);
+                            summary.failed += 1;
+                            break;
+                        }
+                        self.mark_replayed(&metadata);
+                        summary.replayed += 1;
+                    }
+                }
+            }
+
+            if let Err(e) = consumer.store_offset_from_message(&event) {
+                error!("unable to store dead letter offset: {}", e);
+            }
+        }
+
+        if let Err(e) = consumer.commit_consumer_state(CommitMode::Sync) {
+            warn!("unable to commit dead letter replay offsets: {}", e);
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn replayer(max_attempts: usize) -> DeadLetterReplayer {
+        DeadLetterReplayer::new(
+            "kafka:9092",
+            "flows_dead_letter_queue",
+            "flows",
+            ReplaySettings {
+                rate_per_sec: 100,
+                max_attempts,
+            },
+        )
+    }
+
+    fn dead_letter(id: &str, replay: usize) -> FlowMessageMetadata {
+        FlowMessageMetadata {
+            id: id.to_owned(),
+            replay,
+            ..testutil::metadata()
+        }
+    }
+
+    #[test]
+    fn test_dead_letters_are_replayed_up_to_max_attempts() {
+        let replayer = replayer(2);
+        let mut run = HashSet::new();
+
+        assert_eq!(
+            replayer.verdict(&dead_letter("a", 0), &mut run),
+            Verdict::Replay
+        );
+        assert_eq!(
+            replayer.verdict(&dead_letter("b", 1), &mut run),
+            Verdict::Replay
+        );
+        assert_eq!(
+            replayer.verdict(&dead_letter("c", 2), &mut run),
+            Verdict::Exhausted
+        );
+    }
+
+    #[test]
+    fn test_dead_letter_is_replayed_once_per_run() {
+        let replayer = replayer(3);
+        let mut run = HashSet::new();
+
+        assert_eq!(
+            replayer.verdict(&dead_letter("a", 0), &mut run),
+            Verdict::Replay
+        );
+        // dead lettered twice, or failed again after it was replayed by this run
+        assert_eq!(
+            replayer.verdict(&dead_letter("a", 0), &mut run),
+            Verdict::Duplicate
+        );
+        assert_eq!(
+            replayer.verdict(&dead_letter("a", 1), &mut run),
+            Verdict::Duplicate
+        );
+    }
+
+    #[test]
+    fn test_replayed_dead_letter_is_not_replayed_by_later_runs() {
+        let replayer = replayer(3);
+        let replayed = dead_letter("a", 0);
+        assert_eq!(
+            replayer.verdict(&replayed, &mut HashSet::new()),
+            Verdict::Replay
+        );
+        replayer.mark_replayed(&replayed);
+
+        // read again, e.g. as offsets of the earlier run were not committed
+        assert_eq!(
+            replayer.verdict(&replayed, &mut HashSet::new()),
+            Verdict::Duplicate
+        );
+        // the replayed message failed again and is a new dead letter
+        assert_eq!(
+            replayer.verdict(&dead_letter("a", 1), &mut HashSet::new()),
+            Verdict::Replay
+        );
+    }
+
+    #[test]
+    fn test_replayed_message_starts_retries_over() {
+        let metadata = FlowMessageMetadata {
+            retry: 4,
+            replay: 1,
+            topic: Some("flows-eu".to_owned()),
+            ..dead_letter("a", 1)
+        };
+
+        let replayed = FlowMessageMetadata::try_from(&replay_headers(&metadata)).unwrap();
+        assert_eq!(replayed.id, "a");
+        assert_eq!(replayed.host, metadata.host);
+        assert_eq!(replayed.timestamp, metadata.timestamp);
+        assert_eq!(replayed.retry, 0);
+        assert_eq!(replayed.replay, 2);
+        assert_eq!(replayed.topic.as_deref(), Some("flows-eu"));
+    }
+}
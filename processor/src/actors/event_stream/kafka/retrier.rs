@@ -1,12 +1,14 @@
 use super::super::transport::RetrierExt;
-use super::consts::*;
 
 use super::get_consumer;
 use super::get_producer;
+use crate::actors::event_stream::kafka::messages::with_source_topic;
 use crate::actors::event_stream::kafka::offset_guard::ConsumerOffsetGuard;
 use crate::actors::messages::FlowMessageMetadata;
+use crate::keys::DEFAULT_MESSAGE_KEY;
 use async_trait::async_trait;
-use chrono::{Duration as ChronoDuration, Utc};
+use chrono::Utc;
+use futures::future::join_all;
 use futures::StreamExt;
 use log::*;
 use rdkafka::consumer::Consumer;
@@ -18,71 +20,139 @@ use tokio::time::Duration;
 use rdkafka::message::Message;
 use rdkafka::message::OwnedHeaders;
 use rdkafka::producer::FutureRecord;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryTierParseError(String);
+
+impl fmt::Display for RetryTierParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid retry tier: {}", self.0)
+    }
+}
+
+impl std::error::Error for RetryTierParseError {}
+
+/// Delay after which flows of a retry topic are processed again, e.g. `5s`, `10m` or `1h`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryTier {
+    pub delay: Duration,
+}
+
+impl RetryTier {
+    /// Topic of the tier, e.g. `flows.retry.5s`
+    pub fn topic(&self, base: &str) -> String {
+        format!("{}.retry.{}", base, self)
+    }
+}
+
+impl fmt::Display for RetryTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.delay.as_secs() {
+            s if s > 0 && s % 3600 == 0 => write!(f, "{}h", s / 3600),
+            s if s > 0 && s % 60 == 0 => write!(f, "{}m", s / 60),
+            s => write!(f, "{}s", s),
+        }
+    }
+}
+
+impl FromStr for RetryTier {
+    type Err = RetryTierParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RetryTierParseError(s.to_owned());
+        let unit_at = s.len().checked_sub(1).ok_or_else(invalid)?;
+        let (value, unit) = s.split_at(unit_at);
+        let value = value.parse::<u64>().map_err(|_| invalid())?;
+        let secs = match unit {
+            "s" => value,
+            "m" => value * 60,
+            "h" => value * 3600,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            delay: Duration::from_secs(secs),
+        })
+    }
+}
+
 #[async_trait]
 impl RetrierExt for Retrier {
     async fn spawn_retriers(&self) {
         info!("Spawning retriers");
-        tokio::join!(
-            self.run_retrier(1),
-            self.run_retrier(2),
-            self.run_retrier(3),
-        );
+        join_all(self.tiers.iter().map(|tier| self.run_retrier(*tier))).await;
     }
 
+    /// Attempt `n` goes to the `n`-th tier, the one after the last tier to the dead letter topic
     fn get_topic_based_on_retry(&self, current_retry: usize) -> Option<String> {
-        let max_retries = self.max_retries + 1;
-        match (current_retry == max_retries, current_retry > max_retries) {
-            (false, false) => Some(format!("{}{}", self.topic_retry, current_retry)),
-            (true, false) => Some(self.topic_dlq.to_owned()),
-            (_, true) => None,
+        match current_retry.checked_sub(1) {
+            Some(tier) if tier < self.tiers.len() => {
+                Some(self.tiers[tier].topic(&self.topic_original))
+            }
+            Some(tier) if tier == self.tiers.len() => Some(self.topic_dlq.to_owned()),
+            _ => None,
         }
     }
 
     fn get_brokers_retry(&self) -> &str {
         &self.brokers
     }
+
+    fn get_dead_letter_topic(&self) -> String {
+        self.topic_dlq.to_owned()
+    }
 }
 
 pub struct Retrier {
     topic_original: String,
-    topic_retry: String,
     topic_dlq: String,
-    max_retries: usize,
+    tiers: Vec<RetryTier>,
     brokers: String,
 }
 
 impl Retrier {
-    pub fn new(brokers: String) -> Self {
+    pub fn new(brokers: String, tiers: Vec<RetryTier>) -> Self {
         Self {
             brokers,
+            tiers,
             ..Self::default()
         }
     }
+
+    pub fn original_topic(&self) -> &str {
+        &self.topic_original
+    }
+
+    pub fn dead_letter_topic(&self) -> &str {
+        &self.topic_dlq
+    }
 }
 
 impl Default for Retrier {
     fn default() -> Self {
         Self {
             topic_original: "flows".to_owned(),
-            topic_retry: "flows_retry_".to_owned(),
             topic_dlq: "flows_dead_letter_queue".to_owned(),
-            max_retries: 2,
+            tiers: vec![],
             brokers: "broker:9092".to_owned(),
         }
     }
 }
 
+/// Time left until a flow put on a retry topic at `produced_at` is due
+fn retry_wait(produced_at: i64, delay: Duration, now: i64) -> Duration {
+    let due = produced_at.saturating_add(delay.as_millis() as i64);
+    Duration::from_millis(due.saturating_sub(now).max(0) as u64)
+}
+
 impl Retrier {
-    async fn run_retrier(&self, retry: usize) {
+    async fn run_retrier(&self, tier: RetryTier) {
         let consumer = Arc::new(get_consumer(&self.brokers));
         let producer = get_producer(&self.brokers);
 
-        let destination_topic = match self.get_topic_based_on_retry(retry) {
-            Some(s) => s,
-            None => return,
-        };
+        let destination_topic = tier.topic(&self.topic_original);
 
         let offset_guard = Arc::new(ConsumerOffsetGuard::new(
             consumer.clone(),
@@ -103,7 +173,6 @@ impl Retrier {
         }
 
         let mut stream = consumer.stream();
-        let retry_interval = (BASE_RETRY_INTERVAL_IN_MILLIS * 10_u64.pow(retry as u32)) as i64;
         info!("Starting retrier, topic: [{}]", destination_topic);
 
         while let Some(event) = stream.next().await {
@@ -115,63 +184,64 @@ impl Retrier {
                     metadata.offset = Some(ev.offset());
                     metadata.partition = Some(ev.partition());
 
-                    if let Some(x) = ev.timestamp().to_millis() {
-                        // TODO pause consumer because it may timeout for the higher retry tiers
-                        let deadline = ChronoDuration::milliseconds(
-                            retry_interval - (Utc::now().timestamp_millis() - x),
+                    // the flow was put on the retry topic at its timestamp, it is due a tier delay later
+                    let produced_at = ev
+                        .timestamp()
+                        .to_millis()
+                        .unwrap_or_else(|| Utc::now().timestamp_millis());
+                    let wait = retry_wait(produced_at, tier.delay, Utc::now().timestamp_millis());
+                    if !wait.is_zero() {
+                        // paused, so the consumer is not considered dead while waiting
+                        let assignment = consumer.assignment().unwrap();
+                        info!(
+                            "pausing {} retry consumer {:?} for {} ms",
+                            destination_topic,
+                            assignment,
+                            wait.as_millis()
+                        );
+                        consumer
+                            .pause(&assignment)
+                            .unwrap_or_else(|e| error!("error while pausing consumer: {}", e));
+                        sleep(wait).await;
+                        consumer
+                            .resume(&assignment)
+                            .unwrap_or_else(|e| error!("error while resuming consumer: {}", e));
+                    }
+
+                    // messages retried before source topics were recorded go to the default one
+                    let source_topic = metadata.topic.as_deref().unwrap_or(&self.topic_original);
+
+                    match producer
+                        .send(
+                            FutureRecord::to(source_topic)
+                                .payload(ev.payload().unwrap())
+                                .key(ev.key().unwrap_or(DEFAULT_MESSAGE_KEY.as_bytes()))
+                                .headers(with_source_topic(
+                                    OwnedHeaders::new()
+                                        .add("host-identifier-x", &metadata.host)
+                                        .add("message-id-x", &metadata.id)
+                                        .add("timestamp-x", &metadata.timestamp.to_string())
+                                        .add("retry-x", &metadata.retry.to_string())
+                                        .add("replay-x", &metadata.replay.to_string()),
+                                    &metadata,
+                                )),
+                            Duration::from_secs(0),
                         )
-                        .num_minutes();
-                        if deadline > 0 {
-                            warn!(
-                                "retry {} sleeping {} seconds",
-                                destination_topic,
-                                deadline as u64 * 60
-                            );
-                            let assignment = consumer.assignment().unwrap();
+                        .await
+                    {
+                        Err((e, _)) => error!(
+                            "Error occured while trying to produce message on retry topic: {}",
+                            e
+                        ),
+                        Ok(_) => {
+                            let offst = metadata.offset.unwrap();
 
-                            info!(
-                                "pausing {:?} for :{} seconds",
-                                assignment,
-                                deadline as u64 * 60
+                            offset_guard.stash_processed_offset(
+                                &consumer,
+                                &destination_topic,
+                                offst,
+                                metadata.partition.unwrap(),
                             );
-                            consumer
-                                .pause(&assignment)
-                                .unwrap_or_else(|e| error!("error while pausing consumer: {}", e));
-                            sleep(Duration::from_secs(deadline as u64 * 60)).await;
-                            consumer
-                                .resume(&assignment)
-                                .unwrap_or_else(|e| error!("error while resuming consumer: {}", e));
-                        }
-
-                        match producer
-                            .send(
-                                FutureRecord::to(&self.topic_original)
-                                    .payload(ev.payload().unwrap())
-                                    .key("KREWETKA")
-                                    .headers(
-                                        OwnedHeaders::new()
-                                            .add("host-identifier-x", &metadata.host)
-                                            .add("message-id-x", &metadata.id)
-                                            .add("timestamp-x", &metadata.timestamp.to_string())
-                                            .add("retry-x", &metadata.retry.to_string()),
-                                    ),
-                                Duration::from_secs(0),
-                            )
-                            .await
-                        {
-                            Err((e, _)) => error!(
-                                "Error occured while trying to produce message on retry topic: {}",
-                                e
-                            ),
-                            Ok(_) => {
-                                let offst = metadata.offset.unwrap();
-
-                                offset_guard.stash_processed_offset(
-                                    &consumer,
-                                    offst,
-                                    metadata.partition.unwrap(),
-                                );
-                            }
                         }
                     }
                 }
@@ -189,3 +259,79 @@ impl Retrier {
         guard_fut.await.unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retrier(tiers: &str) -> Retrier {
+        Retrier::new(
+            "broker:9092".to_owned(),
+            tiers.split(',').map(|t| t.parse().unwrap()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_retry_tiers_are_parsed() {
+        assert_eq!(
+            "5s".parse::<RetryTier>(),
+            Ok(RetryTier {
+                delay: Duration::from_secs(5)
+            })
+        );
+        assert_eq!(
+            "2m".parse::<RetryTier>().map(|t| t.delay),
+            Ok(Duration::from_secs(120))
+        );
+        assert_eq!(
+            "1h".parse::<RetryTier>().map(|t| t.topic("flows")),
+            Ok("flows.retry.1h".to_owned())
+        );
+        assert_eq!(
+            "90s".parse::<RetryTier>().map(|t| t.topic("flows")),
+            Ok("flows.retry.90s".to_owned())
+        );
+        assert!("5".parse::<RetryTier>().is_err());
+        assert!("s".parse::<RetryTier>().is_err());
+        assert!("".parse::<RetryTier>().is_err());
+        assert!("5d".parse::<RetryTier>().is_err());
+    }
+
+    #[test]
+    fn test_attempts_go_through_tiers_then_dead_letter() {
+        let retrier = retrier("5s,30s");
+        assert_eq!(
+            retrier.get_topic_based_on_retry(1),
+            Some("flows.retry.5s".to_owned())
+        );
+        assert_eq!(
+            retrier.get_topic_based_on_retry(2),
+            Some("flows.retry.30s".to_owned())
+        );
+        assert_eq!(
+            retrier.get_topic_based_on_retry(3),
+            Some("flows_dead_letter_queue".to_owned())
+        );
+        assert_eq!(retrier.get_topic_based_on_retry(4), None);
+    }
+
+    #[test]
+    fn test_without_tiers_failures_are_dead_lettered() {
+        let retrier = Retrier::new("broker:9092".to_owned(), vec![]);
+        assert_eq!(
+            retrier.get_topic_based_on_retry(1),
+            Some("flows_dead_letter_queue".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_retry_waits_until_due() {
+        let delay = Duration::from_secs(5);
+        assert_eq!(
+            retry_wait(1_000, delay, 2_000),
+            Duration::from_millis(4_000)
+        );
+        assert_eq!(retry_wait(1_000, delay, 6_000), Duration::ZERO);
+        assert_eq!(retry_wait(1_000, delay, 60_000), Duration::ZERO);
+    }
+}
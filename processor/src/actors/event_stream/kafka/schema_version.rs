@@ -0,0 +1,132 @@
+use crate::pb::FlowMessage;
+
+use prost::Message as PBMessage;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Version of payloads without the `schema-version-x` header, written before versions existed
+pub const DEFAULT_SCHEMA_VERSION: u32 = 1;
+
+/// Decodes a protobuf payload written with a single schema version into the current flow message
+pub type VersionDecoder = fn(&[u8]) -> Result<FlowMessage, prost::DecodeError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaVersionError {
+    InvalidHeader(String),
+    Unsupported(u32),
+}
+
+impl fmt::Display for SchemaVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidHeader(h) => write!(f, "invalid schema version header {h:?}"),
+            Self::Unsupported(v) => write!(f, "schema version {v} is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaVersionError {}
+
+/// Parses the value of the `schema-version-x` header, a missing header is the first version
+pub fn parse_version(header: Option<&[u8]>) -> Result<u32, SchemaVersionError> {
+    match header {
+        None => Ok(DEFAULT_SCHEMA_VERSION),
+        Some(h) => std::str::from_utf8(h)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .ok_or_else(|| SchemaVersionError::InvalidHeader(String::from_utf8_lossy(h).into())),
+    }
+}
+
+fn decode_v1(payload: &[u8]) -> Result<FlowMessage, prost::DecodeError> {
+    FlowMessage::decode(payload)
+}
+
+/// Decoders of every supported schema version, so a topic written by collectors of different
+/// versions during a rollout is decoded message by message. A new version is supported by
+/// registering its decoder in `default`
+pub struct SchemaDecoders {
+    decoders: BTreeMap<u32, VersionDecoder>,
+}
+
+impl Default for SchemaDecoders {
+    fn default() -> Self {
+        Self::empty().register(DEFAULT_SCHEMA_VERSION, decode_v1)
+    }
+}
+
+impl SchemaDecoders {
+    pub fn empty() -> Self {
+        Self {
+            decoders: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(mut self, version: u32, decoder: VersionDecoder) -> Self {
+        self.decoders.insert(version, decoder);
+        self
+    }
+
+    pub fn versions(&self) -> Vec<u32> {
+        self.decoders.keys().copied().collect()
+    }
+
+    /// Decoder of the version, unsupported versions are dead lettered
+    pub fn decoder(&self, version: u32) -> Result<VersionDecoder, SchemaVersionError> {
+        self.decoders
+            .get(&version)
+            .copied()
+            .ok_or(SchemaVersionError::Unsupported(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Version in which collectors sent ports swapped, only to exercise dispatching
+    fn decode_swapped_ports(payload: &[u8]) -> Result<FlowMessage, prost::DecodeError> {
+        let mut flow = FlowMessage::decode(payload)?;
+        std::mem::swap(&mut flow.l4_src_port, &mut flow.l4_dst_port);
+        Ok(flow)
+    }
+
+    #[test]
+    fn test_version_is_read_from_header() {
+        assert_eq!(parse_version(None), Ok(DEFAULT_SCHEMA_VERSION));
+        assert_eq!(parse_version(Some(b"2")), Ok(2));
+        assert_eq!(
+            parse_version(Some(b"v2")),
+            Err(SchemaVersionError::InvalidHeader("v2".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_messages_of_two_versions_are_decoded_by_their_decoder() {
+        let decoders = SchemaDecoders::default().register(2, decode_swapped_ports);
+        assert_eq!(decoders.versions(), vec![1, 2]);
+
+        let payload = FlowMessage {
+            l4_src_port: 51000,
+            l4_dst_port: 443,
+            ..Default::default()
+        }
+        .encode_to_vec();
+
+        let v1 = decoders.decoder(parse_version(None).unwrap()).unwrap()(&payload).unwrap();
+        assert_eq!((v1.l4_src_port, v1.l4_dst_port), (51000, 443));
+        let v2 = decoders
+            .decoder(parse_version(Some(b"2")).unwrap())
+            .unwrap()(&payload)
+        .unwrap();
+        assert_eq!((v2.l4_src_port, v2.l4_dst_port), (443, 51000));
+    }
+
+    #[test]
+    fn test_unknown_version_is_unsupported() {
+        assert!(matches!(
+            SchemaDecoders::default().decoder(3),
+            Err(SchemaVersionError::Unsupported(3))
+        ));
+    }
+}
@@ -1,7 +1,10 @@
 use crate::pb::FlowMessage;
 
 use super::event_stream::errors::EventStreamError;
+use crate::dead_letter::DeadLetter;
 use actix::Message;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
 
 pub enum ProcessedFinished {
     Ack(i64),
@@ -12,22 +15,35 @@ pub enum ProcessedFinished {
 #[rtype(result = "()")]
 #[derive(Clone)]
 pub enum AckMessage {
-    Ack(i64, i32),
+    /// topic, offset and partition of the acknowledged message
+    Ack(String, i64, i32),
     NackRetry(FlowMessageWithMetadata),
+    /// Message which would fail the same way however often retried, e.g. an overflowing counter
+    NackDeadLetter(FlowMessageWithMetadata, DeadLetter),
 }
 
 #[derive(Message)]
 #[rtype(result = "Result<(), ()>")]
 pub struct MessageFromEventStream(pub FlowMessageWithMetadata);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct FlowMessageMetadata {
     pub timestamp: u64,
     pub host: String,
     pub id: String,
     pub retry: usize,
+    pub replay: usize,
+    /// Topic the flow was originally consumed from, retried messages are routed back to it
+    pub topic: Option<String>,
     pub offset: Option<i64>,
     pub partition: Option<i32>,
+    /// Enrichment ran out of its budget, some stages did not enrich the flow
+    pub enrichment_timed_out: bool,
+    /// Source or destination port exceeded a scan detection threshold when the flow was seen
+    pub scan_suspected: bool,
+    /// Owners of the addresses found by the `assets` enrichment stage, empty when unknown
+    pub src_asset: String,
+    pub dst_asset: String,
 }
 
 // TODO move it to kafka dir
@@ -49,15 +65,77 @@ pub struct FlowMessageWithMetadata {
     pub metadata: FlowMessageMetadata,
 }
 
+impl FlowMessageWithMetadata {
+    /// Rough size of the flow waiting in the storage buffer, its numeric fields plus the length
+    /// of its strings. It is not the size of the stored row, e.g. addresses are stored in 4 bytes
+    /// and columns filled by enrichment are not counted, it only has to grow with the batch
+    pub fn estimated_bytes(&self) -> usize {
+        let f = &self.flow_message;
+        // out/in bytes and packets, flow duration
+        let u64_fields = 5 * std::mem::size_of::<u64>();
+        // l7 proto, ports, protocol, tcp flags
+        let u32_fields = 5 * std::mem::size_of::<u32>();
+        // malicious and timestamp
+        let metadata = 1 + std::mem::size_of::<u64>();
+
+        u64_fields
+            + u32_fields
+            + metadata
+            + f.ipv4_src_addr.len()
+            + f.ipv4_dst_addr.len()
+            + self.metadata.host.len()
+            + self.metadata.src_asset.len()
+            + self.metadata.dst_asset.len()
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 #[derive(Clone, Debug)]
 pub struct ClassifyFlowMessageWithMetadata(pub FlowMessageWithMetadata);
 
+/// Flow to be stored, optionally with an ack signalled once its batch is stashed
 #[derive(Message)]
 #[rtype(result = "()")]
 #[derive(Clone, Debug)]
-pub struct PersistFlowMessageWithMetadata(pub FlowMessageWithMetadata);
+pub struct PersistFlowMessageWithMetadata(pub FlowMessageWithMetadata, pub Option<PersistAck>);
+
+impl PersistFlowMessageWithMetadata {
+    /// The receiver resolves once the batch containing the flow is stashed, it is closed
+    /// without a result when the flow is lost before that, e.g. on shutdown
+    pub fn with_ack(
+        flow: FlowMessageWithMetadata,
+    ) -> (Self, oneshot::Receiver<Result<(), PersistError>>) {
+        let (tx, rx) = oneshot::channel();
+        (
+            Self(flow, Some(PersistAck(Arc::new(Mutex::new(Some(tx)))))),
+            rx,
+        )
+    }
+}
+
+/// Why a flow persisted with an ack was not stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PersistError {
+    /// the batch failed to be saved, its rows are sent to retry
+    BatchFailed,
+    /// the row was rejected from a saved batch and sent to retry
+    Rejected,
+}
+
+/// Sender of the persistence result, messages issued through the broker are cloned for every
+/// subscriber so clones share it and only the first result is sent
+#[derive(Clone, Debug)]
+pub struct PersistAck(Arc<Mutex<Option<oneshot::Sender<Result<(), PersistError>>>>>);
+
+impl PersistAck {
+    pub fn send(&self, result: Result<(), PersistError>) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            // the caller may have stopped waiting
+            let _ = tx.send(result);
+        }
+    }
+}
 
 impl From<FlowMessageWithMetadata> for ClassifyFlowMessageWithMetadata {
     fn from(original_flow_message: FlowMessageWithMetadata) -> Self {
@@ -67,7 +145,13 @@ impl From<FlowMessageWithMetadata> for ClassifyFlowMessageWithMetadata {
 
 impl From<ClassifyFlowMessageWithMetadata> for PersistFlowMessageWithMetadata {
     fn from(original_flow_message: ClassifyFlowMessageWithMetadata) -> Self {
-        Self(original_flow_message.0)
+        Self(original_flow_message.0, None)
+    }
+}
+
+impl From<FlowMessageWithMetadata> for PersistFlowMessageWithMetadata {
+    fn from(original_flow_message: FlowMessageWithMetadata) -> Self {
+        Self(original_flow_message, None)
     }
 }
 
@@ -1,9 +1,11 @@
 // pub mod acker;
+pub mod aggregation;
 pub mod broker;
 pub mod classification_client_grpc;
 pub mod consts;
 pub mod event_stream;
 pub mod messages;
 pub mod storage;
+pub mod suspicious_stream;
 
 type BrokerType = actix_broker::SystemBroker;
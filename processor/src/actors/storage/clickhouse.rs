@@ -1,26 +1,178 @@
-use super::storage_actor::{AStorage, StorageError};
+use super::storage_actor::{AStorage, InsertResult, StorageError};
 // use crate::actors::acknowleger::messages::PutOnRetryMessage;
 
 use crate::actors::messages::AckMessage;
+use crate::dead_letter::{DeadLetter, DeadLetterReason};
 
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
-use clickhouse_rs::{row, types::Block, Pool};
+use super::insert_settings::InsertSettings;
+use super::routing::{self, Route};
+use super::rowbinary;
+use super::schema::{
+    self, ColumnCoercions, ColumnIndexes, CounterOverflow, MissingDefaults, NullSentinels,
+    RowSettings, SchemaError,
+};
+use super::sharding::{self, InsertMode};
+use super::sorting::{self, SortField};
+use super::tiers::TierRule;
+use clickhouse_rs::{types::Block, Pool};
+use futures::future::join_all;
 use futures::stream::StreamExt;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use log::error;
+use log::{error, info, warn};
 use serde::Deserialize;
 
 use crate::actors::messages::FlowMessageWithMetadata;
+use crate::keys::KeyField;
+use crate::metrics::{CLICKHOUSE_HANDLE_WAIT_SECONDS, CLICKHOUSE_POOL_EXHAUSTED};
+use crate::privacy::Redaction;
 use async_trait::async_trait;
 use std::sync::Arc;
 
+/// Header with statistics of a finished query sent by the http interface
+const CLICKHOUSE_SUMMARY_HEADER: &str = "X-ClickHouse-Summary";
+/// Encoded rows are sent in chunks of about this size while the batch is encoded
+const ROW_BINARY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Rows written according to the query summary, e.g. `{"written_rows":"3",...}`
+fn written_rows(summary: &str) -> Option<usize> {
+    serde_json::from_str::<HashMap<String, String>>(summary)
+        .ok()?
+        .get("written_rows")?
+        .parse()
+        .ok()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct ClickhouseSettings {
     host: String,
     port: u16,
     user: String,
     password: String,
+    #[serde(default)]
+    null_sentinels: NullSentinels,
+    #[serde(default)]
+    missing_defaults: MissingDefaults,
+    /// Columns stored with a type different from the `messages` schema
+    #[serde(default)]
+    column_types: ColumnCoercions,
+    /// Skipping indexes of tables created when they are missing, none without them
+    #[serde(default)]
+    indexes: ColumnIndexes,
+    /// Overrides the overflow of counters coerced to narrower numeric types
+    #[serde(default)]
+    on_counter_overflow: Option<CounterOverflow>,
+    #[serde(default)]
+    insert_format: InsertFormat,
+    #[serde(default = "default_http_port")]
+    http_port: u16,
+    #[serde(default)]
+    create_table_if_missing: bool,
+    #[serde(default = "default_pool_min")]
+    pool_min: usize,
+    #[serde(default = "default_pool_max")]
+    pool_max: usize,
+    #[serde(default = "default_handle_timeout_ms")]
+    handle_timeout_ms: u64,
+    /// `pool_min` connections are opened on start up instead of by the first inserts
+    #[serde(default)]
+    warmup_connections: bool,
+    /// Routes choosing the table of each flow, everything is stored in `messages` without them
+    #[serde(default, deserialize_with = "routing::deserialize_rules")]
+    table_routes: Vec<Route>,
+    /// Rules choosing the storage tier of each flow, everything is in the default tier without them
+    #[serde(default, deserialize_with = "routing::deserialize_rules")]
+    tier_rules: Vec<TierRule>,
+    /// Field the `shard_key` column is derived from, there is no such column without it
+    #[serde(default)]
+    shard_key: Option<KeyField>,
+    /// Replica reads are sent to, e.g. readback and backfill, reads go to `host` without it
+    #[serde(default)]
+    read_host: Option<String>,
+    /// Native port of the read replica, `port` when not set
+    #[serde(default)]
+    read_port: Option<u16>,
+    /// Fields every flush buffer is sorted by before it is inserted, left in arrival order without them
+    #[serde(default, deserialize_with = "sorting::deserialize_fields")]
+    sort_buffer_by: Vec<SortField>,
+    /// Clickhouse settings applied to every insert, e.g. `async_insert`, server defaults without them
+    #[serde(default)]
+    insert_settings: InsertSettings,
+    #[serde(default)]
+    insert_mode: InsertMode,
+    /// Hosts of the shards `direct-shard` inserts go to, they share `port` and `http_port`
+    #[serde(default, deserialize_with = "sharding::deserialize_shards")]
+    shards: Vec<String>,
+    /// Suffix of the local table behind each `Distributed` one, e.g. `messages_local`
+    #[serde(default = "default_local_table_suffix")]
+    local_table_suffix: String,
+}
+
+/// Part of clickhouse settings shaping the tables, enough to print their DDL without a server
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct TableSettings {
+    #[serde(default)]
+    column_types: ColumnCoercions,
+    #[serde(default)]
+    indexes: ColumnIndexes,
+    #[serde(default)]
+    on_counter_overflow: Option<CounterOverflow>,
+    #[serde(default, deserialize_with = "routing::deserialize_rules")]
+    table_routes: Vec<Route>,
+    #[serde(default)]
+    shard_key: Option<KeyField>,
+}
+
+impl TableSettings {
+    /// `CREATE TABLE` statements of every table flows are stored in
+    pub fn ddl(&self) -> String {
+        let coercions = self
+            .column_types
+            .clone()
+            .with_counter_overflow(self.on_counter_overflow);
+        routing::tables(&self.table_routes)
+            .into_iter()
+            .map(|table| {
+                format!(
+                    "{};\n",
+                    schema::create_table_ddl(table, &coercions, &self.indexes, self.shard_key)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+fn default_http_port() -> u16 {
+    8123
+}
+
+fn default_pool_min() -> usize {
+    10
+}
+
+fn default_pool_max() -> usize {
+    20
+}
+
+fn default_handle_timeout_ms() -> u64 {
+    3000
+}
+
+fn default_local_table_suffix() -> String {
+    sharding::DEFAULT_LOCAL_TABLE_SUFFIX.to_owned()
+}
+
+/// Format in which batches are sent to clickhouse.
+/// `RowBinary` goes through the HTTP interface, as the native protocol only accepts blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InsertFormat {
+    #[default]
+    Block,
+    RowBinary,
 }
 
 impl From<ClickhouseSettings> for ClickhouseState {
@@ -31,63 +183,430 @@ impl From<ClickhouseSettings> for ClickhouseState {
 
 impl std::fmt::Display for ClickhouseSettings {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "tcp://{}:{}@{}:{}/default?compression=lz4&send_retries=0",
-            self.user, self.password, self.host, self.port
+        write!(f, "{}", self.dsn(&self.host, self.port))
+    }
+}
+
+impl ClickhouseSettings {
+    fn dsn(&self, host: &str, port: u16) -> String {
+        format!(
+            "tcp://{}:{}@{}:{}/default?compression=lz4&send_retries=0&pool_min={}&pool_max={}",
+            self.user, self.password, host, port, self.pool_min, self.pool_max
         )
     }
+
+    /// Dsn of native inserts. Clickhouse-rs sends parameters it does not know as settings of
+    /// every query, so insert settings are set once for the pool instead of before each insert
+    fn insert_dsn(&self, host: &str, port: u16) -> String {
+        let mut dsn = self.dsn(host, port);
+        for (name, value) in self.insert_settings.pairs() {
+            dsn.push_str(&format!("&{}={}", name, value));
+        }
+        dsn
+    }
+
+    /// Dsn of the read replica, `None` when reads go to the write endpoint
+    fn read_dsn(&self) -> Option<String> {
+        if self.read_host.is_none() && self.read_port.is_none() {
+            return None;
+        }
+        let host = self.read_host.as_deref().unwrap_or(&self.host);
+        Some(self.dsn(host, self.read_port.unwrap_or(self.port)))
+    }
+
+    fn row_binary_insert_url(&self, host: &str) -> String {
+        format!("http://{}:{}/", host, self.http_port)
+    }
+
+    fn local_table(&self, table: &str) -> String {
+        format!("{}{}", table, self.local_table_suffix)
+    }
+
+    /// A random key places rows apart from the kafka partitions of their flows
+    fn check_shard_key(&self) -> Result<(), SchemaError> {
+        if self.shard_key == Some(KeyField::Random) {
+            return Err(SchemaError::Sharding(
+                "shard_key can not be random".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `direct-shard` inserts need to know the shards and how rows are spread over them
+    fn check_insert_mode(&self) -> Result<(), SchemaError> {
+        if self.insert_mode != InsertMode::DirectShard {
+            return Ok(());
+        }
+        if self.shard_key.is_none() {
+            return Err(SchemaError::Sharding(
+                "direct-shard inserts require shard_key".to_owned(),
+            ));
+        }
+        if self.shards.is_empty() {
+            return Err(SchemaError::Sharding(
+                "direct-shard inserts require shards".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Node a batch is inserted on
+struct Endpoint<'a> {
+    pool: &'a Pool,
+    host: &'a str,
 }
 
 pub struct ClickhouseState {
     pub settings: ClickhouseSettings,
+    /// Inserts, schema verification and migrations
     pub pool: Arc<Pool>,
+    /// Reads, the same pool as `pool` without a read replica
+    pub read_pool: Arc<Pool>,
+    /// Inserts of `direct-shard` mode, one per host of `shards`
+    shard_pools: Vec<Pool>,
+    http: reqwest::Client,
+    redaction: Redaction,
+    /// set once `pool_min` connections were opened, or right away without warmup
+    warm: AtomicBool,
 }
 
 impl ClickhouseState {
-    pub fn new(settings: ClickhouseSettings) -> Self {
-        let dsn = settings.to_string();
-        let pool = Arc::new(Pool::new(dsn));
+    pub fn new(mut settings: ClickhouseSettings) -> Self {
+        settings.column_types = settings
+            .column_types
+            .clone()
+            .with_counter_overflow(settings.on_counter_overflow);
+        let pool = Arc::new(Pool::new(
+            settings.insert_dsn(&settings.host, settings.port),
+        ));
+        // reads do not share connections carrying insert settings
+        let read_pool = match settings.read_dsn() {
+            Some(read_dsn) => Arc::new(Pool::new(read_dsn)),
+            None if settings.insert_settings.is_empty() => pool.clone(),
+            None => Arc::new(Pool::new(settings.to_string())),
+        };
+        let shard_pools = match settings.insert_mode {
+            InsertMode::DirectShard => settings
+                .shards
+                .iter()
+                .map(|host| Pool::new(settings.insert_dsn(host, settings.port)))
+                .collect(),
+            InsertMode::Distributed => Vec::new(),
+        };
+        let http = reqwest::Client::new();
 
-        Self { settings, pool }
+        Self {
+            pool,
+            read_pool,
+            shard_pools,
+            http,
+            redaction: Redaction::default(),
+            warm: AtomicBool::new(!settings.warmup_connections),
+            settings,
+        }
     }
 
-    fn push_to_block(block: &mut Block, f: &FlowMessageWithMetadata) -> AckMessage {
-        let ts_secs = f.metadata.timestamp / 1000;
-        let ts_ns = f.metadata.timestamp % 1000 * 1_000_000;
+    /// Flows are redacted right before their rows are built, the ones sent to retry are intact
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
 
-        match block.push(row! {
-           host: f.metadata.host.as_str(),
-           out_bytes: f.flow_message.out_bytes,
-           out_pkts:   f.flow_message.out_pkts,
-           in_bytes:   f.flow_message.in_bytes,
-           in_pkts:    f.flow_message.in_pkts,
-           ipv4_src_addr: f.flow_message.ipv4_src_addr.as_str(),
-           ipv4_dst_addr: f.flow_message.ipv4_dst_addr.as_str(),
-           l7_proto:       f.flow_message.l7_proto,
-           l4_dst_port:    f.flow_message.l4_dst_port,
-           l4_src_port:    f.flow_message.l4_src_port,
-           flow_duration_milliseconds: f.flow_message.flow_duration_milliseconds,
-           protocol:       f.flow_message.protocol,
-           tcp_flags:      f.flow_message.tcp_flags,
-           malicious:      f.malicious.unwrap_or(false),
-           timestamp:      DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(ts_secs as i64, ts_ns as u32), Utc)
-        }) {
-            Ok(()) => AckMessage::Ack(f.metadata.offset.unwrap(), f.metadata.partition.unwrap()),
-            Err(_e) => AckMessage::NackRetry(f.to_owned()),
+    fn row_settings(&self) -> RowSettings<'_> {
+        RowSettings {
+            sentinels: &self.settings.null_sentinels,
+            defaults: &self.settings.missing_defaults,
+            tiers: &self.settings.tier_rules,
+            coercions: &self.settings.column_types,
+            redaction: &self.redaction,
+            shard_key: self.settings.shard_key,
         }
     }
-}
 
-#[async_trait]
-impl AStorage for ClickhouseState {
-    async fn stash(
+    /// Field both the shard key and keys of messages sent to retry topics are derived from
+    pub fn shard_key(&self) -> Option<KeyField> {
+        self.settings.shard_key
+    }
+
+    /// Succeeds when a pooled connection answers, used by the readiness check
+    pub async fn ping(&self) -> Result<(), clickhouse_rs::errors::Error> {
+        let mut client = self.pool.get_handle().await?;
+        client.ping().await
+    }
+
+    /// Opens `pool_min` connections of every pool inserts go through at once, i.e. of every
+    /// shard with `direct-shard` inserts, so the first inserts do not wait for them.
+    /// Every connection is held until all of them answered, otherwise the pool would reuse one
+    pub async fn warm_up(&self) -> Result<(), String> {
+        if self.warm.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        let handle_timeout = Duration::from_millis(self.settings.handle_timeout_ms);
+        let pools = std::iter::once(self.pool.as_ref()).chain(self.shard_pools.iter());
+        let connections = pools
+            .flat_map(|pool| (0..self.settings.pool_min).map(move |_| pool))
+            .map(|pool| async move {
+                let mut client = tokio::time::timeout(handle_timeout, pool.get_handle())
+                    .await
+                    .map_err(|_| format!("connection not opened within {:?}", handle_timeout))?
+                    .map_err(|e| e.to_string())?;
+                client.ping().await.map_err(|e| e.to_string())?;
+                Ok::<_, String>(client)
+            });
+        let clients = futures::future::try_join_all(connections).await?;
+
+        info!(
+            "opened {} clickhouse connections in {:?}",
+            clients.len(),
+            started.elapsed()
+        );
+        self.warm.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Checks that `messages` and all tables flows are routed to match what `stash` inserts.
+    /// A missing table is created when `create_table_if_missing` is set
+    /// With `direct-shard` inserts the local tables are checked on every shard as well
+    pub async fn verify_schema(&self) -> Result<(), SchemaError> {
+        self.settings.check_shard_key()?;
+        self.settings.check_insert_mode()?;
+        for table in routing::tables(&self.settings.table_routes) {
+            self.verify_table_schema(&self.pool, table).await?;
+            for pool in &self.shard_pools {
+                self.verify_table_schema(pool, &self.settings.local_table(table))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn verify_table_schema(&self, pool: &Pool, table: &str) -> Result<(), SchemaError> {
+        let mut client = pool
+            .get_handle()
+            .await
+            .map_err(|e| SchemaError::Database(e.to_string()))?;
+
+        let block = client
+            .query(format!(
+                "SELECT name, type FROM system.columns \
+                WHERE database = currentDatabase() AND table = '{}'",
+                table
+            ))
+            .fetch_all()
+            .await
+            .map_err(|e| SchemaError::Database(e.to_string()))?;
+
+        let columns = block
+            .rows()
+            .map(|r| Ok((r.get::<String, _>("name")?, r.get::<String, _>("type")?)))
+            .collect::<Result<HashMap<String, String>, clickhouse_rs::errors::Error>>()
+            .map_err(|e| SchemaError::Database(e.to_string()))?;
+
+        if columns.is_empty() {
+            if !self.settings.create_table_if_missing {
+                return Err(SchemaError::TableMissing(table.to_owned()));
+            }
+
+            info!("table {} does not exist, creating it", table);
+            return client
+                .execute(schema::create_table_ddl(
+                    table,
+                    &self.settings.column_types,
+                    &self.settings.indexes,
+                    self.settings.shard_key,
+                ))
+                .await
+                .map_err(|e| SchemaError::Database(e.to_string()));
+        }
+
+        let mismatches = schema::compare_columns(
+            &columns,
+            &self.settings.column_types,
+            self.settings.shard_key,
+        );
+        if !mismatches.is_empty() {
+            return Err(SchemaError::Mismatch(table.to_owned(), mismatches));
+        }
+
+        Ok(())
+    }
+
+    fn push_to_block(
+        block: &mut Block,
+        f: &FlowMessageWithMetadata,
+        row: &RowSettings,
+    ) -> AckMessage {
+        let row = match schema::build_row(f, row) {
+            Ok(row) => row,
+            Err(e) => return ClickhouseState::unbuilt(f, e),
+        };
+
+        match block.push(row) {
+            Ok(()) => AckMessage::Ack(
+                f.metadata.topic.clone().unwrap(),
+                f.metadata.offset.unwrap(),
+                f.metadata.partition.unwrap(),
+            ),
+            Err(e) => {
+                error!("unable to push message {}: {}", f.metadata.id, e);
+                AckMessage::NackRetry(f.to_owned())
+            }
+        }
+    }
+
+    /// Rows which overflow with the `dead_letter` overflow skip the retries, they would never fit
+    fn unbuilt(f: &FlowMessageWithMetadata, e: SchemaError) -> AckMessage {
+        error!("unable to build row for message {}: {}", f.metadata.id, e);
+        match e {
+            SchemaError::Overflow(..) => AckMessage::NackDeadLetter(
+                f.to_owned(),
+                DeadLetter::new(DeadLetterReason::ValidationFailed, &e),
+            ),
+            _ => AckMessage::NackRetry(f.to_owned()),
+        }
+    }
+
+    /// Encodes the row value by value, a failed row is cut off so it does not leave partial data
+    fn push_to_row_binary(
+        buf: &mut Vec<u8>,
+        f: &FlowMessageWithMetadata,
+        row: &RowSettings,
+    ) -> AckMessage {
+        let start = buf.len();
+        let mut encoded = Ok(());
+        let built = schema::for_each_value(f, row, |column, value| {
+            if encoded.is_ok() {
+                encoded = rowbinary::encode_column(column, value, buf);
+            }
+        });
+
+        match (built, encoded) {
+            (Ok(()), Ok(())) => AckMessage::Ack(
+                f.metadata.topic.clone().unwrap(),
+                f.metadata.offset.unwrap(),
+                f.metadata.partition.unwrap(),
+            ),
+            (Err(e), _) => {
+                buf.truncate(start);
+                ClickhouseState::unbuilt(f, e)
+            }
+            (Ok(()), Err(e)) => {
+                buf.truncate(start);
+                error!("unable to encode message {}: {}", f.metadata.id, e);
+                AckMessage::NackRetry(f.to_owned())
+            }
+        }
+    }
+
+    /// Writes rows into the body of the insert while it is being sent, so only a chunk of the
+    /// batch is encoded at a time. Writing stops once the request fails
+    async fn write_row_binary(
+        &self,
+        mut body: hyper::body::Sender,
+        msgs: &[FlowMessageWithMetadata],
+    ) -> Vec<AckMessage> {
+        let row = self.row_settings();
+        let mut acks = Vec::with_capacity(msgs.len());
+        let mut chunk = Vec::with_capacity(ROW_BINARY_CHUNK_BYTES);
+        for f in msgs {
+            acks.push(ClickhouseState::push_to_row_binary(&mut chunk, f, &row));
+            if chunk.len() >= ROW_BINARY_CHUNK_BYTES {
+                let full =
+                    std::mem::replace(&mut chunk, Vec::with_capacity(ROW_BINARY_CHUNK_BYTES));
+                if body.send_data(full.into()).await.is_err() {
+                    return acks;
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = body.send_data(chunk.into()).await;
+        }
+        // dropping the sender ends the body
+        acks
+    }
+
+    async fn stash_row_binary(
+        &self,
+        host: &str,
+        table: &str,
+        msgs: Vec<FlowMessageWithMetadata>,
+    ) -> Result<InsertResult, StorageError> {
+        let query = format!(
+            "INSERT INTO {} ({}) FORMAT RowBinary",
+            table,
+            schema::column_names(self.settings.shard_key).join(", ")
+        );
+
+        let (sender, body) = hyper::Body::channel();
+        let request = self
+            .http
+            .post(self.settings.row_binary_insert_url(host))
+            .query(&[("query", query)])
+            .query(&self.settings.insert_settings.pairs())
+            .basic_auth(&self.settings.user, Some(&self.settings.password))
+            .timeout(Duration::from_secs(30))
+            .body(body)
+            .send();
+        let (acks, response) = tokio::join!(self.write_row_binary(sender, &msgs), request);
+        let response = response.and_then(|r| r.error_for_status());
+
+        let encoded = acks
+            .iter()
+            .filter(|a| matches!(a, AckMessage::Ack(..)))
+            .count();
+
+        match response {
+            Ok(r) => {
+                let inserted = r
+                    .headers()
+                    .get(CLICKHOUSE_SUMMARY_HEADER)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(written_rows)
+                    .unwrap_or(encoded);
+                if inserted != encoded {
+                    warn!("clickhouse wrote {} rows out of {} sent", inserted, encoded);
+                }
+                Ok(InsertResult::new(acks, inserted))
+            }
+            Err(e) => {
+                error!("unable to insert messages to clickhouse: {}", e);
+                let nacks = msgs
+                    .into_iter()
+                    .map(AckMessage::NackRetry)
+                    .collect::<Vec<AckMessage>>();
+                Err(StorageError::DatabaseSave((Box::new(e), nacks)))
+            }
+        }
+    }
+
+    fn build_block(&self, msgs: &[FlowMessageWithMetadata]) -> (Block, Vec<AckMessage>) {
+        let mut block = Block::with_capacity(msgs.len());
+        let row = self.row_settings();
+
+        let acks = msgs
+            .iter()
+            .map(|f| ClickhouseState::push_to_block(&mut block, f, &row))
+            .collect::<Vec<AckMessage>>();
+        (block, acks)
+    }
+
+    async fn stash_block(
         &self,
+        pool: &Pool,
+        table: &str,
         msgs: Vec<FlowMessageWithMetadata>,
-    ) -> Result<Vec<AckMessage>, StorageError> {
-        let handler = self.pool.as_ref().get_handle();
+    ) -> Result<InsertResult, StorageError> {
+        let handler = pool.get_handle();
+        let handle_timeout = Duration::from_millis(self.settings.handle_timeout_ms);
+
+        let started = Instant::now();
+        let handle = tokio::time::timeout(handle_timeout, handler).await;
+        CLICKHOUSE_HANDLE_WAIT_SECONDS.observe(started.elapsed().as_secs_f64());
 
-        let mut client = match tokio::time::timeout(Duration::from_secs(3), handler).await {
+        let mut client = match handle {
             Ok(res) => res.map_err(|e| {
                 StorageError::DatabaseSave((
                     Box::new(e),
@@ -97,25 +616,26 @@ impl AStorage for ClickhouseState {
                         .collect::<Vec<AckMessage>>(),
                 ))
             })?,
-            Err(e) => {
-                return Err(StorageError::DatabaseSave((
-                    Box::new(e),
+            Err(_) => {
+                CLICKHOUSE_POOL_EXHAUSTED.inc();
+                error!(
+                    "unable to acquire clickhouse connection within {:?}",
+                    handle_timeout
+                );
+                return Err(StorageError::Timeout(
                     msgs.into_iter()
                         .map(AckMessage::NackRetry)
                         .collect::<Vec<AckMessage>>(),
-                )))
+                ));
             }
         };
 
-        let mut block = Block::with_capacity(msgs.len());
+        let (block, acks) = self.build_block(&msgs);
 
-        let acks = msgs
-            .iter()
-            .map(|f| ClickhouseState::push_to_block(&mut block, f))
-            .collect::<Vec<AckMessage>>();
-
-        match client.insert("messages", block).await {
-            Ok(()) => Ok(acks),
+        // native protocol does not report written rows, all rows of the block are accepted or none
+        let inserted = block.row_count();
+        match client.insert(table, block).await {
+            Ok(()) => Ok(InsertResult::new(acks, inserted)),
             Err(e) => {
                 error!("unable to insert messages to clickhouse: {}", e);
                 let nacks = msgs
@@ -126,4 +646,486 @@ impl AStorage for ClickhouseState {
             }
         }
     }
+
+    async fn insert(
+        &self,
+        endpoint: Endpoint<'_>,
+        table: &str,
+        mut msgs: Vec<FlowMessageWithMetadata>,
+    ) -> Result<InsertResult, StorageError> {
+        sorting::sort(&self.settings.sort_buffer_by, &mut msgs);
+        match self.settings.insert_format {
+            InsertFormat::Block => self.stash_block(endpoint.pool, table, msgs).await,
+            InsertFormat::RowBinary => self.stash_row_binary(endpoint.host, table, msgs).await,
+        }
+    }
+
+    async fn stash_table(
+        &self,
+        table: &str,
+        msgs: Vec<FlowMessageWithMetadata>,
+    ) -> Result<InsertResult, StorageError> {
+        let (key, shards) = match (self.settings.insert_mode, self.settings.shard_key) {
+            (InsertMode::DirectShard, Some(key)) if !self.shard_pools.is_empty() => {
+                (key, self.shard_pools.len())
+            }
+            _ => {
+                let endpoint = Endpoint {
+                    pool: &self.pool,
+                    host: &self.settings.host,
+                };
+                return self.insert(endpoint, table, msgs).await;
+            }
+        };
+
+        // shards are written at once, a slow shard does not hold back inserts into the others
+        let local_table = self.settings.local_table(table);
+        let inserts =
+            sharding::split_by_shard(key, shards, msgs)
+                .into_iter()
+                .map(|(shard, msgs)| {
+                    let endpoint = Endpoint {
+                        pool: &self.shard_pools[shard],
+                        host: &self.settings.shards[shard],
+                    };
+                    self.insert(endpoint, &local_table, msgs)
+                });
+        merge_results(join_all(inserts).await)
+    }
+}
+
+/// Combines results of inserts of a single batch, e.g. into several tables. Rows of inserts
+/// which failed are retried like rows which were rejected, the batch fails only when nothing
+/// was inserted. A single result is returned as it is
+fn merge_results(
+    results: Vec<Result<InsertResult, StorageError>>,
+) -> Result<InsertResult, StorageError> {
+    if results.len() == 1 {
+        return results.into_iter().next().unwrap();
+    }
+
+    let mut acks = Vec::new();
+    let mut inserted = 0;
+    let mut last_error = None;
+    for result in results {
+        match result {
+            Ok(result) => {
+                inserted += result.inserted;
+                acks.extend(result.acks);
+            }
+            Err(StorageError::DatabaseSave((e, nacks))) => {
+                acks.extend(nacks);
+                last_error = Some(e);
+            }
+            Err(StorageError::Timeout(nacks)) => {
+                acks.extend(nacks);
+                last_error = Some("timed out acquiring connection".into());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    match last_error {
+        Some(e) if inserted == 0 => Err(StorageError::DatabaseSave((e, acks))),
+        _ => Ok(InsertResult::new(acks, inserted)),
+    }
+}
+
+#[async_trait]
+impl AStorage for ClickhouseState {
+    async fn stash(
+        &self,
+        msgs: Vec<FlowMessageWithMetadata>,
+    ) -> Result<InsertResult, StorageError> {
+        let batches = routing::split_by_table(&self.settings.table_routes, msgs);
+
+        let mut results = Vec::new();
+        for (table, msgs) in batches {
+            results.push(self.stash_table(&table, msgs).await);
+        }
+        merge_results(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn settings(extra: serde_json::Value) -> ClickhouseSettings {
+        let mut value = serde_json::json!({
+            "host": "clickhouse",
+            "port": 9000,
+            "user": "default",
+            "password": "",
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value::<ClickhouseSettings>(value).unwrap()
+    }
+
+    #[test]
+    fn test_written_rows_from_summary() {
+        assert_eq!(
+            written_rows(
+                r#"{"read_rows":"0","read_bytes":"0","written_rows":"3","written_bytes":"120","total_rows_to_read":"0"}"#
+            ),
+            Some(3)
+        );
+        assert_eq!(written_rows(r#"{"read_rows":"0"}"#), None);
+        assert_eq!(written_rows("not json"), None);
+    }
+
+    #[test]
+    fn test_ddl_of_every_routed_table() {
+        let settings = TableSettings {
+            table_routes: vec!["malicious=true->messages_flagged".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let ddl = settings.ddl();
+        assert!(ddl.starts_with("CREATE TABLE IF NOT EXISTS messages ("));
+        assert!(ddl.contains(";\n\nCREATE TABLE IF NOT EXISTS messages_flagged ("));
+        assert!(ddl.ends_with("ORDER BY (timestamp, host);\n"));
+    }
+
+    #[test]
+    fn test_insert_settings_stay_on_insert_pools() {
+        let plain = settings(serde_json::json!({}));
+        assert_eq!(plain.insert_dsn("clickhouse", 9000), plain.to_string());
+        let state = ClickhouseState::new(plain);
+        assert!(Arc::ptr_eq(&state.pool, &state.read_pool));
+
+        let tuned = settings(serde_json::json!({
+            "insert_settings": {"async_insert": "1", "wait_for_async_insert": "1"},
+        }));
+        assert_eq!(
+            tuned.insert_dsn("clickhouse", 9000),
+            format!("{}&async_insert=1&wait_for_async_insert=1", tuned)
+        );
+        let state = ClickhouseState::new(tuned);
+        assert!(!Arc::ptr_eq(&state.pool, &state.read_pool));
+    }
+
+    #[test]
+    fn test_reads_go_to_replica_when_configured() {
+        assert_eq!(settings(serde_json::json!({})).read_dsn(), None);
+
+        let replica = settings(serde_json::json!({"read_host": "clickhouse-replica"}));
+        assert!(replica.to_string().contains("@clickhouse:9000/"));
+        assert!(replica
+            .read_dsn()
+            .unwrap()
+            .contains("@clickhouse-replica:9000/"));
+
+        let other_port = settings(serde_json::json!({"read_host": "replica", "read_port": 9440}));
+        assert!(other_port.read_dsn().unwrap().contains("@replica:9440/"));
+    }
+
+    #[test]
+    fn test_random_shard_key_is_refused() {
+        for (key, refused) in [("random", true), ("host", false), ("five_tuple", false)] {
+            let s = settings(serde_json::json!({ "shard_key": key }));
+            assert_eq!(
+                matches!(s.check_shard_key(), Err(SchemaError::Sharding(_))),
+                refused,
+                "{key}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_direct_shard_inserts_need_shards_and_key() {
+        let distributed = settings(serde_json::json!({}));
+        assert_eq!(distributed.insert_mode, InsertMode::Distributed);
+        assert!(distributed.check_insert_mode().is_ok());
+
+        let direct = settings(serde_json::json!({
+            "insert_mode": "direct-shard",
+            "shards": "shard-1, shard-2",
+            "shard_key": "host",
+        }));
+        assert!(direct.check_insert_mode().is_ok());
+        assert_eq!(direct.shards, vec!["shard-1", "shard-2"]);
+        assert_eq!(direct.local_table("messages"), "messages_local");
+        assert_eq!(
+            direct.row_binary_insert_url("shard-2"),
+            "http://shard-2:8123/"
+        );
+        assert_eq!(ClickhouseState::new(direct).shard_pools.len(), 2);
+
+        let without_key = settings(serde_json::json!({
+            "insert_mode": "direct-shard",
+            "shards": "shard-1",
+        }));
+        assert!(matches!(
+            without_key.check_insert_mode(),
+            Err(SchemaError::Sharding(_))
+        ));
+        let without_shards = settings(serde_json::json!({
+            "insert_mode": "direct-shard",
+            "shard_key": "host",
+        }));
+        assert!(matches!(
+            without_shards.check_insert_mode(),
+            Err(SchemaError::Sharding(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_row_binary_rows_are_written_into_the_body() {
+        let state = ClickhouseState::new(settings(serde_json::json!({})));
+        // enough rows for several chunks
+        let msgs = (0..5000)
+            .map(|i| {
+                testutil::flow()
+                    .with_host(&format!("edge-{}", i))
+                    .with_offset(i)
+                    .build()
+            })
+            .collect::<Vec<FlowMessageWithMetadata>>();
+
+        let mut expected = Vec::new();
+        for f in &msgs {
+            for (column, value) in schema::build_row(f, &state.row_settings()).unwrap() {
+                rowbinary::encode_column(&column, &value, &mut expected).unwrap();
+            }
+        }
+        assert!(expected.len() > 2 * ROW_BINARY_CHUNK_BYTES);
+
+        let (sender, body) = hyper::Body::channel();
+        let (acks, sent) = tokio::join!(
+            state.write_row_binary(sender, &msgs),
+            hyper::body::to_bytes(body)
+        );
+        assert_eq!(sent.unwrap().as_ref(), expected.as_slice());
+        assert!(acks.iter().all(|a| matches!(a, AckMessage::Ack(..))));
+        assert_eq!(acks.len(), msgs.len());
+    }
+
+    #[test]
+    fn test_block_is_sorted_by_configured_fields() {
+        let state = ClickhouseState::new(settings(
+            serde_json::json!({"sort_buffer_by": "host, timestamp"}),
+        ));
+        let mut msgs = vec![
+            testutil::flow()
+                .with_host("edge-2")
+                .with_timestamp(20)
+                .with_offset(0)
+                .build(),
+            testutil::flow()
+                .with_host("edge-1")
+                .with_timestamp(30)
+                .with_offset(1)
+                .build(),
+            testutil::flow()
+                .with_host("edge-2")
+                .with_timestamp(10)
+                .with_offset(2)
+                .build(),
+            testutil::flow()
+                .with_host("edge-1")
+                .with_timestamp(10)
+                .with_offset(3)
+                .build(),
+        ];
+
+        sorting::sort(&state.settings.sort_buffer_by, &mut msgs);
+        let (block, acks) = state.build_block(&msgs);
+
+        let rows = block
+            .rows()
+            .map(|r| r.get::<String, _>("host").unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(rows, vec!["edge-1", "edge-1", "edge-2", "edge-2"]);
+        // acks follow the rows, every flow is acked whatever its position
+        let offsets = acks
+            .iter()
+            .map(|a| match a {
+                AckMessage::Ack(_, offset, _) => *offset,
+                _ => panic!("row was not pushed"),
+            })
+            .collect::<Vec<i64>>();
+        assert_eq!(offsets, vec![3, 1, 2, 0]);
+    }
+
+    type InsertBodies = Arc<std::sync::Mutex<Vec<hyper::body::Bytes>>>;
+
+    /// Http interface of clickhouse which accepts every insert and keeps its body. Inserts are
+    /// answered once `answered_together` of them arrived
+    fn fake_clickhouse(answered_together: usize) -> (u16, InsertBodies) {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::convert::Infallible;
+
+        let bodies = InsertBodies::default();
+        let barrier = Arc::new(tokio::sync::Barrier::new(answered_together));
+        let make_service = make_service_fn({
+            let bodies = bodies.clone();
+            move |_| {
+                let (bodies, barrier) = (bodies.clone(), barrier.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: hyper::Request<hyper::Body>| {
+                        let (bodies, barrier) = (bodies.clone(), barrier.clone());
+                        async move {
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            bodies.lock().unwrap().push(body);
+                            barrier.wait().await;
+                            Ok::<_, Infallible>(hyper::Response::new(hyper::Body::empty()))
+                        }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+        (port, bodies)
+    }
+
+    fn row_binary(state: &ClickhouseState, msgs: &[&FlowMessageWithMetadata]) -> Vec<u8> {
+        let mut rows = Vec::new();
+        for f in msgs {
+            for (column, value) in schema::build_row(f, &state.row_settings()).unwrap() {
+                rowbinary::encode_column(&column, &value, &mut rows).unwrap();
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn test_rows_are_inserted_in_address_order() {
+        let (port, bodies) = fake_clickhouse(1);
+        let state = ClickhouseState::new(settings(serde_json::json!({
+            "host": "127.0.0.1",
+            "http_port": port,
+            "insert_format": "rowbinary",
+            "sort_buffer_by": "ipv4_src_addr",
+        })));
+        let from =
+            |addr: &str, offset: i64| testutil::flow().with_offset(offset).with_src(addr).build();
+        let msgs = vec![
+            from("10.0.0.10", 0),
+            from("9.0.0.1", 1),
+            from("10.0.0.9", 2),
+        ];
+        // text order would put 10.0.0.10 before 10.0.0.9 and 9.0.0.1 last
+        let expected = row_binary(&state, &[&msgs[1], &msgs[2], &msgs[0]]);
+
+        let result = state.stash_table("messages", msgs).await.unwrap();
+        assert_eq!(result.inserted, 3);
+        assert_eq!(bodies.lock().unwrap()[0].as_ref(), expected.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_shards_are_inserted_at_once() {
+        // neither shard is answered until both are being written
+        let (port, bodies) = fake_clickhouse(2);
+        let state = ClickhouseState::new(settings(serde_json::json!({
+            "host": "127.0.0.1",
+            "http_port": port,
+            "insert_format": "rowbinary",
+            "insert_mode": "direct-shard",
+            "shards": "127.0.0.1, 127.0.0.1",
+            "shard_key": "host",
+        })));
+        let msgs = (0..8)
+            .map(|i| {
+                testutil::flow()
+                    .with_host(&format!("edge-{}", i))
+                    .with_offset(i)
+                    .build()
+            })
+            .collect::<Vec<FlowMessageWithMetadata>>();
+        assert_eq!(
+            sharding::split_by_shard(KeyField::Host, 2, msgs.clone()).len(),
+            2
+        );
+
+        let result =
+            tokio::time::timeout(Duration::from_secs(5), state.stash_table("messages", msgs))
+                .await
+                .expect("shards were inserted one after another")
+                .unwrap();
+        assert_eq!(result.inserted, 8);
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_counter_overflow_policies() {
+        let state = |policy: &str| {
+            ClickhouseState::new(settings(serde_json::json!({
+                "column_types": {"in_bytes": "UInt32", "out_bytes": "UInt32"},
+                "on_counter_overflow": policy,
+            })))
+        };
+        let mut f = testutil::flow().build();
+        f.flow_message.out_bytes = u32::MAX as u64;
+        f.flow_message.in_bytes = u32::MAX as u64 + 1;
+        let msgs = vec![f];
+
+        let (block, acks) = state("saturate").build_block(&msgs);
+        let row = block.rows().next().unwrap();
+        assert_eq!(row.get::<u32, _>("out_bytes").unwrap(), u32::MAX);
+        assert_eq!(row.get::<u32, _>("in_bytes").unwrap(), u32::MAX);
+        assert!(matches!(acks[..], [AckMessage::Ack(..)]));
+
+        // a single giant flow does not fail the batch, it goes straight to the dead letter topic
+        let (block, acks) = state("dead_letter").build_block(&msgs);
+        assert_eq!(block.row_count(), 0);
+        assert!(matches!(
+            &acks[..],
+            [AckMessage::NackDeadLetter(_, d)] if d.reason == DeadLetterReason::ValidationFailed
+        ));
+
+        let (block, _) = state("widen").build_block(&msgs);
+        let row = block.rows().next().unwrap();
+        assert_eq!(row.get::<u64, _>("in_bytes").unwrap(), u32::MAX as u64 + 1);
+
+        let unknown_policy = serde_json::json!({
+            "host": "clickhouse",
+            "port": 9000,
+            "user": "default",
+            "password": "",
+            "on_counter_overflow": "wrap",
+        });
+        assert!(serde_json::from_value::<ClickhouseSettings>(unknown_policy).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_is_kept_in_arrival_order_by_default() {
+        let (port, bodies) = fake_clickhouse(1);
+        let state = ClickhouseState::new(settings(serde_json::json!({
+            "host": "127.0.0.1",
+            "http_port": port,
+            "insert_format": "rowbinary",
+        })));
+        let msgs = vec![
+            testutil::flow()
+                .with_host("edge-2")
+                .with_timestamp(20)
+                .with_offset(0)
+                .build(),
+            testutil::flow()
+                .with_host("edge-1")
+                .with_timestamp(10)
+                .with_offset(1)
+                .build(),
+        ];
+        let expected = row_binary(&state, &[&msgs[0], &msgs[1]]);
+
+        state.stash_table("messages", msgs).await.unwrap();
+        assert_eq!(bodies.lock().unwrap()[0].as_ref(), expected.as_slice());
+
+        let unknown_field = serde_json::json!({
+            "host": "clickhouse",
+            "port": 9000,
+            "user": "default",
+            "password": "",
+            "sort_buffer_by": "host,bytes",
+        });
+        assert!(serde_json::from_value::<ClickhouseSettings>(unknown_field).is_err());
+    }
 }
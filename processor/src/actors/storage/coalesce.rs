@@ -0,0 +1,165 @@
+use crate::actors::messages::{AckMessage, FlowMessageWithMetadata};
+
+use std::collections::HashMap;
+
+/// Field identifying updates of the same flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoalesceField {
+    /// exporter host, so flows of different exporters are never merged
+    Host,
+    SrcAddr,
+    DstAddr,
+    SrcPort,
+    DstPort,
+    Protocol,
+}
+
+impl CoalesceField {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "host" => Some(Self::Host),
+            "src_addr" => Some(Self::SrcAddr),
+            "dst_addr" => Some(Self::DstAddr),
+            "src_port" => Some(Self::SrcPort),
+            "dst_port" => Some(Self::DstPort),
+            "protocol" => Some(Self::Protocol),
+            _ => None,
+        }
+    }
+
+    fn value(&self, f: &FlowMessageWithMetadata) -> String {
+        let flow = &f.flow_message;
+        match self {
+            Self::Host => f.metadata.host.clone(),
+            Self::SrcAddr => flow.ipv4_src_addr.clone(),
+            Self::DstAddr => flow.ipv4_dst_addr.clone(),
+            Self::SrcPort => flow.l4_src_port.to_string(),
+            Self::DstPort => flow.l4_dst_port.to_string(),
+            Self::Protocol => flow.protocol.to_string(),
+        }
+    }
+}
+
+/// Host and 5-tuple
+pub fn default_coalesce_key() -> Vec<CoalesceField> {
+    vec![
+        CoalesceField::Host,
+        CoalesceField::SrcAddr,
+        CoalesceField::DstAddr,
+        CoalesceField::SrcPort,
+        CoalesceField::DstPort,
+        CoalesceField::Protocol,
+    ]
+}
+
+/// The latest update is kept with the highest counters seen, the flow stays flagged when
+/// any of its updates was
+fn merge(kept: &mut FlowMessageWithMetadata, update: FlowMessageWithMetadata) {
+    let previous = std::mem::replace(kept, update);
+    let (flow, prev) = (&mut kept.flow_message, &previous.flow_message);
+    flow.in_bytes = flow.in_bytes.max(prev.in_bytes);
+    flow.out_bytes = flow.out_bytes.max(prev.out_bytes);
+    flow.in_pkts = flow.in_pkts.max(prev.in_pkts);
+    flow.out_pkts = flow.out_pkts.max(prev.out_pkts);
+    flow.flow_duration_milliseconds = flow
+        .flow_duration_milliseconds
+        .max(prev.flow_duration_milliseconds);
+    flow.tcp_flags |= prev.tcp_flags;
+    if previous.malicious == Some(true) {
+        kept.malicious = Some(true);
+    }
+}
+
+/// Merges updates of the same flow within the batch, in place of its first update.
+/// Returns the rows to insert and acks of the merged updates, which are stored through the
+/// row they were merged into
+pub fn coalesce(
+    batch: Vec<FlowMessageWithMetadata>,
+    key: &[CoalesceField],
+) -> (Vec<FlowMessageWithMetadata>, Vec<AckMessage>) {
+    let mut rows: Vec<FlowMessageWithMetadata> = Vec::with_capacity(batch.len());
+    let mut index = HashMap::new();
+    let mut merged = Vec::new();
+
+    for f in batch {
+        let flow_key = key.iter().map(|k| k.value(&f)).collect::<Vec<String>>();
+        match index.get(&flow_key) {
+            Some(&i) => {
+                let metadata = rows[i].metadata.clone();
+                merge(&mut rows[i], f);
+                if let (Some(topic), Some(offset), Some(partition)) =
+                    (metadata.topic, metadata.offset, metadata.partition)
+                {
+                    merged.push(AckMessage::Ack(topic, offset, partition));
+                }
+            }
+            None => {
+                index.insert(flow_key, rows.len());
+                rows.push(f);
+            }
+        }
+    }
+    (rows, merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn update(
+        src_port: u32,
+        in_bytes: u64,
+        out_bytes: u64,
+        offset: i64,
+    ) -> FlowMessageWithMetadata {
+        testutil::flow()
+            .with_malicious(Some(offset == 1))
+            .with_timestamp(offset as u64)
+            .with_offset(offset)
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_src_port(src_port)
+            .with_dst_port(443)
+            .with_protocol(6)
+            .with_traffic((in_bytes, out_bytes), (0, 0))
+            .build()
+    }
+
+    #[test]
+    fn test_updates_of_same_flow_keep_max_counters() {
+        let batch = vec![
+            update(40000, 100, 1000, 0),
+            update(40001, 5, 5, 1),
+            update(40000, 300, 900, 2),
+            update(40000, 200, 3000, 3),
+        ];
+
+        let (rows, merged) = coalesce(batch, &default_coalesce_key());
+
+        assert_eq!(rows.len(), 2);
+        let long_flow = &rows[0];
+        assert_eq!(long_flow.flow_message.in_bytes, 300);
+        assert_eq!(long_flow.flow_message.out_bytes, 3000);
+        // the latest update is the one stored
+        assert_eq!(long_flow.metadata.offset, Some(3));
+        assert_eq!(rows[1].metadata.offset, Some(1));
+        assert!(matches!(
+            merged[..],
+            [AckMessage::Ack(_, 0, 0), AckMessage::Ack(_, 2, 0)]
+        ));
+    }
+
+    #[test]
+    fn test_key_decides_what_is_merged() {
+        let batch = vec![update(40001, 5, 5, 1), update(40000, 100, 1000, 0)];
+
+        let (rows, merged) = coalesce(batch, &[CoalesceField::SrcAddr, CoalesceField::DstAddr]);
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].flow_message.in_bytes, 100);
+        // flagged by the merged update
+        assert_eq!(rows[0].malicious, Some(true));
+        assert!(matches!(merged[..], [AckMessage::Ack(_, 1, 0)]));
+    }
+}
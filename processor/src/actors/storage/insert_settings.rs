@@ -0,0 +1,185 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Query level settings of clickhouse which are passed through to inserts
+pub const PASSED_THROUGH: &[&str] = &[
+    "max_insert_block_size",
+    "min_insert_block_size_rows",
+    "min_insert_block_size_bytes",
+    "max_partitions_per_insert_block",
+    "async_insert",
+    "wait_for_async_insert",
+    "wait_for_async_insert_timeout",
+    "async_insert_max_data_size",
+    "async_insert_busy_timeout_ms",
+    "insert_deduplicate",
+    "insert_quorum",
+    "insert_quorum_timeout",
+];
+
+/// Settings which only apply with `async_insert` enabled
+const ASYNC_ONLY: &[&str] = &[
+    "wait_for_async_insert",
+    "wait_for_async_insert_timeout",
+    "async_insert_max_data_size",
+    "async_insert_busy_timeout_ms",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertSettingsError {
+    Unknown(String),
+    /// every passed through setting takes an unsigned number, booleans included
+    InvalidValue(String, String),
+    Incompatible(String),
+}
+
+impl fmt::Display for InsertSettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(f, "insert setting {name} is not passed through"),
+            Self::InvalidValue(name, value) => {
+                write!(f, "insert setting {name} has invalid value {value}")
+            }
+            Self::Incompatible(reason) => write!(f, "incompatible insert settings: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for InsertSettingsError {}
+
+/// Clickhouse settings applied to every insert of flows, sorted by name, none by default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct InsertSettings(BTreeMap<&'static str, u64>);
+
+impl InsertSettings {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn enabled(&self, name: &str) -> Option<bool> {
+        self.0.get(name).map(|v| *v != 0)
+    }
+
+    /// Name and value pairs, e.g. query parameters of the http interface
+    pub fn pairs(&self) -> Vec<(&'static str, String)> {
+        self.0.iter().map(|(k, v)| (*k, v.to_string())).collect()
+    }
+
+    fn validate(self) -> Result<Self, InsertSettingsError> {
+        let async_insert = self.enabled("async_insert").unwrap_or(false);
+        if let Some(name) = ASYNC_ONLY
+            .iter()
+            .find(|name| !async_insert && self.0.contains_key(**name))
+        {
+            return Err(InsertSettingsError::Incompatible(format!(
+                "{name} requires async_insert = 1"
+            )));
+        }
+        // rows are acknowledged to kafka once the insert returns, which would be before they
+        // are written
+        if async_insert && self.enabled("wait_for_async_insert") == Some(false) {
+            return Err(InsertSettingsError::Incompatible(
+                "async_insert without wait_for_async_insert acknowledges rows before they are written"
+                    .to_owned(),
+            ));
+        }
+        if async_insert && self.enabled("insert_quorum") == Some(true) {
+            return Err(InsertSettingsError::Incompatible(
+                "insert_quorum is not supported with async_insert".to_owned(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for InsertSettings {
+    type Error = InsertSettingsError;
+
+    fn try_from(raw: HashMap<String, String>) -> Result<Self, Self::Error> {
+        raw.into_iter()
+            .map(|(name, value)| {
+                let name = PASSED_THROUGH
+                    .iter()
+                    .find(|s| **s == name)
+                    .ok_or_else(|| InsertSettingsError::Unknown(name.clone()))?;
+                let value = value
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| InsertSettingsError::InvalidValue(name.to_string(), value))?;
+                Ok((*name, value))
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .map(InsertSettings)?
+            .validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(raw: &[(&str, &str)]) -> Result<InsertSettings, InsertSettingsError> {
+        raw.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>()
+            .try_into()
+    }
+
+    #[test]
+    fn test_settings_are_passed_through_sorted() {
+        let settings = settings(&[
+            ("wait_for_async_insert", "1"),
+            ("async_insert", "1"),
+            ("max_insert_block_size", "100000"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            settings.pairs(),
+            vec![
+                ("async_insert", "1".to_owned()),
+                ("max_insert_block_size", "100000".to_owned()),
+                ("wait_for_async_insert", "1".to_owned()),
+            ]
+        );
+        assert!(InsertSettings::default().pairs().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_and_invalid_settings_are_rejected() {
+        assert_eq!(
+            settings(&[("max_threads", "8")]),
+            Err(InsertSettingsError::Unknown("max_threads".to_owned()))
+        );
+        assert_eq!(
+            settings(&[("async_insert", "true")]),
+            Err(InsertSettingsError::InvalidValue(
+                "async_insert".to_owned(),
+                "true".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_incompatible_settings_are_rejected() {
+        for raw in [
+            vec![("wait_for_async_insert", "1")],
+            vec![
+                ("async_insert", "0"),
+                ("async_insert_busy_timeout_ms", "200"),
+            ],
+            vec![("async_insert", "1"), ("wait_for_async_insert", "0")],
+            vec![("async_insert", "1"), ("insert_quorum", "2")],
+        ] {
+            assert!(
+                matches!(settings(&raw), Err(InsertSettingsError::Incompatible(_))),
+                "{:?}",
+                raw
+            );
+        }
+        assert!(settings(&[("async_insert", "1")]).is_ok());
+        assert!(settings(&[("insert_quorum", "2")]).is_ok());
+    }
+}
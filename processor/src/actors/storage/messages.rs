@@ -3,3 +3,13 @@ use actix::Message;
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct InitFlusher;
+
+/// Flushes rows as soon as they arrive instead of waiting for a full batch, sent on shutdown
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct DrainStorage;
+
+/// Stops storage workers once they saved what they hold, resolves when they are done
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct StopStorage;
@@ -1,4 +1,14 @@
 pub mod clickhouse;
-mod consts;
+pub mod coalesce;
+pub mod consts;
+pub mod insert_settings;
 pub mod messages;
+pub mod readback;
+pub mod routing;
+pub mod rowbinary;
+pub mod sampling;
+pub mod schema;
+pub mod sharding;
+pub mod sorting;
 pub mod storage_actor;
+pub mod tiers;
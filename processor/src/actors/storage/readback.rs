@@ -0,0 +1,309 @@
+use super::clickhouse::ClickhouseState;
+use super::schema::MESSAGES_TABLE;
+use crate::pb::FlowMessage;
+
+use clickhouse_rs::types::{Block, Complex};
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct ReadbackError(String);
+
+impl fmt::Display for ReadbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to read stored flows: {}", self.0)
+    }
+}
+
+impl std::error::Error for ReadbackError {}
+
+impl From<clickhouse_rs::errors::Error> for ReadbackError {
+    fn from(e: clickhouse_rs::errors::Error) -> Self {
+        ReadbackError(e.to_string())
+    }
+}
+
+/// Selects flows stored within the lookback window, optionally of a single host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadbackQuery {
+    pub lookback: Duration,
+    pub host: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for ReadbackQuery {
+    fn default() -> Self {
+        Self {
+            lookback: Duration::from_secs(300),
+            host: None,
+            limit: 100,
+        }
+    }
+}
+
+impl ReadbackQuery {
+    fn where_clause(&self) -> String {
+        let mut clause = format!(
+            "timestamp >= now() - INTERVAL {} SECOND",
+            self.lookback.as_secs()
+        );
+        if let Some(host) = &self.host {
+            clause.push_str(&format!(" AND host = {}", quote(host)));
+        }
+        clause
+    }
+}
+
+/// Quotes a string literal, native protocol has no bound parameters
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Subset of a stored row, enough to tell which flow it was
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFlow {
+    pub host: String,
+    pub timestamp: u32,
+    pub ipv4_src_addr: Option<Ipv4Addr>,
+    pub ipv4_dst_addr: Option<Ipv4Addr>,
+    pub l4_src_port: u32,
+    pub l4_dst_port: u32,
+    pub protocol: u32,
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+    pub malicious: bool,
+}
+
+/// Flow rebuilt from a stored row with the metadata it was stored with
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFlowMessage {
+    pub host: String,
+    /// seconds since epoch
+    pub timestamp: u32,
+    /// message id the flow was stored with, empty for rows stored before ids were
+    pub id: String,
+    /// hash of the flow fields, tells apart rows stored without an id
+    pub row_hash: u64,
+    pub flow: FlowMessage,
+}
+
+impl StoredFlowMessage {
+    /// Position of the row in the order ranges are read in
+    pub fn cursor(&self) -> RangeCursor {
+        RangeCursor {
+            timestamp: self.timestamp,
+            host: self.host.clone(),
+            id: self.id.clone(),
+            row_hash: self.row_hash,
+        }
+    }
+}
+
+/// Last row of a page, the next page starts right after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeCursor {
+    pub timestamp: u32,
+    pub host: String,
+    pub id: String,
+    pub row_hash: u64,
+}
+
+/// Page of flows stored within `[from, to)` in seconds since epoch, oldest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeQuery {
+    pub from: i64,
+    pub to: i64,
+    /// none for the first page
+    pub after: Option<RangeCursor>,
+    pub limit: usize,
+}
+
+/// Hash of the flow fields, rows stored without an id are ordered by it within a second
+const ROW_HASH: &str = "cityHash64(out_bytes, out_pkts, in_bytes, in_pkts, \
+    assumeNotNull(ipv4_src_addr), assumeNotNull(ipv4_dst_addr), assumeNotNull(l7_proto), \
+    l4_dst_port, l4_src_port, flow_duration_milliseconds, protocol, tcp_flags)";
+
+impl RangeQuery {
+    /// Pages by the position of the last row read rather than an offset, rows are in a total
+    /// order so none of them repeats or goes missing between pages
+    fn sql(&self) -> String {
+        let mut clause = format!(
+            "timestamp >= toDateTime({}) AND timestamp < toDateTime({})",
+            self.from, self.to
+        );
+        if let Some(after) = &self.after {
+            clause.push_str(&format!(
+                " AND (timestamp, host, id, row_hash) > (toDateTime({}), {}, {}, {})",
+                after.timestamp,
+                quote(&after.host),
+                quote(&after.id),
+                after.row_hash
+            ));
+        }
+
+        format!(
+            "SELECT host, toUnixTimestamp(timestamp) AS ts, id, {} AS row_hash, \
+                out_bytes, out_pkts, in_bytes, in_pkts, \
+                ipv4_src_addr, ipv4_dst_addr, l7_proto, l4_dst_port, l4_src_port, \
+                flow_duration_milliseconds, protocol, tcp_flags \
+            FROM {} WHERE {} \
+            ORDER BY timestamp, host, id, row_hash LIMIT {}",
+            ROW_HASH, MESSAGES_TABLE, clause, self.limit
+        )
+    }
+}
+
+impl ClickhouseState {
+    async fn fetch(&self, sql: String) -> Result<Block<Complex>, ReadbackError> {
+        let mut client = self.read_pool.get_handle().await?;
+        Ok(client.query(sql).fetch_all().await?)
+    }
+
+    /// Number of flows stored within the query window
+    pub async fn count_recent(&self, query: &ReadbackQuery) -> Result<u64, ReadbackError> {
+        let block = self
+            .fetch(format!(
+                "SELECT count() AS c FROM {} WHERE {}",
+                MESSAGES_TABLE,
+                query.where_clause()
+            ))
+            .await?;
+
+        let count = block.rows().next().map(|r| r.get::<u64, _>("c"));
+        Ok(count.transpose()?.unwrap_or(0))
+    }
+
+    /// Latest flows stored within the query window, newest first
+    pub async fn recent_rows(
+        &self,
+        query: &ReadbackQuery,
+    ) -> Result<Vec<StoredFlow>, ReadbackError> {
+        let block = self
+            .fetch(format!(
+                "SELECT host, toUnixTimestamp(timestamp) AS ts, ipv4_src_addr, ipv4_dst_addr, \
+                    l4_src_port, l4_dst_port, protocol, in_bytes, out_bytes, malicious \
+                FROM {} WHERE {} ORDER BY timestamp DESC LIMIT {}",
+                MESSAGES_TABLE,
+                query.where_clause(),
+                query.limit
+            ))
+            .await?;
+
+        block
+            .rows()
+            .map(|r| -> Result<StoredFlow, ReadbackError> {
+                Ok(StoredFlow {
+                    host: r.get("host")?,
+                    timestamp: r.get("ts")?,
+                    ipv4_src_addr: r.get("ipv4_src_addr")?,
+                    ipv4_dst_addr: r.get("ipv4_dst_addr")?,
+                    l4_src_port: r.get("l4_src_port")?,
+                    l4_dst_port: r.get("l4_dst_port")?,
+                    protocol: r.get("protocol")?,
+                    in_bytes: r.get("in_bytes")?,
+                    out_bytes: r.get("out_bytes")?,
+                    malicious: r.get::<u8, _>("malicious")? == 1,
+                })
+            })
+            .collect()
+    }
+
+    /// Flows within the range with all fields needed to publish them again
+    pub async fn flows_in_range(
+        &self,
+        query: &RangeQuery,
+    ) -> Result<Vec<StoredFlowMessage>, ReadbackError> {
+        let block = self.fetch(query.sql()).await?;
+
+        block
+            .rows()
+            .map(|r| -> Result<StoredFlowMessage, ReadbackError> {
+                let addr = |column: &str| -> Result<String, ReadbackError> {
+                    let addr: Option<Ipv4Addr> = r.get(column)?;
+                    Ok(addr.map(|a| a.to_string()).unwrap_or_default())
+                };
+
+                Ok(StoredFlowMessage {
+                    host: r.get("host")?,
+                    timestamp: r.get("ts")?,
+                    id: r.get("id")?,
+                    row_hash: r.get("row_hash")?,
+                    flow: FlowMessage {
+                        out_bytes: r.get("out_bytes")?,
+                        out_pkts: r.get("out_pkts")?,
+                        in_bytes: r.get("in_bytes")?,
+                        in_pkts: r.get("in_pkts")?,
+                        ipv4_src_addr: addr("ipv4_src_addr")?,
+                        ipv4_dst_addr: addr("ipv4_dst_addr")?,
+                        l7_proto: r.get::<Option<f32>, _>("l7_proto")?.unwrap_or_default(),
+                        l4_dst_port: r.get("l4_dst_port")?,
+                        l4_src_port: r.get("l4_src_port")?,
+                        flow_duration_milliseconds: r.get("flow_duration_milliseconds")?,
+                        protocol: r.get("protocol")?,
+                        tcp_flags: r.get("tcp_flags")?,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_where_clause() {
+        let query = ReadbackQuery::default();
+        assert_eq!(
+            query.where_clause(),
+            "timestamp >= now() - INTERVAL 300 SECOND"
+        );
+
+        let query = ReadbackQuery {
+            lookback: Duration::from_secs(60),
+            host: Some("rpi-5.15".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            query.where_clause(),
+            "timestamp >= now() - INTERVAL 60 SECOND AND host = 'rpi-5.15'"
+        );
+    }
+
+    #[test]
+    fn test_range_query_pages_in_order() {
+        let mut query = RangeQuery {
+            from: 1_669_000_000,
+            to: 1_669_003_600,
+            after: None,
+            limit: 1000,
+        };
+        assert!(query.sql().ends_with(
+            "WHERE timestamp >= toDateTime(1669000000) AND timestamp < toDateTime(1669003600) \
+            ORDER BY timestamp, host, id, row_hash LIMIT 1000"
+        ));
+
+        let last = StoredFlowMessage {
+            host: "rpi-5.15".to_owned(),
+            timestamp: 1_669_000_042,
+            id: "d3b07384".to_owned(),
+            row_hash: 7,
+            flow: FlowMessage::default(),
+        };
+        query.after = Some(last.cursor());
+        assert!(query.sql().ends_with(
+            "WHERE timestamp >= toDateTime(1669000000) AND timestamp < toDateTime(1669003600) \
+            AND (timestamp, host, id, row_hash) > (toDateTime(1669000042), 'rpi-5.15', 'd3b07384', 7) \
+            ORDER BY timestamp, host, id, row_hash LIMIT 1000"
+        ));
+    }
+
+    #[test]
+    fn test_quote_escapes_literal() {
+        assert_eq!(quote("a'b"), r"'a\'b'");
+        assert_eq!(quote(r"a\'"), r"'a\\\''");
+    }
+}
@@ -0,0 +1,245 @@
+use super::schema::MESSAGES_TABLE;
+use crate::actors::messages::FlowMessageWithMetadata;
+
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteParseError(String);
+
+impl fmt::Display for RouteParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid rule: {}", self.0)
+    }
+}
+
+impl std::error::Error for RouteParseError {}
+
+/// Single check of a flow field, all conditions of a route have to match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// classification result, unclassified flows never match
+    Malicious(bool),
+    Protocol(u32),
+    DstPort(u32),
+    Host(String),
+}
+
+impl Condition {
+    fn matches(&self, f: &FlowMessageWithMetadata) -> bool {
+        match self {
+            Condition::Malicious(m) => f.malicious == Some(*m),
+            Condition::Protocol(p) => f.flow_message.protocol == *p,
+            Condition::DstPort(p) => f.flow_message.l4_dst_port == *p,
+            Condition::Host(h) => f.metadata.host == *h,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = RouteParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, value) = s
+            .split_once('=')
+            .ok_or_else(|| RouteParseError(format!("condition {} is not field=value", s)))?;
+        let value = value.trim();
+        let invalid = || RouteParseError(format!("invalid value of {}: {}", field, value));
+
+        match field.trim() {
+            "malicious" => value
+                .parse()
+                .map(Condition::Malicious)
+                .map_err(|_| invalid()),
+            "protocol" => value
+                .parse()
+                .map(Condition::Protocol)
+                .map_err(|_| invalid()),
+            "l4_dst_port" => value.parse().map(Condition::DstPort).map_err(|_| invalid()),
+            "host" => Ok(Condition::Host(value.to_owned())),
+            other => Err(RouteParseError(format!("unknown field {}", other))),
+        }
+    }
+}
+
+/// Sends flows matching all conditions to the table, e.g. `malicious=true,protocol=6->messages_flagged`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub conditions: Vec<Condition>,
+    pub table: String,
+}
+
+impl Route {
+    fn matches(&self, f: &FlowMessageWithMetadata) -> bool {
+        matches_all(&self.conditions, f)
+    }
+}
+
+pub fn matches_all(conditions: &[Condition], f: &FlowMessageWithMetadata) -> bool {
+    conditions.iter().all(|c| c.matches(f))
+}
+
+/// Parses `conditions->target` where conditions are `field=value` separated with commas.
+/// Target is used as a table name or a column value, so it is limited to word characters
+pub fn parse_rule(s: &str) -> Result<(Vec<Condition>, String), RouteParseError> {
+    let (conditions, target) = s
+        .split_once("->")
+        .ok_or_else(|| RouteParseError(format!("rule {} has no target", s)))?;
+
+    let target = target.trim();
+    if target.is_empty()
+        || !target
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(RouteParseError(format!("invalid target {}", target)));
+    }
+
+    let conditions = conditions
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(Condition::from_str)
+        .collect::<Result<Vec<Condition>, RouteParseError>>()?;
+
+    Ok((conditions, target.to_owned()))
+}
+
+impl FromStr for Route {
+    type Err = RouteParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (conditions, table) = parse_rule(s)?;
+        Ok(Route { conditions, table })
+    }
+}
+
+/// Accepts rules separated with `;`
+pub fn deserialize_rules<'de, D, R>(deserializer: D) -> Result<Vec<R>, D::Error>
+where
+    D: Deserializer<'de>,
+    R: FromStr<Err = RouteParseError>,
+{
+    String::deserialize(deserializer)?
+        .split(';')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(R::from_str)
+        .collect::<Result<Vec<R>, RouteParseError>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Table a flow is stored in, decided by the first matching route.
+/// Flows matching none of them go to `messages`
+pub fn route_table<'a>(routes: &'a [Route], f: &FlowMessageWithMetadata) -> &'a str {
+    routes
+        .iter()
+        .find(|r| r.matches(f))
+        .map_or(MESSAGES_TABLE, |r| r.table.as_str())
+}
+
+/// Splits a batch into batches per table, keeping the order of flows within each of them
+pub fn split_by_table(
+    routes: &[Route],
+    msgs: Vec<FlowMessageWithMetadata>,
+) -> Vec<(String, Vec<FlowMessageWithMetadata>)> {
+    let mut batches: Vec<(String, Vec<FlowMessageWithMetadata>)> = Vec::new();
+    for f in msgs {
+        let table = route_table(routes, &f);
+        match batches.iter_mut().find(|(t, _)| t == table) {
+            Some((_, batch)) => batch.push(f),
+            None => batches.push((table.to_owned(), vec![f])),
+        }
+    }
+    batches
+}
+
+/// All tables flows can be stored in
+pub fn tables(routes: &[Route]) -> Vec<&str> {
+    let mut tables = vec![MESSAGES_TABLE];
+    for route in routes {
+        if !tables.contains(&route.table.as_str()) {
+            tables.push(&route.table);
+        }
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(
+            "malicious=true, protocol=6 -> messages_flagged".parse::<Route>(),
+            Ok(Route {
+                conditions: vec![Condition::Malicious(true), Condition::Protocol(6)],
+                table: "messages_flagged".to_owned(),
+            })
+        );
+        assert!("malicious=yes->messages_flagged".parse::<Route>().is_err());
+        assert!("score=1->messages_flagged".parse::<Route>().is_err());
+        assert!("malicious=true->messages; DROP".parse::<Route>().is_err());
+        assert!("malicious=true".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn test_deserialize_routes() {
+        #[derive(Deserialize)]
+        struct Settings {
+            #[serde(deserialize_with = "deserialize_rules")]
+            routes: Vec<Route>,
+        }
+
+        let settings: Settings = serde_json::from_value(serde_json::json!({
+            "routes": "malicious=true->messages_flagged; protocol=17->messages_udp;"
+        }))
+        .unwrap();
+        assert_eq!(settings.routes.len(), 2);
+        assert_eq!(
+            tables(&settings.routes),
+            vec!["messages", "messages_flagged", "messages_udp"]
+        );
+    }
+
+    #[test]
+    fn test_split_by_first_matching_route() {
+        let routes = vec![
+            "malicious=true->messages_flagged".parse::<Route>().unwrap(),
+            "protocol=17->messages_udp".parse::<Route>().unwrap(),
+        ];
+
+        let udp = testutil::flow().with_protocol(17);
+        let batches = split_by_table(
+            &routes,
+            vec![
+                udp.clone().with_malicious(Some(true)).build(),
+                testutil::flow().with_malicious(Some(false)).build(),
+                udp.clone().build(),
+                udp.with_malicious(Some(false)).build(),
+            ],
+        );
+        let sizes = batches
+            .iter()
+            .map(|(t, b)| (t.as_str(), b.len()))
+            .collect::<Vec<(&str, usize)>>();
+
+        assert_eq!(
+            sizes,
+            vec![
+                ("messages_flagged", 1),
+                ("messages", 1),
+                ("messages_udp", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_everything_goes_to_messages_by_default() {
+        let flagged = testutil::flow().with_malicious(Some(true)).build();
+        assert_eq!(route_table(&[], &flagged), MESSAGES_TABLE);
+    }
+}
@@ -0,0 +1,121 @@
+use clickhouse_rs::types::Value;
+use either::Either;
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowBinaryError {
+    UnsupportedValue(String, String),
+}
+
+impl fmt::Display for RowBinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RowBinaryError::UnsupportedValue(c, v) => {
+                write!(f, "value {v} of column {c} cannot be encoded as RowBinary")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RowBinaryError {}
+
+/// Appends a value of the column to the buffer in ClickHouse RowBinary format.
+/// Columns of a row have to be appended in the same order as in the `INSERT` statement
+pub fn encode_column(column: &str, value: &Value, buf: &mut Vec<u8>) -> Result<(), RowBinaryError> {
+    encode_value(value, buf)
+        .map_err(|_| RowBinaryError::UnsupportedValue(column.to_owned(), format!("{value:?}")))
+}
+
+fn encode_value(value: &Value, buf: &mut Vec<u8>) -> Result<(), ()> {
+    match value {
+        Value::UInt8(v) => buf.push(*v),
+        Value::UInt16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::UInt32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::UInt64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::Float32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::Float64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::String(s) => {
+            encode_leb128(s.len() as u64, buf);
+            buf.extend_from_slice(s);
+        }
+        // octets are already kept in the little endian order of UInt32
+        Value::Ipv4(octets) => buf.extend_from_slice(octets),
+        Value::DateTime(secs, _) => buf.extend_from_slice(&secs.to_le_bytes()),
+        Value::ChronoDateTime(dt) => buf.extend_from_slice(&(dt.timestamp() as u32).to_le_bytes()),
+        Value::Nullable(Either::Left(_)) => buf.push(1),
+        Value::Nullable(Either::Right(v)) => {
+            buf.push(0);
+            encode_value(v, buf)?;
+        }
+        _ => return Err(()),
+    }
+    Ok(())
+}
+
+fn encode_leb128(mut v: u64, buf: &mut Vec<u8>) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use clickhouse_rs::types::SqlType;
+
+    fn encode(value: Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_column("c", &value, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_encode_values() {
+        assert_eq!(encode(Value::UInt8(1)), vec![1]);
+        assert_eq!(encode(Value::UInt32(53)), vec![53, 0, 0, 0]);
+        assert_eq!(encode(Value::UInt64(256)), vec![0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(Value::Float32(1.0)), 1.0f32.to_le_bytes().to_vec());
+        assert_eq!(encode(Value::from("abc")), vec![3, b'a', b'b', b'c']);
+        assert_eq!(encode(Value::Ipv4([1, 0, 0, 10])), vec![1, 0, 0, 10]);
+        assert_eq!(
+            encode(Value::from(Utc.timestamp_opt(1_670_000_000, 0).unwrap())),
+            1_670_000_000u32.to_le_bytes().to_vec()
+        );
+    }
+
+    #[test]
+    fn test_encode_nullable() {
+        assert_eq!(
+            encode(Value::Nullable(Either::Left(&SqlType::Float32))),
+            vec![1]
+        );
+        assert_eq!(
+            encode(Value::Nullable(Either::Right(Box::new(Value::UInt8(7))))),
+            vec![0, 7]
+        );
+    }
+
+    #[test]
+    fn test_encode_long_string_length() {
+        let encoded = encode(Value::from("a".repeat(300)));
+        assert_eq!(&encoded[..2], &[0xac, 0x02]);
+        assert_eq!(encoded.len(), 302);
+    }
+
+    #[test]
+    fn test_unsupported_value() {
+        let mut buf = Vec::new();
+        assert!(matches!(
+            encode_column("c", &Value::Int8(1), &mut buf),
+            Err(RowBinaryError::UnsupportedValue(_, _))
+        ));
+    }
+}
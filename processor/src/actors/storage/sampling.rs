@@ -0,0 +1,168 @@
+use super::routing::{matches_all, Condition, RouteParseError};
+use crate::actors::messages::{AckMessage, FlowMessageWithMetadata};
+use crate::actors::suspicious_stream::suspicion_score;
+use crate::metrics::STORAGE_SAMPLING;
+use crate::settings::StorageSettings;
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Conditions of a flow which is stored whatever the sample rate, e.g. `protocol=6,l4_dst_port=22`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepRule(pub Vec<Condition>);
+
+impl FromStr for KeepRule {
+    type Err = RouteParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(Condition::from_str)
+            .collect::<Result<Vec<Condition>, RouteParseError>>()
+            .map(KeepRule)
+    }
+}
+
+/// Why a flow reaching storage is stored or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// flagged or matching an always keep rule, never sampled out
+    AlwaysKept,
+    Kept,
+    Dropped,
+}
+
+impl Sampling {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::AlwaysKept => "always_kept",
+            Self::Kept => "kept",
+            Self::Dropped => "dropped",
+        }
+    }
+}
+
+/// Stores 1 in `sample_rate` classified flows to cut the volume of benign traffic. Flows
+/// scoring at least `always_keep_min_score` or matching an always keep rule bypass it, they
+/// are scored by classification and scan detection before they reach storage
+#[derive(Debug)]
+pub struct StorageSampler {
+    sample_rate: u64,
+    always_keep_min_score: u8,
+    always_keep_rules: Vec<KeepRule>,
+    seen: AtomicU64,
+}
+
+impl StorageSampler {
+    pub fn new(settings: &StorageSettings) -> Self {
+        Self {
+            sample_rate: settings.sample_rate.max(1),
+            always_keep_min_score: settings.always_keep_min_score,
+            always_keep_rules: settings.always_keep_rules.clone(),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sample_rate > 1
+    }
+
+    fn always_kept(&self, f: &FlowMessageWithMetadata) -> bool {
+        (self.always_keep_min_score > 0 && suspicion_score(f) >= self.always_keep_min_score)
+            || self.always_keep_rules.iter().any(|r| matches_all(&r.0, f))
+    }
+
+    pub fn sample(&self, f: &FlowMessageWithMetadata) -> Sampling {
+        if !self.is_enabled() {
+            return Sampling::Kept;
+        }
+        let sampling = if self.always_kept(f) {
+            Sampling::AlwaysKept
+        } else if self.seen.fetch_add(1, Ordering::Relaxed) % self.sample_rate == 0 {
+            Sampling::Kept
+        } else {
+            Sampling::Dropped
+        };
+        STORAGE_SAMPLING
+            .with_label_values(&[sampling.as_str()])
+            .inc();
+        sampling
+    }
+}
+
+/// Acknowledges a flow sampled out, so its offset is committed as if it was stored
+pub fn sampled_out_ack(f: &FlowMessageWithMetadata) -> Option<AckMessage> {
+    Some(AckMessage::Ack(
+        f.metadata.topic.clone()?,
+        f.metadata.offset?,
+        f.metadata.partition?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn sampler(sample_rate: u64, always_keep_min_score: u8, rules: &[&str]) -> StorageSampler {
+        StorageSampler::new(&StorageSettings {
+            sample_rate,
+            always_keep_min_score,
+            always_keep_rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_benign_flows_are_sampled() {
+        let sampler = sampler(4, 1, &[]);
+        let benign = testutil::flow().with_malicious(Some(false)).build();
+        let kept = (0..100)
+            .filter(|_| sampler.sample(&benign) == Sampling::Kept)
+            .count();
+        assert_eq!(kept, 25);
+
+        let everything = self::sampler(1, 1, &[]);
+        assert!(!everything.is_enabled());
+        assert_eq!(everything.sample(&testutil::flow().build()), Sampling::Kept);
+    }
+
+    #[test]
+    fn test_flagged_flows_survive_any_sample_rate() {
+        let benign = testutil::flow()
+            .with_malicious(Some(false))
+            .with_dst_port(443);
+        let malicious = benign.clone().with_malicious(Some(true)).build();
+        let ssh = benign.clone().with_dst_port(22).build();
+        let benign = benign.build();
+        for sample_rate in [2, 10, 1000, u64::MAX] {
+            let sampler = sampler(sample_rate, 1, &["l4_dst_port=22"]);
+            let mut benign_kept = 0;
+            for _ in 0..100 {
+                assert_eq!(sampler.sample(&malicious), Sampling::AlwaysKept);
+                assert_eq!(sampler.sample(&ssh), Sampling::AlwaysKept);
+                if sampler.sample(&benign) == Sampling::Kept {
+                    benign_kept += 1;
+                }
+            }
+            // always kept flows do not count towards the sampled ones
+            assert_eq!(benign_kept, 99 / sample_rate + 1);
+        }
+    }
+
+    #[test]
+    fn test_score_bypass_can_be_disabled() {
+        let sampler = sampler(1000, 0, &[]);
+        let malicious = testutil::flow()
+            .with_malicious(Some(true))
+            .with_offset(7)
+            .build();
+        sampler.sample(&testutil::flow().with_malicious(Some(false)).build());
+        assert_eq!(sampler.sample(&malicious), Sampling::Dropped);
+        assert_eq!(
+            sampled_out_ack(&malicious).map(|a| matches!(a, AckMessage::Ack(_, 7, 0))),
+            Some(true)
+        );
+    }
+}
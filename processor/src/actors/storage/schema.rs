@@ -0,0 +1,1481 @@
+use super::tiers::{tier_of, TierRule};
+use crate::actors::messages::FlowMessageWithMetadata;
+use crate::keys::{self, KeyField};
+use crate::metrics::COLUMN_OVERFLOWS;
+use crate::privacy::Redaction;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clickhouse_rs::types::{DateTimeType, SqlType, Value};
+use either::Either;
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+pub const MESSAGES_TABLE: &str = "messages";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    UnknownColumn(String),
+    ColumnNotNullable(String),
+    InvalidSentinel(String, String),
+    InvalidDefault(String, String),
+    InvalidValue(String, String),
+    InvalidCoercion(String, String),
+    InvalidIndex(String, String),
+    OutOfRange(String, String),
+    /// out of range with the `dead_letter` overflow, retrying the row would not help
+    Overflow(String, String),
+    TableMissing(String),
+    Mismatch(String, Vec<ColumnMismatch>),
+    Database(String),
+    Sharding(String),
+}
+
+/// Difference between a column expected by `stash` and the one present in the table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnMismatch {
+    Missing {
+        column: String,
+        expected: String,
+    },
+    WrongType {
+        column: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for ColumnMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColumnMismatch::Missing { column, expected } => {
+                write!(f, "column {column} is missing, expected {expected}")
+            }
+            ColumnMismatch::WrongType {
+                column,
+                expected,
+                actual,
+            } => write!(f, "column {column} has type {actual}, expected {expected}"),
+        }
+    }
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::UnknownColumn(c) => write!(f, "column {c} does not exist"),
+            SchemaError::ColumnNotNullable(c) => write!(f, "column {c} is not nullable"),
+            SchemaError::InvalidSentinel(c, s) => {
+                write!(f, "sentinel {s:?} is not a valid value of column {c}")
+            }
+            SchemaError::InvalidDefault(c, d) => {
+                write!(f, "default {d:?} is not a valid value of column {c}")
+            }
+            SchemaError::InvalidValue(c, v) => {
+                write!(f, "value {v:?} is not a valid value of column {c}")
+            }
+            SchemaError::InvalidCoercion(c, t) => {
+                write!(f, "column {c} can not be coerced to {t:?}")
+            }
+            SchemaError::InvalidIndex(c, i) => {
+                write!(f, "{i:?} is not a valid index of column {c}")
+            }
+            SchemaError::OutOfRange(c, v) => {
+                write!(f, "value {v} is out of range of column {c}")
+            }
+            SchemaError::Overflow(c, v) => {
+                write!(f, "value {v} overflows column {c}, it is dead lettered")
+            }
+            SchemaError::TableMissing(t) => write!(f, "table {t} does not exist"),
+            SchemaError::Mismatch(t, mismatches) => {
+                write!(f, "table {t} does not match the expected schema: ")?;
+                let listed = mismatches
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect::<Vec<String>>();
+                write!(f, "{}", listed.join("; "))
+            }
+            SchemaError::Database(e) => write!(f, "unable to read table schema: {e}"),
+            SchemaError::Sharding(e) => write!(f, "invalid insert mode: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    String,
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
+    Float32,
+    IPv4,
+    DateTime,
+}
+
+impl FromStr for ColumnType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "String" => Ok(ColumnType::String),
+            "UInt8" => Ok(ColumnType::UInt8),
+            "UInt16" => Ok(ColumnType::UInt16),
+            "UInt32" => Ok(ColumnType::UInt32),
+            "UInt64" => Ok(ColumnType::UInt64),
+            "Float32" => Ok(ColumnType::Float32),
+            "IPv4" => Ok(ColumnType::IPv4),
+            "DateTime" => Ok(ColumnType::DateTime),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ColumnType {
+    fn sql_type(&self) -> &'static SqlType {
+        match self {
+            ColumnType::String => &SqlType::String,
+            ColumnType::UInt8 => &SqlType::UInt8,
+            ColumnType::UInt16 => &SqlType::UInt16,
+            ColumnType::UInt32 => &SqlType::UInt32,
+            ColumnType::UInt64 => &SqlType::UInt64,
+            ColumnType::Float32 => &SqlType::Float32,
+            ColumnType::IPv4 => &SqlType::Ipv4,
+            ColumnType::DateTime => &SqlType::DateTime(DateTimeType::DateTime32),
+        }
+    }
+
+    fn ddl(&self) -> &'static str {
+        match self {
+            ColumnType::String => "String",
+            ColumnType::UInt8 => "UInt8",
+            ColumnType::UInt16 => "UInt16",
+            ColumnType::UInt32 => "UInt32",
+            ColumnType::UInt64 => "UInt64",
+            ColumnType::Float32 => "Float32",
+            ColumnType::IPv4 => "IPv4",
+            ColumnType::DateTime => "DateTime",
+        }
+    }
+
+    /// Parses a configured sentinel into the raw value it is compared against.
+    /// IPv4 addresses arrive as strings, so their sentinels are strings as well
+    fn parse_raw(&self, raw: &str) -> Option<Value> {
+        match self {
+            ColumnType::String | ColumnType::IPv4 => Some(Value::from(raw.to_owned())),
+            ColumnType::UInt8 => raw.parse::<u8>().ok().map(Value::from),
+            ColumnType::UInt16 => raw.parse::<u16>().ok().map(Value::from),
+            ColumnType::UInt32 => raw.parse::<u32>().ok().map(Value::from),
+            ColumnType::UInt64 => raw.parse::<u64>().ok().map(Value::from),
+            ColumnType::Float32 => raw.parse::<f32>().ok().map(Value::from),
+            ColumnType::DateTime => None,
+        }
+    }
+
+    /// Raw value stored when a non nullable field is absent and no default is configured
+    fn zero(&self) -> Value {
+        match self {
+            ColumnType::String => Value::from(""),
+            ColumnType::UInt8 => Value::from(0u8),
+            ColumnType::UInt16 => Value::from(0u16),
+            ColumnType::UInt32 => Value::from(0u32),
+            ColumnType::UInt64 => Value::from(0u64),
+            ColumnType::Float32 => Value::from(0f32),
+            ColumnType::IPv4 => Value::from(Ipv4Addr::UNSPECIFIED.to_string()),
+            ColumnType::DateTime => Value::from(DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp(0, 0),
+                Utc,
+            )),
+        }
+    }
+
+    fn convert(&self, column: &str, raw: Value) -> Result<Value, SchemaError> {
+        match (self, raw) {
+            (ColumnType::IPv4, Value::String(s)) => {
+                let addr = std::str::from_utf8(&s)
+                    .ok()
+                    .and_then(|s| s.parse::<Ipv4Addr>().ok())
+                    .ok_or_else(|| {
+                        SchemaError::InvalidValue(
+                            column.to_owned(),
+                            String::from_utf8_lossy(&s).into_owned(),
+                        )
+                    })?;
+
+                // clickhouse stores IPv4 octets in little endian order
+                let mut octets = addr.octets();
+                octets.reverse();
+                Ok(Value::Ipv4(octets))
+            }
+            (_, raw) => Ok(raw),
+        }
+    }
+
+    /// Inclusive range of integer columns
+    fn int_range(&self) -> Option<(i128, i128)> {
+        match self {
+            ColumnType::UInt8 => Some((0, u8::MAX as i128)),
+            ColumnType::UInt16 => Some((0, u16::MAX as i128)),
+            ColumnType::UInt32 => Some((0, u32::MAX as i128)),
+            ColumnType::UInt64 => Some((0, u64::MAX as i128)),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        self.int_range().is_some() || *self == ColumnType::Float32
+    }
+
+    /// Whether raw values of this type can be cast to `target`, checked when configured
+    fn coerces_to(&self, target: ColumnType) -> bool {
+        match (self, target) {
+            (source, target) if *source == target => true,
+            (ColumnType::DateTime, _) | (_, ColumnType::DateTime) => false,
+            (_, ColumnType::String) => true,
+            (ColumnType::String, target) => target.is_numeric(),
+            (ColumnType::IPv4, _) | (_, ColumnType::IPv4) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Text of a raw string or number, as stored in a `String` column
+fn raw_text(raw: &Value) -> Option<String> {
+    match raw {
+        Value::String(s) => Some(String::from_utf8_lossy(s).into_owned()),
+        Value::UInt8(v) => Some(v.to_string()),
+        Value::UInt16(v) => Some(v.to_string()),
+        Value::UInt32(v) => Some(v.to_string()),
+        Value::UInt64(v) => Some(v.to_string()),
+        Value::Float32(v) => Some(v.to_string()),
+        Value::Float64(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Numeric raw value on its way to another numeric type
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn of(raw: &Value) -> Option<Number> {
+        match raw {
+            Value::UInt8(v) => Some(Number::Int(*v as i128)),
+            Value::UInt16(v) => Some(Number::Int(*v as i128)),
+            Value::UInt32(v) => Some(Number::Int(*v as i128)),
+            Value::UInt64(v) => Some(Number::Int(*v as i128)),
+            Value::Float32(v) => Some(Number::Float(*v as f64)),
+            Value::Float64(v) => Some(Number::Float(*v)),
+            Value::String(s) => {
+                let s = std::str::from_utf8(s).ok()?.trim();
+                s.parse::<i128>()
+                    .map(Number::Int)
+                    .or_else(|_| s.parse::<f64>().map(Number::Float))
+                    .ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What happens with a value which does not fit into the coerced column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The row is rejected
+    Reject,
+    /// The closest value within the range is stored
+    Clamp,
+    /// The row is sent straight to the dead letter topic instead of being retried
+    DeadLetter,
+}
+
+impl Overflow {
+    fn outcome(&self) -> &'static str {
+        match self {
+            Overflow::Reject => "rejected",
+            Overflow::Clamp => "clamped",
+            Overflow::DeadLetter => "dead_lettered",
+        }
+    }
+}
+
+/// Byte and packet counters, the columns `on_counter_overflow` applies to
+pub const COUNTER_COLUMNS: &[&str] = &["out_bytes", "out_pkts", "in_bytes", "in_pkts"];
+
+/// What happens with counters which do not fit into their coerced column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterOverflow {
+    /// The column maximum is stored
+    Saturate,
+    /// The row is sent straight to the dead letter topic
+    DeadLetter,
+    /// Counters are stored as `UInt64` whatever their coerced numeric type
+    Widen,
+}
+
+/// Type a column is cast to before it is stored, e.g. `UInt16,clamp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coercion {
+    pub target: ColumnType,
+    pub overflow: Overflow,
+}
+
+impl Coercion {
+    fn apply(&self, column: &str, raw: Value) -> Result<Value, SchemaError> {
+        let text = raw_text(&raw).unwrap_or_default();
+        if self.target == ColumnType::String {
+            return Ok(Value::from(text));
+        }
+        if !self.target.is_numeric() {
+            return Ok(raw);
+        }
+
+        let invalid = || SchemaError::InvalidValue(column.to_owned(), text.clone());
+        let number = Number::of(&raw).ok_or_else(invalid)?;
+        let overflowed = || {
+            COLUMN_OVERFLOWS
+                .with_label_values(&[column, self.overflow.outcome()])
+                .inc()
+        };
+        let out_of_range = || {
+            overflowed();
+            match self.overflow {
+                Overflow::DeadLetter => SchemaError::Overflow(column.to_owned(), text.clone()),
+                _ => SchemaError::OutOfRange(column.to_owned(), text.clone()),
+            }
+        };
+
+        match (self.target.int_range(), &number) {
+            (_, Number::Float(v)) if v.is_nan() => Err(invalid()),
+            (Some((min, max)), _) => {
+                // fractions are truncated, as by clickhouse casts
+                let v = match number {
+                    Number::Int(v) => v,
+                    Number::Float(v) => v.trunc() as i128,
+                };
+                let v = match self.overflow {
+                    _ if (min..=max).contains(&v) => v,
+                    Overflow::Clamp => {
+                        overflowed();
+                        v.clamp(min, max)
+                    }
+                    Overflow::Reject | Overflow::DeadLetter => return Err(out_of_range()),
+                };
+                Ok(match self.target {
+                    ColumnType::UInt8 => Value::from(v as u8),
+                    ColumnType::UInt16 => Value::from(v as u16),
+                    ColumnType::UInt32 => Value::from(v as u32),
+                    _ => Value::from(v as u64),
+                })
+            }
+            (None, _) => {
+                let v = match number {
+                    Number::Int(v) => v as f64,
+                    Number::Float(v) => v,
+                };
+                let max = f32::MAX as f64;
+                let v = match self.overflow {
+                    _ if (-max..=max).contains(&v) => v,
+                    Overflow::Clamp => {
+                        overflowed();
+                        v.clamp(-max, max)
+                    }
+                    Overflow::Reject | Overflow::DeadLetter => return Err(out_of_range()),
+                };
+                Ok(Value::from(v as f32))
+            }
+        }
+    }
+}
+
+/// Per column types differing from the `messages` schema, e.g. ports stored as `UInt16`
+/// or numbers stored as `String` in legacy tables
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct ColumnCoercions(HashMap<&'static str, Coercion>);
+
+impl ColumnCoercions {
+    fn get(&self, column: &str) -> Option<&Coercion> {
+        self.0.get(column)
+    }
+
+    /// Overrides the overflow of numeric counter coercions, coercions of counters to
+    /// `String` never overflow and are kept as they are
+    pub fn with_counter_overflow(mut self, policy: Option<CounterOverflow>) -> Self {
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return self,
+        };
+        self.0.retain(|column, coercion| {
+            let numeric = coercion.target.is_numeric();
+            if !COUNTER_COLUMNS.contains(column) || !numeric {
+                return true;
+            }
+            match policy {
+                CounterOverflow::Saturate => coercion.overflow = Overflow::Clamp,
+                CounterOverflow::DeadLetter => coercion.overflow = Overflow::DeadLetter,
+                CounterOverflow::Widen => return coercion.target == ColumnType::UInt64,
+            }
+            true
+        });
+        self
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for ColumnCoercions {
+    type Error = SchemaError;
+
+    fn try_from(raw: HashMap<String, String>) -> Result<Self, Self::Error> {
+        raw.into_iter()
+            .map(|(name, coercion)| {
+                let c = column(&name).ok_or_else(|| SchemaError::UnknownColumn(name.clone()))?;
+                let invalid = || SchemaError::InvalidCoercion(name.clone(), coercion.clone());
+
+                let (target, overflow) = match coercion.split_once(',') {
+                    Some((target, overflow)) => (target.trim(), Some(overflow.trim())),
+                    None => (coercion.trim(), None),
+                };
+                let target = target.parse::<ColumnType>().map_err(|_| invalid())?;
+                let overflow = match overflow {
+                    None | Some("reject") => Overflow::Reject,
+                    Some("clamp") => Overflow::Clamp,
+                    Some("dead_letter") => Overflow::DeadLetter,
+                    Some(_) => return Err(invalid()),
+                };
+                if !c.column_type.coerces_to(target) {
+                    return Err(invalid());
+                }
+
+                Ok((c.name, Coercion { target, overflow }))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map(ColumnCoercions)
+    }
+}
+
+/// Parses a type of data skipping index clickhouse supports along with its arguments, so only
+/// numbers in their ranges end up in the DDL. Returns the type as it is written to the DDL
+fn skip_index_type(raw: &str) -> Option<String> {
+    let (family, args) = match raw.split_once('(') {
+        Some((family, args)) => (family.trim(), Some(args.strip_suffix(')')?)),
+        None => (raw.trim(), None),
+    };
+    let args = args.map_or_else(Vec::new, |args| args.split(',').map(str::trim).collect());
+    // unsigned integer arguments, each at least its minimum
+    let uints = |mins: &[u64]| -> Option<Vec<String>> {
+        if args.len() != mins.len() {
+            return None;
+        }
+        args.iter()
+            .zip(mins)
+            .map(|(arg, min)| {
+                let v = arg.parse::<u64>().ok().filter(|v| v >= min)?;
+                Some(v.to_string())
+            })
+            .collect()
+    };
+
+    let args = match (family, args.as_slice()) {
+        ("minmax" | "bloom_filter", []) => return Some(family.to_owned()),
+        // false positive rate
+        ("bloom_filter", [rate]) => {
+            let rate = rate.parse::<f64>().ok().filter(|r| *r > 0.0 && *r < 1.0)?;
+            vec![rate.to_string()]
+        }
+        // max rows, 0 for unlimited
+        ("set", _) => uints(&[0])?,
+        // n, size of the filter in bytes, number of hash functions, seed
+        ("ngrambf_v1", _) => uints(&[1, 1, 1, 0])?,
+        // size of the filter in bytes, number of hash functions, seed
+        ("tokenbf_v1", _) => uints(&[1, 1, 0])?,
+        _ => return None,
+    };
+    Some(format!("{}({})", family, args.join(", ")))
+}
+
+/// Data skipping index of a column, e.g. `bloom_filter(0.01)` on `ipv4_dst_addr`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkipIndex {
+    index_type: String,
+    granularity: u32,
+}
+
+/// Per column skipping indexes added to created tables, none by default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct ColumnIndexes(BTreeMap<&'static str, SkipIndex>);
+
+impl ColumnIndexes {
+    /// `INDEX` clauses of the `CREATE TABLE` statement, in the order of column names
+    fn ddl(&self) -> impl Iterator<Item = String> + '_ {
+        self.0.iter().map(|(column, index)| {
+            format!(
+                "INDEX idx_{column} {column} TYPE {} GRANULARITY {}",
+                index.index_type, index.granularity
+            )
+        })
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for ColumnIndexes {
+    type Error = SchemaError;
+
+    fn try_from(raw: HashMap<String, String>) -> Result<Self, Self::Error> {
+        raw.into_iter()
+            .map(|(name, index)| {
+                let c = column(&name).ok_or_else(|| SchemaError::UnknownColumn(name.clone()))?;
+                let invalid = || SchemaError::InvalidIndex(name.clone(), index.clone());
+
+                // arguments of the type may contain commas, the granularity follows the last one
+                let (index_type, granularity) = match index.rsplit_once(',') {
+                    Some((t, g)) if !g.contains(')') => (t.trim(), g.trim().parse().ok()),
+                    _ => (index.trim(), Some(1)),
+                };
+                let granularity = granularity.filter(|g| *g > 0).ok_or_else(invalid)?;
+                let index_type = skip_index_type(index_type).ok_or_else(invalid)?;
+
+                Ok((
+                    c.name,
+                    SkipIndex {
+                        index_type,
+                        granularity,
+                    },
+                ))
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .map(ColumnIndexes)
+    }
+}
+
+pub struct Column {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+    /// Raw value of the field, `None` when the message does not carry it
+    extract: fn(&FlowMessageWithMetadata, &RowSettings) -> Option<Value>,
+}
+
+/// Configuration applied to every built row
+#[derive(Debug, Clone, Copy)]
+pub struct RowSettings<'a> {
+    pub sentinels: &'a NullSentinels,
+    pub defaults: &'a MissingDefaults,
+    pub tiers: &'a [TierRule],
+    pub coercions: &'a ColumnCoercions,
+    pub redaction: &'a Redaction,
+    pub shard_key: Option<KeyField>,
+}
+
+impl Column {
+    /// Type the column is stored as, the coerced one if configured
+    fn stored_type(&self, coercions: &ColumnCoercions) -> ColumnType {
+        coercions
+            .get(self.name)
+            .map_or(self.column_type, |c| c.target)
+    }
+
+    /// Type as reported by `system.columns`
+    pub fn ddl_type(&self, coercions: &ColumnCoercions) -> String {
+        let ddl = self.stored_type(coercions).ddl();
+        if self.nullable {
+            format!("Nullable({ddl})")
+        } else {
+            ddl.to_owned()
+        }
+    }
+
+    /// Casts the raw value to the coerced type, then converts it to the stored one
+    fn convert(&self, raw: Value, row: &RowSettings) -> Result<Value, SchemaError> {
+        let raw = match row.coercions.get(self.name) {
+            Some(coercion) => coercion.apply(self.name, raw)?,
+            None => raw,
+        };
+        self.stored_type(row.coercions).convert(self.name, raw)
+    }
+
+    fn value(&self, f: &FlowMessageWithMetadata, row: &RowSettings) -> Result<Value, SchemaError> {
+        let null = || Value::Nullable(Either::Left(self.stored_type(row.coercions).sql_type()));
+        let raw = match ((self.extract)(f, row), row.defaults.get(self.name)) {
+            (Some(raw), _) => raw,
+            (None, Some(default)) => default.clone(),
+            (None, None) if self.nullable => return Ok(null()),
+            (None, None) => self.column_type.zero(),
+        };
+
+        if !self.nullable {
+            return self.convert(raw, row);
+        }
+
+        if row.sentinels.matches(self.name, &raw) {
+            return Ok(null());
+        }
+
+        let value = self.convert(raw, row)?;
+        Ok(Value::Nullable(Either::Right(Box::new(value))))
+    }
+}
+
+/// Columns of the `messages` table, kept in sync with the migrations
+pub const MESSAGES_COLUMNS: &[Column] = &[
+    Column {
+        name: "host",
+        column_type: ColumnType::String,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.metadata.host.as_str())),
+    },
+    Column {
+        name: "out_bytes",
+        column_type: ColumnType::UInt64,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.out_bytes)),
+    },
+    Column {
+        name: "out_pkts",
+        column_type: ColumnType::UInt64,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.out_pkts)),
+    },
+    Column {
+        name: "in_bytes",
+        column_type: ColumnType::UInt64,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.in_bytes)),
+    },
+    Column {
+        name: "in_pkts",
+        column_type: ColumnType::UInt64,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.in_pkts)),
+    },
+    Column {
+        name: "ipv4_src_addr",
+        column_type: ColumnType::IPv4,
+        nullable: true,
+        extract: |f, _| Some(Value::from(f.flow_message.ipv4_src_addr.as_str())),
+    },
+    Column {
+        name: "ipv4_dst_addr",
+        column_type: ColumnType::IPv4,
+        nullable: true,
+        extract: |f, _| Some(Value::from(f.flow_message.ipv4_dst_addr.as_str())),
+    },
+    Column {
+        name: "l7_proto",
+        column_type: ColumnType::Float32,
+        nullable: true,
+        extract: |f, _| Some(Value::from(f.flow_message.l7_proto)),
+    },
+    Column {
+        name: "l4_dst_port",
+        column_type: ColumnType::UInt32,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.l4_dst_port)),
+    },
+    Column {
+        name: "l4_src_port",
+        column_type: ColumnType::UInt32,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.l4_src_port)),
+    },
+    Column {
+        name: "flow_duration_milliseconds",
+        column_type: ColumnType::UInt64,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.flow_duration_milliseconds)),
+    },
+    Column {
+        name: "protocol",
+        column_type: ColumnType::UInt32,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.protocol)),
+    },
+    Column {
+        name: "tcp_flags",
+        column_type: ColumnType::UInt32,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.flow_message.tcp_flags)),
+    },
+    Column {
+        name: "malicious",
+        column_type: ColumnType::UInt8,
+        nullable: false,
+        // unclassified flows have no value
+        extract: |f, _| f.malicious.map(|m| Value::from(u8::from(m))),
+    },
+    Column {
+        name: "tier",
+        column_type: ColumnType::String,
+        nullable: false,
+        extract: |f, row| Some(Value::from(tier_of(row.tiers, f))),
+    },
+    Column {
+        name: "enrichment_timed_out",
+        column_type: ColumnType::UInt8,
+        nullable: false,
+        extract: |f, _| Some(Value::from(u8::from(f.metadata.enrichment_timed_out))),
+    },
+    Column {
+        name: "scan_suspected",
+        column_type: ColumnType::UInt8,
+        nullable: false,
+        extract: |f, _| Some(Value::from(u8::from(f.metadata.scan_suspected))),
+    },
+    Column {
+        name: "src_asset",
+        column_type: ColumnType::String,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.metadata.src_asset.clone())),
+    },
+    Column {
+        name: "dst_asset",
+        column_type: ColumnType::String,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.metadata.dst_asset.clone())),
+    },
+    Column {
+        name: "id",
+        column_type: ColumnType::String,
+        nullable: false,
+        extract: |f, _| Some(Value::from(f.metadata.id.as_str())),
+    },
+    Column {
+        name: "timestamp",
+        column_type: ColumnType::DateTime,
+        nullable: false,
+        extract: |f, _| {
+            let ts_secs = f.metadata.timestamp / 1000;
+            let ts_ns = f.metadata.timestamp % 1000 * 1_000_000;
+
+            Some(Value::from(DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp(ts_secs as i64, ts_ns as u32),
+                Utc,
+            )))
+        },
+    },
+];
+
+/// Sharding key of a Distributed table, stored only when a key field is configured
+pub static SHARD_KEY_COLUMN: Column = Column {
+    name: "shard_key",
+    column_type: ColumnType::UInt32,
+    nullable: false,
+    extract: |f, row| row.shard_key.map(|k| Value::from(keys::shard_key(k, f))),
+};
+
+/// Columns of flows tables, `messages` ones followed by the shard key if there is one
+pub fn columns(shard_key: Option<KeyField>) -> impl Iterator<Item = &'static Column> {
+    MESSAGES_COLUMNS
+        .iter()
+        .chain(shard_key.map(|_| &SHARD_KEY_COLUMN))
+}
+
+pub fn column(name: &str) -> Option<&'static Column> {
+    MESSAGES_COLUMNS.iter().find(|c| c.name == name)
+}
+
+pub fn column_names(shard_key: Option<KeyField>) -> Vec<&'static str> {
+    columns(shard_key).map(|c| c.name).collect()
+}
+
+/// Sorting key of flows tables, flows are read back in this order
+pub const ORDER_BY: &[&str] = &["timestamp", "host"];
+
+/// DDL of a flows table in the current shape of `messages`, columns are equivalent to all
+/// migrations applied
+pub fn create_table_ddl(
+    table: &str,
+    coercions: &ColumnCoercions,
+    indexes: &ColumnIndexes,
+    shard_key: Option<KeyField>,
+) -> String {
+    let columns = columns(shard_key)
+        .map(|c| format!("    {} {}", c.name, c.ddl_type(coercions)))
+        .chain(indexes.ddl().map(|i| format!("    {i}")))
+        .collect::<Vec<String>>();
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n{}\n) Engine=MergeTree\nORDER BY ({})",
+        table,
+        columns.join(",\n"),
+        ORDER_BY.join(", ")
+    )
+}
+
+/// Compares columns of the existing table (name to type) with the expected ones.
+/// Additional columns in the table are fine as inserts always list their columns
+pub fn compare_columns(
+    actual: &HashMap<String, String>,
+    coercions: &ColumnCoercions,
+    shard_key: Option<KeyField>,
+) -> Vec<ColumnMismatch> {
+    columns(shard_key)
+        .filter_map(|c| {
+            let expected = c.ddl_type(coercions);
+            match actual.get(c.name) {
+                None => Some(ColumnMismatch::Missing {
+                    column: c.name.to_owned(),
+                    expected,
+                }),
+                Some(t) if *t != expected => Some(ColumnMismatch::WrongType {
+                    column: c.name.to_owned(),
+                    expected,
+                    actual: t.to_owned(),
+                }),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+/// Builds a single `messages` row of the redacted flow, replacing configured sentinels with NULL.
+/// Absent fields are stored as their configured default, NULL or zero value of the column
+pub fn build_row(
+    f: &FlowMessageWithMetadata,
+    row: &RowSettings,
+) -> Result<Vec<(String, Value)>, SchemaError> {
+    let f = row.redaction.apply(f);
+    columns(row.shard_key)
+        .map(|c| Ok((c.name.to_owned(), c.value(&f, row)?)))
+        .collect()
+}
+
+/// Hands every value of a single `messages` row to `write` in column order, like `build_row`
+/// without collecting the row first
+pub fn for_each_value(
+    f: &FlowMessageWithMetadata,
+    row: &RowSettings,
+    mut write: impl FnMut(&'static str, &Value),
+) -> Result<(), SchemaError> {
+    let f = row.redaction.apply(f);
+    for c in columns(row.shard_key) {
+        write(c.name, &c.value(&f, row)?);
+    }
+    Ok(())
+}
+
+/// Per column values which are stored as NULL instead of their raw value
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct NullSentinels(HashMap<&'static str, Value>);
+
+impl NullSentinels {
+    fn matches(&self, column: &str, raw: &Value) -> bool {
+        self.0.get(column).map_or(false, |s| s == raw)
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for NullSentinels {
+    type Error = SchemaError;
+
+    fn try_from(raw: HashMap<String, String>) -> Result<Self, Self::Error> {
+        raw.into_iter()
+            .map(|(name, sentinel)| {
+                let c = column(&name).ok_or_else(|| SchemaError::UnknownColumn(name.clone()))?;
+                if !c.nullable {
+                    return Err(SchemaError::ColumnNotNullable(name));
+                }
+
+                let value = c
+                    .column_type
+                    .parse_raw(&sentinel)
+                    .ok_or(SchemaError::InvalidSentinel(name, sentinel))?;
+                Ok((c.name, value))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map(NullSentinels)
+    }
+}
+
+/// Per column values stored when a message does not carry the field
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct MissingDefaults(HashMap<&'static str, Value>);
+
+impl MissingDefaults {
+    fn get(&self, column: &str) -> Option<&Value> {
+        self.0.get(column)
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for MissingDefaults {
+    type Error = SchemaError;
+
+    fn try_from(raw: HashMap<String, String>) -> Result<Self, Self::Error> {
+        raw.into_iter()
+            .map(|(name, default)| {
+                let c = column(&name).ok_or_else(|| SchemaError::UnknownColumn(name.clone()))?;
+                let value = c
+                    .column_type
+                    .parse_raw(&default)
+                    .ok_or_else(|| SchemaError::InvalidDefault(name.clone(), default.clone()))?;
+                // addresses are only checked once converted
+                c.column_type
+                    .convert(c.name, value.clone())
+                    .map_err(|_| SchemaError::InvalidDefault(name, default))?;
+                Ok((c.name, value))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map(MissingDefaults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use lazy_static::lazy_static;
+
+    fn sentinels(raw: &[(&str, &str)]) -> Result<NullSentinels, SchemaError> {
+        raw.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>()
+            .try_into()
+    }
+
+    lazy_static! {
+        static ref NO_COERCIONS: ColumnCoercions = ColumnCoercions::default();
+        static ref NO_REDACTION: Redaction = Redaction::default();
+    }
+
+    fn row_settings<'a>(
+        sentinels: &'a NullSentinels,
+        defaults: &'a MissingDefaults,
+    ) -> RowSettings<'a> {
+        RowSettings {
+            sentinels,
+            defaults,
+            tiers: &[],
+            coercions: &NO_COERCIONS,
+            redaction: &NO_REDACTION,
+            shard_key: None,
+        }
+    }
+
+    fn row_value(row: &[(String, Value)], column: &str) -> Value {
+        row.iter().find(|(c, _)| c == column).unwrap().1.clone()
+    }
+
+    #[test]
+    fn test_sentinels_are_validated_against_schema() {
+        assert!(sentinels(&[("l7_proto", "0"), ("ipv4_src_addr", "")]).is_ok());
+        assert_eq!(
+            sentinels(&[("unknown", "0")]),
+            Err(SchemaError::UnknownColumn("unknown".to_owned()))
+        );
+        assert_eq!(
+            sentinels(&[("out_bytes", "0")]),
+            Err(SchemaError::ColumnNotNullable("out_bytes".to_owned()))
+        );
+        assert_eq!(
+            sentinels(&[("l7_proto", "none")]),
+            Err(SchemaError::InvalidSentinel(
+                "l7_proto".to_owned(),
+                "none".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_sentinel_values_are_stored_as_null() {
+        let sentinels = sentinels(&[("l7_proto", "0"), ("ipv4_src_addr", "")]).unwrap();
+
+        let row = build_row(
+            &testutil::flow().with_src("").with_dst("10.0.0.1").build(),
+            &row_settings(&sentinels, &MissingDefaults::default()),
+        )
+        .unwrap();
+        assert_eq!(
+            row_value(&row, "l7_proto"),
+            Value::Nullable(Either::Left(&SqlType::Float32))
+        );
+        assert_eq!(
+            row_value(&row, "ipv4_src_addr"),
+            Value::Nullable(Either::Left(&SqlType::Ipv4))
+        );
+
+        let row = build_row(
+            &testutil::flow()
+                .with_src("192.168.0.1")
+                .with_dst("10.0.0.1")
+                .with_l7_proto(7.0)
+                .build(),
+            &row_settings(&sentinels, &MissingDefaults::default()),
+        )
+        .unwrap();
+        assert_eq!(
+            row_value(&row, "l7_proto"),
+            Value::Nullable(Either::Right(Box::new(Value::Float32(7.0))))
+        );
+        assert_eq!(
+            row_value(&row, "ipv4_src_addr"),
+            Value::Nullable(Either::Right(Box::new(Value::Ipv4([1, 0, 168, 192]))))
+        );
+    }
+
+    fn expected_columns() -> HashMap<String, String> {
+        MESSAGES_COLUMNS
+            .iter()
+            .map(|c| (c.name.to_owned(), c.ddl_type(&ColumnCoercions::default())))
+            .collect()
+    }
+
+    #[test]
+    fn test_matching_schema_has_no_mismatches() {
+        let mut actual = expected_columns();
+        actual.insert("extra".to_owned(), "String".to_owned());
+        assert_eq!(
+            compare_columns(&actual, &ColumnCoercions::default(), None),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_schema_mismatches_are_listed() {
+        let mut actual = expected_columns();
+        actual.remove("host");
+        // migration making the column nullable was not applied
+        actual.insert("l7_proto".to_owned(), "Float32".to_owned());
+
+        let mismatches = compare_columns(&actual, &ColumnCoercions::default(), None);
+        assert_eq!(
+            mismatches,
+            vec![
+                ColumnMismatch::Missing {
+                    column: "host".to_owned(),
+                    expected: "String".to_owned(),
+                },
+                ColumnMismatch::WrongType {
+                    column: "l7_proto".to_owned(),
+                    expected: "Nullable(Float32)".to_owned(),
+                    actual: "Float32".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            SchemaError::Mismatch(MESSAGES_TABLE.to_owned(), mismatches).to_string(),
+            "table messages does not match the expected schema: \
+            column host is missing, expected String; \
+            column l7_proto has type Float32, expected Nullable(Float32)"
+        );
+    }
+
+    #[test]
+    fn test_create_table_ddl_lists_all_columns() {
+        let ddl = create_table_ddl(
+            MESSAGES_TABLE,
+            &ColumnCoercions::default(),
+            &ColumnIndexes::default(),
+            None,
+        );
+        assert!(ddl.starts_with("CREATE TABLE IF NOT EXISTS messages ("));
+        assert!(ddl.contains("    ipv4_src_addr Nullable(IPv4),\n"));
+        assert!(ddl.contains("    timestamp DateTime\n)"));
+        assert!(ddl.ends_with("ORDER BY (timestamp, host)"));
+        // sorting key has to consist of existing columns
+        assert!(ORDER_BY.iter().all(|c| column(c).is_some()));
+        assert!(!ddl.contains("shard_key"));
+    }
+
+    #[test]
+    fn test_shard_key_column_is_stored_when_configured() {
+        let ddl = create_table_ddl(
+            MESSAGES_TABLE,
+            &ColumnCoercions::default(),
+            &ColumnIndexes::default(),
+            Some(KeyField::Host),
+        );
+        assert!(ddl.contains("    timestamp DateTime,\n    shard_key UInt32\n)"));
+        assert_eq!(
+            compare_columns(
+                &expected_columns(),
+                &ColumnCoercions::default(),
+                Some(KeyField::Host)
+            ),
+            vec![ColumnMismatch::Missing {
+                column: "shard_key".to_owned(),
+                expected: "UInt32".to_owned(),
+            }]
+        );
+
+        let sentinels = NullSentinels::default();
+        let defaults = MissingDefaults::default();
+        let f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_l7_proto(7.0)
+            .build();
+        let without = build_row(&f, &row_settings(&sentinels, &defaults)).unwrap();
+        assert!(without.iter().all(|(c, _)| c != "shard_key"));
+
+        let settings = RowSettings {
+            shard_key: Some(KeyField::SrcIp),
+            ..row_settings(&sentinels, &defaults)
+        };
+        let built = build_row(&f, &settings).unwrap();
+        assert_eq!(
+            row_value(&built, "shard_key"),
+            Value::from(keys::shard_key(KeyField::SrcIp, &f))
+        );
+    }
+
+    #[test]
+    fn test_invalid_address_is_rejected() {
+        assert_eq!(
+            build_row(
+                &testutil::flow().with_src("").with_dst("10.0.0.1").build(),
+                &row_settings(&NullSentinels::default(), &MissingDefaults::default())
+            ),
+            Err(SchemaError::InvalidValue(
+                "ipv4_src_addr".to_owned(),
+                "".to_owned()
+            ))
+        );
+    }
+
+    fn defaults(raw: &[(&str, &str)]) -> Result<MissingDefaults, SchemaError> {
+        raw.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>()
+            .try_into()
+    }
+
+    #[test]
+    fn test_defaults_are_validated_against_schema() {
+        assert!(defaults(&[("malicious", "1"), ("ipv4_src_addr", "0.0.0.0")]).is_ok());
+        assert_eq!(
+            defaults(&[("unknown", "0")]),
+            Err(SchemaError::UnknownColumn("unknown".to_owned()))
+        );
+        assert_eq!(
+            defaults(&[("ipv4_src_addr", "none")]),
+            Err(SchemaError::InvalidDefault(
+                "ipv4_src_addr".to_owned(),
+                "none".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unclassified_flow_is_stored_with_default() {
+        let row = build_row(
+            &testutil::flow()
+                .with_src("10.0.0.2")
+                .with_dst("10.0.0.1")
+                .with_l7_proto(7.0)
+                .build(),
+            &row_settings(&NullSentinels::default(), &MissingDefaults::default()),
+        )
+        .unwrap();
+        assert_eq!(row_value(&row, "malicious"), Value::UInt8(0));
+
+        let classified_as_malicious = defaults(&[("malicious", "1")]).unwrap();
+        let row = build_row(
+            &testutil::flow()
+                .with_src("10.0.0.2")
+                .with_dst("10.0.0.1")
+                .with_l7_proto(7.0)
+                .build(),
+            &row_settings(&NullSentinels::default(), &classified_as_malicious),
+        )
+        .unwrap();
+        assert_eq!(row_value(&row, "malicious"), Value::UInt8(1));
+    }
+
+    #[test]
+    fn test_absent_fields_are_stored_as_default_null_or_zero() {
+        let absent = |name, column_type, nullable| Column {
+            name,
+            column_type,
+            nullable,
+            extract: |_, _| None,
+        };
+        let f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_l7_proto(7.0)
+            .build();
+        let none = MissingDefaults::default();
+        let configured = defaults(&[("ipv4_src_addr", "10.0.0.9"), ("out_bytes", "7")]).unwrap();
+        let sentinels = NullSentinels::default();
+
+        let nullable = absent("ipv4_src_addr", ColumnType::IPv4, true);
+        assert_eq!(
+            nullable.value(&f, &row_settings(&sentinels, &none)),
+            Ok(Value::Nullable(Either::Left(&SqlType::Ipv4)))
+        );
+        assert_eq!(
+            nullable.value(&f, &row_settings(&sentinels, &configured)),
+            Ok(Value::Nullable(Either::Right(Box::new(Value::Ipv4([
+                9, 0, 0, 10
+            ])))))
+        );
+
+        let required = absent("out_bytes", ColumnType::UInt64, false);
+        assert_eq!(
+            required.value(&f, &row_settings(&sentinels, &none)),
+            Ok(Value::UInt64(0))
+        );
+        assert_eq!(
+            required.value(&f, &row_settings(&sentinels, &configured)),
+            Ok(Value::UInt64(7))
+        );
+
+        let address = absent("ipv4_dst_addr", ColumnType::IPv4, false);
+        assert_eq!(
+            address.value(&f, &row_settings(&sentinels, &none)),
+            Ok(Value::Ipv4([0, 0, 0, 0]))
+        );
+    }
+
+    #[test]
+    fn test_tier_is_stored_from_rules() {
+        let tiers = vec!["malicious=true->flagged".parse::<TierRule>().unwrap()];
+        let sentinels = NullSentinels::default();
+        let defaults = MissingDefaults::default();
+        let settings = RowSettings {
+            tiers: &tiers,
+            ..row_settings(&sentinels, &defaults)
+        };
+
+        let mut f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_l7_proto(7.0)
+            .build();
+        let built = build_row(&f, &settings).unwrap();
+        assert_eq!(row_value(&built, "tier"), Value::from("default"));
+
+        f.malicious = Some(true);
+        let built = build_row(&f, &settings).unwrap();
+        assert_eq!(row_value(&built, "tier"), Value::from("flagged"));
+    }
+
+    fn coercions(raw: &[(&str, &str)]) -> Result<ColumnCoercions, SchemaError> {
+        raw.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>()
+            .try_into()
+    }
+
+    #[test]
+    fn test_coercions_are_validated_against_schema() {
+        assert!(coercions(&[("l4_dst_port", "UInt16,clamp"), ("out_bytes", "String")]).is_ok());
+        assert_eq!(
+            coercions(&[("unknown", "UInt16")]),
+            Err(SchemaError::UnknownColumn("unknown".to_owned()))
+        );
+        assert_eq!(
+            coercions(&[("l4_dst_port", "UInt16,wrap")]),
+            Err(SchemaError::InvalidCoercion(
+                "l4_dst_port".to_owned(),
+                "UInt16,wrap".to_owned()
+            ))
+        );
+        assert_eq!(
+            coercions(&[("timestamp", "UInt32")]),
+            Err(SchemaError::InvalidCoercion(
+                "timestamp".to_owned(),
+                "UInt32".to_owned()
+            ))
+        );
+    }
+
+    fn indexes(raw: &[(&str, &str)]) -> Result<ColumnIndexes, SchemaError> {
+        raw.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>()
+            .try_into()
+    }
+
+    #[test]
+    fn test_indexes_are_appended_to_created_tables() {
+        let indexes = indexes(&[
+            ("ipv4_dst_addr", "bloom_filter(0.01),4"),
+            ("host", "ngrambf_v1(3,256, 2,0)"),
+            ("l4_dst_port", "set(100)"),
+            ("protocol", "minmax"),
+        ])
+        .unwrap();
+        let ddl = create_table_ddl(MESSAGES_TABLE, &ColumnCoercions::default(), &indexes, None);
+        assert!(ddl.contains(
+            "    timestamp DateTime,\n    \
+            INDEX idx_host host TYPE ngrambf_v1(3, 256, 2, 0) GRANULARITY 1,\n    \
+            INDEX idx_ipv4_dst_addr ipv4_dst_addr TYPE bloom_filter(0.01) GRANULARITY 4,\n    \
+            INDEX idx_l4_dst_port l4_dst_port TYPE set(100) GRANULARITY 1,\n    \
+            INDEX idx_protocol protocol TYPE minmax GRANULARITY 1\n)"
+        ));
+
+        for (column, index) in [
+            ("ipv4_dst_addr", "btree"),
+            ("ipv4_dst_addr", "bloom_filter(0.01"),
+            ("ipv4_dst_addr", "minmax,0"),
+            ("ipv4_dst_addr", "minmax()"),
+            ("ipv4_dst_addr", "bloom_filter(1.5)"),
+            (
+                "ipv4_dst_addr",
+                "bloom_filter(0.01) GRANULARITY 1; DROP TABLE x; --)",
+            ),
+            ("l4_dst_port", "set(-1)"),
+            ("l4_dst_port", "set(1, 2)"),
+            ("host", "ngrambf_v1(3, 256, 2)"),
+            ("host", "tokenbf_v1(0, 2, 0)"),
+        ] {
+            assert_eq!(
+                self::indexes(&[(column, index)]),
+                Err(SchemaError::InvalidIndex(
+                    column.to_owned(),
+                    index.to_owned()
+                ))
+            );
+        }
+        assert_eq!(
+            self::indexes(&[("unknown", "minmax")]),
+            Err(SchemaError::UnknownColumn("unknown".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_values_are_clamped_or_rejected() {
+        let sentinels = NullSentinels::default();
+        let defaults = MissingDefaults::default();
+        let mut f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_l7_proto(7.0)
+            .build();
+        f.flow_message.l4_dst_port = 70_000;
+        f.flow_message.l4_src_port = 443;
+
+        let clamped =
+            coercions(&[("l4_dst_port", "UInt16,clamp"), ("l4_src_port", "UInt16")]).unwrap();
+        let settings = RowSettings {
+            coercions: &clamped,
+            ..row_settings(&sentinels, &defaults)
+        };
+        let row = build_row(&f, &settings).unwrap();
+        assert_eq!(row_value(&row, "l4_dst_port"), Value::UInt16(u16::MAX));
+        assert_eq!(row_value(&row, "l4_src_port"), Value::UInt16(443));
+
+        let rejected = coercions(&[("l4_dst_port", "UInt16,reject")]).unwrap();
+        let settings = RowSettings {
+            coercions: &rejected,
+            ..row_settings(&sentinels, &defaults)
+        };
+        assert_eq!(
+            build_row(&f, &settings),
+            Err(SchemaError::OutOfRange(
+                "l4_dst_port".to_owned(),
+                "70000".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_counters_at_and_beyond_their_column_range() {
+        let sentinels = NullSentinels::default();
+        let defaults = MissingDefaults::default();
+        let mut f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_l7_proto(7.0)
+            .build();
+        f.flow_message.out_bytes = u64::MAX;
+        f.flow_message.in_bytes = u32::MAX as u64;
+        f.flow_message.in_pkts = u32::MAX as u64 + 1;
+
+        // counters fit the UInt64 columns of the messages schema
+        let row = build_row(&f, &row_settings(&sentinels, &defaults)).unwrap();
+        assert_eq!(row_value(&row, "out_bytes"), Value::UInt64(u64::MAX));
+
+        let narrow = || {
+            coercions(&[
+                ("in_bytes", "UInt32"),
+                ("in_pkts", "UInt32"),
+                ("l4_dst_port", "UInt16"),
+                ("out_pkts", "String"),
+            ])
+            .unwrap()
+        };
+        let build = |coercions: &ColumnCoercions| {
+            build_row(
+                &f,
+                &RowSettings {
+                    coercions,
+                    ..row_settings(&sentinels, &defaults)
+                },
+            )
+        };
+
+        let saturated = narrow().with_counter_overflow(Some(CounterOverflow::Saturate));
+        let row = build(&saturated).unwrap();
+        assert_eq!(row_value(&row, "in_bytes"), Value::UInt32(u32::MAX));
+        assert_eq!(row_value(&row, "in_pkts"), Value::UInt32(u32::MAX));
+
+        let dead_lettered = narrow().with_counter_overflow(Some(CounterOverflow::DeadLetter));
+        assert_eq!(
+            build(&dead_lettered),
+            Err(SchemaError::Overflow(
+                "in_pkts".to_owned(),
+                "4294967296".to_owned()
+            ))
+        );
+
+        let widened = narrow().with_counter_overflow(Some(CounterOverflow::Widen));
+        let row = build(&widened).unwrap();
+        assert_eq!(
+            row_value(&row, "in_pkts"),
+            Value::UInt64(u32::MAX as u64 + 1)
+        );
+        // only numeric counters are affected
+        assert_eq!(widened.get("l4_dst_port"), narrow().get("l4_dst_port"));
+        assert_eq!(widened.get("out_pkts"), narrow().get("out_pkts"));
+        let ddl = create_table_ddl(MESSAGES_TABLE, &widened, &ColumnIndexes::default(), None);
+        assert!(ddl.contains("    in_pkts UInt64,\n"));
+
+        // without a policy counters overflow as their coercions say
+        assert_eq!(
+            build(&narrow().with_counter_overflow(None)),
+            Err(SchemaError::OutOfRange(
+                "in_pkts".to_owned(),
+                "4294967296".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_numbers_are_coerced_to_legacy_string_columns() {
+        let sentinels = NullSentinels::default();
+        let defaults = MissingDefaults::default();
+        let legacy = coercions(&[("out_bytes", "String"), ("l7_proto", "String")]).unwrap();
+        let settings = RowSettings {
+            coercions: &legacy,
+            ..row_settings(&sentinels, &defaults)
+        };
+
+        let mut f = testutil::flow()
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_l7_proto(7.5)
+            .build();
+        f.flow_message.out_bytes = 1024;
+        let row = build_row(&f, &settings).unwrap();
+        assert_eq!(row_value(&row, "out_bytes"), Value::from("1024"));
+        assert_eq!(
+            row_value(&row, "l7_proto"),
+            Value::Nullable(Either::Right(Box::new(Value::from("7.5"))))
+        );
+
+        let ddl = create_table_ddl(MESSAGES_TABLE, &legacy, &ColumnIndexes::default(), None);
+        assert!(ddl.contains("    out_bytes String,\n"));
+        assert!(ddl.contains("    l7_proto Nullable(String),\n"));
+    }
+}
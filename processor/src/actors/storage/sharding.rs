@@ -0,0 +1,94 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+use crate::keys::{self, KeyField};
+
+use serde::{Deserialize, Deserializer};
+
+pub const DEFAULT_LOCAL_TABLE_SUFFIX: &str = "_local";
+
+/// Where batches of flows are inserted on a sharded cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum InsertMode {
+    /// into the tables on `host`, a `Distributed` table forwards rows to the shards
+    #[default]
+    Distributed,
+    /// straight into the local tables of the shard each row belongs to
+    DirectShard,
+}
+
+/// Comma separated hosts of the shards in the order of the cluster definition
+pub fn deserialize_shards<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    Ok(String::deserialize(deserializer)?
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Shard the `Distributed` engine would send the flow to, given `shard_key` as its sharding
+/// expression and shards of equal weight
+pub fn shard_index(key: KeyField, f: &FlowMessageWithMetadata, shards: usize) -> usize {
+    keys::shard_key(key, f) as usize % shards
+}
+
+/// Splits a batch into batches per shard, keeping the order of flows within each of them
+pub fn split_by_shard(
+    key: KeyField,
+    shards: usize,
+    msgs: Vec<FlowMessageWithMetadata>,
+) -> Vec<(usize, Vec<FlowMessageWithMetadata>)> {
+    let mut batches: Vec<(usize, Vec<FlowMessageWithMetadata>)> = Vec::new();
+    for f in msgs {
+        let shard = shard_index(key, &f, shards);
+        match batches.iter_mut().find(|(s, _)| *s == shard) {
+            Some((_, batch)) => batch.push(f),
+            None => batches.push((shard, vec![f])),
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_flows_are_split_by_their_shard_key() {
+        let msgs = ["edge-1", "edge-2", "edge-1", "edge-3", "edge-2"]
+            .iter()
+            .enumerate()
+            .map(|(i, host)| {
+                testutil::flow()
+                    .with_host(host)
+                    .with_offset(i as i64)
+                    .build()
+            })
+            .collect::<Vec<FlowMessageWithMetadata>>();
+
+        let batches = split_by_shard(KeyField::Host, 3, msgs.clone());
+        for (shard, batch) in &batches {
+            assert!(batch
+                .iter()
+                .all(|f| keys::shard_key(KeyField::Host, f) as usize % 3 == *shard));
+        }
+        // flows of a host stay together and in order
+        let edge_1 = batches
+            .iter()
+            .find(|(s, _)| *s == shard_index(KeyField::Host, &msgs[0], 3))
+            .unwrap();
+        let offsets = edge_1
+            .1
+            .iter()
+            .filter(|f| f.metadata.host == "edge-1")
+            .map(|f| f.metadata.offset.unwrap())
+            .collect::<Vec<i64>>();
+        assert_eq!(offsets, vec![0, 2]);
+        assert_eq!(batches.iter().map(|(_, b)| b.len()).sum::<usize>(), 5);
+
+        assert_eq!(split_by_shard(KeyField::Host, 1, msgs).len(), 1);
+    }
+}
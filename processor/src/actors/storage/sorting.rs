@@ -0,0 +1,102 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+
+use serde::{Deserialize, Deserializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownSortField(String);
+
+impl fmt::Display for UnknownSortField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown sort field: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownSortField {}
+
+/// Column of a flows table a flush buffer can be sorted by, named as the column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Host,
+    Timestamp,
+    Ipv4SrcAddr,
+    Ipv4DstAddr,
+    L4SrcPort,
+    L4DstPort,
+    Protocol,
+}
+
+impl FromStr for SortField {
+    type Err = UnknownSortField;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(Self::Host),
+            "timestamp" => Ok(Self::Timestamp),
+            "ipv4_src_addr" => Ok(Self::Ipv4SrcAddr),
+            "ipv4_dst_addr" => Ok(Self::Ipv4DstAddr),
+            "l4_src_port" => Ok(Self::L4SrcPort),
+            "l4_dst_port" => Ok(Self::L4DstPort),
+            "protocol" => Ok(Self::Protocol),
+            other => Err(UnknownSortField(other.to_owned())),
+        }
+    }
+}
+
+/// Orders addresses like a `Nullable(IPv4)` column, numerically. Addresses which are not ipv4
+/// are stored as `NULL`, which comes after every address
+fn compare_addresses(a: &str, b: &str) -> Ordering {
+    match (a.parse::<Ipv4Addr>(), b.parse::<Ipv4Addr>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => Ordering::Equal,
+    }
+}
+
+impl SortField {
+    fn compare(&self, a: &FlowMessageWithMetadata, b: &FlowMessageWithMetadata) -> Ordering {
+        let (fa, fb) = (&a.flow_message, &b.flow_message);
+        match self {
+            Self::Host => a.metadata.host.cmp(&b.metadata.host),
+            Self::Timestamp => a.metadata.timestamp.cmp(&b.metadata.timestamp),
+            Self::Ipv4SrcAddr => compare_addresses(&fa.ipv4_src_addr, &fb.ipv4_src_addr),
+            Self::Ipv4DstAddr => compare_addresses(&fa.ipv4_dst_addr, &fb.ipv4_dst_addr),
+            Self::L4SrcPort => fa.l4_src_port.cmp(&fb.l4_src_port),
+            Self::L4DstPort => fa.l4_dst_port.cmp(&fb.l4_dst_port),
+            Self::Protocol => fa.protocol.cmp(&fb.protocol),
+        }
+    }
+}
+
+/// Accepts fields separated with `,`, e.g. `host,timestamp`
+pub fn deserialize_fields<'de, D>(deserializer: D) -> Result<Vec<SortField>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(SortField::from_str)
+        .collect::<Result<Vec<SortField>, UnknownSortField>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Sorts flows by the fields in order, so an inserted block is already ordered like the
+/// table's `ORDER BY` and merges have less to do. Flows equal on all fields keep their order
+pub fn sort(fields: &[SortField], msgs: &mut [FlowMessageWithMetadata]) {
+    if fields.is_empty() {
+        return;
+    }
+    msgs.sort_by(|a, b| {
+        fields
+            .iter()
+            .map(|field| field.compare(a, b))
+            .find(|o| o.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+}
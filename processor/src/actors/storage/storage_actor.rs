@@ -1,14 +1,23 @@
-use super::consts::{STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS, STORAGE_MAX_BUFFER_SIZE};
+use super::coalesce::{coalesce, CoalesceField};
+use super::consts::STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS;
+use super::sampling::{sampled_out_ack, Sampling, StorageSampler};
 
-use tokio::time::interval;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep_until, Instant};
 
 use crate::actors::broker::Broker;
 use crate::actors::event_stream::messages::FlushCollectedEventsToPipeline;
 use crate::actors::messages::AckMessage;
 use crate::actors::messages::FlowMessageWithMetadata;
 use crate::actors::messages::PersistFlowMessageWithMetadata;
+use crate::actors::messages::{PersistAck, PersistError};
 use actix::Actor;
 
+use futures::future::join_all;
 use futures::stream::StreamExt;
 use log::error;
 
@@ -21,29 +30,64 @@ use async_trait::async_trait;
 
 use log::info;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
 use tokio::sync::Mutex as TokioMtx;
+use tokio::sync::Notify;
 
 use super::super::consts::MAILBOX_CAPACITY;
-use super::messages::InitFlusher;
+use super::messages::{DrainStorage, InitFlusher, StopStorage};
 
 use crate::actors::BrokerType;
+use crate::metrics::{
+    STORAGE_COALESCED_ROWS, STORAGE_INFLIGHT_INSERTS, STORAGE_INSERTED_ROWS, STORAGE_REJECTED_ROWS,
+};
+use crate::settings::StorageSettings;
+use crate::shutdown::Signal;
+use crate::webhook::FlaggedFlowWebhook;
 
 #[derive(Debug)]
 pub enum StorageError {
     Database(Box<dyn Error>),
     DatabaseSave((Box<dyn Error>, Vec<AckMessage>)),
+    /// Connection was not acquired in time, nothing was saved
+    Timeout(Vec<AckMessage>),
 }
 
+/// Outcome of a saved batch, `acks` also carry retries of rows which were not inserted
+#[derive(Debug)]
+pub struct InsertResult {
+    pub acks: Vec<AckMessage>,
+    /// rows accepted by the database
+    pub inserted: usize,
+    /// rows which never reached the database, e.g. failed to be encoded
+    pub rejected: usize,
+}
+
+impl InsertResult {
+    pub fn new(acks: Vec<AckMessage>, inserted: usize) -> Self {
+        let rejected = acks
+            .iter()
+            .filter(|a| !matches!(a, AckMessage::Ack(..)))
+            .count();
+
+        Self {
+            acks,
+            inserted,
+            rejected,
+        }
+    }
+}
+
+/// Flow waiting in the storage channel with its ack, if anyone awaits its persistence
+type QueuedFlow = (FlowMessageWithMetadata, Option<PersistAck>);
+
 pub type FlowMessageStream = futures::stream::Iter<std::vec::IntoIter<FlowMessageWithMetadata>>;
 pub type OffsetStream = futures::stream::Iter<std::vec::IntoIter<i64>>;
 #[async_trait]
 pub trait AStorage: 'static {
-    async fn stash(
-        &self,
-        msgs: Vec<FlowMessageWithMetadata>,
-    ) -> Result<Vec<AckMessage>, StorageError>;
+    async fn stash(&self, msgs: Vec<FlowMessageWithMetadata>)
+        -> Result<InsertResult, StorageError>;
 }
 
 pub struct StorageActor<S>
@@ -51,23 +95,220 @@ where
     S: AStorage,
 {
     storage: Arc<S>,
-    buffer: Arc<Mutex<Vec<FlowMessageWithMetadata>>>,
+    tx: UnboundedSender<QueuedFlow>,
+    /// shared by all flush workers, each takes its own batch from it
+    rx: Arc<TokioMtx<UnboundedReceiver<QueuedFlow>>>,
+    pending: Arc<PendingBatch>,
     pub broker: Arc<TokioMtx<Broker>>,
+    settings: StorageSettings,
+    shutdown: watch::Sender<bool>,
+    /// raised once all workers stopped
+    stopped: Arc<Signal>,
+    webhook: Option<Arc<FlaggedFlowWebhook>>,
+    sampler: StorageSampler,
+}
+
+/// Size of messages waiting in the storage channel, wakes a worker once a batch is full
+struct PendingBatch {
+    count: AtomicUsize,
+    bytes: AtomicUsize,
+    /// settings are read from the environment once, so the size is fixed for the process
+    max_count: usize,
+    /// 0 disables the byte limit
+    max_bytes: usize,
+    /// set on shutdown, any waiting row is flushed right away
+    draining: AtomicBool,
+    full: Notify,
+    /// none disables the age limit
+    max_age: Option<Duration>,
+    /// when waiting rows were queued, oldest first, taken in the order of the channel
+    queued_at: std::sync::Mutex<VecDeque<Instant>>,
+    /// raised when a row is queued into an empty channel
+    first_row: Notify,
+    /// number of flush workers sharing the channel
+    workers: usize,
+    /// workers still to take their share of rows split on the interval
+    split_left: AtomicUsize,
+}
+
+impl PendingBatch {
+    fn new(max_count: usize, max_bytes: usize, max_age: Option<Duration>, workers: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_count: max_count.max(1),
+            max_bytes,
+            draining: AtomicBool::new(false),
+            full: Notify::new(),
+            max_age,
+            queued_at: std::sync::Mutex::new(VecDeque::new()),
+            first_row: Notify::new(),
+            workers: workers.max(1),
+            split_left: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_full(&self, count: usize, bytes: usize) -> bool {
+        count >= self.max_count || (self.max_bytes > 0 && bytes >= self.max_bytes)
+    }
+
+    fn should_flush(&self, count: usize, bytes: usize) -> bool {
+        self.is_full(count, bytes) || (count > 0 && self.draining.load(Ordering::SeqCst))
+    }
+
+    fn add(&self, bytes: usize) {
+        if self.max_age.is_some() {
+            let mut queued_at = self.queued_at.lock().unwrap();
+            queued_at.push_back(Instant::now());
+            if queued_at.len() == 1 {
+                self.first_row.notify_waiters();
+            }
+        }
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        let bytes = self.bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if self.should_flush(count, bytes) {
+            self.full.notify_one();
+        }
+    }
+
+    /// Wakes every worker, a worker busy with an insert picks up the rest once it is done
+    fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.full.notify_waiters();
+        self.notify_if_full();
+    }
+
+    /// Most rows a worker takes at once. Rows short of a full batch are split evenly between
+    /// idle workers, so one of them does not insert everything waiting while the rest stay idle
+    fn batch_limit(&self) -> usize {
+        let count = self.count.load(Ordering::SeqCst);
+        let max_count = self.max_count;
+        if count >= max_count || self.draining.load(Ordering::SeqCst) {
+            return max_count;
+        }
+        if count == 0 {
+            return 0;
+        }
+
+        let parts = match self
+            .split_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            }) {
+            // share of a split another worker started, including the one being taken
+            Ok(left) => left,
+            Err(_) => {
+                self.split_left.store(self.workers - 1, Ordering::SeqCst);
+                self.full.notify_waiters();
+                self.workers
+            }
+        };
+        (count + parts - 1) / parts
+    }
+
+    /// Undoes `add` of a row which did not make it into the channel
+    fn cancel(&self, bytes: usize) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        self.bytes.fetch_sub(bytes, Ordering::SeqCst);
+        if self.max_age.is_some() {
+            self.queued_at.lock().unwrap().pop_back();
+        }
+    }
+
+    fn remove(&self, count: usize, bytes: usize) {
+        self.count.fetch_sub(count, Ordering::SeqCst);
+        self.bytes.fetch_sub(bytes, Ordering::SeqCst);
+        if self.max_age.is_some() {
+            let mut queued_at = self.queued_at.lock().unwrap();
+            let taken = count.min(queued_at.len());
+            queued_at.drain(..taken);
+        }
+    }
+
+    /// Resolves once the oldest waiting row is older than the maximum age, never without it.
+    /// Unlike the interval, the age is not reset by a flush which left older rows waiting
+    async fn expired(&self) {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return std::future::pending().await,
+        };
+        loop {
+            // registered before the check, so a row queued right after it is not missed
+            let first_row = self.first_row.notified();
+            let oldest = self.queued_at.lock().unwrap().front().copied();
+            match oldest {
+                Some(queued_at) if queued_at.elapsed() >= max_age => return,
+                Some(queued_at) => sleep_until(queued_at + max_age).await,
+                None => first_row.await,
+            }
+        }
+    }
+
+    /// Wakes another worker if more than a batch was waiting
+    fn notify_if_full(&self) {
+        let count = self.count.load(Ordering::SeqCst);
+        let bytes = self.bytes.load(Ordering::SeqCst);
+        if self.should_flush(count, bytes) {
+            self.full.notify_one();
+        }
+    }
+}
+
+/// Shared by all flush workers, what happens to a batch around its insert
+#[derive(Clone)]
+struct FlushContext {
+    broker: Arc<TokioMtx<Broker>>,
+    webhook: Option<Arc<FlaggedFlowWebhook>>,
+    /// updates of the same flow within a batch are merged by this key before the insert
+    coalesce_key: Option<Arc<[CoalesceField]>>,
+    /// bounds inserts running at once, workers wait for a permit before taking a batch
+    inserts: Option<Arc<Semaphore>>,
+}
+
+impl FlushContext {
+    /// Waits until fewer than the maximum inserts are running, rows keep waiting in the
+    /// channel meanwhile and their capacity is not freed, which backpressures the consumer
+    async fn insert_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let inserts = self.inserts.clone()?;
+        inserts.acquire_owned().await.ok()
+    }
 }
 
 impl<S> StorageActor<S>
 where
     S: AStorage,
 {
-    pub fn new(storage: Arc<S>, broker: Arc<TokioMtx<Broker>>) -> Self {
-        let buffer = Arc::new(Mutex::new(Vec::with_capacity(STORAGE_MAX_BUFFER_SIZE)));
+    pub fn new(storage: Arc<S>, broker: Arc<TokioMtx<Broker>>, settings: StorageSettings) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (shutdown, _) = watch::channel(false);
+        let pending = Arc::new(PendingBatch::new(
+            settings.flush_batch_size,
+            settings.flush_max_bytes,
+            (settings.max_batch_age_ms > 0)
+                .then(|| Duration::from_millis(settings.max_batch_age_ms)),
+            settings.storage_workers,
+        ));
+        let sampler = StorageSampler::new(&settings);
 
         Self {
             storage,
-            buffer,
+            tx,
+            rx: Arc::new(TokioMtx::new(rx)),
+            pending,
             broker,
+            settings,
+            shutdown,
+            stopped: Arc::new(Signal::default()),
+            webhook: None,
+            sampler,
         }
     }
+
+    /// Flagged flows are posted to the webhook once they are stored
+    pub fn with_webhook(mut self, webhook: Option<Arc<FlaggedFlowWebhook>>) -> Self {
+        self.webhook = webhook;
+        self
+    }
 }
 
 impl<S> Actor for StorageActor<S>
@@ -91,6 +332,12 @@ where
         });
         info!("[storage actor] subscribe to desired kind of messages");
     }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
+        // workers save what they hold before they exit
+        let _ = self.shutdown.send(true);
+        actix::Running::Stop
+    }
 }
 
 impl<S> Handler<PersistFlowMessageWithMetadata> for StorageActor<S>
@@ -104,12 +351,71 @@ where
         msg: PersistFlowMessageWithMetadata,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let buffer = self.buffer.clone();
+        // sampled out flows are acknowledged as if they were stored
+        if self.sampler.sample(&msg.0) == Sampling::Dropped {
+            if let Some(ack) = msg.1 {
+                ack.send(Ok(()));
+            }
+            if let Some(ack) = sampled_out_ack(&msg.0) {
+                let broker = self.broker.clone();
+                tokio::spawn(async move {
+                    broker.lock().await.issue_async(ack);
+                });
+            }
+            return;
+        }
+
+        // counted before it is sent, a worker could take it and subtract it first otherwise
+        let bytes = msg.0.estimated_bytes();
+        self.pending.add(bytes);
+        if self.tx.send((msg.0, msg.1)).is_err() {
+            self.pending.cancel(bytes);
+            error!("storage channel is closed, message is lost");
+        }
+    }
+}
+
+impl<S> Handler<DrainStorage> for StorageActor<S>
+where
+    S: AStorage + Unpin,
+{
+    type Result = ();
+
+    fn handle(&mut self, _msg: DrainStorage, _ctx: &mut Self::Context) -> Self::Result {
+        info!("draining storage buffer");
+        self.pending.drain();
+    }
+}
+
+impl<S> Handler<StopStorage> for StorageActor<S>
+where
+    S: AStorage + Unpin,
+{
+    type Result = ResponseFuture<()>;
 
-        buffer.lock().unwrap().push(msg.0);
+    fn handle(&mut self, _msg: StopStorage, _ctx: &mut Self::Context) -> Self::Result {
+        // workers save what they hold before they exit
+        let _ = self.shutdown.send(true);
+        let stopped = self.stopped.clone();
+        Box::pin(async move { stopped.wait().await })
     }
 }
 
+fn random_delay<R: Rng>(rng: &mut R, max_ms: u64) -> Duration {
+    Duration::from_millis(rng.gen_range(0..=max_ms))
+}
+
+/// Interval to the next flush, stretched by a random jitter
+fn next_flush_delay<R: Rng>(rng: &mut R, jitter_ms: u64) -> Duration {
+    STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS + random_delay(rng, jitter_ms)
+}
+
+/// Workers take turns across the flush interval, so their intervals do not align.
+/// The last one flushes first a full interval after start, like a single worker does
+fn stagger_delay(worker: usize, workers: usize) -> Duration {
+    STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS * (worker + 1) as u32 / workers.max(1) as u32
+}
+
 async fn after_stash_action(broker: &Arc<TokioMtx<Broker>>, msgs: Vec<AckMessage>) -> usize {
     let msgs_len = msgs.len();
     let mut broker = broker.lock().await;
@@ -119,6 +425,199 @@ async fn after_stash_action(broker: &Arc<TokioMtx<Broker>>, msgs: Vec<AckMessage
     msgs_len
 }
 
+/// Takes messages waiting in the shared channel until the batch is full by count or bytes,
+/// or until the worker took its share of a split
+async fn take_batch(
+    rx: &TokioMtx<UnboundedReceiver<QueuedFlow>>,
+    pending: &PendingBatch,
+) -> Vec<QueuedFlow> {
+    let mut rx = rx.lock().await;
+    let limit = pending.batch_limit();
+    let mut batch = Vec::new();
+    let mut bytes = 0;
+    while batch.len() < limit && !pending.is_full(batch.len(), bytes) {
+        match rx.try_recv() {
+            Ok(msg) => {
+                bytes += msg.0.estimated_bytes();
+                batch.push(msg);
+            }
+            Err(_) => break,
+        }
+    }
+    pending.remove(batch.len(), bytes);
+    batch
+}
+
+/// Whether the row was not stored and went to retry or the dead letter topic instead
+fn is_retried(acks: &[AckMessage], id: &str) -> bool {
+    acks.iter().any(|a| {
+        matches!(a, AckMessage::NackRetry(f) | AckMessage::NackDeadLetter(f, _) if f.metadata.id == id)
+    })
+}
+
+/// Posts stored flagged flows, rows sent to retry are posted once they are stored
+fn notify_flagged(
+    webhook: &FlaggedFlowWebhook,
+    flagged: Vec<FlowMessageWithMetadata>,
+    acks: &[AckMessage],
+) {
+    for flow in flagged {
+        if !is_retried(acks, &flow.metadata.id) {
+            webhook.notify(&flow);
+        }
+    }
+}
+
+/// Tells flows awaiting persistence how their batch went, `stashed` are acks of a saved batch
+fn send_persist_acks(
+    awaited: Vec<(String, PersistAck)>,
+    stashed: Result<&[AckMessage], PersistError>,
+) {
+    for (id, ack) in awaited {
+        let result = match &stashed {
+            Ok(acks) if is_retried(acks, &id) => Err(PersistError::Rejected),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.clone()),
+        };
+        ack.send(result);
+    }
+}
+
+async fn flush_batch<S: AStorage>(
+    worker: usize,
+    storage: &S,
+    ctx: &FlushContext,
+    batch: Vec<QueuedFlow>,
+) {
+    // nothing is allocated unless someone awaits persistence
+    let mut awaited = Vec::new();
+    let batch = batch
+        .into_iter()
+        .map(|(flow, ack)| {
+            if let Some(ack) = ack {
+                awaited.push((flow.metadata.id.clone(), ack));
+            }
+            flow
+        })
+        .collect::<Vec<FlowMessageWithMetadata>>();
+
+    let broker = &ctx.broker;
+    let webhook = ctx.webhook.as_deref();
+    let (batch, merged) = match &ctx.coalesce_key {
+        Some(key) => coalesce(batch, key),
+        None => (batch, Vec::new()),
+    };
+    STORAGE_COALESCED_ROWS.inc_by(merged.len() as u64);
+
+    let flagged = match webhook {
+        Some(webhook) => batch
+            .iter()
+            .filter(|f| webhook.is_flagged(f))
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    STORAGE_INFLIGHT_INSERTS.inc();
+    let stashed = storage.stash(batch).await;
+    STORAGE_INFLIGHT_INSERTS.dec();
+
+    let capacity_freed = match stashed {
+        Ok(result) => {
+            send_persist_acks(awaited, Ok(&result.acks));
+            if let Some(webhook) = webhook {
+                notify_flagged(webhook, flagged, &result.acks);
+            }
+            STORAGE_INSERTED_ROWS.inc_by(result.inserted as u64);
+            STORAGE_REJECTED_ROWS.inc_by(result.rejected as u64);
+            info!(
+                "[storage worker {}] saved batch of {} rows ({} rejected), processing rps: {}",
+                worker,
+                result.inserted,
+                result.rejected,
+                result.inserted as u64 / STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS.as_secs()
+            );
+            after_stash_action(broker, result.acks).await
+        }
+        Err(StorageError::DatabaseSave((e, s))) => {
+            error!("[storage worker {}] failed to save batch: {:?}", worker, e);
+            send_persist_acks(awaited, Err(PersistError::BatchFailed));
+            after_stash_action(broker, s).await
+        }
+        Err(StorageError::Timeout(s)) => {
+            error!(
+                "[storage worker {}] failed to save batch: timed out acquiring connection",
+                worker
+            );
+            send_persist_acks(awaited, Err(PersistError::BatchFailed));
+            after_stash_action(broker, s).await
+        }
+        Err(_) => {
+            panic!("it is imposible to be here")
+        }
+    };
+    // merged updates are acknowledged once the row they were merged into is handled
+    let capacity_freed = capacity_freed + after_stash_action(broker, merged).await;
+    info!("storage buffer freed: {capacity_freed:?}");
+    broker
+        .lock()
+        .await
+        .issue_async(FlushCollectedEventsToPipeline(capacity_freed));
+}
+
+/// Flushes its own batch every interval, once a batch is full or its oldest row is too old,
+/// drains the channel once shutdown is signalled
+async fn flush_worker<S: AStorage>(
+    worker: usize,
+    storage: Arc<S>,
+    rx: Arc<TokioMtx<UnboundedReceiver<QueuedFlow>>>,
+    pending: Arc<PendingBatch>,
+    ctx: FlushContext,
+    jitter_ms: u64,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    // spread workers across the flush interval, and instances started at the same time
+    // by a random delay. Being woken up earlier does not move the turn of a worker
+    let mut next_flush = Instant::now() + stagger_delay(worker, pending.workers);
+    if jitter_ms > 0 {
+        let max_initial_ms = STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS.as_millis() as u64;
+        next_flush += random_delay(&mut rand::thread_rng(), max_initial_ms);
+    }
+
+    loop {
+        let stopping = tokio::select! {
+            _ = sleep_until(next_flush) => {
+                next_flush = Instant::now() + next_flush_delay(&mut rand::thread_rng(), jitter_ms);
+                false
+            }
+            _ = pending.full.notified() => false,
+            _ = pending.expired() => false,
+            _ = shutdown.changed() => true,
+        };
+
+        let permit = ctx.insert_permit().await;
+        let batch = take_batch(&rx, &pending).await;
+        pending.notify_if_full();
+        if !batch.is_empty() {
+            flush_batch(worker, storage.as_ref(), &ctx, batch).await;
+        }
+        drop(permit);
+
+        if stopping {
+            loop {
+                let _permit = ctx.insert_permit().await;
+                let batch = take_batch(&rx, &pending).await;
+                if batch.is_empty() {
+                    break;
+                }
+                flush_batch(worker, storage.as_ref(), &ctx, batch).await;
+            }
+            info!("[storage worker {}] drained, stopping", worker);
+            return;
+        }
+    }
+}
+
 impl<S> Handler<InitFlusher> for StorageActor<S>
 where
     S: AStorage + Unpin,
@@ -126,43 +625,429 @@ where
     type Result = ResponseFuture<()>;
 
     fn handle(&mut self, _msg: InitFlusher, _ctx: &mut Self::Context) -> Self::Result {
-        let mut interval = interval(STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS);
-        let storage = self.storage.clone();
-        let buffer = self.buffer.clone();
-        let broker = self.broker.clone();
+        let ctx = FlushContext {
+            broker: self.broker.clone(),
+            webhook: self.webhook.clone(),
+            coalesce_key: self
+                .settings
+                .coalesce_flows
+                .then(|| self.settings.coalesce_key.clone().into()),
+            inserts: (self.settings.max_inflight_inserts > 0)
+                .then(|| Arc::new(Semaphore::new(self.settings.max_inflight_inserts))),
+        };
+        let workers = (0..self.settings.storage_workers.max(1))
+            .map(|worker| {
+                flush_worker(
+                    worker,
+                    self.storage.clone(),
+                    self.rx.clone(),
+                    self.pending.clone(),
+                    ctx.clone(),
+                    self.settings.flush_jitter_ms,
+                    self.shutdown.subscribe(),
+                )
+            })
+            .collect::<Vec<_>>();
+        info!(
+            "started {} storage workers, at most {} inserts at once{}",
+            workers.len(),
+            match self.settings.max_inflight_inserts {
+                0 => workers.len(),
+                max => max.min(workers.len()),
+            },
+            if self.settings.preserve_order {
+                ", order preserved"
+            } else {
+                ""
+            }
+        );
+        let stopped = self.stopped.clone();
 
         Box::pin(async move {
-            loop {
-                interval.tick().await;
-                let messages_to_save = buffer
-                    .lock()
-                    .unwrap()
-                    .drain(..)
-                    .collect::<Vec<FlowMessageWithMetadata>>();
-
-                info!(
-                    "saved batch processing rps: {}",
-                    messages_to_save.len() as u64 / STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS.as_secs()
-                );
-
-                if !messages_to_save.is_empty() {
-                    let capacity_freed = match storage.stash(messages_to_save).await {
-                        Ok(s) => after_stash_action(&broker, s).await,
-                        Err(StorageError::DatabaseSave((e, s))) => {
-                            error!("failed to save batch: {:?}", e);
-                            after_stash_action(&broker, s).await
-                        }
-                        Err(_) => {
-                            panic!("it is imposible to be here")
-                        }
-                    };
-                    info!("storage buffer freed: {capacity_freed:?}");
-                    broker
-                        .lock()
-                        .await
-                        .issue_async(FlushCollectedEventsToPipeline(capacity_freed));
-                }
+            join_all(workers).await;
+            stopped.raise();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::consts::STORAGE_MAX_BUFFER_SIZE;
+    use super::*;
+    use crate::testutil;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::time::sleep;
+
+    #[test]
+    fn test_insert_result_counts_rejected_rows() {
+        let result = InsertResult::new(
+            vec![
+                AckMessage::Ack("flows".to_owned(), 1, 0),
+                AckMessage::Ack("flows".to_owned(), 2, 0),
+                AckMessage::NackRetry(testutil::flow().with_offset(3).build()),
+            ],
+            2,
+        );
+
+        assert_eq!(result.inserted, 2);
+        assert_eq!(result.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_take_batch_leaves_rest_for_other_workers() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = TokioMtx::new(rx);
+        let pending = PendingBatch::new(STORAGE_MAX_BUFFER_SIZE, 0, None, 1);
+        for _ in 0..STORAGE_MAX_BUFFER_SIZE + 5 {
+            let flow = testutil::flow().build();
+            pending.add(flow.estimated_bytes());
+            tx.send((flow, None)).unwrap();
+        }
+
+        assert_eq!(
+            take_batch(&rx, &pending).await.len(),
+            STORAGE_MAX_BUFFER_SIZE
+        );
+        assert_eq!(take_batch(&rx, &pending).await.len(), 5);
+        assert!(take_batch(&rx, &pending).await.is_empty());
+        assert_eq!(pending.count.load(Ordering::SeqCst), 0);
+        assert_eq!(pending.bytes.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_row_not_sent_is_not_counted() {
+        let pending = PendingBatch::new(10, 0, Some(Duration::from_secs(60)), 1);
+        let row_bytes = testutil::flow().build().estimated_bytes();
+        pending.add(row_bytes);
+        pending.add(row_bytes);
+        pending.cancel(row_bytes);
+
+        assert_eq!(pending.count.load(Ordering::SeqCst), 1);
+        assert_eq!(pending.bytes.load(Ordering::SeqCst), row_bytes);
+        assert_eq!(pending.queued_at.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_rows_are_split_between_workers() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = TokioMtx::new(rx);
+        let pending = PendingBatch::new(STORAGE_MAX_BUFFER_SIZE, 0, None, 4);
+        let send = |n: usize| {
+            for _ in 0..n {
+                let flow = testutil::flow().build();
+                pending.add(flow.estimated_bytes());
+                tx.send((flow, None)).unwrap();
+            }
+        };
+
+        send(100);
+        // woken up even though it is not polled yet
+        let notified = pending.full.notified();
+        assert_eq!(take_batch(&rx, &pending).await.len(), 25);
+        tokio::time::timeout(Duration::from_millis(10), notified)
+            .await
+            .expect("idle workers should be woken up to take their share");
+        for _ in 0..3 {
+            assert_eq!(take_batch(&rx, &pending).await.len(), 25);
+        }
+        assert!(take_batch(&rx, &pending).await.is_empty());
+
+        // the next split is of what waits by then
+        send(10);
+        assert_eq!(take_batch(&rx, &pending).await.len(), 3);
+        assert_eq!(take_batch(&rx, &pending).await.len(), 3);
+        assert_eq!(pending.count.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_workers_take_turns_across_the_interval() {
+        let turns = (0..3).map(|w| stagger_delay(w, 3)).collect::<Vec<_>>();
+        assert_eq!(
+            turns,
+            vec![
+                STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS / 3,
+                STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS * 2 / 3,
+                STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS,
+            ]
+        );
+        assert_eq!(stagger_delay(0, 1), STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS);
+    }
+
+    #[tokio::test]
+    async fn test_byte_limit_triggers_flush() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = TokioMtx::new(rx);
+        let row_bytes = testutil::flow().build().estimated_bytes();
+        let pending = PendingBatch::new(STORAGE_MAX_BUFFER_SIZE, row_bytes * 3, None, 1);
+
+        for _ in 0..2 {
+            pending.add(row_bytes);
+            tx.send((testutil::flow().build(), None)).unwrap();
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), pending.full.notified())
+                .await
+                .is_err(),
+            "batch is not full yet"
+        );
+
+        for _ in 0..5 {
+            pending.add(row_bytes);
+            tx.send((testutil::flow().build(), None)).unwrap();
+        }
+        tokio::time::timeout(Duration::from_millis(10), pending.full.notified())
+            .await
+            .expect("worker should be woken up by the byte limit");
+
+        // batches are cut by bytes, long before the count limit
+        assert_eq!(take_batch(&rx, &pending).await.len(), 3);
+        assert_eq!(take_batch(&rx, &pending).await.len(), 3);
+        assert_eq!(take_batch(&rx, &pending).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rows_past_batch_size_are_taken_in_batches() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = TokioMtx::new(rx);
+        let pending = PendingBatch::new(5, 0, None, 1);
+
+        // rows keep arriving while the worker is busy, so more than a batch is waiting
+        for _ in 0..8 {
+            let flow = testutil::flow().build();
+            pending.add(flow.estimated_bytes());
+            tx.send((flow, None)).unwrap();
+        }
+        tokio::time::timeout(Duration::from_millis(10), pending.full.notified())
+            .await
+            .expect("worker should be woken up by the full batch");
+
+        assert_eq!(take_batch(&rx, &pending).await.len(), 5);
+        assert_eq!(take_batch(&rx, &pending).await.len(), 3);
+        assert_eq!(pending.count.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_row_age_triggers_flush() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = TokioMtx::new(rx);
+        let pending = PendingBatch::new(1, 0, Some(Duration::from_millis(100)), 1);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(30), pending.expired())
+                .await
+                .is_err(),
+            "nothing is waiting"
+        );
+
+        for _ in 0..2 {
+            let flow = testutil::flow().build();
+            pending.add(flow.estimated_bytes());
+            tx.send((flow, None)).unwrap();
+            sleep(Duration::from_millis(60)).await;
+        }
+        assert_eq!(take_batch(&rx, &pending).await.len(), 1);
+
+        // the row left waiting is 60ms old already, the flush does not reset its age
+        tokio::time::timeout(Duration::from_millis(80), pending.expired())
+            .await
+            .expect("worker should be woken up by the age of the oldest row");
+
+        assert_eq!(take_batch(&rx, &pending).await.len(), 1);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(150), pending.expired())
+                .await
+                .is_err(),
+            "every row was taken"
+        );
+    }
+
+    #[derive(Default)]
+    struct RecordingStorage {
+        saved: std::sync::Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl AStorage for RecordingStorage {
+        async fn stash(
+            &self,
+            msgs: Vec<FlowMessageWithMetadata>,
+        ) -> Result<InsertResult, StorageError> {
+            *self.saved.lock().unwrap() += msgs.len();
+            Ok(InsertResult::new(vec![], msgs.len()))
+        }
+    }
+
+    struct TimingOutStorage;
+
+    #[async_trait]
+    impl AStorage for TimingOutStorage {
+        async fn stash(
+            &self,
+            _msgs: Vec<FlowMessageWithMetadata>,
+        ) -> Result<InsertResult, StorageError> {
+            Err(StorageError::Timeout(vec![]))
+        }
+    }
+
+    #[actix::test]
+    async fn test_awaited_flows_are_acked_once_stashed() {
+        let ctx = FlushContext {
+            broker: Arc::new(TokioMtx::new(Broker)),
+            webhook: None,
+            coalesce_key: None,
+            inserts: None,
+        };
+
+        let (saved, saved_rx) = PersistFlowMessageWithMetadata::with_ack(testutil::flow().build());
+        let batch = vec![(saved.0, saved.1), (testutil::flow().build(), None)];
+        flush_batch(0, &RecordingStorage::default(), &ctx, batch).await;
+        assert_eq!(saved_rx.await, Ok(Ok(())));
+
+        let (failed, failed_rx) =
+            PersistFlowMessageWithMetadata::with_ack(testutil::flow().build());
+        flush_batch(0, &TimingOutStorage, &ctx, vec![(failed.0, failed.1)]).await;
+        assert_eq!(failed_rx.await, Ok(Err(PersistError::BatchFailed)));
+    }
+
+    #[actix::test]
+    async fn test_rows_accepted_before_shutdown_are_saved() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = Arc::new(TokioMtx::new(rx));
+        let pending = Arc::new(PendingBatch::new(STORAGE_MAX_BUFFER_SIZE, 0, None, 1));
+        let storage = Arc::new(RecordingStorage::default());
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let worker = actix::spawn(flush_worker(
+            0,
+            storage.clone(),
+            rx,
+            pending.clone(),
+            FlushContext {
+                broker: Arc::new(TokioMtx::new(Broker)),
+                webhook: None,
+                coalesce_key: None,
+                inserts: None,
+            },
+            0,
+            shutdown_rx,
+        ));
+
+        for _ in 0..20 {
+            let flow = testutil::flow().build();
+            pending.add(flow.estimated_bytes());
+            tx.send((flow, None)).unwrap();
+        }
+
+        // far from a full batch, draining saves rows without waiting for the interval
+        pending.drain();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while *storage.saved.lock().unwrap() < 20 {
+                sleep(Duration::from_millis(10)).await;
             }
         })
+        .await
+        .expect("drained rows should be saved right away");
+
+        // rows accepted while draining are saved as well
+        let flow = testutil::flow().build();
+        pending.add(flow.estimated_bytes());
+        tx.send((flow, None)).unwrap();
+        shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), worker)
+            .await
+            .expect("worker should stop once drained")
+            .unwrap();
+        assert_eq!(*storage.saved.lock().unwrap(), 21);
+    }
+
+    /// Takes a while to save a batch, records the most inserts it saw at once
+    #[derive(Default)]
+    struct SlowStorage {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        saved: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AStorage for SlowStorage {
+        async fn stash(
+            &self,
+            msgs: Vec<FlowMessageWithMetadata>,
+        ) -> Result<InsertResult, StorageError> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.saved.fetch_add(msgs.len(), Ordering::SeqCst);
+            Ok(InsertResult::new(vec![], msgs.len()))
+        }
+    }
+
+    #[actix::test]
+    async fn test_inflight_inserts_are_bounded() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = Arc::new(TokioMtx::new(rx));
+        let pending = Arc::new(PendingBatch::new(5, 0, None, 4));
+        let storage = Arc::new(SlowStorage::default());
+        let (shutdown, _) = watch::channel(false);
+        let ctx = FlushContext {
+            broker: Arc::new(TokioMtx::new(Broker)),
+            webhook: None,
+            coalesce_key: None,
+            inserts: Some(Arc::new(Semaphore::new(2))),
+        };
+        let workers = (0..4)
+            .map(|worker| {
+                actix::spawn(flush_worker(
+                    worker,
+                    storage.clone(),
+                    rx.clone(),
+                    pending.clone(),
+                    ctx.clone(),
+                    0,
+                    shutdown.subscribe(),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..40 {
+            let flow = testutil::flow().build();
+            pending.add(flow.estimated_bytes());
+            tx.send((flow, None)).unwrap();
+        }
+        shutdown.send(true).unwrap();
+        for worker in workers {
+            tokio::time::timeout(Duration::from_secs(5), worker)
+                .await
+                .expect("worker should stop once drained")
+                .unwrap();
+        }
+
+        assert_eq!(storage.saved.load(Ordering::SeqCst), 40);
+        assert_eq!(storage.max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_flush_delay_without_jitter() {
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(
+            next_flush_delay(&mut rng, 0),
+            STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS
+        );
+    }
+
+    #[test]
+    fn test_flush_delay_is_jittered_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let jitter = Duration::from_millis(500);
+
+        let delays = (0..100)
+            .map(|_| next_flush_delay(&mut rng, 500))
+            .collect::<Vec<Duration>>();
+
+        assert!(delays
+            .iter()
+            .all(|d| *d >= STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS
+                && *d <= STORAGE_BUFFER_FLUSH_INTEVAL_IN_SECS + jitter));
+        assert!(delays.iter().any(|d| *d != delays[0]));
     }
 }
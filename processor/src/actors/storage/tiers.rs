@@ -0,0 +1,62 @@
+use super::routing::{matches_all, parse_rule, Condition, RouteParseError};
+use crate::actors::messages::FlowMessageWithMetadata;
+
+use std::str::FromStr;
+
+/// Tier of flows matching no rule
+pub const DEFAULT_TIER: &str = "default";
+
+/// Stores flows matching all conditions with the tier, e.g. `malicious=true->flagged`.
+/// Table TTL expressions can keep tiers for different periods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TierRule {
+    pub conditions: Vec<Condition>,
+    pub tier: String,
+}
+
+impl FromStr for TierRule {
+    type Err = RouteParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (conditions, tier) = parse_rule(s)?;
+        Ok(TierRule { conditions, tier })
+    }
+}
+
+/// Tier of the first matching rule
+pub fn tier_of<'a>(rules: &'a [TierRule], f: &FlowMessageWithMetadata) -> &'a str {
+    rules
+        .iter()
+        .find(|r| matches_all(&r.conditions, f))
+        .map_or(DEFAULT_TIER, |r| r.tier.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_first_matching_rule_decides_tier() {
+        let rules = vec![
+            "malicious=true->flagged".parse::<TierRule>().unwrap(),
+            "protocol=17->short".parse::<TierRule>().unwrap(),
+        ];
+
+        let udp = testutil::flow().with_protocol(17);
+        let tcp = testutil::flow().with_protocol(6);
+
+        let flagged_udp = udp.clone().with_malicious(Some(true)).build();
+        assert_eq!(tier_of(&rules, &flagged_udp), "flagged");
+        assert_eq!(tier_of(&rules, &udp.build()), "short");
+        let benign_tcp = tcp.clone().with_malicious(Some(false)).build();
+        assert_eq!(tier_of(&rules, &benign_tcp), DEFAULT_TIER);
+        let flagged_tcp = tcp.with_malicious(Some(true)).build();
+        assert_eq!(tier_of(&[], &flagged_tcp), DEFAULT_TIER);
+    }
+
+    #[test]
+    fn test_tier_is_a_plain_word() {
+        assert!("malicious=true->flagged'".parse::<TierRule>().is_err());
+    }
+}
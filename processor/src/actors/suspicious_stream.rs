@@ -0,0 +1,140 @@
+use crate::actors::consts::MAILBOX_CAPACITY;
+use crate::actors::messages::{FlowMessageWithMetadata, PersistFlowMessageWithMetadata};
+use crate::actors::BrokerType;
+use crate::metrics::SUSPICIOUS_FLOWS;
+use crate::privacy::Redaction;
+use crate::settings::SuspiciousStreamSettings;
+use crate::webhook;
+
+use actix::{Actor, Context, Handler, ResponseFuture};
+use actix_broker::BrokerSubscribe;
+use log::{error, info};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Signals which flagged a classified flow, the classifier verdict and scan detection each
+/// add a point
+pub fn suspicion_score(f: &FlowMessageWithMetadata) -> u8 {
+    u8::from(f.malicious == Some(true)) + u8::from(f.metadata.scan_suspected)
+}
+
+/// Flow as published to the suspicious topic, the flagged flow webhook notification with
+/// the score it was published for
+pub fn record(f: &FlowMessageWithMetadata, score: u8) -> Vec<u8> {
+    let mut notification = webhook::notification(f);
+    notification["score"] = score.into();
+    serde_json::to_vec(&notification).expect("notification is always serializable")
+}
+
+/// Publishes classified flows scoring at least `min_score` to a topic of their own, for
+/// consumers alerting in real time. Flows are stored regardless by the storage actor, which
+/// gets its own copy of them, so a slow or failing topic never holds back persistence
+pub struct SuspiciousStreamActor {
+    producer: FutureProducer,
+    topic: String,
+    min_score: u8,
+    redaction: Arc<Redaction>,
+}
+
+impl SuspiciousStreamActor {
+    pub fn new(
+        producer: FutureProducer,
+        topic: String,
+        settings: &SuspiciousStreamSettings,
+        redaction: Arc<Redaction>,
+    ) -> Self {
+        Self {
+            producer,
+            topic,
+            min_score: settings.min_score.max(1),
+            redaction,
+        }
+    }
+}
+
+impl Actor for SuspiciousStreamActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "Started suspicious stream actor publishing to {}",
+            self.topic
+        );
+        ctx.set_mailbox_capacity(MAILBOX_CAPACITY);
+        self.subscribe_async::<BrokerType, PersistFlowMessageWithMetadata>(ctx);
+    }
+}
+
+impl Handler<PersistFlowMessageWithMetadata> for SuspiciousStreamActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(
+        &mut self,
+        msg: PersistFlowMessageWithMetadata,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let score = suspicion_score(&msg.0);
+        if score < self.min_score {
+            return Box::pin(async {});
+        }
+
+        let flow = self.redaction.apply(&msg.0);
+        let payload = record(&flow, score);
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        Box::pin(async move {
+            let delivery = producer
+                .send(
+                    FutureRecord::to(&topic)
+                        .key(&flow.metadata.host)
+                        .payload(&payload),
+                    Duration::from_secs(0),
+                )
+                .await;
+            let outcome = match delivery {
+                Ok(_) => "published",
+                Err((e, _)) => {
+                    error!("unable to publish suspicious flow to {}: {}", topic, e);
+                    "failed"
+                }
+            };
+            SUSPICIOUS_FLOWS.with_label_values(&[outcome]).inc();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_score_counts_signals() {
+        let score = |malicious, scan_suspected| {
+            let f = testutil::flow()
+                .with_malicious(malicious)
+                .with_scan_suspected(scan_suspected)
+                .build();
+            suspicion_score(&f)
+        };
+        assert_eq!(score(None, false), 0);
+        assert_eq!(score(Some(false), false), 0);
+        assert_eq!(score(Some(false), true), 1);
+        assert_eq!(score(Some(true), false), 1);
+        assert_eq!(score(Some(true), true), 2);
+    }
+
+    #[test]
+    fn test_record_carries_score() {
+        let f = testutil::flow()
+            .with_malicious(Some(true))
+            .with_scan_suspected(true)
+            .with_src("10.1.1.1")
+            .build();
+        let record: serde_json::Value = serde_json::from_slice(&record(&f, 2)).unwrap();
+        assert_eq!(record["score"], 2);
+        assert_eq!(record["malicious"], true);
+        assert_eq!(record["ipv4_src_addr"], "10.1.1.1");
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowlistError(String);
+
+impl fmt::Display for AllowlistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid allowed source: {}", self.0)
+    }
+}
+
+impl std::error::Error for AllowlistError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AllowedSource {
+    /// host identifier sent by collector, matched as it is
+    Host(String),
+    /// network the host identifier has to be an address of
+    Net { addr: IpAddr, prefix: u8 },
+}
+
+impl AllowedSource {
+    fn parse(entry: &str) -> Result<Self, AllowlistError> {
+        let invalid = || AllowlistError(entry.to_owned());
+        match entry.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+                let prefix = prefix.parse::<u8>().map_err(|_| invalid())?;
+                if prefix > max_prefix(&addr) {
+                    return Err(invalid());
+                }
+                Ok(Self::Net { addr, prefix })
+            }
+            None => Ok(match entry.parse::<IpAddr>() {
+                Ok(addr) => Self::Net {
+                    addr,
+                    prefix: max_prefix(&addr),
+                },
+                Err(_) => Self::Host(entry.to_owned()),
+            }),
+        }
+    }
+
+    fn matches(&self, host: &str, addr: Option<IpAddr>) -> bool {
+        match (self, addr) {
+            (Self::Host(allowed), _) => allowed == host,
+            (Self::Net { addr: net, prefix }, Some(addr)) => in_network(&addr, net, *prefix),
+            (Self::Net { .. }, None) => false,
+        }
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn in_network(addr: &IpAddr, net: &IpAddr, prefix: u8) -> bool {
+    match (addr, net) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(*addr) & mask == u32::from(*net) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(*addr) & mask == u128::from(*net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Sources flows are accepted from, checked against the host identifier of every consumed
+/// message. Empty list accepts everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceAllowlist {
+    entries: Vec<AllowedSource>,
+}
+
+impl FromStr for SourceAllowlist {
+    type Err = AllowlistError;
+
+    /// Comma separated host identifiers, addresses and networks, e.g. `collector-1,10.0.0.0/8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let entries = s
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(AllowedSource::parse)
+            .collect::<Result<Vec<AllowedSource>, AllowlistError>>()?;
+        Ok(Self { entries })
+    }
+}
+
+impl SourceAllowlist {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        if self.entries.is_empty() {
+            return true;
+        }
+        let addr = host.parse::<IpAddr>().ok();
+        self.entries.iter().any(|e| e.matches(host, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_everything_is_allowed_by_default() {
+        let allowlist = "".parse::<SourceAllowlist>().unwrap();
+        assert!(allowlist.is_empty());
+        assert!(allowlist.allows("rogue"));
+    }
+
+    #[test]
+    fn test_hosts_and_networks_are_allowed() {
+        let allowlist = "collector-1-5.15.0, 10.1.0.0/16, 192.168.0.7, fd00::/8"
+            .parse::<SourceAllowlist>()
+            .unwrap();
+
+        assert!(allowlist.allows("collector-1-5.15.0"));
+        assert!(allowlist.allows("10.1.200.3"));
+        assert!(allowlist.allows("192.168.0.7"));
+        assert!(allowlist.allows("fd00::1"));
+        assert!(!allowlist.allows("collector-2-5.15.0"));
+        assert!(!allowlist.allows("10.2.0.1"));
+        assert!(!allowlist.allows("192.168.0.8"));
+    }
+
+    #[test]
+    fn test_invalid_networks_are_rejected() {
+        for entry in ["10.0.0.0/33", "10.0.0.0/x", "collector/24"] {
+            assert!(entry.parse::<SourceAllowlist>().is_err(), "{}", entry);
+        }
+    }
+}
@@ -1,25 +1,52 @@
 use crate::actors::broker::Broker;
 use crate::actors::classification_client_grpc::client::Classifier;
 
+use crate::actors::event_stream::kafka::payload_dump::PayloadDumper;
 use crate::actors::event_stream::kafka::retrier::Retrier;
+use crate::actors::event_stream::kafka::DeadLetterReplayer;
 
 use crate::actors::event_stream::{kafka::KafkaProcessingAgent, EventStreamActor};
 
-use crate::actors::storage::storage_actor::StorageActor;
+use crate::actors::aggregation::{
+    AggregationActor, KafkaRollupSink, RollupDestinations, RollupStorage,
+};
+use crate::actors::storage::messages::{DrainStorage, StopStorage};
+use crate::actors::storage::storage_actor::{AStorage, StorageActor};
+use crate::actors::suspicious_stream::SuspiciousStreamActor;
 
 use tokio::sync::Mutex as TokioMtx;
 
+use common::host_normalization::{HostNormalization, HostNormalizationError};
+use common::log_sampling;
+use common::redact::redact_credentials;
+
+use crate::actors::event_stream::kafka::get_producer;
 use crate::actors::storage::clickhouse::ClickhouseState;
-use crate::consts::DEFAULT_ENV_VAR_PREFIX;
+use crate::actors::storage::schema::SchemaError;
+use crate::allowlist::{AllowlistError, SourceAllowlist};
+use crate::consts::{DEFAULT_ENV_VAR_PREFIX, LIVENESS_STALE_AFTER};
+use crate::dead_letter_cap::DeadLetterCap;
+use crate::enrich::{Enrichment, EnrichmentError};
+use crate::health::{Readiness, Watchdog};
 use crate::pb::flow_message_classifier_client::FlowMessageClassifierClient;
+use crate::privacy::{Redaction, RedactionError};
+use crate::sampler::FlowSampler;
+use crate::scan_detection::ScanDetection;
 use crate::settings::ProcessorSettings;
-use actix::Actor;
+use crate::shutdown::{
+    self, Consumption, COMMIT_OFFSETS_TIMEOUT, DRAIN_STORAGE_TIMEOUT, FLUSH_STORAGE_TIMEOUT,
+    STOP_CONSUMER_TIMEOUT,
+};
+use crate::statsd::StatsdPusher;
+use crate::webhook::FlaggedFlowWebhook;
+use actix::{Actor, Addr};
 
 use config::builder::DefaultState;
 use config::{Config, ConfigBuilder, Environment};
 
 use crate::actors::classification_client_grpc;
 
+use log::{error, info, warn};
 use serde::Deserialize;
 use std::sync::Arc;
 
@@ -27,6 +54,12 @@ use std::sync::Arc;
 pub enum ConfigErr {
     Read(config::ConfigError),
     MissingNeccessarySetting(String),
+    Schema(SchemaError),
+    Redaction(RedactionError),
+    Enrichment(EnrichmentError),
+    Allowlist(AllowlistError),
+    HostNormalization(HostNormalizationError),
+    Storage(String),
 }
 
 pub struct ApplicationState {
@@ -34,12 +67,45 @@ pub struct ApplicationState {
     brokers: String,
     clickhouse_state: Arc<ClickhouseState>,
     classification_state: Classifier,
+    retrier: Arc<Retrier>,
+    dead_letter_replayer: Arc<DeadLetterReplayer>,
+    sampler: Arc<FlowSampler>,
+    watchdog: Arc<Watchdog>,
+    readiness: Arc<Readiness>,
+    redaction: Arc<Redaction>,
+    enrichment: Arc<Enrichment>,
+    allowlist: SourceAllowlist,
+    host_normalization: HostNormalization,
 }
 
 pub fn get_config<'d, T: Deserialize<'d>>(config: &Config) -> Result<T, ConfigErr> {
     config.clone().try_deserialize().map_err(ConfigErr::Read)
 }
 
+/// Single line summary of where flows come from and where they end up, secrets are redacted
+fn pipeline_banner(settings: &ProcessorSettings) -> String {
+    let classifier = Classifier {
+        host: settings.grpc_classification_host.clone(),
+        port: settings.grpc_classification_port,
+    };
+    let mut banner = format!(
+        "pipeline: kafka [brokers: {}, topics: {}] -> classifier [{}] -> clickhouse [{}]",
+        settings.kafka_brokers,
+        settings.kafka_topics.join(","),
+        classifier.dsn(),
+        settings.clickhouse_settings,
+    );
+    if settings.aggregation_settings.enabled {
+        banner.push_str(&format!(
+            " -> rollups [table: {}, key: {}, window: {}s]",
+            settings.aggregation_settings.table,
+            settings.aggregation_settings.key.as_str(),
+            settings.aggregation_settings.window_secs
+        ));
+    }
+    redact_credentials(&banner)
+}
+
 impl ApplicationState {
     pub async fn new() -> Result<Self, ConfigErr> {
         let base_config_builder = ConfigBuilder::<DefaultState>::default();
@@ -52,38 +118,212 @@ impl ApplicationState {
         let deserialized_config =
             get_config::<ProcessorSettings>(&config).expect("Getting config failed");
 
+        info!("{}", pipeline_banner(&deserialized_config));
+        deserialized_config
+            .storage_settings
+            .check_ordering()
+            .map_err(|e| {
+                error!("{}", e);
+                ConfigErr::Storage(e)
+            })?;
+        log_sampling::set_debug_log_sample_rate(deserialized_config.debug_log_sample_rate);
+
         // set kafka settings
         let brokers = deserialized_config.kafka_brokers;
+        let redaction = Redaction::try_from(&deserialized_config.redaction).map_err(|e| {
+            error!("{}", e);
+            ConfigErr::Redaction(e)
+        })?;
+        let enrichment = Enrichment::try_from(&deserialized_config.enrichment).map_err(|e| {
+            error!("{}", e);
+            ConfigErr::Enrichment(e)
+        })?;
+        info!("enrichment stages: {:?}", enrichment.names());
+        let allowlist = deserialized_config
+            .allowed_sources
+            .parse::<SourceAllowlist>()
+            .map_err(|e| {
+                error!("{}", e);
+                ConfigErr::Allowlist(e)
+            })?;
+        if !allowlist.is_empty() {
+            info!(
+                "accepting flows only from {}",
+                deserialized_config.allowed_sources
+            );
+        }
+        let host_normalization = HostNormalization::new(&deserialized_config.host_normalization)
+            .map_err(|e| {
+                error!("{}", e);
+                ConfigErr::HostNormalization(e)
+            })?;
+        if !host_normalization.is_noop() {
+            info!(
+                "host identifiers are normalized: {:?}",
+                deserialized_config.host_normalization
+            );
+        }
+
         // set clickhouse settings
-        let clickhouse_state = Arc::new(ClickhouseState::new(
-            deserialized_config.clickhouse_settings,
-        ));
+        let clickhouse_state = Arc::new(
+            ClickhouseState::new(deserialized_config.clickhouse_settings)
+                .with_redaction(redaction.clone()),
+        );
+
+        // fail before accepting traffic rather than on the first insert
+        clickhouse_state.verify_schema().await.map_err(|e| {
+            error!("clickhouse schema check failed: {}", e);
+            ConfigErr::Schema(e)
+        })?;
+        // the readiness check keeps failing and retries it until it succeeds
+        if let Err(e) = clickhouse_state.warm_up().await {
+            warn!("clickhouse pool warmup failed: {}", e);
+        }
 
         let classification_state = Classifier {
             port: deserialized_config.grpc_classification_port,
             host: deserialized_config.grpc_classification_host,
         };
 
+        let retrier = Arc::new(Retrier::new(
+            brokers.clone(),
+            deserialized_config.retry_settings.tiers,
+        ));
+        let dead_letter_replayer = Arc::new(DeadLetterReplayer::new(
+            &brokers,
+            retrier.dead_letter_topic(),
+            retrier.original_topic(),
+            deserialized_config.replay_settings,
+        ));
+
+        let sampler = Arc::new(
+            FlowSampler::new(&deserialized_config.sampler_settings)
+                .with_redaction(redaction.clone()),
+        );
+
+        let watchdog = Arc::new(Watchdog::new(LIVENESS_STALE_AFTER));
+        let readiness = Arc::new(Readiness::new(
+            get_producer(&brokers),
+            classification_state.dsn(),
+            clickhouse_state.clone(),
+        ));
+
         let state = ApplicationState {
             config,
             brokers,
             clickhouse_state,
             classification_state,
+            retrier,
+            dead_letter_replayer,
+            sampler,
+            watchdog,
+            readiness,
+            redaction: Arc::new(redaction),
+            enrichment: Arc::new(enrichment),
+            allowlist,
+            host_normalization,
         };
 
         Ok(state)
     }
 
-    pub async fn init_actors(&self) {
+    pub fn dead_letter_replayer(&self) -> Arc<DeadLetterReplayer> {
+        self.dead_letter_replayer.clone()
+    }
+
+    pub fn clickhouse_state(&self) -> Arc<ClickhouseState> {
+        self.clickhouse_state.clone()
+    }
+
+    pub fn sampler(&self) -> Arc<FlowSampler> {
+        self.sampler.clone()
+    }
+
+    pub fn watchdog(&self) -> Arc<Watchdog> {
+        self.watchdog.clone()
+    }
+
+    pub fn readiness(&self) -> Arc<Readiness> {
+        self.readiness.clone()
+    }
+
+    pub async fn init_actors(&self) -> Pipeline {
         // deserialize env config
-        let _deserialized_config =
+        let deserialized_config =
             get_config::<ProcessorSettings>(&self.config).expect("Getting config failed");
 
+        // metrics are pushed besides being served when an agent is configured
+        if let Some(statsd) = StatsdPusher::new(&deserialized_config.metrics) {
+            tokio::spawn(statsd.run());
+        }
+
         // starting event stream actor
         let broker = Arc::new(TokioMtx::new(Broker));
 
+        // shared by stored flagged flows and scan detections, so they are posted within one rate
+        let webhook = FlaggedFlowWebhook::new(
+            &deserialized_config.webhook,
+            self.redaction.as_ref().clone(),
+        )
+        .map(Arc::new);
+
         // init storage actor
-        StorageActor::new(self.clickhouse_state.clone(), broker.clone()).start();
+        let preserve_order = deserialized_config.storage_settings.preserve_order;
+        let storage = StorageActor::new(
+            self.clickhouse_state.clone(),
+            broker.clone(),
+            deserialized_config.storage_settings,
+        )
+        .with_webhook(webhook.clone())
+        .start();
+
+        // init aggregation actor
+        if deserialized_config.aggregation_settings.enabled {
+            let settings = &deserialized_config.aggregation_settings;
+            let mut destinations: Vec<Arc<dyn RollupStorage + Send + Sync>> = Vec::new();
+            if settings.destination.to_clickhouse() {
+                destinations.push(self.clickhouse_state.clone());
+            }
+            if settings.destination.to_kafka() {
+                destinations.push(Arc::new(KafkaRollupSink::new(
+                    get_producer(&self.brokers),
+                    settings.kafka_topic.clone(),
+                )));
+            }
+            info!("rollups are saved to {:?}", settings.destination);
+
+            AggregationActor::new(
+                Arc::new(RollupDestinations::new(destinations)),
+                broker.clone(),
+                deserialized_config.aggregation_settings,
+                self.redaction.clone(),
+            )
+            .start();
+        }
+
+        // flagged flows are published besides being stored, best effort
+        if let Some(topic) = &deserialized_config.suspicious_stream.topic {
+            info!(
+                "flows scoring at least {} are published to {}",
+                deserialized_config.suspicious_stream.min_score, topic
+            );
+            SuspiciousStreamActor::new(
+                get_producer(&self.brokers),
+                topic.clone(),
+                &deserialized_config.suspicious_stream,
+                self.redaction.clone(),
+            )
+            .start();
+        }
+
+        let scan_detection = ScanDetection::new(
+            deserialized_config.scan_detection.clone(),
+            self.redaction.as_ref().clone(),
+        )
+        .with_webhook(webhook);
+        if scan_detection.is_enabled() {
+            info!("scan detection: {:?}", deserialized_config.scan_detection);
+        }
 
         // init classification actor
         let grpc_client =
@@ -94,16 +334,263 @@ impl ApplicationState {
                 }
             };
 
-        classification_client_grpc::client::ClassificationActor {
-            client: grpc_client,
-        }
+        classification_client_grpc::client::ClassificationActor::new(
+            grpc_client,
+            self.enrichment.clone(),
+            Arc::new(scan_detection),
+        )
+        .with_preserved_order(preserve_order)
         .start();
 
-        let processing_agent = Arc::new(KafkaProcessingAgent::new("flows", &self.brokers));
-        let retrier = Arc::new(Retrier::new(self.brokers.clone()));
+        let dead_letter_cap = Arc::new(DeadLetterCap::new(
+            deserialized_config.dead_letter_cap.clone(),
+        ));
+        if dead_letter_cap.is_enabled() {
+            info!("dead letter cap: {:?}", deserialized_config.dead_letter_cap);
+        }
 
-        let event_stream_actor = EventStreamActor::new(processing_agent, retrier, broker);
+        let processing_agent = Arc::new(
+            KafkaProcessingAgent::new(
+                &deserialized_config.kafka_topics,
+                &self.brokers,
+                &deserialized_config.consumer_settings,
+                PayloadDumper::new(deserialized_config.payload_dump_settings),
+                self.retrier.dead_letter_topic(),
+                self.sampler.clone(),
+                self.watchdog.clone(),
+            )
+            .with_message_key(self.clickhouse_state.shard_key())
+            .with_host_normalization(self.host_normalization.clone())
+            .with_allowlist(self.allowlist.clone())
+            .with_clock_skew(deserialized_config.clock_skew)
+            .with_dead_letter_cap(dead_letter_cap),
+        );
+        let event_stream_actor =
+            EventStreamActor::new(processing_agent.clone(), self.retrier.clone(), broker);
 
         event_stream_actor.start();
+
+        Pipeline::new(processing_agent, storage)
+    }
+}
+
+/// Handles of running pipeline stages, needed to stop them in order
+pub struct Pipeline<C = KafkaProcessingAgent, S = ClickhouseState>
+where
+    S: AStorage + Unpin,
+{
+    processing_agent: Arc<C>,
+    storage: Addr<StorageActor<S>>,
+}
+
+impl<C, S> Pipeline<C, S>
+where
+    C: Consumption,
+    S: AStorage + Unpin,
+{
+    pub fn new(processing_agent: Arc<C>, storage: Addr<StorageActor<S>>) -> Self {
+        Self {
+            processing_agent,
+            storage,
+        }
+    }
+
+    /// Stops the pipeline front to back, so every consumed flow is saved before its offset is committed:
+    /// stop consumer, drain storage buffer, flush clickhouse, commit final offsets
+    pub async fn shutdown(self) {
+        shutdown::stage(
+            "stop consumer",
+            STOP_CONSUMER_TIMEOUT,
+            self.processing_agent.stop(),
+        )
+        .await;
+
+        // flows still being classified reach storage meanwhile
+        let drained = shutdown::stage("drain storage buffer", DRAIN_STORAGE_TIMEOUT, async {
+            if let Err(e) = self.storage.send(DrainStorage).await {
+                error!("storage actor is gone: {}", e);
+            }
+            self.processing_agent.wait_until_acked().await;
+        })
+        .await;
+        if drained.is_none() {
+            warn!(
+                "{} consumed flows were not saved, they are consumed again after restart",
+                self.processing_agent.in_flight()
+            );
+        }
+
+        if let Some(Err(e)) = shutdown::stage(
+            "flush clickhouse",
+            FLUSH_STORAGE_TIMEOUT,
+            self.storage.send(StopStorage),
+        )
+        .await
+        {
+            error!("storage actor is gone: {}", e);
+        }
+
+        // offsets are stored only for saved flows, so committing them loses nothing
+        if let Some(Err(e)) =
+            shutdown::stage("commit final offsets", COMMIT_OFFSETS_TIMEOUT, async {
+                self.processing_agent.commit()
+            })
+            .await
+        {
+            error!("unable to commit final offsets: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::messages::{
+        AckMessage, FlowMessageWithMetadata, PersistFlowMessageWithMetadata,
+    };
+    use crate::actors::storage::storage_actor::{InsertResult, StorageError};
+    use crate::settings::StorageSettings;
+    use crate::testutil;
+    use actix::{Context, Handler};
+    use actix_broker::{BrokerSubscribe, SystemBroker};
+    use async_trait::async_trait;
+    use rdkafka::error::KafkaResult;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tokio::time::{sleep, timeout, Duration};
+
+    const TOPIC: &str = "drained-flows";
+
+    /// Counts consumed flows the way the kafka agent does, records what was left at commit
+    #[derive(Default)]
+    struct FakeConsumer {
+        stopped: AtomicBool,
+        in_flight: AtomicUsize,
+        in_flight_at_commit: Mutex<Option<usize>>,
+    }
+
+    #[async_trait]
+    impl Consumption for FakeConsumer {
+        async fn stop(&self) {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+
+        fn in_flight(&self) -> usize {
+            self.in_flight.load(Ordering::SeqCst)
+        }
+
+        async fn wait_until_acked(&self) {
+            while self.in_flight() > 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        fn commit(&self) -> KafkaResult<()> {
+            *self.in_flight_at_commit.lock().unwrap() = Some(self.in_flight());
+            Ok(())
+        }
+    }
+
+    /// Stands in for the event stream actor, every ack or retry releases a consumed flow
+    struct AckCounter(Arc<FakeConsumer>);
+
+    impl Actor for AckCounter {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Self::Context) {
+            self.subscribe_sync::<SystemBroker, AckMessage>(ctx);
+        }
+    }
+
+    impl Handler<AckMessage> for AckCounter {
+        type Result = ();
+
+        fn handle(&mut self, msg: AckMessage, _ctx: &mut Self::Context) -> Self::Result {
+            let topic = match &msg {
+                AckMessage::Ack(topic, ..) => Some(topic.as_str()),
+                AckMessage::NackRetry(f) | AckMessage::NackDeadLetter(f, _) => {
+                    f.metadata.topic.as_deref()
+                }
+            };
+            if topic == Some(TOPIC) {
+                self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct AckingStorage {
+        saved: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AStorage for AckingStorage {
+        async fn stash(
+            &self,
+            msgs: Vec<FlowMessageWithMetadata>,
+        ) -> Result<InsertResult, StorageError> {
+            // slow enough for the drain to have to wait for it
+            sleep(Duration::from_millis(50)).await;
+            self.saved.fetch_add(msgs.len(), Ordering::SeqCst);
+            let acks = msgs
+                .iter()
+                .map(|f| {
+                    AckMessage::Ack(
+                        f.metadata.topic.clone().unwrap(),
+                        f.metadata.offset.unwrap(),
+                        f.metadata.partition.unwrap(),
+                    )
+                })
+                .collect();
+            Ok(InsertResult::new(acks, msgs.len()))
+        }
+    }
+
+    fn consumed(offset: i64) -> FlowMessageWithMetadata {
+        testutil::flow()
+            .with_topic(TOPIC)
+            .with_offset(offset)
+            .build()
+    }
+
+    #[actix::test]
+    async fn test_shutdown_saves_consumed_flows_before_committing() {
+        let consumer = Arc::new(FakeConsumer::default());
+        let counter = AckCounter(consumer.clone()).start();
+        let storage = Arc::new(AckingStorage::default());
+        let storage_actor = StorageActor::new(
+            storage.clone(),
+            Arc::new(TokioMtx::new(Broker)),
+            StorageSettings::default(),
+        )
+        .start();
+        // the counter is subscribed once it handled its first message
+        counter
+            .send(AckMessage::Ack("other".to_owned(), 0, 0))
+            .await
+            .unwrap();
+
+        // 20 flows wait in the storage buffer, far from a full batch or the flush interval
+        consumer.in_flight.store(21, Ordering::SeqCst);
+        for offset in 0..20 {
+            storage_actor
+                .send(PersistFlowMessageWithMetadata::from(consumed(offset)))
+                .await
+                .unwrap();
+        }
+        // one could not be classified and goes to retry instead
+        Broker.issue_async(AckMessage::NackRetry(consumed(20)));
+
+        timeout(
+            Duration::from_secs(5),
+            Pipeline::new(consumer.clone(), storage_actor).shutdown(),
+        )
+        .await
+        .expect("shutdown should not wait for any timeout");
+
+        assert!(consumer.stopped.load(Ordering::SeqCst));
+        assert_eq!(storage.saved.load(Ordering::SeqCst), 20);
+        assert_eq!(consumer.in_flight(), 0);
+        assert_eq!(*consumer.in_flight_at_commit.lock().unwrap(), Some(0));
     }
 }
@@ -0,0 +1,296 @@
+use crate::actors::messages::PersistFlowMessageWithMetadata;
+use crate::enrich::Enrich;
+use crate::metrics::ASSET_LOOKUPS;
+use crate::settings::AssetSettings;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::warn;
+use lru::LruCache;
+use redis::aio::ConnectionManager;
+use tokio::sync::{mpsc, OnceCell};
+use tokio_stream::wrappers::ReceiverStream;
+
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Key value store owners of addresses are kept in, maintained outside of the processor
+#[async_trait]
+pub trait AssetStore: Send + Sync {
+    /// Owner stored under the key, `None` when there is none
+    async fn get(&self, key: &str) -> Result<Option<String>, String>;
+}
+
+/// Redis connected on the first lookup, the connection is reestablished when it breaks
+pub struct RedisStore {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisStore {
+    pub fn new(url: &str) -> Result<Self, String> {
+        Ok(Self {
+            client: redis::Client::open(url).map_err(|e| e.to_string())?,
+            connection: OnceCell::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl AssetStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, String> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .map_err(|e| e.to_string())?;
+        redis::cmd("GET")
+            .arg(key)
+            .query_async::<_, Option<String>>(&mut connection.clone())
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Lookups queued and not started yet, misses beyond it are not looked up until a later flow
+const ASSET_LOOKUP_QUEUE_SIZE: usize = 1024;
+/// Lookups waited for at once by the background task
+const ASSET_LOOKUP_CONCURRENCY: usize = 16;
+
+/// Owners cached by address along with the time they expire at
+type OwnerCache = Arc<Mutex<LruCache<String, (String, Instant)>>>;
+
+/// Fills `src_asset` and `dst_asset` with owners of the flow addresses. Flows never wait for
+/// redis, they are enriched from the cache only. An address missing from it is queued and looked
+/// up in the background, its flows are stored with an empty owner until the lookup lands.
+/// Owners are cached for `cache_ttl_secs`, addresses without one as well, an expired owner is
+/// still used while it is looked up again. A lookup failing or running out of `timeout_ms` is not
+/// cached, the address is queued again by its next flow
+pub struct AssetLookup {
+    cache: OwnerCache,
+    /// addresses queued or being looked up, so an address is not queued once per flow
+    pending: Arc<Mutex<HashSet<String>>>,
+    queue: mpsc::Sender<String>,
+}
+
+/// Looks up queued addresses until the lookup is dropped
+async fn look_up_queued(
+    store: Box<dyn AssetStore>,
+    queue: mpsc::Receiver<String>,
+    cache: OwnerCache,
+    pending: Arc<Mutex<HashSet<String>>>,
+    key_pattern: String,
+    ttl: Duration,
+    timeout: Duration,
+) {
+    let store = &store;
+    let key_pattern = &key_pattern;
+    ReceiverStream::new(queue)
+        .for_each_concurrent(ASSET_LOOKUP_CONCURRENCY, |addr| {
+            let cache = cache.clone();
+            let pending = pending.clone();
+            async move {
+                let key = key_pattern.replace("{ip}", &addr);
+                let owner = match tokio::time::timeout(timeout, store.get(&key)).await {
+                    Ok(Ok(owner)) => {
+                        let outcome = if owner.is_some() { "found" } else { "missing" };
+                        ASSET_LOOKUPS.with_label_values(&[outcome]).inc();
+                        Some(owner.unwrap_or_default())
+                    }
+                    Ok(Err(e)) => {
+                        warn!("unable to look up owner of {}: {}", addr, e);
+                        ASSET_LOOKUPS.with_label_values(&["failed"]).inc();
+                        None
+                    }
+                    Err(_) => {
+                        ASSET_LOOKUPS.with_label_values(&["timed_out"]).inc();
+                        None
+                    }
+                };
+                if let Some(owner) = owner {
+                    cache
+                        .lock()
+                        .unwrap()
+                        .put(addr.clone(), (owner, Instant::now() + ttl));
+                }
+                pending.lock().unwrap().remove(&addr);
+            }
+        })
+        .await
+}
+
+impl AssetLookup {
+    pub fn new(settings: &AssetSettings) -> Result<Self, String> {
+        let url = settings
+            .redis_url
+            .as_deref()
+            .ok_or_else(|| "stage assets requires redis_url".to_owned())?;
+        let store = RedisStore::new(url).map_err(|e| format!("invalid redis_url: {e}"))?;
+        Ok(Self::with_store(Box::new(store), settings))
+    }
+
+    /// Has to be called within a tokio runtime, lookups run in a task spawned on it
+    pub fn with_store(store: Box<dyn AssetStore>, settings: &AssetSettings) -> Self {
+        let size = NonZeroUsize::new(settings.cache_size.max(1)).expect("size is at least 1");
+        let cache = Arc::new(Mutex::new(LruCache::new(size)));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let (tx, rx) = mpsc::channel(ASSET_LOOKUP_QUEUE_SIZE);
+        tokio::spawn(look_up_queued(
+            store,
+            rx,
+            cache.clone(),
+            pending.clone(),
+            settings.key_pattern.clone(),
+            Duration::from_secs(settings.cache_ttl_secs),
+            Duration::from_millis(settings.timeout_ms),
+        ));
+        Self {
+            cache,
+            pending,
+            queue: tx,
+        }
+    }
+
+    /// Queues a lookup of the address unless one is queued already
+    fn queue_lookup(&self, addr: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains(addr) {
+            return;
+        }
+        match self.queue.try_send(addr.to_owned()) {
+            Ok(()) => {
+                pending.insert(addr.to_owned());
+                ASSET_LOOKUPS.with_label_values(&["queued"]).inc();
+            }
+            Err(_) => ASSET_LOOKUPS.with_label_values(&["skipped"]).inc(),
+        }
+    }
+
+    /// Owner of the address, empty when it is unknown or was not looked up yet
+    pub fn owner(&self, addr: &str) -> String {
+        if addr.is_empty() {
+            return String::new();
+        }
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map(|(owner, expires)| (owner.clone(), *expires > Instant::now()));
+        match cached {
+            Some((owner, true)) => {
+                ASSET_LOOKUPS.with_label_values(&["cached"]).inc();
+                owner
+            }
+            Some((owner, false)) => {
+                ASSET_LOOKUPS.with_label_values(&["cached"]).inc();
+                self.queue_lookup(addr);
+                owner
+            }
+            None => {
+                self.queue_lookup(addr);
+                String::new()
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Enrich for AssetLookup {
+    async fn enrich(&self, msg: &mut PersistFlowMessageWithMetadata) {
+        let f = &msg.0.flow_message;
+        let (src, dst) = (self.owner(&f.ipv4_src_addr), self.owner(&f.ipv4_dst_addr));
+        msg.0.metadata.src_asset = src;
+        msg.0.metadata.dst_asset = dst;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// In memory store counting the lookups which reached it
+    struct Store {
+        owners: HashMap<String, String>,
+        delay: Duration,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AssetStore for Store {
+        async fn get(&self, key: &str) -> Result<Option<String>, String> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(self.delay).await;
+            Ok(self.owners.get(key).cloned())
+        }
+    }
+
+    fn lookup(delay: Duration, cache_ttl_secs: u64) -> (AssetLookup, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let store = Store {
+            owners: HashMap::from([("asset:10.0.0.1".to_owned(), "printer-2nd-floor".to_owned())]),
+            delay,
+            lookups: lookups.clone(),
+        };
+        let settings = AssetSettings {
+            cache_ttl_secs,
+            ..Default::default()
+        };
+        (AssetLookup::with_store(Box::new(store), &settings), lookups)
+    }
+
+    /// Cached owner of the address once the lookup queued by its flow is done
+    async fn looked_up(lookup: &AssetLookup, addr: &str) -> String {
+        lookup.owner(addr);
+        while lookup.pending.lock().unwrap().contains(addr) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let cache = lookup.cache.lock().unwrap();
+        cache
+            .peek(addr)
+            .map(|(owner, _)| owner.clone())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_owners_and_misses_are_cached() {
+        let (lookup, lookups) = lookup(Duration::ZERO, 300);
+        assert_eq!(looked_up(&lookup, "10.0.0.1").await, "printer-2nd-floor");
+        assert_eq!(looked_up(&lookup, "10.0.0.2").await, "");
+        for _ in 0..3 {
+            assert_eq!(lookup.owner("10.0.0.1"), "printer-2nd-floor");
+            assert_eq!(lookup.owner("10.0.0.2"), "");
+        }
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+
+        // expired owners are still used while they are looked up again
+        let (lookup, lookups) = self::lookup(Duration::ZERO, 0);
+        assert_eq!(looked_up(&lookup, "10.0.0.1").await, "printer-2nd-floor");
+        assert_eq!(looked_up(&lookup, "10.0.0.1").await, "printer-2nd-floor");
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_flows_do_not_wait_for_lookups() {
+        let (lookup, lookups) = lookup(Duration::from_secs(60), 300);
+        // the lookup is queued once however many flows miss the cache
+        for _ in 0..3 {
+            assert_eq!(lookup.owner("10.0.0.1"), "");
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_lookups_are_not_cached() {
+        let (lookup, lookups) = lookup(Duration::from_secs(60), 300);
+        // the default timeout is 20ms
+        assert_eq!(looked_up(&lookup, "10.0.0.1").await, "");
+        assert_eq!(looked_up(&lookup, "10.0.0.1").await, "");
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use futures::future::join_all;
+use log::{error, info};
+use prost::Message;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::actors::event_stream::kafka::get_producer;
+use crate::actors::storage::clickhouse::ClickhouseState;
+use crate::actors::storage::readback::{RangeQuery, ReadbackError, StoredFlowMessage};
+use crate::settings::BackfillSettings;
+
+/// Publishes flows stored in clickhouse within a time range back to kafka
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct BackfillArgs {
+    /// Start of the range (inclusive), e.g. 2022-11-21T10:00:00Z
+    #[arg(long)]
+    pub from: DateTime<Utc>,
+
+    /// End of the range (exclusive)
+    #[arg(long)]
+    pub to: DateTime<Utc>,
+
+    /// Topic flows are published to, the first of KAFKA_TOPICS by default
+    #[arg(long)]
+    pub topic: Option<String>,
+
+    /// Number of flows read from clickhouse at once
+    #[arg(long, default_value_t = 1000)]
+    pub batch_size: usize,
+
+    /// Maximum number of flows published per second, unlimited when 0
+    #[arg(long, default_value_t = 0)]
+    pub rate: u64,
+}
+
+/// Time to wait before publishing more, so that `sent` flows are spread at `rate` per second
+fn rate_delay(sent: u64, rate: u64, elapsed: Duration) -> Duration {
+    if rate == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64(sent as f64 / rate as f64).saturating_sub(elapsed)
+}
+
+/// Id of a backfilled flow, derived from the stored row so publishing the same range again
+/// gives the same ids. Rows stored without an id are told apart by the hash of their fields
+fn backfill_id(flow: &StoredFlowMessage) -> String {
+    if flow.id.is_empty() {
+        format!(
+            "backfill-{}-{}-{:016x}",
+            flow.host, flow.timestamp, flow.row_hash
+        )
+    } else {
+        format!("backfill-{}", flow.id)
+    }
+}
+
+/// Headers the collector sets, so backfilled flows are processed like fresh ones
+fn headers(flow: &StoredFlowMessage, id: &str) -> OwnedHeaders {
+    OwnedHeaders::new()
+        .add("host-identifier-x", &flow.host)
+        .add("message-id-x", id)
+        .add("timestamp-x", &(flow.timestamp as u64 * 1000).to_string())
+        .add("retry-x", "0")
+}
+
+async fn publish(producer: &FutureProducer, topic: &str, flows: &[StoredFlowMessage]) -> usize {
+    let payloads = flows
+        .iter()
+        .map(|f| f.flow.encode_to_vec())
+        .collect::<Vec<Vec<u8>>>();
+    let ids = flows.iter().map(backfill_id).collect::<Vec<String>>();
+
+    let deliveries = flows.iter().enumerate().map(|(i, f)| {
+        producer.send(
+            FutureRecord::to(topic)
+                .payload(&payloads[i])
+                .key("KREWETKA")
+                .headers(headers(f, &ids[i])),
+            Duration::from_secs(0),
+        )
+    });
+
+    join_all(deliveries)
+        .await
+        .into_iter()
+        .filter(|d| match d {
+            Ok(_) => true,
+            Err((e, _)) => {
+                error!("unable to publish backfilled flow: {}", e);
+                false
+            }
+        })
+        .count()
+}
+
+/// Reads the range page by page and publishes every page before reading the next one
+pub async fn run(args: BackfillArgs, settings: BackfillSettings) -> Result<usize, ReadbackError> {
+    let topic = args
+        .topic
+        .unwrap_or_else(|| settings.kafka_topics[0].clone());
+    let storage = ClickhouseState::new(settings.clickhouse_settings);
+    let producer = get_producer(&settings.kafka_brokers);
+
+    info!(
+        "backfilling flows stored between {} and {} to topic {}",
+        args.from, args.to, topic
+    );
+
+    let started = Instant::now();
+    let mut query = RangeQuery {
+        from: args.from.timestamp(),
+        to: args.to.timestamp(),
+        after: None,
+        limit: args.batch_size,
+    };
+    let mut read = 0;
+    let mut published = 0;
+
+    loop {
+        let flows = storage.flows_in_range(&query).await?;
+        let last = match flows.last() {
+            Some(last) => last.cursor(),
+            None => break,
+        };
+        query.after = Some(last);
+        read += flows.len();
+
+        published += publish(&producer, &topic, &flows).await;
+        info!("published {} of {} flows read so far", published, read);
+
+        sleep(rate_delay(read as u64, args.rate, started.elapsed())).await;
+    }
+
+    info!("backfill finished, published {} flows", published);
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_delay() {
+        assert_eq!(rate_delay(1000, 0, Duration::ZERO), Duration::ZERO);
+        assert_eq!(
+            rate_delay(1000, 500, Duration::from_millis(500)),
+            Duration::from_millis(1500)
+        );
+        // already slower than the rate
+        assert_eq!(
+            rate_delay(1000, 500, Duration::from_secs(3)),
+            Duration::ZERO
+        );
+    }
+
+    fn stored(id: &str, row_hash: u64) -> StoredFlowMessage {
+        StoredFlowMessage {
+            host: "rpi-5.15".to_owned(),
+            timestamp: 1_669_000_042,
+            id: id.to_owned(),
+            row_hash,
+            flow: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_backfill_ids_come_from_the_row() {
+        assert_eq!(backfill_id(&stored("6f1ed002", 1)), "backfill-6f1ed002");
+        // the same row read on another page or run gets the same id
+        assert_eq!(backfill_id(&stored("", 255)), backfill_id(&stored("", 255)));
+        assert_eq!(
+            backfill_id(&stored("", 255)),
+            "backfill-rpi-5.15-1669000042-00000000000000ff"
+        );
+        assert_ne!(backfill_id(&stored("", 1)), backfill_id(&stored("", 2)));
+    }
+
+    #[test]
+    fn test_args() {
+        let args = BackfillArgs::parse_from([
+            "backfill",
+            "--from",
+            "2022-11-21T10:00:00Z",
+            "--to",
+            "2022-11-21T11:00:00Z",
+            "--topic",
+            "flows_scratch",
+            "--rate",
+            "200",
+        ]);
+
+        assert_eq!(args.to.timestamp() - args.from.timestamp(), 3600);
+        assert_eq!(args.topic.as_deref(), Some("flows_scratch"));
+        assert_eq!(args.batch_size, 1000);
+        assert_eq!(args.rate, 200);
+    }
+}
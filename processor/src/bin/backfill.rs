@@ -0,0 +1,25 @@
+use clap::Parser;
+use config::builder::DefaultState;
+use config::{ConfigBuilder, Environment};
+use processor::application_state::get_config;
+use processor::backfill::{self, BackfillArgs};
+use processor::consts::DEFAULT_ENV_VAR_PREFIX;
+use processor::settings::BackfillSettings;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Setup logger
+    let env = env_logger::Env::default();
+    env_logger::init_from_env(env);
+
+    let args = BackfillArgs::parse();
+
+    let config = ConfigBuilder::<DefaultState>::default()
+        .add_source(Environment::with_prefix(DEFAULT_ENV_VAR_PREFIX).separator("__"))
+        .build()?;
+    let settings = get_config::<BackfillSettings>(&config).expect("Getting config failed");
+
+    backfill::run(args, settings).await?;
+    Ok(())
+}
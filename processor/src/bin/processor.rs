@@ -1,19 +1,49 @@
-use actix_web::{App, HttpServer};
-use log::info;
-use processor::application_state::ApplicationState;
-use processor::consts::HTTP_PORT;
-use processor::handler::healthz;
+use actix_web::{web, App, HttpServer};
+use clap::Parser;
+use config::builder::DefaultState;
+use config::{ConfigBuilder, Environment};
+use log::{error, info};
+use processor::application_state::{get_config, ApplicationState};
+use processor::consts::{DEFAULT_ENV_VAR_PREFIX, HTTP_PORT};
+use processor::handler::{
+    healthz, healthz_data, livez, metrics, readyz, replay_dead_letters, sample,
+};
+use processor::settings::DdlSettings;
 
 pub mod pb {
     include!("../flow.rs");
 }
 
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct ProcessorArgs {
+    /// Print DDL of the tables flows are stored in, as configured, and exit
+    #[arg(long)]
+    print_ddl: bool,
+}
+
+/// DDL is derived from the columns rows are built from, so it can not drift from inserts
+fn print_ddl() {
+    let config = ConfigBuilder::<DefaultState>::default()
+        .add_source(Environment::with_prefix(DEFAULT_ENV_VAR_PREFIX).separator("__"))
+        .build()
+        .expect("Reading config failed");
+    let settings = get_config::<DdlSettings>(&config).expect("Getting config failed");
+
+    print!("{}", settings.clickhouse_settings.ddl());
+}
+
 #[actix_web::main]
 async fn main() {
     // Setup logger
     let env = env_logger::Env::default();
     env_logger::init_from_env(env);
 
+    if ProcessorArgs::parse().print_ddl {
+        print_ddl();
+        return;
+    }
+
     info!("Starting application");
 
     let state = match ApplicationState::new().await {
@@ -21,11 +51,37 @@ async fn main() {
         Err(e) => panic!("ApplicationState init error: {:?}", e),
     };
 
-    state.init_actors().await;
+    let pipeline = state.init_actors().await;
+
+    let replayer = web::Data::from(state.dead_letter_replayer());
+    let storage = web::Data::from(state.clickhouse_state());
+    let sampler = web::Data::from(state.sampler());
+    let watchdog = web::Data::from(state.watchdog());
+    let readiness = web::Data::from(state.readiness());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(replayer.clone())
+            .app_data(storage.clone())
+            .app_data(sampler.clone())
+            .app_data(watchdog.clone())
+            .app_data(readiness.clone())
+            .service(healthz)
+            .service(healthz_data)
+            .service(livez)
+            .service(readyz)
+            .service(replay_dead_letters)
+            .service(metrics)
+            .service(sample)
+    })
+    .bind(format!("0.0.0.0:{}", HTTP_PORT))
+    .unwrap_or_else(|_| panic!("unable to bind to port {}", HTTP_PORT))
+    .run()
+    .await;
 
-    HttpServer::new(|| App::new().service(healthz))
-        .bind(format!("0.0.0.0:{}", HTTP_PORT))
-        .unwrap_or_else(|_| panic!("unable to bind to port {}", HTTP_PORT))
-        .run()
-        .await;
+    // the server returns once SIGTERM or ctrl-c is received
+    info!("Shutting down");
+    if let Err(e) = server {
+        error!("http server failed: {}", e);
+    }
+    pipeline.shutdown().await;
 }
@@ -0,0 +1,95 @@
+use crate::actors::messages::FlowMessageMetadata;
+use crate::settings::{ClockSkewSettings, FutureTimestamps};
+
+/// What happened to a flow timestamped in the future
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkewOutcome {
+    /// timestamp is at most the allowed skew ahead, or it is kept as it is
+    Accepted,
+    /// timestamp was replaced with the ingest time
+    Clamped,
+    /// flow has to be dead lettered
+    Rejected,
+}
+
+impl ClockSkewSettings {
+    /// Checks the flow timestamp against `now` (in milliseconds), a clamped one is replaced
+    /// in place, so retries of the flow carry the ingest time
+    pub fn apply(&self, metadata: &mut FlowMessageMetadata, now: u64) -> SkewOutcome {
+        if metadata.timestamp <= now.saturating_add(self.max_clock_skew_ms) {
+            return SkewOutcome::Accepted;
+        }
+
+        match self.future_timestamps {
+            FutureTimestamps::Keep => SkewOutcome::Accepted,
+            FutureTimestamps::Clamp => {
+                metadata.timestamp = now;
+                SkewOutcome::Clamped
+            }
+            FutureTimestamps::Reject => SkewOutcome::Rejected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const NOW: u64 = 1_670_000_000_000;
+
+    fn metadata(timestamp: u64) -> FlowMessageMetadata {
+        FlowMessageMetadata {
+            timestamp,
+            ..testutil::metadata()
+        }
+    }
+
+    fn settings(future_timestamps: FutureTimestamps) -> ClockSkewSettings {
+        ClockSkewSettings {
+            max_clock_skew_ms: 1000,
+            future_timestamps,
+        }
+    }
+
+    #[test]
+    fn test_timestamps_within_skew_are_accepted() {
+        for policy in [
+            FutureTimestamps::Keep,
+            FutureTimestamps::Clamp,
+            FutureTimestamps::Reject,
+        ] {
+            let mut m = metadata(NOW + 1000);
+            assert_eq!(settings(policy).apply(&mut m, NOW), SkewOutcome::Accepted);
+            assert_eq!(m.timestamp, NOW + 1000);
+
+            // flows from the past are never touched
+            let mut m = metadata(0);
+            assert_eq!(settings(policy).apply(&mut m, NOW), SkewOutcome::Accepted);
+        }
+    }
+
+    #[test]
+    fn test_future_timestamps_are_clamped() {
+        let mut m = metadata(NOW + 1001);
+        let outcome = settings(FutureTimestamps::Clamp).apply(&mut m, NOW);
+
+        assert_eq!(outcome, SkewOutcome::Clamped);
+        assert_eq!(m.timestamp, NOW);
+    }
+
+    #[test]
+    fn test_future_timestamps_are_rejected() {
+        let mut m = metadata(NOW + 86_400_000);
+        let outcome = settings(FutureTimestamps::Reject).apply(&mut m, NOW);
+
+        assert_eq!(outcome, SkewOutcome::Rejected);
+        assert_eq!(m.timestamp, NOW + 86_400_000);
+
+        let mut m = metadata(NOW + 86_400_000);
+        assert_eq!(
+            settings(FutureTimestamps::Keep).apply(&mut m, NOW),
+            SkewOutcome::Accepted
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use std::time::Duration;
+
 pub const DEFAULT_ENV_VAR_PREFIX: &str = "KREWETKA";
 pub const ACTORS_MAILBOX_CAPACITY: usize = 200;
 pub const HTTP_PORT: usize = 8080;
+/// Liveness fails when the consumer loop made no progress for this long
+pub const LIVENESS_STALE_AFTER: Duration = Duration::from_secs(60);
@@ -0,0 +1,80 @@
+use std::fmt;
+
+/// Longest detail sent along with the reason, longer ones are cut
+const DEAD_LETTER_DETAIL_MAX_CHARS: usize = 256;
+
+/// Why a message was moved to the dead letter topic, sent in `dead-letter-reason-x` header so
+/// consumers of the topic can bucket failures without parsing the detail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeadLetterReason {
+    /// payload could not be decompressed or decoded into a flow
+    DecodeError,
+    /// flow was decoded but is not acceptable, e.g. its timestamp is too far ahead
+    /// or a value does not fit its column
+    ValidationFailed,
+    /// payload does not match its checksum
+    ChecksumMismatch,
+    /// payload is in a format, schema version or checksum the processor does not support
+    SchemaUnsupported,
+    /// flow could not be stored and ran out of retries
+    StorageFailed,
+}
+
+impl DeadLetterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DecodeError => "decode_error",
+            Self::ValidationFailed => "validation_failed",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::SchemaUnsupported => "schema_unsupported",
+            Self::StorageFailed => "storage_failed",
+        }
+    }
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Reason of a dead letter along with a short human readable detail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub reason: DeadLetterReason,
+    pub detail: String,
+}
+
+impl DeadLetter {
+    pub fn new(reason: DeadLetterReason, detail: impl fmt::Display) -> Self {
+        let mut detail = detail.to_string();
+        if let Some((end, _)) = detail.char_indices().nth(DEAD_LETTER_DETAIL_MAX_CHARS) {
+            detail.truncate(end);
+        }
+        Self { reason, detail }
+    }
+}
+
+impl fmt::Display for DeadLetter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.reason, self.detail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_details_are_cut() {
+        let dead_letter = DeadLetter::new(DeadLetterReason::DecodeError, "ż".repeat(1000));
+        assert_eq!(
+            dead_letter.detail.chars().count(),
+            DEAD_LETTER_DETAIL_MAX_CHARS
+        );
+
+        let dead_letter = DeadLetter::new(DeadLetterReason::StorageFailed, "failed 3 attempts");
+        assert_eq!(dead_letter.detail, "failed 3 attempts");
+        assert_eq!(dead_letter.to_string(), "storage_failed: failed 3 attempts");
+    }
+}
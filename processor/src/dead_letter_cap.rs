@@ -0,0 +1,253 @@
+use crate::metrics::{
+    DEAD_LETTERED_MESSAGES, DEAD_LETTER_CAP_BREACHES, DEAD_LETTER_CAP_CLOSED, DEAD_LETTER_RATE,
+};
+use crate::settings::{DeadLetterCapSettings, DeadLetterPolicy};
+use crate::webhook;
+
+use log::error;
+use serde_json::json;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Window in which more messages were dead lettered than the cap allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breach {
+    pub consumed: u64,
+    pub dead_lettered: u64,
+    pub window: Duration,
+}
+
+impl fmt::Display for Breach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} messages were dead lettered within {}s, {} were consumed",
+            self.dead_lettered,
+            self.window.as_secs(),
+            self.consumed
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    consumed: u64,
+    dead_lettered: u64,
+    /// breach of the window was already reported
+    breached: bool,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            consumed: 0,
+            dead_lettered: 0,
+            breached: false,
+        }
+    }
+}
+
+/// Counts consumed and dead lettered messages in consecutive windows and reports a window in
+/// which too many of them were dead lettered, so a broken collector or schema change does not
+/// silently empty the pipeline into the dead letter topic
+#[derive(Debug)]
+pub struct DeadLetterCap {
+    settings: DeadLetterCapSettings,
+    window: Mutex<Window>,
+    closed: AtomicBool,
+}
+
+impl Default for DeadLetterCap {
+    fn default() -> Self {
+        Self::new(DeadLetterCapSettings::default())
+    }
+}
+
+impl DeadLetterCap {
+    pub fn new(settings: DeadLetterCapSettings) -> Self {
+        Self {
+            settings,
+            window: Mutex::new(Window::new(Instant::now())),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.max_per_sec > 0 || self.settings.max_percent > 0
+    }
+
+    /// True once a fail-closed cap was exceeded, nothing should be consumed anymore
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn consumed(&self) {
+        self.record(false, Instant::now());
+    }
+
+    pub fn dead_lettered(&self) {
+        DEAD_LETTERED_MESSAGES.inc();
+        self.record(true, Instant::now());
+    }
+
+    fn record(&self, dead_lettered: bool, now: Instant) {
+        if let Some(breach) = self.observe(dead_lettered, now) {
+            self.trip(&breach);
+        }
+    }
+
+    fn window_len(&self) -> Duration {
+        Duration::from_secs(self.settings.window_secs.max(1))
+    }
+
+    /// Per second cap is checked as messages are dead lettered, the percentage only once the
+    /// window is complete. A window is reported at most once
+    fn observe(&self, dead_lettered: bool, now: Instant) -> Option<Breach> {
+        let window_len = self.window_len();
+        let mut window = self.window.lock().unwrap();
+        let mut breach = None;
+
+        if now.duration_since(window.started) >= window_len {
+            DEAD_LETTER_RATE.set(window.dead_lettered as f64 / window_len.as_secs_f64());
+            if !window.breached && self.exceeds_percent(&window) {
+                breach = Some(self.breach(&window));
+            }
+            *window = Window::new(now);
+        }
+
+        if dead_lettered {
+            window.dead_lettered += 1;
+        } else {
+            window.consumed += 1;
+        }
+
+        if breach.is_none() && !window.breached && self.exceeds_rate(&window) {
+            window.breached = true;
+            breach = Some(self.breach(&window));
+        }
+        breach
+    }
+
+    fn exceeds_rate(&self, window: &Window) -> bool {
+        let max = self.settings.max_per_sec;
+        max > 0 && window.dead_lettered > max.saturating_mul(self.window_len().as_secs())
+    }
+
+    fn exceeds_percent(&self, window: &Window) -> bool {
+        let max = self.settings.max_percent;
+        max > 0 && window.consumed > 0 && window.dead_lettered * 100 > max * window.consumed
+    }
+
+    fn breach(&self, window: &Window) -> Breach {
+        Breach {
+            consumed: window.consumed,
+            dead_lettered: window.dead_lettered,
+            window: self.window_len(),
+        }
+    }
+
+    fn trip(&self, breach: &Breach) {
+        DEAD_LETTER_CAP_BREACHES.inc();
+        error!(
+            "DEAD LETTER CAP EXCEEDED: {} (max {}/s, {}%)",
+            breach, self.settings.max_per_sec, self.settings.max_percent
+        );
+
+        if let Some(url) = &self.settings.alert_url {
+            webhook::alert(
+                url,
+                json!({
+                    "alert": "dead_letter_cap_exceeded",
+                    "consumed": breach.consumed,
+                    "dead_lettered": breach.dead_lettered,
+                    "window_secs": breach.window.as_secs(),
+                    "max_per_sec": self.settings.max_per_sec,
+                    "max_percent": self.settings.max_percent,
+                    "fail_closed": self.settings.policy == DeadLetterPolicy::FailClosed,
+                }),
+            );
+        }
+
+        if self.settings.policy == DeadLetterPolicy::FailClosed
+            && !self.closed.swap(true, Ordering::SeqCst)
+        {
+            DEAD_LETTER_CAP_CLOSED.set(1);
+            error!(
+                "consumption is stopped by the dead letter cap until the processor is restarted"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cap(max_per_sec: u64, max_percent: u64, policy: DeadLetterPolicy) -> DeadLetterCap {
+        DeadLetterCap::new(DeadLetterCapSettings {
+            max_per_sec,
+            max_percent,
+            window_secs: 10,
+            policy,
+            alert_url: None,
+        })
+    }
+
+    #[test]
+    fn test_rate_is_breached_once_per_window() {
+        let cap = cap(2, 0, DeadLetterPolicy::FailOpen);
+        let start = cap.window.lock().unwrap().started;
+
+        for _ in 0..20 {
+            assert_eq!(cap.observe(true, start), None);
+        }
+        let breach = cap.observe(true, start).unwrap();
+        assert_eq!(breach.dead_lettered, 21);
+        assert_eq!(cap.observe(true, start), None);
+
+        // next window starts over
+        let next = start + Duration::from_secs(10);
+        assert_eq!(cap.observe(true, next), None);
+    }
+
+    #[test]
+    fn test_percent_is_checked_when_window_completes() {
+        let cap = cap(0, 10, DeadLetterPolicy::FailOpen);
+        let start = cap.window.lock().unwrap().started;
+
+        for _ in 0..9 {
+            assert_eq!(cap.observe(false, start), None);
+        }
+        assert_eq!(cap.observe(true, start), None);
+        assert_eq!(cap.observe(true, start), None);
+
+        let breach = cap.observe(false, start + Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            breach,
+            Breach {
+                consumed: 9,
+                dead_lettered: 2,
+                window: Duration::from_secs(10),
+            }
+        );
+    }
+
+    #[test]
+    fn test_fail_closed_cap_stops_consumption() {
+        let open = cap(1, 0, DeadLetterPolicy::FailOpen);
+        let closed = cap(1, 0, DeadLetterPolicy::FailClosed);
+        assert!(!cap(0, 0, DeadLetterPolicy::FailClosed).is_enabled());
+
+        let now = Instant::now();
+        for _ in 0..11 {
+            open.record(true, now);
+            closed.record(true, now);
+        }
+        assert!(!open.is_closed());
+        assert!(closed.is_closed());
+    }
+}
@@ -0,0 +1,325 @@
+use crate::actors::messages::PersistFlowMessageWithMetadata;
+use crate::assets::AssetLookup;
+use crate::metrics::ENRICHMENT_TIMEOUTS;
+use crate::pb::FlowMessage;
+use crate::settings::EnrichmentSettings;
+
+use async_trait::async_trait;
+use tokio::time::{timeout_at, Instant};
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnrichmentError(String);
+
+impl fmt::Display for EnrichmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid enrichment: {}", self.0)
+    }
+}
+
+impl std::error::Error for EnrichmentError {}
+
+/// Single stage of the enrichment pipeline, run on classified flows before they are stored.
+/// Flows sent back to a retry topic are enriched again, so stages have to be idempotent.
+/// A stage running out of the enrichment budget is dropped at its next await point, stages
+/// waiting on external I/O have to leave the flow valid at each of them
+#[async_trait]
+pub trait Enrich: Send + Sync {
+    async fn enrich(&self, msg: &mut PersistFlowMessageWithMetadata);
+}
+
+const TCP: u32 = 6;
+const UDP: u32 = 17;
+
+/// Orients flows from client to server, the side with the lower port is taken for the server.
+/// Addresses, ports and counters are swapped for flows reported the other way round
+pub struct Direction;
+
+#[async_trait]
+impl Enrich for Direction {
+    async fn enrich(&self, msg: &mut PersistFlowMessageWithMetadata) {
+        let f = &mut msg.0.flow_message;
+        if !matches!(f.protocol, TCP | UDP) || f.l4_src_port == 0 || f.l4_src_port >= f.l4_dst_port
+        {
+            return;
+        }
+
+        std::mem::swap(&mut f.ipv4_src_addr, &mut f.ipv4_dst_addr);
+        std::mem::swap(&mut f.l4_src_port, &mut f.l4_dst_port);
+        std::mem::swap(&mut f.in_bytes, &mut f.out_bytes);
+        std::mem::swap(&mut f.in_pkts, &mut f.out_pkts);
+    }
+}
+
+/// Fills the nDPI protocol of flows nprobe did not recognize from the well known destination port
+pub struct Service;
+
+impl Service {
+    /// nDPI protocol ids, see `ndpi_protocol_ids.h`
+    fn l7_proto(f: &FlowMessage) -> Option<f32> {
+        match (f.protocol, f.l4_dst_port) {
+            (TCP, 21) => Some(1.0),
+            (TCP, 110) => Some(2.0),
+            (TCP, 25) => Some(3.0),
+            (TCP, 143) => Some(4.0),
+            (TCP | UDP, 53) => Some(5.0),
+            (TCP, 80) => Some(7.0),
+            (UDP, 123) => Some(9.0),
+            (TCP, 179) => Some(13.0),
+            (UDP, 161) => Some(14.0),
+            (TCP, 443) => Some(91.0),
+            (TCP, 22) => Some(92.0),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Enrich for Service {
+    async fn enrich(&self, msg: &mut PersistFlowMessageWithMetadata) {
+        let f = &mut msg.0.flow_message;
+        if f.l7_proto != 0.0 {
+            return;
+        }
+        if let Some(l7_proto) = Self::l7_proto(f) {
+            f.l7_proto = l7_proto;
+        }
+    }
+}
+
+/// Stage implementing the given name, stages looking up external stores are set up from
+/// their settings
+fn stage(
+    name: &str,
+    settings: &EnrichmentSettings,
+) -> Result<(&'static str, Box<dyn Enrich>), EnrichmentError> {
+    match name {
+        "direction" => Ok(("direction", Box::new(Direction))),
+        "service" => Ok(("service", Box::new(Service))),
+        "assets" => AssetLookup::new(&settings.assets)
+            .map(|lookup| ("assets", Box::new(lookup) as Box<dyn Enrich>))
+            .map_err(EnrichmentError),
+        _ => Err(EnrichmentError(format!("unknown stage {name}"))),
+    }
+}
+
+/// Enrichment stages run in the configured order, none by default
+#[derive(Default)]
+pub struct Enrichment {
+    stages: Vec<(&'static str, Box<dyn Enrich>)>,
+    /// time all stages of a flow may take, unbounded without it
+    budget: Option<Duration>,
+}
+
+impl fmt::Debug for Enrichment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Enrichment")
+            .field("stages", &self.names())
+            .field("budget", &self.budget)
+            .finish()
+    }
+}
+
+impl TryFrom<&EnrichmentSettings> for Enrichment {
+    type Error = EnrichmentError;
+
+    /// Stages are separated with commas, `<name>=off` keeps a stage in the list but skips it
+    fn try_from(settings: &EnrichmentSettings) -> Result<Self, Self::Error> {
+        let mut stages: Vec<(&'static str, Box<dyn Enrich>)> = Vec::new();
+        let mut seen = Vec::new();
+        for entry in settings.stages.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (name, enabled) = match entry.split_once('=') {
+                None => (entry, true),
+                Some((name, "on")) => (name.trim(), true),
+                Some((name, "off")) => (name.trim(), false),
+                Some(_) => return Err(EnrichmentError(entry.to_owned())),
+            };
+            let (name, stage) = stage(name, settings)?;
+            if seen.contains(&name) {
+                return Err(EnrichmentError(format!("stage {name} is listed twice")));
+            }
+            seen.push(name);
+
+            if enabled {
+                stages.push((name, stage));
+            }
+        }
+
+        let budget = match settings.budget_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        Ok(Self { stages, budget })
+    }
+}
+
+impl Enrichment {
+    pub fn names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Runs the stages until the budget runs out. The stage running at that moment is cancelled
+    /// and the rest is skipped, the flow is marked as `enrichment_timed_out` and goes on with
+    /// the stages which finished
+    pub async fn apply(&self, msg: &mut PersistFlowMessageWithMetadata) {
+        let deadline = self.budget.map(|budget| Instant::now() + budget);
+        for (name, stage) in &self.stages {
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    stage.enrich(msg).await;
+                    continue;
+                }
+            };
+
+            if Instant::now() >= deadline || timeout_at(deadline, stage.enrich(msg)).await.is_err()
+            {
+                ENRICHMENT_TIMEOUTS.with_label_values(&[*name]).inc();
+                msg.0.metadata.enrichment_timed_out = true;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn enrichment(stages: &str) -> Result<Enrichment, EnrichmentError> {
+        Enrichment::try_from(&EnrichmentSettings {
+            stages: stages.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    // dns response reported from the server side
+    fn dns_response() -> PersistFlowMessageWithMetadata {
+        testutil::flow()
+            .with_malicious(Some(false))
+            .with_src("10.0.0.1")
+            .with_dst("10.0.0.2")
+            .with_src_port(53)
+            .with_dst_port(40000)
+            .with_protocol(UDP)
+            .with_traffic((120, 61), (1, 1))
+            .build()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_enriched_by_default() {
+        let mut f = dns_response();
+        Enrichment::default().apply(&mut f).await;
+        assert_eq!(f.0.flow_message, dns_response().0.flow_message);
+    }
+
+    #[tokio::test]
+    async fn test_flows_are_oriented_from_client() {
+        let mut f = dns_response();
+        enrichment("direction").unwrap().apply(&mut f).await;
+
+        let m = &f.0.flow_message;
+        assert_eq!(
+            (m.ipv4_src_addr.as_str(), m.l4_src_port),
+            ("10.0.0.2", 40000)
+        );
+        assert_eq!((m.ipv4_dst_addr.as_str(), m.l4_dst_port), ("10.0.0.1", 53));
+        assert_eq!((m.in_bytes, m.out_bytes), (61, 120));
+
+        // oriented flows are kept as they are
+        let oriented = f.0.flow_message.clone();
+        enrichment("direction").unwrap().apply(&mut f).await;
+        assert_eq!(f.0.flow_message, oriented);
+    }
+
+    #[tokio::test]
+    async fn test_stages_run_in_configured_order() {
+        let mut f = dns_response();
+        enrichment("direction, service")
+            .unwrap()
+            .apply(&mut f)
+            .await;
+        assert_eq!(f.0.flow_message.l7_proto, 5.0);
+
+        // the service is looked up before the flow is oriented
+        let mut f = dns_response();
+        enrichment("service, direction")
+            .unwrap()
+            .apply(&mut f)
+            .await;
+        assert_eq!(f.0.flow_message.l7_proto, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_stages_are_skipped() {
+        let enrichment = enrichment("direction=off,service=on").unwrap();
+        assert_eq!(enrichment.names(), vec!["service"]);
+
+        let mut f = dns_response();
+        enrichment.apply(&mut f).await;
+        assert_eq!(f.0.flow_message.l4_dst_port, 40000);
+    }
+
+    /// Stage waiting on a slow resolver
+    struct Slow;
+
+    #[async_trait]
+    impl Enrich for Slow {
+        async fn enrich(&self, msg: &mut PersistFlowMessageWithMetadata) {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            msg.0.flow_message.l7_proto = 1.0;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stages_over_budget_are_cancelled() {
+        let slow = Enrichment {
+            stages: vec![
+                stage("direction", &EnrichmentSettings::default()).unwrap(),
+                ("slow", Box::new(Slow) as Box<dyn Enrich>),
+                stage("service", &EnrichmentSettings::default()).unwrap(),
+            ],
+            budget: Some(Duration::from_millis(20)),
+        };
+
+        let mut f = dns_response();
+        tokio::time::timeout(Duration::from_secs(1), slow.apply(&mut f))
+            .await
+            .expect("enrichment is not bounded by its budget");
+
+        // oriented before the budget ran out, the service was not looked up
+        assert_eq!(f.0.flow_message.l4_dst_port, 53);
+        assert_eq!(f.0.flow_message.l7_proto, 0.0);
+        assert!(f.0.metadata.enrichment_timed_out);
+
+        let mut f = dns_response();
+        enrichment("direction").unwrap().apply(&mut f).await;
+        assert!(!f.0.metadata.enrichment_timed_out);
+    }
+
+    #[test]
+    fn test_invalid_stages_are_rejected() {
+        assert_eq!(
+            enrichment("direction,geoip").unwrap_err(),
+            EnrichmentError("unknown stage geoip".to_owned())
+        );
+        assert_eq!(
+            enrichment("service,service=off").unwrap_err(),
+            EnrichmentError("stage service is listed twice".to_owned())
+        );
+        assert!(enrichment("service=maybe").is_err());
+        assert_eq!(
+            enrichment("assets").unwrap_err(),
+            EnrichmentError("stage assets requires redis_url".to_owned())
+        );
+        assert!(enrichment("").unwrap().names().is_empty());
+    }
+}
@@ -13,6 +13,7 @@ pub struct FlowMessageClass {
     #[prost(bool, tag="1")]
     pub malicious: bool,
 }
+#[derive(serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct FlowMessage {
     #[prost(uint64, tag="1")]
@@ -0,0 +1,162 @@
+use crate::pb::FlowMessage;
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+pub const PROTOCOL_ICMP: u32 = 1;
+pub const PROTOCOL_TCP: u32 = 6;
+pub const PROTOCOL_UDP: u32 = 17;
+
+/// Values derived from raw flow fields.
+/// Accessors of fields which may be malformed (addresses, ports) return `None` instead of failing
+pub trait FlowMessageExt {
+    fn src_ip(&self) -> Option<Ipv4Addr>;
+    fn dst_ip(&self) -> Option<Ipv4Addr>;
+    fn src_socket(&self) -> Option<SocketAddr>;
+    fn dst_socket(&self) -> Option<SocketAddr>;
+    fn total_bytes(&self) -> u64;
+    fn total_pkts(&self) -> u64;
+    fn duration(&self) -> Duration;
+    /// Packets per second, `None` when the flow has no duration
+    fn pps(&self) -> Option<f64>;
+    /// Bytes per second, `None` when the flow has no duration
+    fn bps(&self) -> Option<f64>;
+    fn is_tcp(&self) -> bool;
+    fn is_udp(&self) -> bool;
+    fn is_icmp(&self) -> bool;
+}
+
+fn socket(addr: &str, port: u32) -> Option<SocketAddr> {
+    let ip = addr.parse::<Ipv4Addr>().ok()?;
+    let port = u16::try_from(port).ok()?;
+    Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+}
+
+fn per_second(value: u64, duration: Duration) -> Option<f64> {
+    if duration.is_zero() {
+        return None;
+    }
+    Some(value as f64 / duration.as_secs_f64())
+}
+
+impl FlowMessageExt for FlowMessage {
+    fn src_ip(&self) -> Option<Ipv4Addr> {
+        self.ipv4_src_addr.parse().ok()
+    }
+
+    fn dst_ip(&self) -> Option<Ipv4Addr> {
+        self.ipv4_dst_addr.parse().ok()
+    }
+
+    fn src_socket(&self) -> Option<SocketAddr> {
+        socket(&self.ipv4_src_addr, self.l4_src_port)
+    }
+
+    fn dst_socket(&self) -> Option<SocketAddr> {
+        socket(&self.ipv4_dst_addr, self.l4_dst_port)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.in_bytes.saturating_add(self.out_bytes)
+    }
+
+    fn total_pkts(&self) -> u64 {
+        self.in_pkts.saturating_add(self.out_pkts)
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::from_millis(self.flow_duration_milliseconds)
+    }
+
+    fn pps(&self) -> Option<f64> {
+        per_second(self.total_pkts(), self.duration())
+    }
+
+    fn bps(&self) -> Option<f64> {
+        per_second(self.total_bytes(), self.duration())
+    }
+
+    fn is_tcp(&self) -> bool {
+        self.protocol == PROTOCOL_TCP
+    }
+
+    fn is_udp(&self) -> bool {
+        self.protocol == PROTOCOL_UDP
+    }
+
+    fn is_icmp(&self) -> bool {
+        self.protocol == PROTOCOL_ICMP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow() -> FlowMessage {
+        FlowMessage {
+            out_bytes: 1000,
+            out_pkts: 4,
+            in_bytes: 500,
+            in_pkts: 2,
+            ipv4_src_addr: "192.168.1.7".to_owned(),
+            ipv4_dst_addr: "8.8.8.8".to_owned(),
+            l7_proto: 7.0,
+            l4_dst_port: 443,
+            l4_src_port: 40112,
+            flow_duration_milliseconds: 2000,
+            protocol: PROTOCOL_TCP,
+            tcp_flags: 27,
+        }
+    }
+
+    #[test]
+    fn test_sockets() {
+        let f = flow();
+        assert_eq!(f.src_socket(), Some("192.168.1.7:40112".parse().unwrap()));
+        assert_eq!(f.dst_socket(), Some("8.8.8.8:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_malformed_sockets() {
+        let f = FlowMessage {
+            ipv4_src_addr: "".to_owned(),
+            l4_dst_port: 70000,
+            ..flow()
+        };
+        assert_eq!(f.src_ip(), None);
+        assert_eq!(f.src_socket(), None);
+        assert_eq!(f.dst_ip(), Some(Ipv4Addr::new(8, 8, 8, 8)));
+        assert_eq!(f.dst_socket(), None);
+    }
+
+    #[test]
+    fn test_totals_and_rates() {
+        let f = flow();
+        assert_eq!(f.total_bytes(), 1500);
+        assert_eq!(f.total_pkts(), 6);
+        assert_eq!(f.pps(), Some(3.0));
+        assert_eq!(f.bps(), Some(750.0));
+
+        let f = FlowMessage {
+            in_bytes: u64::MAX,
+            flow_duration_milliseconds: 0,
+            ..flow()
+        };
+        assert_eq!(f.total_bytes(), u64::MAX);
+        assert_eq!(f.pps(), None);
+        assert_eq!(f.bps(), None);
+    }
+
+    #[test]
+    fn test_protocols() {
+        let f = flow();
+        assert!(f.is_tcp() && !f.is_udp() && !f.is_icmp());
+
+        let f = FlowMessage {
+            protocol: PROTOCOL_UDP,
+            ..flow()
+        };
+        assert!(f.is_udp() && !f.is_tcp());
+    }
+}
@@ -1,6 +1,100 @@
-use actix_web::{get, http, HttpResponse, Responder};
+use actix_web::{get, http, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+use std::time::Duration;
+
+use crate::actors::event_stream::kafka::DeadLetterReplayer;
+use crate::actors::storage::clickhouse::ClickhouseState;
+use crate::actors::storage::readback::ReadbackQuery;
+use crate::health::{Readiness, Watchdog};
+use crate::metrics;
+use crate::sampler::FlowSampler;
 
 #[get("/healthz")]
 async fn healthz() -> impl Responder {
     HttpResponse::build(http::StatusCode::OK).body("OK".to_owned())
 }
+
+/// Alive while the consumer loop makes progress, a stuck loop is fixed by a restart
+#[get("/livez")]
+async fn livez(watchdog: web::Data<Watchdog>) -> impl Responder {
+    let since_last_beat = watchdog.since_last_beat().as_secs();
+    if watchdog.is_alive() {
+        HttpResponse::build(http::StatusCode::OK)
+            .body(format!("consumer loop progressed {}s ago", since_last_beat))
+    } else {
+        HttpResponse::build(http::StatusCode::SERVICE_UNAVAILABLE)
+            .body(format!("consumer loop stuck for {}s", since_last_beat))
+    }
+}
+
+/// Ready when kafka, the classifier and clickhouse are reachable
+#[get("/readyz")]
+async fn readyz(readiness: web::Data<Readiness>) -> impl Responder {
+    let report = readiness.check().await;
+    let status = if report.ready {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    HttpResponse::build(status).json(report)
+}
+
+#[derive(Debug, Deserialize)]
+struct DataHealthParams {
+    lookback_secs: Option<u64>,
+    host: Option<String>,
+}
+
+/// Healthy when any flow was stored recently
+#[get("/healthz/data")]
+async fn healthz_data(
+    storage: web::Data<ClickhouseState>,
+    params: web::Query<DataHealthParams>,
+) -> impl Responder {
+    let params = params.into_inner();
+    let mut query = ReadbackQuery {
+        host: params.host,
+        ..Default::default()
+    };
+    if let Some(secs) = params.lookback_secs {
+        query.lookback = Duration::from_secs(secs);
+    }
+
+    match storage.count_recent(&query).await {
+        Ok(0) => HttpResponse::build(http::StatusCode::SERVICE_UNAVAILABLE).body(format!(
+            "no flows stored in the last {}s",
+            query.lookback.as_secs()
+        )),
+        Ok(count) => HttpResponse::build(http::StatusCode::OK).body(format!(
+            "{} flows stored in the last {}s",
+            count,
+            query.lookback.as_secs()
+        )),
+        Err(e) => HttpResponse::build(http::StatusCode::SERVICE_UNAVAILABLE).body(e.to_string()),
+    }
+}
+
+#[post("/replay")]
+async fn replay_dead_letters(replayer: web::Data<DeadLetterReplayer>) -> impl Responder {
+    if replayer.into_inner().start() {
+        HttpResponse::build(http::StatusCode::ACCEPTED)
+            .body("dead letter replay started".to_owned())
+    } else {
+        HttpResponse::build(http::StatusCode::CONFLICT)
+            .body("dead letter replay already in progress".to_owned())
+    }
+}
+
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    HttpResponse::build(http::StatusCode::OK)
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::gather())
+}
+
+/// Random sample of recently consumed flows, newest first
+#[get("/sample")]
+async fn sample(sampler: web::Data<FlowSampler>) -> impl Responder {
+    HttpResponse::build(http::StatusCode::OK).json(sampler.sample())
+}
@@ -0,0 +1,169 @@
+use crate::actors::storage::clickhouse::ClickhouseState;
+
+use rdkafka::producer::FutureProducer;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::transport::Endpoint;
+
+/// How long a single readiness check may take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Progress of the consumer loop, it beats on every iteration including idle ones.
+/// A stale beat means the loop is stuck, not that there is nothing to consume
+pub struct Watchdog {
+    started: Instant,
+    last_beat_ms: AtomicU64,
+    stale_after: Duration,
+}
+
+impl Watchdog {
+    pub fn new(stale_after: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            last_beat_ms: AtomicU64::new(0),
+            stale_after,
+        }
+    }
+
+    pub fn beat(&self) {
+        self.last_beat_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn since_last_beat(&self) -> Duration {
+        let last_beat = Duration::from_millis(self.last_beat_ms.load(Ordering::SeqCst));
+        self.started.elapsed().saturating_sub(last_beat)
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.since_last_beat() <= self.stale_after
+    }
+}
+
+/// Result of every readiness check, `ok` or the reason it failed
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+impl ReadinessReport {
+    fn new(checks: Vec<(&'static str, Result<(), String>)>) -> Self {
+        let ready = checks.iter().all(|(_, r)| r.is_ok());
+        let checks = checks
+            .into_iter()
+            .map(|(name, r)| (name, r.err().unwrap_or_else(|| "ok".to_owned())))
+            .collect();
+        Self { ready, checks }
+    }
+}
+
+/// Checks services flows pass through: kafka, the classifier and clickhouse
+pub struct Readiness {
+    producer: FutureProducer,
+    classifier_dsn: String,
+    storage: Arc<ClickhouseState>,
+}
+
+impl Readiness {
+    pub fn new(
+        producer: FutureProducer,
+        classifier_dsn: String,
+        storage: Arc<ClickhouseState>,
+    ) -> Self {
+        Self {
+            producer,
+            classifier_dsn,
+            storage,
+        }
+    }
+
+    pub async fn check(&self) -> ReadinessReport {
+        let (kafka, classifier, storage) = tokio::join!(
+            self.check_kafka(),
+            self.check_classifier(),
+            self.check_storage()
+        );
+
+        ReadinessReport::new(vec![
+            ("kafka", kafka),
+            ("classifier", classifier),
+            ("clickhouse", storage),
+        ])
+    }
+
+    async fn check_kafka(&self) -> Result<(), String> {
+        let producer = self.producer.clone();
+        // fetching metadata blocks until brokers answer
+        tokio::task::spawn_blocking(move || {
+            producer
+                .client()
+                .fetch_metadata(None, CHECK_TIMEOUT)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+
+    async fn check_classifier(&self) -> Result<(), String> {
+        Endpoint::from_shared(self.classifier_dsn.clone())
+            .map_err(|e| e.to_string())?
+            .connect_timeout(CHECK_TIMEOUT)
+            .connect()
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Not ready until the pool is warmed up, a warmup which failed on start up is retried
+    async fn check_storage(&self) -> Result<(), String> {
+        tokio::time::timeout(CHECK_TIMEOUT, async {
+            self.storage.ping().await.map_err(|e| e.to_string())?;
+            self.storage
+                .warm_up()
+                .await
+                .map_err(|e| format!("warmup failed: {}", e))
+        })
+        .await
+        .map_err(|_| "timed out".to_owned())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_goes_stale() {
+        let watchdog = Watchdog::new(Duration::from_millis(20));
+        watchdog.beat();
+        assert!(watchdog.is_alive());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!watchdog.is_alive());
+
+        watchdog.beat();
+        assert!(watchdog.is_alive());
+        assert!(watchdog.since_last_beat() < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_report_is_ready_only_when_all_checks_pass() {
+        let report = ReadinessReport::new(vec![("kafka", Ok(())), ("clickhouse", Ok(()))]);
+        assert!(report.ready);
+
+        let report = ReadinessReport::new(vec![
+            ("kafka", Ok(())),
+            ("clickhouse", Err("connection refused".to_owned())),
+        ]);
+        assert!(!report.ready);
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"ready":false,"checks":{"clickhouse":"connection refused","kafka":"ok"}}"#
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+
+pub use common::keys::KeyField;
+use common::keys::KeyParts;
+
+/// Key of messages produced without a configured key field
+pub const DEFAULT_MESSAGE_KEY: &str = "KREWETKA";
+
+fn key_parts(f: &FlowMessageWithMetadata) -> KeyParts {
+    KeyParts {
+        host: &f.metadata.host,
+        ipv4_src_addr: &f.flow_message.ipv4_src_addr,
+        l4_src_port: f.flow_message.l4_src_port,
+        ipv4_dst_addr: &f.flow_message.ipv4_dst_addr,
+        l4_dst_port: f.flow_message.l4_dst_port,
+        protocol: f.flow_message.protocol,
+    }
+}
+
+/// Kafka message key of the flow, the same key collector sends with `kafka_key_field` set to
+/// the field. `random` isn't derived from the flow and falls back to `DEFAULT_MESSAGE_KEY`
+pub fn message_key(field: Option<KeyField>, f: &FlowMessageWithMetadata) -> String {
+    field
+        .and_then(|k| k.key(&key_parts(f)))
+        .unwrap_or_else(|| DEFAULT_MESSAGE_KEY.to_owned())
+}
+
+/// Sharding key of the stored row. `random` is refused as `shard_key` on startup, rows would
+/// not be placed where their partitions are, so it has no sharding key of its own
+pub fn shard_key(field: KeyField, f: &FlowMessageWithMetadata) -> u32 {
+    field.shard_key(&key_parts(f)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_keys_of_flow() {
+        let f = testutil::flow()
+            .with_host("foobar")
+            .with_src("10.0.0.1")
+            .build();
+        assert_eq!(message_key(None, &f), DEFAULT_MESSAGE_KEY);
+        assert_eq!(message_key(Some(KeyField::Random), &f), DEFAULT_MESSAGE_KEY);
+        assert_eq!(message_key(Some(KeyField::Host), &f), "foobar");
+        assert_eq!(message_key(Some(KeyField::SrcIp), &f), "10.0.0.1");
+
+        // kafka `toPositive` of murmur2 of "foobar"
+        assert_eq!(shard_key(KeyField::Host, &f), 1357151166);
+        assert_ne!(
+            shard_key(KeyField::SrcIp, &f),
+            shard_key(
+                KeyField::SrcIp,
+                &testutil::flow().with_src("10.0.0.2").build()
+            )
+        );
+    }
+}
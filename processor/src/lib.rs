@@ -1,9 +1,28 @@
 pub mod actors;
+pub mod allowlist;
 pub mod application_state;
+pub mod assets;
+pub mod backfill;
+pub mod clock_skew;
 pub mod consts;
+pub mod dead_letter;
+pub mod dead_letter_cap;
+pub mod enrich;
+pub mod flow_ext;
 pub mod handler;
+pub mod health;
+pub mod keys;
+pub mod metrics;
 pub mod migrator;
+pub mod privacy;
+pub mod sampler;
+pub mod scan_detection;
 pub mod settings;
+pub mod shutdown;
+pub mod statsd;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+pub mod webhook;
 
 pub mod pb {
     include!("flow.rs");
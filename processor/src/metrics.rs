@@ -0,0 +1,145 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Gauge, Histogram, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref CLICKHOUSE_HANDLE_WAIT_SECONDS: Histogram = register_histogram!(
+        "processor_clickhouse_handle_wait_seconds",
+        "Time spent waiting for a connection from the clickhouse pool",
+        vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
+    pub static ref CLICKHOUSE_POOL_EXHAUSTED: IntCounter = register_int_counter!(
+        "processor_clickhouse_pool_exhausted_total",
+        "Connections which were not acquired from the clickhouse pool within the timeout"
+    )
+    .unwrap();
+    pub static ref STORAGE_INSERTED_ROWS: IntCounter = register_int_counter!(
+        "processor_storage_inserted_rows_total",
+        "Rows accepted by clickhouse"
+    )
+    .unwrap();
+    pub static ref STORAGE_REJECTED_ROWS: IntCounter = register_int_counter!(
+        "processor_storage_rejected_rows_total",
+        "Rows of saved batches which did not reach clickhouse and were sent to retry"
+    )
+    .unwrap();
+    pub static ref COLUMN_OVERFLOWS: IntCounterVec = register_int_counter_vec!(
+        "processor_column_overflows_total",
+        "Values out of range of their coerced column, labelled by column and outcome",
+        &["column", "outcome"]
+    )
+    .unwrap();
+    pub static ref STORAGE_COALESCED_ROWS: IntCounter = register_int_counter!(
+        "processor_storage_coalesced_rows_total",
+        "Updates of flows merged into a later update of the same flow instead of being stored"
+    )
+    .unwrap();
+    pub static ref STORAGE_INFLIGHT_INSERTS: IntGauge = register_int_gauge!(
+        "processor_storage_inflight_inserts",
+        "Batches being inserted into clickhouse right now"
+    )
+    .unwrap();
+    pub static ref ROLLUP_LATE_FLOWS: IntCounterVec = register_int_counter_vec!(
+        "processor_rollup_late_flows_total",
+        "Flows which arrived after their rollup window was saved",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref ENRICHMENT_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "processor_enrichment_timeouts_total",
+        "Flows stored partially enriched because the stage ran out of the enrichment budget",
+        &["stage"]
+    )
+    .unwrap();
+    pub static ref ASSET_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "processor_asset_lookups_total",
+        "Owners of flow addresses looked up by the assets enrichment stage by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref FUTURE_TIMESTAMPS: IntCounterVec = register_int_counter_vec!(
+        "processor_future_timestamps_total",
+        "Consumed messages timestamped further in the future than the allowed clock skew",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref REJECTED_SOURCE_MESSAGES: IntCounter = register_int_counter!(
+        "processor_rejected_source_messages_total",
+        "Consumed messages dropped because their host is not in the allowed sources"
+    )
+    .unwrap();
+    pub static ref CORRUPTED_MESSAGES: IntCounter = register_int_counter!(
+        "processor_corrupted_messages_total",
+        "Consumed messages whose payload did not match their checksum, moved to the dead letter topic"
+    )
+    .unwrap();
+    pub static ref DEAD_LETTERED_MESSAGES: IntCounter = register_int_counter!(
+        "processor_dead_lettered_messages_total",
+        "Messages moved to the dead letter topic, undecodable ones and ones which ran out of retries"
+    )
+    .unwrap();
+    pub static ref DEAD_LETTER_REASONS: IntCounterVec = register_int_counter_vec!(
+        "processor_dead_letter_reasons_total",
+        "Messages moved to the dead letter topic, labelled by the reason code they were sent with",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref DEAD_LETTER_RATE: Gauge = register_gauge!(
+        "processor_dead_letter_rate",
+        "Dead lettered messages per second over the last complete dead letter cap window"
+    )
+    .unwrap();
+    pub static ref DEAD_LETTER_CAP_BREACHES: IntCounter = register_int_counter!(
+        "processor_dead_letter_cap_breaches_total",
+        "Windows in which dead lettered messages exceeded the configured cap"
+    )
+    .unwrap();
+    pub static ref DEAD_LETTER_CAP_CLOSED: IntGauge = register_int_gauge!(
+        "processor_dead_letter_cap_closed",
+        "1 once consumption is stopped by a fail-closed dead letter cap"
+    )
+    .unwrap();
+    pub static ref SCAN_DETECTIONS: IntCounterVec = register_int_counter_vec!(
+        "processor_scan_detections_total",
+        "Scan detection thresholds crossed by a source or destination port, labelled by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref WEBHOOK_NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "processor_webhook_notifications_total",
+        "Notifications about stored flagged flows by outcome, sent, failed or dropped",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref SUSPICIOUS_FLOWS: IntCounterVec = register_int_counter_vec!(
+        "processor_suspicious_flows_total",
+        "Flows published to the suspicious topic by outcome, published or failed",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref STORAGE_SAMPLING: IntCounterVec = register_int_counter_vec!(
+        "processor_storage_sampling_total",
+        "Flows reaching storage by sampling outcome, kept, always_kept or dropped",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref KAFKA_CONSUMER_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "processor_kafka_consumer_lag",
+        "Messages between the high watermark and the committed offset of the consumer group",
+        &["topic", "partition"]
+    )
+    .unwrap();
+}
+
+/// All registered metrics in prometheus text format
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("unable to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
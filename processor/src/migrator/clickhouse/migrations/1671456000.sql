@@ -0,0 +1,4 @@
+ALTER TABLE messages
+    MODIFY COLUMN ipv4_src_addr Nullable(IPv4),
+    MODIFY COLUMN ipv4_dst_addr Nullable(IPv4),
+    MODIFY COLUMN l7_proto Nullable(Float32)
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS flow_rollups (
+	 window_start	DateTime,
+	 key_kind		LowCardinality(String),
+	 host			String,
+	 src_addr		String,
+	 dst_addr		String,
+	 dst_port		UInt32,
+	 protocol		UInt32,
+	 flows			UInt64,
+	 malicious_flows	UInt64,
+	 in_bytes		UInt64,
+	 out_bytes		UInt64,
+	 in_pkts		UInt64,
+	 out_pkts		UInt64
+) Engine=SummingMergeTree
+ORDER BY (key_kind, window_start, host, src_addr, dst_addr, dst_port, protocol)
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS tier String DEFAULT 'default' AFTER malicious
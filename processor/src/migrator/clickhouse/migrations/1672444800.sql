@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS enrichment_timed_out UInt8 DEFAULT 0 AFTER tier
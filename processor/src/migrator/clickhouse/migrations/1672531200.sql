@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS scan_suspected UInt8 DEFAULT 0 AFTER enrichment_timed_out
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS src_asset String DEFAULT '' AFTER scan_suspected, ADD COLUMN IF NOT EXISTS dst_asset String DEFAULT '' AFTER src_asset
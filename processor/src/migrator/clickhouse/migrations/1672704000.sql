@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS id String DEFAULT '' AFTER dst_asset
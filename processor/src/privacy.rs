@@ -0,0 +1,311 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+use crate::pb::FlowMessage;
+use crate::settings::RedactionSettings;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactionError(String);
+
+impl fmt::Display for RedactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid redaction: {}", self.0)
+    }
+}
+
+impl std::error::Error for RedactionError {}
+
+/// How the value of a redacted field is replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactionMethod {
+    /// Address keyed with the deployment secret, the same address always maps to the same one
+    Hash,
+    /// Address with bits after the prefix cleared, e.g. `10.1.2.3` becomes `10.1.2.0` with 24
+    Truncate(u8),
+    Zero,
+}
+
+impl RedactionMethod {
+    fn parse(field: &str, method: &str) -> Result<Self, RedactionError> {
+        let invalid = || RedactionError(format!("{field}={method}"));
+        match method.split_once('/') {
+            None if method == "hash" => Ok(RedactionMethod::Hash),
+            None if method == "truncate" => Ok(RedactionMethod::Truncate(24)),
+            None if method == "zero" => Ok(RedactionMethod::Zero),
+            Some(("truncate", prefix_len)) => match prefix_len.parse::<u8>() {
+                Ok(prefix_len) if prefix_len <= 32 => Ok(RedactionMethod::Truncate(prefix_len)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Flow fields which may be redacted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RedactedField {
+    Ipv4SrcAddr,
+    Ipv4DstAddr,
+    InBytes,
+    OutBytes,
+    InPkts,
+    OutPkts,
+}
+
+impl RedactedField {
+    fn parse(field: &str) -> Option<Self> {
+        match field {
+            "ipv4_src_addr" => Some(RedactedField::Ipv4SrcAddr),
+            "ipv4_dst_addr" => Some(RedactedField::Ipv4DstAddr),
+            "in_bytes" => Some(RedactedField::InBytes),
+            "out_bytes" => Some(RedactedField::OutBytes),
+            "in_pkts" => Some(RedactedField::InPkts),
+            "out_pkts" => Some(RedactedField::OutPkts),
+            _ => None,
+        }
+    }
+
+    fn is_address(&self) -> bool {
+        matches!(
+            self,
+            RedactedField::Ipv4SrcAddr | RedactedField::Ipv4DstAddr
+        )
+    }
+}
+
+/// Redaction applied to flows before they are stored or aggregated, nothing is redacted by default.
+/// Flows on kafka topics, including retry ones, are kept intact, so retried flows are redacted once
+#[derive(Clone, Default)]
+pub struct Redaction {
+    fields: Vec<(RedactedField, RedactionMethod)>,
+    hash_key: Vec<u8>,
+}
+
+impl fmt::Debug for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the key must not end up in logs
+        f.debug_struct("Redaction")
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
+
+impl TryFrom<&RedactionSettings> for Redaction {
+    type Error = RedactionError;
+
+    fn try_from(settings: &RedactionSettings) -> Result<Self, Self::Error> {
+        let mut fields = settings
+            .fields
+            .iter()
+            .map(|(name, method)| {
+                let field = RedactedField::parse(name)
+                    .ok_or_else(|| RedactionError(format!("unknown field {name}")))?;
+                let method = RedactionMethod::parse(name, method.trim())?;
+                if !field.is_address() && method != RedactionMethod::Zero {
+                    return Err(RedactionError(format!("{name} can only be zeroed")));
+                }
+                Ok((field, method))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        fields.sort_by_key(|(field, _)| *field as u8);
+
+        let hash_key = settings.hash_key.clone().unwrap_or_default().into_bytes();
+        if hash_key.is_empty() && fields.iter().any(|(_, m)| *m == RedactionMethod::Hash) {
+            return Err(RedactionError("hashing requires a hash key".to_owned()));
+        }
+
+        Ok(Self { fields, hash_key })
+    }
+}
+
+impl Redaction {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Redacted copy of the flow, the flow itself when nothing is redacted
+    pub fn apply<'a>(&self, f: &'a FlowMessageWithMetadata) -> Cow<'a, FlowMessageWithMetadata> {
+        if self.is_empty() {
+            return Cow::Borrowed(f);
+        }
+
+        let mut redacted = f.clone();
+        for (field, method) in &self.fields {
+            self.redact(&mut redacted.flow_message, *field, *method);
+        }
+        Cow::Owned(redacted)
+    }
+
+    fn redact(&self, flow: &mut FlowMessage, field: RedactedField, method: RedactionMethod) {
+        match field {
+            RedactedField::Ipv4SrcAddr => {
+                flow.ipv4_src_addr = self.redact_address(&flow.ipv4_src_addr, method)
+            }
+            RedactedField::Ipv4DstAddr => {
+                flow.ipv4_dst_addr = self.redact_address(&flow.ipv4_dst_addr, method)
+            }
+            RedactedField::InBytes => flow.in_bytes = 0,
+            RedactedField::OutBytes => flow.out_bytes = 0,
+            RedactedField::InPkts => flow.in_pkts = 0,
+            RedactedField::OutPkts => flow.out_pkts = 0,
+        }
+    }
+
+    /// Malformed addresses are hashed as they are, or cleared, so they never leak
+    fn redact_address(&self, addr: &str, method: RedactionMethod) -> String {
+        match (method, addr.parse::<Ipv4Addr>()) {
+            (RedactionMethod::Hash, _) => self.hash_address(addr).to_string(),
+            (RedactionMethod::Truncate(prefix_len), Ok(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - prefix_len as u32)
+                    .unwrap_or_default();
+                Ipv4Addr::from(u32::from(ip) & mask).to_string()
+            }
+            (RedactionMethod::Truncate(_), Err(_)) | (RedactionMethod::Zero, _) => {
+                Ipv4Addr::UNSPECIFIED.to_string()
+            }
+        }
+    }
+
+    /// First four bytes of HMAC-SHA256 of the address, so it is still stored as an IPv4
+    fn hash_address(&self, addr: &str) -> Ipv4Addr {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.hash_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(addr.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        Ipv4Addr::new(digest[0], digest[1], digest[2], digest[3])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use std::collections::HashMap;
+
+    fn redaction(
+        fields: &[(&str, &str)],
+        hash_key: Option<&str>,
+    ) -> Result<Redaction, RedactionError> {
+        let settings = RedactionSettings {
+            fields: fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<String, String>>(),
+            hash_key: hash_key.map(str::to_owned),
+        };
+        Redaction::try_from(&settings)
+    }
+
+    #[test]
+    fn test_nothing_is_redacted_by_default() {
+        let f = testutil::flow().with_src("192.168.17.34").build();
+        assert!(matches!(Redaction::default().apply(&f), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_addresses_are_truncated() {
+        let f = testutil::flow()
+            .with_src("192.168.17.34")
+            .with_dst("10.0.0.1")
+            .build();
+        let redaction = redaction(
+            &[
+                ("ipv4_src_addr", "truncate"),
+                ("ipv4_dst_addr", "truncate/8"),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let redacted = redaction.apply(&f);
+        assert_eq!(redacted.flow_message.ipv4_src_addr, "192.168.17.0");
+        assert_eq!(redacted.flow_message.ipv4_dst_addr, "10.0.0.0");
+        // the original flow is kept for retries
+        assert_eq!(f.flow_message.ipv4_src_addr, "192.168.17.34");
+
+        let everything = redaction(&[("ipv4_src_addr", "truncate/0")], None).unwrap();
+        assert_eq!(everything.apply(&f).flow_message.ipv4_src_addr, "0.0.0.0");
+    }
+
+    #[test]
+    fn test_sizes_are_zeroed() {
+        let redaction = redaction(
+            &[
+                ("in_bytes", "zero"),
+                ("out_bytes", "zero"),
+                ("in_pkts", "zero"),
+                ("out_pkts", "zero"),
+                ("ipv4_dst_addr", "zero"),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let f = testutil::flow()
+            .with_src("192.168.17.34")
+            .with_dst("10.0.0.1")
+            .with_traffic((100, 200), (3, 4))
+            .build();
+        let redacted = redaction.apply(&f);
+        let m = &redacted.flow_message;
+        assert_eq!(
+            (m.in_bytes, m.out_bytes, m.in_pkts, m.out_pkts),
+            (0, 0, 0, 0)
+        );
+        assert_eq!(m.ipv4_dst_addr, "0.0.0.0");
+        assert_eq!(m.ipv4_src_addr, "192.168.17.34");
+    }
+
+    #[test]
+    fn test_hashing_is_keyed_and_deterministic() {
+        let f = testutil::flow().with_src("192.168.17.34").build();
+        let hashed = |key| {
+            redaction(&[("ipv4_src_addr", "hash")], Some(key))
+                .unwrap()
+                .apply(&f)
+                .flow_message
+                .ipv4_src_addr
+                .clone()
+        };
+
+        let first = hashed("secret");
+        assert_ne!(first, "192.168.17.34");
+        assert!(first.parse::<Ipv4Addr>().is_ok());
+        assert_eq!(first, hashed("secret"));
+        assert_ne!(first, hashed("other-secret"));
+
+        // different addresses map to different ones
+        let redaction = redaction(
+            &[("ipv4_src_addr", "hash"), ("ipv4_dst_addr", "hash")],
+            Some("secret"),
+        )
+        .unwrap();
+        let redacted = redaction.apply(&f);
+        assert_eq!(redacted.flow_message.ipv4_src_addr, first);
+        assert_ne!(redacted.flow_message.ipv4_dst_addr, first);
+    }
+
+    #[test]
+    fn test_invalid_redaction_is_rejected() {
+        assert_eq!(
+            redaction(&[("protocol", "zero")], None).unwrap_err(),
+            RedactionError("unknown field protocol".to_owned())
+        );
+        assert_eq!(
+            redaction(&[("in_bytes", "hash")], Some("secret")).unwrap_err(),
+            RedactionError("in_bytes can only be zeroed".to_owned())
+        );
+        assert!(redaction(&[("ipv4_src_addr", "truncate/33")], None).is_err());
+        assert!(redaction(&[("ipv4_src_addr", "scramble")], None).is_err());
+        assert_eq!(
+            redaction(&[("ipv4_src_addr", "hash")], None).unwrap_err(),
+            RedactionError("hashing requires a hash key".to_owned())
+        );
+    }
+}
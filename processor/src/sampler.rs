@@ -0,0 +1,242 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+use crate::privacy::Redaction;
+use crate::settings::SamplerSettings;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Serialize;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Flow kept by the sampler, only for inspection
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SampledFlow {
+    pub host: String,
+    pub timestamp: u64,
+    pub ipv4_src_addr: String,
+    pub ipv4_dst_addr: String,
+    pub l4_src_port: u32,
+    pub l4_dst_port: u32,
+    pub protocol: u32,
+    pub l7_proto: f32,
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+    pub in_pkts: u64,
+    pub out_pkts: u64,
+    pub tcp_flags: u32,
+    pub flow_duration_milliseconds: u64,
+}
+
+impl From<&FlowMessageWithMetadata> for SampledFlow {
+    fn from(f: &FlowMessageWithMetadata) -> Self {
+        let flow = &f.flow_message;
+        Self {
+            host: f.metadata.host.clone(),
+            timestamp: f.metadata.timestamp,
+            ipv4_src_addr: flow.ipv4_src_addr.clone(),
+            ipv4_dst_addr: flow.ipv4_dst_addr.clone(),
+            l4_src_port: flow.l4_src_port,
+            l4_dst_port: flow.l4_dst_port,
+            protocol: flow.protocol,
+            l7_proto: flow.l7_proto,
+            in_bytes: flow.in_bytes,
+            out_bytes: flow.out_bytes,
+            in_pkts: flow.in_pkts,
+            out_pkts: flow.out_pkts,
+            tcp_flags: flow.tcp_flags,
+            flow_duration_milliseconds: flow.flow_duration_milliseconds,
+        }
+    }
+}
+
+/// Uniform sample of flows seen since `started`
+struct Reservoir {
+    started: Instant,
+    seen: u64,
+    items: Vec<(Instant, SampledFlow)>,
+}
+
+impl Reservoir {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            seen: 0,
+            items: Vec::new(),
+        }
+    }
+
+    /// Algorithm R, the flow is redacted and converted only when it makes it into the sample
+    fn offer<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        capacity: usize,
+        now: Instant,
+        flow: &FlowMessageWithMetadata,
+        redaction: &Redaction,
+    ) {
+        self.seen += 1;
+        let sampled = || SampledFlow::from(redaction.apply(flow).as_ref());
+        if self.items.len() < capacity {
+            self.items.push((now, sampled()));
+            return;
+        }
+
+        let slot = rng.gen_range(0..self.seen) as usize;
+        if slot < capacity {
+            self.items[slot] = (now, sampled());
+        }
+    }
+}
+
+struct Windows {
+    current: Reservoir,
+    previous: Reservoir,
+}
+
+/// Keeps a random sample of recently consumed flows for diagnostics.
+/// Reservoirs of two consecutive windows are kept, the sample is drawn from their flows
+/// which are still within the window, so it approximates a sliding window
+pub struct FlowSampler {
+    size: usize,
+    window: Duration,
+    windows: Mutex<Windows>,
+    redaction: Redaction,
+}
+
+impl FlowSampler {
+    pub fn new(settings: &SamplerSettings) -> Self {
+        let now = Instant::now();
+        Self {
+            size: settings.size,
+            window: Duration::from_secs(settings.window_secs),
+            windows: Mutex::new(Windows {
+                current: Reservoir::new(now),
+                previous: Reservoir::new(now),
+            }),
+            redaction: Redaction::default(),
+        }
+    }
+
+    /// Sampled flows are served over http, so they are redacted like stored ones
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Never waits, the flow is skipped if the sample is being read or updated
+    pub fn offer(&self, flow: &FlowMessageWithMetadata) {
+        self.offer_at(&mut rand::thread_rng(), flow, Instant::now())
+    }
+
+    fn offer_at<R: Rng>(&self, rng: &mut R, flow: &FlowMessageWithMetadata, now: Instant) {
+        if self.size == 0 {
+            return;
+        }
+        let mut windows = match self.windows.try_lock() {
+            Ok(w) => w,
+            Err(_) => return,
+        };
+
+        if now.duration_since(windows.current.started) >= self.window {
+            windows.previous = std::mem::replace(&mut windows.current, Reservoir::new(now));
+        }
+        windows
+            .current
+            .offer(rng, self.size, now, flow, &self.redaction);
+    }
+
+    /// Current sample, newest flows first
+    pub fn sample(&self) -> Vec<SampledFlow> {
+        self.sample_at(&mut rand::thread_rng(), Instant::now())
+            .into_iter()
+            .map(|(_, flow)| flow)
+            .collect()
+    }
+
+    fn sample_at<R: Rng>(&self, rng: &mut R, now: Instant) -> Vec<(Instant, SampledFlow)> {
+        let windows = self.windows.lock().unwrap();
+        let recent = windows
+            .current
+            .items
+            .iter()
+            .chain(windows.previous.items.iter())
+            .filter(|(t, _)| now.duration_since(*t) <= self.window)
+            .collect::<Vec<&(Instant, SampledFlow)>>();
+
+        let mut sample = recent
+            .choose_multiple(rng, self.size)
+            .map(|item| (*item).clone())
+            .collect::<Vec<(Instant, SampledFlow)>>();
+        sample.sort_by(|a, b| b.0.cmp(&a.0));
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn sampler(size: usize, window_secs: u64) -> FlowSampler {
+        FlowSampler::new(&SamplerSettings { size, window_secs })
+    }
+
+    #[test]
+    fn test_sample_is_bounded_and_uniform() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let now = Instant::now();
+
+        // count how often each of the first 10 flows ends up in a sample of 10 out of 100
+        let mut hits = [0u32; 100];
+        for _ in 0..2000 {
+            let sampler = sampler(10, 60);
+            for port in 0..100 {
+                let f = testutil::flow().with_src_port(port).build();
+                sampler.offer_at(&mut rng, &f, now);
+            }
+            let sample = sampler.sample_at(&mut rng, now);
+            assert_eq!(sample.len(), 10);
+            for (_, f) in sample {
+                hits[f.l4_src_port as usize] += 1;
+            }
+        }
+
+        // every flow is expected in 10% of samples
+        assert!(hits.iter().all(|h| (120..=280).contains(h)), "{:?}", hits);
+    }
+
+    #[test]
+    fn test_old_flows_leave_the_sample() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let sampler = sampler(10, 60);
+        let start = Instant::now();
+
+        for port in 0..5 {
+            let f = testutil::flow().with_src_port(port).build();
+            sampler.offer_at(&mut rng, &f, start);
+        }
+        let later = start + Duration::from_secs(90);
+        for port in 5..8 {
+            let f = testutil::flow().with_src_port(port).build();
+            sampler.offer_at(&mut rng, &f, later);
+        }
+
+        let ports = sampler
+            .sample_at(&mut rng, later)
+            .into_iter()
+            .map(|(_, f)| f.l4_src_port)
+            .collect::<Vec<u32>>();
+        assert_eq!(ports.len(), 3);
+        assert!(ports.iter().all(|p| *p >= 5));
+    }
+
+    #[test]
+    fn test_disabled_sampler() {
+        let sampler = sampler(0, 60);
+        sampler.offer(&testutil::flow().with_src_port(1).build());
+        assert!(sampler.sample().is_empty());
+    }
+}
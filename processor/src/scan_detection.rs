@@ -0,0 +1,393 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+use crate::metrics::SCAN_DETECTIONS;
+use crate::privacy::Redaction;
+use crate::settings::ScanDetectionSettings;
+use crate::webhook::{self, FlaggedFlowWebhook};
+
+use log::warn;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Threshold a flow pushed over within the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detection {
+    /// source reached more distinct destination addresses than allowed
+    HorizontalScan { distinct_destinations: u64 },
+    /// destination port received more flows than allowed
+    PortFlood { flows: u64 },
+}
+
+impl Detection {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::HorizontalScan { .. } => "horizontal_scan",
+            Self::PortFlood { .. } => "port_flood",
+        }
+    }
+
+    fn count(&self) -> u64 {
+        match self {
+            Self::HorizontalScan {
+                distinct_destinations,
+            } => *distinct_destinations,
+            Self::PortFlood { flows } => *flows,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    /// distinct destinations of sources not yet detected as scanning, a source is dropped once
+    /// it crosses the threshold so none of them holds more than the threshold plus one
+    destinations: HashMap<String, HashSet<String>>,
+    flows_per_port: HashMap<u32, u64>,
+    scanning: HashSet<String>,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            destinations: HashMap::new(),
+            flows_per_port: HashMap::new(),
+            scanning: HashSet::new(),
+        }
+    }
+
+    fn tracked_sources(&self) -> usize {
+        self.destinations.len() + self.scanning.len()
+    }
+}
+
+/// Counts distinct destinations per source and flows per destination port in consecutive
+/// windows. Flows of a source or to a port over its threshold are marked as `scan_suspected`
+/// for the rest of the window, and the flow crossing it is reported. Disabled by default
+pub struct ScanDetection {
+    settings: ScanDetectionSettings,
+    redaction: Redaction,
+    webhook: Option<Arc<FlaggedFlowWebhook>>,
+    window: Mutex<Window>,
+}
+
+impl Default for ScanDetection {
+    fn default() -> Self {
+        Self::new(ScanDetectionSettings::default(), Redaction::default())
+    }
+}
+
+impl ScanDetection {
+    /// Alerts are redacted like stored flows
+    pub fn new(settings: ScanDetectionSettings, redaction: Redaction) -> Self {
+        Self {
+            settings,
+            redaction,
+            webhook: None,
+            window: Mutex::new(Window::new(Instant::now())),
+        }
+    }
+
+    /// Detections are queued to the flagged flow webhook, sharing its rate limit
+    pub fn with_webhook(mut self, webhook: Option<Arc<FlaggedFlowWebhook>>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.max_distinct_destinations > 0 || self.settings.max_flows_per_dst_port > 0
+    }
+
+    /// Marks the flow when it is suspected and reports thresholds it crossed
+    pub fn apply(&self, f: &mut FlowMessageWithMetadata) {
+        if !self.is_enabled() {
+            return;
+        }
+        for detection in self.observe(f, Instant::now()) {
+            self.report(f, &detection);
+        }
+    }
+
+    fn window_len(&self) -> Duration {
+        Duration::from_secs(self.settings.window_secs.max(1))
+    }
+
+    fn observe(&self, f: &mut FlowMessageWithMetadata, now: Instant) -> Vec<Detection> {
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.started) >= self.window_len() {
+            *window = Window::new(now);
+        }
+
+        let mut detections = Vec::new();
+        let flow = &f.flow_message;
+        let max_destinations = self.settings.max_distinct_destinations;
+        if max_destinations > 0 && !flow.ipv4_src_addr.is_empty() {
+            if window.scanning.contains(&flow.ipv4_src_addr) {
+                f.metadata.scan_suspected = true;
+            } else if window.destinations.contains_key(&flow.ipv4_src_addr)
+                || window.tracked_sources() < self.settings.max_tracked_sources
+            {
+                let destinations = window
+                    .destinations
+                    .entry(flow.ipv4_src_addr.clone())
+                    .or_default();
+                destinations.insert(flow.ipv4_dst_addr.clone());
+                let distinct_destinations = destinations.len() as u64;
+                // once detected the source is only remembered, not its destinations
+                if distinct_destinations > max_destinations {
+                    window.destinations.remove(&flow.ipv4_src_addr);
+                    window.scanning.insert(flow.ipv4_src_addr.clone());
+                    f.metadata.scan_suspected = true;
+                    detections.push(Detection::HorizontalScan {
+                        distinct_destinations,
+                    });
+                }
+            }
+        }
+
+        let max_flows = self.settings.max_flows_per_dst_port;
+        if max_flows > 0 {
+            let flows = window
+                .flows_per_port
+                .entry(f.flow_message.l4_dst_port)
+                .or_default();
+            *flows += 1;
+            if *flows > max_flows {
+                f.metadata.scan_suspected = true;
+            }
+            if *flows == max_flows + 1 {
+                detections.push(Detection::PortFlood { flows: *flows });
+            }
+        }
+        detections
+    }
+
+    fn report(&self, f: &FlowMessageWithMetadata, detection: &Detection) {
+        SCAN_DETECTIONS.with_label_values(&[detection.kind()]).inc();
+        let flow = self.redaction.apply(f);
+        warn!(
+            "{} suspected from {} to port {}: {} within {}s",
+            detection.kind(),
+            flow.flow_message.ipv4_src_addr,
+            flow.flow_message.l4_dst_port,
+            detection.count(),
+            self.window_len().as_secs()
+        );
+
+        if let Some(queue) = &self.webhook {
+            queue.alert(json!({
+                "alert": "scan_suspected",
+                "kind": detection.kind(),
+                "count": detection.count(),
+                "window_secs": self.window_len().as_secs(),
+                "flow": webhook::notification(&flow),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::WebhookSettings;
+    use crate::testutil;
+
+    fn detection(max_distinct_destinations: u64, max_flows_per_dst_port: u64) -> ScanDetection {
+        ScanDetection::new(
+            ScanDetectionSettings {
+                window_secs: 10,
+                max_distinct_destinations,
+                max_flows_per_dst_port,
+                ..Default::default()
+            },
+            Redaction::default(),
+        )
+    }
+
+    #[test]
+    fn test_source_reaching_many_destinations_is_suspected() {
+        let detection = detection(3, 0);
+        let start = detection.window.lock().unwrap().started;
+
+        // repeated destinations are counted once
+        for dst in ["10.0.0.1", "10.0.0.2", "10.0.0.2", "10.0.0.3"] {
+            let mut f = testutil::flow()
+                .with_src("10.1.1.1")
+                .with_dst(dst)
+                .with_dst_port(22)
+                .build();
+            assert!(detection.observe(&mut f, start).is_empty());
+            assert!(!f.metadata.scan_suspected);
+        }
+
+        let mut f = testutil::flow()
+            .with_src("10.1.1.1")
+            .with_dst("10.0.0.4")
+            .with_dst_port(22)
+            .build();
+        assert_eq!(
+            detection.observe(&mut f, start),
+            vec![Detection::HorizontalScan {
+                distinct_destinations: 4
+            }]
+        );
+        assert!(f.metadata.scan_suspected);
+
+        // marked for the rest of the window, reported once
+        let mut f = testutil::flow()
+            .with_src("10.1.1.1")
+            .with_dst("10.0.0.1")
+            .with_dst_port(22)
+            .build();
+        assert!(detection.observe(&mut f, start).is_empty());
+        assert!(f.metadata.scan_suspected);
+        let mut other = testutil::flow()
+            .with_src("10.1.1.2")
+            .with_dst("10.0.0.5")
+            .with_dst_port(22)
+            .build();
+        detection.observe(&mut other, start);
+        assert!(!other.metadata.scan_suspected);
+
+        let mut f = testutil::flow()
+            .with_src("10.1.1.1")
+            .with_dst("10.0.0.1")
+            .with_dst_port(22)
+            .build();
+        detection.observe(&mut f, start + Duration::from_secs(10));
+        assert!(!f.metadata.scan_suspected);
+    }
+
+    #[test]
+    fn test_flows_over_port_threshold_are_suspected() {
+        let detection = detection(0, 2);
+        let start = detection.window.lock().unwrap().started;
+
+        let suspected = (0..4)
+            .map(|i| {
+                let mut f = testutil::flow()
+                    .with_src(&format!("10.1.1.{}", i))
+                    .with_dst("10.0.0.1")
+                    .with_dst_port(3389)
+                    .build();
+                let detections = detection.observe(&mut f, start);
+                (f.metadata.scan_suspected, detections.len())
+            })
+            .collect::<Vec<(bool, usize)>>();
+        assert_eq!(
+            suspected,
+            vec![(false, 0), (false, 0), (true, 1), (true, 0)]
+        );
+
+        let mut f = testutil::flow()
+            .with_src("10.1.1.1")
+            .with_dst("10.0.0.1")
+            .with_dst_port(443)
+            .build();
+        detection.observe(&mut f, start);
+        assert!(!f.metadata.scan_suspected);
+    }
+
+    #[test]
+    fn test_sources_beyond_the_cap_are_not_tracked() {
+        let detection = ScanDetection::new(
+            ScanDetectionSettings {
+                window_secs: 10,
+                max_distinct_destinations: 1,
+                max_tracked_sources: 2,
+                ..Default::default()
+            },
+            Redaction::default(),
+        );
+        let start = detection.window.lock().unwrap().started;
+
+        for src in ["10.1.1.1", "10.1.1.2", "10.1.1.3"] {
+            detection.observe(
+                &mut testutil::flow()
+                    .with_src(src)
+                    .with_dst("10.0.0.1")
+                    .with_dst_port(22)
+                    .build(),
+                start,
+            );
+        }
+        assert_eq!(detection.window.lock().unwrap().tracked_sources(), 2);
+
+        // tracked sources keep being counted, a scanner keeps its slot
+        let mut f = testutil::flow()
+            .with_src("10.1.1.1")
+            .with_dst("10.0.0.2")
+            .with_dst_port(22)
+            .build();
+        assert_eq!(detection.observe(&mut f, start).len(), 1);
+        let mut f = testutil::flow()
+            .with_src("10.1.1.3")
+            .with_dst("10.0.0.2")
+            .with_dst_port(22)
+            .build();
+        assert!(detection.observe(&mut f, start).is_empty());
+        assert!(!f.metadata.scan_suspected);
+
+        let mut f = testutil::flow()
+            .with_src("10.1.1.3")
+            .with_dst("10.0.0.1")
+            .with_dst_port(22)
+            .build();
+        detection.observe(&mut f, start + Duration::from_secs(10));
+        let mut f = testutil::flow()
+            .with_src("10.1.1.3")
+            .with_dst("10.0.0.2")
+            .with_dst_port(22)
+            .build();
+        assert_eq!(
+            detection
+                .observe(&mut f, start + Duration::from_secs(10))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_detections_are_queued_to_the_webhook() {
+        let settings = WebhookSettings {
+            url: Some("http://127.0.0.1:9/alerts".to_owned()),
+            queue_size: 1,
+            ..Default::default()
+        };
+        let (webhook, mut queue) = FlaggedFlowWebhook::queued(&settings, Redaction::default());
+        let detection = detection(0, 1).with_webhook(Some(Arc::new(webhook)));
+
+        for port in [22, 22, 3389, 3389] {
+            detection.apply(
+                &mut testutil::flow()
+                    .with_src("10.1.1.1")
+                    .with_dst("10.0.0.1")
+                    .with_dst_port(port)
+                    .build(),
+            );
+        }
+
+        // the second detection does not fit the queue and is dropped
+        let alert = queue.try_recv().unwrap();
+        assert_eq!(alert["alert"], "scan_suspected");
+        assert_eq!(alert["kind"], "port_flood");
+        assert_eq!(alert["count"], 2);
+        assert_eq!(alert["flow"]["l4_dst_port"], 22);
+        assert!(queue.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_nothing_is_suspected_by_default() {
+        let detection = ScanDetection::default();
+        assert!(!detection.is_enabled());
+
+        let mut f = testutil::flow()
+            .with_src("10.1.1.1")
+            .with_dst("10.0.0.1")
+            .with_dst_port(22)
+            .build();
+        detection.apply(&mut f);
+        assert!(!f.metadata.scan_suspected);
+    }
+}
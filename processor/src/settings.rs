@@ -1,9 +1,23 @@
-use crate::actors::storage::clickhouse::ClickhouseSettings;
-use serde::Deserialize;
+use crate::actors::aggregation::AggregationKey;
+use crate::actors::event_stream::kafka::decode_format::DecodeFormat;
+use crate::actors::event_stream::kafka::retrier::RetryTier;
+use crate::actors::event_stream::kafka::AutoOffsetReset;
+use crate::actors::storage::clickhouse::{ClickhouseSettings, TableSettings};
+use crate::actors::storage::coalesce::{default_coalesce_key, CoalesceField};
+use crate::actors::storage::consts::STORAGE_MAX_BUFFER_SIZE;
+use crate::actors::storage::routing;
+use crate::actors::storage::sampling::KeepRule;
+use common::host_normalization::HostNormalizationSettings;
+use common::log_sampling::DEFAULT_DEBUG_LOG_SAMPLE_RATE;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct ProcessorSettings {
-    pub kafka_topic: String,
+    /// Topics flows are consumed from, `KREWETKA__KAFKA_TOPIC` is still accepted
+    #[serde(alias = "kafka_topic", deserialize_with = "deserialize_topics")]
+    pub kafka_topics: Vec<String>,
     pub kafka_brokers: String,
     // pub clickhouse_user: String,
     // pub clickhouse_password: String,
@@ -12,9 +26,779 @@ pub struct ProcessorSettings {
     pub clickhouse_settings: ClickhouseSettings,
     pub grpc_classification_port: u16,
     pub grpc_classification_host: String,
+    #[serde(default)]
+    pub replay_settings: ReplaySettings,
+    #[serde(default)]
+    pub storage_settings: StorageSettings,
+    #[serde(default)]
+    pub payload_dump_settings: PayloadDumpSettings,
+    #[serde(default)]
+    pub aggregation_settings: AggregationSettings,
+    #[serde(default)]
+    pub consumer_settings: ConsumerSettings,
+    #[serde(default)]
+    pub sampler_settings: SamplerSettings,
+    #[serde(default)]
+    pub retry_settings: RetrySettings,
+    #[serde(default)]
+    pub redaction: RedactionSettings,
+    #[serde(default)]
+    pub enrichment: EnrichmentSettings,
+    #[serde(default)]
+    pub webhook: WebhookSettings,
+    #[serde(default)]
+    pub clock_skew: ClockSkewSettings,
+    #[serde(default)]
+    pub dead_letter_cap: DeadLetterCapSettings,
+    #[serde(default)]
+    pub host_normalization: HostNormalizationSettings,
+    #[serde(default)]
+    pub scan_detection: ScanDetectionSettings,
+    #[serde(default)]
+    pub suspicious_stream: SuspiciousStreamSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    /// Comma separated host identifiers, addresses and networks flows are accepted from,
+    /// everything is accepted when empty
+    #[serde(default)]
+    pub allowed_sources: String,
+    /// Only 1 in this many messages is logged by per message debug logs
+    #[serde(default = "default_debug_log_sample_rate")]
+    pub debug_log_sample_rate: u64,
+}
+
+fn default_debug_log_sample_rate() -> u64 {
+    DEFAULT_DEBUG_LOG_SAMPLE_RATE
+}
+
+/// Webhook notified about stored flows flagged by the classifier, disabled by default
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub url: Option<String>,
+    /// Notifications posted per second, the rest waits in the queue
+    pub max_per_sec: u32,
+    /// Notifications waiting to be posted, new ones are dropped when it is full
+    pub queue_size: usize,
+    /// Lowest suspicion score of a posted flow, a point for the `malicious` verdict and a point
+    /// for scan detection
+    pub min_score: u8,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            max_per_sec: 10,
+            queue_size: 1000,
+            min_score: 1,
+        }
+    }
+}
+
+/// What happens to flows timestamped further in the future than the allowed skew
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FutureTimestamps {
+    /// stored as they are
+    #[default]
+    Keep,
+    /// timestamp is replaced with the ingest time
+    Clamp,
+    /// moved to the dead letter topic
+    Reject,
+}
+
+/// Handling of flows from devices with clocks running ahead, they are kept by default
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ClockSkewSettings {
+    /// How far ahead of the processor clock a flow timestamp is accepted
+    pub max_clock_skew_ms: u64,
+    pub future_timestamps: FutureTimestamps,
+}
+
+impl Default for ClockSkewSettings {
+    fn default() -> Self {
+        Self {
+            max_clock_skew_ms: 60_000,
+            future_timestamps: FutureTimestamps::Keep,
+        }
+    }
+}
+
+/// How metric names and labels are written to StatsD
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// labels are sent as tags
+    #[default]
+    Dogstatsd,
+    /// label values are appended to the metric name, for agents without tags
+    Statsd,
+}
+
+/// Metrics pushed to a StatsD or DogStatsD agent besides being served at `/metrics`,
+/// not pushed by default
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// `host:port` of the agent, metrics are sent over UDP
+    pub statsd_addr: Option<String>,
+    pub statsd_prefix: String,
+    pub statsd_format: StatsdFormat,
+    pub statsd_flush_interval_ms: u64,
+    /// Percentage of counter updates sent, the agent scales the sent ones back up
+    pub statsd_counter_sample_percent: u64,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            statsd_addr: None,
+            statsd_prefix: "krewetka.processor".to_owned(),
+            statsd_format: StatsdFormat::Dogstatsd,
+            statsd_flush_interval_ms: 10_000,
+            statsd_counter_sample_percent: 100,
+        }
+    }
+}
+
+/// What happens to consumption once the dead letter cap is exceeded
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterPolicy {
+    /// consumption goes on, the breach is only reported
+    #[default]
+    FailOpen,
+    /// consumption stops until the processor is restarted
+    FailClosed,
+}
+
+/// Cap on how many consumed messages may end up in the dead letter topic, disabled by default
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeadLetterCapSettings {
+    /// Dead lettered messages per second averaged over the window, 0 is unbounded
+    pub max_per_sec: u64,
+    /// Dead lettered messages as a percentage of consumed ones within the window, 0 is unbounded
+    pub max_percent: u64,
+    pub window_secs: u64,
+    pub policy: DeadLetterPolicy,
+    /// Url the breach is posted to, besides being logged
+    pub alert_url: Option<String>,
+}
+
+impl Default for DeadLetterCapSettings {
+    fn default() -> Self {
+        Self {
+            max_per_sec: 0,
+            max_percent: 0,
+            window_secs: 60,
+            policy: DeadLetterPolicy::FailOpen,
+            alert_url: None,
+        }
+    }
+}
+
+/// Thresholds of scan detection on classified flows, disabled by default
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ScanDetectionSettings {
+    /// Length of windows both thresholds are counted in
+    pub window_secs: u64,
+    /// Distinct destination addresses a source may reach within the window, 0 is unbounded
+    pub max_distinct_destinations: u64,
+    /// Flows a destination port may receive within the window, 0 is unbounded
+    pub max_flows_per_dst_port: u64,
+    /// Sources whose destinations are counted within the window, later ones wait for the next
+    pub max_tracked_sources: usize,
+}
+
+impl Default for ScanDetectionSettings {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            max_distinct_destinations: 0,
+            max_flows_per_dst_port: 0,
+            max_tracked_sources: 100_000,
+        }
+    }
+}
+
+/// Topic classified flows scoring at least `min_score` are published to, disabled by default
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SuspiciousStreamSettings {
+    pub topic: Option<String>,
+    /// Signals a flow has to be flagged by, out of the classifier verdict and scan detection
+    pub min_score: u8,
+}
+
+impl Default for SuspiciousStreamSettings {
+    fn default() -> Self {
+        Self {
+            topic: None,
+            min_score: 1,
+        }
+    }
+}
+
+/// Enrichment stages run on classified flows before they are stored, none by default
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct EnrichmentSettings {
+    /// Comma separated stage names in the order they run, `<name>=off` skips a stage
+    pub stages: String,
+    /// Time all stages of a flow may take together, the flow is stored with the stages which
+    /// finished in time once it runs out. 0 is unbounded
+    pub budget_ms: u64,
+    /// Lookups of the `assets` stage
+    pub assets: AssetSettings,
+}
+
+/// Owners of addresses looked up in redis by the `assets` enrichment stage
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AssetSettings {
+    /// Required by the stage, e.g. `redis://assets:6379/0`
+    pub redis_url: Option<String>,
+    /// Key of an address, `{ip}` is replaced with it
+    pub key_pattern: String,
+    /// Addresses whose owners are kept in memory, the least recently used are evicted first
+    pub cache_size: usize,
+    /// How long a cached owner, or the lack of one, is used before it is looked up again
+    pub cache_ttl_secs: u64,
+    /// Time a single lookup may take, the owner is left empty once it runs out
+    pub timeout_ms: u64,
+}
+
+impl Default for AssetSettings {
+    fn default() -> Self {
+        Self {
+            redis_url: None,
+            key_pattern: "asset:{ip}".to_owned(),
+            cache_size: 10000,
+            cache_ttl_secs: 300,
+            timeout_ms: 20,
+        }
+    }
+}
+
+/// Privacy redaction of flows before they are stored or aggregated, nothing is redacted by default
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RedactionSettings {
+    /// Field name to `hash`, `truncate[/<prefix-len>]` or `zero`
+    pub fields: HashMap<String, String>,
+    /// Secret addresses are hashed with, same within a deployment
+    pub hash_key: Option<String>,
+}
+
+/// Tiers a flow which failed to be processed goes through before it is dead lettered
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Delays of the tiers, each one with its own topic, failures are dead lettered without them
+    #[serde(deserialize_with = "deserialize_retry_tiers")]
+    pub tiers: Vec<RetryTier>,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            tiers: ["5s", "30s"]
+                .iter()
+                .map(|t| t.parse().expect("default retry tiers are valid"))
+                .collect(),
+        }
+    }
+}
+
+/// Settings of the diagnostic sample of consumed flows
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SamplerSettings {
+    /// Number of flows in the sample, sampling is disabled with 0
+    pub size: usize,
+    /// Only flows consumed within this window are sampled
+    pub window_secs: u64,
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            size: 100,
+            window_secs: 60,
+        }
+    }
+}
+
+/// Settings of the consumer reading flows topic
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConsumerSettings {
+    pub auto_offset_reset: AutoOffsetReset,
+    /// When disabled, offsets of acknowledged messages are committed in batches
+    pub enable_auto_commit: bool,
+    /// Longest time acknowledged offsets wait to be committed, without auto commit
+    pub commit_interval_ms: u64,
+    /// Acknowledged messages which trigger a commit before the interval ends, without auto commit
+    pub commit_batch_size: usize,
+    /// How often consumer lag is estimated from broker watermarks
+    pub lag_poll_interval_secs: u64,
+    /// Schema registry used to decode avro payloads, they are dead lettered when unset
+    pub schema_registry_url: Option<String>,
+    pub decode_format: DecodeFormat,
+    /// Messages prefetched per partition, librdkafka `queued.min.messages`
+    #[serde(deserialize_with = "deserialize_in_range::<_, 1, 10_000_000>")]
+    pub max_poll_records: Option<u64>,
+    /// Bytes fetched from a broker in a single request, librdkafka `fetch.max.bytes`
+    #[serde(deserialize_with = "deserialize_in_range::<_, 0, 2_147_483_135>")]
+    pub fetch_max_bytes: Option<u64>,
+    /// Upper bound of the prefetched messages queue, librdkafka `queued.max.messages.kbytes`
+    #[serde(deserialize_with = "deserialize_in_range::<_, 1, 2_097_151>")]
+    pub queued_max_messages_kbytes: Option<u64>,
+}
+
+impl ConsumerSettings {
+    /// Fetch settings passed to librdkafka, unset ones keep librdkafka defaults
+    pub fn fetch_overrides(&self) -> Vec<(&'static str, String)> {
+        [
+            ("queued.min.messages", self.max_poll_records),
+            ("fetch.max.bytes", self.fetch_max_bytes),
+            (
+                "queued.max.messages.kbytes",
+                self.queued_max_messages_kbytes,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|v| (key, v.to_string())))
+        .collect()
+    }
+}
+
+/// Optional number within `MIN..=MAX`, so librdkafka settings out of range fail at load
+/// rather than when a consumer is created
+fn deserialize_in_range<'de, D: Deserializer<'de>, const MIN: u64, const MAX: u64>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(v) if !(MIN..=MAX).contains(&v) => Err(D::Error::custom(format!(
+            "{v} is out of range {MIN}..={MAX}"
+        ))),
+        v => Ok(v),
+    }
+}
+
+impl Default for ConsumerSettings {
+    fn default() -> Self {
+        Self {
+            auto_offset_reset: AutoOffsetReset::Latest,
+            enable_auto_commit: true,
+            commit_interval_ms: 5000,
+            commit_batch_size: 1000,
+            lag_poll_interval_secs: 15,
+            schema_registry_url: None,
+            decode_format: DecodeFormat::Protobuf,
+            max_poll_records: None,
+            fetch_max_bytes: None,
+            queued_max_messages_kbytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AggregationSettings {
+    pub enabled: bool,
+    pub window_secs: u64,
+    /// Flows of a window already saved are still aggregated this long after the window ended
+    pub allowed_lateness_ms: u64,
+    pub key: AggregationKey,
+    pub table: String,
+    pub destination: RollupDestination,
+    /// Topic rollups are published to with `kafka` or `both` destination
+    pub kafka_topic: String,
+}
+
+impl Default for AggregationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: 60,
+            allowed_lateness_ms: 10_000,
+            key: AggregationKey::Host,
+            table: "flow_rollups".to_owned(),
+            destination: RollupDestination::Clickhouse,
+            kafka_topic: "flow_rollups".to_owned(),
+        }
+    }
+}
+
+/// Where rollups are saved
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RollupDestination {
+    Clickhouse,
+    Kafka,
+    Both,
+}
+
+impl RollupDestination {
+    pub fn to_clickhouse(&self) -> bool {
+        matches!(self, Self::Clickhouse | Self::Both)
+    }
+
+    pub fn to_kafka(&self) -> bool {
+        matches!(self, Self::Kafka | Self::Both)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PayloadDumpSettings {
+    /// Directory where undecodable payloads are written, dumping is disabled when unset
+    pub dir: Option<String>,
+    pub max_files: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for PayloadDumpSettings {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_files: 100,
+            max_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct StorageSettings {
+    /// Upper bound of a random delay added to every flush interval and used as initial delay,
+    /// so flushes of instances started at the same time do not align
+    pub flush_jitter_ms: u64,
+    /// Flows of a partition are classified one at a time and inserted in the order they were
+    /// consumed, a batch at a time by a single worker
+    pub preserve_order: bool,
+    /// Number of workers taking batches from the storage channel and inserting them in parallel
+    pub storage_workers: usize,
+    /// Number of inserts running at once across all workers, 0 leaves it to the number of workers
+    pub max_inflight_inserts: usize,
+    /// Estimated size of waiting rows which triggers a flush before the interval ends, 0 disables it
+    pub flush_max_bytes: usize,
+    /// Number of waiting rows which triggers a flush before the interval ends
+    pub flush_batch_size: usize,
+    /// Age of the oldest waiting row which triggers a flush before the interval ends, 0 disables it
+    pub max_batch_age_ms: u64,
+    /// Updates of the same flow within a batch are merged into a single row
+    pub coalesce_flows: bool,
+    /// Fields identifying updates of the same flow
+    #[serde(deserialize_with = "deserialize_coalesce_key")]
+    pub coalesce_key: Vec<CoalesceField>,
+    /// Only 1 in this many classified flows is stored, besides always kept ones
+    pub sample_rate: u64,
+    /// Flows scoring at least this much are stored whatever the sample rate, 0 disables it
+    pub always_keep_min_score: u8,
+    /// Flows matching any of them are stored whatever the sample rate
+    #[serde(deserialize_with = "routing::deserialize_rules")]
+    pub always_keep_rules: Vec<KeepRule>,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            flush_jitter_ms: 0,
+            preserve_order: false,
+            storage_workers: 1,
+            max_inflight_inserts: 0,
+            flush_max_bytes: 0,
+            flush_batch_size: STORAGE_MAX_BUFFER_SIZE,
+            max_batch_age_ms: 0,
+            coalesce_flows: false,
+            coalesce_key: default_coalesce_key(),
+            sample_rate: 1,
+            always_keep_min_score: 1,
+            always_keep_rules: Vec::new(),
+        }
+    }
+}
+
+impl StorageSettings {
+    /// Parallel inserts reorder batches, so they can not be configured along with `preserve_order`
+    pub fn check_ordering(&self) -> Result<(), String> {
+        if !self.preserve_order {
+            return Ok(());
+        }
+        if self.storage_workers > 1 {
+            return Err(format!(
+                "preserve_order requires a single storage worker, {} are configured",
+                self.storage_workers
+            ));
+        }
+        if self.max_inflight_inserts > 1 {
+            return Err(format!(
+                "preserve_order allows a single insert at once, max_inflight_inserts is {}",
+                self.max_inflight_inserts
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReplaySettings {
+    pub rate_per_sec: u32,
+    pub max_attempts: usize,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self {
+            rate_per_sec: 100,
+            max_attempts: 3,
+        }
+    }
+}
+
+/// Accepts a single topic, comma separated topics or a list of them
+fn deserialize_topics<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Topics {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let topics = match Topics::deserialize(deserializer)? {
+        Topics::One(s) => s.split(',').map(str::to_owned).collect(),
+        Topics::Many(topics) => topics,
+    }
+    .into_iter()
+    .map(|t| t.trim().to_owned())
+    .filter(|t| !t.is_empty())
+    .collect::<Vec<String>>();
+
+    if topics.is_empty() {
+        return Err(D::Error::custom("at least one kafka topic is required"));
+    }
+    Ok(topics)
+}
+
+/// Comma separated delays, e.g. `5s,30s`
+fn deserialize_retry_tiers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<RetryTier>, D::Error> {
+    String::deserialize(deserializer)?
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| t.parse().map_err(D::Error::custom))
+        .collect()
+}
+
+/// Comma separated fields, e.g. `host,src_addr,dst_addr`
+fn deserialize_coalesce_key<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<CoalesceField>, D::Error> {
+    let key = String::deserialize(deserializer)?
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(|f| {
+            CoalesceField::parse(f)
+                .ok_or_else(|| D::Error::custom(format!("unknown coalesce key field {}", f)))
+        })
+        .collect::<Result<Vec<CoalesceField>, D::Error>>()?;
+
+    if key.is_empty() {
+        return Err(D::Error::custom(
+            "at least one coalesce key field is required",
+        ));
+    }
+    Ok(key)
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct BackfillSettings {
+    #[serde(alias = "kafka_topic", deserialize_with = "deserialize_topics")]
+    pub kafka_topics: Vec<String>,
+    pub kafka_brokers: String,
+    pub clickhouse_settings: ClickhouseSettings,
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct MigratorSettings {
     pub clickhouse_settings: ClickhouseSettings,
 }
+
+/// Read by `--print-ddl`, which needs neither kafka nor a running clickhouse
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+pub struct DdlSettings {
+    #[serde(default)]
+    pub clickhouse_settings: TableSettings,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct TopicsOnly {
+        #[serde(alias = "kafka_topic", deserialize_with = "deserialize_topics")]
+        kafka_topics: Vec<String>,
+    }
+
+    fn topics(json: serde_json::Value) -> Result<Vec<String>, serde_json::Error> {
+        serde_json::from_value::<TopicsOnly>(json).map(|t| t.kafka_topics)
+    }
+
+    #[test]
+    fn test_single_topic_is_accepted() {
+        assert_eq!(
+            topics(serde_json::json!({ "kafka_topic": "flows" })).unwrap(),
+            vec!["flows"]
+        );
+    }
+
+    #[test]
+    fn test_multiple_topics() {
+        assert_eq!(
+            topics(serde_json::json!({ "kafka_topics": "flows, flows-lab," })).unwrap(),
+            vec!["flows", "flows-lab"]
+        );
+        assert_eq!(
+            topics(serde_json::json!({ "kafka_topics": ["flows", "flows-lab"] })).unwrap(),
+            vec!["flows", "flows-lab"]
+        );
+    }
+
+    #[test]
+    fn test_empty_topics_are_rejected() {
+        assert!(topics(serde_json::json!({ "kafka_topics": "" })).is_err());
+        assert!(topics(serde_json::json!({ "kafka_topics": [] })).is_err());
+    }
+
+    #[test]
+    fn test_retry_tiers() {
+        let settings =
+            serde_json::from_value::<RetrySettings>(serde_json::json!({ "tiers": "10s, 5m" }))
+                .unwrap();
+        assert_eq!(
+            settings
+                .tiers
+                .iter()
+                .map(|t| t.topic("flows"))
+                .collect::<Vec<String>>(),
+            vec!["flows.retry.10s", "flows.retry.5m"]
+        );
+
+        let settings =
+            serde_json::from_value::<RetrySettings>(serde_json::json!({ "tiers": "" })).unwrap();
+        assert!(settings.tiers.is_empty());
+
+        assert!(
+            serde_json::from_value::<RetrySettings>(serde_json::json!({ "tiers": "5x" })).is_err()
+        );
+    }
+
+    #[test]
+    fn test_coalesce_key() {
+        let settings = serde_json::from_value::<StorageSettings>(serde_json::json!({
+            "coalesce_flows": true,
+            "coalesce_key": "host, src_addr,dst_addr"
+        }))
+        .unwrap();
+        assert!(settings.coalesce_flows);
+        assert_eq!(
+            settings.coalesce_key,
+            vec![
+                CoalesceField::Host,
+                CoalesceField::SrcAddr,
+                CoalesceField::DstAddr
+            ]
+        );
+        assert_eq!(
+            StorageSettings::default().coalesce_key,
+            default_coalesce_key()
+        );
+
+        for key in ["", "src_addr,mac"] {
+            assert!(serde_json::from_value::<StorageSettings>(
+                serde_json::json!({ "coalesce_key": key })
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_preserve_order_rejects_parallel_inserts() {
+        let ordered = StorageSettings {
+            preserve_order: true,
+            ..Default::default()
+        };
+        assert_eq!(ordered.check_ordering(), Ok(()));
+        assert_eq!(
+            StorageSettings {
+                max_inflight_inserts: 1,
+                ..ordered.clone()
+            }
+            .check_ordering(),
+            Ok(())
+        );
+
+        assert!(StorageSettings {
+            storage_workers: 4,
+            ..ordered.clone()
+        }
+        .check_ordering()
+        .is_err());
+        assert!(StorageSettings {
+            max_inflight_inserts: 2,
+            ..ordered
+        }
+        .check_ordering()
+        .is_err());
+
+        // parallel inserts are up to the other settings without it
+        assert_eq!(
+            StorageSettings {
+                storage_workers: 4,
+                ..Default::default()
+            }
+            .check_ordering(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_fetch_overrides_keep_librdkafka_defaults() {
+        assert_eq!(ConsumerSettings::default().fetch_overrides(), vec![]);
+
+        let settings = serde_json::from_value::<ConsumerSettings>(serde_json::json!({
+            "max_poll_records": 500,
+            "queued_max_messages_kbytes": 16384
+        }))
+        .unwrap();
+        assert_eq!(
+            settings.fetch_overrides(),
+            vec![
+                ("queued.min.messages", "500".to_owned()),
+                ("queued.max.messages.kbytes", "16384".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch_settings_out_of_range_are_rejected() {
+        let settings =
+            |json| serde_json::from_value::<ConsumerSettings>(json).map(|s| s.fetch_overrides());
+        assert!(settings(serde_json::json!({ "max_poll_records": 0 })).is_err());
+        assert!(settings(serde_json::json!({ "fetch_max_bytes": 2_147_483_136u64 })).is_err());
+        assert!(settings(serde_json::json!({ "queued_max_messages_kbytes": 2_097_152 })).is_err());
+        assert!(settings(serde_json::json!({ "fetch_max_bytes": 0 })).is_ok());
+    }
+}
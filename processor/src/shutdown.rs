@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use rdkafka::error::KafkaResult;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+use tokio::time::{timeout, Duration};
+
+/// How long the consumer loop may take to hand over the message it is processing
+pub const STOP_CONSUMER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long consumed flows may take to be classified and saved, or moved to a retry topic
+pub const DRAIN_STORAGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long storage workers may take to finish their inserts
+pub const FLUSH_STORAGE_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long kafka may take to confirm the final commit
+pub const COMMIT_OFFSETS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a single step of the shutdown, `None` when it did not finish in time
+pub async fn stage<F: Future>(name: &str, limit: Duration, fut: F) -> Option<F::Output> {
+    info!("shutdown: {}...", name);
+    match timeout(limit, fut).await {
+        Ok(output) => {
+            info!("shutdown: {} done", name);
+            Some(output)
+        }
+        Err(_) => {
+            warn!("shutdown: {} timed out after {:?}", name, limit);
+            None
+        }
+    }
+}
+
+/// Consumer side of the pipeline as far as its shutdown is concerned
+#[async_trait]
+pub trait Consumption: Send + Sync {
+    /// Stops consuming new messages, waits until the message being processed is handed over
+    async fn stop(&self);
+    /// Consumed messages which were not acknowledged yet
+    fn in_flight(&self) -> usize;
+    /// Waits until every consumed message is saved or moved to a retry topic
+    async fn wait_until_acked(&self);
+    /// Commits offsets of acknowledged messages
+    fn commit(&self) -> KafkaResult<()>;
+}
+
+/// Flag raised once, e.g. to tell a loop to stop
+#[derive(Debug, Default)]
+pub struct Signal {
+    raised: AtomicBool,
+    notify: Notify,
+}
+
+impl Signal {
+    pub fn raise(&self) {
+        self.raised.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait(&self) {
+        loop {
+            // register before checking the flag, so raising in between is not missed
+            let notified = self.notify.notified();
+            if self.is_raised() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_signal_wakes_up_waiters() {
+        let signal = Arc::new(Signal::default());
+        let waiter = tokio::spawn({
+            let signal = signal.clone();
+            async move { signal.wait().await }
+        });
+        assert!(stage("waiting", Duration::from_millis(20), signal.wait())
+            .await
+            .is_none());
+
+        signal.raise();
+        assert!(signal.is_raised());
+        timeout(Duration::from_millis(100), waiter)
+            .await
+            .expect("raised signal should wake up waiters")
+            .unwrap();
+        assert_eq!(
+            stage("raised", Duration::from_millis(20), signal.wait()).await,
+            Some(())
+        );
+    }
+
+    /// Source of `stage` in a file, up to the end of the function
+    fn stage_source(file: &str) -> &str {
+        let start = file
+            .find("/// Runs a single step of the shutdown")
+            .expect("stage is missing");
+        let end = file[start..].find("\n}\n").expect("stage is not closed");
+        &file[start..start + end]
+    }
+
+    /// Collector and processor are built on their own, each has a copy of `stage`
+    #[test]
+    fn test_stage_is_the_same_as_in_collector() {
+        assert_eq!(
+            stage_source(include_str!("shutdown.rs")),
+            stage_source(include_str!("../../collector/src/shutdown.rs"))
+        );
+    }
+}
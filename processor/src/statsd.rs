@@ -0,0 +1,279 @@
+use crate::settings::{MetricsSettings, StatsdFormat};
+
+use log::{error, info};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::HashMap;
+use tokio::net::UdpSocket;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+/// Lines are packed into datagrams of at most this size, so they are not fragmented
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+/// Pushes the metrics served at `/metrics` to a StatsD agent, so teams without Prometheus
+/// see the same instrumentation. Counters and histogram counts are sent as increments since
+/// the previous push, gauges as they are
+pub struct StatsdPusher {
+    addr: String,
+    prefix: String,
+    format: StatsdFormat,
+    flush_interval: Duration,
+    /// fraction of counter updates sent
+    sample_rate: f64,
+    /// counter values sent last time by metric line
+    previous: HashMap<String, f64>,
+}
+
+impl StatsdPusher {
+    /// `None` when no agent is configured
+    pub fn new(settings: &MetricsSettings) -> Option<Self> {
+        Some(Self {
+            addr: settings.statsd_addr.clone()?,
+            prefix: settings.statsd_prefix.trim_end_matches('.').to_owned(),
+            format: settings.statsd_format,
+            flush_interval: Duration::from_millis(settings.statsd_flush_interval_ms.max(100)),
+            sample_rate: settings.statsd_counter_sample_percent.clamp(1, 100) as f64 / 100.0,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Pushes until the process exits, a failed push is logged and the next one is attempted
+    pub async fn run(mut self) {
+        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("unable to open statsd socket: {}", e);
+                return;
+            }
+        };
+        info!(
+            "pushing metrics to statsd at {} every {:?}",
+            self.addr, self.flush_interval
+        );
+
+        let sample_rate = self.sample_rate;
+        let mut flush = interval(self.flush_interval);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            flush.tick().await;
+            let lines = self.render(&prometheus::gather(), || {
+                rand::random::<f64>() < sample_rate
+            });
+            for datagram in pack(&lines) {
+                if let Err(e) = socket.send_to(datagram.as_bytes(), &self.addr).await {
+                    error!("unable to push metrics to statsd at {}: {}", self.addr, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn name(&self, family: &str, suffix: &str, labels: &[(&str, &str)]) -> String {
+        let mut name = format!("{}.{}{}", self.prefix, family, suffix);
+        if self.format == StatsdFormat::Statsd {
+            for (_, value) in labels {
+                name.push('.');
+                name.push_str(&sanitize(value));
+            }
+        }
+        name
+    }
+
+    fn tags(&self, labels: &[(&str, &str)]) -> String {
+        if self.format != StatsdFormat::Dogstatsd || labels.is_empty() {
+            return String::new();
+        }
+        let tags = labels
+            .iter()
+            .map(|(k, v)| format!("{}:{}", k, sanitize(v)))
+            .collect::<Vec<String>>();
+        format!("|#{}", tags.join(","))
+    }
+
+    /// Increment since the previous push, the whole value once the counter was reset
+    fn increment(&mut self, key: String, value: f64) -> f64 {
+        let previous = self.previous.insert(key, value).unwrap_or(0.0);
+        if value >= previous {
+            value - previous
+        } else {
+            value
+        }
+    }
+
+    fn counter(
+        &mut self,
+        name: String,
+        tags: &str,
+        value: f64,
+        sampled: &mut impl FnMut() -> bool,
+    ) -> Option<String> {
+        let increment = self.increment(format!("{}{}", name, tags), value);
+        if increment <= 0.0 {
+            return None;
+        }
+        if self.sample_rate >= 1.0 {
+            return Some(format!("{}:{}|c{}", name, increment, tags));
+        }
+        // an increment which is not sent is made up for by the agent scaling up sent ones
+        sampled().then(|| format!("{}:{}|c|@{}{}", name, increment, self.sample_rate, tags))
+    }
+
+    /// StatsD lines of all metric families, `sampled` decides whether a counter update is sent
+    fn render(
+        &mut self,
+        families: &[MetricFamily],
+        mut sampled: impl FnMut() -> bool,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            for metric in family.get_metric() {
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name(), l.get_value()))
+                    .collect::<Vec<(&str, &str)>>();
+                let tags = self.tags(&labels);
+                let name = |suffix: &str| self.name(family.get_name(), suffix, &labels);
+
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let name = name("");
+                        let value = metric.get_counter().get_value();
+                        lines.extend(self.counter(name, &tags, value, &mut sampled));
+                    }
+                    MetricType::GAUGE => lines.push(format!(
+                        "{}:{}|g{}",
+                        name(""),
+                        metric.get_gauge().get_value(),
+                        tags
+                    )),
+                    MetricType::HISTOGRAM => {
+                        let (count, sum) = (name(".count"), name(".sum"));
+                        let histogram = metric.get_histogram();
+                        let count_value = histogram.get_sample_count() as f64;
+                        lines.extend(self.counter(count, &tags, count_value, &mut sampled));
+                        let sum_value = histogram.get_sample_sum();
+                        lines.extend(self.counter(sum, &tags, sum_value, &mut sampled));
+                    }
+                    // nothing registers them
+                    MetricType::SUMMARY | MetricType::UNTYPED => {}
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Characters with a meaning in the StatsD protocol are replaced
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' | '\n' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Joins lines with newlines into as few datagrams as fit
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(d) if d.len() + 1 + line.len() <= MAX_DATAGRAM_BYTES => {
+                d.push('\n');
+                d.push_str(line);
+            }
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Counter, CounterVec, Gauge, Histogram, HistogramOpts, Opts, Registry};
+
+    fn pusher(format: StatsdFormat, sample_percent: u64) -> StatsdPusher {
+        StatsdPusher::new(&MetricsSettings {
+            statsd_addr: Some("127.0.0.1:8125".to_owned()),
+            statsd_format: format,
+            statsd_counter_sample_percent: sample_percent,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn registry() -> (Registry, CounterVec, Gauge, Histogram) {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("rows_total", "rows"), &["outcome"]).unwrap();
+        let gauge = Gauge::new("inflight", "inflight").unwrap();
+        let histogram = Histogram::with_opts(HistogramOpts::new("wait_seconds", "wait")).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        (registry, counter, gauge, histogram)
+    }
+
+    #[test]
+    fn test_counters_are_sent_as_increments() {
+        let (registry, counter, gauge, histogram) = registry();
+        let mut pusher = pusher(StatsdFormat::Dogstatsd, 100);
+
+        counter.with_label_values(&["sent"]).inc_by(5.0);
+        gauge.set(3.0);
+        histogram.observe(0.5);
+        assert_eq!(
+            pusher.render(&registry.gather(), || true),
+            vec![
+                "krewetka.processor.inflight:3|g",
+                "krewetka.processor.rows_total:5|c|#outcome:sent",
+                "krewetka.processor.wait_seconds.count:1|c",
+                "krewetka.processor.wait_seconds.sum:0.5|c",
+            ]
+        );
+
+        // counters which did not move are not sent
+        counter.with_label_values(&["sent"]).inc_by(2.0);
+        assert_eq!(
+            pusher.render(&registry.gather(), || true),
+            vec![
+                "krewetka.processor.inflight:3|g",
+                "krewetka.processor.rows_total:2|c|#outcome:sent",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plain_statsd_names_carry_label_values() {
+        let (registry, counter, _, _) = registry();
+        let mut pusher = pusher(StatsdFormat::Statsd, 100);
+
+        counter.with_label_values(&["a:b"]).inc();
+        let lines = pusher.render(&registry.gather(), || true);
+        assert!(lines.contains(&"krewetka.processor.rows_total.a_b:1|c".to_owned()));
+    }
+
+    #[test]
+    fn test_counters_are_sampled() {
+        let registry = Registry::new();
+        let counter = Counter::new("flows_total", "flows").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let mut pusher = pusher(StatsdFormat::Dogstatsd, 10);
+
+        counter.inc_by(10.0);
+        assert!(pusher.render(&registry.gather(), || false).is_empty());
+        counter.inc_by(10.0);
+        assert_eq!(
+            pusher.render(&registry.gather(), || true),
+            vec!["krewetka.processor.flows_total:10|c|@0.1"]
+        );
+    }
+
+    #[test]
+    fn test_lines_are_packed_into_datagrams() {
+        let line = "x".repeat(700);
+        let datagrams = pack(&[line.clone(), line.clone(), line.clone()]);
+        assert_eq!(datagrams, vec![format!("{}\n{}", line, line), line]);
+    }
+}
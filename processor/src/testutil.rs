@@ -0,0 +1,310 @@
+//! Helpers for tests, flow fixtures and checks against a real kafka.
+//! Built only for tests and with the `testutil` feature. The ordering check is used by the
+//! storage integration tests of the processor, the collector is a separate crate and does not
+//! depend on it
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use rdkafka::consumer::Consumer;
+use rdkafka::message::{BorrowedMessage, FromBytes, Headers, Message};
+use tokio::time::timeout;
+
+use crate::actors::event_stream::kafka::get_consumer_with_group;
+use crate::actors::messages::{FlowMessageMetadata, FlowMessageWithMetadata};
+use crate::pb::FlowMessage;
+
+/// Metadata of a flow consumed from offset 0 of partition 0 of the `flows` topic,
+/// tests override the fields they check with `..metadata()`
+pub fn metadata() -> FlowMessageMetadata {
+    FlowMessageMetadata {
+        host: "host".to_owned(),
+        id: "id".to_owned(),
+        topic: Some("flows".to_owned()),
+        offset: Some(0),
+        partition: Some(0),
+        ..Default::default()
+    }
+}
+
+/// Builder of an unclassified flow with the default [`metadata`], tests set only the fields
+/// they check
+pub fn flow() -> FlowBuilder {
+    FlowBuilder(FlowMessageWithMetadata {
+        flow_message: FlowMessage::default(),
+        malicious: None,
+        metadata: metadata(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowBuilder(FlowMessageWithMetadata);
+
+impl FlowBuilder {
+    /// Replaces the whole flow message, for fields without a setter of their own
+    pub fn with_message(mut self, flow_message: FlowMessage) -> Self {
+        self.0.flow_message = flow_message;
+        self
+    }
+
+    pub fn with_malicious(mut self, malicious: Option<bool>) -> Self {
+        self.0.malicious = malicious;
+        self
+    }
+
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.0.metadata.host = host.to_owned();
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.0.metadata.timestamp = timestamp;
+        self
+    }
+
+    /// Sets the offset and an id unique to it, like flows consumed one after another
+    pub fn with_offset(mut self, offset: i64) -> Self {
+        self.0.metadata.offset = Some(offset);
+        self.0.metadata.id = offset.to_string();
+        self
+    }
+
+    pub fn with_topic(mut self, topic: &str) -> Self {
+        self.0.metadata.topic = Some(topic.to_owned());
+        self
+    }
+
+    pub fn with_partition(mut self, partition: i32) -> Self {
+        self.0.metadata.partition = Some(partition);
+        self
+    }
+
+    pub fn with_scan_suspected(mut self, scan_suspected: bool) -> Self {
+        self.0.metadata.scan_suspected = scan_suspected;
+        self
+    }
+
+    pub fn with_src(mut self, ipv4_src_addr: &str) -> Self {
+        self.0.flow_message.ipv4_src_addr = ipv4_src_addr.to_owned();
+        self
+    }
+
+    pub fn with_dst(mut self, ipv4_dst_addr: &str) -> Self {
+        self.0.flow_message.ipv4_dst_addr = ipv4_dst_addr.to_owned();
+        self
+    }
+
+    pub fn with_src_port(mut self, l4_src_port: u32) -> Self {
+        self.0.flow_message.l4_src_port = l4_src_port;
+        self
+    }
+
+    pub fn with_dst_port(mut self, l4_dst_port: u32) -> Self {
+        self.0.flow_message.l4_dst_port = l4_dst_port;
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: u32) -> Self {
+        self.0.flow_message.protocol = protocol;
+        self
+    }
+
+    pub fn with_l7_proto(mut self, l7_proto: f32) -> Self {
+        self.0.flow_message.l7_proto = l7_proto;
+        self
+    }
+
+    /// Sets incoming and outgoing bytes and packets
+    pub fn with_traffic(mut self, bytes: (u64, u64), pkts: (u64, u64)) -> Self {
+        (self.0.flow_message.in_bytes, self.0.flow_message.out_bytes) = bytes;
+        (self.0.flow_message.in_pkts, self.0.flow_message.out_pkts) = pkts;
+        self
+    }
+
+    pub fn build(self) -> FlowMessageWithMetadata {
+        self.0
+    }
+}
+
+/// Header carrying the position of a message in the order it was sent, set by test producers
+pub const SEQUENCE_HEADER: &str = "sequence-x";
+
+/// Message of a key consumed out of the order it was sent in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reordering {
+    pub key: Vec<u8>,
+    pub partition: i32,
+    pub offset: i64,
+    pub sequence: u64,
+    /// sequence of the message of the same key stored before it
+    pub previous_sequence: u64,
+}
+
+/// Messages of a key which ended up in more than a single partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitKey {
+    pub key: Vec<u8>,
+    pub partitions: (i32, i32),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OrderingReport {
+    pub checked: usize,
+    pub reorderings: Vec<Reordering>,
+    pub split_keys: Vec<SplitKey>,
+}
+
+impl OrderingReport {
+    pub fn is_ordered(&self) -> bool {
+        self.reorderings.is_empty() && self.split_keys.is_empty()
+    }
+}
+
+impl fmt::Display for OrderingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "checked {} messages", self.checked)?;
+        for r in &self.reorderings {
+            write!(
+                f,
+                "\n  key {:?}: sequence {} at {}/{} is stored after sequence {}",
+                String::from_utf8_lossy(&r.key),
+                r.sequence,
+                r.partition,
+                r.offset,
+                r.previous_sequence
+            )?;
+        }
+        for s in &self.split_keys {
+            write!(
+                f,
+                "\n  key {:?}: split between partitions {} and {}",
+                String::from_utf8_lossy(&s.key),
+                s.partitions.0,
+                s.partitions.1
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that, per key, offsets grow with the order messages were sent in.
+/// Messages of a partition have to be recorded in offset order, like a consumer delivers them
+#[derive(Debug, Default)]
+pub struct OrderingCheck {
+    /// partition and sequence of the last message of every key
+    last: HashMap<Vec<u8>, (i32, u64)>,
+    report: OrderingReport,
+}
+
+impl OrderingCheck {
+    pub fn record(&mut self, key: &[u8], partition: i32, offset: i64, sequence: u64) {
+        self.report.checked += 1;
+        match self.last.get(key) {
+            Some(&(last_partition, _)) if last_partition != partition => {
+                self.report.split_keys.push(SplitKey {
+                    key: key.to_vec(),
+                    partitions: (last_partition, partition),
+                });
+                return;
+            }
+            Some(&(_, previous_sequence)) if previous_sequence > sequence => {
+                self.report.reorderings.push(Reordering {
+                    key: key.to_vec(),
+                    partition,
+                    offset,
+                    sequence,
+                    previous_sequence,
+                });
+                return;
+            }
+            _ => {}
+        }
+        self.last.insert(key.to_vec(), (partition, sequence));
+    }
+
+    pub fn report(self) -> OrderingReport {
+        self.report
+    }
+}
+
+fn sequence(msg: &BorrowedMessage) -> Option<u64> {
+    let headers = msg.headers()?;
+    let (_, value) = (0..headers.count())
+        .filter_map(|idx| headers.get(idx))
+        .find(|(name, _)| *name == SEQUENCE_HEADER)?;
+    str::from_bytes(value).ok()?.parse::<u64>().ok()
+}
+
+/// Consumes `expected` messages of the topic from the beginning with a fresh consumer group and
+/// checks their order per key against their `sequence-x` header. Panics when messages without
+/// a key or a sequence show up or they do not arrive within `wait`
+pub async fn verify_partition_ordering(
+    brokers: &str,
+    topic: &str,
+    group_id: &str,
+    expected: usize,
+    wait: Duration,
+) -> OrderingReport {
+    let consumer = get_consumer_with_group(brokers, group_id);
+    consumer.subscribe(&[topic]).unwrap();
+
+    let mut check = OrderingCheck::default();
+    for _ in 0..expected {
+        let msg = timeout(wait, consumer.recv())
+            .await
+            .expect("timed out waiting for messages")
+            .unwrap();
+        let key = msg.key().expect("message without a key");
+        let sequence = sequence(&msg).expect("message without a sequence header");
+        check.record(key, msg.partition(), msg.offset(), sequence);
+    }
+    check.report()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordered_keys_pass() {
+        let mut check = OrderingCheck::default();
+        check.record(b"a", 0, 0, 0);
+        check.record(b"b", 1, 0, 1);
+        check.record(b"a", 0, 1, 2);
+        check.record(b"b", 1, 1, 3);
+
+        let report = check.report();
+        assert!(report.is_ordered(), "{}", report);
+        assert_eq!(report.checked, 4);
+    }
+
+    #[test]
+    fn test_reordering_and_split_keys_are_reported() {
+        let mut check = OrderingCheck::default();
+        check.record(b"a", 0, 0, 1);
+        check.record(b"a", 0, 1, 0);
+        check.record(b"a", 0, 2, 2);
+        check.record(b"b", 0, 3, 3);
+        check.record(b"b", 1, 0, 4);
+
+        let report = check.report();
+        assert!(!report.is_ordered());
+        assert_eq!(
+            report.reorderings,
+            vec![Reordering {
+                key: b"a".to_vec(),
+                partition: 0,
+                offset: 1,
+                sequence: 0,
+                previous_sequence: 1,
+            }]
+        );
+        assert_eq!(
+            report.split_keys,
+            vec![SplitKey {
+                key: b"b".to_vec(),
+                partitions: (0, 1),
+            }]
+        );
+    }
+}
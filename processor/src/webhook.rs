@@ -0,0 +1,238 @@
+use crate::actors::messages::FlowMessageWithMetadata;
+use crate::actors::suspicious_stream::suspicion_score;
+use crate::metrics::WEBHOOK_NOTIFICATIONS;
+use crate::privacy::Redaction;
+use crate::settings::WebhookSettings;
+
+use log::{error, info};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Compact description of a flagged flow posted to the webhook, redacted like stored flows
+pub fn notification(flow: &FlowMessageWithMetadata) -> serde_json::Value {
+    let f = &flow.flow_message;
+    json!({
+        "id": flow.metadata.id,
+        "host": flow.metadata.host,
+        "timestamp": flow.metadata.timestamp,
+        "malicious": flow.malicious,
+        "scan_suspected": flow.metadata.scan_suspected,
+        "ipv4_src_addr": f.ipv4_src_addr,
+        "l4_src_port": f.l4_src_port,
+        "ipv4_dst_addr": f.ipv4_dst_addr,
+        "l4_dst_port": f.l4_dst_port,
+        "protocol": f.protocol,
+        "in_bytes": f.in_bytes,
+        "out_bytes": f.out_bytes,
+    })
+}
+
+/// Posts flows scoring at least `min_score` once they are stored, best effort and independent
+/// of storage, notifications which do not fit the queue or fail to be delivered are dropped
+pub struct FlaggedFlowWebhook {
+    tx: mpsc::Sender<serde_json::Value>,
+    redaction: Redaction,
+    min_score: u8,
+}
+
+impl FlaggedFlowWebhook {
+    /// Has to be called within a tokio runtime, `None` when no url is configured
+    pub fn new(settings: &WebhookSettings, redaction: Redaction) -> Option<Self> {
+        let url = settings.url.clone()?;
+        let min_score = settings.min_score.max(1);
+        info!(
+            "flows scoring at least {} are posted to webhook at most {} per second",
+            min_score, settings.max_per_sec
+        );
+
+        let (tx, rx) = mpsc::channel(settings.queue_size.max(1));
+        tokio::spawn(deliver(url, settings.max_per_sec, rx));
+        Some(Self {
+            tx,
+            redaction,
+            min_score,
+        })
+    }
+
+    /// Webhook whose notifications are left in the returned queue rather than posted
+    #[cfg(test)]
+    pub(crate) fn queued(
+        settings: &WebhookSettings,
+        redaction: Redaction,
+    ) -> (Self, mpsc::Receiver<serde_json::Value>) {
+        let (tx, rx) = mpsc::channel(settings.queue_size.max(1));
+        let webhook = Self {
+            tx,
+            redaction,
+            min_score: settings.min_score.max(1),
+        };
+        (webhook, rx)
+    }
+
+    pub fn is_flagged(&self, flow: &FlowMessageWithMetadata) -> bool {
+        suspicion_score(flow) >= self.min_score
+    }
+
+    /// Never waits, the notification is dropped when the queue is full
+    pub fn notify(&self, flow: &FlowMessageWithMetadata) {
+        self.queue(notification(&self.redaction.apply(flow)));
+    }
+
+    /// Queues an alert the caller has already redacted, posted like notifications, within the
+    /// same rate and dropped when the queue is full
+    pub fn alert(&self, alert: serde_json::Value) {
+        self.queue(alert);
+    }
+
+    fn queue(&self, body: serde_json::Value) {
+        if self.tx.try_send(body).is_err() {
+            WEBHOOK_NOTIFICATIONS.with_label_values(&["dropped"]).inc();
+        }
+    }
+}
+
+async fn post(
+    http: &reqwest::Client,
+    url: &str,
+    body: &serde_json::Value,
+) -> reqwest::Result<reqwest::Response> {
+    http.post(url)
+        .header("Content-Type", "application/json")
+        .timeout(WEBHOOK_TIMEOUT)
+        .body(body.to_string())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+}
+
+/// Posts a single alert in the background, has to be called within a tokio runtime
+pub fn alert(url: &str, alert: serde_json::Value) {
+    let url = url.to_owned();
+    tokio::spawn(async move {
+        if let Err(e) = post(&reqwest::Client::new(), &url, &alert).await {
+            error!("unable to post alert to {}: {}", url, e);
+        }
+    });
+}
+
+async fn deliver(url: String, max_per_sec: u32, mut rx: mpsc::Receiver<serde_json::Value>) {
+    let http = reqwest::Client::new();
+    let mut rate = interval(Duration::from_secs(1) / max_per_sec.max(1));
+    rate.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    while let Some(notification) = rx.recv().await {
+        rate.tick().await;
+        match post(&http, &url, &notification).await {
+            Ok(_) => WEBHOOK_NOTIFICATIONS.with_label_values(&["sent"]).inc(),
+            Err(e) => {
+                error!("unable to post flagged flow to webhook: {}", e);
+                WEBHOOK_NOTIFICATIONS.with_label_values(&["failed"]).inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Reads headers and the whole body of a single request
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let len = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..len]);
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let content_length = text[..end]
+                    .lines()
+                    .find_map(|l| {
+                        l.to_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(str::to_owned)
+                    })
+                    .and_then(|l| l.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if len == 0 || request.len() >= end + 4 + content_length {
+                    return text;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flows_scoring_at_least_min_score_are_flagged() {
+        let settings = |min_score| WebhookSettings {
+            url: Some("http://127.0.0.1:9/alerts".to_owned()),
+            min_score,
+            ..Default::default()
+        };
+        let scanned = |malicious, scan_suspected| {
+            testutil::flow()
+                .with_malicious(malicious)
+                .with_scan_suspected(scan_suspected)
+                .build()
+        };
+
+        let webhook = FlaggedFlowWebhook::new(&settings(1), Redaction::default()).unwrap();
+        assert!(webhook.is_flagged(&scanned(Some(true), false)));
+        assert!(webhook.is_flagged(&scanned(Some(false), true)));
+        assert!(!webhook.is_flagged(&scanned(Some(false), false)));
+        assert!(!webhook.is_flagged(&scanned(None, false)));
+
+        let webhook = FlaggedFlowWebhook::new(&settings(2), Redaction::default()).unwrap();
+        assert!(webhook.is_flagged(&scanned(Some(true), true)));
+        assert!(!webhook.is_flagged(&scanned(Some(true), false)));
+        assert!(!webhook.is_flagged(&scanned(None, true)));
+    }
+
+    #[tokio::test]
+    async fn test_zero_min_score_does_not_flag_every_flow() {
+        let settings = WebhookSettings {
+            url: Some("http://127.0.0.1:9/alerts".to_owned()),
+            min_score: 0,
+            ..Default::default()
+        };
+        let webhook = FlaggedFlowWebhook::new(&settings, Redaction::default()).unwrap();
+        let benign = testutil::flow().with_malicious(Some(false)).build();
+        assert!(!webhook.is_flagged(&benign));
+    }
+
+    #[tokio::test]
+    async fn test_flagged_flow_is_posted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let settings = WebhookSettings {
+            url: Some(format!("http://{}/alerts", listener.local_addr().unwrap())),
+            ..Default::default()
+        };
+        let webhook = FlaggedFlowWebhook::new(&settings, Redaction::default()).unwrap();
+        let f = testutil::flow()
+            .with_malicious(Some(true))
+            .with_timestamp(1)
+            .with_src("10.0.0.2")
+            .with_dst("10.0.0.1")
+            .with_dst_port(22)
+            .with_protocol(6)
+            .build();
+        webhook.notify(&f);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_request(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        assert!(request.starts_with("POST /alerts"));
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let posted: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(posted, notification(&f));
+    }
+}
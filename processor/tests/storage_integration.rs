@@ -0,0 +1,441 @@
+//! Integration tests of the storage layer against real Kafka and Clickhouse started with
+//! testcontainers.
+//!
+//! Flows are produced in the same format the collector's kafka exporter uses, consumed and
+//! decoded the way the processor does it and stashed in Clickhouse with migrations applied.
+//! The actors are not started: flows skip classification, they are labeled up front and
+//! stashed directly, so a mismatch between rows and the migrated schema is what gets caught.
+//!
+//! It requires docker, hence it is ignored by default
+//! `cargo test --features integration -- --ignored`
+#![cfg(feature = "integration")]
+
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use clickhouse_rs::types::{Block, Complex};
+use clickhouse_rs::Pool;
+use prost::Message as PBMessage;
+use rdkafka::consumer::Consumer;
+use rdkafka::message::{Message, OwnedHeaders};
+use rdkafka::producer::FutureRecord;
+use testcontainers::core::WaitFor;
+use testcontainers::{clients, Container, GenericImage, RunnableImage};
+use tokio::time::{sleep, timeout};
+
+use processor::actors::event_stream::kafka::{get_consumer_with_group, get_producer};
+use processor::actors::messages::{FlowMessageMetadata, FlowMessageWithMetadata};
+use processor::actors::storage::clickhouse::{ClickhouseSettings, ClickhouseState};
+use processor::actors::storage::readback::ReadbackQuery;
+use processor::actors::storage::schema::SchemaError;
+use processor::actors::storage::storage_actor::AStorage;
+use processor::migrator::clickhouse::ClickhouseMigrations;
+use processor::migrator::migrate::AbstractMigratorSql;
+use processor::pb::FlowMessage;
+use processor::testutil::{self, verify_partition_ordering, SEQUENCE_HEADER};
+
+const TOPIC: &str = "flows";
+// kafka advertises the address clients have to connect to, so the port has to be known upfront
+const KAFKA_HOST_PORT: u16 = 19092;
+const CLICKHOUSE_USER: &str = "krewetka";
+const CLICKHOUSE_PASSWORD: &str = "krewetka";
+
+fn kafka() -> RunnableImage<GenericImage> {
+    let image = GenericImage::new("docker.redpanda.com/vectorized/redpanda", "v22.3.11")
+        .with_wait_for(WaitFor::message_on_stderr("Successfully started Redpanda!"));
+    let advertise_addr = format!("PLAINTEXT://localhost:{}", KAFKA_HOST_PORT);
+    let args = [
+        "redpanda",
+        "start",
+        "--overprovisioned",
+        "--smp",
+        "1",
+        "--memory",
+        "512M",
+        "--node-id",
+        "0",
+        "--check=false",
+        "--kafka-addr",
+        "PLAINTEXT://0.0.0.0:9092",
+        "--advertise-kafka-addr",
+        advertise_addr.as_str(),
+        "--set",
+        "redpanda.auto_create_topics_enabled=true",
+    ]
+    .iter()
+    .map(|a| a.to_string())
+    .collect::<Vec<String>>();
+
+    RunnableImage::from((image, args)).with_mapped_port((KAFKA_HOST_PORT, 9092))
+}
+
+fn clickhouse() -> GenericImage {
+    GenericImage::new("clickhouse/clickhouse-server", "22.6.8.35")
+        .with_env_var("CLICKHOUSE_USER", CLICKHOUSE_USER)
+        .with_env_var("CLICKHOUSE_PASSWORD", CLICKHOUSE_PASSWORD)
+        .with_exposed_port(9000)
+        .with_exposed_port(8123)
+}
+
+fn clickhouse_state(
+    clickhouse: &Container<GenericImage>,
+    settings: serde_json::Value,
+) -> ClickhouseState {
+    let mut base = serde_json::json!({
+        "host": "localhost",
+        "port": clickhouse.get_host_port_ipv4(9000),
+        "http_port": clickhouse.get_host_port_ipv4(8123),
+        "user": CLICKHOUSE_USER,
+        "password": CLICKHOUSE_PASSWORD,
+    });
+    base.as_object_mut()
+        .unwrap()
+        .extend(settings.as_object().unwrap().clone());
+
+    let settings: ClickhouseSettings = serde_json::from_value(base).unwrap();
+    ClickhouseState::new(settings)
+}
+
+async fn wait_for_clickhouse(pool: &Pool) {
+    for _ in 0..30 {
+        if let Ok(Ok(mut client)) = timeout(Duration::from_secs(1), pool.get_handle()).await {
+            if client.ping().await.is_ok() {
+                return;
+            }
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+    panic!("clickhouse did not become ready");
+}
+
+/// Applies schema the same way migrator does
+async fn migrate(clickhouse: &Container<GenericImage>) {
+    let state = clickhouse_state(clickhouse, serde_json::json!({}));
+    wait_for_clickhouse(&state.pool).await;
+
+    let mut migrator = ClickhouseMigrations::new(state);
+    migrator
+        .get_migrations_from_dir(Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/migrator/clickhouse/migrations"
+        )))
+        .expect("no migrations found");
+    migrator.init_migration_info_persistant().await.unwrap();
+    migrator.run_migrations().await.unwrap();
+}
+
+async fn query(state: &ClickhouseState, sql: &str) -> Block<Complex> {
+    let mut client = state.pool.get_handle().await.unwrap();
+    client.query(sql).fetch_all().await.unwrap()
+}
+
+fn flows() -> Vec<(FlowMessage, bool)> {
+    vec![
+        (
+            FlowMessage {
+                out_bytes: 77,
+                out_pkts: 1,
+                in_bytes: 61,
+                in_pkts: 1,
+                ipv4_src_addr: "10.0.0.2".to_owned(),
+                ipv4_dst_addr: "10.0.0.1".to_owned(),
+                l7_proto: 5.0,
+                l4_dst_port: 53,
+                l4_src_port: 56341,
+                flow_duration_milliseconds: 12,
+                protocol: 17,
+                tcp_flags: 0,
+            },
+            false,
+        ),
+        (
+            FlowMessage {
+                out_bytes: 1024,
+                out_pkts: 8,
+                in_bytes: 4096,
+                in_pkts: 16,
+                ipv4_src_addr: "192.168.1.7".to_owned(),
+                ipv4_dst_addr: "8.8.8.8".to_owned(),
+                l7_proto: 7.178,
+                l4_dst_port: 443,
+                l4_src_port: 40112,
+                flow_duration_milliseconds: 1500,
+                protocol: 6,
+                tcp_flags: 27,
+            },
+            true,
+        ),
+    ]
+}
+
+async fn produce_like_collector(brokers: &str, flows: &[(FlowMessage, bool)]) {
+    let producer = get_producer(brokers);
+
+    for (i, (flow, _)) in flows.iter().enumerate() {
+        let mut buffer = Vec::new();
+        flow.encode(&mut buffer).unwrap();
+
+        producer
+            .send(
+                FutureRecord::to(TOPIC)
+                    .payload(&buffer)
+                    .key("KREWETKA-e2e")
+                    .headers(
+                        OwnedHeaders::new()
+                            .add("host-identifier-x", "e2e-host")
+                            .add("message-id-x", &format!("e2e-{}", i))
+                            .add("timestamp-x", &Utc::now().timestamp_millis().to_string())
+                            .add("retry-x", "0"),
+                    ),
+                Duration::from_secs(5),
+            )
+            .await
+            .expect("unable to produce flow");
+    }
+}
+
+async fn consume_like_processor(
+    brokers: &str,
+    flows: &[(FlowMessage, bool)],
+) -> Vec<FlowMessageWithMetadata> {
+    let consumer = get_consumer_with_group(brokers, "krewetka-e2e");
+    consumer.subscribe(&[TOPIC]).unwrap();
+
+    let mut consumed = Vec::with_capacity(flows.len());
+    while consumed.len() < flows.len() {
+        let msg = timeout(Duration::from_secs(30), consumer.recv())
+            .await
+            .expect("timed out waiting for flows")
+            .unwrap();
+
+        let mut metadata = FlowMessageMetadata::try_from(msg.headers().unwrap()).unwrap();
+        metadata.topic = Some(msg.topic().to_owned());
+        metadata.offset = Some(msg.offset());
+        metadata.partition = Some(msg.partition());
+
+        let flow_message = FlowMessage::decode(msg.payload().unwrap()).unwrap();
+        let malicious = flows
+            .iter()
+            .find(|(f, _)| *f == flow_message)
+            .map(|(_, m)| *m);
+
+        consumed.push(FlowMessageWithMetadata {
+            flow_message,
+            malicious,
+            metadata,
+        });
+    }
+
+    consumed
+}
+
+#[tokio::test]
+#[ignore]
+async fn flows_land_in_clickhouse() {
+    let docker = clients::Cli::default();
+    let _kafka = docker.run(kafka());
+    let clickhouse = docker.run(clickhouse());
+    migrate(&clickhouse).await;
+
+    let brokers = format!("localhost:{}", KAFKA_HOST_PORT);
+    let state = clickhouse_state(&clickhouse, serde_json::json!({}));
+    state
+        .verify_schema()
+        .await
+        .expect("migrated schema mismatch");
+
+    let flows = flows();
+    produce_like_collector(&brokers, &flows).await;
+    let consumed = consume_like_processor(&brokers, &flows).await;
+
+    let result = state
+        .stash(consumed)
+        .await
+        .unwrap_or_else(|e| panic!("stash failed: {:?}", e));
+    assert_eq!(result.acks.len(), flows.len());
+    assert_eq!(result.inserted, flows.len());
+    assert_eq!(result.rejected, 0);
+
+    let block = query(
+        &state,
+        "SELECT host, out_bytes, out_pkts, in_bytes, in_pkts, \
+            toString(assumeNotNull(ipv4_src_addr)) AS src, \
+            toString(assumeNotNull(ipv4_dst_addr)) AS dst, \
+            assumeNotNull(l7_proto) AS l7, l4_dst_port, l4_src_port, \
+            flow_duration_milliseconds, protocol, tcp_flags, malicious \
+        FROM messages ORDER BY out_bytes",
+    )
+    .await;
+
+    let rows = block
+        .rows()
+        .map(|r| {
+            let host: String = r.get("host").unwrap();
+            let malicious: u8 = r.get("malicious").unwrap();
+            let flow = FlowMessage {
+                out_bytes: r.get("out_bytes").unwrap(),
+                out_pkts: r.get("out_pkts").unwrap(),
+                in_bytes: r.get("in_bytes").unwrap(),
+                in_pkts: r.get("in_pkts").unwrap(),
+                ipv4_src_addr: r.get("src").unwrap(),
+                ipv4_dst_addr: r.get("dst").unwrap(),
+                l7_proto: r.get("l7").unwrap(),
+                l4_dst_port: r.get("l4_dst_port").unwrap(),
+                l4_src_port: r.get("l4_src_port").unwrap(),
+                flow_duration_milliseconds: r.get("flow_duration_milliseconds").unwrap(),
+                protocol: r.get("protocol").unwrap(),
+                tcp_flags: r.get("tcp_flags").unwrap(),
+            };
+            assert_eq!(host, "e2e-host");
+            (flow, malicious == 1)
+        })
+        .collect::<Vec<(FlowMessage, bool)>>();
+
+    assert_eq!(rows, flows);
+
+    let recent = ReadbackQuery {
+        host: Some("e2e-host".to_owned()),
+        ..Default::default()
+    };
+    assert_eq!(
+        state.count_recent(&recent).await.unwrap(),
+        flows.len() as u64
+    );
+    let stored = state.recent_rows(&recent).await.unwrap();
+    assert_eq!(stored.len(), flows.len());
+    assert!(stored.iter().any(|f| f.malicious && f.l4_dst_port == 443));
+}
+
+/// Messages of every key are produced concurrently, like the collector exporter sends them, and
+/// have to be consumed in the order they were sent
+#[tokio::test]
+#[ignore]
+async fn keyed_flows_keep_their_order() {
+    const ORDERED_TOPIC: &str = "ordered-flows";
+    const PER_KEY: usize = 200;
+    let docker = clients::Cli::default();
+    let _kafka = docker.run(kafka());
+
+    let brokers = format!("localhost:{}", KAFKA_HOST_PORT);
+    let producer = get_producer(&brokers);
+    let keys = ["host-a", "host-b", "host-c"];
+
+    let sends = (0..PER_KEY * keys.len()).map(|sequence| {
+        let producer = producer.clone();
+        let key = keys[sequence % keys.len()];
+        async move {
+            let mut payload = Vec::new();
+            flows()[0].0.encode(&mut payload).unwrap();
+            producer
+                .send(
+                    FutureRecord::to(ORDERED_TOPIC)
+                        .payload(&payload)
+                        .key(key)
+                        .headers(
+                            OwnedHeaders::new()
+                                .add("host-identifier-x", key)
+                                .add(SEQUENCE_HEADER, &sequence.to_string()),
+                        ),
+                    Duration::from_secs(5),
+                )
+                .await
+                .expect("unable to produce flow");
+        }
+    });
+    futures::future::join_all(sends).await;
+
+    let report = verify_partition_ordering(
+        &brokers,
+        ORDERED_TOPIC,
+        "krewetka-e2e-ordering",
+        PER_KEY * keys.len(),
+        Duration::from_secs(30),
+    )
+    .await;
+    assert!(report.is_ordered(), "{}", report);
+}
+
+#[tokio::test]
+#[ignore]
+async fn missing_table_is_created() {
+    let docker = clients::Cli::default();
+    let clickhouse = docker.run(clickhouse());
+    let state = clickhouse_state(&clickhouse, serde_json::json!({}));
+    wait_for_clickhouse(&state.pool).await;
+
+    assert!(matches!(
+        state.verify_schema().await,
+        Err(SchemaError::TableMissing(_))
+    ));
+
+    let state = clickhouse_state(
+        &clickhouse,
+        serde_json::json!({ "create_table_if_missing": true }),
+    );
+    state.verify_schema().await.unwrap();
+    // created table matches the expected schema
+    state.verify_schema().await.unwrap();
+}
+
+#[tokio::test]
+#[ignore]
+async fn insert_formats_store_identical_rows() {
+    let docker = clients::Cli::default();
+    let clickhouse = docker.run(clickhouse());
+    migrate(&clickhouse).await;
+
+    let mut flows = flows();
+    // nulled by sentinel
+    flows[0].0.l7_proto = 0.0;
+    let msgs = flows
+        .into_iter()
+        .enumerate()
+        .map(|(i, (flow_message, malicious))| FlowMessageWithMetadata {
+            flow_message,
+            malicious: Some(malicious),
+            metadata: FlowMessageMetadata {
+                timestamp: 1_670_000_000_123 + i as u64,
+                host: "e2e-host".to_owned(),
+                id: format!("e2e-{}", i),
+                topic: Some(TOPIC.to_owned()),
+                offset: Some(i as i64),
+                ..testutil::metadata()
+            },
+        })
+        .collect::<Vec<FlowMessageWithMetadata>>();
+
+    let mut stored = Vec::new();
+    for insert_format in ["block", "rowbinary"] {
+        let state = clickhouse_state(
+            &clickhouse,
+            serde_json::json!({
+                "insert_format": insert_format,
+                "null_sentinels": { "l7_proto": "0" },
+            }),
+        );
+
+        let result = state
+            .stash(msgs.clone())
+            .await
+            .unwrap_or_else(|e| panic!("{} stash failed: {:?}", insert_format, e));
+        assert_eq!(result.acks.len(), msgs.len());
+        assert_eq!(result.inserted, msgs.len());
+
+        let rows = query(
+            &state,
+            "SELECT formatRow('TabSeparated', *) AS r FROM messages ORDER BY out_bytes",
+        )
+        .await
+        .rows()
+        .map(|r| r.get::<String, _>("r").unwrap())
+        .collect::<Vec<String>>();
+        stored.push(rows);
+
+        let mut client = state.pool.get_handle().await.unwrap();
+        client.execute("TRUNCATE TABLE messages").await.unwrap();
+    }
+
+    assert_eq!(stored[0].len(), msgs.len());
+    assert_eq!(stored[0], stored[1]);
+}